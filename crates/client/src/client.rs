@@ -1,50 +1,395 @@
-use crate::error::{Result, ClientError};
-use serde::{Deserialize, Serialize};
+use crate::config::{ClientConfig, ReadConsistency};
+use crate::error::{ClientError, Result};
+use crate::format::{self, FormatOptions};
+use crate::cluster::{Cluster, Listing, Node, Route};
+use crate::value::QueryResult;
+use crate::pool::{Connection, PoolStats};
+use crate::retry::{self, RequestOptions};
+use crate::stream::RowStream;
+use crate::transaction::{IsolationLevel, Transaction};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{header, HeaderMap, Method, Request, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct QueryResult {
-    pub columns: Vec<String>,
-    pub rows: Vec<Vec<String>>,
-}
+/// Result format of the server's HTTP API this client reads
+const RESULT_FORMAT: u32 = 2;
 
-/// Database client with connection pooling
+/// Database client with connection pooling, speaking the server's HTTP API
+/// to each node of a cluster (see `cluster`)
 pub struct DatabaseClient {
-    connection_string: String,
+    config: ClientConfig,
+    format: FormatOptions,
+    cluster: Cluster,
+}
+
+/// Body of an `/api/query` response
+#[derive(Deserialize)]
+struct QueryReply {
+    success: bool,
+    result_format: Option<u32>,
+    rows_affected: Option<u64>,
+    result: Option<QueryResult>,
+    /// Continues the result with `/api/query/next`, if rows remain
+    cursor: Option<String>,
+}
+
+/// `error` of a failed request's body
+#[derive(Deserialize)]
+struct ErrorReply {
+    code: String,
+    message: String,
+}
+
+/// The parts of a failure response that say what went wrong
+#[derive(Deserialize)]
+struct FailureReply {
+    error: ErrorReply,
+    leader: Option<String>,
+    /// Set when the request did not run and may succeed if sent again
+    #[serde(default)]
+    retryable: bool,
+}
+
+/// How one attempt at a request failed
+pub(crate) enum Attempt {
+    /// It never reached the server
+    Unsent(ClientError),
+    /// It may have reached the server, and run
+    Lost(ClientError),
+}
+
+impl Attempt {
+    pub(crate) fn into_error(self) -> ClientError {
+        match self {
+            Attempt::Unsent(e) | Attempt::Lost(e) => e,
+        }
+    }
 }
 
 impl DatabaseClient {
+    /// Connect as a connection string such as
+    /// `nextdb://localhost:8080/?timeout=5s` says (see `config`), or
+    /// `nextdb://db1:8080,db2:8080/` to fall back on another node
     pub async fn new(connection_string: &str) -> Result<Self> {
+        Self::connect_with(connection_string.parse()?).await
+    }
+    
+    pub async fn connect_with(config: ClientConfig) -> Result<Self> {
+        let cluster = Cluster::new(&config)?;
         let client = Self { 
-            connection_string: connection_string.to_string() 
+            config,
+            format: FormatOptions::default(),
+            cluster,
         };
         client.connect().await?;
         Ok(client)
     }
     
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+    
+    /// What the connection pools are doing, added up across the nodes,
+    /// for debugging
+    pub fn pool_stats(&self) -> PoolStats {
+        self.cluster.stats()
+    }
+    
+    /// Address of the node the client takes to lead its cluster, if it
+    /// knows of one
+    pub fn leader(&self) -> Option<String> {
+        self.cluster.leader().map(|leader| leader.address().to_string())
+    }
+    
+    /// Options for rendering results in the interactive client
+    pub fn with_format(mut self, format: FormatOptions) -> Self {
+        self.format = format;
+        self
+    }
+    
+    /// Open the first connections to the first configured node that
+    /// answers its health check, and ask it for the rest of its cluster
     pub async fn connect(&self) -> Result<()> {
-        // Simplified connection logic
-        tracing::info!("Connecting to database at: {}", self.connection_string);
+        let mut error = None;
+        for node in self.cluster.nodes() {
+            tracing::info!("Connecting to database at: {}", node.address());
+            match self.check(&node).await {
+                Ok(()) => {
+                    self.cluster.succeeded(&node, Route::Any);
+                    self.refresh().await;
+                    return Ok(());
+                }
+                Err(e) => {
+                    tracing::debug!("Cannot connect to {}: {}", node.address(), e);
+                    self.cluster.failed(&node);
+                    error = Some(e);
+                }
+            }
+        }
+        Err(error.expect("a cluster has a node"))
+    }
+    
+    async fn check(&self, node: &Node) -> Result<()> {
+        node.pool().fill().await?;
+        let (status, _, _) = self.send(node, Method::GET, "/health", None, self.config.timeout).await.map_err(Attempt::into_error)?;
+        if status != StatusCode::OK {
+            return Err(ClientError::Connection(format!("{} is unhealthy: its health check answered {}", node.address(), status)));
+        }
         Ok(())
     }
     
+    /// Ask a node which nodes its cluster has and which one leads. A
+    /// failure leaves what the client knew as it was.
+    async fn refresh(&self) {
+        let node = self.cluster.node(Route::Any);
+        let listing = match self.send(&node, Method::GET, "/api/cluster/nodes", None, self.config.timeout).await {
+            Ok((status, _, body)) if status.is_success() => serde_json::from_slice::<Listing>(&body)
+                .map_err(|e| ClientError::Network(format!("cannot read the server's response: {}", e))),
+            Ok((status, headers, body)) => Err(failure(status, &headers, &body)),
+            Err(attempt) => Err(self.unreachable(&node, attempt.into_error())),
+        };
+        match listing.and_then(|listing| self.cluster.learn(listing)) {
+            Ok(()) => tracing::debug!("Leader of the cluster: {:?}", self.leader()),
+            Err(e) => tracing::debug!("Cannot ask {} for its cluster: {}", node.address(), e),
+        }
+    }
+    
+    /// Note that `node` failed with `error` if it could not be reached,
+    /// returning the error
+    pub(crate) fn unreachable(&self, node: &Arc<Node>, error: ClientError) -> ClientError {
+        if matches!(error, ClientError::Connection(_) | ClientError::Network(_)) {
+            self.cluster.failed(node);
+        }
+        error
+    }
+    
     pub async fn execute_query(&self, sql: &str) -> Result<QueryResult> {
-        // Simplified query execution
-        tracing::info!("Executing query: {}", sql);
-        
-        Ok(QueryResult {
-            columns: vec!["id".to_string(), "name".to_string()],
-            rows: vec![
-                vec!["1".to_string(), "Alice".to_string()],
-                vec!["2".to_string(), "Bob".to_string()],
-            ],
-        })
+        self.execute_query_with(sql, &RequestOptions::default()).await
+    }
+    
+    /// The rows of `sql` read as `T`s, their fields matched to columns by
+    /// name (see `row`)
+    pub async fn query_as<T: DeserializeOwned>(&self, sql: &str) -> Result<Vec<T>> {
+        self.execute_query(sql).await?.rows_as()
+    }
+    
+    /// The one row of `sql` read as a `T`, failing if there is not exactly one
+    pub async fn query_one<T: DeserializeOwned>(&self, sql: &str) -> Result<T> {
+        let rows = self.query_as(sql).await?;
+        if rows.len() != 1 {
+            return Err(ClientError::UnexpectedRows { expected: "one row", found: rows.len() });
+        }
+        Ok(rows.into_iter().next().unwrap())
+    }
+    
+    /// The row of `sql` read as a `T` if there is one, failing if there are
+    /// more
+    pub async fn query_opt<T: DeserializeOwned>(&self, sql: &str) -> Result<Option<T>> {
+        let rows = self.query_as(sql).await?;
+        if rows.len() > 1 {
+            return Err(ClientError::UnexpectedRows { expected: "at most one row", found: rows.len() });
+        }
+        Ok(rows.into_iter().next())
+    }
+    
+    /// The rows of `sql`, fetched `fetch_size` at a time as they are taken
+    /// from the stream (see `stream`)
+    pub fn query_stream(&self, sql: &str) -> RowStream<'_> {
+        RowStream::new(self, sql)
+    }
+    
+    /// `execute_query` with settings for this query alone
+    pub async fn execute_query_with(&self, sql: &str, options: &RequestOptions) -> Result<QueryResult> {
+        let body = serde_json::json!({ "sql": sql });
+        let reads_only = retry::reads_only(sql);
+        let (route, path) = match reads_only {
+            true => self.read_target("/api/query", options),
+            false => (Route::Leader, "/api/query".to_string()),
+        };
+        let idempotent = options.idempotent || reads_only;
+        let (_, status, headers, body) = self.send_retrying(route, Method::POST, &path, Some(body.to_string()), idempotent, options).await?;
+        query_result(status, &headers, &body)
+    }
+    
+    /// Begin a transaction on the server at `isolation` (see `transaction`)
+    pub async fn begin(&self, isolation: IsolationLevel) -> Result<Transaction<'_>> {
+        Transaction::begin(self, isolation).await
+    }
+    
+    /// Run `body` in a transaction and commit it, running it again in a new
+    /// transaction, as the retry policy allows, while it fails with
+    /// `ClientError::Conflict`. The transaction is rolled back if `body`
+    /// fails.
+    pub async fn transaction<T, F>(&self, isolation: IsolationLevel, mut body: F) -> Result<T>
+    where
+        F: AsyncFnMut(&mut Transaction<'_>) -> Result<T>,
+    {
+        let policy = &self.config.retry;
+        let mut retry = 0;
+        loop {
+            let mut txn = self.begin(isolation).await?;
+            let result = match body(&mut txn).await {
+                Ok(value) => txn.commit().await.map(|()| value),
+                Err(e) => {
+                    if let Err(rollback) = txn.rollback().await {
+                        tracing::debug!("Cannot roll back a failed transaction: {}", rollback);
+                    }
+                    Err(e)
+                }
+            };
+            match result {
+                Err(ClientError::Conflict(message)) if retry < policy.max_retries => {
+                    retry += 1;
+                    let wait = policy.backoff(retry);
+                    tracing::debug!("Running a transaction again in {:?} after a conflict: {}", wait, message);
+                    tokio::time::sleep(wait).await;
+                }
+                result => return result,
+            }
+        }
+    }
+    
+    /// Where to send a read of `path` at the consistency `options` ask
+    /// for: a node that may lag the leader, or the leader itself
+    pub(crate) fn read_target(&self, path: &str, options: &RequestOptions) -> (Route, String) {
+        match options.consistency.unwrap_or(self.config.consistency) {
+            ReadConsistency::Eventual => (Route::Any, path.to_string()),
+            consistency => {
+                let separator = if path.contains('?') { '&' } else { '?' };
+                (Route::Leader, format!("{}{}consistency={}", path, separator, consistency.parameter()))
+            }
+        }
+    }
+    
+    /// `send` to the node `route` picks, and send again while it fails in
+    /// ways the retry policy covers (see `retry`), to another node if that
+    /// one cannot be reached or does not lead. Requests that are not
+    /// `idempotent` fail with `ClientError::Ambiguous` if they may have run.
+    /// Returns the node that answered with its response.
+    pub(crate) async fn send_retrying(
+        &self,
+        mut route: Route,
+        method: Method,
+        path: &str,
+        body: Option<String>,
+        idempotent: bool,
+        options: &RequestOptions,
+    ) -> Result<(Arc<Node>, StatusCode, HeaderMap, Bytes)> {
+        let policy = &self.config.retry;
+        let retries = if options.no_retry { 0 } else { policy.max_retries };
+        let timeout = options.timeout.unwrap_or(self.config.timeout);
+        let mut retry = 0;
+        loop {
+            let node = self.cluster.node(route);
+            let (error, retry_after) = match self.send(&node, method.clone(), path, body.clone(), timeout).await {
+                Ok((status, headers, body)) if status.is_success() || retry == retries || !retryable(&body) => {
+                    if status.is_success() {
+                        self.cluster.succeeded(&node, route);
+                    }
+                    return Ok((node, status, headers, body));
+                }
+                Ok((status, headers, body)) => (failure(status, &headers, &body), retry_after(&headers)),
+                Err(Attempt::Lost(e)) if !idempotent => {
+                    return Err(ClientError::Ambiguous(Box::new(self.unreachable(&node, e))));
+                }
+                Err(Attempt::Unsent(e @ ClientError::Connection(_)) | Attempt::Lost(e)) if retry < retries => {
+                    let e = self.unreachable(&node, e);
+                    self.refresh().await;
+                    (e, None)
+                }
+                Err(attempt) => return Err(self.unreachable(&node, attempt.into_error())),
+            };
+            retry += 1;
+            let mut wait = policy.backoff(retry).max(retry_after.unwrap_or_default());
+            if let ClientError::NotLeader { leader } = &error {
+                route = Route::Leader;
+                match leader {
+                    // Sent straight on to a leader the client did not know of
+                    Some(leader) => match self.cluster.follow(leader) {
+                        Ok(next) if !Arc::ptr_eq(&next, &node) && Arc::ptr_eq(&next, &self.cluster.node(route)) => {
+                            wait = Duration::ZERO;
+                        }
+                        Ok(_) => {}
+                        Err(e) => tracing::debug!("Cannot follow the leader to {}: {}", leader, e),
+                    },
+                    None => self.refresh().await,
+                }
+            }
+            tracing::debug!("Retrying {} {} in {:?} after: {}", method, path, wait, error);
+            tokio::time::sleep(wait).await;
+        }
+    }
+    
+    /// Send a request with `body` as JSON on a connection to `node`, and
+    /// read the response within `timeout`
+    pub(crate) async fn send(
+        &self,
+        node: &Node,
+        method: Method,
+        path: &str,
+        body: Option<String>,
+        timeout: Duration,
+    ) -> std::result::Result<(StatusCode, HeaderMap, Bytes), Attempt> {
+        let request = self.request(method, path, body).map_err(Attempt::Unsent)?;
+        let mut connection = node.pool().checkout().await.map_err(Attempt::Unsent)?;
+        self.exchange(&mut connection, request, timeout).await
+    }
+    
+    /// A connection of its own to the node `route` picks
+    pub(crate) async fn checkout(&self, route: Route) -> Result<(Arc<Node>, Connection)> {
+        let node = self.cluster.node(route);
+        match node.pool().checkout().await {
+            Ok(connection) => Ok((node, connection)),
+            Err(e) => Err(self.unreachable(&node, e)),
+        }
+    }
+    
+    /// Take the node at `leader` to lead, as a `not_leader` answer says
+    pub(crate) fn follow(&self, leader: &str) -> Result<()> {
+        self.cluster.follow(leader).map(|_| ())
+    }
+    
+    /// A request with `body` as JSON, and the client's token, for any node
+    pub(crate) fn request(&self, method: Method, path: &str, body: Option<String>) -> Result<Request<Full<Bytes>>> {
+        let mut request = Request::builder().method(method).uri(path);
+        if body.is_some() {
+            request = request.header(header::CONTENT_TYPE, "application/json");
+        }
+        if let Some(token) = &self.config.token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        request.body(Full::new(Bytes::from(body.unwrap_or_default())))
+            .map_err(|e| ClientError::Query(format!("invalid request: {}", e)))
+    }
+    
+    /// Send `request` on `connection`, and read the whole response within
+    /// `timeout`. A connection that timed out is not used again, as the
+    /// rest of its response may yet arrive.
+    pub(crate) async fn exchange(
+        &self,
+        connection: &mut Connection,
+        request: Request<Full<Bytes>>,
+        timeout: Duration,
+    ) -> std::result::Result<(StatusCode, HeaderMap, Bytes), Attempt> {
+        match tokio::time::timeout(timeout, connection.send(request)).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e @ ClientError::Connection(_))) => Err(Attempt::Unsent(e)),
+            Ok(Err(e)) => Err(Attempt::Lost(e)),
+            Err(_) => Err(Attempt::Lost(ClientError::Timeout)),
+        }
     }
     
     pub async fn run_interactive(&self) -> Result<()> {
         use std::io::{self, Write};
         
+        let mut options = self.format.clone();
+        
         println!("NextDB Interactive Client");
-        println!("Connected to: {}", self.connection_string);
+        println!("Connected to: {}", self.config.address());
         println!("Type 'exit' to quit, 'help' for commands");
         println!();
         
@@ -66,15 +411,32 @@ impl DatabaseClient {
                     println!("  SELECT * FROM table_name  - Query data");
                     println!("  INSERT INTO ...           - Insert data");
                     println!("  CREATE TABLE ...          - Create table");
+                    println!("  \\dt                       - List tables");
+                    println!("  \\d table_name             - Describe a table");
+                    println!("  \\format [table|csv|json]  - Show or set the output format");
                     println!("  help                      - Show this help");
                     println!("  exit                      - Exit client");
                 }
                 "" => continue,
-                sql => {
-                    match self.execute_query(sql).await {
+                input if input.split_whitespace().next() == Some("\\format") => {
+                    match input.split_whitespace().nth(1).map(str::parse) {
+                        None => println!("Output format is {}", options.output),
+                        Some(Ok(output)) => {
+                            options.output = output;
+                            println!("Output format is {}", options.output);
+                        }
+                        Some(Err(e)) => println!("Error: {}", e),
+                    }
+                }
+                input => {
+                    let sql = expand_shortcut(input).unwrap_or_else(|| input.to_string());
+                    if retry::reads_only(&sql) {
+                        self.print_stream(&sql, &options).await;
+                        continue;
+                    }
+                    match self.execute_query(&sql).await {
                         Ok(result) => {
-                            // Print result table
-                            println!("{}", format_query_result(&result));
+                            print!("{}", format::format_result(&result, &options));
                         }
                         Err(e) => {
                             println!("Error: {}", e);
@@ -86,48 +448,358 @@ impl DatabaseClient {
         
         Ok(())
     }
+    
+    /// Print the rows of `sql` a page at a time, as they arrive
+    async fn print_stream(&self, sql: &str, options: &FormatOptions) {
+        use futures::StreamExt;
+        use std::io::Write;
+        
+        let mut rows = self.query_stream(sql);
+        let mut printer = None;
+        let mut page = Vec::new();
+        let mut failed = None;
+        loop {
+            let more = match rows.next().await {
+                Some(Ok(row)) => {
+                    page.push(row.into_values());
+                    true
+                }
+                Some(Err(e)) => {
+                    failed = Some(e);
+                    false
+                }
+                None => false,
+            };
+            if page.len() == self.config.fetch_size || (!more && !page.is_empty()) {
+                let printer = printer.get_or_insert_with(|| format::RowPrinter::new(rows.columns().unwrap_or_default(), options));
+                print!("{}", printer.print(&page));
+                std::io::stdout().flush().unwrap();
+                page.clear();
+            }
+            if !more {
+                break;
+            }
+        }
+        match failed {
+            Some(e) => println!("Error: {}", e),
+            None => {
+                let printer = printer.unwrap_or_else(|| format::RowPrinter::new(rows.columns().unwrap_or_default(), options));
+                print!("{}", printer.finish());
+            }
+        }
+    }
 }
 
-fn format_query_result(result: &QueryResult) -> String {
-    let mut output = String::new();
-    
-    // Header
-    output.push_str(&format!("| {} |\n", result.columns.join(" | ")));
-    
-    // Separator
-    let separator = result.columns
-        .iter()
-        .map(|col| "-".repeat(col.len().max(3)))
-        .collect::<Vec<_>>()
-        .join("-|-");
-    output.push_str(&format!("|{}|\n", separator));
-    
-    // Rows
-    for row in &result.rows {
-        output.push_str(&format!("| {} |\n", row.join(" | ")));
+/// Translate psql-style backslash commands into the equivalent SQL
+fn expand_shortcut(input: &str) -> Option<String> {
+    let mut parts = input.split_whitespace();
+    match (parts.next()?, parts.next(), parts.next()) {
+        ("\\dt", None, None) => Some("SHOW TABLES".to_string()),
+        ("\\d", Some(table), None) => Some(format!("DESCRIBE {}", table)),
+        _ => None,
     }
-    
-    output.push_str(&format!("\n({} rows)\n", result.rows.len()));
-    
-    output
+}
+
+/// The result a response to a query carries, or the error it reports
+pub(crate) fn query_result(status: StatusCode, headers: &HeaderMap, body: &[u8]) -> Result<QueryResult> {
+    query_page(status, headers, body).map(|(result, _)| result)
+}
+
+/// `query_result`, with the cursor to the rows after it if some remain
+pub(crate) fn query_page(status: StatusCode, headers: &HeaderMap, body: &[u8]) -> Result<(QueryResult, Option<String>)> {
+    let Ok(reply) = serde_json::from_slice::<QueryReply>(body) else {
+        return Err(failure(status, headers, body));
+    };
+    if !reply.success {
+        return Err(failure(status, headers, body));
+    }
+    if reply.result_format != Some(RESULT_FORMAT) {
+        return Err(ClientError::Network(format!(
+            "the server sent results in format {:?}, and this client reads format {}", reply.result_format, RESULT_FORMAT
+        )));
+    }
+    let result = reply.result.unwrap_or(QueryResult {
+        columns: Vec::new(),
+        rows: Vec::new(),
+        rows_affected: reply.rows_affected,
+    });
+    Ok((result, reply.cursor))
+}
+
+/// The error a failure response describes. Responses without the API's
+/// error body, such as from a proxy in between, keep their status.
+pub(crate) fn failure(status: StatusCode, headers: &HeaderMap, body: &[u8]) -> ClientError {
+    match serde_json::from_slice::<FailureReply>(body) {
+        Ok(reply) => ClientError::from_server(&reply.error.code, reply.error.message, retry_after(headers), reply.leader),
+        Err(_) => ClientError::Server {
+            code: status.as_u16().to_string(),
+            message: String::from_utf8_lossy(body).into_owned(),
+        },
+    }
+}
+
+/// Whether a failure response says the request did not run and may be
+/// sent again. A node that does not lead runs no writes, so it may be sent
+/// again to the leader.
+fn retryable(body: &[u8]) -> bool {
+    serde_json::from_slice::<FailureReply>(body).is_ok_and(|reply| reply.retryable || reply.error.code == "not_leader")
+}
+
+/// How long a response asks the client to wait before trying again
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers.get(header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// An error with the errors that caused it, which hyper keeps out of its
+/// own messages
+pub(crate) fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock::{MockServer, Reply};
+    use crate::retry::RetryPolicy;
+    use crate::value::Value;
+    
+    /// A server answering the client's health check, then `script`, then
+    /// with success
+    async fn scripted(script: Vec<Reply>) -> MockServer {
+        let success = serde_json::json!({ "success": true, "result_format": RESULT_FORMAT, "rows_affected": 1 });
+        MockServer::start([vec![Reply::ok(serde_json::json!("ok"))], script].concat(), Reply::ok(success), usize::MAX).await
+    }
+    
+    async fn connect(server: &MockServer, max_retries: u32) -> DatabaseClient {
+        let retry = RetryPolicy { max_retries, initial_backoff: Duration::from_millis(20), max_backoff: Duration::from_secs(1) };
+        DatabaseClient::connect_with(ClientConfig { retry, ..server.config() }).await.unwrap()
+    }
+    
+    fn shed() -> Reply {
+        let body = serde_json::json!({ "success": false, "retryable": true, "error": { "code": "queue_full", "message": "busy" } });
+        Reply::Json(503, Vec::new(), body)
+    }
     
     #[tokio::test]
-    async fn test_client_connection() {
-        let client = DatabaseClient::new("localhost:5432").await;
-        assert!(client.is_ok());
+    async fn test_retries_with_growing_backoff() {
+        let server = scripted(vec![shed(), shed(), shed()]).await;
+        let client = connect(&server, 3).await;
+        let result = client.execute_query("INSERT INTO t VALUES (1)").await.unwrap();
+        assert_eq!(result.rows_affected, Some(1));
+        
+        // The health check, then four attempts, each retry waiting at least
+        // half of 20ms, 40ms and 80ms
+        let received = server.received();
+        assert_eq!(received.len(), 5);
+        for (i, pair) in received[1..].windows(2).enumerate() {
+            let least = Duration::from_millis(10 << i);
+            assert!(pair[1].at - pair[0].at >= least, "retry {} after {:?}", i + 1, pair[1].at - pair[0].at);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_retries_stop_at_the_limit() {
+        let server = scripted(vec![shed(); 5]).await;
+        let client = connect(&server, 2).await;
+        assert!(matches!(client.execute_query("SELECT 1").await, Err(ClientError::Unavailable { .. })));
+        assert_eq!(server.received().len(), 1 + 3);
+        
+        // Not retried at all when asked not to, or when not marked retryable
+        let options = RequestOptions::new().no_retry();
+        assert!(matches!(client.execute_query_with("SELECT 1", &options).await, Err(ClientError::Unavailable { .. })));
+        assert_eq!(server.received().len(), 1 + 4);
+        
+        let server = scripted(vec![Reply::Json(400, Vec::new(), serde_json::json!({
+            "success": false, "error": { "code": "parse_error", "message": "bad" },
+        }))]).await;
+        let client = connect(&server, 2).await;
+        assert!(matches!(client.execute_query("SELEKT 1").await, Err(ClientError::Query(_))));
+        assert_eq!(server.received().len(), 2);
+    }
+    
+    #[tokio::test]
+    async fn test_writes_not_retried_after_ambiguous_failures() {
+        let success = Reply::ok(serde_json::json!({ "success": true, "result_format": RESULT_FORMAT, "rows_affected": 1 }));
+        let server = scripted(vec![Reply::Hang, Reply::Hang, success, Reply::Hang]).await;
+        let client = connect(&server, 3).await;
+        
+        // The connection broke after the INSERT went out
+        match client.execute_query("INSERT INTO t VALUES (1)").await {
+            Err(ClientError::Ambiguous(cause)) => assert!(matches!(*cause, ClientError::Network(_)), "{:?}", cause),
+            other => panic!("expected an ambiguous failure, got {:?}", other),
+        }
+        assert_eq!(server.received().len(), 2);
+        
+        // Reads, and writes vouched for as idempotent, are sent again
+        client.execute_query("SELECT * FROM t").await.unwrap();
+        assert_eq!(server.received().len(), 4);
+        client.execute_query_with("UPDATE t SET v = 1", &RequestOptions::new().idempotent()).await.unwrap();
+        assert_eq!(server.received().len(), 6);
+    }
+    
+    #[tokio::test]
+    async fn test_timeouts_lose_attempts_and_their_connections() {
+        let late = || Reply::Late(Duration::from_secs(1), Box::new(Reply::ok(serde_json::json!("late"))));
+        let success = Reply::ok(serde_json::json!({ "success": true, "result_format": RESULT_FORMAT, "rows_affected": 1 }));
+        let begun = Reply::ok(serde_json::json!({ "transaction_id": "t1" }));
+        let server = scripted(vec![late(), late(), success, late(), begun, late()]).await;
+        let client = connect(&server, 3).await;
+        let options = RequestOptions::new().timeout(Duration::from_millis(100));
+        let before = client.pool_stats();
+        
+        // A read is sent again after each attempt times out, on a new
+        // connection each time
+        let started = std::time::Instant::now();
+        client.execute_query_with("SELECT 1", &options).await.unwrap();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_secs(1), "took {:?}", elapsed);
+        assert_eq!(server.received().len(), 1 + 3);
+        assert_eq!(client.pool_stats().reaped - before.reaped, 2);
+        
+        // A write that timed out may have run
+        match client.execute_query_with("INSERT INTO t VALUES (1)", &options).await {
+            Err(ClientError::Ambiguous(cause)) => assert!(matches!(*cause, ClientError::Timeout), "{:?}", cause),
+            other => panic!("expected an ambiguous failure, got {:?}", other),
+        }
+        assert_eq!(server.received().len(), 1 + 4);
+        
+        // A statement in a transaction times out alone, and the next one is
+        // not answered with its late response
+        let mut txn = client.begin(IsolationLevel::ReadCommitted).await.unwrap();
+        assert!(matches!(txn.query_with("SELECT 1", &options).await, Err(ClientError::Timeout)));
+        assert_eq!(txn.query("SELECT 1").await.unwrap().rows_affected, Some(1));
+        assert_eq!(client.pool_stats().reaped - before.reaped, 4);
     }
     
     #[tokio::test]
-    async fn test_client_query() {
-        let client = DatabaseClient::new("localhost:5432").await.unwrap();
+    async fn test_reads_ask_for_their_consistency() {
+        let server = scripted(Vec::new()).await;
+        let config = ClientConfig { consistency: ReadConsistency::LeaderOnly, ..server.config() };
+        let client = DatabaseClient::connect_with(config).await.unwrap();
+        let linearizable = RequestOptions::new().consistency(ReadConsistency::Linearizable);
+        client.execute_query("SELECT 1").await.unwrap();
+        client.execute_query_with("SELECT 1", &linearizable).await.unwrap();
+        client.execute_query_with("SELECT 1", &RequestOptions::new().consistency(ReadConsistency::Eventual)).await.unwrap();
+        // Writes are as up to date as can be anyway
+        client.execute_query_with("INSERT INTO t VALUES (1)", &linearizable).await.unwrap();
+        assert_eq!(server.paths()[1..], [
+            "/api/query?consistency=leader",
+            "/api/query?consistency=linearizable",
+            "/api/query",
+            "/api/query",
+        ]);
+    }
+    
+    #[tokio::test]
+    async fn test_client_connection() {
+        // Nothing listens on a port just released
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        match DatabaseClient::new(&format!("127.0.0.1:{}", port)).await {
+            Err(ClientError::Connection(message)) => assert!(message.contains("cannot connect"), "{}", message),
+            Err(e) => panic!("expected a connection error, got {}", e),
+            Ok(_) => panic!("connected to a closed port"),
+        }
+        assert!(matches!(DatabaseClient::new("nextdb://localhost?pool=0").await, Err(ClientError::Connection(_))));
+    }
+    
+    #[test]
+    fn test_expand_shortcut() {
+        assert_eq!(expand_shortcut("\\dt").as_deref(), Some("SHOW TABLES"));
+        assert_eq!(expand_shortcut("\\d  users").as_deref(), Some("DESCRIBE users"));
+        assert_eq!(expand_shortcut("\\d"), None);
+        assert_eq!(expand_shortcut("SELECT 1"), None);
+    }
+    
+    #[test]
+    fn test_failure_maps_error_codes() {
+        let body = |code: &str| serde_json::json!({ "success": false, "error": { "code": code, "message": "m" } }).to_string();
+        let mut headers = HeaderMap::new();
+        
+        assert!(matches!(failure(StatusCode::BAD_REQUEST, &headers, body("parse_error").as_bytes()), ClientError::Query(_)));
+        assert!(matches!(failure(StatusCode::NOT_FOUND, &headers, body("table_not_found").as_bytes()), ClientError::NotFound(_)));
+        assert!(matches!(failure(StatusCode::CONFLICT, &headers, body("constraint_violation").as_bytes()), ClientError::ConstraintViolation(_)));
+        assert!(matches!(failure(StatusCode::UNAUTHORIZED, &headers, body("unauthorized").as_bytes()), ClientError::Authentication));
+        assert!(matches!(failure(StatusCode::FORBIDDEN, &headers, body("read_only_token").as_bytes()), ClientError::PermissionDenied(_)));
+        
+        headers.insert(header::RETRY_AFTER, "3".parse().unwrap());
+        match failure(StatusCode::TOO_MANY_REQUESTS, &headers, body("rate_limited").as_bytes()) {
+            ClientError::Unavailable { retry_after, .. } => assert_eq!(retry_after, Some(Duration::from_secs(3))),
+            other => panic!("expected unavailable, got {:?}", other),
+        }
+        let redirect = serde_json::json!({ "success": false, "error": { "code": "not_leader", "message": "m" }, "leader": "b:8080" });
+        match failure(StatusCode::TEMPORARY_REDIRECT, &headers, redirect.to_string().as_bytes()) {
+            ClientError::NotLeader { leader } => assert_eq!(leader.as_deref(), Some("b:8080")),
+            other => panic!("expected not leader, got {:?}", other),
+        }
+        
+        // Codes this client does not know, and bodies that are not the API's
+        match failure(StatusCode::INTERNAL_SERVER_ERROR, &headers, body("corruption").as_bytes()) {
+            ClientError::Server { code, .. } => assert_eq!(code, "corruption"),
+            other => panic!("expected a server error, got {:?}", other),
+        }
+        match failure(StatusCode::BAD_GATEWAY, &headers, b"Bad Gateway") {
+            ClientError::Server { code, message } => assert_eq!((code.as_str(), message.as_str()), ("502", "Bad Gateway")),
+            other => panic!("expected a server error, got {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn test_typed_result_round_trip() {
+        let json = serde_json::json!({
+            "columns": [
+                { "name": "i", "data_type": "INTEGER" },
+                { "name": "f", "data_type": "FLOAT" },
+                { "name": "t", "data_type": "TEXT" },
+                { "name": "b", "data_type": "BOOLEAN" },
+                { "name": "x", "data_type": "BLOB" },
+                { "name": "ts", "data_type": "TIMESTAMP" },
+                { "name": "n", "data_type": null },
+            ],
+            "rows": [
+                [1, 1.0, "1", true, { "$base64": "AJ//" }, "2024-03-10T07:30:00.250Z", null],
+                [null, { "$float": "NaN" }, "null", false, { "$base64": "" }, null, 7],
+            ],
+            "rows_affected": null,
+        });
+        
+        let result: QueryResult = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(result.rows[0], vec![
+            Value::Integer(1),
+            Value::Float(1.0),
+            Value::Text("1".to_string()),
+            Value::Boolean(true),
+            Value::Blob(vec![0, 159, 255]),
+            Value::Timestamp("2024-03-10T07:30:00.250Z".to_string()),
+            Value::Null,
+        ]);
+        assert!(matches!(result.rows[1][1], Value::Float(v) if v.is_nan()));
+        assert_eq!(result.rows[1][2], Value::Text("null".to_string()));
+        assert_eq!(result.rows[1][6], Value::Integer(7));
+        assert_eq!(serde_json::to_value(&result).unwrap(), json);
+        
+        assert_eq!(format::format_table(&result, &FormatOptions::default()), [
+            "| i    | f   | t    | b     | x        | ts                       | n    |",
+            "|------|-----|------|-------|----------|--------------------------|------|",
+            "|    1 | 1.0 | 1    | true  | \\x009fff | 2024-03-10T07:30:00.250Z | NULL |",
+            "| NULL | NaN | null | false | \\x       | NULL                     |    7 |",
+            "",
+            "(2 rows)",
+            "",
+        ].join("\n"));
         
-        let result = client.execute_query("SELECT * FROM users").await.unwrap();
-        assert_eq!(result.columns, vec!["id", "name"]);
-        assert_eq!(result.rows.len(), 2);
+        let bad = serde_json::json!({
+            "columns": [{ "name": "x", "data_type": "BLOB" }],
+            "rows": [[{ "$base64": "not base64!" }]],
+        });
+        assert!(serde_json::from_value::<QueryResult>(bad).is_err());
     }
 }
\ No newline at end of file
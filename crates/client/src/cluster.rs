@@ -0,0 +1,296 @@
+//! The nodes of the cluster a `DatabaseClient` talks to.
+//!
+//! The client starts from the addresses its config gives (see `config`)
+//! and keeps a pool of connections to each node it knows of. It asks
+//! `/api/cluster/nodes` for the other nodes, and for which one leads, on
+//! connecting and whenever a node cannot be reached or answers `not_leader`
+//! without naming the leader. Statements that write, and transactions, go
+//! to the leader once one is known, and a `not_leader` answer naming the
+//! leader sends the request on there. Everything else goes to the node that
+//! last served a request that any node could.
+//!
+//! A node that cannot be reached is avoided for a second, and twice as long
+//! each time it fails again in a row, up to a minute. Requests fail over to
+//! the other nodes as the retry policy allows (see `retry`), and a node that
+//! keeps going down does not keep costing them a connection attempt. Once
+//! every node is avoided, the one whose time is up first is tried anyway. A
+//! standalone server is a cluster of one.
+
+use crate::config::ClientConfig;
+use crate::error::Result;
+use crate::pool::{Pool, PoolStats};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a node is avoided after it first fails
+const FIRST_AVOIDANCE: Duration = Duration::from_secs(1);
+const MAX_AVOIDANCE: Duration = Duration::from_secs(60);
+
+/// Which node a request must go to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Route {
+    /// The leader, as only it writes
+    Leader,
+    Any,
+}
+
+/// A node of the cluster, and the connections to it
+pub(crate) struct Node {
+    pool: Arc<Pool>,
+    health: Mutex<Health>,
+}
+
+#[derive(Default)]
+struct Health {
+    /// Failures since it last served a request
+    failures: u32,
+    avoided_until: Option<Instant>,
+}
+
+impl Node {
+    fn new(address: String, config: &ClientConfig) -> Result<Arc<Self>> {
+        let pool = Arc::new(Pool::new(address, config)?);
+        Ok(Arc::new(Self { pool, health: Mutex::new(Health::default()) }))
+    }
+
+    pub(crate) fn address(&self) -> &str {
+        self.pool.address()
+    }
+
+    pub(crate) fn pool(&self) -> &Arc<Pool> {
+        &self.pool
+    }
+
+    /// Until when the node is avoided, if it is at `now`
+    fn avoided_until(&self, now: Instant) -> Option<Instant> {
+        self.health.lock().unwrap().avoided_until.filter(|until| *until > now)
+    }
+}
+
+/// Body of an `/api/cluster/nodes` response
+#[derive(Deserialize)]
+pub(crate) struct Listing {
+    /// Null from a standalone server
+    term: Option<u64>,
+    nodes: Vec<Listed>,
+}
+
+#[derive(Deserialize)]
+struct Listed {
+    address: Option<String>,
+    role: String,
+}
+
+pub(crate) struct Cluster {
+    config: ClientConfig,
+    nodes: Mutex<Nodes>,
+}
+
+struct Nodes {
+    /// In the order the client learned of them, the configured ones first
+    all: Vec<Arc<Node>>,
+    leader: Option<Arc<Node>>,
+    /// Where requests any node may serve go first
+    preferred: Arc<Node>,
+}
+
+impl Nodes {
+    fn find_or_add(&mut self, address: &str, config: &ClientConfig) -> Result<Arc<Node>> {
+        if let Some(node) = self.all.iter().find(|node| node.address() == address) {
+            return Ok(node.clone());
+        }
+        let node = Node::new(address.to_string(), config)?;
+        self.all.push(node.clone());
+        Ok(node)
+    }
+}
+
+impl Cluster {
+    pub(crate) fn new(config: &ClientConfig) -> Result<Self> {
+        let all = config.addresses().into_iter()
+            .map(|address| Node::new(address, config))
+            .collect::<Result<Vec<_>>>()?;
+        let preferred = all[0].clone();
+        Ok(Self { config: config.clone(), nodes: Mutex::new(Nodes { all, leader: None, preferred }) })
+    }
+
+    /// Every node the client knows of
+    pub(crate) fn nodes(&self) -> Vec<Arc<Node>> {
+        self.nodes.lock().unwrap().all.clone()
+    }
+
+    /// The node taken to lead, if any
+    pub(crate) fn leader(&self) -> Option<Arc<Node>> {
+        self.nodes.lock().unwrap().leader.clone()
+    }
+
+    /// The node to send a request to: the leader or the preferred node as
+    /// `route` asks, or the first other one if that one is avoided
+    pub(crate) fn node(&self, route: Route) -> Arc<Node> {
+        let nodes = self.nodes.lock().unwrap();
+        let first = match (route, &nodes.leader) {
+            (Route::Leader, Some(leader)) => leader,
+            _ => &nodes.preferred,
+        };
+        let now = Instant::now();
+        std::iter::once(first).chain(&nodes.all)
+            .min_by_key(|node| node.avoided_until(now))
+            .expect("a cluster has a node")
+            .clone()
+    }
+
+    /// Note that `node` served a request sent by `route`. One that served
+    /// a write leads, as far as the client knows, whatever a node listed.
+    pub(crate) fn succeeded(&self, node: &Arc<Node>, route: Route) {
+        *node.health.lock().unwrap() = Health::default();
+        let mut nodes = self.nodes.lock().unwrap();
+        match route {
+            Route::Leader => nodes.leader = Some(node.clone()),
+            Route::Any => nodes.preferred = node.clone(),
+        }
+    }
+
+    /// Note that `node` could not be reached, and avoid it for a while
+    pub(crate) fn failed(&self, node: &Arc<Node>) {
+        let avoidance = {
+            let mut health = node.health.lock().unwrap();
+            health.failures += 1;
+            let avoidance = FIRST_AVOIDANCE.saturating_mul(2u32.saturating_pow(health.failures - 1)).min(MAX_AVOIDANCE);
+            health.avoided_until = Some(Instant::now() + avoidance);
+            avoidance
+        };
+        tracing::debug!("Avoiding {} for {:?}, as it cannot be reached", node.address(), avoidance);
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.leader.as_ref().is_some_and(|leader| Arc::ptr_eq(leader, node)) {
+            nodes.leader = None;
+        }
+    }
+
+    /// Take the node at `address` to lead, returning it
+    pub(crate) fn follow(&self, address: &str) -> Result<Arc<Node>> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let leader = nodes.find_or_add(address, &self.config)?;
+        if nodes.leader.as_ref().is_none_or(|known| !Arc::ptr_eq(known, &leader)) {
+            tracing::debug!("Following the leader to {}", address);
+        }
+        nodes.leader = Some(leader.clone());
+        Ok(leader)
+    }
+
+    /// Learn of the nodes a node listed, and which one leads. A leader the
+    /// client cannot reach is not taken, as the node may not have noticed
+    /// it is gone.
+    pub(crate) fn learn(&self, listing: Listing) -> Result<()> {
+        if listing.term.is_none() {
+            return Ok(());
+        }
+        let mut nodes = self.nodes.lock().unwrap();
+        let (now, mut leader) = (Instant::now(), None);
+        for listed in listing.nodes {
+            let Some(address) = listed.address else {
+                continue;
+            };
+            let node = nodes.find_or_add(&address, &self.config)?;
+            if listed.role == "leader" && node.avoided_until(now).is_none() {
+                leader = Some(node);
+            }
+        }
+        nodes.leader = leader;
+        Ok(())
+    }
+
+    /// What the pools are doing, added up
+    pub(crate) fn stats(&self) -> PoolStats {
+        self.nodes().iter().map(|node| node.pool.stats()).fold(PoolStats::default(), |total, stats| PoolStats {
+            in_use: total.in_use + stats.in_use,
+            idle: total.idle + stats.idle,
+            waiters: total.waiters + stats.waiters,
+            created: total.created + stats.created,
+            reaped: total.reaped + stats.reaped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster() -> Cluster {
+        let config: ClientConfig = "nextdb://a:1,b:2,c:3".parse().unwrap();
+        Cluster::new(&config).unwrap()
+    }
+
+    fn listing(term: Option<u64>, nodes: &[(&str, &str)]) -> Listing {
+        let nodes = nodes.iter().map(|(address, role)| serde_json::json!({ "address": address, "role": role })).collect::<Vec<_>>();
+        serde_json::from_value(serde_json::json!({ "term": term, "leader": null, "nodes": nodes })).unwrap()
+    }
+
+    #[test]
+    fn test_requests_routed_around_failed_nodes() {
+        let cluster = cluster();
+        assert_eq!(cluster.node(Route::Any).address(), "a:1");
+        assert_eq!(cluster.node(Route::Leader).address(), "a:1");
+
+        // Reads stay on the node that last served one
+        let a = cluster.node(Route::Any);
+        cluster.failed(&a);
+        let b = cluster.node(Route::Any);
+        assert_eq!(b.address(), "b:2");
+        cluster.succeeded(&b, Route::Any);
+        assert_eq!(cluster.node(Route::Any).address(), "b:2");
+
+        // Writes go to the leader, until it fails
+        let c = cluster.follow("c:3").unwrap();
+        assert_eq!(cluster.node(Route::Leader).address(), "c:3");
+        assert_eq!(cluster.node(Route::Any).address(), "b:2");
+        cluster.failed(&c);
+        assert!(cluster.leader().is_none());
+        assert_eq!(cluster.node(Route::Leader).address(), "b:2");
+
+        // With every node avoided, the one avoided the shortest is tried
+        cluster.failed(&b);
+        cluster.failed(&a);
+        assert_eq!(cluster.node(Route::Any).address(), "c:3");
+        cluster.succeeded(&a, Route::Leader);
+        assert_eq!(cluster.node(Route::Any).address(), "a:1");
+        assert_eq!(cluster.leader().unwrap().address(), "a:1");
+    }
+
+    #[test]
+    fn test_avoidance_grows_with_each_failure() {
+        let cluster = cluster();
+        let a = cluster.node(Route::Any);
+        let avoided = || a.avoided_until(Instant::now()).unwrap() - Instant::now();
+        cluster.failed(&a);
+        assert!(avoided() <= FIRST_AVOIDANCE);
+        cluster.failed(&a);
+        cluster.failed(&a);
+        assert!(avoided() > 3 * FIRST_AVOIDANCE && avoided() <= 4 * FIRST_AVOIDANCE);
+        for _ in 0..40 {
+            cluster.failed(&a);
+        }
+        assert!(avoided() <= MAX_AVOIDANCE);
+        cluster.succeeded(&a, Route::Any);
+        assert!(a.avoided_until(Instant::now()).is_none());
+    }
+
+    #[test]
+    fn test_nodes_learned_from_listings() {
+        let cluster = cluster();
+        cluster.learn(listing(None, &[("0.0.0.0:8080", "standalone")])).unwrap();
+        assert_eq!(cluster.nodes().len(), 3);
+
+        cluster.learn(listing(Some(4), &[("b:2", "follower"), ("d:4", "leader")])).unwrap();
+        let addresses: Vec<String> = cluster.nodes().iter().map(|node| node.address().to_string()).collect();
+        assert_eq!(addresses, ["a:1", "b:2", "c:3", "d:4"]);
+        assert_eq!(cluster.node(Route::Leader).address(), "d:4");
+
+        // Mid-election no node leads
+        cluster.learn(listing(Some(5), &[("b:2", "candidate"), ("d:4", "follower")])).unwrap();
+        assert!(cluster.leader().is_none());
+        cluster.failed(&cluster.nodes()[1]);
+        cluster.learn(listing(Some(5), &[("b:2", "leader")])).unwrap();
+        assert!(cluster.leader().is_none());
+    }
+}
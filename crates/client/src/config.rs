@@ -0,0 +1,523 @@
+//! Client settings and the connection strings they are written as.
+//!
+//! A connection string looks like
+//!
+//! ```text
+//! nextdb://db.example.com:8080/?timeout=5s&pool=8&token=secret
+//! ```
+//!
+//! The port defaults to 8080 and every option is optional. Several nodes of
+//! one cluster may be given, separated by commas, as in
+//! `nextdb://db1:8080,db2:8080/`: the client tries them in turn and learns
+//! of the rest from the cluster (see `cluster`). `timeout`,
+//! `connect_timeout`, `checkout_timeout`, `idle_timeout`, `keepalive` and
+//! `backoff` take a number with a unit of `ms`, `s`, `m` or `h`, `pool` a
+//! positive number of connections, `min_pool` a number no larger, `retries`
+//! a number of retries (see `retry`), `fetch_size` a positive number of
+//! rows, `consistency` one of `eventual`, `leader` or `linearizable` (see
+//! `ReadConsistency`), and `token` the API token to send, percent-encoded
+//! where it holds `&`, `=` or `%`. A bare `host:port`, or one with the
+//! `http://` scheme, is read as if it had the `nextdb://` scheme. The
+//! client speaks plain HTTP, so `https://` is refused for now.
+
+use crate::error::{ClientError, Result};
+use crate::retry::RetryPolicy;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// How up to date a read must be, in cluster mode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadConsistency {
+    /// Served by whichever node the client sends it to, which may lag the
+    /// leader
+    #[default]
+    Eventual,
+    /// Served by the leader, from what it has applied
+    LeaderOnly,
+    /// Served by the leader once a majority confirms it still leads, so it
+    /// sees every write committed before it was sent
+    Linearizable,
+}
+
+impl ReadConsistency {
+    /// The name a connection string gives the level by
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReadConsistency::Eventual => "eventual",
+            ReadConsistency::LeaderOnly => "leader",
+            ReadConsistency::Linearizable => "linearizable",
+        }
+    }
+
+    /// The server's `?consistency=` for the level
+    pub(crate) fn parameter(&self) -> &'static str {
+        match self {
+            ReadConsistency::Eventual => "local",
+            ReadConsistency::LeaderOnly => "leader",
+            ReadConsistency::Linearizable => "linearizable",
+        }
+    }
+}
+
+impl FromStr for ReadConsistency {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        [ReadConsistency::Eventual, ReadConsistency::LeaderOnly, ReadConsistency::Linearizable].into_iter()
+            .find(|level| level.name() == s)
+            .ok_or_else(|| format!("consistency must be eventual, leader or linearizable, not '{}'", s))
+    }
+}
+
+/// How a `DatabaseClient` reaches the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientConfig {
+    pub host: String,
+    pub port: u16,
+    /// Other nodes of the cluster, tried in turn when `host` cannot be
+    /// reached
+    pub fallback_hosts: Vec<(String, u16)>,
+    /// How long a request may take to send and read the whole response
+    /// before it fails with `ClientError::Timeout`, unless `RequestOptions`
+    /// says otherwise
+    pub timeout: Duration,
+    /// How long opening a connection may take before it fails with
+    /// `ClientError::Connection`
+    pub connect_timeout: Duration,
+    /// How long a connection idles before TCP keepalive probes check the
+    /// server is still there; None leaves keepalive off
+    pub keepalive: Option<Duration>,
+    /// Most connections held open to the server at once
+    pub pool_size: usize,
+    /// Connections opened on connecting and kept open however long they idle
+    pub min_pool_size: usize,
+    /// How long a request waits for a connection when all are in use,
+    /// before it fails with `ClientError::Timeout`
+    pub checkout_timeout: Duration,
+    /// How long a connection may idle before it is checked with a health
+    /// check before reuse, or closed if more than `min_pool_size` are idle
+    pub idle_timeout: Duration,
+    /// Which failed requests are sent again, and when
+    pub retry: RetryPolicy,
+    /// Rows `DatabaseClient::query_stream`, and entries
+    /// `DatabaseClient::kv_scan_prefix`, ask the server for at a time
+    pub fetch_size: usize,
+    /// How up to date reads are, unless `RequestOptions` says otherwise
+    pub consistency: ReadConsistency,
+    /// API token sent with every request, if the server requires one
+    pub token: Option<String>,
+}
+
+impl ClientConfig {
+    pub fn new(host: &str, port: u16) -> Self {
+        Self { host: host.to_string(), port, ..Self::default() }
+    }
+
+    /// `host:port`, with an IPv6 host in brackets
+    pub fn address(&self) -> String {
+        address(&self.host, self.port)
+    }
+
+    /// `address`, then those of the fallback hosts
+    pub fn addresses(&self) -> Vec<String> {
+        let fallbacks = self.fallback_hosts.iter().map(|(host, port)| address(host, *port));
+        std::iter::once(self.address()).chain(fallbacks).collect()
+    }
+}
+
+fn address(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: DEFAULT_PORT,
+            fallback_hosts: Vec::new(),
+            timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+            keepalive: None,
+            pool_size: 4,
+            min_pool_size: 0,
+            checkout_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(30),
+            retry: RetryPolicy::default(),
+            fetch_size: 1000,
+            consistency: ReadConsistency::default(),
+            token: None,
+        }
+    }
+}
+
+impl From<ConnectionString> for ClientConfig {
+    fn from(connection: ConnectionString) -> Self {
+        let defaults = ClientConfig::default();
+        Self {
+            host: connection.host,
+            port: connection.port.unwrap_or(defaults.port),
+            fallback_hosts: connection.fallback_hosts.into_iter()
+                .map(|(host, port)| (host, port.unwrap_or(DEFAULT_PORT)))
+                .collect(),
+            timeout: connection.timeout.unwrap_or(defaults.timeout),
+            connect_timeout: connection.connect_timeout.unwrap_or(defaults.connect_timeout),
+            keepalive: connection.keepalive.or(defaults.keepalive),
+            pool_size: connection.pool_size.unwrap_or(defaults.pool_size),
+            min_pool_size: connection.min_pool_size.unwrap_or(defaults.min_pool_size),
+            checkout_timeout: connection.checkout_timeout.unwrap_or(defaults.checkout_timeout),
+            idle_timeout: connection.idle_timeout.unwrap_or(defaults.idle_timeout),
+            retry: RetryPolicy {
+                max_retries: connection.max_retries.unwrap_or(defaults.retry.max_retries),
+                initial_backoff: connection.initial_backoff.unwrap_or(defaults.retry.initial_backoff),
+                ..defaults.retry
+            },
+            fetch_size: connection.fetch_size.unwrap_or(defaults.fetch_size),
+            consistency: connection.consistency.unwrap_or(defaults.consistency),
+            token: connection.token,
+        }
+    }
+}
+
+impl FromStr for ClientConfig {
+    type Err = ClientError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(s.parse::<ConnectionString>()?.into())
+    }
+}
+
+/// The parts of a connection string, with options it leaves out as None
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionString {
+    pub host: String,
+    pub port: Option<u16>,
+    pub fallback_hosts: Vec<(String, Option<u16>)>,
+    pub timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    pub keepalive: Option<Duration>,
+    pub pool_size: Option<usize>,
+    pub min_pool_size: Option<usize>,
+    pub checkout_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub max_retries: Option<u32>,
+    pub initial_backoff: Option<Duration>,
+    pub fetch_size: Option<usize>,
+    pub consistency: Option<ReadConsistency>,
+    pub token: Option<String>,
+}
+
+impl FromStr for ConnectionString {
+    type Err = ClientError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: String| ClientError::Connection(format!("invalid connection string: {}", reason));
+        let rest = match s.split_once("://") {
+            Some(("nextdb" | "http", rest)) => rest,
+            Some(("https", _)) => return Err(invalid("https is not supported yet".to_string())),
+            Some((scheme, _)) => return Err(invalid(format!("unsupported scheme '{}', expected nextdb or http", scheme))),
+            None => s,
+        };
+        let (rest, query) = match rest.split_once('?') {
+            Some((rest, query)) => (rest, Some(query)),
+            None => (rest, None),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, ""),
+        };
+        if !path.is_empty() && path != "/" {
+            return Err(invalid(format!("unexpected path '{}'", path)));
+        }
+        if authority.contains('@') {
+            return Err(invalid("credentials go in the token option, not before the host".to_string()));
+        }
+
+        let mut hosts = authority.split(',').map(split_host_port);
+        let (host, port) = hosts.next().expect("split yields at least one part").map_err(invalid)?;
+        let fallback_hosts = hosts.collect::<std::result::Result<_, _>>().map_err(invalid)?;
+        let mut connection = ConnectionString {
+            host,
+            port,
+            fallback_hosts,
+            timeout: None,
+            connect_timeout: None,
+            keepalive: None,
+            pool_size: None,
+            min_pool_size: None,
+            checkout_timeout: None,
+            idle_timeout: None,
+            max_retries: None,
+            initial_backoff: None,
+            fetch_size: None,
+            consistency: None,
+            token: None,
+        };
+        for pair in query.unwrap_or("").split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=')
+                .ok_or_else(|| invalid(format!("option '{}' has no value", pair)))?;
+            let value = percent_decode(value).map_err(invalid)?;
+            if value.is_empty() {
+                return Err(invalid(format!("option '{}' has no value", key)));
+            }
+            let duplicate = match key {
+                "timeout" => connection.timeout.replace(parse_duration(&value).map_err(invalid)?).is_some(),
+                "connect_timeout" => connection.connect_timeout.replace(parse_duration(&value).map_err(invalid)?).is_some(),
+                "keepalive" => connection.keepalive.replace(parse_duration(&value).map_err(invalid)?).is_some(),
+                "pool" => {
+                    let size = value.parse().ok().filter(|&size: &usize| size > 0)
+                        .ok_or_else(|| invalid(format!("pool must be a positive number, not '{}'", value)))?;
+                    connection.pool_size.replace(size).is_some()
+                }
+                "min_pool" => {
+                    let size = value.parse()
+                        .map_err(|_| invalid(format!("min_pool must be a number, not '{}'", value)))?;
+                    connection.min_pool_size.replace(size).is_some()
+                }
+                "checkout_timeout" => connection.checkout_timeout.replace(parse_duration(&value).map_err(invalid)?).is_some(),
+                "idle_timeout" => connection.idle_timeout.replace(parse_duration(&value).map_err(invalid)?).is_some(),
+                "retries" => {
+                    let retries = value.parse()
+                        .map_err(|_| invalid(format!("retries must be a number, not '{}'", value)))?;
+                    connection.max_retries.replace(retries).is_some()
+                }
+                "backoff" => connection.initial_backoff.replace(parse_duration(&value).map_err(invalid)?).is_some(),
+                "fetch_size" => {
+                    let size = value.parse().ok().filter(|&size: &usize| size > 0)
+                        .ok_or_else(|| invalid(format!("fetch_size must be a positive number, not '{}'", value)))?;
+                    connection.fetch_size.replace(size).is_some()
+                }
+                "consistency" => connection.consistency.replace(value.parse().map_err(invalid)?).is_some(),
+                "token" => connection.token.replace(value).is_some(),
+                _ => return Err(invalid(format!("unknown option '{}'", key))),
+            };
+            if duplicate {
+                return Err(invalid(format!("option '{}' is given twice", key)));
+            }
+        }
+        let pool_size = connection.pool_size.unwrap_or(ClientConfig::default().pool_size);
+        if connection.min_pool_size.is_some_and(|min| min > pool_size) {
+            return Err(invalid(format!("min_pool must not exceed pool, which is {}", pool_size)));
+        }
+        Ok(connection)
+    }
+}
+
+impl fmt::Display for ConnectionString {
+    /// The connection string, with the token left out
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "nextdb://{}", address(&self.host, self.port.unwrap_or(DEFAULT_PORT)))?;
+        for (host, port) in &self.fallback_hosts {
+            write!(f, ",{}", address(host, port.unwrap_or(DEFAULT_PORT)))?;
+        }
+        f.write_str("/")?;
+        let mut separator = '?';
+        if let Some(timeout) = self.timeout {
+            write!(f, "{}timeout={}ms", separator, timeout.as_millis())?;
+            separator = '&';
+        }
+        if let Some(timeout) = self.connect_timeout {
+            write!(f, "{}connect_timeout={}ms", separator, timeout.as_millis())?;
+            separator = '&';
+        }
+        if let Some(keepalive) = self.keepalive {
+            write!(f, "{}keepalive={}ms", separator, keepalive.as_millis())?;
+            separator = '&';
+        }
+        if let Some(pool_size) = self.pool_size {
+            write!(f, "{}pool={}", separator, pool_size)?;
+            separator = '&';
+        }
+        if let Some(min_pool_size) = self.min_pool_size {
+            write!(f, "{}min_pool={}", separator, min_pool_size)?;
+            separator = '&';
+        }
+        if let Some(timeout) = self.checkout_timeout {
+            write!(f, "{}checkout_timeout={}ms", separator, timeout.as_millis())?;
+            separator = '&';
+        }
+        if let Some(timeout) = self.idle_timeout {
+            write!(f, "{}idle_timeout={}ms", separator, timeout.as_millis())?;
+            separator = '&';
+        }
+        if let Some(retries) = self.max_retries {
+            write!(f, "{}retries={}", separator, retries)?;
+            separator = '&';
+        }
+        if let Some(backoff) = self.initial_backoff {
+            write!(f, "{}backoff={}ms", separator, backoff.as_millis())?;
+            separator = '&';
+        }
+        if let Some(fetch_size) = self.fetch_size {
+            write!(f, "{}fetch_size={}", separator, fetch_size)?;
+            separator = '&';
+        }
+        if let Some(consistency) = self.consistency {
+            write!(f, "{}consistency={}", separator, consistency.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// `host`, `host:port`, `[v6]` or `[v6]:port`
+fn split_host_port(authority: &str) -> std::result::Result<(String, Option<u16>), String> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, after) = bracketed.split_once(']').ok_or("unclosed '[' in the host")?;
+            match after {
+                "" => (host, None),
+                after => (host, Some(after.strip_prefix(':').ok_or("expected ':' after the IPv6 host")?)),
+            }
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    if host.is_empty() {
+        return Err("missing host".to_string());
+    }
+    if host.chars().any(|c| c.is_whitespace() || matches!(c, '/' | '?' | '#')) {
+        return Err(format!("invalid host '{}'", host));
+    }
+    let port = port
+        .map(|port| port.parse::<u16>().ok().filter(|&port| port > 0).ok_or(format!("invalid port '{}'", port)))
+        .transpose()?;
+    Ok((host.to_string(), port))
+}
+
+/// `5s`, `250ms`, `2m` or `1h`
+fn parse_duration(value: &str) -> std::result::Result<Duration, String> {
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits);
+    let number: u64 = number.parse().map_err(|_| format!("timeout must be a number with a unit, not '{}'", value))?;
+    let millis = match unit {
+        "ms" => Some(number),
+        "s" => number.checked_mul(1000),
+        "m" => number.checked_mul(60_000),
+        "h" => number.checked_mul(3_600_000),
+        _ => return Err(format!("timeout '{}' needs a unit of ms, s, m or h", value)),
+    };
+    match millis {
+        Some(millis) if millis > 0 => Ok(Duration::from_millis(millis)),
+        _ => Err(format!("timeout '{}' is out of range", value)),
+    }
+}
+
+/// Decode `%XX` escapes
+fn percent_decode(value: &str) -> std::result::Result<String, String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = value.get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("invalid escape in '{}'", value))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| format!("'{}' does not decode to UTF-8", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_connection_strings() {
+        let config: ClientConfig = "nextdb://db.example.com:9000/?timeout=5s&connect_timeout=2s&keepalive=1m&pool=8&min_pool=2&checkout_timeout=1s&idle_timeout=2m&retries=5&backoff=50ms&fetch_size=200&consistency=linearizable&token=s3cr%26t"
+            .parse().unwrap();
+        assert_eq!(config, ClientConfig {
+            host: "db.example.com".to_string(),
+            port: 9000,
+            fallback_hosts: Vec::new(),
+            timeout: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(2),
+            keepalive: Some(Duration::from_secs(60)),
+            pool_size: 8,
+            min_pool_size: 2,
+            checkout_timeout: Duration::from_secs(1),
+            idle_timeout: Duration::from_secs(120),
+            retry: RetryPolicy { max_retries: 5, initial_backoff: Duration::from_millis(50), ..RetryPolicy::default() },
+            fetch_size: 200,
+            consistency: ReadConsistency::Linearizable,
+            token: Some("s3cr&t".to_string()),
+        });
+
+        // Whatever is left out takes its default
+        for s in ["nextdb://localhost", "nextdb://localhost/", "localhost", "nextdb://localhost:8080/?", "http://localhost:8080"] {
+            assert_eq!(s.parse::<ClientConfig>().unwrap(), ClientConfig::default(), "{}", s);
+        }
+        let config: ClientConfig = "127.0.0.1:5432".parse().unwrap();
+        assert_eq!((config.host.as_str(), config.port), ("127.0.0.1", 5432));
+        let config: ClientConfig = "nextdb://[::1]:7000?timeout=250ms".parse().unwrap();
+        assert_eq!((config.address(), config.timeout), ("[::1]:7000".to_string(), Duration::from_millis(250)));
+
+        // Other nodes of a cluster follow the first
+        let config: ClientConfig = "nextdb://db1:9000,db2,[::1]:9002/?pool=2".parse().unwrap();
+        assert_eq!(config.addresses(), ["db1:9000", "db2:8080", "[::1]:9002"]);
+        assert_eq!(config.pool_size, 2);
+        let connection: ConnectionString = "db1,db2:9001".parse().unwrap();
+        assert_eq!(connection.to_string(), "nextdb://db1:8080,db2:9001/");
+
+        // The token is not shown
+        let connection: ConnectionString = "nextdb://h?pool=2&token=abc&timeout=1m&keepalive=10s".parse().unwrap();
+        assert_eq!(connection.to_string(), "nextdb://h:8080/?timeout=60000ms&keepalive=10000ms&pool=2");
+        let connection: ConnectionString = "nextdb://h?min_pool=1&idle_timeout=5s&retries=0&fetch_size=50&consistency=leader".parse().unwrap();
+        assert_eq!(connection.to_string(), "nextdb://h:8080/?min_pool=1&idle_timeout=5000ms&retries=0&fetch_size=50&consistency=leader");
+        assert_eq!(connection.to_string().parse::<ConnectionString>().unwrap().timeout, connection.timeout);
+    }
+
+    #[test]
+    fn test_reject_malformed_connection_strings() {
+        for (s, reason) in [
+            ("", "missing host"),
+            ("nextdb://", "missing host"),
+            ("nextdb://:8080", "missing host"),
+            ("ftp://localhost", "unsupported scheme 'ftp'"),
+            ("https://localhost", "https is not supported yet"),
+            ("nextdb://localhost:0", "invalid port '0'"),
+            ("nextdb://localhost:99999", "invalid port '99999'"),
+            ("nextdb://localhost:http", "invalid port 'http'"),
+            ("nextdb://db1,,db2", "missing host"),
+            ("nextdb://db1,db2:0", "invalid port '0'"),
+            ("nextdb://[::1", "unclosed '['"),
+            ("nextdb://[::1]8080", "expected ':'"),
+            ("nextdb://user:pw@localhost", "credentials go in the token option"),
+            ("nextdb://localhost/db", "unexpected path '/db'"),
+            ("nextdb://localhost?timeout=5", "needs a unit"),
+            ("nextdb://localhost?timeout=soon", "number with a unit"),
+            ("nextdb://localhost?timeout=0s", "out of range"),
+            ("nextdb://localhost?pool=0", "pool must be a positive number"),
+            ("nextdb://localhost?pool=8&pool=9", "option 'pool' is given twice"),
+            ("nextdb://localhost?min_pool=few", "min_pool must be a number"),
+            ("nextdb://localhost?pool=2&min_pool=3", "min_pool must not exceed pool"),
+            ("nextdb://localhost?checkout_timeout=0ms", "out of range"),
+            ("nextdb://localhost?connect_timeout=0s", "out of range"),
+            ("nextdb://localhost?keepalive=often", "number with a unit"),
+            ("nextdb://localhost?token=", "option 'token' has no value"),
+            ("nextdb://localhost?token", "option 'token' has no value"),
+            ("nextdb://localhost?token=%zz", "invalid escape"),
+            ("nextdb://localhost?retries=-1", "retries must be a number"),
+            ("nextdb://localhost?fetch_size=0", "fetch_size must be a positive number"),
+            ("nextdb://localhost?consistency=strong", "consistency must be eventual, leader or linearizable"),
+            ("nextdb://localhost?compress=lz4", "unknown option 'compress'"),
+        ] {
+            match s.parse::<ConnectionString>() {
+                Err(ClientError::Connection(message)) => assert!(message.contains(reason), "{}: {}", s, message),
+                other => panic!("{}: expected a connection error, got {:?}", s, other),
+            }
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -5,17 +6,95 @@ pub enum ClientError {
     #[error("Connection error: {0}")]
     Connection(String),
     
+    /// The server could not parse, plan or run the query as written
     #[error("Query error: {0}")]
     Query(String),
     
+    /// A table, column or database the query names, or a key, does not exist
+    #[error("Not found: {0}")]
+    NotFound(String),
+    
+    #[error("Already exists: {0}")]
+    AlreadyExists(String),
+    
+    #[error("Constraint violation: {0}")]
+    ConstraintViolation(String),
+    
+    #[error("Transaction error: {0}")]
+    Transaction(String),
+    
+    /// The transaction conflicted with another and was rolled back; it may
+    /// succeed if run again
+    #[error("Transaction conflict: {0}")]
+    Conflict(String),
+    
+    /// The transaction idled past the server's timeout and was rolled back
+    #[error("Transaction expired: {0}")]
+    TransactionExpired(String),
+    
     #[error("Timeout error")]
     Timeout,
     
     #[error("Authentication failed")]
     Authentication,
     
+    /// The API token may not run the query, or not in that database
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    
+    /// The server does not lead its cluster and did not pass the write on
+    #[error("Not the leader{}", leader.as_ref().map(|leader| format!("; the leader is at {}", leader)).unwrap_or_default())]
+    NotLeader { leader: Option<String> },
+    
+    /// The server is overloaded, rate limits the client or is shutting
+    /// down; the request may succeed later
+    #[error("Server unavailable: {message}")]
+    Unavailable { message: String, retry_after: Option<Duration> },
+    
+    /// Any other failure the server reports, with its error code
+    #[error("Server error ({code}): {message}")]
+    Server { code: String, message: String },
+    
     #[error("Network error: {0}")]
     Network(String),
+    
+    /// A result row does not fit the type it was read into
+    #[error("Cannot read result: {0}")]
+    Decode(String),
+    
+    /// The query returned more or fewer rows than asked for
+    #[error("Expected {expected}, the query returned {found} rows")]
+    UnexpectedRows { expected: &'static str, found: usize },
+    
+    /// A statement that writes failed after it was sent, so it may or may
+    /// not have run, and was not sent again (see `retry`)
+    #[error("The statement may or may not have run: {0}")]
+    Ambiguous(Box<ClientError>),
+}
+
+impl ClientError {
+    /// The error for a failure the server reported with `code`, one of the
+    /// stable error codes of its HTTP API
+    pub(crate) fn from_server(code: &str, message: String, retry_after: Option<Duration>, leader: Option<String>) -> Self {
+        match code {
+            "parse_error" | "plan_error" | "invalid_query" | "execution_error" | "invalid_request"
+                | "invalid_parameters" | "batch_too_large" | "invalid_key" | "key_too_large" => ClientError::Query(message),
+            "table_not_found" | "column_not_found" | "database_not_found" | "statement_not_found"
+                | "key_not_found" => ClientError::NotFound(message),
+            "table_exists" | "database_exists" => ClientError::AlreadyExists(message),
+            "constraint_violation" => ClientError::ConstraintViolation(message),
+            "transaction_error" | "lock_timeout" | "transaction_not_found" | "transaction_committed"
+                | "transaction_rolled_back" => ClientError::Transaction(message),
+            "transaction_conflict" => ClientError::Conflict(message),
+            "transaction_expired" => ClientError::TransactionExpired(message),
+            "unauthorized" => ClientError::Authentication,
+            "read_only_token" | "admin_required" | "database_forbidden" => ClientError::PermissionDenied(message),
+            "not_leader" => ClientError::NotLeader { leader },
+            "rate_limited" | "too_many_queries" | "write_rate_limited" | "queue_full" | "queue_timeout"
+                | "shutting_down" | "replication_error" | "leader_unreachable" => ClientError::Unavailable { message, retry_after },
+            _ => ClientError::Server { code: code.to_string(), message },
+        }
+    }
 }
 
-pub type Result<T> = std::result::Result<T, ClientError>;
\ No newline at end of file
+pub type Result<T> = std::result::Result<T, ClientError>;
@@ -0,0 +1,456 @@
+//! Rendering query results as aligned text tables, CSV or JSON.
+//!
+//! In tables, //! cells that would break the table layout or read ambiguously are quoted
+//! with escapes: text containing the `|` delimiter, control characters such
+//! as newlines, leading or trailing spaces, a leading quote, or text equal to
+//! the NULL marker. Column widths count terminal cells, so wide characters
+//! such as CJK take two and combining marks none.
+//!
+//! CSV quotes fields as in RFC 4180 and leaves NULL fields empty, so that
+//! they differ from the quoted empty string. JSON is an array with one object
+//! per row, holding each value as the server sends it.
+
+use crate::value::{ColumnMeta, QueryResult, Value};
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Layout of a rendered result
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Csv,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "table" => Ok(OutputFormat::Table),
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown output format '{}', expected table, csv or json", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Table => "table",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Json => "json",
+        })
+    }
+}
+
+/// How BLOB cells are rendered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlobFormat {
+    /// `\x` followed by two hex digits per byte
+    #[default]
+    Hex,
+    /// A quoted byte string: valid UTF-8 is shown as text and every other
+    /// byte as a `\xNN` escape
+    Escape,
+}
+
+/// Options for `format_result`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatOptions {
+    pub output: OutputFormat,
+    /// Marker shown for NULL cells in tables
+    pub null: String,
+    /// How BLOBs are shown in tables and CSV
+    pub blob: BlobFormat,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self { output: OutputFormat::Table, null: "NULL".to_string(), blob: BlobFormat::Hex }
+    }
+}
+
+/// Render `result` in the layout `options` selects
+pub fn format_result(result: &QueryResult, options: &FormatOptions) -> String {
+    let mut printer = RowPrinter::new(&result.columns, options);
+    let mut output = printer.print(&result.rows);
+    output.push_str(&printer.finish());
+    output
+}
+
+/// Render `result` as a table with one line per row, followed by the row count
+pub fn format_table(result: &QueryResult, options: &FormatOptions) -> String {
+    format_result(result, &FormatOptions { output: OutputFormat::Table, ..options.clone() })
+}
+
+/// Render `result` as CSV with a header line
+pub fn format_csv(result: &QueryResult, options: &FormatOptions) -> String {
+    format_result(result, &FormatOptions { output: OutputFormat::Csv, ..options.clone() })
+}
+
+/// Render `result` as a JSON array of objects keyed by column name, with
+/// keys in column order
+pub fn format_json(result: &QueryResult) -> String {
+    format_result(result, &FormatOptions { output: OutputFormat::Json, ..FormatOptions::default() })
+}
+
+/// Renders a result a batch of rows at a time, as they are read from
+/// `DatabaseClient::query_stream`. A table takes its column widths and
+/// alignment from the first batch, and widens a column for a later cell
+/// that needs more room.
+pub struct RowPrinter {
+    options: FormatOptions,
+    columns: Vec<ColumnMeta>,
+    started: bool,
+    widths: Vec<usize>,
+    /// Which table columns are right-aligned
+    numeric: Vec<bool>,
+    rows: usize,
+}
+
+impl RowPrinter {
+    pub fn new(columns: &[ColumnMeta], options: &FormatOptions) -> Self {
+        Self {
+            options: options.clone(),
+            columns: columns.to_vec(),
+            started: false,
+            widths: Vec::new(),
+            numeric: Vec::new(),
+            rows: 0,
+        }
+    }
+
+    /// Render `rows`, after the header if they are the first
+    pub fn print(&mut self, rows: &[Vec<Value>]) -> String {
+        let mut output = String::new();
+        match self.options.output {
+            OutputFormat::Table => self.table_rows(&mut output, rows),
+            OutputFormat::Csv => self.csv_rows(&mut output, rows),
+            OutputFormat::Json => self.json_rows(&mut output, rows),
+        }
+        self.started = true;
+        self.rows += rows.len();
+        output
+    }
+
+    /// Render what follows the last row
+    pub fn finish(mut self) -> String {
+        let mut output = self.print(&[]);
+        match self.options.output {
+            OutputFormat::Table => output.push_str(&format!("\n({} rows)\n", self.rows)),
+            OutputFormat::Csv => {}
+            OutputFormat::Json if self.rows == 0 => output.push_str("[]\n"),
+            OutputFormat::Json => output.push_str("]\n"),
+        }
+        output
+    }
+
+    fn table_rows(&mut self, output: &mut String, rows: &[Vec<Value>]) {
+        let options = &self.options;
+        let cells: Vec<Vec<String>> = rows.iter()
+            .map(|row| row.iter().map(|value| format_cell(value, options)).collect())
+            .collect();
+        if !self.started {
+            let header: Vec<String> = self.columns.iter().map(|column| quote_if_needed(&column.name, options)).collect();
+            self.widths = header.iter().map(|name| display_width(name)).collect();
+            // Numeric columns are right-aligned, like in psql
+            self.numeric = (0..self.widths.len())
+                .map(|i| {
+                    let mut values = rows.iter().map(|row| &row[i]).filter(|value| !matches!(value, Value::Null));
+                    let first = values.next();
+                    first.is_some_and(Value::is_numeric) && values.all(Value::is_numeric)
+                })
+                .collect();
+            widen(&mut self.widths, &cells);
+            write_line(output, &header, &self.widths, &vec![false; self.widths.len()]);
+            let separator: Vec<String> = self.widths.iter().map(|width| "-".repeat(width + 2)).collect();
+            output.push_str(&format!("|{}|\n", separator.join("|")));
+        }
+        widen(&mut self.widths, &cells);
+        for row in &cells {
+            write_line(output, row, &self.widths, &self.numeric);
+        }
+    }
+
+    fn csv_rows(&self, output: &mut String, rows: &[Vec<Value>]) {
+        if !self.started {
+            let header: Vec<String> = self.columns.iter().map(|column| csv_field(&column.name)).collect();
+            output.push_str(&header.join(","));
+            output.push('\n');
+        }
+        for row in rows {
+            let fields: Vec<String> = row.iter()
+                .map(|value| match value {
+                    Value::Null => String::new(),
+                    Value::Blob(bytes) if self.options.blob == BlobFormat::Escape => csv_field(&escape_bytes(bytes)),
+                    value => csv_field(&value.to_string()),
+                })
+                .collect();
+            output.push_str(&fields.join(","));
+            output.push('\n');
+        }
+    }
+
+    fn json_rows(&self, output: &mut String, rows: &[Vec<Value>]) {
+        let keys: Vec<String> = self.columns.iter()
+            .map(|column| serde_json::Value::String(column.name.clone()).to_string())
+            .collect();
+        for (i, row) in rows.iter().enumerate() {
+            output.push_str(if self.rows + i == 0 { "[" } else { ",\n " });
+            let fields: Vec<String> = keys.iter().zip(row)
+                .map(|(key, value)| format!("{}:{}", key, value.to_json()))
+                .collect();
+            write!(output, "{{{}}}", fields.join(",")).unwrap();
+        }
+    }
+}
+
+fn widen(widths: &mut [usize], rows: &[Vec<String>]) {
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(display_width(cell));
+        }
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    if text.is_empty() || text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn write_line(output: &mut String, cells: &[String], widths: &[usize], right_aligned: &[bool]) {
+    output.push('|');
+    for ((cell, width), right) in cells.iter().zip(widths).zip(right_aligned) {
+        let padding = " ".repeat(width - display_width(cell));
+        if *right {
+            write!(output, " {}{} |", padding, cell).unwrap();
+        } else {
+            write!(output, " {}{} |", cell, padding).unwrap();
+        }
+    }
+    output.push('\n');
+}
+
+fn format_cell(value: &Value, options: &FormatOptions) -> String {
+    match value {
+        Value::Null => options.null.clone(),
+        Value::Text(text) => quote_if_needed(text, options),
+        Value::Blob(bytes) if options.blob == BlobFormat::Escape => escape_bytes(bytes),
+        value => value.to_string(),
+    }
+}
+
+fn quote_if_needed(text: &str, options: &FormatOptions) -> String {
+    let needs_quotes = text == options.null
+        || text.starts_with(['"', ' '])
+        || text.ends_with(' ')
+        || text.chars().any(|c| c == '|' || c.is_control());
+    if needs_quotes {
+        format!("{:?}", text)
+    } else {
+        text.to_string()
+    }
+}
+
+fn escape_bytes(bytes: &[u8]) -> String {
+    let mut escaped = String::from("b\"");
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' | '\\' => write!(escaped, "\\{}", c).unwrap(),
+                c if c.is_control() => escaped.extend(c.escape_debug()),
+                c => escaped.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            write!(escaped, "\\x{:02x}", byte).unwrap();
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Terminal cells taken by `text`
+fn display_width(text: &str) -> usize {
+    text.chars().map(char_width).sum()
+}
+
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        // Combining marks, zero-width spaces and joiners, variation selectors
+        0x0300..=0x036F | 0x1AB0..=0x1AFF | 0x1DC0..=0x1DFF | 0x200B..=0x200F
+        | 0x20D0..=0x20FF | 0xFE00..=0xFE0F | 0xFE20..=0xFE2F => 0,
+        // Hangul Jamo, CJK, Hangul syllables, fullwidth forms and emoji
+        0x1100..=0x115F | 0x2E80..=0x303E | 0x3041..=0x33FF | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF | 0xA000..=0xA4CF | 0xAC00..=0xD7A3 | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F | 0xFF00..=0xFF60 | 0xFFE0..=0xFFE6 | 0x1F300..=0x1F64F
+        | 0x1F900..=0x1F9FF | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::ColumnMeta;
+
+    fn result(columns: &[&str], rows: Vec<Vec<Value>>) -> QueryResult {
+        QueryResult {
+            columns: columns.iter()
+                .map(|name| ColumnMeta { name: name.to_string(), data_type: None })
+                .collect(),
+            rows,
+            rows_affected: None,
+        }
+    }
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+
+    #[test]
+    fn test_delimiters_and_control_characters_quoted() {
+        let result = result(&["id", "note"], vec![
+            vec![Value::Integer(1), text("a|b")],
+            vec![Value::Integer(22), text("line one\nline two")],
+            vec![Value::Integer(333), text("tab\there \"quoted\"")],
+            vec![Value::Null, text("NULL")],
+            vec![Value::Integer(-4), text(" padded ")],
+            vec![Value::Integer(5), text("plain \"inner\" quotes")],
+        ]);
+
+        assert_eq!(format_table(&result, &FormatOptions::default()), [
+            r#"| id   | note                   |"#,
+            r#"|------|------------------------|"#,
+            r#"|    1 | "a|b"                  |"#,
+            r#"|   22 | "line one\nline two"   |"#,
+            r#"|  333 | "tab\there \"quoted\"" |"#,
+            r#"| NULL | "NULL"                 |"#,
+            r#"|   -4 | " padded "             |"#,
+            r#"|    5 | plain "inner" quotes   |"#,
+            "",
+            "(6 rows)",
+            "",
+        ].join("\n"));
+
+        // Every line keeps to a single line of the same width
+        let table = format_table(&result, &FormatOptions::default());
+        assert_eq!(table.lines().count(), 10);
+        assert!(table.lines().take(8).all(|line| line.len() == 33));
+    }
+
+    #[test]
+    fn test_values_wider_than_names() {
+        let result = result(&["n", "s"], vec![
+            vec![Value::Integer(1234567), text("a much longer value")],
+            vec![Value::Null, Value::Null],
+            vec![Value::Float(-2.5), text("x")],
+        ]);
+
+        // Each column is as wide as its widest cell, NULL included
+        assert_eq!(format_table(&result, &FormatOptions::default()), [
+            "| n       | s                   |",
+            "|---------|---------------------|",
+            "| 1234567 | a much longer value |",
+            "|    NULL | NULL                |",
+            "|    -2.5 | x                   |",
+            "",
+            "(3 rows)",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_wide_unicode_aligned() {
+        let result = result(&["名前", "x"], vec![
+            vec![text("東京"), text("e\u{301}")],
+            vec![text("ab"), text("🦀🦀")],
+            vec![text("Zoë"), Value::Float(f64::NAN)],
+        ]);
+
+        assert_eq!(format_table(&result, &FormatOptions::default()), [
+            "| 名前 | x    |",
+            "|------|------|",
+            "| 東京 | e\u{301}    |",
+            "| ab   | 🦀🦀 |",
+            "| Zoë  | NaN  |",
+            "",
+            "(3 rows)",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_blob_and_null_options() {
+        let result = result(&["b"], vec![
+            vec![Value::Blob(b"caf\xc3\xa9 \xff\x00\"".to_vec())],
+            vec![Value::Null],
+        ]);
+
+        let hex = format_table(&result, &FormatOptions::default());
+        assert!(hex.contains("| \\x636166c3a920ff0022 |"));
+
+        let options = FormatOptions { null: "∅".to_string(), blob: BlobFormat::Escape, ..Default::default() };
+        assert_eq!(format_table(&result, &options), [
+            r#"| b                |"#,
+            r#"|------------------|"#,
+            r#"| b"café \xff\0\"" |"#,
+            r#"| ∅                |"#,
+            "",
+            "(2 rows)",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_csv_quoting() {
+        let result = result(&["id", "note, text"], vec![
+            vec![Value::Integer(1), text("plain")],
+            vec![Value::Integer(2), text("a, b")],
+            vec![Value::Integer(3), text("say \"hi\"")],
+            vec![Value::Integer(4), text("two\nlines")],
+            vec![Value::Null, text("")],
+            vec![Value::Float(0.5), Value::Null],
+        ]);
+        let options = FormatOptions { output: OutputFormat::Csv, ..Default::default() };
+
+        assert_eq!(format_result(&result, &options), [
+            r#"id,"note, text""#,
+            r#"1,plain"#,
+            r#"2,"a, b""#,
+            r#"3,"say ""hi""""#,
+            "4,\"two\nlines\"",
+            r#","""#,
+            r#"0.5,"#,
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_json_output() {
+        let result = result(&["z", "a", "note"], vec![
+            vec![Value::Integer(1), Value::Float(f64::NAN), text("quote \" and \\")],
+            vec![Value::Null, Value::Blob(vec![0, 255]), text("ü\n")],
+        ]);
+        let options = FormatOptions { output: OutputFormat::Json, ..Default::default() };
+        let output = format_result(&result, &options);
+
+        // Keys keep the column order
+        assert!(output.starts_with(r#"[{"z":1,"a":{"$float":"NaN"},"note":"#), "{}", output);
+        let parsed: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(parsed, serde_json::json!([
+            { "z": 1, "a": { "$float": "NaN" }, "note": "quote \" and \\" },
+            { "z": null, "a": { "$base64": "AP8=" }, "note": "ü\n" },
+        ]));
+
+        assert_eq!(format_json(&self::result(&["x"], vec![])), "[]\n");
+        assert_eq!("JSON".parse::<OutputFormat>(), Ok(OutputFormat::Json));
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+}
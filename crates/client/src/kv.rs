@@ -0,0 +1,138 @@
+//! Raw keys and values, read and written without SQL.
+//!
+//! `DatabaseClient::kv_get`, `kv_put`, `kv_delete`, `kv_scan_prefix` and
+//! `kv_multi_get` use the server's `/api/kv` endpoints. Keys and values are
+//! any bytes, base64-encoded on the wire. Keys whose first byte is 0x01
+//! hold SQL data, and fail with `ClientError::Query`. The requests are
+//! retried, time out and carry the token as queries do; puts and deletes go
+//! to the leader, and are sent again after failures that leave it unknown
+//! whether they ran, as running them twice does no harm.
+//!
+//! A missing key is not an error: `kv_get` returns `None` for it. A prefix
+//! scan asks the server for `ClientConfig::fetch_size` entries at a time.
+//! Reads are as up to date as `ClientConfig::consistency` asks, or for
+//! `kv_get_with` its `RequestOptions`.
+
+use crate::client::{self, DatabaseClient};
+use crate::cluster::Route;
+use crate::error::{ClientError, Result};
+use crate::retry::RequestOptions;
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL};
+use base64::Engine;
+use hyper::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+/// Body of an `/api/kv/{key}` response
+#[derive(Deserialize)]
+struct ValueReply {
+    value: String,
+}
+
+/// Body of an `/api/kv` response
+#[derive(Deserialize)]
+struct ScanReply {
+    entries: Vec<EntryReply>,
+    /// Where the next page starts, if there are more entries
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EntryReply {
+    key: String,
+    value: String,
+}
+
+/// Body of an `/api/kv/multi-get` response
+#[derive(Deserialize)]
+struct MultiGetReply {
+    values: Vec<Option<String>>,
+}
+
+impl DatabaseClient {
+    /// The value of `key`, or `None` if it is not set
+    pub async fn kv_get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.kv_get_with(key, &RequestOptions::default()).await
+    }
+
+    /// `kv_get` with settings for this read alone, such as its consistency
+    pub async fn kv_get_with(&self, key: &[u8], options: &RequestOptions) -> Result<Option<Vec<u8>>> {
+        let (route, path) = self.read_target(&key_path(key), options);
+        match self.kv_send(route, Method::GET, &path, None, options).await {
+            Ok(ValueReply { value }) => decode(&value).map(Some),
+            Err(ClientError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Set `key` to `value`
+    pub async fn kv_put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let body = serde_json::json!({ "value": BASE64.encode(value) });
+        self.kv_send::<serde_json::Value>(Route::Leader, Method::PUT, &key_path(key), Some(body), &RequestOptions::default()).await?;
+        Ok(())
+    }
+
+    /// Remove `key`, whether or not it is set
+    pub async fn kv_delete(&self, key: &[u8]) -> Result<()> {
+        self.kv_send::<serde_json::Value>(Route::Leader, Method::DELETE, &key_path(key), None, &RequestOptions::default()).await?;
+        Ok(())
+    }
+
+    /// The first `limit` entries whose keys start with `prefix`, in key
+    /// order, asked for a page at a time
+    pub async fn kv_scan_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        let mut after = None;
+        while entries.len() < limit {
+            let page = (limit - entries.len()).min(self.config().fetch_size);
+            let mut path = format!("/api/kv?prefix={}&limit={}", BASE64_URL.encode(prefix), page);
+            if let Some(after) = &after {
+                path.push_str(&format!("&after={}", after));
+            }
+            let (route, path) = self.read_target(&path, &RequestOptions::default());
+            let reply: ScanReply = self.kv_send(route, Method::GET, &path, None, &RequestOptions::default()).await?;
+            for entry in reply.entries {
+                entries.push((decode(&entry.key)?, decode(&entry.value)?));
+            }
+            match reply.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        entries.truncate(limit);
+        Ok(entries)
+    }
+
+    /// The values of `keys`, in the same order, `None` for those not set
+    pub async fn kv_multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let keys: Vec<String> = keys.iter().map(|key| BASE64.encode(key)).collect();
+        let body = serde_json::json!({ "keys": keys });
+        let (route, path) = self.read_target("/api/kv/multi-get", &RequestOptions::default());
+        let reply: MultiGetReply = self.kv_send(route, Method::POST, &path, Some(body), &RequestOptions::default()).await?;
+        reply.values.iter().map(|value| value.as_deref().map(decode).transpose()).collect()
+    }
+
+    async fn kv_send<T: DeserializeOwned>(
+        &self,
+        route: Route,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+        options: &RequestOptions,
+    ) -> Result<T> {
+        let body = body.map(|body| body.to_string());
+        let (_, status, headers, body) = self.send_retrying(route, method, path, body, true, options).await?;
+        if status != StatusCode::OK {
+            return Err(client::failure(status, &headers, &body));
+        }
+        serde_json::from_slice(&body).map_err(|e| ClientError::Network(format!("cannot read the server's response: {}", e)))
+    }
+}
+
+fn key_path(key: &[u8]) -> String {
+    format!("/api/kv/{}", BASE64_URL.encode(key))
+}
+
+fn decode(encoded: &str) -> Result<Vec<u8>> {
+    BASE64.decode(encoded).map_err(|e| ClientError::Network(format!("the server sent invalid base64: {}", e)))
+}
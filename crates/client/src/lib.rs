@@ -1,5 +1,25 @@
 pub mod client;
+mod cluster;
+pub mod config;
 pub mod error;
+pub mod format;
+pub mod kv;
+#[cfg(test)]
+mod mock;
+pub mod pool;
+pub mod retry;
+pub mod row;
+pub mod stream;
+pub mod transaction;
+pub mod value;
 
 pub use client::DatabaseClient;
-pub use error::{ClientError, Result};
\ No newline at end of file
+pub use config::{ClientConfig, ConnectionString, ReadConsistency};
+pub use error::{ClientError, Result};
+pub use format::{BlobFormat, FormatOptions, OutputFormat};
+pub use pool::PoolStats;
+pub use retry::{RequestOptions, RetryPolicy};
+pub use row::Row;
+pub use stream::RowStream;
+pub use transaction::{IsolationLevel, Transaction};
+pub use value::{ColumnMeta, QueryResult, Value};
\ No newline at end of file
@@ -0,0 +1,131 @@
+//! A scripted HTTP/1 server for the client's tests.
+
+use crate::config::ClientConfig;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// How the server answers one request
+#[derive(Debug, Clone)]
+pub(crate) enum Reply {
+    /// This status, headers and JSON body
+    Json(u16, Vec<(&'static str, &'static str)>, serde_json::Value),
+    /// None: the connection is closed once the request is read
+    Hang,
+    /// This reply, once this long has passed
+    Late(Duration, Box<Reply>),
+}
+
+impl Reply {
+    pub(crate) fn ok(body: serde_json::Value) -> Self {
+        Reply::Json(200, Vec::new(), body)
+    }
+}
+
+/// A request the server read
+#[derive(Debug, Clone)]
+pub(crate) struct Received {
+    pub(crate) path: String,
+    pub(crate) at: Instant,
+}
+
+pub(crate) struct MockServer {
+    address: String,
+    received: Arc<Mutex<Vec<Received>>>,
+}
+
+impl MockServer {
+    /// Answer requests with the replies of `script` in turn, then with
+    /// `otherwise`, closing each connection after `close_after` requests.
+    /// Requests for the cluster's nodes are answered as a standalone server
+    /// would, and not logged.
+    pub(crate) async fn start(script: Vec<Reply>, otherwise: Reply, close_after: usize) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let script = Arc::new(Mutex::new(VecDeque::from(script)));
+        let log = received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (log, script, otherwise) = (log.clone(), script.clone(), otherwise.clone());
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    for served in 1.. {
+                        let Some(path) = read_request(&mut stream).await else {
+                            return;
+                        };
+                        let reply = if path == "/api/cluster/nodes" {
+                            Reply::ok(serde_json::json!({ "term": null, "leader": null, "nodes": [] }))
+                        } else {
+                            log.lock().unwrap().push(Received { path, at: Instant::now() });
+                            script.lock().unwrap().pop_front().unwrap_or_else(|| otherwise.clone())
+                        };
+                        let mut reply = reply;
+                        while let Reply::Late(delay, later) = reply {
+                            tokio::time::sleep(delay).await;
+                            reply = *later;
+                        }
+                        let Reply::Json(status, headers, body) = reply else {
+                            return;
+                        };
+                        let close = served == close_after;
+                        let mut response = format!("HTTP/1.1 {} Mock\r\ncontent-type: application/json\r\n", status);
+                        for (name, value) in headers {
+                            response.push_str(&format!("{}: {}\r\n", name, value));
+                        }
+                        if close {
+                            response.push_str("connection: close\r\n");
+                        }
+                        let body = body.to_string();
+                        response.push_str(&format!("content-length: {}\r\n\r\n{}", body.len(), body));
+                        if stream.write_all(response.as_bytes()).await.is_err() || close {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        Self { address, received }
+    }
+
+    /// A client config for the server
+    pub(crate) fn config(&self) -> ClientConfig {
+        let (host, port) = self.address.split_once(':').unwrap();
+        ClientConfig::new(host, port.parse().unwrap())
+    }
+
+    pub(crate) fn received(&self) -> Vec<Received> {
+        self.received.lock().unwrap().clone()
+    }
+
+    pub(crate) fn paths(&self) -> Vec<String> {
+        self.received().into_iter().map(|request| request.path).collect()
+    }
+}
+
+/// The path of the next request on the connection, once it is read whole
+async fn read_request(stream: &mut BufReader<tokio::net::TcpStream>) -> Option<String> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await.ok()? == 0 {
+        return None;
+    }
+    let path = line.split_whitespace().nth(1)?.to_string();
+    let mut length = 0;
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await.ok()?;
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().ok()?;
+            }
+        }
+    }
+    stream.read_exact(&mut vec![0; length]).await.ok()?;
+    Some(path)
+}
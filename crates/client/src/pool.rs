@@ -0,0 +1,401 @@
+//! Connections a `DatabaseClient` keeps open to the server.
+//!
+//! Each connection serves one request at a time, and the pool opens at most
+//! `pool_size` of them. A request checks one out, waiting behind the
+//! requests that asked before it for up to `checkout_timeout`, and hands it
+//! back once it has read the whole response. An idle connection is reused
+//! if there is one, newest first, and one is opened otherwise.
+//!
+//! `min_pool_size` connections are opened on connecting. A connection that
+//! idled longer than `idle_timeout` is closed when it is next checked out,
+//! unless that would leave fewer than `min_pool_size` idle, in which case it
+//! must first answer a health check. Connections the server closed are
+//! dropped, and a request that could not be sent on one is sent on a new
+//! connection; one that may have reached the server is never sent again.
+//! Failures before a request went out are `ClientError::Connection`, and
+//! ones after `ClientError::Network`.
+//!
+//! Opening a connection, handshake included, fails once it takes longer
+//! than `connect_timeout`. With `keepalive` set, the OS probes connections
+//! that idled that long, so a server that went away without closing them is
+//! noticed. A connection whose request was given up on before the response
+//! was read, say because it timed out, is never used again.
+
+use crate::client::error_chain;
+use crate::config::ClientConfig;
+use crate::error::{ClientError, Result};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::client::conn::http1::{self, SendRequest};
+use hyper::header::HeaderValue;
+use hyper::{header, HeaderMap, Method, Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use socket2::{SockRef, TcpKeepalive};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// What a pool is doing, for debugging
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Connections checked out, including ones being opened
+    pub in_use: usize,
+    /// Open connections waiting to be checked out
+    pub idle: usize,
+    /// Requests waiting for a connection
+    pub waiters: usize,
+    /// Connections opened since the client connected
+    pub created: u64,
+    /// Connections dropped: closed by the server, failing a health check,
+    /// idle too long, or given up on mid-request
+    pub reaped: u64,
+}
+
+struct Idle {
+    sender: SendRequest<Full<Bytes>>,
+    since: Instant,
+}
+
+pub(crate) struct Pool {
+    address: String,
+    size: usize,
+    min_size: usize,
+    checkout_timeout: Duration,
+    idle_timeout: Duration,
+    connect_timeout: Duration,
+    keepalive: Option<Duration>,
+    /// One permit per connection that may be checked out, handed out in the
+    /// order they were asked for
+    permits: Arc<Semaphore>,
+    idle: Mutex<Vec<Idle>>,
+    waiters: AtomicUsize,
+    created: AtomicU64,
+    reaped: AtomicU64,
+}
+
+impl Pool {
+    /// A pool of connections to the server at `address`
+    pub(crate) fn new(address: String, config: &ClientConfig) -> Result<Self> {
+        if config.pool_size == 0 || config.min_pool_size > config.pool_size {
+            return Err(ClientError::Connection(format!(
+                "the pool needs a size of at least 1 and of at least min_pool_size, not {} with min_pool_size {}",
+                config.pool_size, config.min_pool_size
+            )));
+        }
+        Ok(Self {
+            address,
+            size: config.pool_size,
+            min_size: config.min_pool_size,
+            checkout_timeout: config.checkout_timeout,
+            idle_timeout: config.idle_timeout,
+            connect_timeout: config.connect_timeout,
+            keepalive: config.keepalive,
+            permits: Arc::new(Semaphore::new(config.pool_size)),
+            idle: Mutex::new(Vec::new()),
+            waiters: AtomicUsize::new(0),
+            created: AtomicU64::new(0),
+            reaped: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub(crate) fn stats(&self) -> PoolStats {
+        PoolStats {
+            in_use: self.size - self.permits.available_permits(),
+            idle: self.idle.lock().unwrap().len(),
+            waiters: self.waiters.load(Ordering::SeqCst),
+            created: self.created.load(Ordering::SeqCst),
+            reaped: self.reaped.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Open connections until `min_pool_size` are idle
+    pub(crate) async fn fill(&self) -> Result<()> {
+        while self.idle.lock().unwrap().len() < self.min_size {
+            let sender = self.open().await?;
+            self.idle.lock().unwrap().push(Idle { sender, since: Instant::now() });
+        }
+        Ok(())
+    }
+
+    /// A connection of its own until the returned one is dropped
+    pub(crate) async fn checkout(self: &Arc<Self>) -> Result<Connection> {
+        let waiting = Waiting::new(&self.waiters);
+        let permit = tokio::time::timeout(self.checkout_timeout, self.permits.clone().acquire_owned()).await
+            .map_err(|_| ClientError::Timeout)?
+            .expect("the pool's semaphore is never closed");
+        drop(waiting);
+
+        loop {
+            let Some((idle, others)) = self.take_idle() else {
+                let sender = self.open().await?;
+                return Ok(Connection { pool: self.clone(), sender: Some(sender), reused: false, done: false, interrupted: false, _permit: permit });
+            };
+            if idle.sender.is_closed() {
+                self.reap();
+                continue;
+            }
+            let mut sender = idle.sender;
+            let stale = idle.since.elapsed() > self.idle_timeout;
+            if stale && (others >= self.min_size || !self.healthy(&mut sender).await) {
+                self.reap();
+                continue;
+            }
+            return Ok(Connection { pool: self.clone(), sender: Some(sender), reused: true, done: false, interrupted: false, _permit: permit });
+        }
+    }
+
+    /// The newest idle connection, and how many stay idle
+    fn take_idle(&self) -> Option<(Idle, usize)> {
+        let mut idle = self.idle.lock().unwrap();
+        idle.pop().map(|connection| (connection, idle.len()))
+    }
+
+    async fn open(&self) -> Result<SendRequest<Full<Bytes>>> {
+        let cannot_connect = |reason: String| ClientError::Connection(format!("cannot connect to {}: {}", self.address, reason));
+        let connecting = async {
+            let stream = TcpStream::connect(&self.address).await.map_err(|e| cannot_connect(e.to_string()))?;
+            stream.set_nodelay(true).map_err(|e| cannot_connect(e.to_string()))?;
+            if let Some(keepalive) = self.keepalive {
+                SockRef::from(&stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))
+                    .map_err(|e| cannot_connect(e.to_string()))?;
+            }
+            http1::handshake(TokioIo::new(stream)).await.map_err(|e| cannot_connect(error_chain(&e)))
+        };
+        let (sender, connection) = tokio::time::timeout(self.connect_timeout, connecting).await
+            .map_err(|_| cannot_connect(format!("timed out after {:?}", self.connect_timeout)))??;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!("Connection to the server failed: {}", error_chain(&e));
+            }
+        });
+        self.created.fetch_add(1, Ordering::SeqCst);
+        Ok(sender)
+    }
+
+    fn reap(&self) {
+        self.reaped.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Whether the connection answers a health check
+    async fn healthy(&self, sender: &mut SendRequest<Full<Bytes>>) -> bool {
+        let Ok(request) = self.request(Method::GET, "/health").body(Full::default()) else {
+            return false;
+        };
+        if sender.ready().await.is_err() {
+            return false;
+        }
+        match sender.send_request(request).await {
+            Ok(response) => response.status() == StatusCode::OK && response.into_body().collect().await.is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// A request to the server at `path`
+    pub(crate) fn request(&self, method: Method, path: &str) -> hyper::http::request::Builder {
+        Request::builder().method(method).uri(path).header(header::HOST, &self.address)
+    }
+}
+
+/// Counts a request among the waiters while it waits, even if it gives up
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(waiters: &'a AtomicUsize) -> Self {
+        waiters.fetch_add(1, Ordering::SeqCst);
+        Self(waiters)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A checked out connection, handed back to the pool when dropped if it
+/// finished its request and the server keeps it open
+pub(crate) struct Connection {
+    pool: Arc<Pool>,
+    sender: Option<SendRequest<Full<Bytes>>>,
+    /// Whether it served a request before, and may have been closed since
+    reused: bool,
+    /// Whether the last response was read to the end
+    done: bool,
+    /// Whether a request was given up on while its response was on the way
+    interrupted: bool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Connection {
+    /// Send `request` and read the whole response
+    pub(crate) async fn send(&mut self, mut request: Request<Full<Bytes>>) -> Result<(StatusCode, HeaderMap, Bytes)> {
+        if !request.headers().contains_key(header::HOST) {
+            let host = HeaderValue::from_str(&self.pool.address)
+                .map_err(|e| ClientError::Connection(format!("invalid address {}: {}", self.pool.address, e)))?;
+            request.headers_mut().insert(header::HOST, host);
+        }
+        self.done = false;
+        // What is left of the abandoned response would be read as this one's
+        if self.interrupted {
+            self.replace().await?;
+        }
+        loop {
+            let sender = self.sender.as_mut().expect("a connection keeps its sender until dropped");
+            // Ready once the connection has settled after the last response,
+            // or failed if the server closed it
+            if sender.ready().await.is_err() && self.reused {
+                self.replace().await?;
+                continue;
+            }
+            self.interrupted = true;
+            match sender.try_send_request(request).await {
+                Ok(response) => {
+                    let (parts, body) = response.into_parts();
+                    let body = body.collect().await.map_err(|e| ClientError::Network(error_chain(&e)))?.to_bytes();
+                    self.interrupted = false;
+                    self.done = true;
+                    self.reused = true;
+                    return Ok((parts.status, parts.headers, body));
+                }
+                Err(mut e) => match e.take_message() {
+                    // The server closed it before the request went out
+                    Some(unsent) if self.reused => {
+                        self.interrupted = false;
+                        self.replace().await?;
+                        request = unsent;
+                    }
+                    Some(_) => {
+                        let reason = error_chain(e.error());
+                        return Err(ClientError::Connection(format!("cannot send to {}: {}", self.pool.address, reason)));
+                    }
+                    None => return Err(ClientError::Network(error_chain(&e.into_error()))),
+                },
+            }
+        }
+    }
+
+    /// Swap a connection the server closed for a new one
+    async fn replace(&mut self) -> Result<()> {
+        self.pool.reap();
+        self.sender = Some(self.pool.open().await?);
+        self.reused = false;
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let Some(sender) = self.sender.take() else {
+            return;
+        };
+        if self.done && !sender.is_closed() {
+            self.pool.idle.lock().unwrap().push(Idle { sender, since: Instant::now() });
+        } else {
+            self.pool.reap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockServer, Reply};
+
+    /// A server answering every request with "ok"
+    async fn serve(close_after: usize) -> MockServer {
+        MockServer::start(Vec::new(), Reply::ok(serde_json::json!("ok")), close_after).await
+    }
+
+    fn pool(server: &MockServer, size: usize, min_size: usize, idle_timeout: Duration) -> Arc<Pool> {
+        let config = ClientConfig {
+            pool_size: size,
+            min_pool_size: min_size,
+            checkout_timeout: Duration::from_millis(100),
+            idle_timeout,
+            ..server.config()
+        };
+        Arc::new(Pool::new(config.address(), &config).unwrap())
+    }
+
+    async fn get(pool: &Arc<Pool>, path: &str) -> Result<Bytes> {
+        let request = pool.request(Method::GET, path).body(Full::default()).unwrap();
+        let (_, _, body) = pool.checkout().await?.send(request).await?;
+        Ok(body)
+    }
+
+    #[tokio::test]
+    async fn test_connections_are_reused_and_replaced_once_closed() {
+        let server = serve(3).await;
+        let pool = pool(&server, 2, 0, Duration::from_secs(30));
+        for i in 0..10 {
+            assert_eq!(get(&pool, &format!("/{}", i)).await.unwrap(), "\"ok\"");
+        }
+        // Every third request closed its connection
+        let stats = pool.stats();
+        assert_eq!((stats.created, stats.reaped, stats.idle, stats.in_use), (4, 3, 1, 0));
+        assert_eq!(server.paths().len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_checkout_waits_at_most_the_timeout() {
+        let server = serve(usize::MAX).await;
+        let pool = pool(&server, 1, 0, Duration::from_secs(30));
+        let held = pool.checkout().await.unwrap();
+        assert!(matches!(pool.checkout().await, Err(ClientError::Timeout)));
+        assert_eq!(pool.stats(), PoolStats { in_use: 1, idle: 0, waiters: 0, created: 1, reaped: 0 });
+
+        // Handed back unused, it is not known to be sound
+        drop(held);
+        assert_eq!(get(&pool, "/").await.unwrap(), "\"ok\"");
+        assert_eq!(pool.stats(), PoolStats { in_use: 0, idle: 1, waiters: 0, created: 2, reaped: 1 });
+    }
+
+    #[tokio::test]
+    async fn test_connections_given_up_on_mid_request_not_reused() {
+        let late = Reply::Late(Duration::from_millis(500), Box::new(Reply::ok(serde_json::json!("late"))));
+        let server = MockServer::start(vec![late], Reply::ok(serde_json::json!("ok")), usize::MAX).await;
+        let pool = pool(&server, 1, 0, Duration::from_secs(30));
+        let mut connection = pool.checkout().await.unwrap();
+        let started = Instant::now();
+        let request = pool.request(Method::GET, "/late").body(Full::default()).unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(100), connection.send(request)).await.is_err());
+        assert!(started.elapsed() < Duration::from_millis(500), "gave up after {:?}", started.elapsed());
+
+        // The next request goes over a new connection, and is not answered
+        // with the late response
+        let request = pool.request(Method::GET, "/next").body(Full::default()).unwrap();
+        assert_eq!(connection.send(request).await.unwrap().2, "\"ok\"");
+        drop(connection);
+        assert_eq!(pool.stats(), PoolStats { in_use: 0, idle: 1, waiters: 0, created: 2, reaped: 1 });
+        assert_eq!(server.paths(), ["/late", "/next"]);
+    }
+
+    #[tokio::test]
+    async fn test_idle_connections_checked_before_reuse() {
+        let server = serve(usize::MAX).await;
+        let pool = pool(&server, 4, 1, Duration::ZERO);
+        pool.fill().await.unwrap();
+        assert_eq!(pool.stats().idle, 1);
+
+        // The one connection kept open answers a health check first
+        get(&pool, "/a").await.unwrap();
+        assert_eq!(server.paths(), ["/health", "/a"]);
+
+        // Beyond the minimum, idle connections are closed instead
+        let mut connections = [pool.checkout().await.unwrap(), pool.checkout().await.unwrap()];
+        for connection in &mut connections {
+            connection.send(pool.request(Method::GET, "/x").body(Full::default()).unwrap()).await.unwrap();
+        }
+        drop(connections);
+        assert_eq!(pool.stats().idle, 2);
+        get(&pool, "/b").await.unwrap();
+        assert_eq!(pool.stats(), PoolStats { in_use: 0, idle: 1, waiters: 0, created: 2, reaped: 1 });
+        assert_eq!(server.paths()[3..], ["/x", "/x", "/health", "/b"]);
+    }
+}
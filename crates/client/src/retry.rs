@@ -0,0 +1,144 @@
+//! When the client sends a request again after it failed.
+//!
+//! A request is retried, up to `RetryPolicy::max_retries` times, when it
+//! could not reach the server, or when the server answers that it did not
+//! run it and marks the failure `retryable`: while its cluster elects a
+//! leader, or when it sheds load or rate limits the client. Each retry
+//! waits twice as long as the one before, from `initial_backoff` up to
+//! `max_backoff`, and a random amount less, so clients that failed together
+//! do not retry together. It waits at least as long as a `Retry-After`
+//! header asks.
+//!
+//! A request that failed after it was sent, when the connection broke or
+//! the request timed out, may or may not have run. Reads are retried then,
+//! but a statement that writes fails with `ClientError::Ambiguous` instead,
+//! as running it twice may not be the same as running it once. Statements
+//! are taken to write unless they start with `SELECT`, `SHOW`, `DESCRIBE`
+//! or `EXPLAIN` (without `ANALYZE`), or are sent with
+//! `RequestOptions::idempotent`. Each attempt has `ClientConfig::timeout`,
+//! or the request's `RequestOptions::timeout`, to read its whole response.
+
+use crate::config::ReadConsistency;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// How many times, and how far apart, failed requests are retried
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; 0 disables retrying
+    pub max_retries: u32,
+    /// Longest wait before the first retry
+    pub initial_backoff: Duration,
+    /// Longest wait before any retry
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before retry number `retry`, counting from 1: half
+    /// the nominal backoff, doubling with each retry, plus up to as much
+    /// again at random
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let nominal = self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff);
+        let half = nominal / 2;
+        let random = RandomState::new().build_hasher().finish();
+        half + half.mul_f64(random as f64 / u64::MAX as f64)
+    }
+}
+
+/// Settings for one request, overriding the client's
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestOptions {
+    pub(crate) no_retry: bool,
+    pub(crate) idempotent: bool,
+    pub(crate) consistency: Option<ReadConsistency>,
+    pub(crate) timeout: Option<Duration>,
+}
+
+impl RequestOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make one attempt, whatever the client's retry policy
+    pub fn no_retry(mut self) -> Self {
+        self.no_retry = true;
+        self
+    }
+
+    /// Retry the statement even after a failure that leaves unknown
+    /// whether it ran, as running it twice is the same as running it once,
+    /// as for an upsert or a write keyed by an idempotency token of its own
+    pub fn idempotent(mut self) -> Self {
+        self.idempotent = true;
+        self
+    }
+
+    /// Read at `consistency` rather than the client's `ClientConfig::consistency`
+    pub fn consistency(mut self, consistency: ReadConsistency) -> Self {
+        self.consistency = Some(consistency);
+        self
+    }
+
+    /// Give each attempt `timeout` rather than the client's
+    /// `ClientConfig::timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// Whether `sql` only reads, and so is safe to run again
+pub(crate) fn reads_only(sql: &str) -> bool {
+    let mut words = sql.split(|c: char| c.is_whitespace() || c == '(')
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_uppercase);
+    match words.next().as_deref() {
+        Some("SELECT" | "SHOW" | "DESCRIBE") => true,
+        Some("EXPLAIN") => words.next().as_deref() != Some("ANALYZE"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_limit() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+        };
+        for (retry, nominal) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (30, 1000)] {
+            let nominal = Duration::from_millis(nominal);
+            for _ in 0..20 {
+                let backoff = policy.backoff(retry);
+                assert!(backoff >= nominal / 2 && backoff <= nominal, "retry {}: {:?}", retry, backoff);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reads_only() {
+        for sql in ["SELECT 1", "  select * from t", "(SELECT 1)", "SHOW TABLES", "describe t", "EXPLAIN SELECT 1"] {
+            assert!(reads_only(sql), "{}", sql);
+        }
+        for sql in ["INSERT INTO t VALUES (1)", "UPDATE t SET a = 1", "EXPLAIN ANALYZE DELETE FROM t", "BEGIN", "", "SELECTED"] {
+            assert!(!reads_only(sql), "{}", sql);
+        }
+    }
+}
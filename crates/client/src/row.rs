@@ -0,0 +1,232 @@
+//! Reading result rows into types that implement `Deserialize`.
+//!
+//! A row reads as a map from column names to values, so a struct's fields
+//! are matched to columns by name, `#[serde(rename)]` included, and columns
+//! no field names are ignored. INTEGER values read as any integer type they
+//! fit in, FLOAT and INTEGER values as floats, TEXT and TIMESTAMP values as
+//! strings, BLOB values as bytes and BOOLEAN values as bools. NULL reads as
+//! `None` for an `Option` field, and fails for any other.
+
+use crate::error::ClientError;
+use crate::value::{ColumnMeta, Value};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::forward_to_deserialize_any;
+use std::fmt;
+use std::sync::Arc;
+
+/// One row of a result, as `DatabaseClient::query_stream` yields them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    /// The result's columns, shared by its rows
+    columns: Arc<[ColumnMeta]>,
+    values: Vec<Value>,
+}
+
+impl Row {
+    pub(crate) fn new(columns: Arc<[ColumnMeta]>, values: Vec<Value>) -> Self {
+        Self { columns, values }
+    }
+
+    pub fn columns(&self) -> &[ColumnMeta] {
+        &self.columns
+    }
+
+    /// The row's values, in column order
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    pub fn into_values(self) -> Vec<Value> {
+        self.values
+    }
+
+    /// The value of the column called `name`
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        let i = self.columns.iter().position(|column| column.name == name)?;
+        self.values.get(i)
+    }
+
+    /// The row read as a `T`, its fields matched to columns by name
+    pub fn read_as<T: DeserializeOwned>(&self) -> Result<T, ClientError> {
+        T::deserialize(RowDeserializer { columns: &self.columns, row: &self.values })
+            .map_err(|e| ClientError::Decode(e.0))
+    }
+}
+
+/// Read every row of a result as a `T`
+pub(crate) fn rows_as<T: DeserializeOwned>(columns: &[ColumnMeta], rows: &[Vec<Value>]) -> Result<Vec<T>, ClientError> {
+    rows.iter()
+        .enumerate()
+        .map(|(i, row)| {
+            T::deserialize(RowDeserializer { columns, row })
+                .map_err(|e| ClientError::Decode(format!("row {}: {}", i + 1, e.0)))
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error(message.to_string())
+    }
+}
+
+struct RowDeserializer<'a> {
+    columns: &'a [ColumnMeta],
+    row: &'a [Value],
+}
+
+impl<'de> de::Deserializer<'de> for RowDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(Columns { columns: self.columns.iter().zip(self.row), current: None })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+/// The columns of a row, as map entries
+struct Columns<'a, I> {
+    columns: I,
+    current: Option<(&'a ColumnMeta, &'a Value)>,
+}
+
+impl<'de, I: Iterator<Item = (&'de ColumnMeta, &'de Value)>> MapAccess<'de> for Columns<'de, I> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        let Some((column, value)) = self.columns.next() else {
+            return Ok(None);
+        };
+        self.current = Some((column, value));
+        seed.deserialize(column.name.as_str().into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<S::Value, Error> {
+        let (column, value) = self.current.take().expect("a value is read after its key");
+        seed.deserialize(ValueDeserializer(value)).map_err(|e| {
+            let data_type = column.data_type.as_deref().unwrap_or("untyped");
+            Error(format!("column `{}` ({}): {}", column.name, data_type, e))
+        })
+    }
+}
+
+struct ValueDeserializer<'a>(&'a Value);
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Null => visitor.visit_unit(),
+            Value::Integer(i) => visitor.visit_i64(*i),
+            Value::Float(f) => visitor.visit_f64(*f),
+            Value::Text(s) | Value::Timestamp(s) => visitor.visit_borrowed_str(s),
+            Value::Boolean(b) => visitor.visit_bool(*b),
+            Value::Blob(bytes) => visitor.visit_borrowed_bytes(bytes),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct enum
+        identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct User {
+        id: u32,
+        #[serde(rename = "full_name")]
+        name: String,
+        score: Option<f64>,
+        active: bool,
+    }
+
+    fn columns(names: &[(&str, &str)]) -> Vec<ColumnMeta> {
+        names.iter()
+            .map(|(name, data_type)| ColumnMeta { name: name.to_string(), data_type: Some(data_type.to_string()) })
+            .collect()
+    }
+
+    #[test]
+    fn test_rows_read_by_column_name() {
+        let columns = columns(&[("active", "BOOLEAN"), ("id", "INTEGER"), ("full_name", "TEXT"), ("score", "FLOAT"), ("extra", "BLOB")]);
+        let rows = vec![
+            vec![Value::Boolean(true), Value::Integer(1), Value::Text("Ada".to_string()), Value::Float(9.5), Value::Blob(vec![1])],
+            vec![Value::Boolean(false), Value::Integer(2), Value::Text("Grace".to_string()), Value::Null, Value::Null],
+        ];
+        let users: Vec<User> = rows_as(&columns, &rows).unwrap();
+        assert_eq!(users, vec![
+            User { id: 1, name: "Ada".to_string(), score: Some(9.5), active: true },
+            User { id: 2, name: "Grace".to_string(), score: None, active: false },
+        ]);
+
+        // Optional fields may be left out of the query altogether
+        let users: Vec<User> = rows_as(&columns[..3], &[rows[0][..3].to_vec()]).unwrap();
+        assert_eq!(users[0].score, None);
+    }
+
+    #[test]
+    fn test_mismatches_name_the_column() {
+        let columns = columns(&[("id", "TEXT"), ("full_name", "TEXT"), ("active", "BOOLEAN")]);
+        let error = |row: Vec<Value>| match rows_as::<User>(&columns, &[row]) {
+            Err(ClientError::Decode(message)) => message,
+            other => panic!("expected a decode error, got {:?}", other),
+        };
+        let name = || Value::Text("Ada".to_string());
+
+        let message = error(vec![Value::Text("one".to_string()), name(), Value::Boolean(true)]);
+        assert!(message.contains("column `id` (TEXT)") && message.contains("string \"one\", expected u32"), "{}", message);
+        let message = error(vec![Value::Integer(-1), name(), Value::Boolean(true)]);
+        assert!(message.contains("column `id`") && message.contains("integer `-1`"), "{}", message);
+        let message = error(vec![Value::Integer(1), name(), Value::Null]);
+        assert!(message.starts_with("row 1: column `active` (BOOLEAN)") && message.contains("expected a boolean"), "{}", message);
+        let message = match rows_as::<User>(&columns[..2], &[vec![Value::Integer(1), name()]]) {
+            Err(ClientError::Decode(message)) => message,
+            other => panic!("expected a decode error, got {:?}", other),
+        };
+        assert!(message.contains("missing field `active`"), "{}", message);
+    }
+
+    #[test]
+    fn test_row_values_by_name() {
+        let columns: Arc<[ColumnMeta]> = columns(&[("id", "INTEGER"), ("full_name", "TEXT"), ("active", "BOOLEAN")]).into();
+        let row = Row::new(columns, vec![Value::Integer(7), Value::Text("Ada".to_string()), Value::Boolean(true)]);
+        assert_eq!(row.get("full_name"), Some(&Value::Text("Ada".to_string())));
+        assert_eq!(row.get("missing"), None);
+        assert_eq!(row.read_as::<User>().unwrap(), User { id: 7, name: "Ada".to_string(), score: None, active: true });
+        assert!(matches!(row.read_as::<(u32,)>(), Err(ClientError::Decode(_))));
+    }
+}
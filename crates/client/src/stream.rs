@@ -0,0 +1,247 @@
+//! Reading results too large to hold at once, a page at a time.
+//!
+//! `DatabaseClient::query_stream` sends a query asking for at most
+//! `ClientConfig::fetch_size` rows, and the server keeps a cursor to the
+//! rest (see the server's `cursor`). The stream asks for the next page only
+//! once every row of the one before it has been taken, so it holds one page
+//! at most however many rows the result has, and every page comes from the
+//! same snapshot. A failure partway through ends the stream with the error.
+//!
+//! A stream dropped before its last row closes the cursor in the background
+//! where a tokio runtime is running, and otherwise the server closes it once
+//! it has idled past its `cursor_idle_timeout_ms`. Statements other than
+//! SELECT run as with `execute_query`, yielding whatever rows they return.
+
+use crate::client::{self, Attempt, DatabaseClient};
+use crate::cluster::{Node, Route};
+use crate::error::Result;
+use crate::retry::{self, RequestOptions};
+use crate::row::Row;
+use crate::value::{ColumnMeta, Value};
+use futures::future::BoxFuture;
+use futures::Stream;
+use hyper::body::Bytes;
+use hyper::{HeaderMap, Method, StatusCode};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+type Response = Result<(Arc<Node>, StatusCode, HeaderMap, Bytes)>;
+
+/// The rows of a query, fetched from the server as they are taken
+pub struct RowStream<'a> {
+    client: &'a DatabaseClient,
+    /// The query, until its first page is asked for
+    sql: Option<String>,
+    columns: Option<Arc<[ColumnMeta]>>,
+    /// What is left of the last page
+    rows: std::vec::IntoIter<Vec<Value>>,
+    /// The server's cursor to the rows after them, while it may be open
+    cursor: Option<String>,
+    /// The node holding the cursor
+    node: Option<Arc<Node>>,
+    fetching: Option<BoxFuture<'a, Response>>,
+    done: bool,
+}
+
+impl<'a> RowStream<'a> {
+    pub(crate) fn new(client: &'a DatabaseClient, sql: &str) -> Self {
+        Self {
+            client,
+            sql: Some(sql.to_string()),
+            columns: None,
+            rows: Vec::new().into_iter(),
+            cursor: None,
+            node: None,
+            fetching: None,
+            done: false,
+        }
+    }
+
+    /// The result's columns, once its first page has arrived
+    pub fn columns(&self) -> Option<&[ColumnMeta]> {
+        self.columns.as_deref()
+    }
+
+    /// Ask for the first page, or the one after the last, if there is one
+    fn fetch(&mut self) -> Option<BoxFuture<'a, Response>> {
+        let client = self.client;
+        let max_rows = client.config().fetch_size;
+        if let Some(sql) = self.sql.take() {
+            let idempotent = retry::reads_only(&sql);
+            let (route, path) = match idempotent {
+                true => client.read_target("/api/query", &RequestOptions::default()),
+                false => (Route::Leader, "/api/query".to_string()),
+            };
+            let body = serde_json::json!({ "sql": sql, "max_rows": max_rows }).to_string();
+            return Some(Box::pin(async move {
+                client.send_retrying(route, Method::POST, &path, Some(body), idempotent, &RequestOptions::default()).await
+            }));
+        }
+        // A page is not asked for again, as the cursor may have moved past
+        // it, nor of another node, which does not hold the cursor
+        let body = serde_json::json!({ "cursor": self.cursor.as_ref()?, "max_rows": max_rows }).to_string();
+        let node = self.node.clone()?;
+        Some(Box::pin(async move {
+            let (status, headers, body) = client.send(&node, Method::POST, "/api/query/next", Some(body), client.config().timeout).await.map_err(Attempt::into_error)?;
+            Ok((node, status, headers, body))
+        }))
+    }
+}
+
+impl Stream for RowStream<'_> {
+    type Item = Result<Row>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(values) = this.rows.next() {
+                let columns = this.columns.clone().expect("a page's rows come with its columns");
+                return Poll::Ready(Some(Ok(Row::new(columns, values))));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+            if this.fetching.is_none() {
+                this.fetching = this.fetch();
+            }
+            let Some(fetching) = this.fetching.as_mut() else {
+                this.done = true;
+                return Poll::Ready(None);
+            };
+            let response = std::task::ready!(fetching.as_mut().poll(cx));
+            this.fetching = None;
+            // A cursor that failed to reach the server is kept to close
+            let page = response.and_then(|(node, status, headers, body)| {
+                this.cursor = None;
+                this.node = Some(node);
+                client::query_page(status, &headers, &body)
+            });
+            match page {
+                Ok((result, cursor)) => {
+                    this.columns = Some(result.columns.into());
+                    this.rows = result.rows.into_iter();
+                    this.cursor = cursor;
+                    this.done = this.cursor.is_none();
+                }
+                Err(e) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for RowStream<'_> {
+    fn drop(&mut self) {
+        let (Some(cursor), Some(node)) = (self.cursor.take(), self.node.take()) else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let body = serde_json::json!({ "cursor": cursor }).to_string();
+        let Ok(request) = self.client.request(Method::POST, "/api/query/close", Some(body)) else {
+            return;
+        };
+        let (pool, timeout) = (node.pool().clone(), self.client.config().timeout);
+        runtime.spawn(async move {
+            let closed = tokio::time::timeout(timeout, async { pool.checkout().await?.send(request).await }).await;
+            match closed {
+                Ok(Ok(_)) => tracing::debug!("Closed the cursor of a dropped result stream"),
+                Ok(Err(e)) => tracing::debug!("Cannot close the cursor of a dropped result stream: {}", e),
+                Err(_) => tracing::debug!("Cannot close the cursor of a dropped result stream: timed out"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ClientConfig;
+    use crate::error::ClientError;
+    use crate::mock::{MockServer, Reply};
+    use futures::StreamExt;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn page(ids: std::ops::Range<i64>, cursor: Option<&str>) -> Reply {
+        let rows: Vec<serde_json::Value> = ids.map(|id| json!([id])).collect();
+        Reply::ok(json!({
+            "success": true,
+            "result_format": 2,
+            "result": { "columns": [{ "name": "id", "data_type": "INTEGER" }], "rows": rows },
+            "cursor": cursor,
+        }))
+    }
+
+    async fn connect(script: Vec<Reply>) -> (MockServer, DatabaseClient) {
+        let server = MockServer::start([vec![Reply::ok(json!("ok"))], script].concat(), Reply::ok(json!({ "success": true })), usize::MAX).await;
+        let client = DatabaseClient::connect_with(ClientConfig { fetch_size: 3, ..server.config() }).await.unwrap();
+        (server, client)
+    }
+
+    async fn ids(rows: &mut RowStream<'_>, count: usize) -> Vec<i64> {
+        let mut ids = Vec::new();
+        for _ in 0..count {
+            match rows.next().await.unwrap().unwrap().values() {
+                [Value::Integer(id)] => ids.push(*id),
+                values => panic!("unexpected row {:?}", values),
+            }
+        }
+        ids
+    }
+
+    #[tokio::test]
+    async fn test_pages_fetched_as_rows_are_taken() {
+        let (server, client) = connect(vec![page(0..3, Some("c1")), page(3..6, Some("c1")), page(6..8, None)]).await;
+
+        let mut rows = client.query_stream("SELECT id FROM t");
+        assert_eq!(server.paths(), ["/health"]);
+        assert_eq!(ids(&mut rows, 3).await, [0, 1, 2]);
+        assert_eq!(rows.columns().unwrap()[0].name, "id");
+        assert_eq!(server.paths(), ["/health", "/api/query"]);
+        assert_eq!(ids(&mut rows, 1).await, [3]);
+        assert_eq!(server.paths(), ["/health", "/api/query", "/api/query/next"]);
+        assert_eq!(ids(&mut rows, 4).await, [4, 5, 6, 7]);
+        assert!(rows.next().await.is_none());
+        assert!(rows.next().await.is_none());
+
+        // A stream read to the end has no cursor left to close
+        drop(rows);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(server.paths().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_stream_closes_its_cursor() {
+        let expired = Reply::Json(410, Vec::new(), json!({ "success": false, "error": { "code": "cursor_expired", "message": "gone" } }));
+        let (server, client) = connect(vec![page(0..3, Some("c1")), Reply::ok(json!({ "success": true })), page(0..3, Some("c2")), expired]).await;
+
+        let mut rows = client.query_stream("SELECT id FROM t");
+        assert_eq!(ids(&mut rows, 1).await, [0]);
+        drop(rows);
+        for _ in 0..100 {
+            if server.paths().len() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.paths()[1..], ["/api/query", "/api/query/close"]);
+
+        // A failure partway through ends the stream, the server having
+        // closed the cursor already
+        let mut rows = client.query_stream("SELECT id FROM t");
+        assert_eq!(ids(&mut rows, 3).await, [0, 1, 2]);
+        match rows.next().await {
+            Some(Err(ClientError::Server { code, .. })) => assert_eq!(code, "cursor_expired"),
+            other => panic!("expected the cursor to have expired, got {:?}", other),
+        }
+        assert!(rows.next().await.is_none());
+        drop(rows);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(server.paths()[3..], ["/api/query", "/api/query/next"]);
+    }
+}
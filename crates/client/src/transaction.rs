@@ -0,0 +1,182 @@
+//! Transactions held open on the server across requests.
+//!
+//! `DatabaseClient::begin` begins a transaction and returns a `Transaction`
+//! to run its statements, which all go over one connection to the leader
+//! checked out for as long as the transaction is open. `commit` or
+//! `rollback` ends it and hands the connection back. A transaction dropped
+//! without either is rolled back in the background where a tokio runtime
+//! is running, and otherwise by the server once it has idled past its
+//! `transaction_idle_timeout_ms`.
+//!
+//! At `RepeatableRead` and above, a commit fails with
+//! `ClientError::Conflict` if another transaction committed a write to a
+//! row this one writes since it began; running the transaction again may
+//! then succeed, which `DatabaseClient::transaction` does. A transaction
+//! the server expired fails with `ClientError::TransactionExpired`.
+//!
+//! Each request has `ClientConfig::timeout` to be answered, or for
+//! `query_with` its `RequestOptions::timeout`. A statement that timed out
+//! fails with `ClientError::Timeout` and may have run; the transaction's
+//! later requests go over a new connection.
+
+use crate::client::{self, Attempt, DatabaseClient};
+use crate::cluster::Route;
+use crate::error::{ClientError, Result};
+use crate::pool::Connection;
+use crate::retry::RequestOptions;
+use crate::value::QueryResult;
+use hyper::body::Bytes;
+use hyper::{HeaderMap, Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// How much of other transactions' work a transaction sees
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    /// The server's default, unless its config sets another
+    #[default]
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    /// The name the server knows the level by
+    pub fn name(&self) -> &'static str {
+        match self {
+            IsolationLevel::ReadUncommitted => "read uncommitted",
+            IsolationLevel::ReadCommitted => "read committed",
+            IsolationLevel::RepeatableRead => "repeatable read",
+            IsolationLevel::Serializable => "serializable",
+        }
+    }
+}
+
+/// Body of an `/api/txn/begin` response
+#[derive(Deserialize)]
+struct BeginReply {
+    transaction_id: String,
+}
+
+/// A transaction open on the server, rolled back if dropped before it ends
+pub struct Transaction<'a> {
+    client: &'a DatabaseClient,
+    id: String,
+    /// The connection the transaction's requests go over, until it ends
+    connection: Option<Connection>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) async fn begin(client: &'a DatabaseClient, isolation: IsolationLevel) -> Result<Self> {
+        let mut followed = false;
+        let (connection, status, headers, body) = loop {
+            let (node, mut connection) = client.checkout(Route::Leader).await?;
+            let body = serde_json::json!({ "isolation_level": isolation.name() });
+            let request = client.request(Method::POST, "/api/txn/begin", Some(body.to_string()))?;
+            let (status, headers, body) = client.exchange(&mut connection, request, client.config().timeout).await
+                .map_err(|attempt| client.unreachable(&node, attempt.into_error()))?;
+            // Begun again on the leader the node names, once
+            if !status.is_success() && !followed {
+                if let ClientError::NotLeader { leader: Some(leader) } = client::failure(status, &headers, &body) {
+                    client.follow(&leader)?;
+                    followed = true;
+                    continue;
+                }
+            }
+            break (connection, status, headers, body);
+        };
+        if !status.is_success() {
+            return Err(client::failure(status, &headers, &body));
+        }
+        let reply: BeginReply = serde_json::from_slice(&body)
+            .map_err(|e| ClientError::Network(format!("cannot read the server's response: {}", e)))?;
+        Ok(Self { client, id: reply.transaction_id, connection: Some(connection) })
+    }
+
+    /// The server's id for the transaction
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Run `sql` in the transaction
+    pub async fn query(&mut self, sql: &str) -> Result<QueryResult> {
+        self.query_with(sql, &RequestOptions::default()).await
+    }
+
+    /// `query` with settings for this statement alone; only its timeout
+    /// applies, as statements in a transaction are never retried
+    pub async fn query_with(&mut self, sql: &str, options: &RequestOptions) -> Result<QueryResult> {
+        let body = serde_json::json!({ "sql": sql });
+        let timeout = options.timeout.unwrap_or(self.client.config().timeout);
+        let (status, headers, body) = self.send("query", Some(body.to_string()), timeout).await.map_err(Attempt::into_error)?;
+        client::query_result(status, &headers, &body)
+    }
+
+    /// Run `sql` in the transaction, reading its rows as `T`s (see `row`)
+    pub async fn query_as<T: DeserializeOwned>(&mut self, sql: &str) -> Result<Vec<T>> {
+        self.query(sql).await?.rows_as()
+    }
+
+    /// Run `sql` in the transaction, returning how many rows it changed
+    pub async fn execute(&mut self, sql: &str) -> Result<u64> {
+        Ok(self.query(sql).await?.rows_affected.unwrap_or(0))
+    }
+
+    /// Commit the transaction. A commit whose response was lost is asked
+    /// for again, which the server answers without committing twice.
+    pub async fn commit(mut self) -> Result<()> {
+        self.end("commit").await
+    }
+
+    pub async fn rollback(mut self) -> Result<()> {
+        self.end("rollback").await
+    }
+
+    async fn end(&mut self, action: &str) -> Result<()> {
+        let timeout = self.client.config().timeout;
+        let (status, headers, body) = match self.send(action, None, timeout).await {
+            Err(Attempt::Lost(e)) => {
+                tracing::debug!("Asking again to {} transaction {} after: {}", action, self.id, e);
+                self.send(action, None, timeout).await.map_err(Attempt::into_error)?
+            }
+            sent => sent.map_err(Attempt::into_error)?,
+        };
+        self.connection = None;
+        if !status.is_success() {
+            return Err(client::failure(status, &headers, &body));
+        }
+        Ok(())
+    }
+
+    /// Send `/api/txn/{id}/{action}` on the transaction's connection
+    async fn send(&mut self, action: &str, body: Option<String>, timeout: Duration) -> std::result::Result<(StatusCode, HeaderMap, Bytes), Attempt> {
+        let path = format!("/api/txn/{}/{}", self.id, action);
+        let request = self.client.request(Method::POST, &path, body).map_err(Attempt::Unsent)?;
+        let connection = self.connection.as_mut().expect("a transaction keeps its connection until it ends");
+        self.client.exchange(connection, request, timeout).await
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        let Some(mut connection) = self.connection.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let Ok(request) = self.client.request(Method::POST, &format!("/api/txn/{}/rollback", self.id), None) else {
+            return;
+        };
+        let (id, timeout) = (self.id.clone(), self.client.config().timeout);
+        runtime.spawn(async move {
+            match tokio::time::timeout(timeout, connection.send(request)).await {
+                Ok(Ok(_)) => tracing::debug!("Rolled back dropped transaction {}", id),
+                Ok(Err(e)) => tracing::debug!("Cannot roll back dropped transaction {}: {}", id, e),
+                Err(_) => tracing::debug!("Cannot roll back dropped transaction {}: timed out", id),
+            }
+        });
+    }
+}
@@ -0,0 +1,164 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::ser::SerializeStruct;
+use crate::row;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::json;
+use std::fmt;
+
+/// Key of the JSON object the server wraps a base64-encoded BLOB in
+pub const JSON_BLOB_KEY: &str = "$base64";
+/// Key of the JSON object the server wraps a NaN or infinite FLOAT in
+pub const JSON_FLOAT_KEY: &str = "$float";
+
+/// A typed value from a query result
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Float(f64),
+    Text(String),
+    Boolean(bool),
+    Blob(Vec<u8>),
+    /// ISO-8601 timestamp in UTC, as sent by the server
+    Timestamp(String),
+}
+
+impl Value {
+    /// Read a value from the natively typed JSON of a result row.
+    /// `data_type` is the SQL type name of its column; without one, or for a
+    /// type this client does not know, the value is read by its JSON shape.
+    pub fn from_json(json: &serde_json::Value, data_type: Option<&str>) -> Result<Value, String> {
+        use serde_json::Value as Json;
+
+        let invalid = || format!("invalid JSON {} for {} column", json, data_type.unwrap_or("untyped"));
+        let marker = |key: &str| match json {
+            Json::Object(map) if map.len() == 1 => map.get(key).and_then(Json::as_str),
+            _ => None,
+        };
+
+        match (json, data_type) {
+            (Json::Null, _) => Ok(Value::Null),
+            (Json::Bool(b), _) => Ok(Value::Boolean(*b)),
+            (Json::Number(n), Some("FLOAT")) => n.as_f64().map(Value::Float).ok_or_else(invalid),
+            (Json::Number(n), _) if n.is_i64() => Ok(Value::Integer(n.as_i64().unwrap())),
+            (Json::Number(n), _) => n.as_f64().map(Value::Float).ok_or_else(invalid),
+            (Json::String(s), Some("TIMESTAMP")) => Ok(Value::Timestamp(s.clone())),
+            (Json::String(s), _) => Ok(Value::Text(s.clone())),
+            (Json::Object(_), _) if marker(JSON_BLOB_KEY).is_some() => {
+                BASE64.decode(marker(JSON_BLOB_KEY).unwrap()).map(Value::Blob).map_err(|_| invalid())
+            }
+            (Json::Object(_), _) if marker(JSON_FLOAT_KEY).is_some() => {
+                marker(JSON_FLOAT_KEY).unwrap().parse().map(Value::Float).map_err(|_| invalid())
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    /// The JSON the server would send for this value
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Null => serde_json::Value::Null,
+            Value::Integer(i) => json!(i),
+            Value::Float(v) if v.is_finite() => json!(v),
+            Value::Float(v) => json!({ JSON_FLOAT_KEY: v.to_string() }),
+            Value::Text(s) | Value::Timestamp(s) => json!(s),
+            Value::Boolean(b) => json!(b),
+            Value::Blob(bytes) => json!({ JSON_BLOB_KEY: BASE64.encode(bytes) }),
+        }
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, Value::Integer(_) | Value::Float(_))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Integer(i) => write!(f, "{}", i),
+            // Debug keeps the decimal point, so 1.0 does not look like an integer
+            Value::Float(v) => write!(f, "{:?}", v),
+            Value::Text(s) | Value::Timestamp(s) => write!(f, "{}", s),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Blob(bytes) => {
+                write!(f, "\\x")?;
+                for byte in bytes {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Name and SQL type of one result column
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnMeta {
+    pub name: String,
+    /// SQL type name such as "INTEGER", or None if the server could not
+    /// determine it
+    pub data_type: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "WireQueryResult")]
+pub struct QueryResult {
+    pub columns: Vec<ColumnMeta>,
+    pub rows: Vec<Vec<Value>>,
+    pub rows_affected: Option<u64>,
+}
+
+impl QueryResult {
+    pub fn column_names(&self) -> Vec<&str> {
+        self.columns.iter().map(|column| column.name.as_str()).collect()
+    }
+
+    /// Every row read as a `T`, its fields matched to columns by name (see
+    /// `row`)
+    pub fn rows_as<T: DeserializeOwned>(&self) -> crate::Result<Vec<T>> {
+        row::rows_as(&self.columns, &self.rows)
+    }
+}
+
+impl Serialize for QueryResult {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let rows: Vec<Vec<serde_json::Value>> = self.rows.iter()
+            .map(|row| row.iter().map(Value::to_json).collect())
+            .collect();
+
+        let mut state = serializer.serialize_struct("QueryResult", 3)?;
+        state.serialize_field("columns", &self.columns)?;
+        state.serialize_field("rows", &rows)?;
+        state.serialize_field("rows_affected", &self.rows_affected)?;
+        state.end()
+    }
+}
+
+#[derive(Deserialize)]
+struct WireQueryResult {
+    columns: Vec<ColumnMeta>,
+    rows: Vec<Vec<serde_json::Value>>,
+    #[serde(default)]
+    rows_affected: Option<u64>,
+}
+
+impl TryFrom<WireQueryResult> for QueryResult {
+    type Error = String;
+
+    fn try_from(wire: WireQueryResult) -> Result<Self, String> {
+        let rows = wire.rows.iter()
+            .map(|row| {
+                if row.len() != wire.columns.len() {
+                    return Err(format!("row has {} values but result has {} columns", row.len(), wire.columns.len()));
+                }
+                row.iter()
+                    .zip(&wire.columns)
+                    .map(|(json, column)| Value::from_json(json, column.data_type.as_deref()))
+                    .collect()
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { columns: wire.columns, rows, rows_affected: wire.rows_affected })
+    }
+}
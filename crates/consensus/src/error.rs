@@ -11,12 +11,18 @@ pub enum ConsensusError {
     #[error("Not leader")]
     NotLeader,
     
+    #[error("Node is stopped")]
+    Stopped,
+    
     #[error("Election timeout")]
     ElectionTimeout,
     
     #[error("Network error: {0}")]
     Network(String),
     
+    #[error("Stale request: serial {serial} is older than the last applied serial {last_applied}")]
+    StaleRequest { serial: u64, last_applied: u64 },
+    
     #[error("Invalid configuration: {0}")]
     Config(String),
     
@@ -1,5 +1,10 @@
 pub mod raft;
+pub mod session;
+pub mod transport;
 pub mod error;
+mod storage;
 
 pub use error::{ConsensusError, Result};
-pub use raft::{RaftNode, RaftConfig};
\ No newline at end of file
+pub use raft::{LogEntry, Member, MemberInfo, Message, MessageBody, NodeId, RaftNode, RaftConfig, RaftMetrics, RaftState};
+pub use session::{ClientRequest, SessionId, SessionStateMachine, StateMachine};
+pub use transport::{Inbound, JoinResponse, Transport};
\ No newline at end of file
@@ -1,9 +1,32 @@
+//! Raft consensus: leader election, log replication, membership changes and
+//! linearizable reads.
+//!
+//! `RaftNode` is the protocol alone and does no I/O beyond persisting its
+//! state when opened on a directory. Messages for peers queue up for
+//! `take_messages`, messages from peers go to `step`, and `tick` lets time
+//! pass. Entries come out of `take_committed` once a majority stores them.
+//! The transport (see `transport`) and the caller's loop do the rest.
+//!
+//! Membership changes add or remove one voter at a time. Each is an entry
+//! carrying the whole new membership, which takes effect as soon as a node
+//! appends it. A node started with `join` has no membership until the
+//! leader's log reaches it, and never stands for election before then.
+
 use crate::error::{Result, ConsensusError};
+use crate::session::ClientRequest;
+use crate::storage::{HardState, RaftStorage};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::error;
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// Most entries sent in one AppendEntries message
+const MAX_ENTRIES_PER_MESSAGE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct NodeId(pub Uuid);
 
 impl NodeId {
@@ -12,56 +35,250 @@ impl NodeId {
     }
 }
 
+impl Default for NodeId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct RaftConfig {
+    /// A new random ID unless given, which is only fit for a single node.
+    /// A node opened on a directory keeps the ID it was first given there.
     pub node_id: NodeId,
+    /// The other voters of a new cluster. Once the log records the
+    /// membership, it is what counts.
     pub peers: Vec<NodeId>,
+    /// Address clients reach each node at, this one included, for cluster
+    /// listings
+    pub addresses: HashMap<NodeId, String>,
+    /// Address of this node's Raft transport, as `host:port`; peers must
+    /// be able to reach it there
+    pub raft_address: Option<String>,
+    /// Raft transport address of each peer
+    pub peer_addresses: HashMap<NodeId, String>,
+    /// Raft address of a running member to ask to add this node, for a node
+    /// starting without peers. Ignored once the node has joined.
+    pub join: Option<String>,
+    /// Have reads confirm with a majority that this node still leads and
+    /// wait for its commits to apply (ReadIndex) instead of reading
+    /// whatever has been applied locally
+    pub linearizable_reads: bool,
+    /// Have a follower pass the writes clients send it on to the leader
+    /// over the Raft transport, instead of redirecting the clients there
+    pub forward_writes: bool,
+    /// Shortest election timeout; each one is drawn at random from this up
+    /// to twice this, so nodes rarely time out together
     pub election_timeout_ms: u64,
     pub heartbeat_interval_ms: u64,
+    /// Seed for the node's random choices, such as election timeouts, so
+    /// tests can make them repeatable. Seeded from the OS when None.
+    pub rng_seed: Option<u64>,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            node_id: NodeId::new(),
+            peers: Vec::new(),
+            addresses: HashMap::new(),
+            raft_address: None,
+            peer_addresses: HashMap::new(),
+            join: None,
+            linearizable_reads: false,
+            forward_writes: false,
+            election_timeout_ms: 150,
+            heartbeat_interval_ms: 50,
+            rng_seed: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum RaftState {
     Follower,
-    Candidate,  
+    Candidate,
     Leader,
 }
 
-/// Simplified Raft node implementation
+/// A voter, with where to reach it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemberInfo {
+    pub node_id: NodeId,
+    pub raft_address: Option<String>,
+    /// Address clients reach the node at
+    pub api_address: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub term: u64,
+    pub index: u64,
+    /// The command; empty for the entry each leader starts its term with
+    /// and for membership changes
+    pub data: Vec<u8>,
+    /// The voters from this entry on, for a membership change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub membership: Option<Vec<MemberInfo>>,
+}
+
+/// A message between two nodes, sent in `term` of the sender
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub term: u64,
+    pub body: MessageBody,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MessageBody {
+    RequestVote { last_log_index: u64, last_log_term: u64 },
+    Vote { granted: bool },
+    /// Entries following `prev_log_index`, or none for a heartbeat. `round`
+    /// counts the leader's heartbeats, for confirming reads.
+    AppendEntries { prev_log_index: u64, prev_log_term: u64, entries: Vec<LogEntry>, leader_commit: u64, round: u64 },
+    /// On success `match_index` is the last entry known to match the
+    /// leader's log; on failure, where the leader should try again after
+    AppendResponse { success: bool, match_index: u64, round: u64 },
+}
+
+/// A Raft node
 pub struct RaftNode {
     config: RaftConfig,
     state: RaftState,
     current_term: u64,
     voted_for: Option<NodeId>,
+    // Leader of the current term, if another node and known
+    leader_id: Option<NodeId>,
+    // Entry i has index i + 1
     log: Vec<LogEntry>,
     commit_index: u64,
     last_applied: u64,
+    // Voters per the log's last membership entry or, before there is one,
+    // the config; None while joining
+    membership: Option<Vec<MemberInfo>>,
+    
+    // Candidate state
+    votes: HashSet<NodeId>,
     
     // Leader state
     next_index: HashMap<NodeId, u64>,
     match_index: HashMap<NodeId, u64>,
+    // The latest heartbeat round each peer has answered, and when it last did
+    acked_round: HashMap<NodeId, u64>,
+    last_heard: HashMap<NodeId, Instant>,
+    round: u64,
+    // The entry this leader started its term with
+    term_start: u64,
+    reads: Vec<PendingRead>,
+    finished_reads: Vec<(u64, Option<u64>)>,
+    next_read: u64,
+    
+    election_deadline: Instant,
+    heartbeat_due: Instant,
+    outbox: Vec<Message>,
+    storage: Option<RaftStorage>,
+    stopped: bool,
+    rng: StdRng,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LogEntry {
-    pub term: u64,
-    pub index: u64,
-    pub data: Vec<u8>,
+/// A read waiting for a majority to confirm this node still leads
+struct PendingRead {
+    id: u64,
+    index: u64,
+    round: u64,
+}
+
+/// Point-in-time view of a node's Raft state
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RaftMetrics {
+    pub node_id: NodeId,
+    pub state: RaftState,
+    pub current_term: u64,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub log_len: u64,
+    /// Voting members, this node included
+    pub cluster_size: usize,
+    /// Linearizable reads this node has started (see `read_index`)
+    pub reads_started: u64,
+}
+
+/// A voting member of the cluster as this node last heard of it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Member {
+    pub node_id: NodeId,
+    pub address: Option<String>,
+    /// Peers other than the known leader are taken to follow
+    pub state: RaftState,
+    pub is_self: bool,
+    /// Whether the node is up, None if unknown: a leader knows which peers
+    /// answer it, and other nodes only know their own health
+    pub healthy: Option<bool>,
 }
 
 impl RaftNode {
+    /// A node keeping its state in memory only
     pub fn new(config: RaftConfig) -> Self {
-        Self {
+        let rng = match config.rng_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let now = Instant::now();
+        let mut node = Self {
             config,
             state: RaftState::Follower,
             current_term: 0,
             voted_for: None,
+            leader_id: None,
             log: Vec::new(),
             commit_index: 0,
             last_applied: 0,
+            membership: None,
+            votes: HashSet::new(),
             next_index: HashMap::new(),
             match_index: HashMap::new(),
-        }
+            acked_round: HashMap::new(),
+            last_heard: HashMap::new(),
+            round: 0,
+            term_start: 0,
+            reads: Vec::new(),
+            finished_reads: Vec::new(),
+            next_read: 1,
+            election_deadline: now,
+            heartbeat_due: now,
+            outbox: Vec::new(),
+            storage: None,
+            stopped: false,
+            rng,
+        };
+        node.membership = node.configured_membership();
+        node.reset_election_deadline();
+        node
+    }
+    
+    /// A node keeping its identity, term, vote and log under `dir`, with
+    /// whatever it stored there before
+    pub fn open(mut config: RaftConfig, dir: &Path) -> Result<Self> {
+        let (storage, stored) = RaftStorage::open(dir, config.node_id)?;
+        config.node_id = stored.node_id;
+        let mut node = Self::new(config);
+        node.current_term = stored.hard_state.term;
+        node.voted_for = stored.hard_state.voted_for;
+        node.log = stored.log;
+        node.storage = Some(storage);
+        node.refresh_membership();
+        Ok(node)
+    }
+    
+    pub fn id(&self) -> NodeId {
+        self.config.node_id
+    }
+    
+    pub fn config(&self) -> &RaftConfig {
+        &self.config
     }
     
     pub fn is_leader(&self) -> bool {
@@ -76,31 +293,575 @@ impl RaftNode {
         &self.state
     }
     
-    pub async fn propose(&mut self, data: Vec<u8>) -> Result<u64> {
-        if !self.is_leader() {
-            return Err(ConsensusError::NotLeader);
+    /// The leader of the current term: this node when it leads, otherwise
+    /// the last node heard from as leader, if any
+    pub fn leader(&self) -> Option<NodeId> {
+        if self.is_leader() {
+            Some(self.config.node_id)
+        } else {
+            self.leader_id
+        }
+    }
+    
+    /// The voters, or None for a joining node that has not yet heard it
+    /// was added
+    pub fn membership(&self) -> Option<&[MemberInfo]> {
+        self.membership.as_deref()
+    }
+    
+    pub fn member(&self, node_id: NodeId) -> Option<&MemberInfo> {
+        self.membership.as_ref()?.iter().find(|member| member.node_id == node_id)
+    }
+    
+    /// Record that `leader` leads `term`, as on hearing from it. A term
+    /// older than the current one is ignored; a newer one is adopted, and
+    /// this node follows.
+    pub fn observe_leader(&mut self, term: u64, leader: NodeId) {
+        if term < self.current_term {
+            return;
+        }
+        if term > self.current_term {
+            self.current_term = term;
+            self.voted_for = None;
+            if let Err(e) = self.persist_state() {
+                self.halt(e);
+                return;
+            }
+        }
+        if leader != self.config.node_id {
+            self.step_down();
+        }
+        self.leader_id = Some(leader);
+    }
+    
+    /// Leave the cluster for good, as on shutdown: the node steps down and
+    /// refuses proposals from then on
+    pub fn stop(&mut self) {
+        self.stopped = true;
+        self.step_down();
+        self.leader_id = None;
+    }
+    
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+    
+    /// Stand for election in a new term, voting for this node. A node
+    /// that is the only voter leads at once; otherwise it asks its peers
+    /// for votes and stays a candidate until it has a majority or hears
+    /// from a leader. Nodes that are not voters never stand. Returns whether
+    /// the node now leads.
+    pub fn campaign(&mut self) -> bool {
+        if self.stopped || self.member(self.id()).is_none() {
+            return false;
+        }
+        self.current_term += 1;
+        self.voted_for = Some(self.config.node_id);
+        self.leader_id = None;
+        self.state = RaftState::Candidate;
+        self.votes = HashSet::from([self.config.node_id]);
+        self.reset_election_deadline();
+        if let Err(e) = self.persist_state() {
+            self.halt(e);
+            return false;
         }
         
-        let index = self.log.len() as u64;
-        let entry = LogEntry {
-            term: self.current_term,
-            index,
-            data,
-        };
-        
-        self.log.push(entry);
-        
-        // In a real implementation, this would replicate to followers
-        Ok(index)
+        if self.votes.len() >= self.quorum() {
+            if let Err(e) = self.become_leader() {
+                self.halt(e);
+            }
+            return self.is_leader();
+        }
+        let (last_log_index, last_log_term) = (self.last_log_index(), self.last_log_term());
+        for peer in self.peers() {
+            self.send(peer, MessageBody::RequestVote { last_log_index, last_log_term });
+        }
+        false
+    }
+    
+    /// How long to wait for a heartbeat before standing for election, drawn
+    /// anew for each wait
+    pub fn next_election_timeout(&mut self) -> Duration {
+        let min = self.config.election_timeout_ms;
+        Duration::from_millis(self.rng.gen_range(min..=min.saturating_mul(2)))
+    }
+    
+    /// Let time pass: a leader sends heartbeats when they are due, and any
+    /// other voter stands for election once its timeout runs out
+    pub fn tick(&mut self) {
+        if self.stopped {
+            return;
+        }
+        let now = Instant::now();
+        if self.is_leader() {
+            if now >= self.heartbeat_due {
+                self.broadcast();
+            }
+        } else if now >= self.election_deadline && !self.campaign() && !self.stopped {
+            self.reset_election_deadline();
+        }
+    }
+    
+    /// Handle a message from a peer
+    pub fn step(&mut self, message: Message) -> Result<()> {
+        if self.stopped || message.to != self.config.node_id {
+            return Ok(());
+        }
+        if message.term > self.current_term {
+            self.current_term = message.term;
+            self.voted_for = None;
+            self.leader_id = None;
+            self.step_down();
+            self.persist_state()?;
+        }
+        let from = message.from;
+        match message.body {
+            MessageBody::RequestVote { last_log_index, last_log_term } => {
+                let up_to_date = (last_log_term, last_log_index) >= (self.last_log_term(), self.last_log_index());
+                let granted = message.term == self.current_term
+                    && up_to_date
+                    && self.voted_for.is_none_or(|vote| vote == from);
+                if granted {
+                    self.voted_for = Some(from);
+                    self.persist_state()?;
+                    self.reset_election_deadline();
+                }
+                self.send(from, MessageBody::Vote { granted });
+            }
+            MessageBody::Vote { granted } => {
+                if self.state == RaftState::Candidate && message.term == self.current_term && granted {
+                    self.votes.insert(from);
+                    let votes = self.votes.iter().filter(|&&voter| self.member(voter).is_some()).count();
+                    if votes >= self.quorum() {
+                        self.become_leader()?;
+                    }
+                }
+            }
+            MessageBody::AppendEntries { prev_log_index, prev_log_term, entries, leader_commit, round } => {
+                if message.term < self.current_term {
+                    self.send(from, MessageBody::AppendResponse { success: false, match_index: 0, round });
+                    return Ok(());
+                }
+                // A candidate in the leader's term gives up
+                self.step_down();
+                self.leader_id = Some(from);
+                self.reset_election_deadline();
+                
+                if prev_log_index > self.last_log_index() || self.term_at(prev_log_index) != prev_log_term {
+                    let hint = prev_log_index.saturating_sub(1).min(self.last_log_index());
+                    self.send(from, MessageBody::AppendResponse { success: false, match_index: hint, round });
+                    return Ok(());
+                }
+                let last_new = prev_log_index + entries.len() as u64;
+                // Skip entries already held, and drop the rest of the log
+                // from the first one that conflicts
+                let mut new = Vec::new();
+                for entry in entries {
+                    if new.is_empty() && entry.index <= self.last_log_index() {
+                        if self.term_at(entry.index) == entry.term {
+                            continue;
+                        }
+                        self.truncate_log(entry.index)?;
+                    }
+                    new.push(entry);
+                }
+                self.append_to_log(new)?;
+                self.commit_index = self.commit_index.max(leader_commit.min(last_new));
+                self.send(from, MessageBody::AppendResponse { success: true, match_index: last_new, round });
+            }
+            MessageBody::AppendResponse { success, match_index, round } => {
+                if !self.is_leader() || message.term != self.current_term {
+                    return Ok(());
+                }
+                self.last_heard.insert(from, Instant::now());
+                let acked = self.acked_round.entry(from).or_default();
+                *acked = (*acked).max(round);
+                if success {
+                    let matched = self.match_index.entry(from).or_default();
+                    *matched = (*matched).max(match_index);
+                    let matched = *matched;
+                    let next = self.next_index.entry(from).or_insert(matched + 1);
+                    *next = (*next).max(matched + 1);
+                    self.advance_commit();
+                    if self.next_index[&from] <= self.last_log_index() {
+                        self.send_append(from);
+                    }
+                } else {
+                    let next = self.next_index.entry(from).or_insert(1);
+                    *next = (match_index + 1).min(next.saturating_sub(1)).max(1);
+                    self.send_append(from);
+                }
+                self.check_reads();
+            }
+        }
+        Ok(())
+    }
+    
+    /// Messages for peers queued since the last call
+    pub fn take_messages(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.outbox)
+    }
+    
+    /// Entries committed since the last call, in log order, for the caller
+    /// to apply
+    pub fn take_committed(&mut self) -> Vec<LogEntry> {
+        let committed = self.log[self.last_applied as usize..self.commit_index as usize].to_vec();
+        self.last_applied = self.commit_index;
+        committed
+    }
+    
+    /// Treat entries up to `index` as committed and applied, as the state
+    /// machine records them on restart
+    pub fn restore_applied(&mut self, index: u64) {
+        let index = index.min(self.last_log_index());
+        self.last_applied = self.last_applied.max(index);
+        self.commit_index = self.commit_index.max(index);
+    }
+    
+    pub async fn propose(&mut self, data: Vec<u8>) -> Result<u64> {
+        self.propose_entry(data, None)
+    }
+    
+    /// Propose a client request; see `SessionStateMachine` for how retries
+    /// of it are applied once
+    pub async fn propose_request(&mut self, request: &ClientRequest) -> Result<u64> {
+        self.propose(request.encode()?).await
+    }
+    
+    /// Propose making `member` a voter, or updating its addresses if it is
+    /// one. Returns the index of the membership entry.
+    pub fn add_member(&mut self, member: MemberInfo) -> Result<u64> {
+        let mut members = self.membership.clone().unwrap_or_default();
+        members.retain(|existing| existing.node_id != member.node_id);
+        members.push(member);
+        self.change_membership(members)
+    }
+    
+    /// Propose removing a voter other than this node. Returns the index of
+    /// the membership entry.
+    pub fn remove_member(&mut self, node_id: NodeId) -> Result<u64> {
+        if node_id == self.config.node_id {
+            return Err(ConsensusError::Config("a leader cannot remove itself".to_string()));
+        }
+        let mut members = self.membership.clone().unwrap_or_default();
+        members.retain(|member| member.node_id != node_id);
+        self.change_membership(members)
+    }
+    
+    /// Start a linearizable read. Once `take_reads` reports it done with an
+    /// index, state applied up to that index reflects every write committed
+    /// before this call. Returns the read's id.
+    pub fn read_index(&mut self) -> Result<u64> {
+        self.check_proposable()?;
+        let id = self.next_read;
+        self.next_read += 1;
+        // Until the entry this term started with commits, earlier terms'
+        // entries may be committed without this node knowing
+        let index = self.commit_index.max(self.term_start);
+        self.broadcast();
+        self.reads.push(PendingRead { id, index, round: self.round });
+        self.check_reads();
+        Ok(id)
+    }
+    
+    /// Reads finished since the last call: each id with the index to read
+    /// at, or None if this node stopped leading first
+    pub fn take_reads(&mut self) -> Vec<(u64, Option<u64>)> {
+        std::mem::take(&mut self.finished_reads)
     }
     
     pub fn get_log_entry(&self, index: u64) -> Option<&LogEntry> {
-        self.log.get(index as usize)
+        self.log.get(index.checked_sub(1)? as usize)
     }
     
     pub fn log_len(&self) -> u64 {
         self.log.len() as u64
     }
+    
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+    
+    /// This node followed by its peers
+    pub fn members(&self) -> Vec<Member> {
+        let leader = self.leader();
+        let address = |node_id: NodeId| {
+            self.member(node_id)
+                .and_then(|member| member.api_address.clone())
+                .or_else(|| self.config.addresses.get(&node_id).cloned())
+        };
+        let healthy = |node_id: NodeId| {
+            let timeout = Duration::from_millis(self.config.election_timeout_ms.saturating_mul(2));
+            self.is_leader().then(|| self.last_heard.get(&node_id).is_some_and(|at| at.elapsed() < timeout))
+        };
+        let this = Member {
+            node_id: self.config.node_id,
+            address: address(self.config.node_id),
+            state: self.state.clone(),
+            is_self: true,
+            healthy: Some(!self.stopped),
+        };
+        let peers = self.peers().into_iter().map(|peer| Member {
+            node_id: peer,
+            address: address(peer),
+            state: if leader == Some(peer) { RaftState::Leader } else { RaftState::Follower },
+            is_self: false,
+            healthy: healthy(peer),
+        });
+        std::iter::once(this).chain(peers).collect()
+    }
+    
+    pub fn metrics(&self) -> RaftMetrics {
+        RaftMetrics {
+            node_id: self.config.node_id,
+            state: self.state.clone(),
+            current_term: self.current_term,
+            commit_index: self.commit_index,
+            last_applied: self.last_applied,
+            log_len: self.log_len(),
+            cluster_size: self.membership.as_ref().map_or(1, Vec::len),
+            reads_started: self.next_read - 1,
+        }
+    }
+    
+    fn configured_membership(&self) -> Option<Vec<MemberInfo>> {
+        if self.config.join.is_some() && self.config.peers.is_empty() {
+            return None;
+        }
+        let this = self.config.node_id;
+        let members = std::iter::once(this)
+            .chain(self.config.peers.iter().copied())
+            .map(|node_id| MemberInfo {
+                node_id,
+                raft_address: if node_id == this {
+                    self.config.raft_address.clone()
+                } else {
+                    self.config.peer_addresses.get(&node_id).cloned()
+                },
+                api_address: self.config.addresses.get(&node_id).cloned(),
+            })
+            .collect();
+        Some(members)
+    }
+    
+    /// Take the membership from the log's last membership entry, or the
+    /// config without one, and track the replication of new voters
+    fn refresh_membership(&mut self) {
+        self.membership = self.log.iter().rev()
+            .find_map(|entry| entry.membership.clone())
+            .or_else(|| self.configured_membership());
+        if self.is_leader() {
+            let next = self.last_log_index() + 1;
+            for peer in self.peers() {
+                self.next_index.entry(peer).or_insert(next);
+                self.match_index.entry(peer).or_insert(0);
+            }
+        }
+    }
+    
+    fn peers(&self) -> Vec<NodeId> {
+        let this = self.config.node_id;
+        self.membership.iter().flatten()
+            .map(|member| member.node_id)
+            .filter(|&node_id| node_id != this)
+            .collect()
+    }
+    
+    fn quorum(&self) -> usize {
+        self.membership.as_ref().map_or(1, Vec::len) / 2 + 1
+    }
+    
+    fn last_log_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+    
+    fn last_log_term(&self) -> u64 {
+        self.term_at(self.last_log_index())
+    }
+    
+    /// Term of the entry at `index`, 0 for none
+    fn term_at(&self, index: u64) -> u64 {
+        self.get_log_entry(index).map_or(0, |entry| entry.term)
+    }
+    
+    fn send(&mut self, to: NodeId, body: MessageBody) {
+        self.outbox.push(Message { from: self.config.node_id, to, term: self.current_term, body });
+    }
+    
+    fn reset_election_deadline(&mut self) {
+        self.election_deadline = Instant::now() + self.next_election_timeout();
+    }
+    
+    /// Stop leading or campaigning, failing reads still waiting
+    fn step_down(&mut self) {
+        self.state = RaftState::Follower;
+        self.votes.clear();
+        self.finished_reads.extend(self.reads.drain(..).map(|read| (read.id, None)));
+    }
+    
+    /// Stop for good after failing to persist state the protocol relies on
+    fn halt(&mut self, e: ConsensusError) {
+        error!("Raft node {} cannot persist its state and stops: {}", self.config.node_id.0, e);
+        self.stop();
+    }
+    
+    fn persist_state(&self) -> Result<()> {
+        match &self.storage {
+            Some(storage) => storage.save_state(&HardState { term: self.current_term, voted_for: self.voted_for }),
+            None => Ok(()),
+        }
+    }
+    
+    fn append_to_log(&mut self, entries: Vec<LogEntry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        if let Some(storage) = &mut self.storage {
+            storage.append(&entries)?;
+        }
+        let membership = entries.iter().any(|entry| entry.membership.is_some());
+        self.log.extend(entries);
+        if membership {
+            self.refresh_membership();
+        }
+        Ok(())
+    }
+    
+    /// Drop the entry at `index` and every one after it
+    fn truncate_log(&mut self, index: u64) -> Result<()> {
+        if let Some(storage) = &mut self.storage {
+            storage.truncate(index)?;
+        }
+        self.log.truncate(index.saturating_sub(1) as usize);
+        self.refresh_membership();
+        Ok(())
+    }
+    
+    fn become_leader(&mut self) -> Result<()> {
+        self.state = RaftState::Leader;
+        self.leader_id = Some(self.config.node_id);
+        self.votes.clear();
+        self.next_index.clear();
+        self.match_index.clear();
+        self.acked_round.clear();
+        self.last_heard.clear();
+        self.refresh_membership();
+        // Earlier terms' entries only commit along with one of this term.
+        // The first leader also records the configured membership, which
+        // the log keeps from then on.
+        let membership = match self.log.iter().any(|entry| entry.membership.is_some()) {
+            true => None,
+            false => self.membership.clone(),
+        };
+        self.term_start = self.append_new(Vec::new(), membership)?;
+        self.broadcast();
+        Ok(())
+    }
+    
+    fn check_proposable(&self) -> Result<()> {
+        if self.stopped {
+            return Err(ConsensusError::Stopped);
+        }
+        if !self.is_leader() {
+            return Err(ConsensusError::NotLeader);
+        }
+        Ok(())
+    }
+    
+    fn propose_entry(&mut self, data: Vec<u8>, membership: Option<Vec<MemberInfo>>) -> Result<u64> {
+        self.check_proposable()?;
+        let index = self.append_new(data, membership)?;
+        for peer in self.peers() {
+            self.send_append(peer);
+        }
+        Ok(index)
+    }
+    
+    fn change_membership(&mut self, members: Vec<MemberInfo>) -> Result<u64> {
+        self.check_proposable()?;
+        // One voter at a time keeps the old and new majorities overlapping
+        let pending = self.log[self.commit_index as usize..].iter().any(|entry| entry.membership.is_some());
+        if pending {
+            return Err(ConsensusError::Config("another membership change is in progress".to_string()));
+        }
+        self.propose_entry(Vec::new(), Some(members))
+    }
+    
+    /// Append an entry of the current term, committing it at once if this
+    /// node is the only voter
+    fn append_new(&mut self, data: Vec<u8>, membership: Option<Vec<MemberInfo>>) -> Result<u64> {
+        let index = self.last_log_index() + 1;
+        self.append_to_log(vec![LogEntry { term: self.current_term, index, data, membership }])?;
+        self.advance_commit();
+        Ok(index)
+    }
+    
+    /// Send every peer what it is missing, or a heartbeat, in a new round
+    fn broadcast(&mut self) {
+        self.round += 1;
+        self.heartbeat_due = Instant::now() + Duration::from_millis(self.config.heartbeat_interval_ms);
+        for peer in self.peers() {
+            self.send_append(peer);
+        }
+        self.check_reads();
+    }
+    
+    /// Send `peer` the entries after the last one it is thought to hold,
+    /// expecting it to take them
+    fn send_append(&mut self, peer: NodeId) {
+        let next = *self.next_index.entry(peer).or_insert(1);
+        let prev_log_index = next - 1;
+        let end = self.log.len().min(prev_log_index as usize + MAX_ENTRIES_PER_MESSAGE);
+        let entries = self.log[prev_log_index as usize..end].to_vec();
+        self.next_index.insert(peer, end as u64 + 1);
+        let body = MessageBody::AppendEntries {
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index),
+            entries,
+            leader_commit: self.commit_index,
+            round: self.round,
+        };
+        self.send(peer, body);
+    }
+    
+    /// Commit up to the highest entry of this term a majority holds
+    fn advance_commit(&mut self) {
+        if !self.is_leader() {
+            return;
+        }
+        let this = self.config.node_id;
+        let mut matched: Vec<u64> = self.membership.iter().flatten()
+            .map(|member| match member.node_id == this {
+                true => self.last_log_index(),
+                false => self.match_index.get(&member.node_id).copied().unwrap_or(0),
+            })
+            .collect();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let Some(&majority) = matched.get(self.quorum() - 1) else {
+            return;
+        };
+        if majority > self.commit_index && self.term_at(majority) == self.current_term {
+            self.commit_index = majority;
+        }
+    }
+    
+    /// Finish the reads whose heartbeat round a majority has answered
+    fn check_reads(&mut self) {
+        if self.reads.is_empty() {
+            return;
+        }
+        let this = self.config.node_id;
+        let quorum = self.quorum();
+        let voters: Vec<NodeId> = self.membership.iter().flatten().map(|member| member.node_id).collect();
+        let acked = |round: u64| {
+            voters.iter()
+                .filter(|&&voter| voter == this || self.acked_round.get(&voter).is_some_and(|&acked| acked >= round))
+                .count() >= quorum
+        };
+        let (done, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.reads).into_iter().partition(|read| acked(read.round));
+        self.reads = waiting;
+        self.finished_reads.extend(done.into_iter().map(|read| (read.id, Some(read.index))));
+    }
 }
 
 #[cfg(test)]
@@ -112,14 +873,31 @@ mod tests {
         let config = RaftConfig {
             node_id: NodeId::new(),
             peers: vec![NodeId::new(), NodeId::new()],
+            addresses: HashMap::new(),
             election_timeout_ms: 150,
             heartbeat_interval_ms: 50,
+            rng_seed: None,
+            ..Default::default()
         };
         
-        let node = RaftNode::new(config);
+        let peer = config.peers[0];
+        let mut node = RaftNode::new(config);
         assert_eq!(node.state(), &RaftState::Follower);
         assert_eq!(node.current_term(), 0);
         assert!(!node.is_leader());
+        assert_eq!(node.leader(), None);
+        
+        let metrics = node.metrics();
+        assert_eq!(metrics.state, RaftState::Follower);
+        assert_eq!((metrics.commit_index, metrics.log_len, metrics.cluster_size), (0, 0, 3));
+        
+        // A leader of a stale term is not believed
+        node.observe_leader(2, peer);
+        assert_eq!((node.leader(), node.current_term()), (Some(peer), 2));
+        node.observe_leader(1, NodeId::new());
+        assert_eq!(node.leader(), Some(peer));
+        node.stop();
+        assert_eq!(node.leader(), None);
     }
     
     #[tokio::test]
@@ -127,12 +905,261 @@ mod tests {
         let config = RaftConfig {
             node_id: NodeId::new(),
             peers: vec![],
+            addresses: HashMap::new(),
             election_timeout_ms: 150,
             heartbeat_interval_ms: 50,
+            rng_seed: None,
+            ..Default::default()
         };
         
         let mut node = RaftNode::new(config);
         let result = node.propose(b"test data".to_vec()).await;
         assert!(matches!(result, Err(ConsensusError::NotLeader)));
+        
+        node.stop();
+        assert!(node.is_stopped());
+        let result = node.propose(b"test data".to_vec()).await;
+        assert!(matches!(result, Err(ConsensusError::Stopped)));
+    }
+    
+    #[test]
+    fn test_campaign_and_members() {
+        let alone = NodeId::new();
+        let mut node = RaftNode::new(RaftConfig {
+            node_id: alone,
+            addresses: HashMap::from([(alone, "10.0.0.1:8080".to_string())]),
+            ..RaftConfig::default()
+        });
+        assert!(node.campaign());
+        assert_eq!((node.leader(), node.current_term()), (Some(alone), 1));
+        let members = node.members();
+        assert_eq!(members.len(), 1);
+        assert_eq!((members[0].state.clone(), members[0].address.as_deref()), (RaftState::Leader, Some("10.0.0.1:8080")));
+        assert_eq!((members[0].is_self, members[0].healthy), (true, Some(true)));
+        
+        // With peers a candidate needs their votes, and learns of a leader
+        // from them
+        let peers = vec![NodeId::new(), NodeId::new()];
+        let mut node = RaftNode::new(RaftConfig { peers: peers.clone(), ..RaftConfig::default() });
+        assert!(!node.campaign());
+        assert_eq!((node.state(), node.leader()), (&RaftState::Candidate, None));
+        node.observe_leader(1, peers[1]);
+        let members = node.members();
+        let states: Vec<_> = members.iter().map(|member| member.state.clone()).collect();
+        assert_eq!(states, [RaftState::Follower, RaftState::Follower, RaftState::Leader]);
+        assert_eq!(members[2].node_id, peers[1]);
+        assert!(members[1..].iter().all(|member| member.healthy.is_none() && member.address.is_none()));
+        
+        node.stop();
+        assert!(!node.campaign());
+        assert_eq!(node.members()[0].healthy, Some(false));
+    }
+    
+    #[test]
+    fn test_seeded_election_timeouts() {
+        let node = |rng_seed| RaftNode::new(RaftConfig {
+            election_timeout_ms: 150,
+            rng_seed,
+            ..RaftConfig::default()
+        });
+        let timeouts = |mut node: RaftNode| -> Vec<Duration> {
+            (0..20).map(|_| node.next_election_timeout()).collect()
+        };
+        
+        let seeded = timeouts(node(Some(7)));
+        assert_eq!(seeded, timeouts(node(Some(7))));
+        assert_ne!(seeded, timeouts(node(Some(8))));
+        assert!(seeded.iter().all(|t| (150..=300).contains(&t.as_millis())));
+        assert!(seeded.windows(2).any(|pair| pair[0] != pair[1]));
+    }
+    
+    #[test]
+    fn test_election_timeouts_uniform() {
+        let mut node = RaftNode::new(RaftConfig {
+            election_timeout_ms: 100,
+            rng_seed: Some(1),
+            ..RaftConfig::default()
+        });
+        
+        // 101 possible timeouts in ten buckets of about 10 each; each bucket
+        // should get close to a tenth of the draws
+        let samples = 100_000;
+        let mut buckets = [0u32; 10];
+        for _ in 0..samples {
+            let millis = node.next_election_timeout().as_millis() as usize;
+            assert!((100..=200).contains(&millis));
+            buckets[((millis - 100) / 10).min(9)] += 1;
+        }
+        let expected = samples as f64 / 10.0;
+        for (i, &count) in buckets.iter().enumerate() {
+            let share = if i == 9 { 11.0 / 10.1 } else { 10.0 / 10.1 };
+            let deviation = (count as f64 - expected * share).abs() / (expected * share);
+            assert!(deviation < 0.05, "bucket {} has {} draws: {:?}", i, count, buckets);
+        }
+    }
+    
+    /// Nodes exchanging messages in memory, where a node that is down
+    /// neither sends nor receives
+    struct Cluster {
+        nodes: Vec<RaftNode>,
+        down: HashSet<NodeId>,
+    }
+    
+    impl Cluster {
+        fn new(size: usize) -> Self {
+            let ids: Vec<NodeId> = (0..size).map(|_| NodeId::new()).collect();
+            let nodes = ids.iter()
+                .map(|&node_id| RaftNode::new(RaftConfig {
+                    node_id,
+                    peers: ids.iter().copied().filter(|&id| id != node_id).collect(),
+                    ..RaftConfig::default()
+                }))
+                .collect();
+            Self { nodes, down: HashSet::new() }
+        }
+        
+        fn node(&mut self, id: NodeId) -> &mut RaftNode {
+            self.nodes.iter_mut().find(|node| node.id() == id).unwrap()
+        }
+        
+        /// Deliver messages until none are left
+        fn deliver(&mut self) {
+            loop {
+                let messages: Vec<Message> = self.nodes.iter_mut().flat_map(RaftNode::take_messages).collect();
+                if messages.is_empty() {
+                    return;
+                }
+                for message in messages {
+                    if !self.down.contains(&message.from) && !self.down.contains(&message.to) {
+                        let to = message.to;
+                        self.node(to).step(message).unwrap();
+                    }
+                }
+            }
+        }
+        
+        fn commands(node: &mut RaftNode) -> Vec<Vec<u8>> {
+            node.take_committed().into_iter().map(|entry| entry.data).filter(|data| !data.is_empty()).collect()
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_election_and_replication() {
+        let mut cluster = Cluster::new(3);
+        let ids: Vec<NodeId> = cluster.nodes.iter().map(RaftNode::id).collect();
+        assert!(!cluster.nodes[0].campaign());
+        cluster.deliver();
+        assert!(cluster.nodes[0].is_leader());
+        assert!(cluster.nodes[1..].iter().all(|node| node.leader() == Some(ids[0])));
+        
+        cluster.nodes[0].propose(b"a".to_vec()).await.unwrap();
+        cluster.deliver();
+        assert_eq!(Cluster::commands(&mut cluster.nodes[0]), vec![b"a".to_vec()]);
+        // Followers learn of the commit with the next heartbeat
+        assert!(Cluster::commands(&mut cluster.nodes[1]).is_empty());
+        cluster.nodes[0].broadcast();
+        cluster.deliver();
+        assert_eq!(Cluster::commands(&mut cluster.nodes[1]), vec![b"a".to_vec()]);
+        assert!(cluster.nodes[0].members()[1..].iter().all(|member| member.healthy == Some(true)));
+        
+        // Node 2 misses "b", so it cannot win an election against node 1
+        cluster.down.insert(ids[2]);
+        cluster.nodes[0].propose(b"b".to_vec()).await.unwrap();
+        cluster.deliver();
+        cluster.down = HashSet::from([ids[0]]);
+        assert!(!cluster.nodes[2].campaign());
+        cluster.deliver();
+        assert!(!cluster.nodes[2].is_leader());
+        
+        // Node 1 has it and wins without the old leader, which then catches up
+        assert!(!cluster.nodes[1].campaign());
+        cluster.deliver();
+        assert!(cluster.nodes[1].is_leader());
+        cluster.nodes[1].propose(b"c".to_vec()).await.unwrap();
+        cluster.deliver();
+        cluster.down.clear();
+        cluster.nodes[0].broadcast();
+        cluster.nodes[1].broadcast();
+        cluster.deliver();
+        assert_eq!(cluster.nodes[0].leader(), Some(ids[1]));
+        cluster.nodes[1].broadcast();
+        cluster.deliver();
+        let log_len = cluster.nodes[1].log_len();
+        assert!(cluster.nodes.iter().all(|node| node.log_len() == log_len));
+        assert_eq!(Cluster::commands(&mut cluster.nodes[0]), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(Cluster::commands(&mut cluster.nodes[2]), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert!(matches!(cluster.nodes[0].propose(b"d".to_vec()).await, Err(ConsensusError::NotLeader)));
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_join_and_read_index() {
+        let mut cluster = Cluster { nodes: vec![RaftNode::new(RaftConfig::default())], down: HashSet::new() };
+        assert!(cluster.nodes[0].campaign());
+        let read = cluster.nodes[0].read_index().unwrap();
+        assert_eq!(cluster.nodes[0].take_reads(), vec![(read, Some(1))]);
+        assert_eq!(cluster.nodes[0].metrics().reads_started, 1);
+        
+        // A joining node knows no members and never stands for election
+        let joiner = NodeId::new();
+        cluster.nodes.push(RaftNode::new(RaftConfig {
+            node_id: joiner,
+            join: Some("10.0.0.1:7080".to_string()),
+            ..RaftConfig::default()
+        }));
+        assert!(cluster.nodes[1].membership().is_none());
+        assert!(!cluster.nodes[1].campaign());
+        
+        let member = MemberInfo { node_id: joiner, raft_address: Some("10.0.0.2:7080".to_string()), api_address: None };
+        let index = cluster.nodes[0].add_member(member.clone()).unwrap();
+        assert!(matches!(cluster.nodes[0].add_member(member.clone()), Err(ConsensusError::Config(_))));
+        assert_eq!(cluster.nodes[0].metrics().cluster_size, 2);
+        cluster.deliver();
+        assert_eq!(cluster.nodes[0].commit_index(), index);
+        assert_eq!(cluster.nodes[1].member(joiner), Some(&member));
+        assert_eq!(cluster.nodes[1].leader(), Some(cluster.nodes[0].id()));
+        
+        // With two voters a read needs the other one to confirm
+        let read = cluster.nodes[0].read_index().unwrap();
+        assert!(cluster.nodes[0].take_reads().is_empty());
+        cluster.deliver();
+        assert_eq!(cluster.nodes[0].take_reads(), vec![(read, Some(index))]);
+        let read = cluster.nodes[0].read_index().unwrap();
+        cluster.nodes[0].stop();
+        assert_eq!(cluster.nodes[0].take_reads(), vec![(read, None)]);
+        assert!(matches!(cluster.nodes[0].read_index(), Err(ConsensusError::Stopped)));
+    }
+    
+    #[tokio::test]
+    async fn test_state_survives_restart() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut node = RaftNode::open(RaftConfig::default(), temp_dir.path()).unwrap();
+        let id = node.id();
+        assert!(node.campaign());
+        node.propose(b"a".to_vec()).await.unwrap();
+        drop(node);
+        
+        // The identity is the stored one whatever the config says, and the
+        // membership comes from the log
+        let node = RaftNode::open(RaftConfig::default(), temp_dir.path()).unwrap();
+        assert_eq!((node.id(), node.current_term(), node.log_len()), (id, 1, 2));
+        assert_eq!(node.get_log_entry(2).unwrap().data, b"a");
+        assert_eq!(node.membership().unwrap().len(), 1);
+        drop(node);
+        
+        // A torn final entry is dropped
+        let mut log = std::fs::OpenOptions::new().append(true).open(temp_dir.path().join("log")).unwrap();
+        std::io::Write::write_all(&mut log, &[0, 0, 1, 0, b'{']).unwrap();
+        let mut node = RaftNode::open(RaftConfig::default(), temp_dir.path()).unwrap();
+        assert_eq!(node.log_len(), 2);
+        node.restore_applied(2);
+        assert!(node.take_committed().is_empty());
+        assert!(node.campaign());
+        node.propose(b"b".to_vec()).await.unwrap();
+        drop(node);
+        let mut node = RaftNode::open(RaftConfig::default(), temp_dir.path()).unwrap();
+        assert_eq!((node.current_term(), node.log_len()), (2, 4));
+        assert!(node.campaign());
+        let commands: Vec<_> = node.take_committed().into_iter().map(|entry| entry.data).collect();
+        assert_eq!(commands, vec![Vec::new(), b"a".to_vec(), Vec::new(), b"b".to_vec(), Vec::new()]);
+    }
+}
@@ -0,0 +1,153 @@
+//! Client sessions, which make retried requests take effect once.
+//!
+//! A client tags each request with its session id and a serial number that
+//! grows with every new request. A request can reach the log more than once
+//! when the client retries it, for example after a leader fails before
+//! answering. The state machine remembers the last serial it applied for each
+//! session with that request's result. A duplicate gets the cached result
+//! instead of being applied again, so every replica applies it exactly once.
+
+use crate::error::{ConsensusError, Result};
+use crate::raft::LogEntry;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SessionId(pub Uuid);
+
+impl SessionId {
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl Default for SessionId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A command as a client submits it, carried in a log entry's data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientRequest {
+    pub session: SessionId,
+    /// Greater than the serial of every earlier request in the session;
+    /// a retry reuses its original serial
+    pub serial: u64,
+    pub command: Vec<u8>,
+}
+
+impl ClientRequest {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+/// Applies committed commands. Must be deterministic, since every replica
+/// applies the same commands in the same order.
+pub trait StateMachine {
+    fn apply(&mut self, command: &[u8]) -> Vec<u8>;
+}
+
+/// Wraps a state machine so each client request is applied at most once
+pub struct SessionStateMachine<S> {
+    inner: S,
+    sessions: HashMap<SessionId, LastApplied>,
+}
+
+struct LastApplied {
+    serial: u64,
+    response: Vec<u8>,
+}
+
+impl<S: StateMachine> SessionStateMachine<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, sessions: HashMap::new() }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Apply a request, or return the cached result if it was applied before.
+    /// Fails for a request older than the session's last one, whose result
+    /// is no longer kept.
+    pub fn apply(&mut self, request: &ClientRequest) -> Result<Vec<u8>> {
+        if let Some(last) = self.sessions.get(&request.session) {
+            if request.serial == last.serial {
+                return Ok(last.response.clone());
+            }
+            if request.serial < last.serial {
+                return Err(ConsensusError::StaleRequest { serial: request.serial, last_applied: last.serial });
+            }
+        }
+
+        let response = self.inner.apply(&request.command);
+        self.sessions.insert(request.session, LastApplied { serial: request.serial, response: response.clone() });
+        Ok(response)
+    }
+
+    /// Apply the client request held in a committed log entry
+    pub fn apply_entry(&mut self, entry: &LogEntry) -> Result<Vec<u8>> {
+        self.apply(&ClientRequest::decode(&entry.data)?)
+    }
+
+    /// Last serial applied for a session, if it has applied any
+    pub fn last_serial(&self, session: SessionId) -> Option<u64> {
+        self.sessions.get(&session).map(|last| last.serial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Adds each command's value to a running total and returns the total
+    #[derive(Default)]
+    struct Counter {
+        total: u64,
+        applied: usize,
+    }
+
+    impl StateMachine for Counter {
+        fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+            self.total += u64::from_be_bytes(command.try_into().unwrap());
+            self.applied += 1;
+            self.total.to_be_bytes().to_vec()
+        }
+    }
+
+    fn request(session: SessionId, serial: u64, amount: u64) -> ClientRequest {
+        ClientRequest { session, serial, command: amount.to_be_bytes().to_vec() }
+    }
+
+    #[test]
+    fn test_duplicate_request_applies_once() {
+        let mut machine = SessionStateMachine::new(Counter::default());
+        let session = SessionId::new();
+
+        let first = machine.apply(&request(session, 1, 5)).unwrap();
+        // A retry lands in the log again after the original
+        let entry = LogEntry { term: 2, index: 7, data: request(session, 1, 5).encode().unwrap(), membership: None };
+        let retried = machine.apply_entry(&entry).unwrap();
+        assert_eq!(retried, first);
+        assert_eq!((machine.inner().total, machine.inner().applied), (5, 1));
+
+        // New serials apply, in this session and independently in others
+        machine.apply(&request(session, 2, 3)).unwrap();
+        let other = SessionId::new();
+        assert_eq!(machine.apply(&request(other, 1, 10)).unwrap(), 18u64.to_be_bytes());
+        assert_eq!((machine.inner().total, machine.inner().applied), (18, 3));
+        assert_eq!(machine.last_serial(session), Some(2));
+
+        // The result of an earlier request is gone once a later one applied
+        assert!(matches!(machine.apply(&request(session, 1, 5)),
+            Err(ConsensusError::StaleRequest { serial: 1, last_applied: 2 })));
+        assert_eq!(machine.inner().applied, 3);
+    }
+}
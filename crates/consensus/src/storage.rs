@@ -0,0 +1,142 @@
+//! Raft state kept on disk, so a node restarts with the identity, term,
+//! vote and log it had.
+//!
+//! A node's directory holds:
+//!
+//! - `node_id`: the node's id, written when the directory is first used
+//! - `state.json`: the current term and the vote cast in it
+//! - `log`: the log entries, each a u32 BE length and the entry as JSON
+//!
+//! Every change is synced before the node acts on it, since a vote or an
+//! acknowledged entry must survive a crash.
+
+use crate::error::{ConsensusError, Result};
+use crate::raft::{LogEntry, NodeId};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use uuid::Uuid;
+
+/// The state a node must not forget between terms
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct HardState {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+}
+
+/// Everything read back from a node's directory
+pub(crate) struct Stored {
+    pub node_id: NodeId,
+    pub hard_state: HardState,
+    pub log: Vec<LogEntry>,
+}
+
+pub(crate) struct RaftStorage {
+    dir: PathBuf,
+    log: File,
+    // Where each entry starts in the log file; entry i has index i + 1
+    offsets: Vec<u64>,
+    end: u64,
+}
+
+impl RaftStorage {
+    /// Open `dir`, creating it if needed, and read back what it holds. The
+    /// directory keeps the first node id it is opened with; `node_id` is
+    /// ignored after that.
+    pub(crate) fn open(dir: &Path, node_id: NodeId) -> Result<(Self, Stored)> {
+        fs::create_dir_all(dir)?;
+        let id_path = dir.join("node_id");
+        let node_id = match fs::read_to_string(&id_path) {
+            Ok(text) => {
+                let id = Uuid::parse_str(text.trim())
+                    .map_err(|e| ConsensusError::Internal(format!("{}: {}", id_path.display(), e)))?;
+                NodeId(id)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                write_atomic(dir, "node_id", node_id.0.to_string().as_bytes())?;
+                node_id
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let hard_state = match fs::read(dir.join("state.json")) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut log = OpenOptions::new().read(true).append(true).create(true).open(dir.join("log"))?;
+        let mut bytes = Vec::new();
+        log.read_to_end(&mut bytes)?;
+        let mut entries: Vec<LogEntry> = Vec::new();
+        let mut offsets = Vec::new();
+        let mut end = 0;
+        while end < bytes.len() {
+            let record = bytes.get(end..end + 4)
+                .map(|len| end + 4 + u32::from_be_bytes(len.try_into().expect("four bytes")) as usize)
+                .and_then(|record_end| Some((record_end, bytes.get(end + 4..record_end)?)))
+                .and_then(|(record_end, json)| Some((record_end, serde_json::from_slice::<LogEntry>(json).ok()?)))
+                .filter(|(_, entry)| entry.index == entries.len() as u64 + 1);
+            let Some((record_end, entry)) = record else {
+                // A write cut short by a crash; nothing after it was acknowledged
+                warn!("Raft log {} ends in a torn entry at byte {}; dropping it", dir.display(), end);
+                break;
+            };
+            offsets.push(end as u64);
+            entries.push(entry);
+            end = record_end;
+        }
+        if end < bytes.len() {
+            log.set_len(end as u64)?;
+            log.sync_all()?;
+        }
+        log.seek(SeekFrom::End(0))?;
+
+        let storage = Self { dir: dir.to_path_buf(), log, offsets, end: end as u64 };
+        Ok((storage, Stored { node_id, hard_state, log: entries }))
+    }
+
+    pub(crate) fn save_state(&self, state: &HardState) -> Result<()> {
+        write_atomic(&self.dir, "state.json", &serde_json::to_vec(state)?)
+    }
+
+    /// Append entries following the last one stored
+    pub(crate) fn append(&mut self, entries: &[LogEntry]) -> Result<()> {
+        let mut bytes = Vec::new();
+        for entry in entries {
+            let json = serde_json::to_vec(entry)?;
+            self.offsets.push(self.end + bytes.len() as u64);
+            bytes.extend_from_slice(&(json.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&json);
+        }
+        self.log.write_all(&bytes)?;
+        self.log.sync_data()?;
+        self.end += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Drop the entry at `index` and every one after it
+    pub(crate) fn truncate(&mut self, index: u64) -> Result<()> {
+        let Some(&offset) = self.offsets.get(index.saturating_sub(1) as usize) else {
+            return Ok(());
+        };
+        self.log.set_len(offset)?;
+        self.log.sync_data()?;
+        self.offsets.truncate(index.saturating_sub(1) as usize);
+        self.end = offset;
+        Ok(())
+    }
+}
+
+/// Replace `dir/name` with `bytes` so a crash leaves the old or the new
+/// contents, never a mix
+fn write_atomic(dir: &Path, name: &str, bytes: &[u8]) -> Result<()> {
+    let temp = dir.join(format!("{}.tmp", name));
+    let mut file = File::create(&temp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&temp, dir.join(name))?;
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
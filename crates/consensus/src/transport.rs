@@ -0,0 +1,229 @@
+//! Raft messages, join requests and forwarded client requests between
+//! nodes, over TCP.
+//!
+//! Each frame is a u32 BE length followed by a `Frame` as JSON. Messages for
+//! a peer go out in the background over a connection kept open to it, and
+//! are dropped while the peer cannot be reached; Raft retries whatever is
+//! lost. A join request or forwarded request opens a connection of its own
+//! and waits there for the answer.
+
+use crate::error::{ConsensusError, Result};
+use crate::raft::{MemberInfo, Message};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Instant};
+use tracing::{debug, warn};
+
+/// Largest frame accepted, which bounds the entries one message can carry
+const MAX_FRAME_BYTES: usize = 64 * 1024 * 1024;
+/// Messages queued for a peer or from peers before more are dropped
+const QUEUE_LEN: usize = 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to drop a peer's messages after failing to connect to it
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Frame {
+    Raft(Message),
+    Join(MemberInfo),
+    JoinReply(JoinResponse),
+    Forward(String),
+    ForwardReply(String),
+}
+
+/// A member's answer to a node asking to join
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum JoinResponse {
+    /// The node is a voter, or will be once the change commits. Until its
+    /// log catches up, it reaches the other voters at their addresses here.
+    Accepted { members: Vec<MemberInfo> },
+    /// Only the leader adds members; it is at this Raft address, if known
+    NotLeader { leader: Option<String> },
+    Rejected(String),
+}
+
+/// What arrives from peers
+#[derive(Debug)]
+pub enum Inbound {
+    Message(Message),
+    Join { member: MemberInfo, reply: oneshot::Sender<JoinResponse> },
+    /// A client request a follower passed on, in a form the transport
+    /// leaves to its users, to answer in kind
+    Forward { request: String, reply: oneshot::Sender<String> },
+}
+
+pub struct Transport {
+    local_addr: SocketAddr,
+    peers: Mutex<HashMap<String, mpsc::Sender<Message>>>,
+    accept: JoinHandle<()>,
+}
+
+impl Transport {
+    /// Listen on `address`, handing what peers send to the receiver
+    pub async fn bind(address: &str) -> Result<(Self, mpsc::Receiver<Inbound>)> {
+        let listener = TcpListener::bind(address).await?;
+        let local_addr = listener.local_addr()?;
+        let (inbound, receiver) = mpsc::channel(QUEUE_LEN);
+        let accept = tokio::spawn(accept(listener, inbound));
+        Ok((Self { local_addr, peers: Mutex::new(HashMap::new()), accept }, receiver))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Queue `message` for the node listening at `address`
+    pub fn send(&self, address: &str, message: Message) {
+        let mut peers = self.peers.lock().unwrap();
+        let queue = peers.entry(address.to_string()).or_insert_with(|| {
+            let (queue, messages) = mpsc::channel(QUEUE_LEN);
+            tokio::spawn(deliver(address.to_string(), messages));
+            queue
+        });
+        if queue.try_send(message).is_err() {
+            debug!("Dropping a Raft message for {}: its queue is full", address);
+        }
+    }
+
+    /// Ask the member listening at `address` to add `member` as a voter
+    pub async fn join(address: &str, member: MemberInfo) -> Result<JoinResponse> {
+        let mut stream = connect(address).await?;
+        write_frame(&mut stream, &Frame::Join(member)).await?;
+        match read_frame(&mut stream).await? {
+            Some(Frame::JoinReply(response)) => Ok(response),
+            _ => Err(ConsensusError::Network(format!("{} did not answer the join request", address))),
+        }
+    }
+
+    /// Pass `request` on to the node listening at `address` and wait for
+    /// its answer
+    pub async fn forward(address: &str, request: String) -> Result<String> {
+        let mut stream = connect(address).await?;
+        write_frame(&mut stream, &Frame::Forward(request)).await?;
+        match read_frame(&mut stream).await? {
+            Some(Frame::ForwardReply(response)) => Ok(response),
+            _ => Err(ConsensusError::Network(format!("{} did not answer the forwarded request", address))),
+        }
+    }
+}
+
+impl Drop for Transport {
+    fn drop(&mut self) {
+        self.accept.abort();
+    }
+}
+
+async fn accept(listener: TcpListener, inbound: mpsc::Sender<Inbound>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let inbound = inbound.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, inbound).await {
+                        debug!("Raft connection from {} failed: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => warn!("Cannot accept a Raft connection: {}", e),
+        }
+    }
+}
+
+async fn serve(mut stream: TcpStream, inbound: mpsc::Sender<Inbound>) -> Result<()> {
+    while let Some(frame) = read_frame(&mut stream).await? {
+        match frame {
+            Frame::Raft(message) => {
+                if inbound.send(Inbound::Message(message)).await.is_err() {
+                    return Ok(());
+                }
+            }
+            Frame::Join(member) => {
+                let (reply, answer) = oneshot::channel();
+                if inbound.send(Inbound::Join { member, reply }).await.is_err() {
+                    return Ok(());
+                }
+                let response = answer.await.unwrap_or_else(|_| JoinResponse::Rejected("the node is stopping".to_string()));
+                write_frame(&mut stream, &Frame::JoinReply(response)).await?;
+            }
+            Frame::Forward(request) => {
+                let (reply, answer) = oneshot::channel();
+                if inbound.send(Inbound::Forward { request, reply }).await.is_err() {
+                    return Ok(());
+                }
+                // Without an answer the connection closes, failing the request
+                let Ok(response) = answer.await else {
+                    return Ok(());
+                };
+                write_frame(&mut stream, &Frame::ForwardReply(response)).await?;
+            }
+            Frame::JoinReply(_) | Frame::ForwardReply(_) => {
+                return Err(ConsensusError::Network("unexpected reply".to_string()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write queued messages to `address` until the transport is dropped
+async fn deliver(address: String, mut messages: mpsc::Receiver<Message>) {
+    let mut stream = None;
+    let mut retry_at = Instant::now();
+    while let Some(message) = messages.recv().await {
+        if stream.is_none() && Instant::now() >= retry_at {
+            match connect(&address).await {
+                Ok(connected) => stream = Some(connected),
+                Err(e) => {
+                    debug!("Cannot reach Raft peer {}: {}", address, e);
+                    retry_at = Instant::now() + RECONNECT_DELAY;
+                }
+            }
+        }
+        let Some(connected) = &mut stream else {
+            continue;
+        };
+        if let Err(e) = write_frame(connected, &Frame::Raft(message)).await {
+            debug!("Lost the connection to Raft peer {}: {}", address, e);
+            stream = None;
+        }
+    }
+}
+
+async fn connect(address: &str) -> Result<TcpStream> {
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await
+        .map_err(|_| ConsensusError::Network(format!("connecting to {} timed out", address)))??;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+async fn write_frame(stream: &mut TcpStream, frame: &Frame) -> Result<()> {
+    let json = serde_json::to_vec(frame)?;
+    let mut bytes = Vec::with_capacity(4 + json.len());
+    bytes.extend_from_slice(&(json.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&json);
+    stream.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// The next frame, or None if the peer closed the connection between frames
+async fn read_frame(stream: &mut TcpStream) -> Result<Option<Frame>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_BYTES {
+        return Err(ConsensusError::Network(format!("frame of {} bytes is over the limit", len)));
+    }
+    let mut json = vec![0; len];
+    stream.read_exact(&mut json).await?;
+    Ok(Some(serde_json::from_slice(&json)?))
+}
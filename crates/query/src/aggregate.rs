@@ -0,0 +1,160 @@
+use crate::{
+    error::{Result, QueryError},
+    ast::AggregateFunc,
+    value::Value,
+};
+use std::cmp::Ordering;
+
+/// Running state of one aggregate function over a group
+#[derive(Debug, Clone)]
+pub enum Accumulator {
+    Count(i64),
+    Sum(Option<Value>),
+    Avg { sum: f64, count: i64 },
+    Min(Option<Value>),
+    Max(Option<Value>),
+}
+
+impl Accumulator {
+    pub fn new(func: AggregateFunc) -> Self {
+        match func {
+            AggregateFunc::Count => Accumulator::Count(0),
+            AggregateFunc::Sum => Accumulator::Sum(None),
+            AggregateFunc::Avg => Accumulator::Avg { sum: 0.0, count: 0 },
+            AggregateFunc::Min => Accumulator::Min(None),
+            AggregateFunc::Max => Accumulator::Max(None),
+        }
+    }
+
+    /// Feed one input row. `value` is None for `COUNT(*)`, which counts rows;
+    /// every other aggregate ignores NULL inputs.
+    pub fn update(&mut self, value: Option<Value>) -> Result<()> {
+        let value = match value {
+            None => {
+                if let Accumulator::Count(count) = self {
+                    *count += 1;
+                }
+                return Ok(());
+            }
+            Some(Value::Null) => return Ok(()),
+            Some(value) => value,
+        };
+
+        match self {
+            Accumulator::Count(count) => *count += 1,
+            Accumulator::Sum(sum) => {
+                numeric("SUM", &value)?;
+                *sum = Some(match (sum.take(), value) {
+                    (None, value) => value,
+                    (Some(Value::Integer(a)), Value::Integer(b)) => a.checked_add(b)
+                        .map(Value::Integer)
+                        .ok_or_else(|| QueryError::Execution("integer overflow in SUM".to_string()))?,
+                    (Some(a), b) => Value::Float(numeric("SUM", &a)? + numeric("SUM", &b)?),
+                });
+            }
+            Accumulator::Avg { sum, count } => {
+                *sum += numeric("AVG", &value)?;
+                *count += 1;
+            }
+            Accumulator::Min(current) => keep_if(current, value, Ordering::Less)?,
+            Accumulator::Max(current) => keep_if(current, value, Ordering::Greater)?,
+        }
+        Ok(())
+    }
+
+    /// Fold in the state of the same aggregate over other rows of the group
+    pub fn merge(&mut self, other: Accumulator) -> Result<()> {
+        match (&mut *self, other) {
+            (Accumulator::Count(count), Accumulator::Count(other)) => *count += other,
+            (Accumulator::Avg { sum, count }, Accumulator::Avg { sum: other_sum, count: other_count }) => {
+                *sum += other_sum;
+                *count += other_count;
+            }
+            (Accumulator::Sum(_), Accumulator::Sum(other))
+            | (Accumulator::Min(_), Accumulator::Min(other))
+            | (Accumulator::Max(_), Accumulator::Max(other)) => {
+                if let Some(value) = other {
+                    self.update(Some(value))?;
+                }
+            }
+            (_, other) => {
+                return Err(QueryError::Execution(format!("cannot merge aggregate state {:?}", other)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Append the partial state as values, which `from_state` reads back
+    pub fn push_state(self, row: &mut Vec<Value>) {
+        match self {
+            Accumulator::Count(count) => row.push(Value::Integer(count)),
+            Accumulator::Avg { sum, count } => row.extend([Value::Float(sum), Value::Integer(count)]),
+            Accumulator::Sum(value) | Accumulator::Min(value) | Accumulator::Max(value) => {
+                row.push(value.unwrap_or(Value::Null));
+            }
+        }
+    }
+
+    /// Read a partial state written by `push_state` for `func`
+    pub fn from_state(func: AggregateFunc, state: &mut impl Iterator<Item = Value>) -> Result<Self> {
+        let invalid = || QueryError::Execution(format!("invalid {} aggregate state", func));
+        let some = |value: Value| if value.is_null() { None } else { Some(value) };
+        Ok(match func {
+            AggregateFunc::Count => match state.next() {
+                Some(Value::Integer(count)) => Accumulator::Count(count),
+                _ => return Err(invalid()),
+            },
+            AggregateFunc::Avg => match (state.next(), state.next()) {
+                (Some(Value::Float(sum)), Some(Value::Integer(count))) => Accumulator::Avg { sum, count },
+                _ => return Err(invalid()),
+            },
+            AggregateFunc::Sum => Accumulator::Sum(some(state.next().ok_or_else(invalid)?)),
+            AggregateFunc::Min => Accumulator::Min(some(state.next().ok_or_else(invalid)?)),
+            AggregateFunc::Max => Accumulator::Max(some(state.next().ok_or_else(invalid)?)),
+        })
+    }
+
+    /// Approximate memory held by the accumulator
+    pub fn size_hint(&self) -> usize {
+        std::mem::size_of::<Self>() + match self {
+            Accumulator::Sum(Some(value)) | Accumulator::Min(Some(value)) | Accumulator::Max(Some(value)) => {
+                value.size_hint()
+            }
+            _ => 0,
+        }
+    }
+
+    /// Final value of the aggregate. SUM, AVG, MIN and MAX of no values are NULL.
+    pub fn finish(self) -> Value {
+        match self {
+            Accumulator::Count(count) => Value::Integer(count),
+            Accumulator::Avg { count: 0, .. } => Value::Null,
+            Accumulator::Avg { sum, count } => Value::Float(sum / count as f64),
+            Accumulator::Sum(value) | Accumulator::Min(value) | Accumulator::Max(value) => {
+                value.unwrap_or(Value::Null)
+            }
+        }
+    }
+}
+
+/// Replace `current` with `value` if it compares as `wanted` against it
+fn keep_if(current: &mut Option<Value>, value: Value, wanted: Ordering) -> Result<()> {
+    let replace = match current {
+        None => true,
+        Some(existing) => value.sql_cmp(existing)? == Some(wanted),
+    };
+    if replace {
+        *current = Some(value);
+    }
+    Ok(())
+}
+
+fn numeric(func: &str, value: &Value) -> Result<f64> {
+    match value {
+        Value::Integer(i) => Ok(*i as f64),
+        Value::Float(f) => Ok(*f),
+        other => Err(QueryError::Execution(format!(
+            "{} cannot be applied to {}", func, other.type_name()
+        ))),
+    }
+}
@@ -0,0 +1,557 @@
+use crate::value;
+pub use nextdb_transaction::IsolationLevel;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SqlStatement {
+    Select(SelectStatement),
+    /// `INSERT ... DEFAULT VALUES` has no columns and a single empty row
+    Insert {
+        table: String,
+        columns: Vec<String>,
+        values: Vec<Vec<Expr>>,
+    },
+    Update {
+        table: String,
+        set_clause: Vec<(String, Expr)>,
+        where_clause: Option<Expr>,
+    },
+    Delete {
+        table: String,
+        where_clause: Option<Expr>,
+    },
+    CreateTable {
+        name: String,
+        columns: Vec<ColumnDef>,
+        primary_key: Vec<String>,
+        if_not_exists: bool,
+    },
+    DropTable {
+        name: String,
+        if_exists: bool,
+    },
+    /// `CREATE DATABASE [IF NOT EXISTS] name`. Databases are kept by the
+    /// server, which runs this and DROP DATABASE; an executor refuses them.
+    CreateDatabase {
+        name: String,
+        if_not_exists: bool,
+    },
+    DropDatabase {
+        name: String,
+        if_exists: bool,
+    },
+    CreateIndex {
+        name: String,
+        table: String,
+        columns: Vec<String>,
+        unique: bool,
+    },
+    AlterTable {
+        table: String,
+        operation: AlterTableOperation,
+    },
+    ShowTables {
+        where_clause: Option<Expr>,
+    },
+    /// `SHOW COLUMNS FROM t` or `DESCRIBE t`
+    ShowColumns {
+        table: String,
+        where_clause: Option<Expr>,
+    },
+    /// `ANALYZE [t]`: gather column statistics for the planner, for every
+    /// table when none is named
+    Analyze {
+        table: Option<String>,
+    },
+    /// `EXPLAIN [ANALYZE] <statement>`: show the plan without running it, or
+    /// with ANALYZE run it and annotate each node with runtime statistics
+    Explain {
+        statement: Box<SqlStatement>,
+        analyze: bool,
+    },
+    /// `BEGIN [ISOLATION LEVEL ...]`, READ COMMITTED unless a level is given
+    Begin {
+        isolation_level: Option<IsolationLevel>,
+    },
+    Commit,
+    Rollback,
+}
+
+impl SqlStatement {
+    /// Whether the statement leaves data and schema unchanged. Transaction
+    /// control counts as read-only, and so does EXPLAIN unless it runs a
+    /// statement that writes.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            SqlStatement::Select(_)
+            | SqlStatement::ShowTables { .. }
+            | SqlStatement::ShowColumns { .. }
+            | SqlStatement::Begin { .. }
+            | SqlStatement::Commit
+            | SqlStatement::Rollback => true,
+            SqlStatement::Explain { statement, analyze } => !analyze || statement.is_read_only(),
+            SqlStatement::Insert { .. }
+            | SqlStatement::Update { .. }
+            | SqlStatement::Delete { .. }
+            | SqlStatement::CreateTable { .. }
+            | SqlStatement::DropTable { .. }
+            | SqlStatement::CreateDatabase { .. }
+            | SqlStatement::DropDatabase { .. }
+            | SqlStatement::CreateIndex { .. }
+            | SqlStatement::AlterTable { .. }
+            | SqlStatement::Analyze { .. } => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SelectStatement {
+    pub columns: Vec<SelectItem>,
+    pub table: Option<String>,
+    pub where_clause: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+    pub order_by: Vec<OrderByExpr>,
+    pub limit: Option<u64>,
+    /// Result rows to skip, before LIMIT applies
+    pub offset: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SelectItem {
+    Wildcard,
+    Expr { expr: Expr, alias: Option<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderByExpr {
+    pub expr: Expr,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum AlterTableOperation {
+    AddColumn(ColumnDef),
+    DropColumn { name: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnDef {
+    pub name: String,
+    pub data_type: DataType,
+    pub nullable: bool,
+    pub primary_key: bool,
+    pub unique: bool,
+    pub default: Option<Expr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DataType {
+    Integer,
+    Float,
+    Text,
+    Boolean,
+    Blob,
+    Timestamp,
+}
+
+impl DataType {
+    /// Type for a SQL type name or one of its aliases, in any case
+    pub fn from_name(name: &str) -> Option<DataType> {
+        Some(match name.to_lowercase().as_str() {
+            "int" | "integer" | "bigint" | "smallint" => DataType::Integer,
+            "float" | "double" | "real" | "decimal" | "numeric" => DataType::Float,
+            "text" | "varchar" | "char" | "string" => DataType::Text,
+            "bool" | "boolean" => DataType::Boolean,
+            "blob" | "bytea" | "bytes" => DataType::Blob,
+            "timestamp" | "datetime" => DataType::Timestamp,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DataType::Integer => "INTEGER",
+            DataType::Float => "FLOAT",
+            DataType::Text => "TEXT",
+            DataType::Boolean => "BOOLEAN",
+            DataType::Blob => "BLOB",
+            DataType::Timestamp => "TIMESTAMP",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Expr {
+    Column(String),
+    Literal(Literal),
+    Unary {
+        op: UnaryOp,
+        expr: Box<Expr>,
+    },
+    Binary {
+        left: Box<Expr>,
+        op: BinaryOp,
+        right: Box<Expr>,
+    },
+    IsNull {
+        expr: Box<Expr>,
+        negated: bool,
+    },
+    /// Scalar function call. `name` is lower case.
+    Function {
+        name: String,
+        args: Vec<Expr>,
+    },
+    /// Aggregate function call. `arg` is None for `COUNT(*)`.
+    Aggregate {
+        func: AggregateFunc,
+        arg: Option<Box<Expr>>,
+    },
+    /// `expr [NOT] IN (SELECT ...)`
+    InSubquery {
+        expr: Box<Expr>,
+        subquery: Box<SelectStatement>,
+        negated: bool,
+    },
+    /// `expr [NOT] IN (value, ...)`
+    InList {
+        expr: Box<Expr>,
+        list: Vec<Expr>,
+        negated: bool,
+    },
+    /// `DEFAULT` in the VALUES list of an INSERT: the column's default value
+    Default,
+    /// `$1`, a parameter of a prepared statement, numbered from 1. See
+    /// `SqlStatement::bind`.
+    Parameter(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AggregateFunc {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl AggregateFunc {
+    /// Look up an aggregate by its (case-insensitive) SQL name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "count" => Some(AggregateFunc::Count),
+            "sum" => Some(AggregateFunc::Sum),
+            "avg" => Some(AggregateFunc::Avg),
+            "min" => Some(AggregateFunc::Min),
+            "max" => Some(AggregateFunc::Max),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AggregateFunc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AggregateFunc::Count => "COUNT",
+            AggregateFunc::Sum => "SUM",
+            AggregateFunc::Avg => "AVG",
+            AggregateFunc::Min => "MIN",
+            AggregateFunc::Max => "MAX",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Literal {
+    Null,
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Boolean(bool),
+    /// `TIMESTAMP '...'`, as microseconds since the epoch in UTC
+    Timestamp(i64),
+    /// `X'...'`, bytes as hex digits
+    Blob(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UnaryOp {
+    Not,
+    Minus,
+    Plus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    Plus,
+    Minus,
+    Multiply,
+    Divide,
+    Modulo,
+    Concat,
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            BinaryOp::Or => "OR",
+            BinaryOp::And => "AND",
+            BinaryOp::Eq => "=",
+            BinaryOp::NotEq => "<>",
+            BinaryOp::Lt => "<",
+            BinaryOp::LtEq => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::GtEq => ">=",
+            BinaryOp::Plus => "+",
+            BinaryOp::Minus => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Modulo => "%",
+            BinaryOp::Concat => "||",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Literal::Null => write!(f, "NULL"),
+            Literal::Integer(i) => write!(f, "{}", i),
+            Literal::Float(v) => write!(f, "{:?}", v),
+            Literal::String(s) => write!(f, "'{}'", s.replace('\'', "''")),
+            Literal::Boolean(b) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            Literal::Timestamp(micros) => write!(f, "TIMESTAMP '{}'", value::format_timestamp(*micros)),
+            Literal::Blob(bytes) => {
+                write!(f, "X'")?;
+                for byte in bytes {
+                    write!(f, "{:02x}", byte)?;
+                }
+                write!(f, "'")
+            }
+        }
+    }
+}
+
+/// Renders the expression back as SQL. Used for error messages and for naming
+/// unaliased result columns.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Column(name) => write!(f, "{}", Ident(name)),
+            Expr::Literal(lit) => write!(f, "{}", lit),
+            Expr::Unary { op: UnaryOp::Not, expr } => write!(f, "NOT {}", Nested(expr)),
+            Expr::Unary { op: UnaryOp::Minus, expr } => write!(f, "-{}", Nested(expr)),
+            Expr::Unary { op: UnaryOp::Plus, expr } => write!(f, "+{}", Nested(expr)),
+            Expr::Binary { left, op, right } => write!(f, "{} {} {}", Nested(left), op, Nested(right)),
+            Expr::IsNull { expr, negated } => {
+                write!(f, "{} IS {}NULL", Nested(expr), if *negated { "NOT " } else { "" })
+            }
+            Expr::Function { name, args } => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "" } else { ", " }, arg)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Aggregate { func, arg: Some(arg) } => write!(f, "{}({})", func, arg),
+            Expr::Aggregate { func, arg: None } => write!(f, "{}(*)", func),
+            Expr::InSubquery { expr, subquery, negated } => {
+                write!(f, "{} {}IN ({})", Nested(expr), if *negated { "NOT " } else { "" }, subquery)
+            }
+            Expr::InList { expr, list, negated } => {
+                write!(f, "{} {}IN (", Nested(expr), if *negated { "NOT " } else { "" })?;
+                for (i, item) in list.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Default => write!(f, "DEFAULT"),
+            Expr::Parameter(n) => write!(f, "${}", n),
+        }
+    }
+}
+
+/// Renders the statement back as SQL, as the slow query log shows it
+impl fmt::Display for SqlStatement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let if_where = |filter: &Option<Expr>| filter.as_ref().map_or(String::new(), |filter| format!(" WHERE {}", filter));
+        let names = |names: &[String]| names.iter().map(|name| Ident(name).to_string()).collect::<Vec<_>>().join(", ");
+        match self {
+            SqlStatement::Select(select) => write!(f, "{}", select),
+            SqlStatement::Insert { table, columns, values } if columns.is_empty() && values.iter().all(Vec::is_empty) => {
+                write!(f, "INSERT INTO {} DEFAULT VALUES", Ident(table))
+            }
+            SqlStatement::Insert { table, columns, values } => {
+                write!(f, "INSERT INTO {}", Ident(table))?;
+                if !columns.is_empty() {
+                    write!(f, " ({})", names(columns))?;
+                }
+                for (i, row) in values.iter().enumerate() {
+                    let row: Vec<String> = row.iter().map(Expr::to_string).collect();
+                    write!(f, "{}({})", if i == 0 { " VALUES " } else { ", " }, row.join(", "))?;
+                }
+                Ok(())
+            }
+            SqlStatement::Update { table, set_clause, where_clause } => {
+                let set: Vec<String> = set_clause.iter().map(|(column, expr)| format!("{} = {}", Ident(column), expr)).collect();
+                write!(f, "UPDATE {} SET {}{}", Ident(table), set.join(", "), if_where(where_clause))
+            }
+            SqlStatement::Delete { table, where_clause } => write!(f, "DELETE FROM {}{}", Ident(table), if_where(where_clause)),
+            SqlStatement::CreateTable { name, columns, primary_key, if_not_exists } => {
+                write!(f, "CREATE TABLE {}{} (", if *if_not_exists { "IF NOT EXISTS " } else { "" }, Ident(name))?;
+                for (i, column) in columns.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "" } else { ", " }, column)?;
+                }
+                if !primary_key.is_empty() && !columns.iter().any(|column| column.primary_key) {
+                    write!(f, ", PRIMARY KEY ({})", names(primary_key))?;
+                }
+                write!(f, ")")
+            }
+            SqlStatement::DropTable { name, if_exists } => {
+                write!(f, "DROP TABLE {}{}", if *if_exists { "IF EXISTS " } else { "" }, Ident(name))
+            }
+            SqlStatement::CreateDatabase { name, if_not_exists } => {
+                write!(f, "CREATE DATABASE {}{}", if *if_not_exists { "IF NOT EXISTS " } else { "" }, Ident(name))
+            }
+            SqlStatement::DropDatabase { name, if_exists } => {
+                write!(f, "DROP DATABASE {}{}", if *if_exists { "IF EXISTS " } else { "" }, Ident(name))
+            }
+            SqlStatement::CreateIndex { name, table, columns, unique } => write!(
+                f, "CREATE {}INDEX {} ON {} ({})",
+                if *unique { "UNIQUE " } else { "" }, Ident(name), Ident(table), names(columns)
+            ),
+            SqlStatement::AlterTable { table, operation: AlterTableOperation::AddColumn(column) } => {
+                write!(f, "ALTER TABLE {} ADD COLUMN {}", Ident(table), column)
+            }
+            SqlStatement::AlterTable { table, operation: AlterTableOperation::DropColumn { name } } => {
+                write!(f, "ALTER TABLE {} DROP COLUMN {}", Ident(table), Ident(name))
+            }
+            SqlStatement::ShowTables { where_clause } => write!(f, "SHOW TABLES{}", if_where(where_clause)),
+            SqlStatement::ShowColumns { table, where_clause } => {
+                write!(f, "SHOW COLUMNS FROM {}{}", Ident(table), if_where(where_clause))
+            }
+            SqlStatement::Analyze { table: Some(table) } => write!(f, "ANALYZE {}", Ident(table)),
+            SqlStatement::Analyze { table: None } => write!(f, "ANALYZE"),
+            SqlStatement::Explain { statement, analyze } => {
+                write!(f, "EXPLAIN {}{}", if *analyze { "ANALYZE " } else { "" }, statement)
+            }
+            SqlStatement::Begin { isolation_level: None } => write!(f, "BEGIN"),
+            SqlStatement::Begin { isolation_level: Some(level) } => {
+                let level = match level {
+                    IsolationLevel::ReadUncommitted => "READ UNCOMMITTED",
+                    IsolationLevel::ReadCommitted => "READ COMMITTED",
+                    IsolationLevel::RepeatableRead => "REPEATABLE READ",
+                    IsolationLevel::Serializable => "SERIALIZABLE",
+                };
+                write!(f, "BEGIN ISOLATION LEVEL {}", level)
+            }
+            SqlStatement::Commit => write!(f, "COMMIT"),
+            SqlStatement::Rollback => write!(f, "ROLLBACK"),
+        }
+    }
+}
+
+impl fmt::Display for ColumnDef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", Ident(&self.name), self.data_type)?;
+        if !self.nullable && !self.primary_key {
+            write!(f, " NOT NULL")?;
+        }
+        if self.primary_key {
+            write!(f, " PRIMARY KEY")?;
+        }
+        if self.unique {
+            write!(f, " UNIQUE")?;
+        }
+        if let Some(default) = &self.default {
+            write!(f, " DEFAULT {}", default)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SelectStatement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SELECT ")?;
+        for (i, item) in self.columns.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match item {
+                SelectItem::Wildcard => write!(f, "*")?,
+                SelectItem::Expr { expr, alias: Some(alias) } => write!(f, "{} AS {}", expr, Ident(alias))?,
+                SelectItem::Expr { expr, alias: None } => write!(f, "{}", expr)?,
+            }
+        }
+        if let Some(table) = &self.table {
+            write!(f, " FROM {}", Ident(table))?;
+        }
+        if let Some(filter) = &self.where_clause {
+            write!(f, " WHERE {}", filter)?;
+        }
+        for (i, expr) in self.group_by.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " GROUP BY " } else { ", " }, expr)?;
+        }
+        if let Some(having) = &self.having {
+            write!(f, " HAVING {}", having)?;
+        }
+        for (i, key) in self.order_by.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " ORDER BY " } else { ", " }, key.expr)?;
+            if key.descending {
+                write!(f, " DESC")?;
+            }
+        }
+        if let Some(limit) = self.limit {
+            write!(f, " LIMIT {}", limit)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " OFFSET {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parenthesizes compound sub-expressions so precedence survives rendering
+struct Nested<'a>(&'a Expr);
+
+impl fmt::Display for Nested<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Expr::Binary { .. } | Expr::IsNull { .. } | Expr::InSubquery { .. } | Expr::InList { .. } => write!(f, "({})", self.0),
+            other => write!(f, "{}", other),
+        }
+    }
+}
+
+/// Quotes identifiers that would not survive case folding or keyword parsing
+struct Ident<'a>(&'a str);
+
+impl fmt::Display for Ident<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let plain = self.0.chars().next().is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+            && self.0.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if plain {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "\"{}\"", self.0.replace('"', "\"\""))
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Result cache for read queries.
+//!
+//! Results are keyed by the normalized SQL of a SELECT: the statement as the
+//! parser understood it, so whitespace, keyword case and comments do not
+//! matter. Each entry remembers the schema and write sequence of every table
+//! the query read; it is served only while all of them are unchanged, so any
+//! write or DDL on a table invalidates the results that depend on it.
+
+use crate::{
+    ast::{Expr, SelectItem, SelectStatement},
+    catalog::{Catalog, TableSchema},
+    functions,
+    planner::PhysicalPlan,
+    result::ResultSet,
+};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Settings for the query result cache
+#[derive(Debug, Clone)]
+pub struct QueryCacheConfig {
+    /// Most results kept; the least recently used is evicted beyond this
+    pub max_entries: usize,
+    /// How long a result may be served after it was computed
+    pub ttl: Duration,
+}
+
+impl Default for QueryCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 256,
+            ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+/// The version of a table a cached result was computed from
+#[derive(Debug, Clone)]
+pub struct TableVersion {
+    schema: Arc<TableSchema>,
+    write_sequence: u64,
+}
+
+impl TableVersion {
+    fn is_current(&self, catalog: &Catalog) -> bool {
+        catalog.get_table(&self.schema.name)
+            .is_some_and(|schema| Arc::ptr_eq(&schema, &self.schema))
+            && catalog.stats(self.schema.id).write_sequence() == self.write_sequence
+    }
+}
+
+struct CacheEntry {
+    result: ResultSet,
+    tables: Vec<TableVersion>,
+    created: Instant,
+    last_used: u64,
+}
+
+pub struct QueryCache {
+    config: QueryCacheConfig,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    // Logical clock for least-recently-used eviction
+    tick: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl QueryCache {
+    pub fn new(config: QueryCacheConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::new(HashMap::new()),
+            tick: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Cached result for `key`, if it is fresh and every table it read is unchanged
+    pub fn get(&self, key: &str, catalog: &Catalog) -> Option<ResultSet> {
+        let mut entries = self.entries.lock();
+        let fresh = entries.get(key).map(|entry| {
+            entry.created.elapsed() < self.config.ttl
+                && entry.tables.iter().all(|table| table.is_current(catalog))
+        });
+
+        match fresh {
+            Some(true) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                let entry = entries.get_mut(key).expect("entry exists");
+                entry.last_used = self.tick.fetch_add(1, Ordering::Relaxed);
+                Some(entry.result.clone())
+            }
+            stale => {
+                if stale.is_some() {
+                    entries.remove(key);
+                }
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Cache `result`. `tables` must be captured before the query started
+    /// reading, so writes that race with it leave the entry already stale.
+    pub fn insert(&self, key: String, result: ResultSet, tables: Vec<TableVersion>) {
+        if self.config.max_entries == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock();
+        if !entries.contains_key(&key) && entries.len() >= self.config.max_entries {
+            let oldest = entries.iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, CacheEntry {
+            result,
+            tables,
+            created: Instant::now(),
+            last_used: self.tick.fetch_add(1, Ordering::Relaxed),
+        });
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().clear();
+    }
+
+    pub fn stats(&self) -> QueryCacheStats {
+        QueryCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.entries.lock().len(),
+        }
+    }
+}
+
+/// Whether the result of `select` depends only on table contents, so it can
+/// be cached
+pub fn is_cacheable(select: &SelectStatement) -> bool {
+    select.table.is_some()
+        && select.columns.iter().all(|item| match item {
+            SelectItem::Wildcard => true,
+            SelectItem::Expr { expr, .. } => is_deterministic(expr),
+        })
+        && select.where_clause.iter().all(is_deterministic)
+        && select.group_by.iter().all(is_deterministic)
+        && select.having.iter().all(is_deterministic)
+        && select.order_by.iter().all(|key| is_deterministic(&key.expr))
+}
+
+fn is_deterministic(expr: &Expr) -> bool {
+    match expr {
+        Expr::Column(_) | Expr::Literal(_) => true,
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => is_deterministic(expr),
+        Expr::Binary { left, right, .. } => is_deterministic(left) && is_deterministic(right),
+        Expr::Function { name, args } => {
+            functions::lookup(name).is_ok_and(|f| !f.volatile) && args.iter().all(is_deterministic)
+        }
+        Expr::Aggregate { arg, .. } => arg.as_deref().is_none_or(is_deterministic),
+        Expr::InSubquery { expr, subquery, .. } => is_deterministic(expr) && is_cacheable(subquery),
+        Expr::InList { expr, list, .. } => is_deterministic(expr) && list.iter().all(is_deterministic),
+        Expr::Default | Expr::Parameter(_) => false,
+    }
+}
+
+/// Current versions of the tables `plan` reads, or None if it reads
+/// something other than tables
+pub fn table_versions(plan: &PhysicalPlan, catalog: &Catalog) -> Option<Vec<TableVersion>> {
+    let mut names = Vec::new();
+    if !scanned_tables(plan, &mut names) {
+        return None;
+    }
+
+    names.into_iter()
+        .map(|name| {
+            let schema = catalog.get_table(&name)?;
+            let write_sequence = catalog.stats(schema.id).write_sequence();
+            Some(TableVersion { schema, write_sequence })
+        })
+        .collect()
+}
+
+fn scanned_tables(plan: &PhysicalPlan, names: &mut Vec<String>) -> bool {
+    match plan {
+        PhysicalPlan::TableScan { table, .. }
+        | PhysicalPlan::IndexScan { table, .. }
+        | PhysicalPlan::KeyLookup { table, .. }
+        | PhysicalPlan::TableCount { table, .. } => {
+            if !names.contains(table) {
+                names.push(table.clone());
+            }
+            true
+        }
+        PhysicalPlan::Values { .. } => true,
+        PhysicalPlan::Filter { input, .. }
+        | PhysicalPlan::HashAggregate { input, .. }
+        | PhysicalPlan::Project { input, .. }
+        | PhysicalPlan::Sort { input, .. }
+        | PhysicalPlan::Limit { input, .. }
+        | PhysicalPlan::Offset { input, .. } => scanned_tables(input, names),
+        PhysicalPlan::SemiJoin { input, subqueries, .. } => {
+            scanned_tables(input, names) && subqueries.iter().all(|plan| scanned_tables(plan, names))
+        }
+        _ => false,
+    }
+}
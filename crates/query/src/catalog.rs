@@ -0,0 +1,481 @@
+use crate::{
+    error::{Result, QueryError},
+    ast::{ColumnDef, DataType, Expr},
+    encoding,
+    eval,
+    executor::decode_stored_row,
+    stats::{Analysis, Collector},
+    value::Value,
+};
+use nextdb_storage::LSMTree;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Rows read per storage scan while loading table statistics
+const LOAD_BATCH_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Column {
+    /// Stable id used in the row encoding; never reused within a table
+    pub id: u32,
+    pub name: String,
+    pub data_type: DataType,
+    pub nullable: bool,
+    /// Value for rows written before the column was added: the DEFAULT
+    /// expression as evaluated when the column was created
+    #[serde(default)]
+    pub default: Option<Value>,
+    /// DEFAULT expression, evaluated for each INSERT that omits the column
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_expr: Option<Expr>,
+}
+
+impl Column {
+    /// Build a column from its definition, evaluating the DEFAULT expression
+    pub fn from_def(id: u32, def: &ColumnDef) -> Result<Self> {
+        let default = match &def.default {
+            Some(expr) => {
+                let value = eval::eval(expr, &[], &[])?.cast_to(def.data_type).map_err(|e| {
+                    QueryError::Invalid(format!("invalid default for column {}: {}", def.name, e))
+                })?;
+                Some(value)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            id,
+            name: def.name.clone(),
+            data_type: def.data_type,
+            nullable: def.nullable,
+            default,
+            default_expr: def.default.clone(),
+        })
+    }
+
+    /// Value for a row that has no stored value for this column
+    pub fn default_value(&self) -> Value {
+        self.default.clone().unwrap_or(Value::Null)
+    }
+
+    /// Value an INSERT gives the column when it omits it or writes DEFAULT
+    pub fn insert_default(&self) -> Result<Value> {
+        match &self.default_expr {
+            Some(expr) => eval::eval(expr, &[], &[])?.cast_to(self.data_type),
+            None => Ok(self.default_value()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexDef {
+    pub id: u32,
+    pub name: String,
+    pub columns: Vec<String>,
+    pub unique: bool,
+}
+
+/// Persisted definition of a table
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableSchema {
+    pub id: u64,
+    pub name: String,
+    pub columns: Vec<Column>,
+    /// Primary key column names. Empty means rows are keyed by a hidden row id.
+    pub primary_key: Vec<String>,
+    pub indexes: Vec<IndexDef>,
+    pub next_column_id: u32,
+    pub next_index_id: u32,
+    /// Bumped by every ALTER TABLE
+    #[serde(default)]
+    pub version: u32,
+    /// Ids of dropped columns whose data may still be present in stored rows
+    #[serde(default)]
+    pub dropped_columns: Vec<u32>,
+}
+
+impl TableSchema {
+    pub fn column(&self, name: &str) -> Option<&Column> {
+        self.columns.iter().find(|c| c.name == name)
+    }
+
+    pub fn column_position(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.name == name)
+    }
+
+    pub fn column_names(&self) -> Vec<String> {
+        self.columns.iter().map(|c| c.name.clone()).collect()
+    }
+
+    pub fn index(&self, name: &str) -> Option<&IndexDef> {
+        self.indexes.iter().find(|i| i.name == name)
+    }
+
+    /// Positions of the primary key columns in `columns`
+    pub fn primary_key_positions(&self) -> Vec<usize> {
+        self.primary_key.iter()
+            .map(|name| self.column_position(name).expect("primary key column exists"))
+            .collect()
+    }
+}
+
+/// Live, in-memory statistics for a table.
+///
+/// The row count is exact: every write that changes it carries the new
+/// count, persisted under the table's stats key, in the same storage batch
+/// as the rows themselves. Column statistics, once ANALYZE has gathered
+/// them, are persisted alongside it.
+#[derive(Debug, Default)]
+pub struct TableStats {
+    rows: AtomicU64,
+    next_row_id: AtomicU64,
+    write_sequence: AtomicU64,
+    analysis: RwLock<Option<Analysis>>,
+    // Held from reading the row count until the batch carrying its new value
+    // is written, so concurrent writers never persist a stale count
+    counter_lock: tokio::sync::Mutex<()>,
+}
+
+/// The persisted part of `TableStats`
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredStats {
+    rows: u64,
+    next_row_id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    analysis: Option<Analysis>,
+}
+
+impl TableStats {
+    pub fn row_count(&self) -> u64 {
+        self.rows.load(Ordering::Acquire)
+    }
+
+    pub async fn lock_counter(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.counter_lock.lock().await
+    }
+
+    /// Set the row count once a batch persisting `rows` has been written.
+    /// Call with the counter lock held.
+    pub fn set_row_count(&self, rows: u64) {
+        self.rows.store(rows, Ordering::Release);
+    }
+
+    /// Stats key value recording `rows` live rows
+    pub fn encode(&self, rows: u64) -> Vec<u8> {
+        let stored = StoredStats {
+            rows,
+            next_row_id: self.next_row_id.load(Ordering::Relaxed),
+            analysis: self.analysis(),
+        };
+        serde_json::to_vec(&stored).expect("stats serialize")
+    }
+
+    /// Column statistics from the last ANALYZE, if the table has been analyzed
+    pub fn analysis(&self) -> Option<Analysis> {
+        self.analysis.read().clone()
+    }
+
+    /// Widen the column statistics, if any, to cover a newly written row
+    pub fn observe_row(&self, schema: &TableSchema, row: &[Value]) {
+        if let Some(analysis) = self.analysis.write().as_mut() {
+            analysis.observe(schema, row);
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let stored: StoredStats = serde_json::from_slice(bytes)
+            .map_err(|e| QueryError::Execution(format!("corrupt table stats: {}", e)))?;
+        Ok(Self {
+            rows: AtomicU64::new(stored.rows),
+            next_row_id: AtomicU64::new(stored.next_row_id),
+            analysis: RwLock::new(stored.analysis),
+            ..Default::default()
+        })
+    }
+
+    /// Number of row writes since the table was loaded. Any change to the
+    /// table's rows changes this, so it identifies a version of the data.
+    pub fn write_sequence(&self) -> u64 {
+        self.write_sequence.load(Ordering::Acquire)
+    }
+
+    pub fn record_write(&self) {
+        self.write_sequence.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Allocate a hidden row id for a table without a primary key
+    pub fn next_row_id(&self) -> i64 {
+        self.next_row_id.fetch_add(1, Ordering::Relaxed) as i64
+    }
+}
+
+/// Table definitions, persisted in the LSM tree under the catalog prefix and
+/// cached in memory.
+pub struct Catalog {
+    storage: Arc<LSMTree>,
+    tables: RwLock<BTreeMap<String, Arc<TableSchema>>>,
+    stats: RwLock<HashMap<u64, Arc<TableStats>>>,
+    next_table_id: AtomicU64,
+    // Bumped by every change to a table definition
+    version: AtomicU64,
+    // Serializes DDL so check-then-write on the catalog is atomic
+    ddl_lock: tokio::sync::Mutex<()>,
+}
+
+impl Catalog {
+    pub async fn open(storage: Arc<LSMTree>) -> Result<Self> {
+        let prefix = encoding::catalog_prefix();
+        let end = encoding::prefix_end(&prefix);
+        let entries = storage.scan(&prefix, &end, usize::MAX).await?;
+
+        let mut tables = BTreeMap::new();
+        let mut stats = HashMap::new();
+        let mut next_table_id = 1;
+        for (_, value) in entries {
+            let schema: TableSchema = serde_json::from_slice(&value)
+                .map_err(|e| QueryError::Execution(format!("corrupt catalog entry: {}", e)))?;
+            next_table_id = next_table_id.max(schema.id + 1);
+            // Tables written before row counters were persisted are counted once
+            let table_stats = match storage.get(&encoding::stats_key(schema.id)).await? {
+                Some(bytes) => TableStats::decode(&bytes)?,
+                None => Self::load_stats(&storage, &schema).await?,
+            };
+            stats.insert(schema.id, Arc::new(table_stats));
+            tables.insert(schema.name.clone(), Arc::new(schema));
+        }
+
+        Ok(Self {
+            storage,
+            tables: RwLock::new(tables),
+            stats: RwLock::new(stats),
+            next_table_id: AtomicU64::new(next_table_id),
+            version: AtomicU64::new(0),
+            ddl_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Count rows and find the highest hidden row id in use
+    async fn load_stats(storage: &LSMTree, schema: &TableSchema) -> Result<TableStats> {
+        let prefix = encoding::table_prefix(schema.id);
+        let end = encoding::prefix_end(&prefix);
+        let mut cursor = prefix.clone();
+        let mut rows = 0;
+        let mut next_row_id = 0;
+
+        loop {
+            let page = storage.scan(&cursor, &end, LOAD_BATCH_SIZE).await?;
+            let Some((last, _)) = page.last() else {
+                break;
+            };
+            rows += page.len() as u64;
+            if schema.primary_key.is_empty() {
+                if let Some(Value::Integer(id)) = encoding::decode_key(&last[prefix.len()..])?.first() {
+                    next_row_id = next_row_id.max(*id as u64 + 1);
+                }
+            }
+            cursor = last.clone();
+            cursor.push(0);
+            if page.len() < LOAD_BATCH_SIZE {
+                break;
+            }
+        }
+
+        Ok(TableStats {
+            rows: AtomicU64::new(rows),
+            next_row_id: AtomicU64::new(next_row_id),
+            ..Default::default()
+        })
+    }
+
+    /// Changes with every CREATE, DROP or ALTER of a table since the
+    /// catalog was opened, so anything prepared against an older version
+    /// can be checked again
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    pub fn get_table(&self, name: &str) -> Option<Arc<TableSchema>> {
+        self.tables.read().get(name).cloned()
+    }
+
+    pub fn table(&self, name: &str) -> Result<Arc<TableSchema>> {
+        self.get_table(name).ok_or_else(|| QueryError::TableNotFound(name.to_string()))
+    }
+
+    /// All tables, ordered by name
+    pub fn tables(&self) -> Vec<Arc<TableSchema>> {
+        self.tables.read().values().cloned().collect()
+    }
+
+    pub fn stats(&self, table_id: u64) -> Arc<TableStats> {
+        self.stats.write().entry(table_id).or_default().clone()
+    }
+
+    /// Take up the stats of a table as another replica persisted them,
+    /// with `bytes` the value it wrote under the table's stats key
+    pub fn load_replicated_stats(&self, table_id: u64, bytes: &[u8]) -> Result<()> {
+        let stored = TableStats::decode(bytes)?;
+        let stats = self.stats(table_id);
+        stats.set_row_count(stored.row_count());
+        stats.next_row_id.fetch_max(stored.next_row_id.load(Ordering::Relaxed), Ordering::Relaxed);
+        *stats.analysis.write() = stored.analysis.into_inner();
+        Ok(())
+    }
+
+    /// Create a table. Returns None if it already exists and `if_not_exists` is set.
+    pub async fn create_table(
+        &self,
+        name: &str,
+        columns: &[ColumnDef],
+        primary_key: &[String],
+        if_not_exists: bool,
+    ) -> Result<Option<Arc<TableSchema>>> {
+        let _guard = self.ddl_lock.lock().await;
+
+        if self.get_table(name).is_some() {
+            if if_not_exists {
+                return Ok(None);
+            }
+            return Err(QueryError::TableExists(name.to_string()));
+        }
+
+        let mut schema_columns: Vec<Column> = Vec::with_capacity(columns.len());
+        for (id, def) in columns.iter().enumerate() {
+            if schema_columns.iter().any(|c| c.name == def.name) {
+                return Err(QueryError::Invalid(format!(
+                    "column {} specified more than once", def.name
+                )));
+            }
+            let mut column = Column::from_def(id as u32, def)?;
+            column.nullable &= !primary_key.contains(&def.name);
+            schema_columns.push(column);
+        }
+
+        for key_column in primary_key {
+            if !schema_columns.iter().any(|c| &c.name == key_column) {
+                return Err(QueryError::ColumnNotFound(key_column.clone()));
+            }
+        }
+
+        // Each UNIQUE column gets a unique index, except a lone primary key
+        // column, which is unique already
+        let indexes: Vec<IndexDef> = columns.iter()
+            .filter(|def| def.unique && primary_key != std::slice::from_ref(&def.name))
+            .enumerate()
+            .map(|(id, def)| IndexDef {
+                id: id as u32,
+                name: format!("{}_{}_key", name, def.name),
+                columns: vec![def.name.clone()],
+                unique: true,
+            })
+            .collect();
+
+        let schema = TableSchema {
+            id: self.next_table_id.fetch_add(1, Ordering::SeqCst),
+            name: name.to_string(),
+            next_column_id: schema_columns.len() as u32,
+            columns: schema_columns,
+            primary_key: primary_key.to_vec(),
+            next_index_id: indexes.len() as u32,
+            indexes,
+            version: 0,
+            dropped_columns: Vec::new(),
+        };
+
+        self.persist(&schema).await?;
+        self.stats.write().insert(schema.id, Arc::new(TableStats::default()));
+        let schema = Arc::new(schema);
+        self.tables.write().insert(name.to_string(), schema.clone());
+        self.version.fetch_add(1, Ordering::SeqCst);
+        Ok(Some(schema))
+    }
+
+    /// Recount the rows of `name` with a full scan and persist the result,
+    /// repairing a counter that has drifted from the data
+    pub async fn recount_rows(&self, name: &str) -> Result<u64> {
+        let schema = self.table(name)?;
+        let stats = self.stats(schema.id);
+        let _counter = stats.lock_counter().await;
+
+        let scanned = Self::load_stats(&self.storage, &schema).await?;
+        stats.next_row_id.fetch_max(scanned.next_row_id.load(Ordering::Relaxed), Ordering::Relaxed);
+        let rows = scanned.row_count();
+        self.storage.put(encoding::stats_key(schema.id), stats.encode(rows)).await?;
+        stats.set_row_count(rows);
+        Ok(rows)
+    }
+
+    /// Gather column statistics for `name` with a full scan and persist
+    /// them, along with the exact row count they were gathered from
+    pub async fn analyze(&self, name: &str) -> Result<Analysis> {
+        let schema = self.table(name)?;
+        let stats = self.stats(schema.id);
+        let _counter = stats.lock_counter().await;
+
+        let prefix = encoding::table_prefix(schema.id);
+        let end = encoding::prefix_end(&prefix);
+        let mut cursor = prefix;
+        let mut collector = Collector::new(&schema);
+        loop {
+            let page = self.storage.scan(&cursor, &end, LOAD_BATCH_SIZE).await?;
+            for (_, value) in &page {
+                collector.add(&decode_stored_row(&schema, value)?);
+            }
+            let Some((last, _)) = page.last() else {
+                break;
+            };
+            cursor = last.clone();
+            cursor.push(0);
+            if page.len() < LOAD_BATCH_SIZE {
+                break;
+            }
+        }
+
+        let analysis = collector.finish();
+        let rows = analysis.rows;
+        *stats.analysis.write() = Some(analysis.clone());
+        self.storage.put(encoding::stats_key(schema.id), stats.encode(rows)).await?;
+        stats.set_row_count(rows);
+        Ok(analysis)
+    }
+
+    /// Remove a table from the catalog. The caller deletes its data.
+    pub async fn drop_table(&self, name: &str) -> Result<Arc<TableSchema>> {
+        let _guard = self.ddl_lock.lock().await;
+
+        let schema = self.table(name)?;
+        self.storage.delete(&encoding::catalog_key(name)).await?;
+        self.storage.delete(&encoding::stats_key(schema.id)).await?;
+        self.tables.write().remove(name);
+        self.stats.write().remove(&schema.id);
+        self.version.fetch_add(1, Ordering::SeqCst);
+        Ok(schema)
+    }
+
+    /// Apply `change` to the current definition of `name` and persist the result.
+    /// Schema changes (as opposed to index changes) should bump `version`.
+    pub async fn alter_table<F>(&self, name: &str, change: F) -> Result<Arc<TableSchema>>
+    where
+        F: FnOnce(&mut TableSchema) -> Result<()>,
+    {
+        let _guard = self.ddl_lock.lock().await;
+
+        let mut schema = (*self.table(name)?).clone();
+        change(&mut schema)?;
+        self.persist(&schema).await?;
+        let schema = Arc::new(schema);
+        self.tables.write().insert(name.to_string(), schema.clone());
+        self.version.fetch_add(1, Ordering::SeqCst);
+        Ok(schema)
+    }
+
+    async fn persist(&self, schema: &TableSchema) -> Result<()> {
+        let bytes = serde_json::to_vec(schema)
+            .map_err(|e| QueryError::Execution(format!("failed to encode catalog entry: {}", e)))?;
+        self.storage.put(encoding::catalog_key(&schema.name), bytes).await?;
+        Ok(())
+    }
+}
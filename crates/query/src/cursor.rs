@@ -0,0 +1,101 @@
+//! Queries whose results are read a page at a time.
+//!
+//! A cursor runs its query in a REPEATABLE READ snapshot of its own, so
+//! every page comes from the same point in time however long the reader
+//! takes between pages. Rows are pulled from the query's row stream as
+//! pages are asked for; only the current page is held in memory.
+
+use crate::{
+    error::Result,
+    executor::{ResultLimits, Row, RowStream},
+    result::{ColumnMeta, ResultSet},
+    value::Value,
+};
+use futures::TryStreamExt;
+use nextdb_transaction::{TransactionId, TransactionManager};
+use std::sync::Arc;
+
+pub struct QueryCursor {
+    columns: Vec<ColumnMeta>,
+    rows: RowStream,
+    // A row read past the end of the last page, to tell whether any remain
+    peeked: Option<Row>,
+    exhausted: bool,
+    limits: ResultLimits,
+    transactions: Arc<TransactionManager>,
+    // The snapshot's transaction, until the cursor is closed
+    txn: Option<TransactionId>,
+}
+
+impl QueryCursor {
+    pub(crate) fn new(
+        columns: Vec<ColumnMeta>,
+        rows: RowStream,
+        limits: ResultLimits,
+        transactions: Arc<TransactionManager>,
+        txn: TransactionId,
+    ) -> Self {
+        Self { columns, rows, peeked: None, exhausted: false, limits, transactions, txn: Some(txn) }
+    }
+
+    /// The next `max_rows` rows at most, fewer only at the end. The result
+    /// limits apply to each page rather than to the whole result.
+    pub async fn next_page(&mut self, max_rows: usize) -> Result<ResultSet> {
+        let mut rows = Vec::with_capacity(max_rows.min(1024));
+        let mut bytes = 0;
+        while rows.len() < max_rows {
+            let Some(row) = self.next_row().await? else {
+                break;
+            };
+            bytes += row.iter().map(Value::size_hint).sum::<usize>();
+            self.limits.check(rows.len() + 1, bytes)?;
+            rows.push(row);
+        }
+        if !self.exhausted {
+            self.peeked = self.next_row().await?;
+        }
+
+        // Later pages keep the column types the first one settled on
+        let page = ResultSet::new(self.columns.clone(), rows);
+        self.columns = page.columns.clone();
+        Ok(page)
+    }
+
+    async fn next_row(&mut self) -> Result<Option<Row>> {
+        if let Some(row) = self.peeked.take() {
+            return Ok(Some(row));
+        }
+        let row = self.rows.try_next().await?;
+        self.exhausted = row.is_none();
+        Ok(row)
+    }
+
+    /// Whether every row has been returned
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted && self.peeked.is_none()
+    }
+
+    /// Release the cursor's snapshot; no more rows can be read. A cursor
+    /// dropped without being closed releases it in the background.
+    pub async fn close(&mut self) -> Result<()> {
+        self.rows = Box::pin(futures::stream::empty());
+        self.peeked = None;
+        self.exhausted = true;
+        if let Some(txn) = self.txn.take() {
+            self.transactions.abort(txn).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for QueryCursor {
+    fn drop(&mut self) {
+        let (Some(txn), Ok(runtime)) = (self.txn.take(), tokio::runtime::Handle::try_current()) else {
+            return;
+        };
+        let transactions = self.transactions.clone();
+        runtime.spawn(async move {
+            let _ = transactions.abort(txn).await;
+        });
+    }
+}
@@ -0,0 +1,461 @@
+//! Byte layouts for SQL data stored in the LSM tree.
+//!
+//! Every SQL key starts with `SQL_NAMESPACE` followed by a one-byte kind:
+//!
+//! - catalog entries: `ns 'c' <table name>`
+//! - table stats:     `ns 's' <table id:u64 BE>`
+//! - table rows:      `ns 't' <table id:u64 BE> <primary key values>`
+//! - index entries:   `ns 'i' <table id:u64 BE> <index id:u32 BE> <indexed values> <primary key values>`
+//! - node metadata:   `ns 'm' <name>`
+//!
+//! Key values use an order-preserving encoding so byte order matches SQL order
+//! and range scans over a prefix see rows sorted by primary key. Row values use
+//! a compact tagged encoding keyed by column id, so adding or dropping columns
+//! does not rewrite existing rows.
+
+use crate::{
+    error::{Result, QueryError},
+    value::Value,
+};
+
+const SQL_NAMESPACE: u8 = 0x01;
+const KIND_CATALOG: u8 = b'c';
+const KIND_STATS: u8 = b's';
+const KIND_TABLE: u8 = b't';
+const KIND_INDEX: u8 = b'i';
+const KIND_META: u8 = b'm';
+const TABLE_PREFIX_LEN: usize = 10;
+
+const TAG_NULL: u8 = 0x00;
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_FLOAT: u8 = 0x03;
+const TAG_TEXT: u8 = 0x04;
+const TAG_BLOB: u8 = 0x05;
+const TAG_TIMESTAMP: u8 = 0x06;
+
+// Escaping for variable-length key values: 0x00 bytes become 0x00 0xFF and the
+// value ends with 0x00 0x01, which sorts below any continuation.
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xFF;
+const TERMINATOR: u8 = 0x01;
+
+pub fn catalog_prefix() -> Vec<u8> {
+    vec![SQL_NAMESPACE, KIND_CATALOG]
+}
+
+pub fn catalog_key(table: &str) -> Vec<u8> {
+    let mut key = catalog_prefix();
+    key.extend_from_slice(table.as_bytes());
+    key
+}
+
+pub fn stats_key(table_id: u64) -> Vec<u8> {
+    let mut key = vec![SQL_NAMESPACE, KIND_STATS];
+    key.extend_from_slice(&table_id.to_be_bytes());
+    key
+}
+
+/// Id of the table a stats key belongs to, or None if `key` is not one
+pub fn stats_key_table(key: &[u8]) -> Option<u64> {
+    match key {
+        [SQL_NAMESPACE, KIND_STATS, id @ ..] => Some(u64::from_be_bytes(id.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+/// Key of a value the node keeps about itself rather than about a table
+pub fn meta_key(name: &str) -> Vec<u8> {
+    let mut key = vec![SQL_NAMESPACE, KIND_META];
+    key.extend_from_slice(name.as_bytes());
+    key
+}
+
+/// Whether `key` belongs to SQL data rather than being free for raw
+/// key-value use
+pub fn is_sql_key(key: &[u8]) -> bool {
+    key.first() == Some(&SQL_NAMESPACE)
+}
+
+pub fn table_prefix(table_id: u64) -> Vec<u8> {
+    let mut key = vec![SQL_NAMESPACE, KIND_TABLE];
+    key.extend_from_slice(&table_id.to_be_bytes());
+    key
+}
+
+pub fn row_key(table_id: u64, primary_key: &[Value]) -> Vec<u8> {
+    let mut key = table_prefix(table_id);
+    encode_key(primary_key, &mut key);
+    key
+}
+
+pub fn index_prefix(table_id: u64, index_id: u32) -> Vec<u8> {
+    let mut key = vec![SQL_NAMESPACE, KIND_INDEX];
+    key.extend_from_slice(&table_id.to_be_bytes());
+    key.extend_from_slice(&index_id.to_be_bytes());
+    key
+}
+
+/// Id of the table a row key belongs to, or None if `key` is not a row key
+pub fn row_key_table(key: &[u8]) -> Option<u64> {
+    if key.len() < TABLE_PREFIX_LEN || key[..2] != [SQL_NAMESPACE, KIND_TABLE] {
+        return None;
+    }
+    Some(u64::from_be_bytes(key[2..TABLE_PREFIX_LEN].try_into().expect("eight bytes")))
+}
+
+/// Encoded primary key values of a row key
+pub fn primary_key_bytes(row_key: &[u8]) -> &[u8] {
+    &row_key[TABLE_PREFIX_LEN..]
+}
+
+/// Index entry key. Appending the row's primary key keeps entries for equal
+/// indexed values distinct; the entry's value is the row key.
+pub fn index_key(table_id: u64, index_id: u32, values: &[Value], row_key: &[u8]) -> Vec<u8> {
+    let mut key = index_prefix(table_id, index_id);
+    encode_key(values, &mut key);
+    key.extend_from_slice(primary_key_bytes(row_key));
+    key
+}
+
+/// Smallest key greater than every key starting with `prefix`
+pub fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // All 0xFF: there is no finite upper bound, use something past any real key
+    vec![u8::MAX; prefix.len() + 1]
+}
+
+/// Append the order-preserving encoding of `values` to `out`
+pub fn encode_key(values: &[Value], out: &mut Vec<u8>) {
+    for value in values {
+        match value {
+            Value::Null => out.push(TAG_NULL),
+            Value::Boolean(b) => {
+                out.push(TAG_BOOLEAN);
+                out.push(*b as u8);
+            }
+            Value::Integer(i) => {
+                out.push(TAG_INTEGER);
+                out.extend_from_slice(&((*i as u64) ^ (1 << 63)).to_be_bytes());
+            }
+            Value::Float(f) => {
+                out.push(TAG_FLOAT);
+                // Normalize -0.0 so it encodes equal to 0.0
+                let bits = if *f == 0.0 { 0 } else { f.to_bits() };
+                let ordered = if bits >> 63 == 1 { !bits } else { bits ^ (1 << 63) };
+                out.extend_from_slice(&ordered.to_be_bytes());
+            }
+            Value::Text(s) => {
+                out.push(TAG_TEXT);
+                encode_escaped(s.as_bytes(), out);
+            }
+            Value::Blob(bytes) => {
+                out.push(TAG_BLOB);
+                encode_escaped(bytes, out);
+            }
+            Value::Timestamp(micros) => {
+                out.push(TAG_TIMESTAMP);
+                out.extend_from_slice(&((*micros as u64) ^ (1 << 63)).to_be_bytes());
+            }
+        }
+    }
+}
+
+fn encode_escaped(bytes: &[u8], out: &mut Vec<u8>) {
+    for &byte in bytes {
+        out.push(byte);
+        if byte == ESCAPE {
+            out.push(ESCAPED_ZERO);
+        }
+    }
+    out.push(ESCAPE);
+    out.push(TERMINATOR);
+}
+
+/// Decode every value in a key-encoded byte string
+pub fn decode_key(mut bytes: &[u8]) -> Result<Vec<Value>> {
+    let mut values = Vec::new();
+    while !bytes.is_empty() {
+        let (value, rest) = decode_key_value(bytes)?;
+        values.push(value);
+        bytes = rest;
+    }
+    Ok(values)
+}
+
+fn decode_key_value(bytes: &[u8]) -> Result<(Value, &[u8])> {
+    let (&tag, rest) = bytes.split_first().ok_or_else(|| corrupt("empty key value"))?;
+    match tag {
+        TAG_NULL => Ok((Value::Null, rest)),
+        TAG_BOOLEAN => {
+            let (&b, rest) = rest.split_first().ok_or_else(|| corrupt("truncated boolean"))?;
+            Ok((Value::Boolean(b != 0), rest))
+        }
+        TAG_INTEGER => {
+            let (raw, rest) = take_u64(rest)?;
+            Ok((Value::Integer((raw ^ (1 << 63)) as i64), rest))
+        }
+        TAG_FLOAT => {
+            let (raw, rest) = take_u64(rest)?;
+            let bits = if raw >> 63 == 1 { raw ^ (1 << 63) } else { !raw };
+            Ok((Value::Float(f64::from_bits(bits)), rest))
+        }
+        TAG_TEXT => {
+            let (bytes, rest) = decode_escaped(rest)?;
+            let text = String::from_utf8(bytes).map_err(|_| corrupt("invalid UTF-8 in key"))?;
+            Ok((Value::Text(text), rest))
+        }
+        TAG_BLOB => {
+            let (bytes, rest) = decode_escaped(rest)?;
+            Ok((Value::Blob(bytes), rest))
+        }
+        TAG_TIMESTAMP => {
+            let (raw, rest) = take_u64(rest)?;
+            Ok((Value::Timestamp((raw ^ (1 << 63)) as i64), rest))
+        }
+        other => Err(corrupt(&format!("unknown key tag {:#04x}", other))),
+    }
+}
+
+fn take_u64(bytes: &[u8]) -> Result<(u64, &[u8])> {
+    if bytes.len() < 8 {
+        return Err(corrupt("truncated fixed-width value"));
+    }
+    let (head, rest) = bytes.split_at(8);
+    Ok((u64::from_be_bytes(head.try_into().unwrap()), rest))
+}
+
+fn decode_escaped(bytes: &[u8]) -> Result<(Vec<u8>, &[u8])> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == ESCAPE {
+            match bytes.get(i + 1) {
+                Some(&ESCAPED_ZERO) => out.push(ESCAPE),
+                Some(&TERMINATOR) => return Ok((out, &bytes[i + 2..])),
+                _ => return Err(corrupt("bad escape sequence in key")),
+            }
+            i += 2;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Err(corrupt("unterminated key value"))
+}
+
+/// Encode a row as `(column id, value)` pairs
+pub fn encode_row(columns: &[(u32, &Value)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(columns.len() as u16).to_le_bytes());
+    for (id, value) in columns {
+        out.extend_from_slice(&id.to_le_bytes());
+        encode_value(value, &mut out);
+    }
+    out
+}
+
+/// Decode a row written by `encode_row`
+pub fn decode_row(bytes: &[u8]) -> Result<Vec<(u32, Value)>> {
+    let mut reader = RowReader { bytes, pos: 0 };
+    let count = u16::from_le_bytes(reader.take_array()?);
+    let mut columns = Vec::with_capacity(count as usize);
+
+    for _ in 0..count {
+        let id = u32::from_le_bytes(reader.take_array()?);
+        columns.push((id, reader.take_value()?));
+    }
+
+    Ok(columns)
+}
+
+/// Decode only the columns in `ids` from a row written by `encode_row`, in
+/// the order of `ids`. Other values are skipped without being materialized,
+/// and columns the row does not store come back as None.
+pub fn decode_row_columns(bytes: &[u8], ids: &[u32]) -> Result<Vec<Option<Value>>> {
+    let mut reader = RowReader { bytes, pos: 0 };
+    let count = u16::from_le_bytes(reader.take_array()?);
+    let mut values = vec![None; ids.len()];
+    let mut missing = ids.len();
+
+    for _ in 0..count {
+        if missing == 0 {
+            break;
+        }
+        let id = u32::from_le_bytes(reader.take_array()?);
+        match ids.iter().position(|&wanted| wanted == id) {
+            Some(i) => {
+                values[i] = Some(reader.take_value()?);
+                missing -= 1;
+            }
+            None => reader.skip_value()?,
+        }
+    }
+
+    Ok(values)
+}
+
+/// Append one value in the tagged form rows store values in: a type tag
+/// followed by the value's bytes
+pub fn encode_value(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(TAG_NULL),
+        Value::Boolean(b) => {
+            out.push(TAG_BOOLEAN);
+            out.push(*b as u8);
+        }
+        Value::Integer(i) => {
+            out.push(TAG_INTEGER);
+            out.extend_from_slice(&i.to_le_bytes());
+        }
+        Value::Float(f) => {
+            out.push(TAG_FLOAT);
+            out.extend_from_slice(&f.to_le_bytes());
+        }
+        Value::Text(s) => {
+            out.push(TAG_TEXT);
+            out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Blob(bytes) => {
+            out.push(TAG_BLOB);
+            out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            out.extend_from_slice(bytes);
+        }
+        Value::Timestamp(micros) => {
+            out.push(TAG_TIMESTAMP);
+            out.extend_from_slice(&micros.to_le_bytes());
+        }
+    }
+}
+
+/// Decode a value written by `encode_value` at the start of `bytes`, with
+/// the number of bytes it took
+pub fn decode_value(bytes: &[u8]) -> Result<(Value, usize)> {
+    let mut reader = RowReader { bytes, pos: 0 };
+    let value = reader.take_value()?;
+    Ok((value, reader.pos))
+}
+
+struct RowReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl RowReader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        let slice = self.bytes.get(self.pos..self.pos + len)
+            .ok_or_else(|| corrupt("truncated row"))?;
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn take_prefixed(&mut self) -> Result<Vec<u8>> {
+        let len = u32::from_le_bytes(self.take_array()?) as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn take_value(&mut self) -> Result<Value> {
+        let [tag] = self.take_array()?;
+        Ok(match tag {
+            TAG_NULL => Value::Null,
+            TAG_BOOLEAN => Value::Boolean(self.take_array::<1>()?[0] != 0),
+            TAG_INTEGER => Value::Integer(i64::from_le_bytes(self.take_array()?)),
+            TAG_FLOAT => Value::Float(f64::from_le_bytes(self.take_array()?)),
+            TAG_TEXT => {
+                let bytes = self.take_prefixed()?;
+                Value::Text(String::from_utf8(bytes).map_err(|_| corrupt("invalid UTF-8 in row"))?)
+            }
+            TAG_BLOB => Value::Blob(self.take_prefixed()?),
+            TAG_TIMESTAMP => Value::Timestamp(i64::from_le_bytes(self.take_array()?)),
+            other => return Err(corrupt(&format!("unknown row tag {:#04x}", other))),
+        })
+    }
+
+    fn skip_value(&mut self) -> Result<()> {
+        let [tag] = self.take_array()?;
+        let len = match tag {
+            TAG_NULL => 0,
+            TAG_BOOLEAN => 1,
+            TAG_INTEGER | TAG_FLOAT | TAG_TIMESTAMP => 8,
+            TAG_TEXT | TAG_BLOB => u32::from_le_bytes(self.take_array()?) as usize,
+            other => return Err(corrupt(&format!("unknown row tag {:#04x}", other))),
+        };
+        self.take(len).map(|_| ())
+    }
+}
+
+fn corrupt(message: &str) -> QueryError {
+    QueryError::Execution(format!("corrupt stored data: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(values: &[Value]) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode_key(values, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_key_encoding_preserves_order() {
+        let ordered = [
+            vec![Value::Integer(i64::MIN)],
+            vec![Value::Integer(-1)],
+            vec![Value::Integer(0)],
+            vec![Value::Integer(7)],
+            vec![Value::Integer(i64::MAX)],
+        ];
+        assert!(ordered.windows(2).all(|w| key(&w[0]) < key(&w[1])));
+
+        let floats = [-1e300, -2.5, -0.0, 0.5, 3.0, f64::INFINITY];
+        assert!(floats.windows(2).all(|w| key(&[Value::Float(w[0])]) <= key(&[Value::Float(w[1])])));
+
+        let texts = ["", "a", "a\0", "a\0b", "ab", "b"];
+        assert!(texts.windows(2).all(|w| {
+            key(&[Value::Text(w[0].to_string())]) < key(&[Value::Text(w[1].to_string())])
+        }));
+
+        // Composite keys order by the first column, then the second
+        assert!(
+            key(&[Value::Text("a".to_string()), Value::Integer(9)])
+                < key(&[Value::Text("ab".to_string()), Value::Integer(1)])
+        );
+    }
+
+    #[test]
+    fn test_key_and_row_round_trip() {
+        let values = vec![
+            Value::Null,
+            Value::Boolean(true),
+            Value::Integer(-42),
+            Value::Float(-2.5),
+            Value::Text("zero\0byte".to_string()),
+            Value::Blob(vec![0, 255, 0, 1]),
+        ];
+        assert_eq!(decode_key(&key(&values)).unwrap(), values);
+
+        let columns: Vec<(u32, &Value)> = values.iter().enumerate().map(|(i, v)| (i as u32 * 3, v)).collect();
+        let decoded = decode_row(&encode_row(&columns)).unwrap();
+        assert_eq!(decoded.len(), values.len());
+        assert!(decoded.iter().zip(&columns).all(|((id, v), (eid, ev))| id == eid && v == *ev));
+
+        // Projected decoding skips over the other values, whatever their width
+        let projected = decode_row_columns(&encode_row(&columns), &[15, 4, 3]).unwrap();
+        assert_eq!(projected, vec![Some(values[5].clone()), None, Some(values[1].clone())]);
+
+        assert!(decode_row(&[1, 0, 0]).is_err());
+    }
+}
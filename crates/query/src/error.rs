@@ -19,6 +19,33 @@ pub enum QueryError {
     
     #[error("Column not found: {0}")]
     ColumnNotFound(String),
+    
+    #[error("Table already exists: {0}")]
+    TableExists(String),
+    
+    #[error("Database not found: {0}")]
+    DatabaseNotFound(String),
+    
+    #[error("Database already exists: {0}")]
+    DatabaseExists(String),
+    
+    #[error("Constraint violation: {constraint} for value {value}")]
+    ConstraintViolation { constraint: String, value: String },
+    
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    
+    #[error("Storage error: {0}")]
+    Storage(#[from] nextdb_storage::StorageError),
+    
+    #[error("Transaction error: {0}")]
+    Transaction(#[from] nextdb_transaction::TransactionError),
+    
+    #[error("Not the leader{}", leader.as_ref().map(|leader| format!("; the leader is at {}", leader)).unwrap_or_default())]
+    NotLeader { leader: Option<String> },
+    
+    #[error("Replication error: {0}")]
+    Replication(String),
 }
 
 pub type Result<T> = std::result::Result<T, QueryError>;
\ No newline at end of file
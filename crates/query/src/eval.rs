@@ -0,0 +1,390 @@
+use crate::{
+    error::{Result, QueryError},
+    ast::{BinaryOp, Expr, UnaryOp},
+    functions,
+    value::Value,
+};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+/// Evaluate `expr` against a row whose column names are `columns`
+pub fn eval(expr: &Expr, columns: &[String], row: &[Value]) -> Result<Value> {
+    eval_with(expr, columns, row, &[])
+}
+
+/// Evaluate `expr`, answering its `IN (SELECT ...)` tests from `sets`: the
+/// materialized subquery results, in the order the subqueries appear in `expr`
+pub fn eval_with(expr: &Expr, columns: &[String], row: &[Value], sets: &[ValueSet]) -> Result<Value> {
+    match expr {
+        Expr::Column(name) => columns.iter()
+            .position(|c| c == name)
+            .map(|i| row[i].clone())
+            .ok_or_else(|| QueryError::ColumnNotFound(name.clone())),
+        Expr::Literal(literal) => Ok(Value::from_literal(literal)),
+        Expr::Unary { op, expr } => eval_unary(*op, eval_with(expr, columns, row, sets)?),
+        Expr::Binary { left, op: BinaryOp::And, right } => {
+            let left_value = eval_bool(left, columns, row, sets)?;
+            if left_value == Some(false) {
+                return Ok(Value::Boolean(false));
+            }
+            Ok(match (left_value, eval_bool(right, columns, row, after(left, sets))?) {
+                (_, Some(false)) => Value::Boolean(false),
+                (Some(true), Some(true)) => Value::Boolean(true),
+                _ => Value::Null,
+            })
+        }
+        Expr::Binary { left, op: BinaryOp::Or, right } => {
+            let left_value = eval_bool(left, columns, row, sets)?;
+            if left_value == Some(true) {
+                return Ok(Value::Boolean(true));
+            }
+            Ok(match (left_value, eval_bool(right, columns, row, after(left, sets))?) {
+                (_, Some(true)) => Value::Boolean(true),
+                (Some(false), Some(false)) => Value::Boolean(false),
+                _ => Value::Null,
+            })
+        }
+        Expr::Binary { left, op, right } => eval_binary(
+            *op,
+            eval_with(left, columns, row, sets)?,
+            eval_with(right, columns, row, after(left, sets))?,
+        ),
+        Expr::IsNull { expr, negated } => {
+            Ok(Value::Boolean(eval_with(expr, columns, row, sets)?.is_null() != *negated))
+        }
+        Expr::Function { name, args } => {
+            let mut values = Vec::with_capacity(args.len());
+            let mut rest = sets;
+            for arg in args {
+                values.push(eval_with(arg, columns, row, rest)?);
+                rest = after(arg, rest);
+            }
+            functions::lookup(name)?.call(values)
+        }
+        Expr::Aggregate { .. } => Err(QueryError::Execution(format!(
+            "aggregate function {} evaluated outside of an aggregation", expr
+        ))),
+        Expr::InSubquery { expr, negated, .. } => {
+            let Some((set, rest)) = sets.split_first() else {
+                return Err(QueryError::Execution("subquery was not materialized".to_string()));
+            };
+            let found = set.contains(&eval_with(expr, columns, row, rest)?);
+            Ok(found.map_or(Value::Null, |found| Value::Boolean(found != *negated)))
+        }
+        Expr::InList { expr, list, negated } => {
+            let value = eval_with(expr, columns, row, sets)?;
+            // Any match decides it; otherwise a NULL on either side makes it unknown
+            let mut unknown = value.is_null();
+            let mut rest = after(expr, sets);
+            for item in list {
+                match eval_binary(BinaryOp::Eq, value.clone(), eval_with(item, columns, row, rest)?)? {
+                    Value::Boolean(true) => return Ok(Value::Boolean(!*negated)),
+                    Value::Null => unknown = true,
+                    _ => {}
+                }
+                rest = after(item, rest);
+            }
+            Ok(if unknown { Value::Null } else { Value::Boolean(*negated) })
+        }
+        Expr::Default => Err(QueryError::Execution("DEFAULT is only allowed as an INSERT value".to_string())),
+        Expr::Parameter(n) => Err(QueryError::Execution(format!("no value was bound to parameter ${}", n))),
+    }
+}
+
+/// Whether a predicate holds for a row. NULL counts as false.
+pub fn is_true(expr: &Expr, columns: &[String], row: &[Value]) -> Result<bool> {
+    is_true_with(expr, columns, row, &[])
+}
+
+pub fn is_true_with(expr: &Expr, columns: &[String], row: &[Value], sets: &[ValueSet]) -> Result<bool> {
+    Ok(eval_bool(expr, columns, row, sets)? == Some(true))
+}
+
+fn eval_bool(expr: &Expr, columns: &[String], row: &[Value], sets: &[ValueSet]) -> Result<Option<bool>> {
+    match eval_with(expr, columns, row, sets)? {
+        Value::Boolean(b) => Ok(Some(b)),
+        Value::Null => Ok(None),
+        other => Err(QueryError::Execution(format!(
+            "expected BOOLEAN for {}, got {}", expr, other.type_name()
+        ))),
+    }
+}
+
+/// The sets left for the expressions following `expr`
+fn after<'a>(expr: &Expr, sets: &'a [ValueSet]) -> &'a [ValueSet] {
+    sets.get(count_subqueries(expr)..).unwrap_or(&[])
+}
+
+/// Number of `IN (SELECT ...)` tests in `expr`, not counting those nested
+/// inside the subqueries themselves
+pub fn count_subqueries(expr: &Expr) -> usize {
+    match expr {
+        Expr::Column(_) | Expr::Literal(_) | Expr::Aggregate { .. } | Expr::Default | Expr::Parameter(_) => 0,
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => count_subqueries(expr),
+        Expr::Binary { left, right, .. } => count_subqueries(left) + count_subqueries(right),
+        Expr::Function { args, .. } => args.iter().map(count_subqueries).sum(),
+        Expr::InSubquery { expr, .. } => 1 + count_subqueries(expr),
+        Expr::InList { expr, list, .. } => count_subqueries(expr) + list.iter().map(count_subqueries).sum::<usize>(),
+    }
+}
+
+/// Materialized single-column result of an `IN (SELECT ...)` subquery
+#[derive(Debug, Default)]
+pub struct ValueSet {
+    values: HashSet<SetKey>,
+    has_null: bool,
+}
+
+/// Hashable form of a non-NULL value. Integral floats hash as integers so
+/// `1 IN (SELECT 1.0)` matches, as `1 = 1.0` does.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum SetKey {
+    Integer(i64),
+    Float(u64),
+    Text(String),
+    Boolean(bool),
+    Blob(Vec<u8>),
+    Timestamp(i64),
+}
+
+impl ValueSet {
+    /// Add `value`, returning whether it was new
+    pub fn insert(&mut self, value: Value) -> bool {
+        match value {
+            Value::Null => !std::mem::replace(&mut self.has_null, true),
+            value => self.values.insert(set_key(value)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len() + self.has_null as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// SQL `value IN (set)`. Unknown (None) if no member matches and either
+    /// `value` is NULL or the set contains a NULL.
+    pub fn contains(&self, value: &Value) -> Option<bool> {
+        if self.is_empty() {
+            return Some(false);
+        }
+        if value.is_null() {
+            return None;
+        }
+        if self.values.contains(&set_key(value.clone())) {
+            Some(true)
+        } else if self.has_null {
+            None
+        } else {
+            Some(false)
+        }
+    }
+}
+
+fn set_key(value: Value) -> SetKey {
+    match value {
+        Value::Integer(i) => SetKey::Integer(i),
+        Value::Float(f) if f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64 => {
+            SetKey::Integer(f as i64)
+        }
+        Value::Float(f) => SetKey::Float(f.to_bits()),
+        Value::Text(s) => SetKey::Text(s),
+        Value::Boolean(b) => SetKey::Boolean(b),
+        Value::Blob(b) => SetKey::Blob(b),
+        Value::Timestamp(t) => SetKey::Timestamp(t),
+        Value::Null => unreachable!("NULL is tracked separately"),
+    }
+}
+
+fn eval_unary(op: UnaryOp, value: Value) -> Result<Value> {
+    match (op, value) {
+        (_, Value::Null) => Ok(Value::Null),
+        (UnaryOp::Not, Value::Boolean(b)) => Ok(Value::Boolean(!b)),
+        (UnaryOp::Minus, Value::Integer(i)) => i.checked_neg()
+            .map(Value::Integer)
+            .ok_or_else(|| QueryError::Execution("integer overflow".to_string())),
+        (UnaryOp::Minus, Value::Float(f)) => Ok(Value::Float(-f)),
+        (UnaryOp::Plus, value @ (Value::Integer(_) | Value::Float(_))) => Ok(value),
+        (op, value) => Err(QueryError::Execution(format!(
+            "operator {:?} cannot be applied to {}", op, value.type_name()
+        ))),
+    }
+}
+
+fn eval_binary(op: BinaryOp, left: Value, right: Value) -> Result<Value> {
+    if left.is_null() || right.is_null() {
+        return Ok(Value::Null);
+    }
+
+    let compare = |accept: fn(Ordering) -> bool| -> Result<Value> {
+        Ok(left.sql_cmp(&right)?.map_or(Value::Null, |o| Value::Boolean(accept(o))))
+    };
+
+    match op {
+        BinaryOp::Eq => compare(|o| o == Ordering::Equal),
+        BinaryOp::NotEq => compare(|o| o != Ordering::Equal),
+        BinaryOp::Lt => compare(|o| o == Ordering::Less),
+        BinaryOp::LtEq => compare(|o| o != Ordering::Greater),
+        BinaryOp::Gt => compare(|o| o == Ordering::Greater),
+        BinaryOp::GtEq => compare(|o| o != Ordering::Less),
+        BinaryOp::Concat => Ok(Value::Text(format!("{}{}", left, right))),
+        BinaryOp::Plus | BinaryOp::Minus | BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Modulo => {
+            eval_arithmetic(op, left, right)
+        }
+        BinaryOp::And | BinaryOp::Or => unreachable!("logical operators are evaluated lazily"),
+    }
+}
+
+fn eval_arithmetic(op: BinaryOp, left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        // Timestamp arithmetic is in seconds: ts ± n shifts by n seconds and
+        // the difference of two timestamps is a FLOAT number of seconds
+        (Value::Timestamp(t), Value::Timestamp(u)) if op == BinaryOp::Minus => {
+            Ok(Value::Float((t as f64 - u as f64) / MICROS_PER_SECOND as f64))
+        }
+        (Value::Timestamp(t), seconds) | (seconds, Value::Timestamp(t))
+            if op == BinaryOp::Plus && as_float(&seconds).is_some() =>
+        {
+            shift_timestamp(t, &seconds, 1)
+        }
+        (Value::Timestamp(t), seconds) if op == BinaryOp::Minus && as_float(&seconds).is_some() => {
+            shift_timestamp(t, &seconds, -1)
+        }
+        (Value::Integer(a), Value::Integer(b)) => {
+            let result = match op {
+                BinaryOp::Plus => a.checked_add(b),
+                BinaryOp::Minus => a.checked_sub(b),
+                BinaryOp::Multiply => a.checked_mul(b),
+                BinaryOp::Divide | BinaryOp::Modulo if b == 0 => {
+                    return Err(QueryError::Execution("division by zero".to_string()))
+                }
+                BinaryOp::Divide => a.checked_div(b),
+                _ => a.checked_rem(b),
+            };
+            result.map(Value::Integer)
+                .ok_or_else(|| QueryError::Execution("integer overflow".to_string()))
+        }
+        (a, b) => {
+            let (Some(x), Some(y)) = (as_float(&a), as_float(&b)) else {
+                return Err(QueryError::Execution(format!(
+                    "operator {} cannot be applied to {} and {}", op, a.type_name(), b.type_name()
+                )));
+            };
+            if matches!(op, BinaryOp::Divide | BinaryOp::Modulo) && y == 0.0 {
+                return Err(QueryError::Execution("division by zero".to_string()));
+            }
+            Ok(Value::Float(match op {
+                BinaryOp::Plus => x + y,
+                BinaryOp::Minus => x - y,
+                BinaryOp::Multiply => x * y,
+                BinaryOp::Divide => x / y,
+                _ => x % y,
+            }))
+        }
+    }
+}
+
+const MICROS_PER_SECOND: i64 = 1_000_000;
+
+fn shift_timestamp(micros: i64, seconds: &Value, sign: i64) -> Result<Value> {
+    let delta = match seconds {
+        Value::Integer(s) => s.checked_mul(MICROS_PER_SECOND * sign),
+        Value::Float(s) => {
+            let delta = (s * MICROS_PER_SECOND as f64).round() * sign as f64;
+            (delta.is_finite() && delta.abs() < i64::MAX as f64).then_some(delta as i64)
+        }
+        _ => None,
+    };
+    delta.and_then(|delta| micros.checked_add(delta))
+        .map(Value::Timestamp)
+        .ok_or_else(|| QueryError::Execution("timestamp out of range".to_string()))
+}
+
+fn as_float(value: &Value) -> Option<f64> {
+    match value {
+        Value::Integer(i) => Some(*i as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::SqlParser;
+    use crate::ast::{SelectItem, SqlStatement};
+
+    fn eval_sql(expr: &str) -> Result<Value> {
+        let SqlStatement::Select(select) = SqlParser::parse(&format!("SELECT {}", expr)).unwrap() else {
+            unreachable!()
+        };
+        let SelectItem::Expr { expr, .. } = &select.columns[0] else {
+            unreachable!()
+        };
+        eval(expr, &["x".to_string()], &[Value::Integer(10)])
+    }
+
+    #[test]
+    fn test_eval_expressions() {
+        assert_eq!(eval_sql("x * 2 + 1").unwrap(), Value::Integer(21));
+        assert_eq!(eval_sql("x / 4.0").unwrap(), Value::Float(2.5));
+        assert_eq!(eval_sql("'n=' || x").unwrap(), Value::Text("n=10".to_string()));
+        assert_eq!(eval_sql("x > 5 AND NULL").unwrap(), Value::Null);
+        assert_eq!(eval_sql("x < 5 AND NULL").unwrap(), Value::Boolean(false));
+        assert_eq!(eval_sql("NULL OR x = 10").unwrap(), Value::Boolean(true));
+        assert_eq!(eval_sql("x + NULL IS NULL").unwrap(), Value::Boolean(true));
+        assert!(matches!(eval_sql("x / 0"), Err(QueryError::Execution(_))));
+        assert!(matches!(eval_sql("y"), Err(QueryError::ColumnNotFound(_))));
+    }
+
+    #[test]
+    fn test_timestamp_arithmetic() {
+        let ts = |s: &str| Value::Timestamp(crate::value::parse_timestamp(s).unwrap());
+        assert_eq!(eval_sql("TIMESTAMP '2024-01-01 00:00:00' + 90").unwrap(), ts("2024-01-01T00:01:30Z"));
+        assert_eq!(eval_sql("x + TIMESTAMP '2024-01-01'").unwrap(), ts("2024-01-01T00:00:10Z"));
+        assert_eq!(eval_sql("TIMESTAMP '2024-01-01' - 0.5").unwrap(), ts("2023-12-31T23:59:59.5Z"));
+        assert_eq!(
+            eval_sql("TIMESTAMP '2024-01-02' - TIMESTAMP '2024-01-01 12:00:00'").unwrap(),
+            Value::Float(43200.0)
+        );
+        assert!(eval_sql("10 - TIMESTAMP '2024-01-01'").is_err());
+        assert!(eval_sql("TIMESTAMP '2024-01-01' * 2").is_err());
+        assert!(SqlParser::parse("SELECT TIMESTAMP 'yesterday'").is_err());
+    }
+
+    #[test]
+    fn test_value_set_null_semantics() {
+        let mut set = ValueSet::default();
+        set.insert(Value::Integer(1));
+        set.insert(Value::Float(2.0));
+        assert_eq!(set.contains(&Value::Float(1.0)), Some(true));
+        assert_eq!(set.contains(&Value::Integer(2)), Some(true));
+        assert_eq!(set.contains(&Value::Integer(3)), Some(false));
+        assert_eq!(set.contains(&Value::Null), None);
+
+        // A NULL member makes every non-match unknown
+        set.insert(Value::Null);
+        assert_eq!(set.contains(&Value::Integer(1)), Some(true));
+        assert_eq!(set.contains(&Value::Integer(3)), None);
+
+        // Nothing is in the empty set, not even NULL
+        assert_eq!(ValueSet::default().contains(&Value::Null), Some(false));
+    }
+
+    #[test]
+    fn test_in_list_null_semantics() {
+        assert_eq!(eval_sql("x IN (1, 10)").unwrap(), Value::Boolean(true));
+        assert_eq!(eval_sql("x IN (1, 10.0)").unwrap(), Value::Boolean(true));
+        assert_eq!(eval_sql("x IN (1, 2)").unwrap(), Value::Boolean(false));
+        assert_eq!(eval_sql("x NOT IN (1, 2)").unwrap(), Value::Boolean(true));
+        assert_eq!(eval_sql("x IN (5 + 5)").unwrap(), Value::Boolean(true));
+
+        // A NULL in the list only matters when nothing matches
+        assert_eq!(eval_sql("x IN (NULL, 10)").unwrap(), Value::Boolean(true));
+        assert_eq!(eval_sql("x IN (NULL, 1)").unwrap(), Value::Null);
+        assert_eq!(eval_sql("x NOT IN (NULL, 1)").unwrap(), Value::Null);
+        assert_eq!(eval_sql("NULL IN (1, 2)").unwrap(), Value::Null);
+    }
+}
@@ -1,42 +1,3183 @@
-use crate::{error::{Result, QueryError}, planner::PhysicalPlan};
-use serde::{Deserialize, Serialize};
+use crate::{
+    error::{Result, QueryError},
+    ast::{AlterTableOperation, ColumnDef, DataType, Expr, IsolationLevel, SqlStatement},
+    cache::{self, QueryCache, QueryCacheConfig},
+    catalog::{Catalog, Column, IndexDef, TableSchema},
+    cursor::QueryCursor,
+    encoding,
+    eval::{self, ValueSet},
+    hash_aggregate,
+    locks::KeyLocks,
+    parser::SqlParser,
+    planner::{CatalogView, PhysicalPlan, QueryPlanner},
+    replication::{self, ReplicatedWrite, Replicator},
+    result::{ColumnMeta, ResultSet},
+    session::{Claim, OpenTransaction, ReadView, SessionId},
+    slow_log::{SlowQuery, SlowQueryConfig, SlowQueryLog},
+    sort,
+    spill::{MemoryBudget, SpillConfig},
+    value::Value,
+};
+use futures::future;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use nextdb_storage::{CompactionSummary, LSMTree, WriteOp};
+use nextdb_transaction::{TransactionError, TransactionId, TransactionManager};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ResultSet {
-    pub columns: Vec<String>,
-    pub rows: Vec<Vec<String>>,
+// Rows fetched from storage per scan call
+const SCAN_BATCH_SIZE: usize = 256;
+
+pub type Row = Vec<Value>;
+
+pub(crate) type RowStream = BoxStream<'static, Result<Row>>;
+type KeyedRowStream = BoxStream<'static, Result<(Vec<u8>, Row)>>;
+
+/// Caps on the size of a materialized result. A query exceeding either one
+/// fails with "result too large" instead of growing without bound.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResultLimits {
+    pub max_result_rows: Option<usize>,
+    /// Measured with `Value::size_hint`, so approximate
+    pub max_result_bytes: Option<usize>,
+}
+
+impl ResultLimits {
+    pub(crate) fn check(&self, rows: usize, bytes: usize) -> Result<()> {
+        if self.max_result_rows.is_some_and(|max| rows > max) {
+            return Err(QueryError::Execution(format!(
+                "result too large: more than {} rows", self.max_result_rows.unwrap()
+            )));
+        }
+        if self.max_result_bytes.is_some_and(|max| bytes > max) {
+            return Err(QueryError::Execution(format!(
+                "result too large: more than {} bytes", self.max_result_bytes.unwrap()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Runtime statistics of one plan node, reported by EXPLAIN ANALYZE
+#[derive(Debug, Default)]
+pub struct OperatorStats {
+    /// Rows the node emitted
+    pub rows: AtomicU64,
+    /// Runs written to disk because the input did not fit the memory budget
+    pub spilled_runs: AtomicU64,
+    pub spilled_rows: AtomicU64,
+    pub spilled_bytes: AtomicU64,
+    /// Most memory the node held at once, for nodes that track it
+    pub peak_memory: AtomicU64,
+}
+
+impl OperatorStats {
+    pub(crate) fn record_spill(&self, rows: u64, bytes: u64) {
+        self.spilled_runs.fetch_add(1, Ordering::Relaxed);
+        self.spilled_rows.fetch_add(rows, Ordering::Relaxed);
+        self.spilled_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_peak_memory(&self, bytes: usize) {
+        self.peak_memory.fetch_max(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn describe(&self) -> String {
+        let mut text = format!("rows: {}", self.rows.load(Ordering::Relaxed));
+        let runs = self.spilled_runs.load(Ordering::Relaxed);
+        if runs > 0 {
+            text.push_str(&format!(
+                ", spilled runs: {}, spilled bytes: {}", runs, self.spilled_bytes.load(Ordering::Relaxed)
+            ));
+        }
+        let peak = self.peak_memory.load(Ordering::Relaxed);
+        if peak > 0 {
+            text.push_str(&format!(", peak memory: {}", peak));
+        }
+        text
+    }
 }
 
-/// Query executor that executes physical plans
-pub struct QueryExecutor;
+/// Where a plan node being streamed records its statistics and counts the
+/// memory it holds. Nodes are numbered in the order `PhysicalPlan::explain`
+/// lists them.
+#[derive(Clone, Default)]
+struct Probe {
+    stats: Option<Arc<Vec<Arc<OperatorStats>>>>,
+    node: usize,
+    /// Memory held by every node of the query, when it has a limit
+    memory: Option<Arc<MemoryBudget>>,
+}
+
+impl Probe {
+    /// The probe of the root of a query
+    fn query(memory_limit: Option<usize>) -> Self {
+        Self { memory: memory_limit.map(|limit| Arc::new(MemoryBudget::new(limit))), ..Self::default() }
+    }
+
+    /// A probe recording statistics for `plan`, within the same query
+    fn analyze(&self, plan: &PhysicalPlan) -> Self {
+        let stats = (0..plan.node_count()).map(|_| Arc::default()).collect();
+        Self { stats: Some(Arc::new(stats)), node: 0, memory: self.memory.clone() }
+    }
+
+    /// The probe of the node `offset` places after this one
+    fn child(&self, offset: usize) -> Self {
+        Self { stats: self.stats.clone(), node: self.node + offset, memory: self.memory.clone() }
+    }
+
+    fn stats(&self) -> Option<Arc<OperatorStats>> {
+        self.stats.as_ref().map(|stats| stats[self.node].clone())
+    }
+}
+
+/// Query executor that executes physical plans against the storage engine.
+///
+/// Read plans run as a pipeline of row streams pulled from the root, so scans
+/// only fetch as many rows from storage as the consumer asks for.
+pub struct QueryExecutor {
+    storage: Arc<LSMTree>,
+    catalog: Arc<Catalog>,
+    cache: Option<QueryCache>,
+    slow_log: Option<SlowQueryLog>,
+    replicator: Option<Arc<dyn Replicator>>,
+    limits: ResultLimits,
+    spill: SpillConfig,
+    query_memory_limit: Option<usize>,
+    key_locks: KeyLocks,
+    transactions: Arc<TransactionManager>,
+    sessions: parking_lot::Mutex<HashMap<SessionId, Arc<tokio::sync::Mutex<OpenTransaction>>>>,
+    poison_on_error: bool,
+    projection_pushdown: bool,
+    key_lookups: bool,
+    columns_decoded: Arc<AtomicU64>,
+}
 
 impl QueryExecutor {
-    pub async fn execute(_plan: PhysicalPlan) -> Result<ResultSet> {
-        // Simplified executor - return empty result set
-        Ok(ResultSet {
-            columns: vec!["id".to_string(), "name".to_string()],
-            rows: vec![
-                vec!["1".to_string(), "Alice".to_string()],
-                vec!["2".to_string(), "Bob".to_string()],
-            ],
+    pub fn new(storage: Arc<LSMTree>, catalog: Arc<Catalog>) -> Self {
+        Self {
+            storage,
+            catalog,
+            cache: None,
+            slow_log: None,
+            replicator: None,
+            limits: ResultLimits::default(),
+            spill: SpillConfig::default(),
+            query_memory_limit: None,
+            key_locks: KeyLocks::default(),
+            transactions: Arc::new(TransactionManager::new()),
+            sessions: parking_lot::Mutex::new(HashMap::new()),
+            poison_on_error: true,
+            projection_pushdown: true,
+            key_lookups: true,
+            columns_decoded: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Memory budget and temporary directory for operators that spill to disk
+    pub fn with_spill_config(mut self, spill: SpillConfig) -> Self {
+        self.spill = spill;
+        self
+    }
+
+    /// Cap the memory one query may hold in sort buffers, aggregation groups
+    /// and `IN (SELECT ...)` sets, as measured by `Value::size_hint`. Sorts
+    /// and aggregations over the cap spill to disk; a query still over it
+    /// fails with "out of memory".
+    pub fn with_query_memory_limit(mut self, bytes: usize) -> Self {
+        self.query_memory_limit = Some(bytes);
+        self
+    }
+
+    pub fn with_result_limits(mut self, limits: ResultLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Cache the results of SELECTs run through `execute_sql` until a table
+    /// they read changes
+    pub fn with_result_cache(mut self, config: QueryCacheConfig) -> Self {
+        self.cache = Some(QueryCache::new(config));
+        self
+    }
+
+    /// Log statements that run longer than `config.threshold`. Statements
+    /// read through a cursor are not timed.
+    pub fn with_slow_query_log(mut self, config: SlowQueryConfig) -> Self {
+        self.slow_log = Some(SlowQueryLog::new(config));
+        self
+    }
+
+    /// Hand writes to `replicator` instead of writing them to storage, for
+    /// a replica in a cluster. See `replication`.
+    pub fn with_replicator(mut self, replicator: Arc<dyn Replicator>) -> Self {
+        self.replicator = Some(replicator);
+        self
+    }
+
+    /// Run transactions through `transactions`, for sharing its clock and
+    /// transaction table with the rest of the server
+    pub fn with_transaction_manager(mut self, transactions: Arc<TransactionManager>) -> Self {
+        self.transactions = transactions;
+        self
+    }
+
+    /// Whether a statement that fails inside a transaction aborts it, so
+    /// later statements fail until ROLLBACK (the default). When disabled the
+    /// failed statement has no effect and the transaction carries on.
+    pub fn with_transaction_poisoning(mut self, enabled: bool) -> Self {
+        self.poison_on_error = enabled;
+        self
+    }
+
+    /// Whether table scans decode only the columns a query reads (the
+    /// default). When disabled they decode every column of each row.
+    pub fn with_projection_pushdown(mut self, enabled: bool) -> Self {
+        self.projection_pushdown = enabled;
+        self
+    }
+
+    /// Whether a primary key `IN` list is answered by fetching just the
+    /// listed keys (the default). When disabled the table is scanned and
+    /// filtered instead.
+    pub fn with_key_lookups(mut self, enabled: bool) -> Self {
+        self.key_lookups = enabled;
+        self
+    }
+
+    /// Load the catalog from `storage` and build an executor over it
+    pub async fn open(storage: Arc<LSMTree>) -> Result<Self> {
+        let catalog = Arc::new(Catalog::open(storage.clone()).await?);
+        Ok(Self::new(storage, catalog))
+    }
+
+    pub fn catalog(&self) -> &Arc<Catalog> {
+        &self.catalog
+    }
+
+    pub fn result_cache(&self) -> Option<&QueryCache> {
+        self.cache.as_ref()
+    }
+
+    pub fn slow_query_log(&self) -> Option<&SlowQueryLog> {
+        self.slow_log.as_ref()
+    }
+
+    /// Column values decoded from stored rows by this executor's scans
+    pub fn columns_decoded(&self) -> u64 {
+        self.columns_decoded.load(Ordering::Relaxed)
+    }
+
+    pub fn transactions(&self) -> &Arc<TransactionManager> {
+        &self.transactions
+    }
+
+    /// The transaction open in `session`, if any
+    pub fn session_transaction(&self, session: SessionId) -> Option<TransactionId> {
+        let txn = self.sessions.lock().get(&session)?.clone();
+        let id = txn.try_lock().ok()?.id;
+        Some(id)
+    }
+
+    /// Whether the session's open transaction has failed and only accepts
+    /// ROLLBACK
+    pub fn session_transaction_failed(&self, session: SessionId) -> bool {
+        let Some(txn) = self.sessions.lock().get(&session).cloned() else {
+            return false;
+        };
+        let failed = txn.try_lock().is_ok_and(|txn| txn.failed);
+        failed
+    }
+
+    /// Parse, plan and execute a single statement on its own, outside any
+    /// session. Use `execute_sql_in` for transactions.
+    pub async fn execute_sql(&self, sql: &str) -> Result<ResultSet> {
+        let statement = SqlParser::parse(sql)?;
+        self.execute_statement(statement).await
+    }
+
+    /// Execute a statement in `session`. BEGIN opens a transaction that
+    /// later statements of the session run in until COMMIT or ROLLBACK;
+    /// outside one, each statement commits by itself.
+    pub async fn execute_sql_in(&self, session: SessionId, sql: &str) -> Result<ResultSet> {
+        match SqlParser::parse(sql) {
+            Ok(statement) => self.execute_statement_in(session, statement).await,
+            Err(e) => {
+                self.fail_transaction(session).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Fail the session's open transaction, if any, as a statement that
+    /// does not parse does, so that only ROLLBACK is accepted
+    pub async fn fail_transaction(&self, session: SessionId) {
+        let open = self.sessions.lock().get(&session).cloned();
+        if let Some(txn) = open {
+            self.fail(&mut *txn.lock().await);
+        }
+    }
+
+    /// Like `execute_sql_in`, for a statement parsed beforehand, as a
+    /// prepared statement is
+    pub async fn execute_statement_in(&self, session: SessionId, statement: SqlStatement) -> Result<ResultSet> {
+        let open = self.sessions.lock().get(&session).cloned();
+        match (statement, open) {
+            (SqlStatement::Begin { isolation_level }, _) => {
+                self.begin(session, isolation_level.unwrap_or(self.transactions.default_isolation_level())).await
+            }
+            (SqlStatement::Commit, _) => self.commit(session).await,
+            (SqlStatement::Rollback, _) => self.rollback(session).await,
+            (statement, None) => self.execute_statement(statement).await,
+            (statement, Some(txn)) => {
+                let mut txn = txn.lock().await;
+                if txn.failed {
+                    return Err(TransactionError::Aborted.into());
+                }
+                let logged = self.logged(&statement);
+                let plan = QueryPlanner::plan(statement, &self.catalog);
+                let result = match plan {
+                    Ok(plan) => self.execute_plan(plan, Some(&mut txn), logged).await,
+                    Err(e) => Err(e),
+                };
+                if result.is_err() {
+                    self.fail(&mut txn);
+                }
+                result
+            }
+        }
+    }
+
+    /// Roll back the session's open transaction, if any, when its client goes away
+    pub async fn end_session(&self, session: SessionId) -> Result<()> {
+        let open = self.sessions.lock().remove(&session);
+        if let Some(txn) = open {
+            self.transactions.abort(txn.lock().await.id).await?;
+        }
+        Ok(())
+    }
+
+    /// Roll back every session's open transaction, for shutting down.
+    /// Returns how many were rolled back.
+    pub async fn end_all_sessions(&self) -> Result<usize> {
+        let open: Vec<_> = self.sessions.lock().drain().map(|(_, txn)| txn).collect();
+        for txn in &open {
+            self.transactions.abort(txn.lock().await.id).await?;
+        }
+        Ok(open.len())
+    }
+
+    async fn begin(&self, session: SessionId, isolation_level: IsolationLevel) -> Result<ResultSet> {
+        if self.sessions.lock().contains_key(&session) {
+            return Err(QueryError::Invalid("a transaction is already in progress".to_string()));
+        }
+        if let Some(replicator) = &self.replicator {
+            replicator.read_barrier().await?;
+        }
+        let id = self.transactions.begin(isolation_level).await?;
+        let txn = Arc::new(tokio::sync::Mutex::new(OpenTransaction::new(id)));
+        self.sessions.lock().insert(session, txn);
+        Ok(ResultSet::empty())
+    }
+
+    async fn commit(&self, session: SessionId) -> Result<ResultSet> {
+        let open = self.sessions.lock().remove(&session);
+        let txn = open.ok_or_else(|| QueryError::Invalid("no transaction is in progress".to_string()))?;
+        let txn = txn.lock().await;
+        if txn.failed {
+            self.transactions.abort(txn.id).await?;
+            return Err(TransactionError::Aborted.into());
+        }
+        if let Err(e) = self.commit_writes(&txn).await {
+            self.transactions.abort(txn.id).await?;
+            return Err(e);
+        }
+        self.transactions.commit(txn.id).await?;
+        Ok(ResultSet::empty())
+    }
+
+    async fn rollback(&self, session: SessionId) -> Result<ResultSet> {
+        let open = self.sessions.lock().remove(&session);
+        let txn = open.ok_or_else(|| QueryError::Invalid("no transaction is in progress".to_string()))?;
+        self.transactions.abort(txn.lock().await.id).await?;
+        Ok(ResultSet::empty())
+    }
+
+    fn fail(&self, txn: &mut OpenTransaction) {
+        if self.poison_on_error {
+            txn.failed = true;
+        }
+    }
+
+    /// Apply a transaction's buffered writes in one storage batch. Each
+    /// statement checked its rows against what the transaction saw; the
+    /// rows and unique values it claimed are checked again against what has
+    /// been committed since, and at REPEATABLE READ and above any row
+    /// another transaction has written since BEGIN is a conflict.
+    async fn commit_writes(&self, txn: &OpenTransaction) -> Result<()> {
+        let writes = self.transactions.write_set(txn.id)?;
+        if writes.is_empty() {
+            return Ok(());
+        }
+
+        let mut claimed: HashSet<Vec<u8>> = writes.keys().cloned().collect();
+        claimed.extend(txn.unique_prefixes.keys().cloned());
+        let _claimed = self.key_locks.lock(claimed).await;
+        self.transactions.check_write_conflicts(txn.id)?;
+
+        for (key, claim) in &txn.new_keys {
+            if matches!(writes.get(key), Some(Some(_))) && self.storage.get(key).await?.is_some() {
+                return Err(claim.violation());
+            }
+        }
+        for (prefix, claim) in &txn.unique_prefixes {
+            let end = encoding::prefix_end(prefix);
+            let ours = writes.range(prefix.clone()..end.clone()).collect::<Vec<_>>();
+            // The transaction may have given the value up again
+            if !ours.iter().any(|(_, value)| value.is_some()) {
+                continue;
+            }
+            let held = self.storage.scan(prefix, &end, ours.len() + 1).await?;
+            // Entries the transaction rewrites or deletes are its own rows'
+            if held.iter().any(|(key, _)| !writes.contains_key(key)) {
+                return Err(claim.violation());
+            }
+        }
+
+        let mut tables: Vec<(u64, i64)> = txn.table_writes.iter().map(|(&id, &delta)| (id, delta)).collect();
+        tables.sort_unstable();
+        let live: HashSet<u64> = self.catalog.tables().iter().map(|schema| schema.id).collect();
+        if tables.iter().any(|(id, _)| !live.contains(id)) {
+            return Err(QueryError::Invalid("a table written by the transaction was dropped".to_string()));
+        }
+
+        let mut ops: Vec<WriteOp> = writes.into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => WriteOp::Put { key, value },
+                None => WriteOp::Delete { key },
+            })
+            .collect();
+        let stats: Vec<_> = tables.iter().map(|(id, delta)| (self.catalog.stats(*id), *delta)).collect();
+        let mut counters = Vec::new();
+        let mut counts = Vec::new();
+        for (table, (stats, delta)) in tables.iter().zip(&stats) {
+            if *delta != 0 {
+                counters.push(stats.lock_counter().await);
+                let rows = stats.row_count().saturating_add_signed(*delta);
+                ops.push(WriteOp::Put { key: encoding::stats_key(table.0), value: stats.encode(rows) });
+                counts.push((stats, rows));
+            }
+        }
+        self.apply_writes(ops).await?;
+        for (stats, rows) in counts {
+            stats.set_row_count(rows);
+        }
+        for (stats, _) in &stats {
+            stats.record_write();
+        }
+        Ok(())
+    }
+
+    /// Execute an already parsed statement on its own, like `execute_sql`
+    pub async fn execute_statement(&self, statement: SqlStatement) -> Result<ResultSet> {
+        if let Some(replicator) = &self.replicator {
+            if is_schema_change(&statement) {
+                return replicator.replicate(ReplicatedWrite::Schema(statement)).await;
+            }
+            if statement.is_read_only() {
+                replicator.read_barrier().await?;
+            }
+        }
+        if let (Some(cache), SqlStatement::Select(select)) = (&self.cache, &statement) {
+            if cache::is_cacheable(select) {
+                let key = select.to_string();
+                return self.execute_cached(cache, key, statement).await;
+            }
+        }
+        let logged = self.logged(&statement);
+        let plan = QueryPlanner::plan(statement, &self.catalog)?;
+        if self.autocommits(&plan) {
+            return self.autocommit(plan, logged).await;
+        }
+        self.execute_plan(plan, None, logged).await
+    }
+    
+    /// Whether `plan`, run outside a transaction, runs in one of its own:
+    /// statements that write rows do, so their writes are checked and
+    /// applied together, and reads do at default isolation levels that take
+    /// a snapshot, so they see one point in time throughout
+    fn autocommits(&self, plan: &PhysicalPlan) -> bool {
+        match plan {
+            PhysicalPlan::Insert { .. } | PhysicalPlan::Update { .. } | PhysicalPlan::Delete { .. } => true,
+            PhysicalPlan::CreateTable { .. } | PhysicalPlan::DropTable { .. } | PhysicalPlan::CreateIndex { .. }
+                | PhysicalPlan::AlterTable { .. } | PhysicalPlan::Analyze { .. } => false,
+            _ => self.transactions.default_isolation_level().uses_snapshot(),
+        }
+    }
+    
+    /// Run `plan` in a transaction of its own at the default isolation
+    /// level, committed if it succeeds and rolled back if not
+    async fn autocommit(&self, plan: PhysicalPlan, statement: Option<SqlStatement>) -> Result<ResultSet> {
+        let id = self.transactions.begin(self.transactions.default_isolation_level()).await?;
+        let mut txn = OpenTransaction::new(id);
+        let result = match self.execute_plan(plan, Some(&mut txn), statement).await {
+            Ok(result) => self.commit_writes(&txn).await.map(|()| result),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(result) => {
+                self.transactions.commit(id).await?;
+                Ok(result)
+            }
+            Err(e) => {
+                self.transactions.abort(id).await?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn execute_cached(&self, cache: &QueryCache, key: String, statement: SqlStatement) -> Result<ResultSet> {
+        if let Some(result) = cache.get(&key, &self.catalog) {
+            return Ok(result);
+        }
+        let logged = self.logged(&statement);
+        let plan = QueryPlanner::plan(statement, &self.catalog)?;
+        let tables = cache::table_versions(&plan, &self.catalog);
+        let result = self.execute_plan(plan, None, logged).await?;
+        if let Some(tables) = tables {
+            cache.insert(key, result.clone(), tables);
+        }
+        Ok(result)
+    }
+
+    pub async fn execute(&self, plan: PhysicalPlan) -> Result<ResultSet> {
+        self.execute_plan(plan, None, None).await
+    }
+
+    /// A copy of `statement` for the slow query log to show, if there is one
+    fn logged(&self, statement: &SqlStatement) -> Option<SqlStatement> {
+        self.slow_log.as_ref().map(|_| statement.clone())
+    }
+
+    /// Execute `plan` by itself, or as part of `txn`, logging it with
+    /// `statement` if it is slow
+    async fn execute_plan(
+        &self,
+        plan: PhysicalPlan,
+        txn: Option<&mut OpenTransaction>,
+        statement: Option<SqlStatement>,
+    ) -> Result<ResultSet> {
+        let view = match &txn {
+            Some(txn) => ReadView::transaction(self.storage.clone(), self.transactions.clone(), txn),
+            None => ReadView::committed(self.storage.clone()),
+        };
+        let schema_change = matches!(
+            plan,
+            PhysicalPlan::CreateTable { .. } | PhysicalPlan::DropTable { .. }
+                | PhysicalPlan::CreateIndex { .. } | PhysicalPlan::AlterTable { .. }
+        );
+        if schema_change && txn.is_some() {
+            return Err(QueryError::Invalid("schema changes cannot run inside a transaction".to_string()));
+        }
+
+        let Some(log) = &self.slow_log else {
+            return self.run_plan(plan, &view, txn).await;
+        };
+        let explained = plan.explain();
+        let started = Instant::now();
+        let result = self.run_plan(plan, &view, txn).await;
+        let elapsed = started.elapsed();
+        log.observe(elapsed, || SlowQuery {
+            sql: statement.map(|statement| statement.to_string()),
+            duration: elapsed,
+            rows_examined: view.rows_examined(),
+            plan: explained,
+            succeeded: result.is_ok(),
+        });
+        result
+    }
+
+    async fn run_plan(&self, plan: PhysicalPlan, view: &ReadView, txn: Option<&mut OpenTransaction>) -> Result<ResultSet> {
+        match plan {
+            PhysicalPlan::Insert { table, rows } => self.insert(&table, &rows, view, txn).await,
+            PhysicalPlan::Update { table, assignments, filter } => {
+                self.update(&table, &assignments, filter, view, txn).await
+            }
+            PhysicalPlan::Delete { table, filter } => self.delete(&table, filter, view, txn).await,
+            PhysicalPlan::CreateTable { name, columns, primary_key, if_not_exists } => {
+                self.create_table(&name, &columns, &primary_key, if_not_exists).await
+            }
+            PhysicalPlan::DropTable { name, if_exists } => self.drop_table(&name, if_exists).await,
+            PhysicalPlan::CreateIndex { name, table, columns, unique } => {
+                self.create_index(&name, &table, &columns, unique).await
+            }
+            PhysicalPlan::AlterTable { table, operation } => self.alter_table(&table, operation).await,
+            PhysicalPlan::Analyze { table } => self.analyze(table.as_deref()).await,
+            query => {
+                let (columns, mut rows) = self.query_stream(query, view)?;
+                // Stop pulling rows as soon as the result is over the limits
+                let mut collected = Vec::new();
+                let mut bytes = 0;
+                while let Some(row) = rows.try_next().await? {
+                    bytes += row.iter().map(Value::size_hint).sum::<usize>();
+                    self.limits.check(collected.len() + 1, bytes)?;
+                    collected.push(row);
+                }
+                Ok(ResultSet::new(columns, collected))
+            }
+        }
+    }
+
+    /// The columns and row stream of a read plan
+    fn query_stream(&self, query: PhysicalPlan, view: &ReadView) -> Result<(Vec<ColumnMeta>, RowStream)> {
+        let types = query.output_columns(&self.catalog).into_iter().map(|(_, data_type)| data_type);
+        let (names, rows) = self.stream(query, Probe::query(self.query_memory_limit), view)?;
+        let columns = names.into_iter()
+            .zip(types.chain(std::iter::repeat(None)))
+            .map(|(name, data_type)| ColumnMeta::new(name, data_type))
+            .collect();
+        Ok((columns, rows))
+    }
+
+    /// Start a read query whose rows are then read a page at a time, from a
+    /// snapshot of its own. See `QueryCursor`.
+    pub async fn open_cursor(&self, statement: SqlStatement) -> Result<QueryCursor> {
+        let plan = QueryPlanner::plan(statement, &self.catalog)?;
+        if !plan.is_query() {
+            return Err(QueryError::Invalid("only queries returning rows can be read through a cursor".to_string()));
+        }
+        if let Some(replicator) = &self.replicator {
+            replicator.read_barrier().await?;
+        }
+        let id = self.transactions.begin(IsolationLevel::RepeatableRead).await?;
+        let view = ReadView::transaction(self.storage.clone(), self.transactions.clone(), &OpenTransaction::new(id));
+        match self.query_stream(plan, &view) {
+            Ok((columns, rows)) => Ok(QueryCursor::new(columns, rows, self.limits, self.transactions.clone(), id)),
+            Err(e) => {
+                self.transactions.abort(id).await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Build the row stream for a read plan, returning its output column names
+    fn stream(&self, plan: PhysicalPlan, probe: Probe, view: &ReadView) -> Result<(Vec<String>, RowStream)> {
+        let (columns, rows) = self.stream_node(plan, &probe, view)?;
+        match probe.stats() {
+            Some(stats) => {
+                let rows = rows.inspect_ok(move |_| {
+                    stats.rows.fetch_add(1, Ordering::Relaxed);
+                });
+                Ok((columns, rows.boxed()))
+            }
+            None => Ok((columns, rows)),
+        }
+    }
+
+    fn stream_node(&self, plan: PhysicalPlan, probe: &Probe, view: &ReadView) -> Result<(Vec<String>, RowStream)> {
+        match plan {
+            PhysicalPlan::TableScan { table, columns, filter, offset } => {
+                let schema = self.catalog.table(&table)?;
+                let positions = columns.iter()
+                    .map(|c| schema.column_position(c).ok_or_else(|| QueryError::ColumnNotFound(c.clone())))
+                    .collect::<Result<Vec<_>>>()?;
+                if self.projection_pushdown {
+                    let rows = self.matching_rows(schema, Some(positions), filter, offset, view)
+                        .map_ok(|(_, row)| row)
+                        .boxed();
+                    return Ok((columns, rows));
+                }
+                let rows = self.matching_rows(schema, None, filter, offset, view)
+                    .map_ok(move |(_, row)| positions.iter().map(|&i| row[i].clone()).collect())
+                    .boxed();
+                Ok((columns, rows))
+            }
+            PhysicalPlan::KeyLookup { table, columns, filter, .. } if !self.key_lookups => {
+                self.stream_node(PhysicalPlan::TableScan { table, columns, filter, offset: 0 }, probe, view)
+            }
+            PhysicalPlan::KeyLookup { table, columns, keys, filter } => {
+                let schema = self.catalog.table(&table)?;
+                let positions = columns.iter()
+                    .map(|c| schema.column_position(c).ok_or_else(|| QueryError::ColumnNotFound(c.clone())))
+                    .collect::<Result<Vec<_>>>()?;
+                let projection = self.projection_pushdown.then_some(positions.clone());
+                let rows = self.lookup_rows(schema, keys, projection, filter, view);
+                if self.projection_pushdown {
+                    return Ok((columns, rows));
+                }
+                Ok((columns, rows.map_ok(move |row| positions.iter().map(|&i| row[i].clone()).collect()).boxed()))
+            }
+            PhysicalPlan::IndexScan { .. } => {
+                Err(QueryError::Execution("index scans are not supported yet".to_string()))
+            }
+            PhysicalPlan::TableCount { table, name } => {
+                let schema = self.catalog.table(&table)?;
+                let count = self.catalog.stats(schema.id).row_count().saturating_add_signed(view.row_delta(schema.id));
+                Ok((vec![name], stream::iter([Ok(vec![Value::Integer(count as i64)])]).boxed()))
+            }
+            PhysicalPlan::Explain { plan, analyze: false } => {
+                let rows: Vec<Result<Row>> = plan.explain().into_iter().map(|line| Ok(vec![Value::Text(line)])).collect();
+                Ok((vec!["plan".to_string()], stream::iter(rows).boxed()))
+            }
+            PhysicalPlan::Explain { plan, analyze: true } => {
+                let analyzed = probe.analyze(&plan);
+                let stats = analyzed.stats.clone().unwrap_or_default();
+                let (_, mut rows) = self.stream((*plan).clone(), analyzed, view)?;
+                let lines = async move {
+                    let started = Instant::now();
+                    while rows.try_next().await?.is_some() {}
+                    let elapsed = started.elapsed();
+
+                    let mut lines = plan.explain_with(&|node| format!(" ({})", stats[node].describe()));
+                    lines.push(format!("Execution time: {:.3} ms", elapsed.as_secs_f64() * 1000.0));
+                    Ok::<_, QueryError>(lines)
+                };
+                let rows = stream::once(lines)
+                    .map_ok(|lines| stream::iter(lines.into_iter().map(|line| Ok(vec![Value::Text(line)]))))
+                    .try_flatten()
+                    .boxed();
+                Ok((vec!["plan".to_string()], rows))
+            }
+            PhysicalPlan::CatalogScan { view } => {
+                let rows = self.catalog_rows(&view)?;
+                Ok((view.columns(), stream::iter(rows.into_iter().map(Ok)).boxed()))
+            }
+            PhysicalPlan::Values { rows } => {
+                Ok((Vec::new(), stream::iter((0..rows).map(|_| Ok(Vec::new()))).boxed()))
+            }
+            PhysicalPlan::Filter { input, predicate } => {
+                let (columns, input) = self.stream(*input, probe.child(1), view)?;
+                let names = columns.clone();
+                let rows = input
+                    .try_filter_map(move |row| {
+                        future::ready(eval::is_true(&predicate, &names, &row).map(|keep| keep.then_some(row)))
+                    })
+                    .boxed();
+                Ok((columns, rows))
+            }
+            PhysicalPlan::SemiJoin { input, subqueries, predicate } => {
+                let mut offset = 1 + input.node_count();
+                let (columns, input) = self.stream(*input, probe.child(1), view)?;
+                let subqueries = subqueries.into_iter()
+                    .map(|plan| {
+                        let child = probe.child(offset);
+                        offset += plan.node_count();
+                        self.stream(plan, child, view).map(|(_, rows)| rows)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let names = columns.clone();
+                let memory = MemoryBudget::new(usize::MAX).within(probe.memory.clone());
+                let rows = async_stream::try_stream! {
+                    let mut sets = Vec::with_capacity(subqueries.len());
+                    for mut rows in subqueries {
+                        let mut set = ValueSet::default();
+                        while let Some(row) = rows.try_next().await? {
+                            let value = row.into_iter().next().unwrap_or(Value::Null);
+                            let size = value.size_hint();
+                            if set.insert(value) {
+                                memory.reserve(size);
+                                memory.check()?;
+                            }
+                        }
+                        sets.push(set);
+                    }
+
+                    for await row in input {
+                        let row = row?;
+                        if eval::is_true_with(&predicate, &names, &row, &sets)? {
+                            yield row;
+                        }
+                    }
+                };
+                Ok((columns, rows.boxed()))
+            }
+            PhysicalPlan::HashAggregate { input, group_by, aggregates } => {
+                let (input_columns, input) = self.stream(*input, probe.child(1), view)?;
+                let columns = group_by.iter().map(|(_, name)| name.clone())
+                    .chain(aggregates.iter().map(|a| a.name.clone()))
+                    .collect();
+                let stats = probe.stats().unwrap_or_default();
+                let rows = hash_aggregate::aggregate_stream(
+                    input, input_columns, group_by, aggregates, self.spill.clone(), probe.memory.clone(), stats,
+                );
+                Ok((columns, rows))
+            }
+            PhysicalPlan::Project { input, exprs } => {
+                let (input_columns, input) = self.stream(*input, probe.child(1), view)?;
+                let columns = exprs.iter().map(|(_, name)| name.clone()).collect();
+                let rows = input
+                    .and_then(move |row| {
+                        future::ready(
+                            exprs.iter()
+                                .map(|(expr, _)| eval::eval(expr, &input_columns, &row))
+                                .collect::<Result<Row>>(),
+                        )
+                    })
+                    .boxed();
+                Ok((columns, rows))
+            }
+            PhysicalPlan::Sort { input, order_by } => {
+                let (columns, input) = self.stream(*input, probe.child(1), view)?;
+                let stats = probe.stats().unwrap_or_default();
+                let rows = sort::sort_stream(input, order_by, columns.clone(), self.spill.clone(), probe.memory.clone(), stats);
+                Ok((columns, rows))
+            }
+            PhysicalPlan::Limit { input, limit } => {
+                let (columns, input) = self.stream(*input, probe.child(1), view)?;
+                Ok((columns, input.take(limit as usize).boxed()))
+            }
+            PhysicalPlan::Offset { input, offset } => {
+                let (columns, input) = self.stream(*input, probe.child(1), view)?;
+                Ok((columns, input.skip(offset as usize).boxed()))
+            }
+            other => Err(QueryError::Execution(format!("plan does not produce rows: {:?}", other))),
+        }
+    }
+
+    /// Rows of `schema` with their storage keys, in primary key order,
+    /// after the first `skip`, which are never decoded. With a projection
+    /// the rows hold just the columns at those positions, and the others are
+    /// never decoded either.
+    fn scan_table(
+        &self,
+        schema: Arc<TableSchema>,
+        projection: Option<Vec<usize>>,
+        skip: u64,
+        view: &ReadView,
+    ) -> KeyedRowStream {
+        let view = view.clone();
+        let decoded = self.columns_decoded.clone();
+        let rows = async_stream::try_stream! {
+            let projection = projection.map(|positions| {
+                let columns: Vec<Column> = positions.iter().map(|&i| schema.columns[i].clone()).collect();
+                let ids: Vec<u32> = columns.iter().map(|column| column.id).collect();
+                (columns, ids)
+            });
+            let prefix = encoding::table_prefix(schema.id);
+            let end = encoding::prefix_end(&prefix);
+            let mut cursor = prefix;
+            let mut skip = skip;
+            loop {
+                let page = view.scan(&cursor, &end, SCAN_BATCH_SIZE).await?;
+                view.record_examined(page.len());
+                let exhausted = page.len() < SCAN_BATCH_SIZE;
+                let skipped = page.len().min(usize::try_from(skip).unwrap_or(usize::MAX));
+                skip -= skipped as u64;
+                if let Some((key, _)) = page.get(skipped.wrapping_sub(1)) {
+                    cursor = key.clone();
+                    cursor.push(0);
+                }
+                for (key, value) in page.into_iter().skip(skipped) {
+                    let row = match &projection {
+                        Some((columns, ids)) => decode_stored_columns(columns, ids, &value)?,
+                        None => decode_stored_row(&schema, &value)?,
+                    };
+                    decoded.fetch_add(row.len() as u64, Ordering::Relaxed);
+                    cursor = key.clone();
+                    cursor.push(0);
+                    yield (key, row);
+                }
+                if exhausted {
+                    break;
+                }
+            }
+        };
+        rows.boxed()
+    }
+
+    /// Rows of `schema` with primary key `keys` that pass `filter`, in key
+    /// order, fetched from storage in one batch
+    fn lookup_rows(
+        &self,
+        schema: Arc<TableSchema>,
+        keys: Vec<Value>,
+        projection: Option<Vec<usize>>,
+        filter: Option<Expr>,
+        view: &ReadView,
+    ) -> RowStream {
+        let view = view.clone();
+        let decoded = self.columns_decoded.clone();
+        let rows = async_stream::try_stream! {
+            let (columns, projection) = match projection {
+                Some(positions) => {
+                    let columns: Vec<Column> = positions.iter().map(|&i| schema.columns[i].clone()).collect();
+                    let ids: Vec<u32> = columns.iter().map(|column| column.id).collect();
+                    (columns.iter().map(|column| column.name.clone()).collect(), Some((columns, ids)))
+                }
+                None => (schema.column_names(), None),
+            };
+            let mut keys: Vec<Vec<u8>> = keys.iter().map(|key| encoding::row_key(schema.id, std::slice::from_ref(key))).collect();
+            keys.sort();
+            keys.dedup();
+            let values = view.multi_get(&keys).await?;
+            view.record_examined(values.iter().flatten().count());
+            for value in values.into_iter().flatten() {
+                let row = match &projection {
+                    Some((columns, ids)) => decode_stored_columns(columns, ids, &value)?,
+                    None => decode_stored_row(&schema, &value)?,
+                };
+                decoded.fetch_add(row.len() as u64, Ordering::Relaxed);
+                if let Some(predicate) = &filter {
+                    if !eval::is_true(predicate, &columns, &row)? {
+                        continue;
+                    }
+                }
+                yield row;
+            }
+        };
+        rows.boxed()
+    }
+
+    /// Rows of `schema` that pass `filter`, which may only read the
+    /// projected columns, after the first `skip` of them
+    fn matching_rows(
+        &self,
+        schema: Arc<TableSchema>,
+        projection: Option<Vec<usize>>,
+        filter: Option<Expr>,
+        skip: u64,
+        view: &ReadView,
+    ) -> KeyedRowStream {
+        let columns = match &projection {
+            Some(positions) => positions.iter().map(|&i| schema.columns[i].name.clone()).collect(),
+            None => schema.column_names(),
+        };
+        match filter {
+            None => self.scan_table(schema, projection, skip, view),
+            Some(predicate) => self.scan_table(schema, projection, 0, view)
+                .try_filter_map(move |(key, row)| {
+                    future::ready(eval::is_true(&predicate, &columns, &row).map(|keep| keep.then_some((key, row))))
+                })
+                .skip(skip as usize)
+                .boxed(),
+        }
+    }
+
+    fn catalog_rows(&self, view: &CatalogView) -> Result<Vec<Row>> {
+        match view {
+            CatalogView::Tables => Ok(self.catalog.tables().iter()
+                .map(|schema| vec![
+                    Value::Text(schema.name.clone()),
+                    Value::Integer(self.catalog.stats(schema.id).row_count() as i64),
+                ])
+                .collect()),
+            CatalogView::Columns { table } => {
+                let schema = self.catalog.table(table)?;
+                Ok(schema.columns.iter()
+                    .map(|column| {
+                        let indexes: Vec<&str> = schema.indexes.iter()
+                            .filter(|index| index.columns.contains(&column.name))
+                            .map(|index| index.name.as_str())
+                            .collect();
+                        vec![
+                            Value::Text(column.name.clone()),
+                            Value::Text(column.data_type.to_string()),
+                            Value::Boolean(column.nullable),
+                            Value::Boolean(schema.primary_key.contains(&column.name)),
+                            if indexes.is_empty() { Value::Null } else { Value::Text(indexes.join(", ")) },
+                        ]
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    async fn insert(
+        &self,
+        table: &str,
+        rows: &[Vec<Expr>],
+        view: &ReadView,
+        txn: Option<&mut OpenTransaction>,
+    ) -> Result<ResultSet> {
+        let schema = self.catalog.table(table)?;
+        let stats = self.catalog.stats(schema.id);
+
+        let mut changes = Vec::with_capacity(rows.len());
+        for exprs in rows {
+            if exprs.len() != schema.columns.len() {
+                return Err(QueryError::Execution(format!("table {} changed while the INSERT was planned", table)));
+            }
+            let row = exprs.iter()
+                .zip(&schema.columns)
+                .map(|(expr, column)| match expr {
+                    Expr::Default => column.insert_default(),
+                    expr => eval::eval(expr, &[], &[]),
+                })
+                .collect::<Result<Row>>()?;
+            let row = coerce_row(&schema, row)?;
+
+            let key = if schema.primary_key.is_empty() {
+                encoding::row_key(schema.id, &[Value::Integer(stats.next_row_id())])
+            } else {
+                primary_row_key(&schema, &row)?
+            };
+            changes.push(RowChange { old: None, new: Some((key, row)) });
+        }
+
+        self.write_row_changes(&schema, &changes, view, txn).await?;
+        Ok(ResultSet::affected(changes.len() as u64))
+    }
+
+    async fn update(
+        &self,
+        table: &str,
+        assignments: &[(String, Expr)],
+        filter: Option<Expr>,
+        view: &ReadView,
+        txn: Option<&mut OpenTransaction>,
+    ) -> Result<ResultSet> {
+        let schema = self.catalog.table(table)?;
+        let columns = schema.column_names();
+        let targets = assignments.iter()
+            .map(|(name, expr)| {
+                schema.column_position(name)
+                    .map(|position| (position, expr))
+                    .ok_or_else(|| QueryError::ColumnNotFound(name.clone()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Collect matches before writing so updated rows are not seen again
+        let matches: Vec<(Vec<u8>, Row)> = self.matching_rows(schema.clone(), None, filter, 0, view).try_collect().await?;
+
+        let mut changes = Vec::with_capacity(matches.len());
+        for (key, old) in matches {
+            let mut new = old.clone();
+            for (position, expr) in &targets {
+                new[*position] = eval::eval(expr, &columns, &old)?;
+            }
+            let new = coerce_row(&schema, new)?;
+
+            let new_key = if schema.primary_key.is_empty() {
+                key.clone()
+            } else {
+                primary_row_key(&schema, &new)?
+            };
+            changes.push(RowChange { old: Some((key, old)), new: Some((new_key, new)) });
+        }
+
+        self.write_row_changes(&schema, &changes, view, txn).await?;
+        Ok(ResultSet::affected(changes.len() as u64))
+    }
+
+    async fn delete(
+        &self,
+        table: &str,
+        filter: Option<Expr>,
+        view: &ReadView,
+        txn: Option<&mut OpenTransaction>,
+    ) -> Result<ResultSet> {
+        let schema = self.catalog.table(table)?;
+        let changes: Vec<RowChange> = self.matching_rows(schema.clone(), None, filter, 0, view)
+            .map_ok(|row| RowChange { old: Some(row), new: None })
+            .try_collect()
+            .await?;
+
+        self.write_row_changes(&schema, &changes, view, txn).await?;
+        Ok(ResultSet::affected(changes.len() as u64))
+    }
+
+    async fn create_table(
+        &self,
+        name: &str,
+        columns: &[ColumnDef],
+        primary_key: &[String],
+        if_not_exists: bool,
+    ) -> Result<ResultSet> {
+        self.catalog.create_table(name, columns, primary_key, if_not_exists).await?;
+        Ok(ResultSet::empty())
+    }
+
+    async fn drop_table(&self, name: &str, if_exists: bool) -> Result<ResultSet> {
+        if if_exists && self.catalog.get_table(name).is_none() {
+            return Ok(ResultSet::empty());
+        }
+
+        let schema = self.catalog.drop_table(name).await?;
+        self.delete_prefix(&encoding::table_prefix(schema.id)).await?;
+        for index in &schema.indexes {
+            self.delete_prefix(&encoding::index_prefix(schema.id, index.id)).await?;
+        }
+
+        Ok(ResultSet::empty())
+    }
+
+    async fn create_index(&self, name: &str, table: &str, columns: &[String], unique: bool) -> Result<ResultSet> {
+        // Publish the index first so concurrent writers maintain it while we backfill
+        let schema = self.catalog.alter_table(table, |schema| {
+            if schema.index(name).is_some() {
+                return Err(QueryError::Invalid(format!("index {} already exists on {}", name, schema.name)));
+            }
+            for column in columns {
+                if schema.column(column).is_none() {
+                    return Err(QueryError::ColumnNotFound(column.clone()));
+                }
+            }
+            schema.indexes.push(IndexDef {
+                id: schema.next_index_id,
+                name: name.to_string(),
+                columns: columns.to_vec(),
+                unique,
+            });
+            schema.next_index_id += 1;
+            Ok(())
+        }).await?;
+        let index = schema.index(name).expect("index was just added").clone();
+
+        if let Err(e) = self.backfill_index(&schema, &index).await {
+            self.catalog.alter_table(table, |schema| {
+                schema.indexes.retain(|i| i.id != index.id);
+                Ok(())
+            }).await?;
+            self.delete_prefix(&encoding::index_prefix(schema.id, index.id)).await?;
+            return Err(e);
+        }
+
+        Ok(ResultSet::empty())
+    }
+
+    async fn alter_table(&self, table: &str, operation: AlterTableOperation) -> Result<ResultSet> {
+        self.catalog.alter_table(table, |schema| {
+            match &operation {
+                AlterTableOperation::AddColumn(def) => {
+                    if schema.column(&def.name).is_some() {
+                        return Err(QueryError::Invalid(format!(
+                            "column {} already exists in {}", def.name, schema.name
+                        )));
+                    }
+                    if def.primary_key {
+                        return Err(QueryError::Invalid("cannot add a primary key column".to_string()));
+                    }
+                    if def.unique {
+                        return Err(QueryError::Invalid(format!(
+                            "cannot add UNIQUE column {}; create a unique index on it instead", def.name
+                        )));
+                    }
+                    let column = Column::from_def(schema.next_column_id, def)?;
+                    // Existing rows read the default, so NOT NULL needs one
+                    if !column.nullable && column.default_value().is_null() {
+                        return Err(QueryError::Invalid(format!(
+                            "column {} is NOT NULL and needs a non-NULL DEFAULT", def.name
+                        )));
+                    }
+                    schema.columns.push(column);
+                    schema.next_column_id += 1;
+                }
+                AlterTableOperation::DropColumn { name } => {
+                    let column = schema.column(name)
+                        .ok_or_else(|| QueryError::ColumnNotFound(name.clone()))?;
+                    if schema.primary_key.contains(name) {
+                        return Err(QueryError::Invalid(format!("cannot drop primary key column {}", name)));
+                    }
+                    if let Some(index) = schema.indexes.iter().find(|i| i.columns.contains(name)) {
+                        return Err(QueryError::Invalid(format!(
+                            "cannot drop column {} used by index {}", name, index.name
+                        )));
+                    }
+                    if schema.columns.len() == 1 {
+                        return Err(QueryError::Invalid(format!("cannot drop the only column of {}", schema.name)));
+                    }
+                    // Logical drop: stored values are ignored until rows are rewritten
+                    schema.dropped_columns.push(column.id);
+                    schema.columns.retain(|c| c.name != *name);
+                }
+            }
+            schema.version += 1;
+            Ok(())
+        }).await?;
+
+        Ok(ResultSet::empty())
+    }
+
+    /// Gather column statistics for `table`, one row per column
+    async fn analyze(&self, table: Option<&str>) -> Result<ResultSet> {
+        let schemas = match table {
+            Some(table) => vec![self.catalog.table(table)?],
+            None => self.catalog.tables(),
+        };
+        let columns = vec![
+            ColumnMeta::new("table_name", Some(DataType::Text)),
+            ColumnMeta::new("column_name", Some(DataType::Text)),
+            ColumnMeta::new("null_count", Some(DataType::Integer)),
+            ColumnMeta::new("distinct_estimate", Some(DataType::Integer)),
+            ColumnMeta::new("min_value", None),
+            ColumnMeta::new("max_value", None),
+        ];
+        let mut rows = Vec::new();
+        for schema in schemas {
+            let analysis = self.catalog.analyze(&schema.name).await?;
+            for column in &schema.columns {
+                let Some(stats) = analysis.columns.get(&column.id) else {
+                    continue;
+                };
+                rows.push(vec![
+                    Value::Text(schema.name.clone()),
+                    Value::Text(column.name.clone()),
+                    Value::Integer(stats.nulls as i64),
+                    Value::Integer(stats.distinct as i64),
+                    stats.min.clone().unwrap_or(Value::Null),
+                    stats.max.clone().unwrap_or(Value::Null),
+                ]);
+            }
+        }
+        Ok(ResultSet::new(columns, rows))
+    }
+
+    /// Compact storage, then analyze again every table that has column
+    /// statistics, so periodic maintenance keeps them fresh
+    pub async fn compact(&self) -> Result<CompactionSummary> {
+        let summary = self.storage.compact().await?;
+        for schema in self.catalog.tables() {
+            if self.catalog.stats(schema.id).analysis().is_some() {
+                self.catalog.analyze(&schema.name).await?;
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Rewrite rows that still carry data for dropped columns, then forget
+    /// those columns. Returns the number of rows rewritten.
+    pub async fn reclaim_dropped_columns(&self, table: &str) -> Result<u64> {
+        let schema = self.catalog.table(table)?;
+        if schema.dropped_columns.is_empty() {
+            return Ok(0);
+        }
+
+        let mut rewritten = 0;
+        let mut rows = self.scan_table(schema.clone(), None, 0, &ReadView::committed(self.storage.clone()));
+        while let Some((key, row)) = rows.try_next().await? {
+            let stored = self.storage.get(&key).await?;
+            let has_dropped = match &stored {
+                Some(bytes) => encoding::decode_row(bytes)?
+                    .iter()
+                    .any(|(id, _)| schema.dropped_columns.contains(id)),
+                None => false,
+            };
+            if has_dropped {
+                self.storage.put(key, encode_stored_row(&schema, &row)).await?;
+                rewritten += 1;
+            }
+        }
+
+        let reclaimed = schema.dropped_columns.clone();
+        self.catalog.alter_table(table, |schema| {
+            schema.dropped_columns.retain(|id| !reclaimed.contains(id));
+            Ok(())
+        }).await?;
+
+        Ok(rewritten)
+    }
+
+    async fn backfill_index(&self, schema: &Arc<TableSchema>, index: &IndexDef) -> Result<()> {
+        let mut rows = self.scan_table(schema.clone(), None, 0, &ReadView::committed(self.storage.clone()));
+        while let Some((key, row)) = rows.try_next().await? {
+            if index.unique {
+                self.check_unique_index(schema, index, &row, Some(&key)).await?;
+            }
+            let values = index_values(schema, index, &row);
+            self.storage.put(encoding::index_key(schema.id, index.id, &values, &key), key).await?;
+        }
+        Ok(())
+    }
+
+    /// Apply a statement's row changes in one atomic storage batch, along
+    /// with their index entries and, when the number of rows changes, the
+    /// table's persisted row count. Inside a transaction the writes are
+    /// buffered instead, and the row count changes when it commits. Nothing
+    /// is written if the new rows would violate the primary key or a unique
+    /// index, whether by clashing with stored rows or with each other.
+    async fn write_row_changes(
+        &self,
+        schema: &TableSchema,
+        changes: &[RowChange],
+        view: &ReadView,
+        txn: Option<&mut OpenTransaction>,
+    ) -> Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let mut puts = Vec::new();
+        for (key, row) in changes.iter().filter_map(|change| change.new.as_ref()) {
+            puts.push(WriteOp::Put { key: key.clone(), value: encode_stored_row(schema, row) });
+            for index in &schema.indexes {
+                let values = index_values(schema, index, row);
+                puts.push(WriteOp::Put {
+                    key: encoding::index_key(schema.id, index.id, &values, key),
+                    value: key.clone(),
+                });
+            }
+        }
+        // Every old entry goes before any new one, so rows that swap keys or
+        // unique values do not delete each other's new entries. Entries
+        // written again are just overwritten, so the changefeed sees an
+        // updated row as one put.
+        let written: HashSet<&[u8]> = puts.iter()
+            .map(|op| match op {
+                WriteOp::Put { key, .. } | WriteOp::Delete { key } => key.as_slice(),
+            })
+            .collect();
+        let mut ops = Vec::new();
+        for (key, row) in changes.iter().filter_map(|change| change.old.as_ref()) {
+            for index in &schema.indexes {
+                let values = index_values(schema, index, row);
+                ops.push(WriteOp::Delete { key: encoding::index_key(schema.id, index.id, &values, key) });
+            }
+            ops.push(WriteOp::Delete { key: key.clone() });
+        }
+        ops.retain(|op| !matches!(op, WriteOp::Delete { key } if written.contains(key.as_slice())));
+        ops.extend(puts);
+        let delta = changes.iter()
+            .map(|change| change.new.is_some() as i64 - change.old.is_some() as i64)
+            .sum::<i64>();
+
+        if let Some(txn) = txn {
+            self.check_constraints(view, schema, changes).await?;
+            self.claim_for_commit(txn, schema, changes);
+            for op in ops {
+                let (key, value) = match op {
+                    WriteOp::Put { key, value } => (key, Some(value)),
+                    WriteOp::Delete { key } => (key, None),
+                };
+                self.transactions.buffer_write(txn.id, key, value)?;
+            }
+            *txn.table_writes.entry(schema.id).or_default() += delta;
+            // Widening early is harmless if the transaction rolls back
+            let stats = self.catalog.stats(schema.id);
+            for (_, row) in changes.iter().filter_map(|change| change.new.as_ref()) {
+                stats.observe_row(schema, row);
+            }
+            return Ok(());
+        }
+
+        // Hold the rows and values being checked until they are written, so
+        // a concurrent statement or commit cannot claim them in between
+        let _claimed = self.key_locks.lock(claimed_keys(schema, changes)).await;
+        self.check_constraints(view, schema, changes).await?;
+
+        let stats = self.catalog.stats(schema.id);
+        for (_, row) in changes.iter().filter_map(|change| change.new.as_ref()) {
+            stats.observe_row(schema, row);
+        }
+        if delta == 0 {
+            self.apply_writes(ops).await?;
+        } else {
+            let _counter = stats.lock_counter().await;
+            let rows = stats.row_count().saturating_add_signed(delta);
+            ops.push(WriteOp::Put { key: encoding::stats_key(schema.id), value: stats.encode(rows) });
+            self.apply_writes(ops).await?;
+            stats.set_row_count(rows);
+        }
+        stats.record_write();
+        Ok(())
+    }
+
+    /// Wait until reads see every write committed before the call, for a
+    /// read that asks to be linearizable. Without a replicator they do.
+    pub async fn confirm_reads(&self) -> Result<()> {
+        match &self.replicator {
+            Some(replicator) => replicator.confirm_reads().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Write raw key-value pairs in one atomic batch, through the
+    /// replicator if there is one, like everything else the executor writes
+    pub async fn write(&self, ops: Vec<WriteOp>) -> Result<()> {
+        self.apply_writes(ops).await
+    }
+
+    /// Apply a write committed at `index` of the replicated log, as every
+    /// replica does in log order, recording the index along with it
+    pub async fn apply_replicated(&self, write: ReplicatedWrite, index: u64) -> Result<ResultSet> {
+        let applied = WriteOp::Put {
+            key: encoding::meta_key(replication::APPLIED_INDEX),
+            value: index.to_be_bytes().to_vec(),
+        };
+        match write {
+            ReplicatedWrite::Batch(writes) => {
+                let mut ops = ReplicatedWrite::into_ops(writes);
+                let mut tables = HashSet::new();
+                let mut stats = Vec::new();
+                for op in &ops {
+                    let (WriteOp::Put { key, .. } | WriteOp::Delete { key }) = op;
+                    tables.extend(encoding::row_key_table(key));
+                    if let WriteOp::Put { key, value } = op {
+                        stats.extend(encoding::stats_key_table(key).map(|table| (table, value.clone())));
+                    }
+                }
+                ops.push(applied);
+                self.write_local(ops).await?;
+                // The replica that wrote the batch counted its rows already
+                for (table, value) in stats {
+                    self.catalog.load_replicated_stats(table, &value)?;
+                }
+                for table in tables {
+                    self.catalog.stats(table).record_write();
+                }
+                Ok(ResultSet::empty())
+            }
+            ReplicatedWrite::Schema(statement) => {
+                // A statement that fails, fails on every replica, and is
+                // applied all the same
+                let logged = self.logged(&statement);
+                let result = match QueryPlanner::plan(statement, &self.catalog) {
+                    Ok(plan) => self.execute_plan(plan, None, logged).await,
+                    Err(e) => Err(e),
+                };
+                self.write_local(vec![applied]).await?;
+                result
+            }
+        }
+    }
+
+    /// Index of the last replicated write applied, or 0 if there is none
+    pub async fn applied_index(&self) -> Result<u64> {
+        let applied = self.storage.get(&encoding::meta_key(replication::APPLIED_INDEX)).await?;
+        Ok(applied.and_then(|bytes| bytes.try_into().ok()).map_or(0, u64::from_be_bytes))
+    }
+
+    /// Write `ops` through the replicator, or straight to storage without one
+    async fn apply_writes(&self, ops: Vec<WriteOp>) -> Result<()> {
+        match &self.replicator {
+            Some(replicator) => replicator.replicate(ReplicatedWrite::batch(ops)).await.map(drop),
+            None => self.write_local(ops).await,
+        }
+    }
+
+    /// Write `ops` in one atomic batch, first saving the values they
+    /// replace for transactions reading from a snapshot
+    async fn write_local(&self, ops: Vec<WriteOp>) -> Result<()> {
+        let _gate = self.transactions.write_gate().await;
+        if self.transactions.has_snapshots() {
+            for op in &ops {
+                let (WriteOp::Put { key, .. } | WriteOp::Delete { key }) = op;
+                let old = self.storage.get(key).await?;
+                self.transactions.preserve(key, old);
+            }
+        }
+        self.storage.write_batch(ops).await?;
+        Ok(())
+    }
+
+    /// Record the primary keys and unique values a transaction's new rows
+    /// take, to check again when it commits
+    fn claim_for_commit(&self, txn: &mut OpenTransaction, schema: &TableSchema, changes: &[RowChange]) {
+        let replaced: HashSet<&[u8]> = changes.iter()
+            .filter_map(|change| change.old.as_ref())
+            .map(|(key, _)| key.as_slice())
+            .collect();
+        for (key, row) in changes.iter().filter_map(|change| change.new.as_ref()) {
+            // A key the transaction has written before was already claimed,
+            // or deleted from a row it saw
+            if !schema.primary_key.is_empty()
+                && !replaced.contains(key.as_slice())
+                && self.transactions.buffered(txn.id, key).is_none()
+            {
+                txn.new_keys.insert(key.clone(), key_claim(schema, row));
+            }
+            for index in schema.indexes.iter().filter(|index| index.unique) {
+                let values = index_values(schema, index, row);
+                if !values.iter().any(Value::is_null) {
+                    txn.unique_prefixes.insert(unique_prefix(schema, index, &values), value_claim(index, &values));
+                }
+            }
+        }
+    }
+
+    /// Fail if the new rows of `changes` would share a primary key or unique
+    /// index values with each other or with a row in `view` the statement
+    /// does not replace
+    async fn check_constraints(&self, view: &ReadView, schema: &TableSchema, changes: &[RowChange]) -> Result<()> {
+        let replaced: HashSet<&[u8]> = changes.iter()
+            .filter_map(|change| change.old.as_ref())
+            .map(|(key, _)| key.as_slice())
+            .collect();
+        let new_rows = || changes.iter().filter_map(|change| change.new.as_ref());
+
+        if !schema.primary_key.is_empty() {
+            let mut seen = HashSet::new();
+            for (key, row) in new_rows() {
+                if !seen.insert(key.as_slice())
+                    || (!replaced.contains(key.as_slice()) && view.get(key).await?.is_some())
+                {
+                    return Err(key_claim(schema, row).violation());
+                }
+            }
+        }
+
+        for index in schema.indexes.iter().filter(|index| index.unique) {
+            let mut seen = HashSet::new();
+            for (_, row) in new_rows() {
+                let values = index_values(schema, index, row);
+                // NULLs never conflict
+                if values.iter().any(Value::is_null) {
+                    continue;
+                }
+                let prefix = unique_prefix(schema, index, &values);
+                let existing = view.scan(&prefix, &encoding::prefix_end(&prefix), 2).await?;
+                if existing.iter().any(|(_, row_key)| !replaced.contains(row_key.as_slice())) || !seen.insert(prefix) {
+                    return Err(value_claim(index, &values).violation());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_unique_index(
+        &self,
+        schema: &TableSchema,
+        index: &IndexDef,
+        row: &[Value],
+        own_key: Option<&[u8]>,
+    ) -> Result<()> {
+        let values = index_values(schema, index, row);
+        // NULLs never conflict
+        if values.iter().any(Value::is_null) {
+            return Ok(());
+        }
+
+        let prefix = unique_prefix(schema, index, &values);
+        let existing = self.storage.scan(&prefix, &encoding::prefix_end(&prefix), 2).await?;
+        if existing.iter().any(|(_, row_key)| Some(row_key.as_slice()) != own_key) {
+            return Err(value_claim(index, &values).violation());
+        }
+        Ok(())
+    }
+
+    async fn delete_prefix(&self, prefix: &[u8]) -> Result<()> {
+        let end = encoding::prefix_end(prefix);
+        loop {
+            let page = self.storage.scan(prefix, &end, SCAN_BATCH_SIZE).await?;
+            if page.is_empty() {
+                return Ok(());
+            }
+            for (key, _) in page {
+                self.storage.delete(&key).await?;
+            }
+        }
+    }
+}
+
+/// Whether `statement` changes the schema or statistics, which replicas
+/// apply by running it again
+fn is_schema_change(statement: &SqlStatement) -> bool {
+    matches!(
+        statement,
+        SqlStatement::CreateTable { .. } | SqlStatement::DropTable { .. } | SqlStatement::CreateIndex { .. }
+            | SqlStatement::AlterTable { .. } | SqlStatement::Analyze { .. }
+    )
+}
+
+/// A row a statement writes: the stored row it replaces or deletes, the row
+/// it becomes, or both, each with its storage key
+struct RowChange {
+    old: Option<(Vec<u8>, Row)>,
+    new: Option<(Vec<u8>, Row)>,
+}
+
+/// Cast each value to its column's type, rejecting NULL in NOT NULL columns
+fn coerce_row(schema: &TableSchema, row: Row) -> Result<Row> {
+    row.into_iter()
+        .zip(&schema.columns)
+        .map(|(value, column)| {
+            let value = value.cast_to(column.data_type).map_err(|e| match e {
+                QueryError::Execution(msg) => QueryError::Execution(format!("column {}: {}", column.name, msg)),
+                other => other,
+            })?;
+            if value.is_null() && !column.nullable {
+                return Err(QueryError::ConstraintViolation {
+                    constraint: format!("NOT NULL on {}.{}", schema.name, column.name),
+                    value: value.to_string(),
+                });
+            }
+            Ok(value)
         })
+        .collect()
+}
+
+fn primary_row_key(schema: &TableSchema, row: &[Value]) -> Result<Vec<u8>> {
+    let mut values = Vec::with_capacity(schema.primary_key.len());
+    for (position, name) in schema.primary_key_positions().into_iter().zip(&schema.primary_key) {
+        if row[position].is_null() {
+            return Err(QueryError::Execution(format!("NULL value in primary key column {}", name)));
+        }
+        values.push(row[position].clone());
+    }
+    Ok(encoding::row_key(schema.id, &values))
+}
+
+fn key_claim(schema: &TableSchema, row: &[Value]) -> Claim {
+    let values: Vec<Value> = schema.primary_key_positions().iter().map(|&i| row[i].clone()).collect();
+    Claim {
+        constraint: format!("PRIMARY KEY of {}", schema.name),
+        value: render_tuple(&values),
+    }
+}
+
+fn value_claim(index: &IndexDef, values: &[Value]) -> Claim {
+    Claim {
+        constraint: format!("UNIQUE index {}", index.name),
+        value: render_tuple(values),
+    }
+}
+
+fn render_tuple(values: &[Value]) -> String {
+    let rendered: Vec<String> = values.iter().map(Value::to_string).collect();
+    format!("({})", rendered.join(", "))
+}
+
+/// Start of the entries of a unique index holding `values`
+fn unique_prefix(schema: &TableSchema, index: &IndexDef, values: &[Value]) -> Vec<u8> {
+    let mut prefix = encoding::index_prefix(schema.id, index.id);
+    encoding::encode_key(values, &mut prefix);
+    prefix
+}
+
+/// Keys a statement claims before checking and writing `changes`: the row
+/// keys it replaces or writes and the non-NULL unique index values of every
+/// new row
+fn claimed_keys(schema: &TableSchema, changes: &[RowChange]) -> HashSet<Vec<u8>> {
+    let mut keys = HashSet::new();
+    for (key, _) in changes.iter().flat_map(|change| change.old.iter().chain(&change.new)) {
+        keys.insert(key.clone());
+    }
+    for (_, row) in changes.iter().filter_map(|change| change.new.as_ref()) {
+        for index in schema.indexes.iter().filter(|index| index.unique) {
+            let values = index_values(schema, index, row);
+            if !values.iter().any(Value::is_null) {
+                keys.insert(unique_prefix(schema, index, &values));
+            }
+        }
     }
+    keys
 }
 
+fn index_values(schema: &TableSchema, index: &IndexDef, row: &[Value]) -> Vec<Value> {
+    index.columns.iter()
+        .map(|name| row[schema.column_position(name).expect("indexed column exists")].clone())
+        .collect()
+}
+
+fn encode_stored_row(schema: &TableSchema, row: &[Value]) -> Vec<u8> {
+    let columns: Vec<(u32, &Value)> = schema.columns.iter().map(|c| c.id).zip(row).collect();
+    encoding::encode_row(&columns)
+}
+
+/// Decode a stored row into current schema column order. Columns added after
+/// the row was written read as their default; dropped columns are skipped.
+pub(crate) fn decode_stored_row(schema: &TableSchema, bytes: &[u8]) -> Result<Row> {
+    let stored = encoding::decode_row(bytes)?;
+    Ok(schema.columns.iter()
+        .map(|column| {
+            stored.iter()
+                .find(|(id, _)| *id == column.id)
+                .map(|(_, value)| value.clone())
+                .unwrap_or_else(|| column.default_value())
+        })
+        .collect())
+}
+
+/// Decode just `columns` of a stored row, whose ids are `ids`, in that order.
+/// Missing columns read as their default like in `decode_stored_row`.
+fn decode_stored_columns(columns: &[Column], ids: &[u32], bytes: &[u8]) -> Result<Row> {
+    let stored = encoding::decode_row_columns(bytes, ids)?;
+    Ok(stored.into_iter().zip(columns)
+        .map(|(value, column)| value.unwrap_or_else(|| column.default_value()))
+        .collect())
+}
+
+/// Group `input` by the `group_by` expressions and fold each group through
+/// the aggregates. Groups are emitted in order of first appearance.
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+    use crate::ast::DataType;
+    use nextdb_storage::{Durability, StorageConfig};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    async fn executor(temp_dir: &TempDir) -> QueryExecutor {
+        let config = StorageConfig {
+            data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+            wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+            durability: Durability::NoSync,
+            ..Default::default()
+        };
+        let storage = Arc::new(LSMTree::open(config).await.unwrap());
+        QueryExecutor::open(storage).await.unwrap()
+    }
+
+    async fn cached_executor(temp_dir: &TempDir, config: QueryCacheConfig) -> QueryExecutor {
+        executor(temp_dir).await.with_result_cache(config)
+    }
+
+    async fn rows(executor: &QueryExecutor, sql: &str) -> Vec<Vec<String>> {
+        executor.execute_sql(sql).await.unwrap_or_else(|e| panic!("{}: {}", sql, e)).text_rows()
+    }
+
+    #[tokio::test]
+    async fn test_insert_select_update_delete() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        db.execute_sql("CREATE TABLE users (id INT PRIMARY KEY, name TEXT, age INT)").await.unwrap();
+        let result = db.execute_sql(
+            "INSERT INTO users VALUES (2, 'Bob', 40), (1, 'Alice', 30), (3, 'Carol', NULL)"
+        ).await.unwrap();
+        assert_eq!(result.rows_affected, Some(3));
+
+        let result = db.execute_sql("SELECT * FROM users").await.unwrap();
+        assert_eq!(result.column_names(), vec!["id", "name", "age"]);
+        assert_eq!(result.text_rows(), vec![
+            vec!["1", "Alice", "30"],
+            vec!["2", "Bob", "40"],
+            vec!["3", "Carol", "NULL"],
+        ]);
+
+        assert_eq!(
+            rows(&db, "SELECT name, age + 1 AS next FROM users WHERE age >= 30 ORDER BY next DESC LIMIT 1").await,
+            vec![vec!["Bob", "41"]]
+        );
+
+        db.execute_sql("UPDATE users SET age = age + 1 WHERE name = 'Alice'").await.unwrap();
+        db.execute_sql("UPDATE users SET id = 10 WHERE id = 3").await.unwrap();
+        assert_eq!(
+            rows(&db, "SELECT id, age FROM users").await,
+            vec![vec!["1", "31"], vec!["2", "40"], vec!["10", "NULL"]]
+        );
+
+        let result = db.execute_sql("DELETE FROM users WHERE age IS NULL OR age > 35").await.unwrap();
+        assert_eq!(result.rows_affected, Some(2));
+        assert_eq!(rows(&db, "SELECT id FROM users").await, vec![vec!["1"]]);
+    }
+
+    #[tokio::test]
+    async fn test_constraint_errors() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        db.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, email TEXT)").await.unwrap();
+        db.execute_sql("CREATE UNIQUE INDEX t_email ON t (email)").await.unwrap();
+        db.execute_sql("INSERT INTO t VALUES (1, 'a@x'), (2, NULL), (3, NULL)").await.unwrap();
+
+        for sql in [
+            "INSERT INTO t VALUES (1, 'b@x')",
+            "INSERT INTO t VALUES (4, 'a@x')",
+            "INSERT INTO t VALUES (NULL, 'c@x')",
+            "INSERT INTO t VALUES ('four', 'c@x')",
+            "UPDATE t SET email = 'a@x' WHERE id = 2",
+            "CREATE TABLE t (id INT)",
+        ] {
+            assert!(db.execute_sql(sql).await.is_err(), "{} should fail", sql);
+        }
+
+        // Updating a row in place does not conflict with itself
+        db.execute_sql("UPDATE t SET email = 'a@x' WHERE id = 1").await.unwrap();
+        assert_eq!(
+            rows(&db, "SELECT * FROM t").await,
+            vec![vec!["1", "a@x"], vec!["2", "NULL"], vec!["3", "NULL"]]
+        );
+    }
+
+    fn violated(result: Result<ResultSet>) -> (String, String) {
+        match result {
+            Err(QueryError::ConstraintViolation { constraint, value }) => (constraint, value),
+            other => panic!("expected a constraint violation, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unique_and_not_null_columns() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+        db.execute_sql("CREATE TABLE users (id INT PRIMARY KEY, email TEXT UNIQUE, name TEXT NOT NULL)").await.unwrap();
+        assert_eq!(rows(&db, "SHOW COLUMNS FROM users WHERE column_name = 'email'").await, vec![
+            vec!["email", "TEXT", "true", "false", "users_email_key"],
+        ]);
+
+        // A duplicate within one statement fails it without writing any row
+        let (constraint, value) = violated(
+            db.execute_sql("INSERT INTO users VALUES (1, 'a@x', 'Ann'), (2, 'b@x', 'Ben'), (3, 'a@x', 'Cat')").await
+        );
+        assert_eq!((constraint.as_str(), value.as_str()), ("UNIQUE index users_email_key", "(a@x)"));
+        let (constraint, _) = violated(db.execute_sql("INSERT INTO users VALUES (1, 'a@x', 'Ann'), (1, 'b@x', 'Ben')").await);
+        assert_eq!(constraint, "PRIMARY KEY of users");
+        assert!(rows(&db, "SELECT * FROM users").await.is_empty());
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM users").await, vec![vec!["0"]]);
+
+        // Any number of rows may have a NULL in a UNIQUE column
+        db.execute_sql("INSERT INTO users VALUES (1, 'a@x', 'Ann'), (2, NULL, 'Ben'), (3, NULL, 'Cat')").await.unwrap();
+        db.execute_sql("INSERT INTO users VALUES (4, NULL, 'Dan')").await.unwrap();
+        assert_eq!(violated(db.execute_sql("UPDATE users SET email = 'z@x' WHERE email IS NULL").await).1, "(z@x)");
+        assert_eq!(violated(db.execute_sql("UPDATE users SET email = 'a@x' WHERE id = 4").await).1, "(a@x)");
+
+        // NOT NULL names the column, whether the NULL is explicit or omitted
+        for sql in [
+            "INSERT INTO users VALUES (5, 'e@x', NULL)",
+            "INSERT INTO users (id, email) VALUES (5, 'e@x')",
+            "UPDATE users SET name = NULL WHERE id = 1",
+        ] {
+            let (constraint, value) = violated(db.execute_sql(sql).await);
+            assert_eq!((constraint.as_str(), value.as_str()), ("NOT NULL on users.name", "NULL"), "{}", sql);
+        }
+
+        // Only the final state of the statement counts: keys and values may
+        // move onto ones that other rows of the same statement give up
+        db.execute_sql("UPDATE users SET id = id + 1").await.unwrap();
+        db.execute_sql("UPDATE users SET email = 'b@x' WHERE id = 3").await.unwrap();
+        db.execute_sql("UPDATE users SET id = 5 - id WHERE id = 2 OR id = 3").await.unwrap();
+        assert_eq!(rows(&db, "SELECT * FROM users").await, vec![
+            vec!["2", "b@x", "Ben"],
+            vec!["3", "a@x", "Ann"],
+            vec!["4", "NULL", "Cat"],
+            vec!["5", "NULL", "Dan"],
+        ]);
+        assert_eq!(rows(&db, "SELECT id FROM users WHERE email = 'a@x'").await, vec![vec!["3"]]);
+
+        assert!(db.execute_sql("ALTER TABLE users ADD COLUMN handle TEXT UNIQUE").await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_unique_inserts() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = Arc::new(executor(&temp_dir).await);
+        db.execute_sql("CREATE TABLE accounts (id INT PRIMARY KEY, handle TEXT UNIQUE)").await.unwrap();
+
+        // Each round, several writers race to take the same handle
+        for round in 0..20 {
+            let writers: Vec<_> = (0..6).map(|w| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    db.execute_sql(&format!("INSERT INTO accounts VALUES ({}, 'h{}')", round * 10 + w, round)).await
+                })
+            }).collect();
+
+            let mut inserted = 0;
+            for writer in writers {
+                match writer.await.unwrap() {
+                    Ok(_) => inserted += 1,
+                    Err(e) => assert!(matches!(e, QueryError::ConstraintViolation { .. }), "{}", e),
+                }
+            }
+            assert_eq!(inserted, 1, "round {}", round);
+        }
+
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM accounts").await, vec![vec!["20"]]);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM accounts WHERE handle = 'h7'").await, vec![vec!["1"]]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_statements_commit_all_or_nothing() {
+        let temp_dir = TempDir::new().unwrap();
+        let transactions = TransactionManager::new().with_default_isolation_level(IsolationLevel::RepeatableRead);
+        let db = Arc::new(executor(&temp_dir).await.with_transaction_manager(Arc::new(transactions)));
+        db.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, writer INT)").await.unwrap();
+
+        // Each round, the middle of three writers overlaps the other two by
+        // half its rows, and a reader counts while they run
+        for round in 0..10 {
+            let reader = {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    let mut counts = Vec::new();
+                    for _ in 0..5 {
+                        counts.push(rows(&db, "SELECT COUNT(*) FROM t").await[0][0].parse::<u64>().unwrap());
+                    }
+                    counts
+                })
+            };
+            let writers: Vec<_> = (0..3).map(|w| {
+                let db = db.clone();
+                let first = round * 100 + w * 5;
+                tokio::spawn(async move {
+                    let values: Vec<String> = (first..first + 10).map(|id| format!("({}, {})", id, round * 3 + w)).collect();
+                    db.execute_sql(&format!("INSERT INTO t VALUES {}", values.join(", "))).await
+                })
+            }).collect();
+
+            for (w, writer) in writers.into_iter().enumerate() {
+                let result = writer.await.unwrap();
+                let count = rows(&db, &format!("SELECT COUNT(*) FROM t WHERE writer = {}", round * 3 + w as u64)).await;
+                match result {
+                    Ok(_) => assert_eq!(count, vec![vec!["10"]], "round {}", round),
+                    // A writer loses to an overlapping one either on the
+                    // primary key or as a write-write conflict at commit
+                    Err(e) => {
+                        assert!(matches!(
+                            e,
+                            QueryError::ConstraintViolation { .. }
+                                | QueryError::Transaction(nextdb_transaction::TransactionError::Conflict)
+                        ), "{}", e);
+                        assert_eq!(count, vec![vec!["0"]], "round {}", round);
+                    }
+                }
+            }
+            for count in reader.await.unwrap() {
+                assert_eq!(count % 10, 0, "round {} saw {} rows", round, count);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_show_tables_and_describe() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        for sql in [
+            "CREATE TABLE users (id INT PRIMARY KEY, email VARCHAR(255) NOT NULL, name TEXT, score FLOAT)",
+            "CREATE TABLE events (user_id INT, seq INT, payload BLOB, PRIMARY KEY (user_id, seq))",
+            "CREATE TABLE log (line TEXT)",
+            "CREATE UNIQUE INDEX users_email ON users (email)",
+            "CREATE INDEX users_name_score ON users (name, score)",
+            "INSERT INTO users VALUES (1, 'a@x', 'Ann', 1.5), (2, 'b@x', 'Ben', NULL)",
+            "INSERT INTO log VALUES ('started'), ('stopped'), ('started')",
+        ] {
+            db.execute_sql(sql).await.unwrap_or_else(|e| panic!("{}: {}", sql, e));
+        }
+
+        let tables = db.execute_sql("SHOW TABLES").await.unwrap();
+        assert_eq!(tables.column_names(), vec!["table_name", "row_estimate"]);
+        assert_eq!(tables.text_rows(), vec![
+            vec!["events", "0"],
+            vec!["log", "3"],
+            vec!["users", "2"],
+        ]);
+        assert_eq!(
+            rows(&db, "SHOW TABLES WHERE row_estimate > 0").await,
+            vec![vec!["log", "3"], vec!["users", "2"]]
+        );
+
+        let columns = db.execute_sql("DESCRIBE users").await.unwrap();
+        assert_eq!(columns.column_names(), vec!["column_name", "data_type", "nullable", "primary_key", "indexes"]);
+        assert_eq!(columns.text_rows(), vec![
+            vec!["id", "INTEGER", "false", "true", "NULL"],
+            vec!["email", "TEXT", "false", "false", "users_email"],
+            vec!["name", "TEXT", "true", "false", "users_name_score"],
+            vec!["score", "FLOAT", "true", "false", "users_name_score"],
+        ]);
+        assert_eq!(
+            rows(&db, "SHOW COLUMNS FROM events WHERE primary_key").await,
+            vec![vec!["user_id", "INTEGER", "false", "true", "NULL"], vec!["seq", "INTEGER", "false", "true", "NULL"]]
+        );
+
+        // The catalog and row estimates survive a restart
+        drop(db);
+        let db = executor(&temp_dir).await;
+        assert_eq!(rows(&db, "SHOW TABLES").await, tables.text_rows());
+        assert_eq!(rows(&db, "DESCRIBE users").await, columns.text_rows());
+        db.execute_sql("INSERT INTO log VALUES ('restarted')").await.unwrap();
+        assert_eq!(rows(&db, "SELECT line FROM log WHERE line = 'restarted'").await.len(), 1);
+        assert_eq!(rows(&db, "SELECT * FROM log").await.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_column_defaults() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+        db.execute_sql(
+            "CREATE TABLE events (id INT PRIMARY KEY, kind TEXT DEFAULT 'info', weight FLOAT DEFAULT 1 + 1, \
+             note TEXT, at TIMESTAMP DEFAULT NOW() NOT NULL)"
+        ).await.unwrap();
+
+        // Omitted columns and DEFAULT take the column default, or NULL without one
+        db.execute_sql("INSERT INTO events (id) VALUES (1)").await.unwrap();
+        db.execute_sql("INSERT INTO events VALUES (2, DEFAULT, 0.5, DEFAULT, DEFAULT), (3, 'warn', DEFAULT, 'x', DEFAULT)").await.unwrap();
+        db.execute_sql("INSERT INTO events (kind, id) VALUES (DEFAULT, 4)").await.unwrap();
+        assert_eq!(rows(&db, "SELECT id, kind, weight, note FROM events").await, vec![
+            vec!["1", "info", "2", "NULL"],
+            vec!["2", "info", "0.5", "NULL"],
+            vec!["3", "warn", "2", "x"],
+            vec!["4", "info", "2", "NULL"],
+        ]);
+
+        // NOW() runs for every row rather than once when the table was created
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        db.execute_sql("INSERT INTO events (id) VALUES (5)").await.unwrap();
+        let times = rows(&db, "SELECT at FROM events WHERE id = 1 OR id = 5").await;
+        assert_ne!(times[0], times[1]);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM events WHERE at IS NULL").await, vec![vec!["0"]]);
+
+        // A NOT NULL column without a default must be given a value
+        db.execute_sql("CREATE TABLE strict (id INT PRIMARY KEY, name TEXT NOT NULL, tag TEXT)").await.unwrap();
+        for sql in [
+            "INSERT INTO strict (id) VALUES (1)",
+            "INSERT INTO strict VALUES (1, DEFAULT, 'x')",
+            "INSERT INTO strict DEFAULT VALUES",
+        ] {
+            assert!(matches!(db.execute_sql(sql).await, Err(QueryError::ConstraintViolation { .. })), "{}", sql);
+        }
+        db.execute_sql("CREATE TABLE log (line TEXT DEFAULT 'empty', n INT)").await.unwrap();
+        db.execute_sql("INSERT INTO log DEFAULT VALUES").await.unwrap();
+        assert_eq!(rows(&db, "SELECT * FROM log").await, vec![vec!["empty", "NULL"]]);
+
+        for sql in [
+            "INSERT INTO strict VALUES (DEFAULT + 1, 'a', 'b')",
+            "SELECT DEFAULT FROM strict",
+            "CREATE TABLE bad (a INT DEFAULT b)",
+        ] {
+            assert!(db.execute_sql(sql).await.is_err(), "{} should fail", sql);
+        }
+
+        // Rows from before ADD COLUMN read the default as of the ALTER; rows
+        // inserted after it evaluate the default afresh
+        db.execute_sql("ALTER TABLE log ADD COLUMN added TIMESTAMP DEFAULT NOW()").await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        db.execute_sql("INSERT INTO log (n) VALUES (1)").await.unwrap();
+        let added = rows(&db, "SELECT added FROM log").await;
+        assert_eq!(added.len(), 2);
+        assert_ne!(added[0], added[1]);
+        assert_eq!(rows(&db, "SELECT added FROM log").await, added);
+    }
+
     #[tokio::test]
-    async fn test_execute_table_scan() {
-        let plan = PhysicalPlan::TableScan {
-            table: "users".to_string(),
-            columns: vec!["*".to_string()],
-            filter: None,
+    async fn test_alter_table_add_and_drop_column() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        db.execute_sql("CREATE TABLE items (id INT PRIMARY KEY, name TEXT, note TEXT)").await.unwrap();
+        db.execute_sql("CREATE INDEX items_name ON items (name)").await.unwrap();
+        db.execute_sql("INSERT INTO items VALUES (1, 'old', 'n1')").await.unwrap();
+
+        db.execute_sql("ALTER TABLE items ADD COLUMN qty INT DEFAULT 5 NOT NULL").await.unwrap();
+        db.execute_sql("ALTER TABLE items ADD COLUMN tag TEXT").await.unwrap();
+        db.execute_sql("INSERT INTO items (id, name, note, tag) VALUES (2, 'new', 'n2', 'x')").await.unwrap();
+        db.execute_sql("INSERT INTO items VALUES (3, 'newer', 'n3', 7, NULL)").await.unwrap();
+        assert_eq!(rows(&db, "SELECT * FROM items").await, vec![
+            vec!["1", "old", "n1", "5", "NULL"],
+            vec!["2", "new", "n2", "5", "x"],
+            vec!["3", "newer", "n3", "7", "NULL"],
+        ]);
+        assert_eq!(rows(&db, "SELECT id FROM items WHERE qty = 5").await, vec![vec!["1"], vec!["2"]]);
+
+        db.execute_sql("ALTER TABLE items DROP COLUMN note").await.unwrap();
+        db.execute_sql("INSERT INTO items VALUES (4, 'latest', 1, 'y')").await.unwrap();
+        let result = db.execute_sql("SELECT * FROM items").await.unwrap();
+        assert_eq!(result.column_names(), vec!["id", "name", "qty", "tag"]);
+        assert_eq!(result.text_rows(), vec![
+            vec!["1", "old", "5", "NULL"],
+            vec!["2", "new", "5", "x"],
+            vec!["3", "newer", "7", "NULL"],
+            vec!["4", "latest", "1", "y"],
+        ]);
+        assert!(matches!(db.execute_sql("SELECT note FROM items").await, Err(QueryError::ColumnNotFound(_))));
+
+        // Re-adding a column with the same name must not resurrect old data
+        db.execute_sql("ALTER TABLE items ADD COLUMN note TEXT").await.unwrap();
+        assert_eq!(rows(&db, "SELECT note FROM items WHERE note IS NOT NULL").await.len(), 0);
+        db.execute_sql("ALTER TABLE items DROP COLUMN note").await.unwrap();
+
+        for sql in [
+            "ALTER TABLE items DROP COLUMN id",
+            "ALTER TABLE items DROP COLUMN name",
+            "ALTER TABLE items ADD COLUMN qty INT",
+            "ALTER TABLE items ADD COLUMN strict INT NOT NULL",
+            "ALTER TABLE items ADD COLUMN bad INT DEFAULT 'text'",
+        ] {
+            assert!(db.execute_sql(sql).await.is_err(), "{} should fail", sql);
+        }
+
+        let schema = db.catalog().table("items").unwrap();
+        assert_eq!(schema.version, 5);
+        assert_eq!(schema.dropped_columns.len(), 2);
+
+        // Rows written before the drop still carry the old values until reclaimed
+        assert_eq!(db.reclaim_dropped_columns("items").await.unwrap(), 3);
+        assert!(db.catalog().table("items").unwrap().dropped_columns.is_empty());
+        assert_eq!(rows(&db, "SELECT * FROM items").await, result.text_rows());
+    }
+
+    #[tokio::test]
+    async fn test_in_subquery() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        db.execute_sql("CREATE TABLE users (id INT PRIMARY KEY, active BOOLEAN)").await.unwrap();
+        db.execute_sql("CREATE TABLE orders (id INT PRIMARY KEY, user_id INT)").await.unwrap();
+        db.execute_sql("INSERT INTO users VALUES (1, TRUE), (2, FALSE), (3, TRUE)").await.unwrap();
+        db.execute_sql("INSERT INTO orders VALUES (10, 1), (11, 2), (12, 3), (13, 4), (14, NULL)").await.unwrap();
+
+        assert_eq!(
+            rows(&db, "SELECT id FROM orders WHERE user_id IN (SELECT id FROM users WHERE active = TRUE)").await,
+            vec![vec!["10"], vec!["12"]]
+        );
+        assert_eq!(
+            rows(&db, "SELECT id FROM orders WHERE user_id NOT IN (SELECT id FROM users WHERE active = TRUE)").await,
+            vec![vec!["11"], vec!["13"]]
+        );
+        assert_eq!(
+            rows(&db, "SELECT id FROM orders WHERE id > 10 AND NOT user_id IN (SELECT id FROM users) ORDER BY id DESC").await,
+            vec![vec!["13"]]
+        );
+
+        // With a NULL in the subquery result, NOT IN is never true: every
+        // non-matching comparison is unknown
+        assert_eq!(
+            rows(&db, "SELECT id FROM users WHERE id NOT IN (SELECT user_id FROM orders)").await,
+            Vec::<Vec<String>>::new()
+        );
+        assert_eq!(
+            rows(&db, "SELECT id FROM users WHERE id IN (SELECT user_id FROM orders WHERE user_id IS NOT NULL AND user_id > 1)").await,
+            vec![vec!["2"], vec!["3"]]
+        );
+        // An empty subquery makes NOT IN true even for NULL
+        assert_eq!(
+            rows(&db, "SELECT id FROM orders WHERE user_id NOT IN (SELECT id FROM users WHERE id > 100)").await.len(),
+            5
+        );
+        // Nested subqueries are planned independently
+        assert_eq!(
+            rows(&db, "SELECT id FROM orders WHERE user_id IN (SELECT id FROM users WHERE id IN (SELECT user_id FROM orders WHERE id < 12))").await,
+            vec![vec!["10"], vec!["11"]]
+        );
+
+        match db.execute_sql("SELECT id FROM orders WHERE user_id IN (SELECT id FROM users WHERE id = user_id)").await {
+            Err(QueryError::Plan(msg)) => assert!(msg.contains("correlated subqueries are not supported yet"), "{}", msg),
+            other => panic!("expected correlated subquery error, got {:?}", other),
+        }
+        for sql in [
+            "SELECT id FROM orders WHERE user_id IN (SELECT * FROM users)",
+            "SELECT user_id IN (SELECT id FROM users) FROM orders",
+            "DELETE FROM orders WHERE user_id IN (SELECT id FROM users)",
+        ] {
+            assert!(matches!(db.execute_sql(sql).await, Err(QueryError::Plan(_))), "{} should fail", sql);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_group_by_having() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        db.execute_sql("CREATE TABLE sales (id INT PRIMARY KEY, region TEXT, amount INT)").await.unwrap();
+        db.execute_sql(
+            "INSERT INTO sales VALUES (1, 'north', 10), (2, 'north', 20), (3, 'south', 5), (4, 'east', 50), \
+             (5, 'east', 60), (6, 'east', NULL), (7, 'west', 100)"
+        ).await.unwrap();
+
+        let result = db.execute_sql(
+            "SELECT region, COUNT(*) AS n, SUM(amount) FROM sales GROUP BY region ORDER BY region"
+        ).await.unwrap();
+        assert_eq!(result.column_names(), vec!["region", "n", "SUM(amount)"]);
+        assert_eq!(result.text_rows(), vec![
+            vec!["east", "3", "110"],
+            vec!["north", "2", "30"],
+            vec!["south", "1", "5"],
+            vec!["west", "1", "100"],
+        ]);
+
+        // HAVING on an aggregate that is not selected
+        assert_eq!(
+            rows(&db, "SELECT region FROM sales GROUP BY region HAVING COUNT(*) > 1 ORDER BY region").await,
+            vec![vec!["east"], vec!["north"]]
+        );
+        assert_eq!(
+            rows(&db, "SELECT region, MAX(amount) FROM sales GROUP BY region HAVING SUM(amount) >= 30 AND region <> 'west' ORDER BY region").await,
+            vec![vec!["east", "60"], vec!["north", "20"]]
+        );
+        // HAVING with ORDER BY and LIMIT on the aggregate value
+        assert_eq!(
+            rows(&db, "SELECT region, SUM(amount) AS total FROM sales GROUP BY region HAVING SUM(amount) > 10 ORDER BY total DESC LIMIT 2").await,
+            vec![vec!["east", "110"], vec!["west", "100"]]
+        );
+        assert_eq!(
+            rows(&db, "SELECT COUNT(amount), AVG(amount), MIN(region) FROM sales").await,
+            vec![vec!["6", "40.833333333333336", "east"]]
+        );
+        assert_eq!(
+            rows(&db, "SELECT COUNT(*), SUM(amount) FROM sales WHERE id > 100").await,
+            vec![vec!["0", "NULL"]]
+        );
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM sales HAVING COUNT(*) > 100").await.len(), 0);
+
+        match db.execute_sql("SELECT region FROM sales GROUP BY region HAVING amount > 10").await {
+            Err(QueryError::Plan(msg)) => assert_eq!(
+                msg, "column amount must appear in the GROUP BY clause or be used in an aggregate function"
+            ),
+            other => panic!("expected grouping error, got {:?}", other),
+        }
+        for sql in [
+            "SELECT id, COUNT(*) FROM sales GROUP BY region",
+            "SELECT * FROM sales GROUP BY region",
+            "SELECT region FROM sales WHERE COUNT(*) > 1 GROUP BY region",
+            "SELECT SUM(COUNT(*)) FROM sales",
+        ] {
+            assert!(matches!(db.execute_sql(sql).await, Err(QueryError::Plan(_))), "{} should fail", sql);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scalar_functions() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        db.execute_sql("CREATE TABLE people (id INT PRIMARY KEY, name TEXT, nick TEXT, score FLOAT)").await.unwrap();
+        db.execute_sql(
+            "INSERT INTO people VALUES (1, upper('  ada '), NULL, round(-2.5)), (2, 'Grace', 'gh', abs(-7.25)), \
+             (3, 'linus', NULLIF('x', 'x'), NULL)"
+        ).await.unwrap();
+
+        assert_eq!(
+            rows(&db, "SELECT trim(name), length(name), coalesce(nick, lower(name), 'none') FROM people").await,
+            vec![
+                vec!["ADA", "6", "  ada "],
+                vec!["Grace", "5", "gh"],
+                vec!["linus", "5", "linus"],
+            ]
+        );
+        assert_eq!(
+            rows(&db, "SELECT id FROM people WHERE substr(lower(name), 1, 1) = 'g' OR abs(score) > 2 ORDER BY length(trim(name)) DESC").await,
+            vec![vec!["2"], vec!["1"]]
+        );
+
+        db.execute_sql("UPDATE people SET name = replace(name, 'n', 'N'), score = coalesce(score, 0) WHERE id = 3").await.unwrap();
+        assert_eq!(rows(&db, "SELECT name, score FROM people WHERE id = 3").await, vec![vec!["liNus", "0"]]);
+        assert_eq!(
+            rows(&db, "SELECT round(SUM(score), 1), count(*) FROM people").await,
+            vec![vec!["4.3", "3"]]
+        );
+
+        for (sql, message) in [
+            ("SELECT uper(name) FROM people", "Unknown function uper (did you mean upper?)"),
+            ("SELECT substr(name) FROM people", "function substr expects 2 to 3 arguments, got 1"),
+            ("SELECT abs('x') FROM people", "argument 1 of abs must be a number, got TEXT"),
+            ("SELECT length(abs(1))", "argument 1 of length must be TEXT or BLOB, got INTEGER"),
+            ("SELECT id FROM people WHERE coalesce() IS NULL", "function coalesce expects at least 1 arguments, got 0"),
+        ] {
+            match db.execute_sql(sql).await {
+                Err(QueryError::Plan(msg)) => assert_eq!(msg, message, "{}", sql),
+                other => panic!("expected plan error for {}, got {:?}", sql, other),
+            }
+        }
+        // Column types are only known at run time
+        assert!(matches!(
+            db.execute_sql("SELECT upper(id) FROM people").await,
+            Err(QueryError::Execution(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_timestamps() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        db.execute_sql("CREATE TABLE events (id INT PRIMARY KEY, at TIMESTAMP, label TEXT)").await.unwrap();
+        db.execute_sql("CREATE INDEX events_at ON events (at)").await.unwrap();
+        // US clocks went forward at 2024-03-10 02:00 local time: the first two
+        // rows are an hour apart although their wall clocks differ by two
+        db.execute_sql(
+            "INSERT INTO events VALUES \
+             (1, '2024-03-10T01:30:00-05:00', 'before dst'), \
+             (2, '2024-03-10T03:30:00-04:00', 'after dst'), \
+             (3, '2024-01-01 00:00:00', 'new year'), \
+             (4, TIMESTAMP '2023-12-31T23:59:59.5Z' + 0.25, 'almost'), \
+             (5, '1969-07-20T20:17:00Z', 'moon landing')"
+        ).await.unwrap();
+
+        assert_eq!(
+            rows(&db, "SELECT at FROM events WHERE id = 1 OR id = 4").await,
+            vec![vec!["2024-03-10T06:30:00Z"], vec!["2023-12-31T23:59:59.750Z"]]
+        );
+        assert_eq!(
+            rows(&db, "SELECT id FROM events WHERE at >= '2024-01-01' AND at < TIMESTAMP '2024-03-10T07:00:00Z' ORDER BY at").await,
+            vec![vec!["3"], vec!["1"]]
+        );
+        assert_eq!(
+            rows(&db, "SELECT id FROM events WHERE at < '1970-01-01' OR at > '2024-03-10T02:15:00-05:00' ORDER BY at DESC").await,
+            vec![vec!["2"], vec!["5"]]
+        );
+        assert_eq!(
+            rows(&db, "SELECT at - TIMESTAMP '2024-03-10T01:30:00-05:00', EXTRACT(HOUR FROM at) FROM events WHERE id = 2").await,
+            vec![vec!["3600", "7"]]
+        );
+        assert_eq!(
+            rows(&db, "SELECT strftime('%Y-%m-%d', at + 86400), extract('year', at) FROM events WHERE label = 'almost'").await,
+            vec![vec!["2024-01-01", "2023"]]
+        );
+        assert_eq!(
+            rows(&db, "SELECT count(*) FROM events WHERE at < CURRENT_TIMESTAMP AND at < now()").await,
+            vec![vec!["5"]]
+        );
+
+        // Index entries are stored in timestamp order, pre-epoch values first
+        let schema = db.catalog.table("events").unwrap();
+        let prefix = encoding::index_prefix(schema.id, schema.index("events_at").unwrap().id);
+        let entries = db.storage.scan(&prefix, &encoding::prefix_end(&prefix), 100).await.unwrap();
+        let indexed: Vec<String> = entries.iter()
+            .map(|(key, _)| encoding::decode_key(&key[prefix.len()..]).unwrap()[0].to_string())
+            .collect();
+        assert_eq!(indexed, vec![
+            "1969-07-20T20:17:00Z",
+            "2023-12-31T23:59:59.750Z",
+            "2024-01-01T00:00:00Z",
+            "2024-03-10T06:30:00Z",
+            "2024-03-10T07:30:00Z",
+        ]);
+
+        assert!(matches!(
+            db.execute_sql("INSERT INTO events VALUES (6, 'next tuesday', NULL)").await,
+            Err(QueryError::Execution(_))
+        ));
+        assert!(matches!(
+            db.execute_sql("INSERT INTO events VALUES (6, 1710000000, NULL)").await,
+            Err(QueryError::Execution(_))
+        ));
+
+        // Results serialize with timestamps as ISO-8601 strings
+        let result = db.execute_sql("SELECT at FROM events WHERE id = 5").await.unwrap();
+        let json = serde_json::to_string(&result).unwrap();
+        assert!(json.contains(r#""rows":[["1969-07-20T20:17:00Z"]]"#), "{}", json);
+    }
+
+    #[tokio::test]
+    async fn test_result_column_types() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        db.execute_sql(
+            "CREATE TABLE t (id INT PRIMARY KEY, f FLOAT, s TEXT, b BOOLEAN, x BLOB, at TIMESTAMP)"
+        ).await.unwrap();
+        db.execute_sql(
+            "INSERT INTO t VALUES (1, 2.5, '1', true, 'hi', '2024-01-01 00:00:00'), (2, NULL, NULL, NULL, NULL, NULL)"
+        ).await.unwrap();
+
+        let types = |result: &ResultSet| result.columns.iter().map(|c| c.data_type).collect::<Vec<_>>();
+        let result = db.execute_sql("SELECT * FROM t ORDER BY id").await.unwrap();
+        assert_eq!(types(&result), vec![
+            Some(DataType::Integer), Some(DataType::Float), Some(DataType::Text),
+            Some(DataType::Boolean), Some(DataType::Blob), Some(DataType::Timestamp),
+        ]);
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["rows"], serde_json::json!([
+            [1, 2.5, "1", true, { "$base64": "aGk=" }, "2024-01-01T00:00:00Z"],
+            [2, null, null, null, null, null],
+        ]));
+        let decoded: ResultSet = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded, result);
+
+        // Types come from the plan, so they are known even without rows
+        let result = db.execute_sql(
+            "SELECT s, COUNT(*) AS n, AVG(id), MIN(at), MAX(id) + 1, upper(s), NULL AS nothing FROM t WHERE id > 5 GROUP BY s"
+        ).await.unwrap();
+        assert!(result.rows.is_empty());
+        assert_eq!(types(&result), vec![
+            Some(DataType::Text), Some(DataType::Integer), Some(DataType::Float),
+            Some(DataType::Timestamp), Some(DataType::Integer), Some(DataType::Text), None,
+        ]);
+
+        // Without a static type, a column takes the type of its values
+        let result = db.execute_sql("SELECT coalesce(NULL, 1.5) AS v").await.unwrap();
+        assert_eq!(types(&result), vec![Some(DataType::Float)]);
+        assert_eq!(types(&db.execute_sql("SELECT COUNT(*) FROM t").await.unwrap()), vec![Some(DataType::Integer)]);
+        assert_eq!(types(&db.execute_sql("DESCRIBE t").await.unwrap())[2], Some(DataType::Boolean));
+    }
+
+    #[tokio::test]
+    async fn test_result_cache() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = cached_executor(&temp_dir, QueryCacheConfig::default()).await;
+        let cache = db.result_cache().unwrap();
+
+        db.execute_sql("CREATE TABLE items (id INT PRIMARY KEY, qty INT)").await.unwrap();
+        db.execute_sql("CREATE TABLE other (id INT PRIMARY KEY)").await.unwrap();
+        db.execute_sql("INSERT INTO items VALUES (1, 5), (2, 7)").await.unwrap();
+
+        let total = "SELECT SUM(qty) FROM items";
+        assert_eq!(rows(&db, total).await, vec![vec!["12"]]);
+        // Delete the rows behind the executor's back: a hit does not read storage
+        let schema = db.catalog.table("items").unwrap();
+        for id in [1, 2] {
+            db.storage.delete(&encoding::row_key(schema.id, &[Value::Integer(id)])).await.unwrap();
+        }
+        assert_eq!(rows(&db, "select  sum(QTY)\n from items -- same query").await, vec![vec!["12"]]);
+        assert_eq!(cache.stats(), cache::QueryCacheStats { hits: 1, misses: 1, entries: 1 });
+
+        // Writes to an unrelated table keep the entry, writes to `items` invalidate it
+        db.execute_sql("INSERT INTO other VALUES (1)").await.unwrap();
+        assert_eq!(rows(&db, total).await, vec![vec!["12"]]);
+        db.execute_sql("INSERT INTO items VALUES (3, 1)").await.unwrap();
+        assert_eq!(rows(&db, total).await, vec![vec!["1"]]);
+        assert_eq!(cache.stats().hits, 2);
+
+        // Queries reading the clock are never cached
+        db.execute_sql("SELECT id FROM items WHERE now() > TIMESTAMP '2000-01-01'").await.unwrap();
+        db.execute_sql("SELECT id FROM items WHERE now() > TIMESTAMP '2000-01-01'").await.unwrap();
+        assert_eq!(cache.stats().hits, 2);
+
+        // Schema changes invalidate too
+        db.execute_sql("ALTER TABLE items ADD COLUMN note TEXT").await.unwrap();
+        assert_eq!(rows(&db, "SELECT * FROM items").await, vec![vec!["3", "1", "NULL"]]);
+        db.execute_sql("ALTER TABLE items DROP COLUMN note").await.unwrap();
+        assert_eq!(rows(&db, "SELECT * FROM items").await, vec![vec!["3", "1"]]);
+    }
+
+    #[tokio::test]
+    async fn test_result_cache_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = QueryCacheConfig { max_entries: 2, ttl: std::time::Duration::from_millis(50) };
+        let db = cached_executor(&temp_dir, config).await;
+        let cache = db.result_cache().unwrap();
+
+        db.execute_sql("CREATE TABLE t (id INT PRIMARY KEY)").await.unwrap();
+        db.execute_sql("INSERT INTO t VALUES (1), (2), (3)").await.unwrap();
+        for sql in ["SELECT id FROM t WHERE id = 1", "SELECT id FROM t WHERE id = 2"] {
+            db.execute_sql(sql).await.unwrap();
+        }
+        // Touch the first query so the second is least recently used
+        db.execute_sql("SELECT id FROM t WHERE id = 1").await.unwrap();
+        db.execute_sql("SELECT id FROM t WHERE id = 3").await.unwrap();
+        assert_eq!(cache.stats(), cache::QueryCacheStats { hits: 1, misses: 3, entries: 2 });
+        db.execute_sql("SELECT id FROM t WHERE id = 2").await.unwrap();
+        assert_eq!(cache.stats().misses, 4);
+
+        tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+        db.execute_sql("SELECT id FROM t WHERE id = 2").await.unwrap();
+        assert_eq!(cache.stats().hits, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_fast_count() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = Arc::new(executor(&temp_dir).await);
+        db.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, v INT)").await.unwrap();
+        db.execute_sql("CREATE INDEX t_v ON t (v)").await.unwrap();
+        db.execute_sql("CREATE TABLE log (msg TEXT)").await.unwrap();
+
+        // Writers insert 30 rows each and delete a third of them concurrently
+        let writers: Vec<_> = (0..6).map(|w| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for i in 0..30 {
+                    let id = w * 100 + i;
+                    db.execute_sql(&format!("INSERT INTO t VALUES ({}, {})", id, i % 3)).await.unwrap();
+                    db.execute_sql(&format!("INSERT INTO log VALUES ('{}')", id)).await.unwrap();
+                }
+                db.execute_sql(&format!("DELETE FROM t WHERE id >= {} AND id < {} AND v = 0", w * 100, w * 100 + 100)).await.unwrap();
+                db.execute_sql(&format!("UPDATE t SET v = 5 WHERE id = {}", w * 100 + 1)).await.unwrap();
+            })
+        }).collect();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM t").await, vec![vec!["120"]]);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM t WHERE id IS NOT NULL").await, vec![vec!["120"]]);
+        assert_eq!(rows(&db, "SELECT count(*) AS n FROM log LIMIT 1").await, vec![vec!["180"]]);
+
+        let explain = |sql: &'static str| {
+            let db = db.clone();
+            async move { rows(&db, &format!("EXPLAIN {}", sql)).await.concat() }
         };
-        
-        let result = QueryExecutor::execute(plan).await.unwrap();
-        assert_eq!(result.columns, vec!["id", "name"]);
-        assert_eq!(result.rows.len(), 2);
+        assert_eq!(explain("SELECT COUNT(*) FROM t").await, vec!["TableCount t"]);
+        assert_eq!(explain("SELECT COUNT(*) AS n FROM t LIMIT 5").await, vec!["Limit 5", "  TableCount t"]);
+        assert_eq!(explain("SELECT COUNT(*) FROM t WHERE v > 1").await, vec![
+            "HashAggregate group by: [] aggregates: [COUNT(*)]",
+            "  TableScan t filter: v > 1",
+        ]);
+        for sql in [
+            "SELECT COUNT(v) FROM t",
+            "SELECT COUNT(*) FROM t GROUP BY v",
+            "SELECT COUNT(*), MAX(v) FROM t",
+            "SELECT COUNT(*) + 1 FROM t",
+        ] {
+            assert!(!explain(sql).await.iter().any(|line| line.contains("TableCount")), "{}", sql);
+        }
+
+        // The counters survive a restart without rescanning, and hidden row
+        // ids keep going from where they were
+        drop(db);
+        let db = executor(&temp_dir).await;
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM t").await, vec![vec!["120"]]);
+        db.execute_sql("INSERT INTO log VALUES ('after restart')").await.unwrap();
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM log").await, vec![vec!["181"]]);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM log WHERE msg IS NOT NULL").await, vec![vec!["181"]]);
+
+        // A drifted counter is repaired by a recount
+        let schema = db.catalog.table("t").unwrap();
+        let stats = db.catalog.stats(schema.id);
+        db.storage.put(encoding::stats_key(schema.id), stats.encode(7)).await.unwrap();
+        stats.set_row_count(7);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM t").await, vec![vec!["7"]]);
+        assert_eq!(db.catalog.recount_rows("t").await.unwrap(), 120);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM t").await, vec![vec!["120"]]);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_analyze() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        db.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, grp INT, note TEXT)").await.unwrap();
+        for chunk in (0..300).collect::<Vec<i64>>().chunks(100) {
+            let values: Vec<String> = chunk.iter()
+                .map(|i| {
+                    let note = if i % 3 == 0 { "NULL".to_string() } else { format!("'n{}'", i) };
+                    format!("({}, {}, {})", i, i % 10, note)
+                })
+                .collect();
+            db.execute_sql(&format!("INSERT INTO t VALUES {}", values.join(", "))).await.unwrap();
+        }
+        let schema = db.catalog.table("t").unwrap();
+        assert!(db.catalog.stats(schema.id).analysis().is_none());
+
+        assert_eq!(rows(&db, "ANALYZE t").await, vec![
+            vec!["t", "id", "0", "300", "0", "299"],
+            vec!["t", "grp", "0", "10", "0", "9"],
+            vec!["t", "note", "100", "200", "n1", "n98"],
+        ]);
+        assert!(db.execute_sql("ANALYZE missing").await.is_err());
+
+        // The planner estimates how many rows a filter keeps
+        let estimate = |sql: &str| {
+            let plan = QueryPlanner::plan(SqlParser::parse(sql).unwrap(), &db.catalog).unwrap();
+            plan.estimated_rows(&db.catalog).round() as u64
+        };
+        assert_eq!(estimate("SELECT * FROM t"), 300);
+        assert_eq!(estimate("SELECT * FROM t WHERE grp = 3"), 30);
+        assert_eq!(estimate("SELECT * FROM t WHERE grp = 42"), 0);
+        assert_eq!(estimate("SELECT * FROM t WHERE id < 100"), 100);
+        assert_eq!(estimate("SELECT * FROM t WHERE note IS NULL"), 100);
+        assert_eq!(estimate("SELECT * FROM t WHERE grp = 3 AND note IS NULL"), 10);
+        assert_eq!(estimate("SELECT * FROM t WHERE id = 7 LIMIT 5"), 1);
+
+        // and runs the most selective terms first
+        assert_eq!(rows(&db, "EXPLAIN SELECT id FROM t WHERE note IS NOT NULL AND id / 2 > 1 AND grp = 3").await.concat(), vec![
+            "Project id",
+            "  TableScan t filter: ((grp = 3) AND (note IS NOT NULL)) AND ((id / 2) > 1)",
+        ]);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM t WHERE note IS NOT NULL AND grp = 3").await, vec![vec!["20"]]);
+
+        // Writes widen the bounds, and the statistics survive a restart
+        // along with the row count they were gathered from
+        db.execute_sql("INSERT INTO t VALUES (1000, 50, 'z')").await.unwrap();
+        drop(db);
+        let db = executor(&temp_dir).await;
+        let stats = db.catalog.stats(schema.id);
+        let analysis = stats.analysis().unwrap();
+        assert_eq!(analysis.rows, 300);
+        assert_eq!(stats.row_count(), 301);
+        let id = &analysis.columns[&0];
+        assert_eq!((id.min.clone(), id.max.clone()), (Some(Value::Integer(0)), Some(Value::Integer(1000))));
+
+        // Compaction refreshes them
+        db.execute_sql("DELETE FROM t WHERE id >= 200").await.unwrap();
+        db.compact().await.unwrap();
+        let analysis = db.catalog.stats(schema.id).analysis().unwrap();
+        assert_eq!(analysis.rows, 200);
+        assert_eq!(analysis.columns[&0].max, Some(Value::Integer(199)));
+        assert_eq!(analysis.columns[&1].distinct, 10);
+    }
+
+    #[tokio::test]
+    async fn test_analyze_all_tables() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        db.execute_sql("CREATE TABLE users (id INT PRIMARY KEY, age INT)").await.unwrap();
+        db.execute_sql("CREATE TABLE tags (name TEXT PRIMARY KEY)").await.unwrap();
+        // Most users are 20 to 29, a few are up to 1000
+        let values: Vec<String> = (0..500)
+            .map(|i| format!("({}, {})", i, if i < 450 { 20 + i % 10 } else { (i - 450) * 20 }))
+            .collect();
+        db.execute_sql(&format!("INSERT INTO users VALUES {}", values.join(", "))).await.unwrap();
+        db.execute_sql("INSERT INTO tags VALUES ('a'), ('b')").await.unwrap();
+
+        let result = rows(&db, "ANALYZE").await;
+        assert_eq!(result.iter().map(|row| format!("{}.{}", row[0], row[1])).collect::<Vec<_>>(),
+            vec!["tags.name", "users.id", "users.age"]);
+
+        let users = db.catalog.table("users").unwrap();
+        let stats = db.catalog.stats(users.id);
+        let analysis = stats.analysis().unwrap();
+        assert_eq!(analysis.rows, 500);
+        assert_eq!(stats.row_count(), 500);
+        let age = &analysis.columns[&1];
+        assert_eq!((age.min.clone(), age.max.clone()), (Some(Value::Integer(0)), Some(Value::Integer(980))));
+        assert!(age.histogram.len() > 2);
+        let tags = db.catalog.table("tags").unwrap();
+        assert_eq!(db.catalog.stats(tags.id).analysis().unwrap().rows, 2);
+
+        // The histogram sees that most ages are low, which the bounds alone
+        // would put at 3% of the rows
+        let plan = QueryPlanner::plan(SqlParser::parse("SELECT * FROM users WHERE age < 30").unwrap(), &db.catalog).unwrap();
+        let estimate = plan.estimated_rows(&db.catalog);
+        assert!((430.0..=480.0).contains(&estimate), "{}", estimate);
+    }
+
+    #[tokio::test]
+    async fn test_cursor_pages_from_snapshot() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+        db.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, v TEXT)").await.unwrap();
+        let values: Vec<String> = (0..250).map(|i| format!("({}, NULL)", i)).collect();
+        db.execute_sql(&format!("INSERT INTO t VALUES {}", values.join(", "))).await.unwrap();
+
+        let select = SqlParser::parse("SELECT id, v FROM t ORDER BY id").unwrap();
+        let mut cursor = db.open_cursor(select).await.unwrap();
+        let first = cursor.next_page(100).await.unwrap();
+        assert_eq!((first.rows.len(), cursor.is_exhausted()), (100, false));
+
+        // Writes after the cursor opened are not seen by later pages
+        db.execute_sql("UPDATE t SET v = 'changed'").await.unwrap();
+        db.execute_sql("DELETE FROM t WHERE id >= 200").await.unwrap();
+        db.execute_sql("INSERT INTO t VALUES (1000, 'new')").await.unwrap();
+        let mut ids: Vec<Value> = first.rows.iter().map(|row| row[0].clone()).collect();
+        for expected in [100, 50] {
+            let page = cursor.next_page(100).await.unwrap();
+            assert_eq!(page.rows.len(), expected);
+            assert_eq!(page.columns, first.columns);
+            assert!(page.rows.iter().all(|row| row[1] == Value::Null));
+            ids.extend(page.rows.into_iter().map(|row| row[0].clone()));
+        }
+        assert!(cursor.is_exhausted());
+        assert_eq!(ids, (0..250).map(Value::Integer).collect::<Vec<_>>());
+        assert!(db.transactions().has_snapshots());
+        cursor.close().await.unwrap();
+        assert!(!db.transactions().has_snapshots());
+
+        // A result that fits one page is exhausted by it
+        let mut cursor = db.open_cursor(SqlParser::parse("SELECT id FROM t WHERE id < 10").unwrap()).await.unwrap();
+        assert_eq!(cursor.next_page(10).await.unwrap().rows.len(), 10);
+        assert!(cursor.is_exhausted());
+        drop(cursor);
+        tokio::task::yield_now().await;
+        assert!(!db.transactions().has_snapshots());
+
+        let error = db.open_cursor(SqlParser::parse("DELETE FROM t").unwrap()).await.err().unwrap();
+        assert!(matches!(error, QueryError::Invalid(_)), "{}", error);
+        assert!(!db.transactions().has_snapshots());
+    }
+
+    #[tokio::test]
+    async fn test_slow_query_log() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = SlowQueryConfig { threshold: Duration::from_millis(5), max_per_second: 10 };
+        let db = executor(&temp_dir).await.with_slow_query_log(config);
+        db.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, v TEXT)").await.unwrap();
+        let values: Vec<String> = (0..5000).map(|i| format!("({}, 'value {}')", i, i)).collect();
+        db.execute_sql(&format!("INSERT INTO t VALUES {}", values.join(", "))).await.unwrap();
+        let log = db.slow_query_log().unwrap();
+        let logged = log.recent().len();
+
+        // Answered from the table's row count, without a scan
+        db.execute_sql("SELECT COUNT(*) FROM t").await.unwrap();
+        assert_eq!(log.recent().len(), logged);
+
+        // Reads the table twice and sorts half of it
+        db.execute_sql("SELECT v FROM t WHERE id IN (SELECT id FROM t WHERE id % 2 = 0) ORDER BY v DESC").await.unwrap();
+        let slow = log.recent().pop().unwrap();
+        assert_eq!(slow.sql.as_deref(), Some("SELECT v FROM t WHERE id IN (SELECT id FROM t WHERE (id % 2) = 0) ORDER BY v DESC"));
+        assert_eq!(slow.rows_examined, 10_000);
+        assert_eq!(slow.plan[..2], ["Project v", "  Sort v DESC"]);
+        assert_eq!(slow.plan.last().unwrap(), "        TableScan t filter: (id % 2) = 0");
+        assert!(slow.duration >= Duration::from_millis(5) && slow.succeeded);
+    }
+
+    /// A log that applies each write to every replica at once, the first
+    /// one being the executor that wrote it
+    #[derive(Default)]
+    struct InstantLog {
+        replicas: parking_lot::Mutex<Vec<Arc<QueryExecutor>>>,
+        index: AtomicU64,
+    }
+
+    impl Replicator for InstantLog {
+        fn replicate(&self, write: ReplicatedWrite) -> future::BoxFuture<'_, Result<ResultSet>> {
+            Box::pin(async move {
+                let index = self.index.fetch_add(1, Ordering::SeqCst) + 1;
+                let replicas = self.replicas.lock().clone();
+                let mut results = Vec::new();
+                for replica in replicas {
+                    results.push(replica.apply_replicated(write.clone(), index).await);
+                }
+                results.remove(0)
+            })
+        }
+
+        fn read_barrier(&self) -> future::BoxFuture<'_, Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn confirm_reads(&self) -> future::BoxFuture<'_, Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replicated_writes() {
+        let (leader_dir, follower_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let log = Arc::new(InstantLog::default());
+        let leader = Arc::new(executor(&leader_dir).await.with_replicator(log.clone()));
+        let follower = Arc::new(executor(&follower_dir).await);
+        log.replicas.lock().extend([leader.clone(), follower.clone()]);
+
+        leader.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, v TEXT)").await.unwrap();
+        leader.execute_sql("CREATE TABLE events (what TEXT)").await.unwrap();
+        leader.execute_sql("INSERT INTO t VALUES (1, 'a'), (2, 'b'), (3, 'c')").await.unwrap();
+        leader.execute_sql("INSERT INTO events VALUES ('x'), ('y')").await.unwrap();
+        leader.execute_sql("UPDATE t SET v = 'B' WHERE id = 2").await.unwrap();
+        leader.execute_sql("DELETE FROM t WHERE id = 3").await.unwrap();
+        leader.execute_sql("ANALYZE t").await.unwrap();
+        // Failing statements fail alike everywhere and still take an index
+        assert!(matches!(leader.execute_sql("CREATE TABLE t (id INT)").await, Err(QueryError::TableExists(_))));
+        assert!(leader.execute_sql("INSERT INTO t VALUES (1, 'again')").await.is_err());
+
+        for sql in ["SELECT * FROM t ORDER BY id", "SELECT COUNT(*) FROM t", "SELECT COUNT(*) FROM events"] {
+            assert_eq!(rows(&follower, sql).await, rows(&leader, sql).await, "{}", sql);
+        }
+        assert_eq!(rows(&follower, "SELECT COUNT(*) FROM t").await, vec![vec!["2"]]);
+        let schema = follower.catalog().table("t").unwrap();
+        assert!(follower.catalog().stats(schema.id).analysis().is_some());
+        assert_eq!(follower.applied_index().await.unwrap(), 8);
+        assert_eq!(leader.applied_index().await.unwrap(), 8);
+
+        // The follower takes over hidden row ids where the leader left off
+        follower.execute_sql("INSERT INTO events VALUES ('z')").await.unwrap();
+        assert_eq!(rows(&follower, "SELECT what FROM events").await, vec![vec!["x"], vec!["y"], vec!["z"]]);
+    }
+
+    #[tokio::test]
+    async fn test_histogram_selectivity() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        // Values crowd toward 0, and 500 alone is 30% of the rows
+        db.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, v INT)").await.unwrap();
+        for chunk in (0..2000).collect::<Vec<i64>>().chunks(500) {
+            let values: Vec<String> = chunk.iter()
+                .map(|i| format!("({}, {})", i, if i % 10 < 3 { 500 } else { i * i / 2000 }))
+                .collect();
+            db.execute_sql(&format!("INSERT INTO t VALUES {}", values.join(", "))).await.unwrap();
+        }
+        db.execute_sql("ANALYZE t").await.unwrap();
+
+        for predicate in [
+            "v < 100",
+            "v >= 1000",
+            "v > 500",
+            "v <= 500",
+            "v = 500",
+            "v >= 200 AND v <= 800",
+            "v < 50 AND id >= 0 AND v > 10",
+            "100 > v",
+        ] {
+            let sql = format!("SELECT * FROM t WHERE {}", predicate);
+            let plan = QueryPlanner::plan(SqlParser::parse(&sql).unwrap(), &db.catalog).unwrap();
+            let estimate = plan.estimated_rows(&db.catalog) / 2000.0;
+            let count = rows(&db, &format!("SELECT COUNT(*) FROM t WHERE {}", predicate)).await;
+            let actual = count[0][0].parse::<f64>().unwrap() / 2000.0;
+            assert!((estimate - actual).abs() < 0.05, "{}: estimated {} of the rows, actually {}", predicate, estimate, actual);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_result_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await
+            .with_result_limits(ResultLimits { max_result_rows: Some(50), max_result_bytes: Some(8192) });
+        db.execute_sql("CREATE TABLE big (id INT PRIMARY KEY, body TEXT)").await.unwrap();
+        for chunk in 0..4 {
+            let values: Vec<String> = (0..50).map(|i| format!("({}, 'row')", chunk * 50 + i)).collect();
+            db.execute_sql(&format!("INSERT INTO big VALUES {}", values.join(", "))).await.unwrap();
+        }
+        db.execute_sql(&format!("INSERT INTO big VALUES (1000, '{}')", "payload-".repeat(1200))).await.unwrap();
+
+        for (sql, message) in [
+            ("SELECT * FROM big", "result too large: more than 50 rows"),
+            ("SELECT id FROM big WHERE id >= 150", "result too large: more than 50 rows"),
+            ("SELECT body FROM big WHERE id = 1000 OR id < 30", "result too large: more than 8192 bytes"),
+        ] {
+            match db.execute_sql(sql).await {
+                Err(QueryError::Execution(msg)) => assert_eq!(msg, message, "{}", sql),
+                other => panic!("expected the result guard for {}, got {:?}", sql, other),
+            }
+        }
+
+        // Results within the limits, including aggregates over the whole table, still work
+        assert_eq!(db.execute_sql("SELECT * FROM big LIMIT 50").await.unwrap().rows.len(), 50);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM big WHERE body = 'row'").await, vec![vec!["200"]]);
+        // DML is not a result set and is unaffected
+        assert_eq!(db.execute_sql("DELETE FROM big WHERE id < 100").await.unwrap().rows_affected, Some(100));
+    }
+
+    #[tokio::test]
+    async fn test_explain_analyze_spills() {
+        let temp_dir = TempDir::new().unwrap();
+        let spill_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await
+            .with_spill_config(SpillConfig { memory_budget: 4 * 1024, dir: spill_dir.path().to_path_buf() });
+
+        db.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, v INT, s TEXT)").await.unwrap();
+        let values: Vec<String> = (0..500).map(|i| format!("({}, {}, 'row {}')", i, (i * 37) % 101, i)).collect();
+        db.execute_sql(&format!("INSERT INTO t VALUES {}", values.join(", "))).await.unwrap();
+
+        // The sort spills but still returns every row in order
+        let sorted = rows(&db, "SELECT v, id FROM t ORDER BY v DESC, id").await;
+        assert_eq!(sorted.len(), 500);
+        let keys: Vec<(i64, i64)> = sorted.iter().map(|row| (row[0].parse().unwrap(), row[1].parse().unwrap())).collect();
+        assert!(keys.windows(2).all(|pair| pair[0].0 > pair[1].0 || (pair[0].0 == pair[1].0 && pair[0].1 < pair[1].1)));
+        assert_eq!(std::fs::read_dir(spill_dir.path()).unwrap().count(), 0);
+
+        let lines = rows(&db, "EXPLAIN ANALYZE SELECT id FROM t WHERE v < 50 ORDER BY s LIMIT 3").await.concat();
+        assert_eq!(lines.len(), 5, "{:?}", lines);
+        assert!(lines[0].starts_with("Project id (rows: 3)"), "{:?}", lines);
+        assert!(lines[1].starts_with("  Limit 3 (rows: 3)"), "{:?}", lines);
+        assert!(lines[2].starts_with("    Sort s (rows: 3, spilled runs: "), "{:?}", lines);
+        assert!(lines[2].contains("peak memory: "), "{:?}", lines);
+        assert_eq!(lines[3], "      TableScan t filter: v < 50 (rows: 248)");
+        assert!(lines[4].starts_with("Execution time: "), "{:?}", lines);
+
+        // Grouping spills too
+        let mut expected = std::collections::BTreeMap::new();
+        for i in 0..500 {
+            *expected.entry((i * 37) % 101).or_insert(0) += 1;
+        }
+        let expected: Vec<Vec<String>> = expected.into_iter().map(|(v, n)| vec![v.to_string(), n.to_string()]).collect();
+        assert_eq!(rows(&db, "SELECT v, COUNT(*) FROM t GROUP BY v ORDER BY v").await, expected);
+        let lines = rows(&db, "EXPLAIN ANALYZE SELECT v, COUNT(*) FROM t GROUP BY v").await.concat();
+        assert!(lines.iter().any(|line| line.trim_start().starts_with("HashAggregate") && line.contains("spilled runs: ")), "{:?}", lines);
+
+        // Plain EXPLAIN does not run the query, and ANALYZE only runs queries
+        assert!(!rows(&db, "EXPLAIN SELECT id FROM t ORDER BY s").await.concat().iter().any(|line| line.contains("rows:")));
+        assert!(db.execute_sql("EXPLAIN ANALYZE DELETE FROM t").await.is_err());
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM t").await, vec![vec!["500"]]);
+    }
+
+    #[tokio::test]
+    async fn test_query_memory_limit() {
+        let temp_dir = TempDir::new().unwrap();
+        let spill_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await
+            .with_spill_config(SpillConfig { dir: spill_dir.path().to_path_buf(), ..SpillConfig::default() })
+            .with_query_memory_limit(32 * 1024);
+
+        db.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, g INT, s TEXT)").await.unwrap();
+        let padding = "x".repeat(100);
+        let values: Vec<String> = (0..2000).map(|i| format!("({}, {}, '{:04} {}')", i, i % 500, (i * 7) % 2000, padding)).collect();
+        db.execute_sql(&format!("INSERT INTO t VALUES {}", values.join(", "))).await.unwrap();
+
+        // Far within the spill budget, but over the query's limit, so the
+        // sort and the aggregation spill
+        let sorted = rows(&db, "SELECT s FROM t ORDER BY s").await;
+        assert_eq!(sorted.len(), 2000);
+        assert!(sorted.windows(2).all(|pair| pair[0] < pair[1]));
+        let lines = rows(&db, "EXPLAIN ANALYZE SELECT s FROM t ORDER BY s").await.concat();
+        assert!(lines.iter().any(|line| line.trim_start().starts_with("Sort s") && line.contains("spilled runs: ")), "{:?}", lines);
+        let groups = rows(&db, "SELECT g, COUNT(*), MIN(s) FROM t GROUP BY g").await;
+        assert_eq!(groups.len(), 500);
+        assert!(groups.iter().all(|row| row[1] == "4"));
+        let lines = rows(&db, "EXPLAIN ANALYZE SELECT g, MIN(s) FROM t GROUP BY g").await.concat();
+        assert!(lines.iter().any(|line| line.trim_start().starts_with("HashAggregate") && line.contains("spilled runs: ")), "{:?}", lines);
+
+        // A subquery's set cannot spill, so the guard fires
+        let sql = "SELECT id FROM t WHERE s IN (SELECT s FROM t WHERE id % 2 = 0) ORDER BY s";
+        match db.execute_sql(sql).await {
+            Err(QueryError::Execution(message)) => {
+                assert_eq!(message, "out of memory: the query needs more than 32768 bytes");
+            }
+            other => panic!("expected the memory guard, got {:?}", other),
+        }
+        assert_eq!(std::fs::read_dir(spill_dir.path()).unwrap().count(), 0);
+
+        // The memory of a failed query is not held against the next one,
+        // and a small set fits
+        assert_eq!(rows(&db, "SELECT id FROM t WHERE id IN (SELECT id FROM t WHERE g = 7) ORDER BY id").await.concat(), ["7", "507", "1007", "1507"]);
+        let unlimited = QueryExecutor::open(db.storage.clone()).await.unwrap();
+        assert_eq!(unlimited.execute_sql(sql).await.unwrap().rows.len(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_projection_pushdown() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        let columns: Vec<String> = (1..10).map(|i| format!("c{} TEXT", i)).collect();
+        db.execute_sql(&format!("CREATE TABLE wide (id INT PRIMARY KEY, {})", columns.join(", "))).await.unwrap();
+        for id in 0..20 {
+            let values: Vec<String> = (1..10).map(|i| format!("'{}-{}'", id, i)).collect();
+            db.execute_sql(&format!("INSERT INTO wide VALUES ({}, {})", id, values.join(", "))).await.unwrap();
+        }
+        let decoded = |sql: &'static str| {
+            let db = &db;
+            async move {
+                let before = db.columns_decoded();
+                let result = rows(db, sql).await;
+                (result, db.columns_decoded() - before)
+            }
+        };
+
+        let (all, wide) = decoded("SELECT * FROM wide").await;
+        assert_eq!(all.len(), 20);
+        assert_eq!(wide, 20 * 10);
+
+        // Two columns out of ten, the filter's among them
+        let (projected, narrow) = decoded("SELECT id, c3 FROM wide WHERE id > 17").await;
+        assert_eq!(projected, vec![vec!["18", "18-3"], vec!["19", "19-3"]]);
+        assert_eq!(narrow, 20 * 2);
+
+        // A column the filter alone reads is decoded but not returned
+        let (filtered, _) = decoded("SELECT c1 FROM wide WHERE c9 = '7-9'").await;
+        assert_eq!(filtered, vec![vec!["7-1"]]);
+
+        // Without pushdown the same query decodes whole rows
+        let db = db.with_projection_pushdown(false);
+        let before = db.columns_decoded();
+        assert_eq!(rows(&db, "SELECT id, c3 FROM wide WHERE id > 17").await, projected);
+        assert_eq!(db.columns_decoded() - before, wide);
+    }
+
+    #[tokio::test]
+    async fn test_primary_key_in_list() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        db.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, v INT, note TEXT)").await.unwrap();
+        let values: Vec<String> = (0..500).map(|i| format!("({}, {}, 'n{}')", i, i % 7, i)).collect();
+        db.execute_sql(&format!("INSERT INTO t VALUES {}", values.join(", "))).await.unwrap();
+        let explain = |sql: &'static str| {
+            let db = &db;
+            async move { rows(db, &format!("EXPLAIN {}", sql)).await.concat() }
+        };
+
+        // Only the listed rows are read, in key order, whatever the list order,
+        // with duplicates, NULLs and absent keys dropping out
+        let lookup = "SELECT id, note FROM t WHERE id IN (420, 3, NULL, 3, 9999, 17)";
+        assert!(explain(lookup).await.iter().any(|line| line.contains("KeyLookup t keys: 5")));
+        let before = db.columns_decoded();
+        let found = rows(&db, lookup).await;
+        assert_eq!(found, vec![vec!["3", "n3"], vec!["17", "n17"], vec!["420", "n420"]]);
+        assert_eq!(db.columns_decoded() - before, 3 * 2);
+
+        // The rest of the predicate still applies
+        assert_eq!(rows(&db, "SELECT id FROM t WHERE v = 3 AND id IN (3, 10, 11)").await, vec![vec!["3"], vec!["10"]]);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM t WHERE id IN (1, 2) OR id = 3").await, vec![vec!["3"]]);
+        assert!(rows(&db, "SELECT id FROM t WHERE id IN (NULL)").await.is_empty());
+
+        // Lists on other columns, NOT IN and keys that need evaluating are scanned
+        for sql in [
+            "SELECT id FROM t WHERE v IN (1, 2)",
+            "SELECT id FROM t WHERE id NOT IN (1, 2)",
+            "SELECT id FROM t WHERE id IN (1, 1 + 1)",
+            "SELECT id FROM t WHERE id IN (1, 2.5)",
+        ] {
+            let lines = explain(sql).await;
+            assert!(lines.iter().any(|line| line.contains("TableScan t filter:")), "{}: {:?}", sql, lines);
+        }
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM t WHERE v IN (1, 2)").await, vec![vec!["144"]]);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM t WHERE id NOT IN (1, 2, NULL)").await, vec![vec!["0"]]);
+        assert_eq!(rows(&db, "SELECT id FROM t WHERE id IN (1, 1 + 1)").await, vec![vec!["1"], vec!["2"]]);
+
+        // Writes since the rows were read are seen, and disabling lookups
+        // scans for the same rows
+        db.execute_sql("DELETE FROM t WHERE id = 3").await.unwrap();
+        db.execute_sql("UPDATE t SET note = 'changed' WHERE id = 420").await.unwrap();
+        let changed = vec![vec!["17", "n17"], vec!["420", "changed"]];
+        assert_eq!(rows(&db, lookup).await, changed);
+        let db = db.with_key_lookups(false);
+        let before = db.columns_decoded();
+        assert_eq!(rows(&db, lookup).await, changed);
+        assert!(db.columns_decoded() - before > 400);
+    }
+
+    #[tokio::test]
+    async fn test_offset() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+
+        db.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, v INT)").await.unwrap();
+        let values: Vec<String> = (0..600).map(|i| format!("({}, {})", i, 599 - i)).collect();
+        db.execute_sql(&format!("INSERT INTO t VALUES {}", values.join(", "))).await.unwrap();
+        let explain = |sql: &'static str| {
+            let db = &db;
+            async move { rows(db, &format!("EXPLAIN {}", sql)).await.concat() }
+        };
+
+        // Without ORDER BY the scan resumes past the skipped keys, across
+        // scan pages, and never decodes them
+        let before = db.columns_decoded();
+        assert_eq!(rows(&db, "SELECT id FROM t OFFSET 597").await, vec![vec!["597"], vec!["598"], vec!["599"]]);
+        assert_eq!(db.columns_decoded() - before, 3);
+        assert_eq!(rows(&db, "SELECT id FROM t LIMIT 2 OFFSET 300").await, vec![vec!["300"], vec!["301"]]);
+        assert!(explain("SELECT id FROM t LIMIT 2 OFFSET 300").await.iter().any(|line| line.contains("TableScan t offset: 300")));
+
+        // With ORDER BY or a filter the skipped rows are discarded after
+        let sorted = "SELECT id FROM t ORDER BY v LIMIT 2 OFFSET 1";
+        assert_eq!(rows(&db, sorted).await, vec![vec!["598"], vec!["597"]]);
+        let lines = explain(sorted).await;
+        assert!(lines.iter().any(|line| line.trim_start().starts_with("Offset 1")), "{:?}", lines);
+        assert!(!lines.iter().any(|line| line.contains("offset:")), "{:?}", lines);
+        assert_eq!(rows(&db, "SELECT id FROM t WHERE v < 10 OFFSET 8").await, vec![vec!["598"], vec!["599"]]);
+
+        // Past the end of the result
+        assert!(rows(&db, "SELECT id FROM t OFFSET 600").await.is_empty());
+        assert!(rows(&db, "SELECT id FROM t ORDER BY v OFFSET 1000").await.is_empty());
+        assert!(rows(&db, "SELECT id FROM t WHERE v < 10 OFFSET 10").await.is_empty());
+    }
+
+    async fn session_rows(executor: &QueryExecutor, session: SessionId, sql: &str) -> Vec<Vec<String>> {
+        executor.execute_sql_in(session, sql).await.unwrap_or_else(|e| panic!("{}: {}", sql, e)).text_rows()
+    }
+
+    #[tokio::test]
+    async fn test_transaction_commits_atomically() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+        let (a, b) = (SessionId(1), SessionId(2));
+
+        db.execute_sql("CREATE TABLE accounts (id INT PRIMARY KEY, owner TEXT UNIQUE, balance INT)").await.unwrap();
+        db.execute_sql("INSERT INTO accounts VALUES (1, 'ann', 100), (2, 'bob', 50)").await.unwrap();
+
+        db.execute_sql_in(a, "BEGIN").await.unwrap();
+        db.execute_sql_in(a, "UPDATE accounts SET balance = balance - 30 WHERE id = 1").await.unwrap();
+        db.execute_sql_in(a, "UPDATE accounts SET balance = balance + 30 WHERE id = 2").await.unwrap();
+        db.execute_sql_in(a, "INSERT INTO accounts VALUES (3, 'cat', 0)").await.unwrap();
+        db.execute_sql_in(a, "DELETE FROM accounts WHERE owner = 'bob'").await.unwrap();
+
+        // The transaction reads its own writes, including counts and unique values
+        assert_eq!(session_rows(&db, a, "SELECT id, balance FROM accounts").await, vec![vec!["1", "70"], vec!["3", "0"]]);
+        assert_eq!(session_rows(&db, a, "SELECT COUNT(*) FROM accounts").await, vec![vec!["2"]]);
+        assert_eq!(
+            session_rows(&db, a, "SELECT id, balance FROM accounts WHERE id IN (3, 2, 1)").await,
+            vec![vec!["1", "70"], vec!["3", "0"]]
+        );
+        assert!(matches!(
+            db.execute_sql_in(a, "INSERT INTO accounts VALUES (4, 'cat', 0)").await,
+            Err(QueryError::ConstraintViolation { .. })
+        ));
+        db.execute_sql_in(a, "ROLLBACK").await.unwrap();
+        db.execute_sql_in(a, "BEGIN").await.unwrap();
+        db.execute_sql_in(a, "UPDATE accounts SET balance = balance - 30 WHERE id = 1").await.unwrap();
+        db.execute_sql_in(a, "INSERT INTO accounts VALUES (3, 'cat', 0)").await.unwrap();
+        db.execute_sql_in(a, "DELETE FROM accounts WHERE owner = 'bob'").await.unwrap();
+
+        // Nobody else sees any of it before COMMIT
+        let before = vec![vec!["1", "100"], vec!["2", "50"]];
+        assert_eq!(rows(&db, "SELECT id, balance FROM accounts").await, before);
+        assert_eq!(session_rows(&db, b, "SELECT id, balance FROM accounts").await, before);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM accounts").await, vec![vec!["2"]]);
+
+        db.execute_sql_in(a, "COMMIT").await.unwrap();
+        assert_eq!(db.session_transaction(a), None);
+        assert_eq!(rows(&db, "SELECT id, balance FROM accounts").await, vec![vec!["1", "70"], vec!["3", "0"]]);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM accounts").await, vec![vec!["2"]]);
+        assert_eq!(rows(&db, "SELECT id FROM accounts WHERE owner = 'cat'").await, vec![vec!["3"]]);
+        assert!(rows(&db, "SELECT id FROM accounts WHERE owner = 'bob'").await.is_empty());
+
+        // A unique value taken by another session since is checked again at COMMIT
+        db.execute_sql_in(a, "BEGIN").await.unwrap();
+        db.execute_sql_in(a, "INSERT INTO accounts VALUES (4, 'dan', 0)").await.unwrap();
+        db.execute_sql("INSERT INTO accounts VALUES (5, 'dan', 0)").await.unwrap();
+        assert!(matches!(db.execute_sql_in(a, "COMMIT").await, Err(QueryError::ConstraintViolation { .. })));
+        assert_eq!(rows(&db, "SELECT id FROM accounts WHERE owner = 'dan'").await, vec![vec!["5"]]);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM accounts").await, vec![vec!["3"]]);
+    }
+
+    #[tokio::test]
+    async fn test_transaction_rollback_and_poisoning() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+        let session = SessionId(7);
+
+        db.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, v INT)").await.unwrap();
+        db.execute_sql("INSERT INTO t VALUES (1, 10)").await.unwrap();
+
+        db.execute_sql_in(session, "BEGIN TRANSACTION").await.unwrap();
+        db.execute_sql_in(session, "INSERT INTO t VALUES (2, 20)").await.unwrap();
+        db.execute_sql_in(session, "DELETE FROM t WHERE id = 1").await.unwrap();
+        assert_eq!(session_rows(&db, session, "SELECT * FROM t").await, vec![vec!["2", "20"]]);
+        db.execute_sql_in(session, "ROLLBACK").await.unwrap();
+        assert_eq!(rows(&db, "SELECT * FROM t").await, vec![vec!["1", "10"]]);
+        assert!(db.execute_sql_in(session, "ROLLBACK").await.is_err());
+
+        // After an error only ROLLBACK is accepted, and COMMIT rolls back
+        db.execute_sql_in(session, "BEGIN").await.unwrap();
+        db.execute_sql_in(session, "INSERT INTO t VALUES (3, 30)").await.unwrap();
+        assert!(db.execute_sql_in(session, "INSERT INTO t VALUES (1, 0)").await.is_err());
+        assert!(matches!(
+            db.execute_sql_in(session, "SELECT * FROM t").await,
+            Err(QueryError::Transaction(TransactionError::Aborted))
+        ));
+        assert!(db.execute_sql_in(session, "SELEC").await.is_err());
+        assert!(db.execute_sql_in(session, "COMMIT").await.is_err());
+        assert_eq!(rows(&db, "SELECT * FROM t").await, vec![vec!["1", "10"]]);
+
+        // Schema changes are refused inside a transaction
+        db.execute_sql_in(session, "BEGIN").await.unwrap();
+        assert!(db.execute_sql_in(session, "CREATE TABLE u (id INT PRIMARY KEY)").await.is_err());
+        assert!(db.execute_sql_in(session, "BEGIN").await.is_err());
+        db.execute_sql_in(session, "ROLLBACK").await.unwrap();
+
+        // Without poisoning the transaction carries on past the failed statement
+        let db = db.with_transaction_poisoning(false);
+        db.execute_sql_in(session, "BEGIN").await.unwrap();
+        db.execute_sql_in(session, "INSERT INTO t VALUES (3, 30)").await.unwrap();
+        assert!(db.execute_sql_in(session, "INSERT INTO t VALUES (1, 0)").await.is_err());
+        db.execute_sql_in(session, "INSERT INTO t VALUES (4, 40)").await.unwrap();
+        db.execute_sql_in(session, "COMMIT").await.unwrap();
+        assert_eq!(rows(&db, "SELECT * FROM t").await, vec![vec!["1", "10"], vec!["3", "30"], vec!["4", "40"]]);
+
+        // A session that goes away rolls back
+        db.execute_sql_in(session, "BEGIN").await.unwrap();
+        db.execute_sql_in(session, "DELETE FROM t").await.unwrap();
+        db.end_session(session).await.unwrap();
+        assert_eq!(db.session_transaction(session), None);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM t").await, vec![vec!["3"]]);
+
+        // and so does every session at shutdown
+        for id in [8, 9] {
+            db.execute_sql_in(SessionId(id), "BEGIN").await.unwrap();
+            db.execute_sql_in(SessionId(id), &format!("INSERT INTO t VALUES ({}, 0)", id)).await.unwrap();
+        }
+        assert_eq!(db.end_all_sessions().await.unwrap(), 2);
+        assert_eq!(db.session_transaction(SessionId(8)), None);
+        assert_eq!(rows(&db, "SELECT COUNT(*) FROM t").await, vec![vec!["3"]]);
+    }
+
+    #[tokio::test]
+    async fn test_transaction_isolation_levels() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = executor(&temp_dir).await;
+        let (a, b) = (SessionId(1), SessionId(2));
+
+        db.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, v INT)").await.unwrap();
+        db.execute_sql("INSERT INTO t VALUES (1, 10), (2, 20)").await.unwrap();
+
+        for (sql, level) in [
+            ("BEGIN", IsolationLevel::ReadCommitted),
+            ("BEGIN ISOLATION LEVEL SERIALIZABLE", IsolationLevel::Serializable),
+            ("START TRANSACTION ISOLATION LEVEL REPEATABLE READ", IsolationLevel::RepeatableRead),
+        ] {
+            db.execute_sql_in(a, sql).await.unwrap();
+            let id = db.session_transaction(a).unwrap();
+            assert_eq!(db.transactions().get_transaction(&id).unwrap().isolation_level, level);
+            db.execute_sql_in(a, "ROLLBACK").await.unwrap();
+        }
+
+        // READ COMMITTED sees each commit as it happens
+        db.execute_sql_in(a, "BEGIN").await.unwrap();
+        db.execute_sql("UPDATE t SET v = 11 WHERE id = 1").await.unwrap();
+        assert_eq!(session_rows(&db, a, "SELECT v FROM t WHERE id = 1").await, vec![vec!["11"]]);
+        db.execute_sql_in(a, "COMMIT").await.unwrap();
+
+        // REPEATABLE READ keeps reading the data as of BEGIN
+        db.execute_sql_in(a, "BEGIN ISOLATION LEVEL REPEATABLE READ").await.unwrap();
+        db.execute_sql("UPDATE t SET v = 12 WHERE id = 1").await.unwrap();
+        db.execute_sql("INSERT INTO t VALUES (3, 30)").await.unwrap();
+        db.execute_sql("DELETE FROM t WHERE id = 2").await.unwrap();
+        assert_eq!(session_rows(&db, a, "SELECT * FROM t").await, vec![vec!["1", "11"], vec!["2", "20"]]);
+        assert_eq!(session_rows(&db, a, "SELECT COUNT(*) FROM t WHERE v > 0").await, vec![vec!["2"]]);
+
+        // and the first of two transactions writing the same row to commit wins
+        db.execute_sql_in(b, "BEGIN ISOLATION LEVEL SERIALIZABLE").await.unwrap();
+        db.execute_sql_in(b, "UPDATE t SET v = 13 WHERE id = 3").await.unwrap();
+        db.execute_sql_in(b, "COMMIT").await.unwrap();
+        db.execute_sql_in(a, "UPDATE t SET v = 0 WHERE id = 1").await.unwrap();
+        assert!(matches!(
+            db.execute_sql_in(a, "COMMIT").await,
+            Err(QueryError::Transaction(TransactionError::Conflict))
+        ));
+        assert_eq!(rows(&db, "SELECT * FROM t").await, vec![vec!["1", "12"], vec!["3", "13"]]);
+        assert!(!db.transactions().has_snapshots());
+    }
+}
@@ -0,0 +1,575 @@
+use crate::{
+    error::{Result, QueryError},
+    ast::DataType,
+    value::{self, Value},
+};
+use chrono::{Datelike, Timelike, Utc};
+use std::cmp::Ordering;
+use std::fmt::Write;
+
+/// Argument types accepted by a scalar function parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArgType {
+    Text,
+    /// TEXT or BLOB
+    Bytes,
+    Integer,
+    /// INTEGER or FLOAT
+    Numeric,
+    /// TIMESTAMP, or TEXT holding an ISO-8601 timestamp
+    Timestamp,
+    Any,
+}
+
+impl ArgType {
+    fn accepts(self, data_type: DataType) -> bool {
+        match self {
+            ArgType::Text => data_type == DataType::Text,
+            ArgType::Bytes => matches!(data_type, DataType::Text | DataType::Blob),
+            ArgType::Integer => data_type == DataType::Integer,
+            ArgType::Numeric => matches!(data_type, DataType::Integer | DataType::Float),
+            ArgType::Timestamp => matches!(data_type, DataType::Timestamp | DataType::Text),
+            ArgType::Any => true,
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            ArgType::Text => "TEXT",
+            ArgType::Bytes => "TEXT or BLOB",
+            ArgType::Integer => "INTEGER",
+            ArgType::Numeric => "a number",
+            ArgType::Timestamp => "TIMESTAMP",
+            ArgType::Any => "any value",
+        }
+    }
+}
+
+/// Result type of a scalar function
+#[derive(Debug, Clone, Copy)]
+enum ReturnType {
+    Fixed(DataType),
+    /// Same type as the first argument whose type is known
+    FirstArg,
+}
+
+/// A built-in scalar function
+pub struct ScalarFunction {
+    pub name: &'static str,
+    params: &'static [ArgType],
+    /// Number of leading `params` that must be supplied
+    required: usize,
+    /// The last parameter may repeat any number of times
+    variadic: bool,
+    /// Return NULL without calling `eval` when any argument is NULL
+    strict: bool,
+    /// May return different results for the same arguments
+    pub volatile: bool,
+    returns: ReturnType,
+    eval: fn(Vec<Value>) -> Result<Value>,
+}
+
+static FUNCTIONS: &[ScalarFunction] = &[
+    ScalarFunction {
+        name: "upper",
+        params: &[ArgType::Text],
+        required: 1,
+        variadic: false,
+        strict: true,
+        volatile: false,
+        returns: ReturnType::Fixed(DataType::Text),
+        eval: |args| Ok(Value::Text(text(&args[0]).to_uppercase())),
+    },
+    ScalarFunction {
+        name: "lower",
+        params: &[ArgType::Text],
+        required: 1,
+        variadic: false,
+        strict: true,
+        volatile: false,
+        returns: ReturnType::Fixed(DataType::Text),
+        eval: |args| Ok(Value::Text(text(&args[0]).to_lowercase())),
+    },
+    ScalarFunction {
+        name: "length",
+        params: &[ArgType::Bytes],
+        required: 1,
+        variadic: false,
+        strict: true,
+        volatile: false,
+        returns: ReturnType::Fixed(DataType::Integer),
+        eval: length,
+    },
+    ScalarFunction {
+        name: "substr",
+        params: &[ArgType::Text, ArgType::Integer, ArgType::Integer],
+        required: 2,
+        variadic: false,
+        strict: true,
+        volatile: false,
+        returns: ReturnType::Fixed(DataType::Text),
+        eval: substr,
+    },
+    ScalarFunction {
+        name: "trim",
+        params: &[ArgType::Text, ArgType::Text],
+        required: 1,
+        variadic: false,
+        strict: true,
+        volatile: false,
+        returns: ReturnType::Fixed(DataType::Text),
+        eval: trim,
+    },
+    ScalarFunction {
+        name: "replace",
+        params: &[ArgType::Text, ArgType::Text, ArgType::Text],
+        required: 3,
+        variadic: false,
+        strict: true,
+        volatile: false,
+        returns: ReturnType::Fixed(DataType::Text),
+        eval: replace,
+    },
+    ScalarFunction {
+        name: "abs",
+        params: &[ArgType::Numeric],
+        required: 1,
+        variadic: false,
+        strict: true,
+        volatile: false,
+        returns: ReturnType::FirstArg,
+        eval: abs,
+    },
+    ScalarFunction {
+        name: "round",
+        params: &[ArgType::Numeric, ArgType::Integer],
+        required: 1,
+        variadic: false,
+        strict: true,
+        volatile: false,
+        returns: ReturnType::FirstArg,
+        eval: round,
+    },
+    ScalarFunction {
+        name: "coalesce",
+        params: &[ArgType::Any],
+        required: 1,
+        variadic: true,
+        strict: false,
+        volatile: false,
+        returns: ReturnType::FirstArg,
+        eval: |args| Ok(args.into_iter().find(|v| !v.is_null()).unwrap_or(Value::Null)),
+    },
+    ScalarFunction {
+        name: "nullif",
+        params: &[ArgType::Any, ArgType::Any],
+        required: 2,
+        variadic: false,
+        strict: false,
+        volatile: false,
+        returns: ReturnType::FirstArg,
+        eval: nullif,
+    },
+    ScalarFunction {
+        name: "now",
+        params: &[],
+        required: 0,
+        variadic: false,
+        strict: false,
+        volatile: true,
+        returns: ReturnType::Fixed(DataType::Timestamp),
+        eval: |_| Ok(Value::Timestamp(Utc::now().timestamp_micros())),
+    },
+    ScalarFunction {
+        name: "extract",
+        params: &[ArgType::Text, ArgType::Timestamp],
+        required: 2,
+        variadic: false,
+        strict: true,
+        volatile: false,
+        returns: ReturnType::Fixed(DataType::Integer),
+        eval: extract,
+    },
+    ScalarFunction {
+        name: "strftime",
+        params: &[ArgType::Text, ArgType::Timestamp],
+        required: 2,
+        variadic: false,
+        strict: true,
+        volatile: false,
+        returns: ReturnType::Fixed(DataType::Text),
+        eval: strftime,
+    },
+];
+
+/// Find a function by name, suggesting near misses if there is none
+pub fn lookup(name: &str) -> Result<&'static ScalarFunction> {
+    let name = name.to_ascii_lowercase();
+    if let Some(function) = FUNCTIONS.iter().find(|f| f.name == name) {
+        return Ok(function);
+    }
+
+    let suggestions: Vec<&str> = FUNCTIONS.iter()
+        .map(|f| f.name)
+        .filter(|candidate| edit_distance(&name, candidate) <= 2 || candidate.starts_with(&name))
+        .collect();
+    let hint = if suggestions.is_empty() {
+        String::new()
+    } else {
+        format!(" (did you mean {}?)", suggestions.join(", "))
+    };
+    Err(QueryError::Plan(format!("Unknown function {}{}", name, hint)))
+}
+
+impl ScalarFunction {
+    /// Check the argument count, and the types of arguments whose type is
+    /// known before execution (None marks an unknown type)
+    pub fn check_args(&self, arg_types: &[Option<DataType>]) -> Result<()> {
+        let max = if self.variadic { usize::MAX } else { self.params.len() };
+        if arg_types.len() < self.required || arg_types.len() > max {
+            let expected = match (self.required, self.variadic) {
+                (n, true) => format!("at least {}", n),
+                (n, false) if n == self.params.len() => n.to_string(),
+                (n, false) => format!("{} to {}", n, self.params.len()),
+            };
+            return Err(QueryError::Plan(format!(
+                "function {} expects {} arguments, got {}", self.name, expected, arg_types.len()
+            )));
+        }
+
+        for (i, data_type) in arg_types.iter().enumerate() {
+            if let Some(data_type) = data_type {
+                self.check_arg(i, *data_type).map_err(QueryError::Plan)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Type of the result given the argument types, if it can be known
+    pub fn return_type(&self, arg_types: &[Option<DataType>]) -> Option<DataType> {
+        match self.returns {
+            ReturnType::Fixed(data_type) => Some(data_type),
+            ReturnType::FirstArg => arg_types.iter().flatten().next().copied(),
+        }
+    }
+
+    pub fn call(&self, args: Vec<Value>) -> Result<Value> {
+        for (i, arg) in args.iter().enumerate() {
+            if let Some(data_type) = arg.data_type() {
+                self.check_arg(i, data_type).map_err(QueryError::Execution)?;
+            }
+        }
+        if self.strict && args.iter().any(Value::is_null) {
+            return Ok(Value::Null);
+        }
+        (self.eval)(args)
+    }
+
+    fn check_arg(&self, position: usize, data_type: DataType) -> std::result::Result<(), String> {
+        let param = self.params[position.min(self.params.len() - 1)];
+        if param.accepts(data_type) {
+            Ok(())
+        } else {
+            Err(format!(
+                "argument {} of {} must be {}, got {}", position + 1, self.name, param.describe(), data_type
+            ))
+        }
+    }
+}
+
+// Argument accessors. Types were checked before `eval` runs.
+
+fn text(value: &Value) -> &str {
+    match value {
+        Value::Text(s) => s,
+        other => unreachable!("expected TEXT, got {:?}", other),
+    }
+}
+
+fn integer(value: &Value) -> i64 {
+    match value {
+        Value::Integer(i) => *i,
+        other => unreachable!("expected INTEGER, got {:?}", other),
+    }
+}
+
+fn timestamp(value: &Value) -> Result<i64> {
+    match value {
+        Value::Timestamp(micros) => Ok(*micros),
+        Value::Text(s) => value::parse_timestamp(s),
+        other => unreachable!("expected TIMESTAMP, got {:?}", other),
+    }
+}
+
+fn length(args: Vec<Value>) -> Result<Value> {
+    let length = match &args[0] {
+        Value::Blob(bytes) => bytes.len(),
+        value => text(value).chars().count(),
+    };
+    Ok(Value::Integer(length as i64))
+}
+
+/// `SUBSTR(s, start[, count])` with 1-based positions. A negative start
+/// counts from the end of the string; bounds past either end are clipped.
+fn substr(args: Vec<Value>) -> Result<Value> {
+    let chars: Vec<char> = text(&args[0]).chars().collect();
+    let len = chars.len() as i64;
+    let start = integer(&args[1]);
+    let begin = match start {
+        s if s > 0 => s - 1,
+        0 => -1,
+        s => len + s,
+    };
+    let end = match args.get(2).map(integer) {
+        None => len,
+        Some(count) if count < 0 => {
+            return Err(QueryError::Execution("negative substring length not allowed".to_string()))
+        }
+        Some(count) => begin.saturating_add(count),
+    };
+
+    let (begin, end) = (begin.clamp(0, len) as usize, end.clamp(0, len) as usize);
+    Ok(Value::Text(if begin < end { chars[begin..end].iter().collect() } else { String::new() }))
+}
+
+/// `TRIM(s[, characters])` strips spaces, or any of `characters`, from both ends
+fn trim(args: Vec<Value>) -> Result<Value> {
+    let s = text(&args[0]);
+    let trimmed = match args.get(1) {
+        Some(set) => {
+            let set = text(set);
+            s.trim_matches(|c| set.contains(c))
+        }
+        None => s.trim_matches(' '),
+    };
+    Ok(Value::Text(trimmed.to_string()))
+}
+
+fn replace(args: Vec<Value>) -> Result<Value> {
+    let (s, from, to) = (text(&args[0]), text(&args[1]), text(&args[2]));
+    if from.is_empty() {
+        return Ok(Value::Text(s.to_string()));
+    }
+    Ok(Value::Text(s.replace(from, to)))
+}
+
+fn abs(args: Vec<Value>) -> Result<Value> {
+    match &args[0] {
+        Value::Integer(i) => i.checked_abs()
+            .map(Value::Integer)
+            .ok_or_else(|| QueryError::Execution("integer overflow".to_string())),
+        Value::Float(f) => Ok(Value::Float(f.abs())),
+        other => unreachable!("expected a number, got {:?}", other),
+    }
+}
+
+/// `ROUND(x[, digits])`, rounding halves away from zero. Negative `digits`
+/// round to tens, hundreds, and so on. Integers stay integers.
+fn round(args: Vec<Value>) -> Result<Value> {
+    let digits = args.get(1).map_or(0, integer);
+    match &args[0] {
+        Value::Float(f) => {
+            let scale = 10f64.powi(digits.clamp(-400, 400) as i32);
+            let rounded = (f * scale).round() / scale;
+            Ok(Value::Float(if rounded.is_finite() { rounded } else { *f }))
+        }
+        Value::Integer(i) if digits >= 0 => Ok(Value::Integer(*i)),
+        Value::Integer(_) if digits < -18 => Ok(Value::Integer(0)),
+        Value::Integer(i) => {
+            let factor = 10i64.pow((-digits) as u32);
+            let (quotient, remainder) = (i / factor, i % factor);
+            let quotient = if remainder.abs() * 2 >= factor { quotient + i.signum() } else { quotient };
+            quotient.checked_mul(factor)
+                .map(Value::Integer)
+                .ok_or_else(|| QueryError::Execution("integer overflow".to_string()))
+        }
+        other => unreachable!("expected a number, got {:?}", other),
+    }
+}
+
+/// `NULLIF(a, b)` is NULL when `a = b`, otherwise `a`
+fn nullif(mut args: Vec<Value>) -> Result<Value> {
+    let b = args.pop().expect("two arguments");
+    let a = args.pop().expect("two arguments");
+    if a.sql_cmp(&b)? == Some(Ordering::Equal) {
+        Ok(Value::Null)
+    } else {
+        Ok(a)
+    }
+}
+
+/// `EXTRACT(field FROM ts)`, in UTC. `second` is truncated to whole seconds,
+/// `dow` counts from Sunday = 0 and `epoch` is whole seconds since 1970.
+fn extract(args: Vec<Value>) -> Result<Value> {
+    let micros = timestamp(&args[1])?;
+    let Some(dt) = value::to_datetime(micros) else {
+        return Err(QueryError::Execution(format!("timestamp {} is out of range", micros)));
+    };
+    let field = text(&args[0]).to_ascii_lowercase();
+    let part = match field.as_str() {
+        "year" => dt.year() as i64,
+        "month" => dt.month() as i64,
+        "day" => dt.day() as i64,
+        "hour" => dt.hour() as i64,
+        "minute" => dt.minute() as i64,
+        "second" => dt.second() as i64,
+        "dow" => dt.weekday().num_days_from_sunday() as i64,
+        "doy" => dt.ordinal() as i64,
+        "epoch" => micros.div_euclid(1_000_000),
+        _ => return Err(QueryError::Execution(format!("unknown timestamp field {}", field))),
+    };
+    Ok(Value::Integer(part))
+}
+
+/// `STRFTIME(format, ts)` using chrono's strftime specifiers, in UTC
+fn strftime(args: Vec<Value>) -> Result<Value> {
+    let micros = timestamp(&args[1])?;
+    let Some(dt) = value::to_datetime(micros) else {
+        return Err(QueryError::Execution(format!("timestamp {} is out of range", micros)));
+    };
+    let mut out = String::new();
+    write!(out, "{}", dt.format(text(&args[0])))
+        .map_err(|_| QueryError::Execution(format!("invalid strftime format '{}'", text(&args[0]))))?;
+    Ok(Value::Text(out))
+}
+
+/// Levenshtein distance, for suggesting function names
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, args: Vec<Value>) -> Result<Value> {
+        lookup(name).unwrap().call(args)
+    }
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+
+    #[test]
+    fn test_string_functions() {
+        assert_eq!(call("upper", vec![text("MiXed ß")]).unwrap(), text("MIXED SS"));
+        assert_eq!(call("lower", vec![text("ÀBC")]).unwrap(), text("àbc"));
+        assert_eq!(call("length", vec![text("héllo")]).unwrap(), Value::Integer(5));
+        assert_eq!(call("length", vec![Value::Blob(vec![0, 1, 2])]).unwrap(), Value::Integer(3));
+        assert_eq!(call("trim", vec![text("  padded \t ")]).unwrap(), text("padded \t"));
+        assert_eq!(call("trim", vec![text("xxhixyx"), text("xy")]).unwrap(), text("hi"));
+        assert_eq!(call("replace", vec![text("a-b-c"), text("-"), text("+")]).unwrap(), text("a+b+c"));
+        assert_eq!(call("replace", vec![text("abc"), text(""), text("x")]).unwrap(), text("abc"));
+
+        let substr = |start: i64, count: Option<i64>| {
+            let mut args = vec![text("hello"), Value::Integer(start)];
+            args.extend(count.map(Value::Integer));
+            call("substr", args)
+        };
+        assert_eq!(substr(2, Some(3)).unwrap(), text("ell"));
+        assert_eq!(substr(2, None).unwrap(), text("ello"));
+        assert_eq!(substr(-3, None).unwrap(), text("llo"));
+        assert_eq!(substr(-3, Some(2)).unwrap(), text("ll"));
+        assert_eq!(substr(0, Some(2)).unwrap(), text("h"));
+        assert_eq!(substr(-10, Some(7)).unwrap(), text("he"));
+        assert_eq!(substr(4, Some(100)).unwrap(), text("lo"));
+        assert_eq!(substr(10, Some(2)).unwrap(), text(""));
+        assert_eq!(substr(2, Some(i64::MAX)).unwrap(), text("ello"));
+        assert!(substr(1, Some(-1)).is_err());
+    }
+
+    #[test]
+    fn test_numeric_functions() {
+        assert_eq!(call("abs", vec![Value::Integer(-7)]).unwrap(), Value::Integer(7));
+        assert_eq!(call("abs", vec![Value::Float(-1.5)]).unwrap(), Value::Float(1.5));
+        assert!(call("abs", vec![Value::Integer(i64::MIN)]).is_err());
+
+        let round = |x: Value, digits: Option<i64>| {
+            let mut args = vec![x];
+            args.extend(digits.map(Value::Integer));
+            call("round", args).unwrap()
+        };
+        assert_eq!(round(Value::Float(2.5), None), Value::Float(3.0));
+        assert_eq!(round(Value::Float(-2.5), None), Value::Float(-3.0));
+        assert_eq!(round(Value::Float(-0.5), None), Value::Float(-1.0));
+        assert_eq!(round(Value::Float(1.2345), Some(2)), Value::Float(1.23));
+        assert_eq!(round(Value::Float(1234.5), Some(-2)), Value::Float(1200.0));
+        assert_eq!(round(Value::Integer(-15), Some(-1)), Value::Integer(-20));
+        assert_eq!(round(Value::Integer(14), Some(-1)), Value::Integer(10));
+        assert_eq!(round(Value::Integer(7), Some(3)), Value::Integer(7));
+        assert_eq!(round(Value::Integer(5), Some(-30)), Value::Integer(0));
+    }
+
+    #[test]
+    fn test_null_handling() {
+        assert_eq!(call("upper", vec![Value::Null]).unwrap(), Value::Null);
+        assert_eq!(call("substr", vec![text("abc"), Value::Null]).unwrap(), Value::Null);
+        assert_eq!(call("round", vec![Value::Float(1.5), Value::Null]).unwrap(), Value::Null);
+
+        // COALESCE and NULLIF look at NULL arguments instead of propagating them
+        assert_eq!(call("coalesce", vec![Value::Null, Value::Integer(1), text("x")]).unwrap(), Value::Integer(1));
+        assert_eq!(call("coalesce", vec![Value::Null, text("x"), Value::Integer(1)]).unwrap(), text("x"));
+        assert_eq!(call("coalesce", vec![Value::Null, Value::Null]).unwrap(), Value::Null);
+        assert_eq!(call("nullif", vec![Value::Integer(1), Value::Float(1.0)]).unwrap(), Value::Null);
+        assert_eq!(call("nullif", vec![Value::Integer(1), Value::Integer(2)]).unwrap(), Value::Integer(1));
+        assert_eq!(call("nullif", vec![Value::Integer(1), Value::Null]).unwrap(), Value::Integer(1));
+        assert_eq!(call("nullif", vec![Value::Null, Value::Integer(1)]).unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn test_timestamp_functions() {
+        // 2024-02-29 is a Thursday
+        let ts = Value::Timestamp(value::parse_timestamp("2024-02-29T13:45:30.5Z").unwrap());
+        let extract = |field: &str| call("extract", vec![text(field), ts.clone()]);
+        assert_eq!(extract("YEAR").unwrap(), Value::Integer(2024));
+        assert_eq!(extract("month").unwrap(), Value::Integer(2));
+        assert_eq!(extract("second").unwrap(), Value::Integer(30));
+        assert_eq!(extract("dow").unwrap(), Value::Integer(4));
+        assert_eq!(extract("doy").unwrap(), Value::Integer(60));
+        assert_eq!(extract("epoch").unwrap(), Value::Integer(1_709_214_330));
+        assert!(extract("fortnight").is_err());
+
+        // Text arguments are parsed, with any offset applied
+        assert_eq!(
+            call("extract", vec![text("hour"), text("2024-02-29T08:45:30-05:00")]).unwrap(),
+            Value::Integer(13)
+        );
+        assert_eq!(
+            call("strftime", vec![text("%Y/%m/%d %H:%M"), ts.clone()]).unwrap(),
+            text("2024/02/29 13:45")
+        );
+        assert!(call("strftime", vec![text("%Q"), ts]).is_err());
+        assert!(matches!(call("now", vec![]).unwrap(), Value::Timestamp(_)));
+    }
+
+    #[test]
+    fn test_lookup_and_argument_checks() {
+        match lookup("uper") {
+            Err(QueryError::Plan(msg)) => assert_eq!(msg, "Unknown function uper (did you mean upper?)"),
+            other => panic!("expected unknown function error, got {:?}", other.map(|f| f.name)),
+        }
+        match lookup("frobnicate") {
+            Err(QueryError::Plan(msg)) => assert_eq!(msg, "Unknown function frobnicate"),
+            other => panic!("expected unknown function error, got {:?}", other.map(|f| f.name)),
+        }
+        assert_eq!(lookup("COALESCE").unwrap().name, "coalesce");
+
+        let substr = lookup("substr").unwrap();
+        assert!(substr.check_args(&[Some(DataType::Text), None]).is_ok());
+        assert!(substr.check_args(&[Some(DataType::Text)]).is_err());
+        assert!(substr.check_args(&[Some(DataType::Integer), Some(DataType::Integer)]).is_err());
+        assert!(lookup("coalesce").unwrap().check_args(&[]).is_err());
+        assert!(lookup("abs").unwrap().check_args(&[Some(DataType::Text)]).is_err());
+        assert!(call("abs", vec![text("x")]).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+//! GROUP BY for more groups than fit in memory.
+//!
+//! Groups are aggregated in a hash table until it outgrows the memory budget.
+//! The table is then spilled: each group's key and partial aggregate state go
+//! to one of several partition runs chosen by a hash of the key, and the
+//! table starts over empty. Once the input is exhausted, each partition is
+//! aggregated on its own by merging the partial states of equal keys, and is
+//! split again with a different hash if it is still too large.
+
+use crate::{
+    aggregate::Accumulator,
+    ast::{AggregateFunc, Expr},
+    encoding,
+    error::Result,
+    eval,
+    executor::{OperatorStats, Row, RowStream},
+    planner::AggregateExpr,
+    spill::{self, MemoryBudget, RunWriter, SpillConfig, SpillRun},
+};
+use futures::stream::{StreamExt, TryStreamExt};
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+// Partitions a spilled table is split into
+const PARTITIONS: usize = 16;
+// Partitions split no deeper than this; a partition that still does not fit
+// is aggregated in memory regardless
+const MAX_DEPTH: u32 = 4;
+
+/// Aggregate `input`, whose rows have `columns`, emitting one row per group:
+/// its `group_by` values followed by its `aggregates`. Groups held in memory
+/// also count towards `query`, the memory of the whole query.
+pub(crate) fn aggregate_stream(
+    mut input: RowStream,
+    columns: Vec<String>,
+    group_by: Vec<(Expr, String)>,
+    aggregates: Vec<AggregateExpr>,
+    config: SpillConfig,
+    query: Option<Arc<MemoryBudget>>,
+    stats: Arc<OperatorStats>,
+) -> RowStream {
+    let width = group_by.len();
+    let funcs: Arc<[AggregateFunc]> = aggregates.iter().map(|a| a.func).collect();
+
+    let rows = async_stream::try_stream! {
+        let budget = Arc::new(MemoryBudget::new(config.memory_budget).within(query));
+        let mut table = GroupTable::new(funcs.clone());
+        let mut partitions: Option<Partitions> = None;
+
+        while let Some(row) = input.try_next().await? {
+            let key = group_by.iter()
+                .map(|(expr, _)| eval::eval(expr, &columns, &row))
+                .collect::<Result<Row>>()?;
+            for (accumulator, aggregate) in table.group(key, &budget).iter_mut().zip(&aggregates) {
+                let value = aggregate.arg.as_ref().map(|arg| eval::eval(arg, &columns, &row)).transpose()?;
+                accumulator.update(value)?;
+            }
+            if budget.is_exceeded() {
+                stats.record_peak_memory(budget.peak());
+                partitions.get_or_insert_with(|| Partitions::new(0)).spill(&mut table, &config, &budget).await?;
+                budget.check()?;
+            }
+        }
+
+        let mut pending = Vec::new();
+        match partitions {
+            None => {
+                // An ungrouped aggregate over no rows still produces one row
+                if width == 0 && table.is_empty() {
+                    table.group(Vec::new(), &budget);
+                }
+                stats.record_peak_memory(budget.peak());
+                for row in table.drain(&budget) {
+                    yield row;
+                }
+            }
+            Some(mut partitions) => {
+                partitions.spill(&mut table, &config, &budget).await?;
+                pending = partitions.finish(&stats).await?;
+            }
+        }
+
+        // Each partition holds partial states: the key, then every state
+        while let Some((run, depth)) = pending.pop() {
+            let mut reader = run.open(budget.clone()).await?;
+            let mut partitions: Option<Partitions> = None;
+            while let Some(mut row) = reader.next().await? {
+                let mut states = row.split_off(width).into_iter();
+                for (accumulator, func) in table.group(row, &budget).iter_mut().zip(funcs.iter()) {
+                    accumulator.merge(Accumulator::from_state(*func, &mut states)?)?;
+                }
+                if budget.is_exceeded() {
+                    if depth < MAX_DEPTH {
+                        stats.record_peak_memory(budget.peak());
+                        partitions.get_or_insert_with(|| Partitions::new(depth + 1)).spill(&mut table, &config, &budget).await?;
+                    }
+                    budget.check()?;
+                }
+            }
+            drop(reader);
+
+            match partitions {
+                None => {
+                    stats.record_peak_memory(budget.peak());
+                    for row in table.drain(&budget) {
+                        yield row;
+                    }
+                }
+                Some(mut partitions) => {
+                    partitions.spill(&mut table, &config, &budget).await?;
+                    pending.extend(partitions.finish(&stats).await?);
+                }
+            }
+        }
+    };
+    rows.boxed()
+}
+
+/// Groups being aggregated in memory, in the order they were first seen
+struct GroupTable {
+    funcs: Arc<[AggregateFunc]>,
+    slots: HashMap<Vec<u8>, usize>,
+    groups: Vec<Group>,
+    held: usize,
+}
+
+struct Group {
+    encoded: Vec<u8>,
+    key: Row,
+    accumulators: Vec<Accumulator>,
+}
+
+impl GroupTable {
+    fn new(funcs: Arc<[AggregateFunc]>) -> Self {
+        Self { funcs, slots: HashMap::new(), groups: Vec::new(), held: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Accumulators of the group with `key`, added if it is new. A new group
+    /// counts against `budget` until the table is drained.
+    fn group(&mut self, key: Row, budget: &MemoryBudget) -> &mut Vec<Accumulator> {
+        let mut encoded = Vec::new();
+        encoding::encode_key(&key, &mut encoded);
+        let slot = match self.slots.entry(encoded) {
+            Entry::Occupied(entry) => *entry.get(),
+            Entry::Vacant(entry) => {
+                let accumulators: Vec<Accumulator> = self.funcs.iter().map(|func| Accumulator::new(*func)).collect();
+                // The encoded key is held twice, by the map and the group
+                let size = spill::row_size(&key)
+                    + 2 * entry.key().len()
+                    + accumulators.iter().map(Accumulator::size_hint).sum::<usize>()
+                    + std::mem::size_of::<Group>()
+                    + 2 * std::mem::size_of::<usize>();
+                budget.reserve(size);
+                self.held += size;
+
+                let encoded = entry.key().clone();
+                entry.insert(self.groups.len());
+                self.groups.push(Group { encoded, key, accumulators });
+                self.groups.len() - 1
+            }
+        };
+        &mut self.groups[slot].accumulators
+    }
+
+    /// Remove every group, returning the final rows
+    fn drain(&mut self, budget: &MemoryBudget) -> Vec<Row> {
+        self.release(budget);
+        std::mem::take(&mut self.groups).into_iter()
+            .map(|group| {
+                let mut row = group.key;
+                row.extend(group.accumulators.into_iter().map(Accumulator::finish));
+                row
+            })
+            .collect()
+    }
+
+    fn release(&mut self, budget: &MemoryBudget) {
+        self.slots.clear();
+        budget.release(self.held);
+        self.held = 0;
+    }
+}
+
+/// Partition runs that spilled groups are written to at one depth
+struct Partitions {
+    depth: u32,
+    writers: Vec<Option<RunWriter>>,
+}
+
+impl Partitions {
+    fn new(depth: u32) -> Self {
+        Self { depth, writers: (0..PARTITIONS).map(|_| None).collect() }
+    }
+
+    /// Write every group of `table` to its partition and empty the table
+    async fn spill(&mut self, table: &mut GroupTable, config: &SpillConfig, budget: &MemoryBudget) -> Result<()> {
+        table.release(budget);
+        for group in std::mem::take(&mut table.groups) {
+            let partition = self.partition(&group.encoded);
+            let writer = match &mut self.writers[partition] {
+                Some(writer) => writer,
+                slot => slot.insert(RunWriter::create(config).await?),
+            };
+            let mut row = group.key;
+            for accumulator in group.accumulators {
+                accumulator.push_state(&mut row);
+            }
+            writer.push(&row).await?;
+        }
+        Ok(())
+    }
+
+    /// The partition of a key. Each depth hashes differently, so a partition
+    /// split again spreads its keys over all the new partitions.
+    fn partition(&self, encoded: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        self.depth.hash(&mut hasher);
+        encoded.hash(&mut hasher);
+        (hasher.finish() % PARTITIONS as u64) as usize
+    }
+
+    /// Finished runs, each with the depth its groups are split at if it
+    /// needs splitting again
+    async fn finish(self, stats: &OperatorStats) -> Result<Vec<(SpillRun, u32)>> {
+        let mut runs = Vec::new();
+        for writer in self.writers.into_iter().flatten() {
+            let run = writer.finish().await?;
+            stats.record_spill(run.rows(), run.bytes());
+            runs.push((run, self.depth));
+        }
+        Ok(runs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::QueryError;
+    use crate::value::Value;
+    use futures::stream;
+    use std::sync::atomic::Ordering;
+    use tempfile::TempDir;
+
+    fn config(dir: &TempDir, memory_budget: usize) -> SpillConfig {
+        SpillConfig { memory_budget, dir: dir.path().to_path_buf() }
+    }
+
+    fn column(name: &str) -> Expr {
+        Expr::Column(name.to_string())
+    }
+
+    fn aggregate(func: AggregateFunc, arg: Option<&str>) -> AggregateExpr {
+        AggregateExpr { func, arg: arg.map(column), name: format!("{}({})", func, arg.unwrap_or("*")) }
+    }
+
+    fn aggregates() -> Vec<AggregateExpr> {
+        vec![
+            aggregate(AggregateFunc::Count, None),
+            aggregate(AggregateFunc::Sum, Some("v")),
+            aggregate(AggregateFunc::Avg, Some("v")),
+            aggregate(AggregateFunc::Min, Some("s")),
+            aggregate(AggregateFunc::Max, Some("v")),
+        ]
+    }
+
+    /// Rows of `(k, v, s)` spreading `count` rows over `groups` keys
+    fn rows(count: i64, groups: i64) -> impl Iterator<Item = Result<Row>> {
+        (0..count).map(move |i| {
+            let v = if i % 7 == 0 { Value::Null } else { Value::Integer(i % 1000) };
+            Ok(vec![Value::Integer((i * 7_919) % groups), v, Value::Text(format!("s{:07}", i))])
+        })
+    }
+
+    async fn run(input: RowStream, group_by: &str, budget: usize, dir: &TempDir, stats: Arc<OperatorStats>) -> Result<Vec<Row>> {
+        let columns = vec!["k".to_string(), "v".to_string(), "s".to_string()];
+        let group_by = vec![(column(group_by), group_by.to_string())];
+        aggregate_stream(input, columns, group_by, aggregates(), config(dir, budget), None, stats).try_collect().await
+    }
+
+    fn sorted(mut rows: Vec<Row>) -> Vec<Row> {
+        rows.sort_by(|a, b| a[0].sort_cmp(&b[0]));
+        rows
+    }
+
+    fn files_in(dir: &TempDir) -> usize {
+        std::fs::read_dir(dir.path()).unwrap().count()
+    }
+
+    #[tokio::test]
+    async fn test_spilled_aggregate_matches_in_memory() {
+        let dir = TempDir::new().unwrap();
+        let stats = Arc::new(OperatorStats::default());
+        let unbounded = run(stream::iter(rows(60_000, 20_000)).boxed(), "k", usize::MAX, &dir, stats.clone()).await.unwrap();
+        assert_eq!(stats.spilled_runs.load(Ordering::Relaxed), 0);
+
+        let spilled = run(stream::iter(rows(60_000, 20_000)).boxed(), "k", 32 * 1024, &dir, stats.clone()).await.unwrap();
+        assert!(stats.spilled_runs.load(Ordering::Relaxed) > PARTITIONS as u64);
+        assert_eq!(unbounded.len(), 20_000);
+        assert_eq!(sorted(spilled), sorted(unbounded));
+        assert_eq!(files_in(&dir), 0);
+
+        // Without GROUP BY there is one group, even over no rows
+        let columns = vec!["k".to_string(), "v".to_string(), "s".to_string()];
+        let empty = aggregate_stream(stream::empty().boxed(), columns, Vec::new(), aggregates(), config(&dir, 1), None, stats)
+            .try_collect::<Vec<Row>>()
+            .await
+            .unwrap();
+        assert_eq!(empty, vec![vec![Value::Integer(0), Value::Null, Value::Null, Value::Null, Value::Null]]);
+    }
+
+    #[tokio::test]
+    async fn test_million_distinct_keys() {
+        let dir = TempDir::new().unwrap();
+        let budget = 1024 * 1024;
+        let count = 1_000_000;
+        let stats = Arc::new(OperatorStats::default());
+        let input = stream::iter((0..count).map(move |i| Ok(vec![Value::Integer((i * 7_919) % count)]))).boxed();
+        let groups: Vec<Row> = aggregate_stream(
+            input,
+            vec!["k".to_string()],
+            vec![(column("k"), "k".to_string())],
+            vec![aggregate(AggregateFunc::Count, None)],
+            config(&dir, budget),
+            None,
+            stats.clone(),
+        ).try_collect().await.unwrap();
+
+        assert_eq!(groups.len(), count as usize);
+        assert!(groups.iter().all(|row| row[1] == Value::Integer(1)));
+        let groups = sorted(groups);
+        assert!(groups.iter().zip(0..).all(|(row, k)| row[0] == Value::Integer(k)));
+
+        // Partitions were split again, and memory stayed within the budget
+        // plus the block of spilled groups being read back
+        assert!(stats.spilled_runs.load(Ordering::Relaxed) > PARTITIONS as u64 * 2);
+        let peak = stats.peak_memory.load(Ordering::Relaxed) as usize;
+        assert!(peak <= budget + 4 * spill::BLOCK_BYTES, "peak memory {}", peak);
+        assert_eq!(files_in(&dir), 0);
+    }
+
+    #[tokio::test]
+    async fn test_spill_files_removed_on_error_and_cancel() {
+        let dir = TempDir::new().unwrap();
+        let stats = Arc::new(OperatorStats::default());
+        // The input fails after the table has spilled
+        let path = dir.path().to_path_buf();
+        let failing = stream::iter(rows(20_000, 20_000))
+            .chain(stream::once(async move {
+                assert!(std::fs::read_dir(path).unwrap().count() > 0);
+                Err(QueryError::Execution("input failed".to_string()))
+            }))
+            .boxed();
+        assert!(run(failing, "k", 16 * 1024, &dir, stats.clone()).await.is_err());
+        assert_eq!(files_in(&dir), 0);
+
+        let columns = vec!["k".to_string(), "v".to_string(), "s".to_string()];
+        let group_by = vec![(column("k"), "k".to_string())];
+        let input = stream::iter(rows(20_000, 20_000)).boxed();
+        let mut groups = aggregate_stream(input, columns, group_by, aggregates(), config(&dir, 16 * 1024), None, stats);
+        groups.try_next().await.unwrap().unwrap();
+        assert!(files_in(&dir) > 0);
+        drop(groups);
+        assert_eq!(files_in(&dir), 0);
+    }
+}
@@ -0,0 +1,478 @@
+use crate::error::{Result, QueryError};
+use std::fmt;
+
+/// Location of a token in the source text (1-based line and column)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    /// Bare or double-quoted identifier. Keywords are bare identifiers and are
+    /// recognized by the parser, so `quoted` distinguishes `"select"` from `select`.
+    Ident { value: String, quoted: bool },
+    String(String),
+    Number(String),
+    /// `X'00ff'`
+    Blob(Vec<u8>),
+    /// `$1`, a parameter of a prepared statement
+    Parameter(usize),
+    LParen,
+    RParen,
+    Comma,
+    Semicolon,
+    Dot,
+    Star,
+    Plus,
+    Minus,
+    Slash,
+    Percent,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    Concat,
+    Eof,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenKind::Ident { value, quoted: true } => write!(f, "\"{}\"", value),
+            TokenKind::Ident { value, quoted: false } => write!(f, "{}", value),
+            TokenKind::String(s) => write!(f, "'{}'", s),
+            TokenKind::Number(n) => write!(f, "{}", n),
+            TokenKind::Blob(bytes) => {
+                write!(f, "X'")?;
+                for byte in bytes {
+                    write!(f, "{:02x}", byte)?;
+                }
+                write!(f, "'")
+            }
+            TokenKind::Parameter(n) => write!(f, "${}", n),
+            TokenKind::LParen => write!(f, "("),
+            TokenKind::RParen => write!(f, ")"),
+            TokenKind::Comma => write!(f, ","),
+            TokenKind::Semicolon => write!(f, ";"),
+            TokenKind::Dot => write!(f, "."),
+            TokenKind::Star => write!(f, "*"),
+            TokenKind::Plus => write!(f, "+"),
+            TokenKind::Minus => write!(f, "-"),
+            TokenKind::Slash => write!(f, "/"),
+            TokenKind::Percent => write!(f, "%"),
+            TokenKind::Eq => write!(f, "="),
+            TokenKind::NotEq => write!(f, "<>"),
+            TokenKind::Lt => write!(f, "<"),
+            TokenKind::LtEq => write!(f, "<="),
+            TokenKind::Gt => write!(f, ">"),
+            TokenKind::GtEq => write!(f, ">="),
+            TokenKind::Concat => write!(f, "||"),
+            TokenKind::Eof => write!(f, "end of input"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub position: Position,
+}
+
+/// SQL tokenizer. Comments (`-- ...` and `/* ... */`) and whitespace are skipped.
+pub struct Lexer<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(sql: &'a str) -> Self {
+        Self {
+            chars: sql.chars().peekable(),
+            line: 1,
+            column: 1,
+        }
+    }
+
+    /// Tokenize the whole input. The returned vector always ends with `Eof`.
+    pub fn tokenize(sql: &str) -> Result<Vec<Token>> {
+        let mut lexer = Lexer::new(sql);
+        let mut tokens = Vec::new();
+        loop {
+            let token = lexer.next_token()?;
+            let done = token.kind == TokenKind::Eof;
+            tokens.push(token);
+            if done {
+                return Ok(tokens);
+            }
+        }
+    }
+
+    fn position(&self) -> Position {
+        Position { line: self.line, column: self.column }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn peek_second(&self) -> Option<char> {
+        let mut ahead = self.chars.clone();
+        ahead.next();
+        ahead.next()
+    }
+
+    fn skip_whitespace_and_comments(&mut self) -> Result<()> {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.bump();
+                }
+                Some('-') if self.peek_second() == Some('-') => {
+                    while let Some(c) = self.bump() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                }
+                Some('/') if self.peek_second() == Some('*') => {
+                    let start = self.position();
+                    self.bump();
+                    self.bump();
+                    loop {
+                        match self.bump() {
+                            Some('*') if self.peek() == Some('/') => {
+                                self.bump();
+                                break;
+                            }
+                            Some(_) => {}
+                            None => {
+                                return Err(QueryError::Parse(format!(
+                                    "Unterminated block comment starting at {}", start
+                                )));
+                            }
+                        }
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Token> {
+        self.skip_whitespace_and_comments()?;
+        let position = self.position();
+
+        let Some(c) = self.bump() else {
+            return Ok(Token { kind: TokenKind::Eof, position });
+        };
+
+        let kind = match c {
+            '(' => TokenKind::LParen,
+            ')' => TokenKind::RParen,
+            ',' => TokenKind::Comma,
+            ';' => TokenKind::Semicolon,
+            '*' => TokenKind::Star,
+            '+' => TokenKind::Plus,
+            '-' => TokenKind::Minus,
+            '/' => TokenKind::Slash,
+            '%' => TokenKind::Percent,
+            '=' => TokenKind::Eq,
+            '.' if !self.peek().is_some_and(|c| c.is_ascii_digit()) => TokenKind::Dot,
+            '<' => match self.peek() {
+                Some('=') => { self.bump(); TokenKind::LtEq }
+                Some('>') => { self.bump(); TokenKind::NotEq }
+                _ => TokenKind::Lt,
+            },
+            '>' => match self.peek() {
+                Some('=') => { self.bump(); TokenKind::GtEq }
+                _ => TokenKind::Gt,
+            },
+            '!' if self.peek() == Some('=') => {
+                self.bump();
+                TokenKind::NotEq
+            }
+            '|' if self.peek() == Some('|') => {
+                self.bump();
+                TokenKind::Concat
+            }
+            '\'' => TokenKind::String(self.read_string(position)?),
+            'x' | 'X' if self.peek() == Some('\'') => {
+                self.bump();
+                let hex = self.read_string(position)?;
+                TokenKind::Blob(decode_hex(&hex).ok_or_else(|| {
+                    QueryError::Parse(format!("Malformed BLOB literal at {}", position))
+                })?)
+            }
+            '$' if self.peek().is_some_and(|c| c.is_ascii_digit()) => {
+                let digits = self.read_number('0', position)?;
+                match digits.parse::<usize>() {
+                    Ok(n) if n > 0 => TokenKind::Parameter(n),
+                    _ => return Err(QueryError::Parse(format!("Malformed parameter at {}", position))),
+                }
+            }
+            '"' => TokenKind::Ident { value: self.read_quoted_ident(position)?, quoted: true },
+            c if c.is_ascii_digit() || c == '.' => TokenKind::Number(self.read_number(c, position)?),
+            c if c.is_alphabetic() || c == '_' => {
+                let mut value = String::from(c);
+                while let Some(c) = self.peek() {
+                    if c.is_alphanumeric() || c == '_' {
+                        value.push(c);
+                        self.bump();
+                    } else {
+                        break;
+                    }
+                }
+                TokenKind::Ident { value, quoted: false }
+            }
+            other => {
+                return Err(QueryError::Parse(format!(
+                    "Unexpected character '{}' at {}", other, position
+                )));
+            }
+        };
+
+        Ok(Token { kind, position })
+    }
+
+    /// Single-quoted string. `''` and backslash escapes (`\'`, `\\`, `\n`, `\t`, `\r`) are supported.
+    fn read_string(&mut self, start: Position) -> Result<String> {
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                Some('\'') => {
+                    if self.peek() == Some('\'') {
+                        self.bump();
+                        value.push('\'');
+                    } else {
+                        return Ok(value);
+                    }
+                }
+                Some('\\') => match self.bump() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('r') => value.push('\r'),
+                    Some(c) => value.push(c),
+                    None => break,
+                },
+                Some(c) => value.push(c),
+                None => break,
+            }
+        }
+        Err(QueryError::Parse(format!("Unterminated string literal starting at {}", start)))
+    }
+
+    /// Double-quoted identifier; `""` escapes a literal quote.
+    fn read_quoted_ident(&mut self, start: Position) -> Result<String> {
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => {
+                    if self.peek() == Some('"') {
+                        self.bump();
+                        value.push('"');
+                    } else if value.is_empty() {
+                        return Err(QueryError::Parse(format!("Empty quoted identifier at {}", start)));
+                    } else {
+                        return Ok(value);
+                    }
+                }
+                Some(c) => value.push(c),
+                None => {
+                    return Err(QueryError::Parse(format!(
+                        "Unterminated quoted identifier starting at {}", start
+                    )));
+                }
+            }
+        }
+    }
+
+    fn read_number(&mut self, first: char, start: Position) -> Result<String> {
+        let mut value = String::from(first);
+        let mut seen_dot = first == '.';
+        let mut seen_exp = false;
+
+        while let Some(c) = self.peek() {
+            if c.is_ascii_digit() {
+                value.push(c);
+            } else if c == '.' && !seen_dot && !seen_exp {
+                seen_dot = true;
+                value.push(c);
+            } else if (c == 'e' || c == 'E') && !seen_exp {
+                seen_exp = true;
+                value.push(c);
+                self.bump();
+                if let Some(sign @ ('+' | '-')) = self.peek() {
+                    value.push(sign);
+                    self.bump();
+                }
+                if !self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    return Err(QueryError::Parse(format!("Malformed number '{}' at {}", value, start)));
+                }
+                continue;
+            } else {
+                break;
+            }
+            self.bump();
+        }
+
+        if self.peek().is_some_and(|c| c.is_alphabetic() || c == '_') {
+            return Err(QueryError::Parse(format!("Malformed number '{}' at {}", value, start)));
+        }
+
+        Ok(value)
+    }
+}
+
+/// Bytes written as pairs of hex digits
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+/// `sql` with every string and number literal replaced by `?`, comments
+/// dropped and whitespace normalized, so it can be logged without the
+/// values it carries and statements differing only in them look the same.
+/// SQL that does not tokenize gives `<invalid>`, since where a literal
+/// ends is then unknown.
+pub fn fingerprint(sql: &str) -> String {
+    let Ok(tokens) = Lexer::tokenize(sql) else {
+        return "<invalid>".to_string();
+    };
+    let mut fingerprint = String::new();
+    let mut previous: Option<&TokenKind> = None;
+    for token in &tokens {
+        let kind = &token.kind;
+        if matches!(kind, TokenKind::Eof | TokenKind::Semicolon) {
+            continue;
+        }
+        let joined = matches!(previous, None | Some(TokenKind::LParen | TokenKind::Dot))
+            || matches!(kind, TokenKind::Comma | TokenKind::RParen | TokenKind::Dot);
+        if !joined {
+            fingerprint.push(' ');
+        }
+        match kind {
+            TokenKind::String(_) | TokenKind::Number(_) | TokenKind::Blob(_) => fingerprint.push('?'),
+            kind => fingerprint.push_str(&kind.to_string()),
+        }
+        previous = Some(kind);
+    }
+    fingerprint
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(sql: &str) -> Vec<TokenKind> {
+        Lexer::tokenize(sql).unwrap().into_iter().map(|t| t.kind).collect()
+    }
+
+    fn ident(value: &str) -> TokenKind {
+        TokenKind::Ident { value: value.to_string(), quoted: false }
+    }
+
+    #[test]
+    fn test_tokenize_basic_statement() {
+        assert_eq!(
+            kinds("SELECT a, b FROM t WHERE x >= 1.5"),
+            vec![
+                ident("SELECT"), ident("a"), TokenKind::Comma, ident("b"), ident("FROM"), ident("t"),
+                ident("WHERE"), ident("x"), TokenKind::GtEq, TokenKind::Number("1.5".to_string()),
+                TokenKind::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_literals_and_comments() {
+        let cases: Vec<(&str, Vec<TokenKind>)> = vec![
+            ("'it''s'", vec![TokenKind::String("it's".to_string()), TokenKind::Eof]),
+            ("'a\\'b\\n'", vec![TokenKind::String("a'b\n".to_string()), TokenKind::Eof]),
+            ("'MiXeD Case'", vec![TokenKind::String("MiXeD Case".to_string()), TokenKind::Eof]),
+            ("\"Weird \"\"Name\"\"\"", vec![
+                TokenKind::Ident { value: "Weird \"Name\"".to_string(), quoted: true }, TokenKind::Eof,
+            ]),
+            ("1e10 .5 42", vec![
+                TokenKind::Number("1e10".to_string()), TokenKind::Number(".5".to_string()),
+                TokenKind::Number("42".to_string()), TokenKind::Eof,
+            ]),
+            ("a -- trailing comment\nb", vec![ident("a"), ident("b"), TokenKind::Eof]),
+            ("a /* block\n comment */ b", vec![ident("a"), ident("b"), TokenKind::Eof]),
+            ("a<>b!=c||d", vec![
+                ident("a"), TokenKind::NotEq, ident("b"), TokenKind::NotEq, ident("c"),
+                TokenKind::Concat, ident("d"), TokenKind::Eof,
+            ]),
+            ("$1 = X'0aFF' || x''", vec![
+                TokenKind::Parameter(1), TokenKind::Eq, TokenKind::Blob(vec![0x0a, 0xff]),
+                TokenKind::Concat, TokenKind::Blob(vec![]), TokenKind::Eof,
+            ]),
+        ];
+
+        for (sql, expected) in cases {
+            assert_eq!(kinds(sql), expected, "tokenizing {:?}", sql);
+        }
+    }
+
+    #[test]
+    fn test_token_positions() {
+        let tokens = Lexer::tokenize("SELECT\n  name").unwrap();
+        assert_eq!(tokens[1].position, Position { line: 2, column: 3 });
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        let cases = [
+            ("'unterminated", "Unterminated string literal starting at line 1, column 1"),
+            ("a /* open", "Unterminated block comment starting at line 1, column 3"),
+            ("x = 12abc", "Malformed number '12' at line 1, column 5"),
+            ("a ? b", "Unexpected character '?' at line 1, column 3"),
+            ("a = $0", "Malformed parameter at line 1, column 5"),
+            ("a = X'abc'", "Malformed BLOB literal at line 1, column 5"),
+        ];
+
+        for (sql, message) in cases {
+            match Lexer::tokenize(sql) {
+                Err(QueryError::Parse(msg)) => assert_eq!(msg, message, "tokenizing {:?}", sql),
+                other => panic!("expected parse error for {:?}, got {:?}", sql, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_hides_literals() {
+        assert_eq!(
+            fingerprint("select name  FROM users\n WHERE email = 'ada@example.com' AND age > 36 -- ada\n;"),
+            "select name FROM users WHERE email = ? AND age > ?"
+        );
+        assert_eq!(
+            fingerprint("INSERT INTO t (id, v) VALUES (1, 'x'), (-2.5e3, \"y\")"),
+            "INSERT INTO t (id, v) VALUES (?, ?), (- ?, \"y\")"
+        );
+        assert_eq!(fingerprint("SELECT COUNT(*) FROM s.t WHERE id IN (1, 2)"), "SELECT COUNT (*) FROM s.t WHERE id IN (?, ?)");
+        assert_eq!(fingerprint("SELECT 'unterminated"), "<invalid>");
+    }
+}
@@ -1,9 +1,40 @@
+pub mod lexer;
+pub mod ast;
 pub mod parser;
+pub mod value;
+pub mod encoding;
+pub mod catalog;
+pub mod eval;
+pub mod aggregate;
+pub mod functions;
 pub mod planner;
+pub mod params;
+pub mod cache;
+pub mod result;
 pub mod executor;
+pub mod cursor;
+pub mod spill;
+pub mod slow_log;
+pub mod replication;
+pub mod session;
+pub mod stats;
+mod sort;
+mod hash_aggregate;
+mod locks;
 pub mod error;
 
 pub use error::{QueryError, Result};
+pub use lexer::fingerprint;
 pub use parser::SqlParser;
+pub use ast::SqlStatement;
+pub use value::Value;
+pub use catalog::Catalog;
 pub use planner::QueryPlanner;
-pub use executor::QueryExecutor;
\ No newline at end of file
+pub use executor::{OperatorStats, QueryExecutor, ResultLimits};
+pub use cursor::QueryCursor;
+pub use spill::SpillConfig;
+pub use slow_log::{SlowQuery, SlowQueryConfig, SlowQueryLog};
+pub use replication::{ReplicatedWrite, Replicator};
+pub use session::SessionId;
+pub use result::{ColumnMeta, ResultSet};
+pub use cache::{QueryCache, QueryCacheConfig};
\ No newline at end of file
@@ -0,0 +1,88 @@
+//! Locks on storage keys that a statement checks for conflicts before it
+//! writes them.
+//!
+//! A statement claims every primary key and unique index value it is about
+//! to write, checks that none of them are taken, then writes. A concurrent
+//! statement claiming any of the same keys waits until the first one has
+//! written or failed, and so sees its rows when it makes its own check. Keys
+//! are claimed all at once or not at all, so two statements can never each
+//! hold a key the other is waiting for.
+
+use parking_lot::Mutex;
+use std::collections::HashSet;
+use tokio::sync::Notify;
+
+#[derive(Debug, Default)]
+pub(crate) struct KeyLocks {
+    held: Mutex<HashSet<Vec<u8>>>,
+    released: Notify,
+}
+
+impl KeyLocks {
+    /// Claim `keys`, waiting while any of them is held by another statement
+    pub(crate) async fn lock(&self, keys: HashSet<Vec<u8>>) -> KeyLockGuard<'_> {
+        loop {
+            let released = {
+                let mut held = self.held.lock();
+                if keys.iter().all(|key| !held.contains(key)) {
+                    held.extend(keys.iter().cloned());
+                    return KeyLockGuard { locks: self, keys };
+                }
+                // Registered before the mutex is released, so a release in
+                // between still wakes us
+                self.released.notified()
+            };
+            released.await;
+        }
+    }
+}
+
+/// Keys claimed by one statement, released on drop
+pub(crate) struct KeyLockGuard<'a> {
+    locks: &'a KeyLocks,
+    keys: HashSet<Vec<u8>>,
+}
+
+impl Drop for KeyLockGuard<'_> {
+    fn drop(&mut self) {
+        let mut held = self.locks.held.lock();
+        for key in &self.keys {
+            held.remove(key);
+        }
+        drop(held);
+        self.locks.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn keys(names: &[&str]) -> HashSet<Vec<u8>> {
+        names.iter().map(|name| name.as_bytes().to_vec()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_claims_wait() {
+        let locks = Arc::new(KeyLocks::default());
+        let first = locks.lock(keys(&["a", "b"])).await;
+
+        // Disjoint keys are claimed right away
+        drop(locks.lock(keys(&["c"])).await);
+
+        let waiter = tokio::spawn({
+            let locks = locks.clone();
+            async move {
+                let _guard = locks.lock(keys(&["b", "c"])).await;
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(5), waiter).await.unwrap().unwrap();
+        assert!(locks.held.lock().is_empty());
+    }
+}
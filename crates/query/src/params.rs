@@ -0,0 +1,282 @@
+//! Parameters of prepared statements.
+//!
+//! A statement may use `$1`, `$2`, ... wherever a value is expected. Binding
+//! replaces each with a literal, so the bound statement plans and runs like
+//! one written with the values inline.
+
+use crate::{
+    ast::{AlterTableOperation, BinaryOp, DataType, Expr, SelectItem, SelectStatement, SqlStatement},
+    catalog::{Catalog, TableSchema},
+    error::{QueryError, Result},
+    value::Value,
+};
+
+impl SqlStatement {
+    /// Number of parameters the statement takes: the highest `$n` it uses
+    pub fn parameter_count(&self) -> usize {
+        let mut count = 0;
+        // Walking needs a mutable statement, and this one is only read
+        let _ = walk_statement(&mut self.clone(), &mut |expr| {
+            if let Expr::Parameter(n) = expr {
+                count = count.max(*n);
+            }
+            Ok(())
+        });
+        count
+    }
+
+    /// The statement with `$n` replaced by `params[n - 1]`
+    pub fn bind(&self, params: &[Value]) -> Result<SqlStatement> {
+        let expected = self.parameter_count();
+        if params.len() != expected {
+            return Err(QueryError::Execution(format!(
+                "statement takes {} parameters, {} given", expected, params.len()
+            )));
+        }
+        let mut bound = self.clone();
+        walk_statement(&mut bound, &mut |expr| {
+            if let Expr::Parameter(n) = expr {
+                *expr = Expr::Literal(params[*n - 1].to_literal());
+            }
+            Ok(())
+        })?;
+        Ok(bound)
+    }
+
+    /// The type each parameter should have, where it can be told from the
+    /// column it is stored in or compared with. Entry `n - 1` is for `$n`.
+    pub fn parameter_types(&self, catalog: &Catalog) -> Vec<Option<DataType>> {
+        let mut types = vec![None; self.parameter_count()];
+        infer_statement(self, catalog, &mut types);
+        types
+    }
+}
+
+/// Call `f` on every expression in the statement, outermost first, including
+/// those in subqueries
+fn walk_statement(statement: &mut SqlStatement, f: &mut dyn FnMut(&mut Expr) -> Result<()>) -> Result<()> {
+    match statement {
+        SqlStatement::Select(select) => walk_select(select, f),
+        SqlStatement::Insert { values, .. } => {
+            for expr in values.iter_mut().flatten() {
+                walk_expr(expr, f)?;
+            }
+            Ok(())
+        }
+        SqlStatement::Update { set_clause, where_clause, .. } => {
+            for (_, expr) in set_clause {
+                walk_expr(expr, f)?;
+            }
+            where_clause.iter_mut().try_for_each(|expr| walk_expr(expr, f))
+        }
+        SqlStatement::Delete { where_clause, .. }
+        | SqlStatement::ShowTables { where_clause }
+        | SqlStatement::ShowColumns { where_clause, .. } => {
+            where_clause.iter_mut().try_for_each(|expr| walk_expr(expr, f))
+        }
+        SqlStatement::CreateTable { columns, .. } => {
+            columns.iter_mut().filter_map(|column| column.default.as_mut()).try_for_each(|expr| walk_expr(expr, f))
+        }
+        SqlStatement::AlterTable { operation: AlterTableOperation::AddColumn(column), .. } => {
+            column.default.iter_mut().try_for_each(|expr| walk_expr(expr, f))
+        }
+        SqlStatement::Explain { statement, .. } => walk_statement(statement, f),
+        SqlStatement::AlterTable { .. }
+        | SqlStatement::DropTable { .. }
+        | SqlStatement::CreateDatabase { .. }
+        | SqlStatement::DropDatabase { .. }
+        | SqlStatement::CreateIndex { .. }
+        | SqlStatement::Analyze { .. }
+        | SqlStatement::Begin { .. }
+        | SqlStatement::Commit
+        | SqlStatement::Rollback => Ok(()),
+    }
+}
+
+fn walk_select(select: &mut SelectStatement, f: &mut dyn FnMut(&mut Expr) -> Result<()>) -> Result<()> {
+    for item in &mut select.columns {
+        if let SelectItem::Expr { expr, .. } = item {
+            walk_expr(expr, f)?;
+        }
+    }
+    for expr in select.where_clause.iter_mut().chain(&mut select.group_by).chain(select.having.iter_mut()) {
+        walk_expr(expr, f)?;
+    }
+    for key in &mut select.order_by {
+        walk_expr(&mut key.expr, f)?;
+    }
+    Ok(())
+}
+
+fn walk_expr(expr: &mut Expr, f: &mut dyn FnMut(&mut Expr) -> Result<()>) -> Result<()> {
+    f(expr)?;
+    match expr {
+        Expr::Column(_) | Expr::Literal(_) | Expr::Default | Expr::Parameter(_) => Ok(()),
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => walk_expr(expr, f),
+        Expr::Binary { left, right, .. } => {
+            walk_expr(left, f)?;
+            walk_expr(right, f)
+        }
+        Expr::Function { args, .. } => args.iter_mut().try_for_each(|arg| walk_expr(arg, f)),
+        Expr::Aggregate { arg, .. } => arg.iter_mut().try_for_each(|arg| walk_expr(arg, f)),
+        Expr::InSubquery { expr, subquery, .. } => {
+            walk_expr(expr, f)?;
+            walk_select(subquery, f)
+        }
+        Expr::InList { expr, list, .. } => {
+            walk_expr(expr, f)?;
+            list.iter_mut().try_for_each(|item| walk_expr(item, f))
+        }
+    }
+}
+
+fn infer_statement(statement: &SqlStatement, catalog: &Catalog, types: &mut [Option<DataType>]) {
+    match statement {
+        SqlStatement::Select(select) => infer_select(select, catalog, types),
+        SqlStatement::Insert { table, columns, values } => {
+            let Some(schema) = catalog.get_table(table) else {
+                return;
+            };
+            let names = if columns.is_empty() { schema.column_names() } else { columns.clone() };
+            for row in values {
+                for (name, expr) in names.iter().zip(row) {
+                    infer_column(&schema, name, expr, types);
+                    infer_expr(expr, Some(&schema), catalog, types);
+                }
+            }
+        }
+        SqlStatement::Update { table, set_clause, where_clause } => {
+            let schema = catalog.get_table(table);
+            for (name, expr) in set_clause {
+                if let Some(schema) = &schema {
+                    infer_column(schema, name, expr, types);
+                }
+                infer_expr(expr, schema.as_deref(), catalog, types);
+            }
+            if let Some(expr) = where_clause {
+                infer_expr(expr, schema.as_deref(), catalog, types);
+            }
+        }
+        SqlStatement::Delete { table, where_clause: Some(expr) } => {
+            infer_expr(expr, catalog.get_table(table).as_deref(), catalog, types);
+        }
+        SqlStatement::Explain { statement, .. } => infer_statement(statement, catalog, types),
+        _ => {}
+    }
+}
+
+fn infer_select(select: &SelectStatement, catalog: &Catalog, types: &mut [Option<DataType>]) {
+    let schema = select.table.as_deref().and_then(|table| catalog.get_table(table));
+    let items = select.columns.iter().filter_map(|item| match item {
+        SelectItem::Expr { expr, .. } => Some(expr),
+        SelectItem::Wildcard => None,
+    });
+    for expr in items.chain(&select.where_clause).chain(&select.group_by).chain(&select.having) {
+        infer_expr(expr, schema.as_deref(), catalog, types);
+    }
+}
+
+/// Type parameters compared with a column of `schema`
+fn infer_expr(expr: &Expr, schema: Option<&TableSchema>, catalog: &Catalog, types: &mut [Option<DataType>]) {
+    match expr {
+        Expr::Binary { left, op, right } => {
+            if matches!(op, BinaryOp::Eq | BinaryOp::NotEq | BinaryOp::Lt | BinaryOp::LtEq | BinaryOp::Gt | BinaryOp::GtEq) {
+                if let (Some(schema), Expr::Column(name)) = (schema, &**left) {
+                    infer_column(schema, name, right, types);
+                }
+                if let (Some(schema), Expr::Column(name)) = (schema, &**right) {
+                    infer_column(schema, name, left, types);
+                }
+            }
+            infer_expr(left, schema, catalog, types);
+            infer_expr(right, schema, catalog, types);
+        }
+        Expr::InList { expr, list, .. } => {
+            for item in list {
+                if let (Some(schema), Expr::Column(name)) = (schema, &**expr) {
+                    infer_column(schema, name, item, types);
+                }
+                infer_expr(item, schema, catalog, types);
+            }
+            infer_expr(expr, schema, catalog, types);
+        }
+        Expr::InSubquery { expr, subquery, .. } => {
+            infer_expr(expr, schema, catalog, types);
+            infer_select(subquery, catalog, types);
+        }
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => infer_expr(expr, schema, catalog, types),
+        Expr::Function { args, .. } => {
+            for arg in args {
+                infer_expr(arg, schema, catalog, types);
+            }
+        }
+        Expr::Aggregate { arg: Some(arg), .. } => infer_expr(arg, schema, catalog, types),
+        Expr::Aggregate { arg: None, .. } | Expr::Column(_) | Expr::Literal(_) | Expr::Default | Expr::Parameter(_) => {}
+    }
+}
+
+/// If `expr` is a parameter, it takes the type of column `name`
+fn infer_column(schema: &TableSchema, name: &str, expr: &Expr, types: &mut [Option<DataType>]) {
+    if let (Expr::Parameter(n), Some(column)) = (expr, schema.column(name)) {
+        types[n - 1].get_or_insert(column.data_type);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{executor::QueryExecutor, parser::SqlParser};
+    use nextdb_storage::{Durability, LSMTree, StorageConfig};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn parse(sql: &str) -> SqlStatement {
+        SqlParser::parse(sql).unwrap()
+    }
+
+    #[test]
+    fn test_bind() {
+        let statement = parse("SELECT * FROM t WHERE a = $1 AND b IN (SELECT b FROM u WHERE c > $2) AND d <> $1");
+        assert_eq!(statement.parameter_count(), 2);
+        let bound = statement.bind(&[Value::Text("it's".to_string()), Value::Integer(7)]).unwrap();
+        assert_eq!(bound, parse("SELECT * FROM t WHERE a = 'it''s' AND b IN (SELECT b FROM u WHERE c > 7) AND d <> 'it''s'"));
+
+        let insert = parse("INSERT INTO t (a, b) VALUES ($1, $2), ($2, NULL)");
+        let bound = insert.bind(&[Value::Blob(vec![0xab]), Value::Null]).unwrap();
+        assert_eq!(bound, parse("INSERT INTO t (a, b) VALUES (X'ab', NULL), (NULL, NULL)"));
+
+        let err = insert.bind(&[Value::Integer(1)]).unwrap_err();
+        assert!(err.to_string().contains("takes 2 parameters, 1 given"), "{}", err);
+        assert!(parse("SELECT 1").bind(&[]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_parameter_types() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+            wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+            durability: Durability::NoSync,
+            ..Default::default()
+        };
+        let storage = Arc::new(LSMTree::open(config).await.unwrap());
+        let executor = QueryExecutor::open(storage).await.unwrap();
+        executor.execute_sql("CREATE TABLE t (id INT PRIMARY KEY, name TEXT, score FLOAT, seen TIMESTAMP)").await.unwrap();
+        let catalog = executor.catalog();
+
+        let types = |sql: &str| parse(sql).parameter_types(catalog);
+        assert_eq!(
+            types("INSERT INTO t VALUES ($1, $2, $3, $4)"),
+            vec![Some(DataType::Integer), Some(DataType::Text), Some(DataType::Float), Some(DataType::Timestamp)]
+        );
+        assert_eq!(
+            types("UPDATE t SET name = $2 WHERE $1 > id"),
+            vec![Some(DataType::Integer), Some(DataType::Text)]
+        );
+        assert_eq!(
+            types("SELECT name FROM t WHERE id IN ($1, $2) OR score + $3 > 1"),
+            vec![Some(DataType::Integer), Some(DataType::Integer), None]
+        );
+        assert_eq!(types("SELECT * FROM missing WHERE a = $1"), vec![None]);
+    }
+}
@@ -1,145 +1,1398 @@
-use crate::error::{Result, QueryError};
-use serde::{Deserialize, Serialize};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum SqlStatement {
-    Select {
-        columns: Vec<String>,
-        table: String,
-        where_clause: Option<String>,
-    },
-    Insert {
-        table: String,
-        columns: Vec<String>,
-        values: Vec<Vec<String>>,
-    },
-    Update {
-        table: String,
-        set_clause: Vec<(String, String)>,
-        where_clause: Option<String>,
-    },
-    Delete {
-        table: String,
-        where_clause: Option<String>,
-    },
-}
+use crate::{
+    error::{Result, QueryError},
+    lexer::{Lexer, Token, TokenKind},
+    value,
+};
+
+pub use crate::ast::{
+    AggregateFunc, AlterTableOperation, BinaryOp, ColumnDef, DataType, Expr, IsolationLevel, Literal, OrderByExpr, SelectItem,
+    SelectStatement, SqlStatement, UnaryOp,
+};
+
+/// Words that cannot be used as bare identifiers or implicit aliases
+const RESERVED: &[&str] = &[
+    "all", "and", "as", "asc", "by", "create", "delete", "desc", "drop", "exists", "false",
+    "from", "group", "having", "if", "in", "index", "insert", "into", "is", "key", "limit", "not", "null", "offset",
+    "on", "or", "order", "primary", "select", "set", "table", "true", "unique", "update", "values", "where",
+];
 
-/// Simplified SQL parser
+/// SQL parser.
+///
+/// Keywords are case-insensitive. Unquoted identifiers are folded to lower case
+/// (so `Users` and `users` name the same table); double-quoted identifiers keep
+/// their case exactly. String literals are never modified. `--` and `/* */`
+/// comments are skipped, and a single trailing `;` is accepted.
 pub struct SqlParser;
 
 impl SqlParser {
     pub fn parse(sql: &str) -> Result<SqlStatement> {
-        let sql = sql.trim().to_lowercase();
-        
-        if sql.starts_with("select") {
-            Self::parse_select(&sql)
-        } else if sql.starts_with("insert") {
-            Self::parse_insert(&sql)
-        } else if sql.starts_with("update") {
-            Self::parse_update(&sql)
-        } else if sql.starts_with("delete") {
-            Self::parse_delete(&sql)
-        } else {
-            Err(QueryError::Parse(format!("Unsupported statement: {}", sql)))
-        }
-    }
-    
-    fn parse_select(sql: &str) -> Result<SqlStatement> {
-        // Very simplified SELECT parsing
-        // Real implementation would use a proper SQL parser
-        
-        if let Some(from_pos) = sql.find(" from ") {
-            let select_part = &sql[6..from_pos].trim(); // Skip "select"
-            let after_from = &sql[from_pos + 6..].trim();
-            
-            let columns: Vec<String> = if select_part == &"*" {
-                vec!["*".to_string()]
-            } else {
-                select_part.split(',')
-                    .map(|s| s.trim().to_string())
-                    .collect()
+        let tokens = Lexer::tokenize(sql)?;
+        let mut parser = Parser::new(tokens);
+        let statement = parser.parse_statement()?;
+        parser.consume(&TokenKind::Semicolon);
+        parser.expect_eof()?;
+        Ok(statement)
+    }
+
+    /// Parse a script of `;`-separated statements. Semicolons inside string
+    /// literals, quoted identifiers and comments do not separate statements,
+    /// and empty statements are skipped.
+    pub fn parse_many(sql: &str) -> Result<Vec<SqlStatement>> {
+        let tokens = Lexer::tokenize(sql)?;
+        let mut parser = Parser::new(tokens);
+        let mut statements = Vec::new();
+        loop {
+            while parser.consume(&TokenKind::Semicolon) {}
+            if *parser.peek_kind() == TokenKind::Eof {
+                return Ok(statements);
+            }
+            statements.push(parser.parse_statement()?);
+            if !parser.consume(&TokenKind::Semicolon) {
+                parser.expect_eof()?;
+            }
+        }
+    }
+}
+
+/// Recursive-descent parser over a token stream
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(tokens: Vec<Token>) -> Self {
+        Self { tokens, pos: 0 }
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+
+    fn peek_kind(&self) -> &TokenKind {
+        &self.peek().kind
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn error<T>(&self, expected: &str) -> Result<T> {
+        let token = self.peek();
+        Err(QueryError::Parse(format!(
+            "Expected {}, found {} at {}", expected, token.kind, token.position
+        )))
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek_kind(), TokenKind::Ident { value, quoted: false } if value.eq_ignore_ascii_case(keyword))
+    }
+
+    /// Whether the token after the next one is `keyword`
+    fn is_next_keyword(&self, keyword: &str) -> bool {
+        matches!(
+            self.tokens.get(self.pos + 1).map(|t| &t.kind),
+            Some(TokenKind::Ident { value, quoted: false }) if value.eq_ignore_ascii_case(keyword)
+        )
+    }
+
+    /// Whether the token after the next one is `kind`
+    fn next_is(&self, kind: &TokenKind) -> bool {
+        self.tokens.get(self.pos + 1).is_some_and(|t| t.kind == *kind)
+    }
+
+    fn parse_keyword(&mut self, keyword: &str) -> bool {
+        if self.is_keyword(keyword) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if self.parse_keyword(keyword) {
+            Ok(())
+        } else {
+            self.error(&keyword.to_uppercase())
+        }
+    }
+
+    fn consume(&mut self, kind: &TokenKind) -> bool {
+        if self.peek_kind() == kind {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Result<()> {
+        if self.consume(&kind) {
+            Ok(())
+        } else {
+            self.error(&format!("'{}'", kind))
+        }
+    }
+
+    fn expect_eof(&mut self) -> Result<()> {
+        if *self.peek_kind() == TokenKind::Eof {
+            Ok(())
+        } else {
+            self.error("end of statement")
+        }
+    }
+
+    fn parse_identifier(&mut self) -> Result<String> {
+        match self.peek_kind().clone() {
+            TokenKind::Ident { value, quoted: true } => {
+                self.advance();
+                Ok(value)
+            }
+            TokenKind::Ident { value, quoted: false } if !is_reserved(&value) => {
+                self.advance();
+                Ok(value.to_lowercase())
+            }
+            _ => self.error("identifier"),
+        }
+    }
+
+    /// A table name. `db.table` names a table of another database, which no
+    /// statement can reach.
+    fn parse_table_name(&mut self) -> Result<String> {
+        let name = self.parse_identifier()?;
+        if self.consume(&TokenKind::Dot) {
+            let table = self.parse_identifier()?;
+            return Err(QueryError::Parse(format!(
+                "cross-database queries are not supported: {}.{} is a table of database {}", name, table, name
+            )));
+        }
+        Ok(name)
+    }
+
+    fn parse_identifier_list(&mut self) -> Result<Vec<String>> {
+        self.expect(TokenKind::LParen)?;
+        let mut names = vec![self.parse_identifier()?];
+        while self.consume(&TokenKind::Comma) {
+            names.push(self.parse_identifier()?);
+        }
+        self.expect(TokenKind::RParen)?;
+        Ok(names)
+    }
+
+    fn parse_statement(&mut self) -> Result<SqlStatement> {
+        if self.is_keyword("select") {
+            Ok(SqlStatement::Select(self.parse_select()?))
+        } else if self.is_keyword("insert") {
+            self.parse_insert()
+        } else if self.is_keyword("update") {
+            self.parse_update()
+        } else if self.is_keyword("delete") {
+            self.parse_delete()
+        } else if self.is_keyword("create") {
+            self.parse_create()
+        } else if self.is_keyword("drop") {
+            self.parse_drop()
+        } else if self.is_keyword("alter") {
+            self.parse_alter()
+        } else if self.is_keyword("show") {
+            self.parse_show()
+        } else if self.parse_keyword("explain") {
+            let analyze = self.parse_keyword("analyze");
+            Ok(SqlStatement::Explain { statement: Box::new(self.parse_statement()?), analyze })
+        } else if self.parse_keyword("analyze") {
+            let table = match self.peek_kind() {
+                TokenKind::Eof | TokenKind::Semicolon => None,
+                _ => Some(self.parse_table_name()?),
             };
-            
-            let (table, where_clause) = if let Some(where_pos) = after_from.find(" where ") {
-                let table = after_from[..where_pos].trim().to_string();
-                let where_part = after_from[where_pos + 7..].trim().to_string();
-                (table, Some(where_part))
+            Ok(SqlStatement::Analyze { table })
+        } else if self.parse_keyword("describe") || self.parse_keyword("desc") {
+            let table = self.parse_table_name()?;
+            Ok(SqlStatement::ShowColumns { table, where_clause: None })
+        } else if self.parse_keyword("begin") {
+            self.skip_transaction_keyword();
+            self.parse_begin()
+        } else if self.parse_keyword("start") {
+            self.expect_keyword("transaction")?;
+            self.parse_begin()
+        } else if self.parse_keyword("commit") {
+            self.skip_transaction_keyword();
+            Ok(SqlStatement::Commit)
+        } else if self.parse_keyword("rollback") {
+            self.skip_transaction_keyword();
+            Ok(SqlStatement::Rollback)
+        } else {
+            self.error("statement")
+        }
+    }
+
+    /// The optional TRANSACTION or WORK after BEGIN, COMMIT and ROLLBACK
+    fn skip_transaction_keyword(&mut self) {
+        if !self.parse_keyword("transaction") {
+            self.parse_keyword("work");
+        }
+    }
+
+    fn parse_begin(&mut self) -> Result<SqlStatement> {
+        let isolation_level = if self.parse_keyword("isolation") {
+            self.expect_keyword("level")?;
+            Some(self.parse_isolation_level()?)
+        } else {
+            None
+        };
+        Ok(SqlStatement::Begin { isolation_level })
+    }
+
+    fn parse_isolation_level(&mut self) -> Result<IsolationLevel> {
+        if self.parse_keyword("serializable") {
+            Ok(IsolationLevel::Serializable)
+        } else if self.parse_keyword("repeatable") {
+            self.expect_keyword("read")?;
+            Ok(IsolationLevel::RepeatableRead)
+        } else if self.parse_keyword("read") {
+            if self.parse_keyword("committed") {
+                Ok(IsolationLevel::ReadCommitted)
+            } else if self.parse_keyword("uncommitted") {
+                Ok(IsolationLevel::ReadUncommitted)
             } else {
-                (after_from.to_string(), None)
-            };
-            
-            Ok(SqlStatement::Select {
-                columns,
-                table,
-                where_clause,
-            })
+                self.error("COMMITTED or UNCOMMITTED")
+            }
         } else {
-            Err(QueryError::Parse("Invalid SELECT statement".to_string()))
+            self.error("isolation level")
         }
     }
-    
-    fn parse_insert(_sql: &str) -> Result<SqlStatement> {
-        // Simplified INSERT parsing
-        Err(QueryError::Parse("INSERT not implemented yet".to_string()))
+
+    fn parse_select(&mut self) -> Result<SelectStatement> {
+        self.expect_keyword("select")?;
+
+        let mut columns = vec![self.parse_select_item()?];
+        while self.consume(&TokenKind::Comma) {
+            columns.push(self.parse_select_item()?);
+        }
+
+        let table = if self.parse_keyword("from") {
+            Some(self.parse_table_name()?)
+        } else {
+            None
+        };
+
+        let where_clause = self.parse_where()?;
+
+        let mut group_by = Vec::new();
+        if self.parse_keyword("group") {
+            self.expect_keyword("by")?;
+            group_by.push(self.parse_expr()?);
+            while self.consume(&TokenKind::Comma) {
+                group_by.push(self.parse_expr()?);
+            }
+        }
+
+        let having = if self.parse_keyword("having") {
+            Some(self.parse_expr()?)
+        } else {
+            None
+        };
+
+        let mut order_by = Vec::new();
+        if self.parse_keyword("order") {
+            self.expect_keyword("by")?;
+            loop {
+                let expr = self.parse_expr()?;
+                let descending = if self.parse_keyword("desc") {
+                    true
+                } else {
+                    self.parse_keyword("asc");
+                    false
+                };
+                order_by.push(OrderByExpr { expr, descending });
+                if !self.consume(&TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+
+        let limit = if self.parse_keyword("limit") {
+            Some(self.parse_unsigned("LIMIT")?)
+        } else {
+            None
+        };
+        let offset = if self.parse_keyword("offset") {
+            Some(self.parse_unsigned("OFFSET")?)
+        } else {
+            None
+        };
+
+        Ok(SelectStatement {
+            columns,
+            table,
+            where_clause,
+            group_by,
+            having,
+            order_by,
+            limit,
+            offset,
+        })
     }
-    
-    fn parse_update(_sql: &str) -> Result<SqlStatement> {
-        // Simplified UPDATE parsing
-        Err(QueryError::Parse("UPDATE not implemented yet".to_string()))
+
+    fn parse_select_item(&mut self) -> Result<SelectItem> {
+        if self.consume(&TokenKind::Star) {
+            return Ok(SelectItem::Wildcard);
+        }
+
+        let expr = self.parse_expr()?;
+        let explicit_alias = self.parse_keyword("as");
+        let alias = if explicit_alias
+            || matches!(self.peek_kind(), TokenKind::Ident { value, quoted } if *quoted || !is_reserved(value))
+        {
+            Some(self.parse_identifier()?)
+        } else {
+            None
+        };
+
+        Ok(SelectItem::Expr { expr, alias })
     }
-    
-    fn parse_delete(_sql: &str) -> Result<SqlStatement> {
-        // Simplified DELETE parsing
-        Err(QueryError::Parse("DELETE not implemented yet".to_string()))
+
+    fn parse_where(&mut self) -> Result<Option<Expr>> {
+        if self.parse_keyword("where") {
+            Ok(Some(self.parse_expr()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn parse_unsigned(&mut self, clause: &str) -> Result<u64> {
+        if let TokenKind::Number(n) = self.peek_kind() {
+            if let Ok(value) = n.parse::<u64>() {
+                self.advance();
+                return Ok(value);
+            }
+        }
+        self.error(&format!("non-negative integer after {}", clause))
     }
+
+    fn parse_insert(&mut self) -> Result<SqlStatement> {
+        self.expect_keyword("insert")?;
+        self.expect_keyword("into")?;
+        let table = self.parse_table_name()?;
+
+        let columns = if *self.peek_kind() == TokenKind::LParen {
+            self.parse_identifier_list()?
+        } else {
+            Vec::new()
+        };
+
+        if columns.is_empty() && self.parse_keyword("default") {
+            self.expect_keyword("values")?;
+            return Ok(SqlStatement::Insert { table, columns, values: vec![Vec::new()] });
+        }
+
+        self.expect_keyword("values")?;
+        let mut values = Vec::new();
+        loop {
+            self.expect(TokenKind::LParen)?;
+            let mut row = vec![self.parse_insert_value()?];
+            while self.consume(&TokenKind::Comma) {
+                row.push(self.parse_insert_value()?);
+            }
+            self.expect(TokenKind::RParen)?;
+            values.push(row);
+            if !self.consume(&TokenKind::Comma) {
+                break;
+            }
+        }
+
+        Ok(SqlStatement::Insert { table, columns, values })
+    }
+
+    fn parse_insert_value(&mut self) -> Result<Expr> {
+        if self.parse_keyword("default") {
+            Ok(Expr::Default)
+        } else {
+            self.parse_expr()
+        }
+    }
+
+    fn parse_update(&mut self) -> Result<SqlStatement> {
+        self.expect_keyword("update")?;
+        let table = self.parse_table_name()?;
+        self.expect_keyword("set")?;
+
+        let mut set_clause = Vec::new();
+        loop {
+            let column = self.parse_identifier()?;
+            self.expect(TokenKind::Eq)?;
+            set_clause.push((column, self.parse_expr()?));
+            if !self.consume(&TokenKind::Comma) {
+                break;
+            }
+        }
+
+        let where_clause = self.parse_where()?;
+        Ok(SqlStatement::Update { table, set_clause, where_clause })
+    }
+
+    fn parse_delete(&mut self) -> Result<SqlStatement> {
+        self.expect_keyword("delete")?;
+        self.expect_keyword("from")?;
+        let table = self.parse_table_name()?;
+        let where_clause = self.parse_where()?;
+        Ok(SqlStatement::Delete { table, where_clause })
+    }
+
+    fn parse_if_not_exists(&mut self) -> Result<bool> {
+        if self.parse_keyword("if") {
+            self.expect_keyword("not")?;
+            self.expect_keyword("exists")?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn parse_create(&mut self) -> Result<SqlStatement> {
+        self.expect_keyword("create")?;
+
+        if self.parse_keyword("table") {
+            return self.parse_create_table();
+        }
+        if self.parse_keyword("database") {
+            let if_not_exists = self.parse_if_not_exists()?;
+            let name = self.parse_identifier()?;
+            return Ok(SqlStatement::CreateDatabase { name, if_not_exists });
+        }
+
+        let unique = self.parse_keyword("unique");
+        if self.parse_keyword("index") {
+            let name = self.parse_identifier()?;
+            self.expect_keyword("on")?;
+            let table = self.parse_table_name()?;
+            let columns = self.parse_identifier_list()?;
+            return Ok(SqlStatement::CreateIndex { name, table, columns, unique });
+        }
+
+        if unique {
+            self.error("INDEX")
+        } else {
+            self.error("TABLE, INDEX or DATABASE")
+        }
+    }
+
+    fn parse_create_table(&mut self) -> Result<SqlStatement> {
+        let if_not_exists = self.parse_if_not_exists()?;
+        let name = self.parse_table_name()?;
+
+        self.expect(TokenKind::LParen)?;
+        let mut columns = Vec::new();
+        let mut primary_key = Vec::new();
+        loop {
+            if self.parse_keyword("primary") {
+                self.expect_keyword("key")?;
+                if !primary_key.is_empty() {
+                    return self.error("a single PRIMARY KEY definition");
+                }
+                primary_key = self.parse_identifier_list()?;
+            } else {
+                columns.push(self.parse_column_def()?);
+            }
+            if !self.consume(&TokenKind::Comma) {
+                break;
+            }
+        }
+        self.expect(TokenKind::RParen)?;
+
+        let inline_keys: Vec<String> = columns.iter()
+            .filter(|c| c.primary_key)
+            .map(|c| c.name.clone())
+            .collect();
+        if !inline_keys.is_empty() {
+            if !primary_key.is_empty() || inline_keys.len() > 1 {
+                return Err(QueryError::Parse(format!("Multiple primary keys for table {}", name)));
+            }
+            primary_key = inline_keys;
+        }
+
+        Ok(SqlStatement::CreateTable { name, columns, primary_key, if_not_exists })
+    }
+
+    fn parse_column_def(&mut self) -> Result<ColumnDef> {
+        let name = self.parse_identifier()?;
+        let data_type = self.parse_data_type()?;
+
+        let mut column = ColumnDef {
+            name,
+            data_type,
+            nullable: true,
+            primary_key: false,
+            unique: false,
+            default: None,
+        };
+
+        loop {
+            if self.parse_keyword("primary") {
+                self.expect_keyword("key")?;
+                column.primary_key = true;
+                column.nullable = false;
+            } else if self.parse_keyword("not") {
+                self.expect_keyword("null")?;
+                column.nullable = false;
+            } else if self.parse_keyword("null") {
+                if column.primary_key {
+                    return self.error("NOT NULL for a primary key column");
+                }
+                column.nullable = true;
+            } else if self.parse_keyword("unique") {
+                column.unique = true;
+            } else if self.parse_keyword("default") {
+                column.default = Some(self.parse_expr()?);
+            } else {
+                break;
+            }
+        }
+
+        Ok(column)
+    }
+
+    fn parse_data_type(&mut self) -> Result<DataType> {
+        let name = match self.peek_kind() {
+            TokenKind::Ident { value, quoted: false } => value.to_lowercase(),
+            _ => return self.error("data type"),
+        };
+
+        let data_type = match DataType::from_name(&name) {
+            Some(data_type) => data_type,
+            None => return self.error("data type"),
+        };
+        self.advance();
+
+        if data_type == DataType::Float && name == "double" {
+            self.parse_keyword("precision");
+        }
+
+        // Length/precision modifiers such as VARCHAR(255) are accepted and ignored
+        if self.consume(&TokenKind::LParen) {
+            self.parse_unsigned("type modifier")?;
+            if self.consume(&TokenKind::Comma) {
+                self.parse_unsigned("type modifier")?;
+            }
+            self.expect(TokenKind::RParen)?;
+        }
+
+        Ok(data_type)
+    }
+
+    fn parse_drop(&mut self) -> Result<SqlStatement> {
+        self.expect_keyword("drop")?;
+        let database = if self.parse_keyword("database") {
+            true
+        } else {
+            self.expect_keyword("table")?;
+            false
+        };
+        let if_exists = if self.parse_keyword("if") {
+            self.expect_keyword("exists")?;
+            true
+        } else {
+            false
+        };
+        if database {
+            let name = self.parse_identifier()?;
+            return Ok(SqlStatement::DropDatabase { name, if_exists });
+        }
+        let name = self.parse_table_name()?;
+        Ok(SqlStatement::DropTable { name, if_exists })
+    }
+
+    fn parse_alter(&mut self) -> Result<SqlStatement> {
+        self.expect_keyword("alter")?;
+        self.expect_keyword("table")?;
+        let table = self.parse_table_name()?;
+
+        let operation = if self.parse_keyword("add") {
+            self.parse_keyword("column");
+            AlterTableOperation::AddColumn(self.parse_column_def()?)
+        } else if self.parse_keyword("drop") {
+            self.parse_keyword("column");
+            AlterTableOperation::DropColumn { name: self.parse_identifier()? }
+        } else {
+            return self.error("ADD or DROP");
+        };
+
+        Ok(SqlStatement::AlterTable { table, operation })
+    }
+
+    fn parse_show(&mut self) -> Result<SqlStatement> {
+        self.expect_keyword("show")?;
+
+        if self.parse_keyword("tables") {
+            let where_clause = self.parse_where()?;
+            Ok(SqlStatement::ShowTables { where_clause })
+        } else if self.parse_keyword("columns") {
+            self.expect_keyword("from")?;
+            let table = self.parse_table_name()?;
+            let where_clause = self.parse_where()?;
+            Ok(SqlStatement::ShowColumns { table, where_clause })
+        } else {
+            self.error("TABLES or COLUMNS")
+        }
+    }
+
+    // Expression grammar, lowest precedence first:
+    //   OR < AND < NOT < comparison / IS NULL / IN < || < + - < * / % < unary - +
+
+    fn parse_expr(&mut self) -> Result<Expr> {
+        self.parse_or()
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut left = self.parse_and()?;
+        while self.parse_keyword("or") {
+            let right = self.parse_and()?;
+            left = binary(left, BinaryOp::Or, right);
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut left = self.parse_not()?;
+        while self.parse_keyword("and") {
+            let right = self.parse_not()?;
+            left = binary(left, BinaryOp::And, right);
+        }
+        Ok(left)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.parse_keyword("not") {
+            let expr = self.parse_not()?;
+            return Ok(Expr::Unary { op: UnaryOp::Not, expr: Box::new(expr) });
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr> {
+        let left = self.parse_concat()?;
+
+        if self.parse_keyword("is") {
+            let negated = self.parse_keyword("not");
+            self.expect_keyword("null")?;
+            return Ok(Expr::IsNull { expr: Box::new(left), negated });
+        }
+
+        if self.is_keyword("in") || (self.is_keyword("not") && self.is_next_keyword("in")) {
+            let negated = self.parse_keyword("not");
+            self.expect_keyword("in")?;
+            self.expect(TokenKind::LParen)?;
+            if !self.is_keyword("select") {
+                let mut list = vec![self.parse_expr()?];
+                while self.consume(&TokenKind::Comma) {
+                    list.push(self.parse_expr()?);
+                }
+                self.expect(TokenKind::RParen)?;
+                return Ok(Expr::InList { expr: Box::new(left), list, negated });
+            }
+            let subquery = self.parse_select()?;
+            self.expect(TokenKind::RParen)?;
+            return Ok(Expr::InSubquery { expr: Box::new(left), subquery: Box::new(subquery), negated });
+        }
+
+        let op = match self.peek_kind() {
+            TokenKind::Eq => BinaryOp::Eq,
+            TokenKind::NotEq => BinaryOp::NotEq,
+            TokenKind::Lt => BinaryOp::Lt,
+            TokenKind::LtEq => BinaryOp::LtEq,
+            TokenKind::Gt => BinaryOp::Gt,
+            TokenKind::GtEq => BinaryOp::GtEq,
+            _ => return Ok(left),
+        };
+        self.advance();
+
+        let right = self.parse_concat()?;
+        Ok(binary(left, op, right))
+    }
+
+    fn parse_concat(&mut self) -> Result<Expr> {
+        let mut left = self.parse_additive()?;
+        while self.consume(&TokenKind::Concat) {
+            let right = self.parse_additive()?;
+            left = binary(left, BinaryOp::Concat, right);
+        }
+        Ok(left)
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr> {
+        let mut left = self.parse_multiplicative()?;
+        loop {
+            let op = match self.peek_kind() {
+                TokenKind::Plus => BinaryOp::Plus,
+                TokenKind::Minus => BinaryOp::Minus,
+                _ => return Ok(left),
+            };
+            self.advance();
+            let right = self.parse_multiplicative()?;
+            left = binary(left, op, right);
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr> {
+        let mut left = self.parse_unary()?;
+        loop {
+            let op = match self.peek_kind() {
+                TokenKind::Star => BinaryOp::Multiply,
+                TokenKind::Slash => BinaryOp::Divide,
+                TokenKind::Percent => BinaryOp::Modulo,
+                _ => return Ok(left),
+            };
+            self.advance();
+            let right = self.parse_unary()?;
+            left = binary(left, op, right);
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        let op = match self.peek_kind() {
+            TokenKind::Minus => UnaryOp::Minus,
+            TokenKind::Plus => UnaryOp::Plus,
+            _ => return self.parse_primary(),
+        };
+        self.advance();
+
+        let expr = self.parse_unary()?;
+        // Fold signs into numeric literals so `-5` is a plain literal
+        match (op, expr) {
+            (UnaryOp::Minus, Expr::Literal(Literal::Integer(i))) => Ok(Expr::Literal(Literal::Integer(-i))),
+            (UnaryOp::Minus, Expr::Literal(Literal::Float(f))) => Ok(Expr::Literal(Literal::Float(-f))),
+            (op, expr) => Ok(Expr::Unary { op, expr: Box::new(expr) }),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        match self.peek_kind().clone() {
+            TokenKind::Number(n) => {
+                let literal = if n.contains(['.', 'e', 'E']) {
+                    n.parse::<f64>().map(Literal::Float).ok()
+                } else {
+                    n.parse::<i64>().map(Literal::Integer).ok()
+                };
+                match literal {
+                    Some(literal) => {
+                        self.advance();
+                        Ok(Expr::Literal(literal))
+                    }
+                    None => self.error("number in range"),
+                }
+            }
+            TokenKind::String(s) => {
+                self.advance();
+                Ok(Expr::Literal(Literal::String(s)))
+            }
+            TokenKind::Blob(bytes) => {
+                self.advance();
+                Ok(Expr::Literal(Literal::Blob(bytes)))
+            }
+            TokenKind::Parameter(n) => {
+                self.advance();
+                Ok(Expr::Parameter(n))
+            }
+            TokenKind::LParen => {
+                self.advance();
+                let expr = self.parse_expr()?;
+                self.expect(TokenKind::RParen)?;
+                Ok(expr)
+            }
+            TokenKind::Ident { value, quoted: false } if value.eq_ignore_ascii_case("null") => {
+                self.advance();
+                Ok(Expr::Literal(Literal::Null))
+            }
+            TokenKind::Ident { value, quoted: false } if value.eq_ignore_ascii_case("true") => {
+                self.advance();
+                Ok(Expr::Literal(Literal::Boolean(true)))
+            }
+            TokenKind::Ident { value, quoted: false } if value.eq_ignore_ascii_case("false") => {
+                self.advance();
+                Ok(Expr::Literal(Literal::Boolean(false)))
+            }
+            TokenKind::Ident { value, quoted: false }
+                if value.eq_ignore_ascii_case("timestamp")
+                    && matches!(self.tokens.get(self.pos + 1).map(|t| &t.kind), Some(TokenKind::String(_))) =>
+            {
+                self.advance();
+                let TokenKind::String(text) = self.peek_kind() else { unreachable!() };
+                match value::parse_timestamp(text) {
+                    Ok(micros) => {
+                        self.advance();
+                        Ok(Expr::Literal(Literal::Timestamp(micros)))
+                    }
+                    Err(_) => self.error("ISO-8601 timestamp"),
+                }
+            }
+            TokenKind::Ident { value, quoted: false }
+                if value.eq_ignore_ascii_case("current_timestamp") && !self.next_is(&TokenKind::LParen) =>
+            {
+                self.advance();
+                Ok(Expr::Function { name: "now".to_string(), args: Vec::new() })
+            }
+            TokenKind::Ident { value, quoted: false } if !is_reserved(&value) && self.next_is(&TokenKind::LParen) => {
+                self.parse_function()
+            }
+            TokenKind::Ident { .. } => match self.parse_identifier() {
+                Ok(name) => Ok(Expr::Column(name)),
+                Err(_) => self.error("expression"),
+            },
+            _ => self.error("expression"),
+        }
+    }
+
+    /// Function call. Names are resolved by the planner, except aggregates.
+    fn parse_function(&mut self) -> Result<Expr> {
+        let name = self.parse_identifier()?;
+        self.expect(TokenKind::LParen)?;
+
+        let Some(func) = AggregateFunc::from_name(&name) else {
+            let mut args = Vec::new();
+            if name == "extract" && self.is_next_keyword("from") {
+                // EXTRACT(field FROM expr) is extract('field', expr)
+                let field = self.parse_identifier()?;
+                self.expect_keyword("from")?;
+                args.push(Expr::Literal(Literal::String(field)));
+                args.push(self.parse_expr()?);
+                self.expect(TokenKind::RParen)?;
+            } else if !self.consume(&TokenKind::RParen) {
+                args.push(self.parse_expr()?);
+                while self.consume(&TokenKind::Comma) {
+                    args.push(self.parse_expr()?);
+                }
+                self.expect(TokenKind::RParen)?;
+            }
+            return Ok(Expr::Function { name, args });
+        };
+
+        let arg = if func == AggregateFunc::Count && self.consume(&TokenKind::Star) {
+            None
+        } else {
+            Some(Box::new(self.parse_expr()?))
+        };
+        self.expect(TokenKind::RParen)?;
+        Ok(Expr::Aggregate { func, arg })
+    }
+}
+
+fn is_reserved(word: &str) -> bool {
+    RESERVED.iter().any(|k| k.eq_ignore_ascii_case(word))
+}
+
+fn binary(left: Expr, op: BinaryOp, right: Expr) -> Expr {
+    Expr::Binary { left: Box::new(left), op, right: Box::new(right) }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+
+    fn col(name: &str) -> Expr {
+        Expr::Column(name.to_string())
+    }
+
+    fn int(i: i64) -> Expr {
+        Expr::Literal(Literal::Integer(i))
+    }
+
+    fn string(s: &str) -> Expr {
+        Expr::Literal(Literal::String(s.to_string()))
+    }
+
+    fn select(sql: &str) -> SelectStatement {
+        match SqlParser::parse(sql).unwrap() {
+            SqlStatement::Select(select) => select,
+            other => panic!("Expected SELECT statement, got {:?}", other),
+        }
+    }
+
     #[test]
     fn test_parse_simple_select() {
         let sql = "SELECT * FROM users";
-        let result = SqlParser::parse(sql).unwrap();
-        
-        match result {
-            SqlStatement::Select { columns, table, where_clause } => {
-                assert_eq!(columns, vec!["*".to_string()]);
-                assert_eq!(table, "users");
-                assert_eq!(where_clause, None);
-            }
-            _ => panic!("Expected SELECT statement"),
-        }
+        let select = select(sql);
+
+        assert_eq!(select.columns, vec![SelectItem::Wildcard]);
+        assert_eq!(select.table, Some("users".to_string()));
+        assert_eq!(select.where_clause, None);
     }
-    
+
     #[test]
     fn test_parse_select_with_columns() {
         let sql = "SELECT id, name FROM users";
-        let result = SqlParser::parse(sql).unwrap();
-        
-        match result {
-            SqlStatement::Select { columns, table, where_clause } => {
-                assert_eq!(columns, vec!["id".to_string(), "name".to_string()]);
-                assert_eq!(table, "users");
-                assert_eq!(where_clause, None);
-            }
-            _ => panic!("Expected SELECT statement"),
-        }
+        let select = select(sql);
+
+        assert_eq!(select.columns, vec![
+            SelectItem::Expr { expr: col("id"), alias: None },
+            SelectItem::Expr { expr: col("name"), alias: None },
+        ]);
+        assert_eq!(select.table, Some("users".to_string()));
+        assert_eq!(select.where_clause, None);
     }
-    
+
     #[test]
     fn test_parse_select_with_where() {
         let sql = "SELECT * FROM users WHERE id = 1";
-        let result = SqlParser::parse(sql).unwrap();
-        
-        match result {
-            SqlStatement::Select { columns, table, where_clause } => {
-                assert_eq!(columns, vec!["*".to_string()]);
-                assert_eq!(table, "users");
-                assert_eq!(where_clause, Some("id = 1".to_string()));
+        let select = select(sql);
+
+        assert_eq!(select.columns, vec![SelectItem::Wildcard]);
+        assert_eq!(select.table, Some("users".to_string()));
+        assert_eq!(select.where_clause.map(|w| w.to_string()), Some("id = 1".to_string()));
+    }
+
+    #[test]
+    fn test_comments_and_trailing_semicolon() {
+        let sql = "-- find one user\nSELECT id, /* the display name */ name\nFROM users -- no joins\nWHERE id = 1; -- done";
+        let select = select(sql);
+
+        assert_eq!(select.columns.len(), 2);
+        assert_eq!(select.table, Some("users".to_string()));
+        assert_eq!(select.where_clause.map(|w| w.to_string()), Some("id = 1".to_string()));
+        assert_eq!(SqlParser::parse("DELETE FROM users;").unwrap(), SqlParser::parse("DELETE FROM users").unwrap());
+    }
+
+    #[test]
+    fn test_parse_many() {
+        let script = "CREATE TABLE notes (id INT PRIMARY KEY, body TEXT);\n\
+                      INSERT INTO notes VALUES (1, 'a; b'), (2, 'it''s;');;\n\
+                      -- a comment; not a statement\n\
+                      SELECT \"odd;name\" FROM notes /* ; */ WHERE body = ';'";
+        let statements = SqlParser::parse_many(script).unwrap();
+
+        assert_eq!(statements.len(), 3);
+        assert!(matches!(statements[0], SqlStatement::CreateTable { .. }));
+        let SqlStatement::Insert { values, .. } = &statements[1] else {
+            panic!("expected INSERT, got {:?}", statements[1]);
+        };
+        assert_eq!(values[0][1], string("a; b"));
+        assert_eq!(values[1][1], string("it's;"));
+        assert_eq!(statements[2], SqlParser::parse("SELECT \"odd;name\" FROM notes WHERE body = ';'").unwrap());
+
+        assert_eq!(SqlParser::parse_many("").unwrap(), vec![]);
+        assert_eq!(SqlParser::parse_many(" ; -- nothing\n;").unwrap(), vec![]);
+        match SqlParser::parse_many("SELECT 1; SELEC 2; SELECT 3") {
+            Err(QueryError::Parse(msg)) => assert_eq!(msg, "Expected statement, found SELEC at line 1, column 11"),
+            other => panic!("expected parse error, got {:?}", other),
+        }
+        match SqlParser::parse_many("SELECT 1 SELECT 2") {
+            Err(QueryError::Parse(msg)) => assert_eq!(msg, "Expected end of statement, found SELECT at line 1, column 10"),
+            other => panic!("expected parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_literals_and_identifiers_keep_case() {
+        let select = select("select Name, \"CamelCase\" FROM Users WHERE title = 'Hello FROM World'");
+
+        assert_eq!(select.columns, vec![
+            SelectItem::Expr { expr: col("name"), alias: None },
+            SelectItem::Expr { expr: col("CamelCase"), alias: None },
+        ]);
+        assert_eq!(select.table, Some("users".to_string()));
+        assert_eq!(select.where_clause, Some(Expr::Binary {
+            left: Box::new(col("title")),
+            op: BinaryOp::Eq,
+            right: Box::new(string("Hello FROM World")),
+        }));
+    }
+
+    #[test]
+    fn test_expression_precedence() {
+        let cases = [
+            ("a + b * c", "a + (b * c)"),
+            ("(a + b) * c", "(a + b) * c"),
+            ("a = 1 OR b = 2 AND c = 3", "(a = 1) OR ((b = 2) AND (c = 3))"),
+            ("NOT a = 1 AND b", "NOT (a = 1) AND b"),
+            ("a || b = 'xy'", "(a || b) = 'xy'"),
+            ("-a - -2", "-a - -2"),
+            ("x IS NOT NULL OR y IS NULL", "(x IS NOT NULL) OR (y IS NULL)"),
+            ("a % 2 <> 0", "(a % 2) <> 0"),
+            ("a NOT IN (1, b + 1) OR c", "(a NOT IN (1, b + 1)) OR c"),
+        ];
+
+        for (input, expected) in cases {
+            let select = select(&format!("SELECT * FROM t WHERE {}", input));
+            assert_eq!(select.where_clause.unwrap().to_string(), expected, "parsing {:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_valid_statement_corpus() {
+        let cases: Vec<(&str, SqlStatement)> = vec![
+            (
+                "SELECT id AS user_id, age + 1 next_age FROM users ORDER BY age DESC, id LIMIT 10 OFFSET 20",
+                SqlStatement::Select(SelectStatement {
+                    columns: vec![
+                        SelectItem::Expr { expr: col("id"), alias: Some("user_id".to_string()) },
+                        SelectItem::Expr {
+                            expr: binary(col("age"), BinaryOp::Plus, int(1)),
+                            alias: Some("next_age".to_string()),
+                        },
+                    ],
+                    table: Some("users".to_string()),
+                    where_clause: None,
+                    group_by: vec![],
+                    having: None,
+                    order_by: vec![
+                        OrderByExpr { expr: col("age"), descending: true },
+                        OrderByExpr { expr: col("id"), descending: false },
+                    ],
+                    limit: Some(10),
+                    offset: Some(20),
+                }),
+            ),
+            (
+                "select 1",
+                SqlStatement::Select(SelectStatement {
+                    columns: vec![SelectItem::Expr { expr: int(1), alias: None }],
+                    table: None,
+                    where_clause: None,
+                    group_by: vec![],
+                    having: None,
+                    order_by: vec![],
+                    limit: None,
+                    offset: None,
+                }),
+            ),
+            (
+                "SELECT\n    *\nFROM\n    users -- every user\nWHERE\n    /* only adults */ age >= 18",
+                SqlStatement::Select(SelectStatement {
+                    columns: vec![SelectItem::Wildcard],
+                    table: Some("users".to_string()),
+                    where_clause: Some(binary(col("age"), BinaryOp::GtEq, int(18))),
+                    group_by: vec![],
+                    having: None,
+                    order_by: vec![],
+                    limit: None,
+                    offset: None,
+                }),
+            ),
+            (
+                "SELECT*FROM users WHERE id>=5",
+                SqlStatement::Select(SelectStatement {
+                    columns: vec![SelectItem::Wildcard],
+                    table: Some("users".to_string()),
+                    where_clause: Some(binary(col("id"), BinaryOp::GtEq, int(5))),
+                    group_by: vec![],
+                    having: None,
+                    order_by: vec![],
+                    limit: None,
+                    offset: None,
+                }),
+            ),
+            (
+                "SELECT region, count(*) FROM sales GROUP BY region HAVING SUM(amount) > 100",
+                SqlStatement::Select(SelectStatement {
+                    columns: vec![
+                        SelectItem::Expr { expr: col("region"), alias: None },
+                        SelectItem::Expr { expr: Expr::Aggregate { func: AggregateFunc::Count, arg: None }, alias: None },
+                    ],
+                    table: Some("sales".to_string()),
+                    where_clause: None,
+                    group_by: vec![col("region")],
+                    having: Some(binary(
+                        Expr::Aggregate { func: AggregateFunc::Sum, arg: Some(Box::new(col("amount"))) },
+                        BinaryOp::Gt,
+                        int(100),
+                    )),
+                    order_by: vec![],
+                    limit: None,
+                    offset: None,
+                }),
+            ),
+            (
+                "SELECT * FROM orders WHERE user_id NOT IN (SELECT id FROM users WHERE active = TRUE)",
+                SqlStatement::Select(SelectStatement {
+                    columns: vec![SelectItem::Wildcard],
+                    table: Some("orders".to_string()),
+                    where_clause: Some(Expr::InSubquery {
+                        expr: Box::new(col("user_id")),
+                        subquery: Box::new(SelectStatement {
+                            columns: vec![SelectItem::Expr { expr: col("id"), alias: None }],
+                            table: Some("users".to_string()),
+                            where_clause: Some(binary(
+                                col("active"),
+                                BinaryOp::Eq,
+                                Expr::Literal(Literal::Boolean(true)),
+                            )),
+                            group_by: vec![],
+                            having: None,
+                            order_by: vec![],
+                            limit: None,
+                            offset: None,
+                        }),
+                        negated: true,
+                    }),
+                    group_by: vec![],
+                    having: None,
+                    order_by: vec![],
+                    limit: None,
+                    offset: None,
+                }),
+            ),
+            (
+                "SELECT * FROM orders WHERE id IN (1, 2 + 1) AND status NOT IN ('void')",
+                SqlStatement::Select(SelectStatement {
+                    columns: vec![SelectItem::Wildcard],
+                    table: Some("orders".to_string()),
+                    where_clause: Some(binary(
+                        Expr::InList {
+                            expr: Box::new(col("id")),
+                            list: vec![int(1), binary(int(2), BinaryOp::Plus, int(1))],
+                            negated: false,
+                        },
+                        BinaryOp::And,
+                        Expr::InList { expr: Box::new(col("status")), list: vec![string("void")], negated: true },
+                    )),
+                    group_by: vec![],
+                    having: None,
+                    order_by: vec![],
+                    limit: None,
+                    offset: None,
+                }),
+            ),
+            (
+                "INSERT INTO users (id, name) VALUES (1, 'Alice'), (2, 'O''Brien')",
+                SqlStatement::Insert {
+                    table: "users".to_string(),
+                    columns: vec!["id".to_string(), "name".to_string()],
+                    values: vec![
+                        vec![int(1), string("Alice")],
+                        vec![int(2), string("O'Brien")],
+                    ],
+                },
+            ),
+            (
+                "INSERT INTO t (a, b) VALUES (DEFAULT, 2), (now(), default)",
+                SqlStatement::Insert {
+                    table: "t".to_string(),
+                    columns: vec!["a".to_string(), "b".to_string()],
+                    values: vec![
+                        vec![Expr::Default, int(2)],
+                        vec![Expr::Function { name: "now".to_string(), args: vec![] }, Expr::Default],
+                    ],
+                },
+            ),
+            (
+                "INSERT INTO t DEFAULT VALUES",
+                SqlStatement::Insert { table: "t".to_string(), columns: vec![], values: vec![vec![]] },
+            ),
+            (
+                "insert into t values (-1.5, NULL, true)",
+                SqlStatement::Insert {
+                    table: "t".to_string(),
+                    columns: vec![],
+                    values: vec![vec![
+                        Expr::Literal(Literal::Float(-1.5)),
+                        Expr::Literal(Literal::Null),
+                        Expr::Literal(Literal::Boolean(true)),
+                    ]],
+                },
+            ),
+            (
+                "UPDATE users SET name = 'Bob', age = age + 1 WHERE id = 2",
+                SqlStatement::Update {
+                    table: "users".to_string(),
+                    set_clause: vec![
+                        ("name".to_string(), string("Bob")),
+                        ("age".to_string(), binary(col("age"), BinaryOp::Plus, int(1))),
+                    ],
+                    where_clause: Some(binary(col("id"), BinaryOp::Eq, int(2))),
+                },
+            ),
+            (
+                "DELETE FROM users",
+                SqlStatement::Delete { table: "users".to_string(), where_clause: None },
+            ),
+            (
+                "CREATE TABLE IF NOT EXISTS \"Accounts\" (\n  id BIGINT PRIMARY KEY,\n  owner VARCHAR(64) NOT NULL,\n  balance DOUBLE PRECISION,\n  active BOOLEAN NULL,\n  avatar BYTEA\n)",
+                SqlStatement::CreateTable {
+                    name: "Accounts".to_string(),
+                    columns: vec![
+                        ColumnDef { name: "id".to_string(), data_type: DataType::Integer, nullable: false, primary_key: true, unique: false, default: None },
+                        ColumnDef { name: "owner".to_string(), data_type: DataType::Text, nullable: false, primary_key: false, unique: false, default: None },
+                        ColumnDef { name: "balance".to_string(), data_type: DataType::Float, nullable: true, primary_key: false, unique: false, default: None },
+                        ColumnDef { name: "active".to_string(), data_type: DataType::Boolean, nullable: true, primary_key: false, unique: false, default: None },
+                        ColumnDef { name: "avatar".to_string(), data_type: DataType::Blob, nullable: true, primary_key: false, unique: false, default: None },
+                    ],
+                    primary_key: vec!["id".to_string()],
+                    if_not_exists: true,
+                },
+            ),
+            (
+                "CREATE TABLE pairs (a INT, b INT, PRIMARY KEY (a, b))",
+                SqlStatement::CreateTable {
+                    name: "pairs".to_string(),
+                    columns: vec![
+                        ColumnDef { name: "a".to_string(), data_type: DataType::Integer, nullable: true, primary_key: false, unique: false, default: None },
+                        ColumnDef { name: "b".to_string(), data_type: DataType::Integer, nullable: true, primary_key: false, unique: false, default: None },
+                    ],
+                    primary_key: vec!["a".to_string(), "b".to_string()],
+                    if_not_exists: false,
+                },
+            ),
+            (
+                "CREATE TABLE users (id INT PRIMARY KEY, email TEXT UNIQUE NOT NULL)",
+                SqlStatement::CreateTable {
+                    name: "users".to_string(),
+                    columns: vec![
+                        ColumnDef { name: "id".to_string(), data_type: DataType::Integer, nullable: false, primary_key: true, unique: false, default: None },
+                        ColumnDef { name: "email".to_string(), data_type: DataType::Text, nullable: false, primary_key: false, unique: true, default: None },
+                    ],
+                    primary_key: vec!["id".to_string()],
+                    if_not_exists: false,
+                },
+            ),
+            (
+                "DROP TABLE IF EXISTS users",
+                SqlStatement::DropTable { name: "users".to_string(), if_exists: true },
+            ),
+            (
+                "CREATE DATABASE IF NOT EXISTS analytics",
+                SqlStatement::CreateDatabase { name: "analytics".to_string(), if_not_exists: true },
+            ),
+            (
+                "DROP DATABASE analytics",
+                SqlStatement::DropDatabase { name: "analytics".to_string(), if_exists: false },
+            ),
+            (
+                "CREATE UNIQUE INDEX users_email ON users (email)",
+                SqlStatement::CreateIndex {
+                    name: "users_email".to_string(),
+                    table: "users".to_string(),
+                    columns: vec!["email".to_string()],
+                    unique: true,
+                },
+            ),
+            (
+                "ALTER TABLE users ADD COLUMN score FLOAT DEFAULT 1.5 NOT NULL",
+                SqlStatement::AlterTable {
+                    table: "users".to_string(),
+                    operation: AlterTableOperation::AddColumn(ColumnDef {
+                        name: "score".to_string(),
+                        data_type: DataType::Float,
+                        nullable: false,
+                        primary_key: false,
+                        unique: false,
+                        default: Some(Expr::Literal(Literal::Float(1.5))),
+                    }),
+                },
+            ),
+            (
+                "alter table users drop nickname",
+                SqlStatement::AlterTable {
+                    table: "users".to_string(),
+                    operation: AlterTableOperation::DropColumn { name: "nickname".to_string() },
+                },
+            ),
+            ("SHOW TABLES", SqlStatement::ShowTables { where_clause: None }),
+            (
+                "show tables where row_estimate > 0",
+                SqlStatement::ShowTables {
+                    where_clause: Some(binary(col("row_estimate"), BinaryOp::Gt, int(0))),
+                },
+            ),
+            (
+                "DESCRIBE Users",
+                SqlStatement::ShowColumns { table: "users".to_string(), where_clause: None },
+            ),
+            (
+                "SHOW COLUMNS FROM users WHERE primary_key",
+                SqlStatement::ShowColumns {
+                    table: "users".to_string(),
+                    where_clause: Some(col("primary_key")),
+                },
+            ),
+            ("ANALYZE users", SqlStatement::Analyze { table: Some("users".to_string()) }),
+            ("ANALYZE", SqlStatement::Analyze { table: None }),
+            ("BEGIN", SqlStatement::Begin { isolation_level: None }),
+            (
+                "begin transaction isolation level repeatable read",
+                SqlStatement::Begin { isolation_level: Some(IsolationLevel::RepeatableRead) },
+            ),
+            (
+                "START TRANSACTION ISOLATION LEVEL SERIALIZABLE",
+                SqlStatement::Begin { isolation_level: Some(IsolationLevel::Serializable) },
+            ),
+            (
+                "BEGIN WORK ISOLATION LEVEL READ UNCOMMITTED",
+                SqlStatement::Begin { isolation_level: Some(IsolationLevel::ReadUncommitted) },
+            ),
+            ("COMMIT;", SqlStatement::Commit),
+            ("rollback work", SqlStatement::Rollback),
+            (
+                "DELETE FROM t WHERE id = $2 AND data = X'00ff'",
+                SqlStatement::Delete {
+                    table: "t".to_string(),
+                    where_clause: Some(binary(
+                        binary(col("id"), BinaryOp::Eq, Expr::Parameter(2)),
+                        BinaryOp::And,
+                        binary(col("data"), BinaryOp::Eq, Expr::Literal(Literal::Blob(vec![0x00, 0xff]))),
+                    )),
+                },
+            ),
+        ];
+
+        for (sql, expected) in cases {
+            let parsed = SqlParser::parse(sql).unwrap_or_else(|e| panic!("failed to parse {:?}: {}", sql, e));
+            assert_eq!(parsed, expected, "parsing {:?}", sql);
+            // Rendered back as SQL, the statement parses the same
+            let rendered = parsed.to_string();
+            assert_eq!(SqlParser::parse(&rendered).ok(), Some(expected), "parsing {:?} rendered from {:?}", rendered, sql);
+        }
+    }
+
+    #[test]
+    fn test_invalid_statement_corpus() {
+        let cases = [
+            ("", "Expected statement, found end of input at line 1, column 1"),
+            ("SELEC * FROM t", "Expected statement, found SELEC at line 1, column 1"),
+            ("SELECT FROM t", "Expected expression, found FROM at line 1, column 8"),
+            ("SELECT * FROM", "Expected identifier, found end of input at line 1, column 14"),
+            ("SELECT * FROM select", "Expected identifier, found select at line 1, column 15"),
+            ("SELECT * FROM t WHERE", "Expected expression, found end of input at line 1, column 22"),
+            ("SELECT * FROM t\nWHERE (a = 1", "Expected ')', found end of input at line 2, column 13"),
+            ("SELECT * FROM t WHERE a IN ()", "Expected expression, found ) at line 1, column 29"),
+            ("SELECT * FROM t WHERE a IN (1, 2", "Expected ')', found end of input at line 1, column 33"),
+            ("SELECT * FROM t WHERE a IN (SELECT b FROM u", "Expected ')', found end of input at line 1, column 44"),
+            ("SELECT a FROM t GROUP a", "Expected BY, found a at line 1, column 23"),
+            ("SELECT lower(a b) FROM t", "Expected ')', found b at line 1, column 16"),
+            ("SELECT COUNT(*", "Expected ')', found end of input at line 1, column 15"),
+            ("SELECT 1;;", "Expected end of statement, found ; at line 1, column 10"),
+            ("SELECT 1; SELECT 2", "Expected end of statement, found SELECT at line 1, column 11"),
+            (";", "Expected statement, found ; at line 1, column 1"),
+            ("SELECT 1 /* open", "Unterminated block comment starting at line 1, column 10"),
+            ("SELECT * FROM t LIMIT -1", "Expected non-negative integer after LIMIT, found - at line 1, column 23"),
+            ("SELECT * FROM t OFFSET", "Expected non-negative integer after OFFSET, found end of input at line 1, column 23"),
+            ("SELECT * FROM t extra", "Expected end of statement, found extra at line 1, column 17"),
+            ("SELECT a b c FROM t", "Expected end of statement, found c at line 1, column 12"),
+            ("INSERT users VALUES (1)", "Expected INTO, found users at line 1, column 8"),
+            ("INSERT INTO users VALUES 1", "Expected '(', found 1 at line 1, column 26"),
+            ("UPDATE users name = 1", "Expected SET, found name at line 1, column 14"),
+            ("DELETE users", "Expected FROM, found users at line 1, column 8"),
+            ("CREATE TABLE t (id WIDGET)", "Expected data type, found WIDGET at line 1, column 20"),
+            ("CREATE TABLE t (a INT PRIMARY KEY, b INT PRIMARY KEY)", "Multiple primary keys for table t"),
+            ("ALTER TABLE t RENAME TO u", "Expected ADD or DROP, found RENAME at line 1, column 15"),
+            ("SHOW USERS", "Expected TABLES or COLUMNS, found USERS at line 1, column 6"),
+            ("CREATE VIEW v", "Expected TABLE, INDEX or DATABASE, found VIEW at line 1, column 8"),
+            ("SELECT * FROM billing.invoices", "cross-database queries are not supported: billing.invoices is a table of database billing"),
+            ("INSERT INTO billing.invoices VALUES (1)", "cross-database queries are not supported: billing.invoices is a table of database billing"),
+            ("SELECT 99999999999999999999", "Expected number in range, found 99999999999999999999 at line 1, column 8"),
+            ("SELECT 'open", "Unterminated string literal starting at line 1, column 8"),
+            ("BEGIN ISOLATION LEVEL READ", "Expected COMMITTED or UNCOMMITTED, found end of input at line 1, column 27"),
+            ("BEGIN ISOLATION SERIALIZABLE", "Expected LEVEL, found SERIALIZABLE at line 1, column 17"),
+            ("START WORK", "Expected TRANSACTION, found WORK at line 1, column 7"),
+        ];
+
+        for (sql, message) in cases {
+            match SqlParser::parse(sql) {
+                Err(QueryError::Parse(msg)) => assert_eq!(msg, message, "parsing {:?}", sql),
+                other => panic!("expected parse error for {:?}, got {:?}", sql, other),
             }
-            _ => panic!("Expected SELECT statement"),
         }
     }
-}
\ No newline at end of file
+}
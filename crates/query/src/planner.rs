@@ -1,36 +1,1045 @@
-use crate::{error::{Result, QueryError}, parser::SqlStatement};
+use crate::{
+    error::{Result, QueryError},
+    ast::{AggregateFunc, AlterTableOperation, BinaryOp, ColumnDef, DataType, Literal, UnaryOp, Expr, OrderByExpr, SelectItem, SelectStatement, SqlStatement},
+    catalog::{Catalog, TableSchema},
+    functions,
+    stats::DEFAULT_SELECTIVITY,
+    value::Value,
+};
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashSet;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Catalog-backed virtual tables used by introspection statements
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CatalogView {
+    /// One row per table: `table_name`, `row_estimate`
+    Tables,
+    /// One row per column of `table`: `column_name`, `data_type`, `nullable`,
+    /// `primary_key`, `indexes`
+    Columns { table: String },
+}
+
+impl CatalogView {
+    pub fn columns(&self) -> Vec<String> {
+        let names: &[&str] = match self {
+            CatalogView::Tables => &["table_name", "row_estimate"],
+            CatalogView::Columns { .. } => &["column_name", "data_type", "nullable", "primary_key", "indexes"],
+        };
+        names.iter().map(|n| n.to_string()).collect()
+    }
+}
+
+/// One aggregate computed by `PhysicalPlan::HashAggregate`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AggregateExpr {
+    pub func: AggregateFunc,
+    /// None for `COUNT(*)`
+    pub arg: Option<Expr>,
+    /// Output column name
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum PhysicalPlan {
+    /// Scan a table in primary key order, emitting `columns`. A query's scan
+    /// lists only the columns it reads, filter included, so the executor can
+    /// leave the rest of each row undecoded.
     TableScan {
         table: String,
         columns: Vec<String>,
-        filter: Option<String>,
+        filter: Option<Expr>,
+        /// Rows passed over undecoded at the start of the scan. Only set
+        /// without a filter.
+        offset: u64,
     },
     IndexScan {
         table: String,
         index: String,
         columns: Vec<String>,
-        filter: Option<String>,
+        filter: Option<Expr>,
+    },
+    /// Rows of a table whose single-column primary key is one of `keys`,
+    /// fetched with one batched point lookup instead of a scan and emitted
+    /// in key order. `filter` is the full predicate the keys came from.
+    KeyLookup {
+        table: String,
+        columns: Vec<String>,
+        keys: Vec<Value>,
+        filter: Option<Expr>,
+    },
+    CatalogScan {
+        view: CatalogView,
+    },
+    /// `SELECT COUNT(*) FROM table` answered from the table's row counter
+    /// instead of a scan; emits one row with the single column `name`
+    TableCount {
+        table: String,
+        name: String,
+    },
+    /// Literal rows with no columns, used as the input of `SELECT` without `FROM`
+    Values {
+        rows: usize,
+    },
+    Filter {
+        input: Box<PhysicalPlan>,
+        predicate: Expr,
+    },
+    /// Filter `input` with a predicate containing `IN (SELECT ...)` tests.
+    /// Each subquery runs first and is materialized into a hash set; they are
+    /// listed in the order they appear in `predicate`.
+    SemiJoin {
+        input: Box<PhysicalPlan>,
+        subqueries: Vec<PhysicalPlan>,
+        predicate: Expr,
+    },
+    /// Group `input` rows by the `group_by` expressions and compute
+    /// `aggregates` per group. Emits the grouping columns, then one column
+    /// per aggregate. Without `group_by`, emits exactly one row.
+    HashAggregate {
+        input: Box<PhysicalPlan>,
+        group_by: Vec<(Expr, String)>,
+        aggregates: Vec<AggregateExpr>,
+    },
+    Project {
+        input: Box<PhysicalPlan>,
+        exprs: Vec<(Expr, String)>,
+    },
+    Sort {
+        input: Box<PhysicalPlan>,
+        order_by: Vec<OrderByExpr>,
+    },
+    Limit {
+        input: Box<PhysicalPlan>,
+        limit: u64,
+    },
+    Offset {
+        input: Box<PhysicalPlan>,
+        offset: u64,
+    },
+    /// Rows hold an expression for every column of the table, in order;
+    /// `Expr::Default` stands for the column's default
+    Insert {
+        table: String,
+        rows: Vec<Vec<Expr>>,
+    },
+    Update {
+        table: String,
+        assignments: Vec<(String, Expr)>,
+        filter: Option<Expr>,
+    },
+    Delete {
+        table: String,
+        filter: Option<Expr>,
+    },
+    CreateTable {
+        name: String,
+        columns: Vec<ColumnDef>,
+        primary_key: Vec<String>,
+        if_not_exists: bool,
+    },
+    DropTable {
+        name: String,
+        if_exists: bool,
+    },
+    CreateIndex {
+        name: String,
+        table: String,
+        columns: Vec<String>,
+        unique: bool,
+    },
+    AlterTable {
+        table: String,
+        operation: AlterTableOperation,
+    },
+    /// Gather column statistics for `table`, or every table if None. Emits
+    /// one row per column.
+    Analyze {
+        table: Option<String>,
+    },
+    /// Describe `plan` instead of running it, or with `analyze` run it and
+    /// describe it with runtime statistics
+    Explain {
+        plan: Box<PhysicalPlan>,
+        analyze: bool,
     },
 }
 
+impl PhysicalPlan {
+    /// The plan tree, one node per line with children indented below it
+    pub fn explain(&self) -> Vec<String> {
+        self.explain_with(&|_| String::new())
+    }
+
+    /// Like `explain`, appending `annotate(node)` to each node's line, where
+    /// `node` numbers the nodes in the order they are listed
+    pub fn explain_with(&self, annotate: &dyn Fn(usize) -> String) -> Vec<String> {
+        let mut lines = Vec::new();
+        self.explain_into(0, 0, annotate, &mut lines);
+        lines
+    }
+
+    /// Inputs of this node, in the order they are listed by `explain`
+    pub fn children(&self) -> Vec<&PhysicalPlan> {
+        match self {
+            PhysicalPlan::Filter { input, .. }
+            | PhysicalPlan::HashAggregate { input, .. }
+            | PhysicalPlan::Project { input, .. }
+            | PhysicalPlan::Sort { input, .. }
+            | PhysicalPlan::Limit { input, .. }
+            | PhysicalPlan::Offset { input, .. } => vec![input],
+            PhysicalPlan::SemiJoin { input, subqueries, .. } => std::iter::once(&**input).chain(subqueries).collect(),
+            PhysicalPlan::Explain { plan, .. } => vec![plan],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Number of nodes in the plan tree, this one included
+    pub fn node_count(&self) -> usize {
+        1 + self.children().into_iter().map(PhysicalPlan::node_count).sum::<usize>()
+    }
+
+    /// Whether the plan emits rows rather than changing data or schema
+    pub fn is_query(&self) -> bool {
+        !matches!(self,
+            PhysicalPlan::Insert { .. }
+            | PhysicalPlan::Update { .. }
+            | PhysicalPlan::Delete { .. }
+            | PhysicalPlan::CreateTable { .. }
+            | PhysicalPlan::DropTable { .. }
+            | PhysicalPlan::CreateIndex { .. }
+            | PhysicalPlan::AlterTable { .. }
+            | PhysicalPlan::Analyze { .. }
+            | PhysicalPlan::Explain { .. })
+    }
+
+    /// Estimated number of rows the plan emits, from the tables' row counts
+    /// and, for tables that have been analyzed, their column statistics
+    pub fn estimated_rows(&self, catalog: &Catalog) -> f64 {
+        match self {
+            PhysicalPlan::TableScan { table, filter, offset, .. } => {
+                (scanned_rows(catalog, table, filter.as_ref()) - *offset as f64).max(0.0)
+            }
+            PhysicalPlan::IndexScan { table, filter, .. } => scanned_rows(catalog, table, filter.as_ref()),
+            PhysicalPlan::KeyLookup { table, keys, filter, .. } => {
+                scanned_rows(catalog, table, filter.as_ref()).min(keys.len() as f64)
+            }
+            PhysicalPlan::CatalogScan { view: CatalogView::Tables } => catalog.tables().len() as f64,
+            PhysicalPlan::CatalogScan { view: CatalogView::Columns { table } } => {
+                catalog.get_table(table).map_or(0.0, |schema| schema.columns.len() as f64)
+            }
+            PhysicalPlan::TableCount { .. } => 1.0,
+            PhysicalPlan::Values { rows } => *rows as f64,
+            PhysicalPlan::Filter { input, .. } | PhysicalPlan::SemiJoin { input, .. } => {
+                input.estimated_rows(catalog) * DEFAULT_SELECTIVITY
+            }
+            PhysicalPlan::HashAggregate { group_by, .. } if group_by.is_empty() => 1.0,
+            PhysicalPlan::HashAggregate { input, .. }
+            | PhysicalPlan::Project { input, .. }
+            | PhysicalPlan::Sort { input, .. } => input.estimated_rows(catalog),
+            PhysicalPlan::Limit { input, limit } => input.estimated_rows(catalog).min(*limit as f64),
+            PhysicalPlan::Offset { input, offset } => (input.estimated_rows(catalog) - *offset as f64).max(0.0),
+            PhysicalPlan::Analyze { table: Some(table) } => {
+                catalog.get_table(table).map_or(0.0, |schema| schema.columns.len() as f64)
+            }
+            PhysicalPlan::Analyze { table: None } => {
+                catalog.tables().iter().map(|schema| schema.columns.len() as f64).sum()
+            }
+            PhysicalPlan::Explain { plan, .. } => plan.explain().len() as f64,
+            _ => 0.0,
+        }
+    }
+
+    /// Names and types of the columns the plan emits. A type is None when it
+    /// depends on the data, like the type of a NULL literal or a MIN over an
+    /// untyped expression.
+    pub fn output_columns(&self, catalog: &Catalog) -> Vec<(String, Option<DataType>)> {
+        let typed = |names: Vec<String>, types: &[DataType]| {
+            names.into_iter().zip(types.iter().map(|t| Some(*t))).collect()
+        };
+        match self {
+            PhysicalPlan::TableScan { table, columns, .. }
+            | PhysicalPlan::IndexScan { table, columns, .. }
+            | PhysicalPlan::KeyLookup { table, columns, .. } => {
+                let schema = catalog.get_table(table);
+                columns.iter()
+                    .map(|name| (name.clone(), schema.as_ref().and_then(|s| s.column(name)).map(|c| c.data_type)))
+                    .collect()
+            }
+            PhysicalPlan::CatalogScan { view: view @ CatalogView::Tables } => {
+                typed(view.columns(), &[DataType::Text, DataType::Integer])
+            }
+            PhysicalPlan::CatalogScan { view: view @ CatalogView::Columns { .. } } => typed(view.columns(), &[
+                DataType::Text, DataType::Text, DataType::Boolean, DataType::Boolean, DataType::Text,
+            ]),
+            PhysicalPlan::TableCount { name, .. } => typed(vec![name.clone()], &[DataType::Integer]),
+            PhysicalPlan::Explain { .. } => typed(vec!["plan".to_string()], &[DataType::Text]),
+            PhysicalPlan::Filter { input, .. }
+            | PhysicalPlan::SemiJoin { input, .. }
+            | PhysicalPlan::Sort { input, .. }
+            | PhysicalPlan::Limit { input, .. }
+            | PhysicalPlan::Offset { input, .. } => input.output_columns(catalog),
+            PhysicalPlan::HashAggregate { input, group_by, aggregates } => {
+                let input = input.output_columns(catalog);
+                group_by.iter()
+                    .map(|(expr, name)| (name.clone(), expr_type(expr, &input)))
+                    .chain(aggregates.iter().map(|aggregate| {
+                        let arg_type = aggregate.arg.as_ref().and_then(|arg| expr_type(arg, &input));
+                        let data_type = match aggregate.func {
+                            AggregateFunc::Count => Some(DataType::Integer),
+                            AggregateFunc::Avg => Some(DataType::Float),
+                            AggregateFunc::Sum | AggregateFunc::Min | AggregateFunc::Max => arg_type,
+                        };
+                        (aggregate.name.clone(), data_type)
+                    }))
+                    .collect()
+            }
+            PhysicalPlan::Project { input, exprs } => {
+                let input = input.output_columns(catalog);
+                exprs.iter().map(|(expr, name)| (name.clone(), expr_type(expr, &input))).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn explain_into(&self, node: usize, depth: usize, annotate: &dyn Fn(usize) -> String, lines: &mut Vec<String>) {
+        let filtered = |filter: &Option<Expr>| filter.as_ref().map_or(String::new(), |f| format!(" filter: {}", f));
+        let list = |items: Vec<String>| items.join(", ");
+        let label = match self {
+            PhysicalPlan::TableScan { table, filter, offset, .. } => {
+                let skipped = if *offset > 0 { format!(" offset: {}", offset) } else { String::new() };
+                format!("TableScan {}{}{}", table, filtered(filter), skipped)
+            }
+            PhysicalPlan::IndexScan { table, index, filter, .. } => {
+                format!("IndexScan {} using {}{}", table, index, filtered(filter))
+            }
+            PhysicalPlan::KeyLookup { table, keys, filter, .. } => {
+                format!("KeyLookup {} keys: {}{}", table, keys.len(), filtered(filter))
+            }
+            PhysicalPlan::CatalogScan { view } => format!("CatalogScan {:?}", view),
+            PhysicalPlan::TableCount { table, .. } => format!("TableCount {}", table),
+            PhysicalPlan::Values { rows } => format!("Values rows: {}", rows),
+            PhysicalPlan::Filter { predicate, .. } => format!("Filter {}", predicate),
+            PhysicalPlan::SemiJoin { predicate, .. } => format!("SemiJoin {}", predicate),
+            PhysicalPlan::HashAggregate { group_by, aggregates, .. } => {
+                let keys = list(group_by.iter().map(|(expr, _)| expr.to_string()).collect());
+                let aggregates = list(aggregates.iter().map(|a| a.name.clone()).collect());
+                format!("HashAggregate group by: [{}] aggregates: [{}]", keys, aggregates)
+            }
+            PhysicalPlan::Project { exprs, .. } => {
+                let exprs = list(exprs.iter().map(|(expr, name)| match expr {
+                    Expr::Column(column) if column == name => name.clone(),
+                    expr => format!("{} AS {}", expr, name),
+                }).collect());
+                format!("Project {}", exprs)
+            }
+            PhysicalPlan::Sort { order_by, .. } => {
+                let keys = list(order_by.iter()
+                    .map(|key| format!("{}{}", key.expr, if key.descending { " DESC" } else { "" }))
+                    .collect());
+                format!("Sort {}", keys)
+            }
+            PhysicalPlan::Limit { limit, .. } => format!("Limit {}", limit),
+            PhysicalPlan::Offset { offset, .. } => format!("Offset {}", offset),
+            PhysicalPlan::Insert { table, rows, .. } => format!("Insert {} rows: {}", table, rows.len()),
+            PhysicalPlan::Update { table, filter, .. } => format!("Update {}{}", table, filtered(filter)),
+            PhysicalPlan::Delete { table, filter } => format!("Delete {}{}", table, filtered(filter)),
+            PhysicalPlan::CreateTable { name, .. } => format!("CreateTable {}", name),
+            PhysicalPlan::DropTable { name, .. } => format!("DropTable {}", name),
+            PhysicalPlan::CreateIndex { name, table, .. } => format!("CreateIndex {} on {}", name, table),
+            PhysicalPlan::AlterTable { table, .. } => format!("AlterTable {}", table),
+            PhysicalPlan::Analyze { table: Some(table) } => format!("Analyze {}", table),
+            PhysicalPlan::Analyze { table: None } => "Analyze".to_string(),
+            PhysicalPlan::Explain { analyze: false, .. } => "Explain".to_string(),
+            PhysicalPlan::Explain { analyze: true, .. } => "Explain Analyze".to_string(),
+        };
+
+        lines.push(format!("{}{}{}", "  ".repeat(depth), label, annotate(node)));
+        let mut child_node = node + 1;
+        for child in self.children() {
+            child.explain_into(child_node, depth + 1, annotate, lines);
+            child_node += child.node_count();
+        }
+    }
+}
+
 /// Query planner that converts SQL statements to execution plans
 pub struct QueryPlanner;
 
 impl QueryPlanner {
-    pub fn plan(statement: SqlStatement) -> Result<PhysicalPlan> {
+    pub fn plan(statement: SqlStatement, catalog: &Catalog) -> Result<PhysicalPlan> {
         match statement {
-            SqlStatement::Select { columns, table, where_clause } => {
-                // Simplified planning - just use table scan
-                Ok(PhysicalPlan::TableScan {
-                    table,
-                    columns,
-                    filter: where_clause,
-                })
+            SqlStatement::Select(select) => Self::plan_select(select, catalog),
+            SqlStatement::Insert { table, columns, values } => {
+                let schema = catalog.table(&table)?;
+                let targets = insert_targets(&schema, &columns)?;
+                let rows = values.into_iter()
+                    .map(|values| {
+                        let mut row = vec![Expr::Default; schema.columns.len()];
+                        // An empty row is DEFAULT VALUES
+                        if values.is_empty() {
+                            return Ok(row);
+                        }
+                        if values.len() != targets.len() {
+                            return Err(QueryError::Invalid(format!(
+                                "INSERT has {} values but {} target columns", values.len(), targets.len()
+                            )));
+                        }
+                        for (expr, &position) in values.into_iter().zip(&targets) {
+                            if expr != Expr::Default {
+                                check_columns(&expr, &[])?;
+                            }
+                            row[position] = expr;
+                        }
+                        Ok(row)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(PhysicalPlan::Insert { table, rows })
+            }
+            SqlStatement::Update { table, set_clause, where_clause } => {
+                let available = catalog.table(&table)?.column_names();
+                for (column, expr) in &set_clause {
+                    if !available.contains(column) {
+                        return Err(QueryError::ColumnNotFound(column.clone()));
+                    }
+                    check_columns(expr, &available)?;
+                }
+                if let Some(filter) = &where_clause {
+                    check_columns(filter, &available)?;
+                }
+                Ok(PhysicalPlan::Update { table, assignments: set_clause, filter: where_clause })
+            }
+            SqlStatement::Delete { table, where_clause } => {
+                let available = catalog.table(&table)?.column_names();
+                if let Some(filter) = &where_clause {
+                    check_columns(filter, &available)?;
+                }
+                Ok(PhysicalPlan::Delete { table, filter: where_clause })
+            }
+            SqlStatement::CreateTable { name, columns, primary_key, if_not_exists } => {
+                Ok(PhysicalPlan::CreateTable { name, columns, primary_key, if_not_exists })
+            }
+            SqlStatement::DropTable { name, if_exists } => Ok(PhysicalPlan::DropTable { name, if_exists }),
+            SqlStatement::CreateIndex { name, table, columns, unique } => {
+                Ok(PhysicalPlan::CreateIndex { name, table, columns, unique })
+            }
+            SqlStatement::AlterTable { table, operation } => {
+                let schema = catalog.table(&table)?;
+                if let AlterTableOperation::DropColumn { name } = &operation {
+                    if schema.column(name).is_none() {
+                        return Err(QueryError::ColumnNotFound(name.clone()));
+                    }
+                }
+                Ok(PhysicalPlan::AlterTable { table, operation })
+            }
+            SqlStatement::ShowTables { where_clause } => {
+                Self::plan_catalog_view(CatalogView::Tables, where_clause)
             }
-            _ => Err(QueryError::Plan("Only SELECT supported".to_string())),
+            SqlStatement::ShowColumns { table, where_clause } => {
+                catalog.table(&table)?;
+                Self::plan_catalog_view(CatalogView::Columns { table }, where_clause)
+            }
+            SqlStatement::Analyze { table } => {
+                if let Some(table) = &table {
+                    catalog.table(table)?;
+                }
+                Ok(PhysicalPlan::Analyze { table })
+            }
+            SqlStatement::Explain { statement, analyze } => {
+                let plan = Self::plan(*statement, catalog)?;
+                if analyze && !plan.is_query() {
+                    return Err(QueryError::Plan("EXPLAIN ANALYZE only runs queries".to_string()));
+                }
+                Ok(PhysicalPlan::Explain { plan: Box::new(plan), analyze })
+            }
+            SqlStatement::Begin { .. } | SqlStatement::Commit | SqlStatement::Rollback => Err(QueryError::Plan(
+                "BEGIN, COMMIT and ROLLBACK are run by a session, not planned".to_string()
+            )),
+            SqlStatement::CreateDatabase { .. } | SqlStatement::DropDatabase { .. } => Err(QueryError::Plan(
+                "CREATE DATABASE and DROP DATABASE are run by the server's HTTP API, not planned".to_string()
+            )),
+        }
+    }
+
+    fn plan_select(select: SelectStatement, catalog: &Catalog) -> Result<PhysicalPlan> {
+        Ok(Self::plan_query(select, catalog)?.0)
+    }
+
+    /// Plan a SELECT, also returning its output column names
+    fn plan_query(select: SelectStatement, catalog: &Catalog) -> Result<(PhysicalPlan, Vec<String>)> {
+        if let Some(planned) = Self::plan_table_count(&select, catalog)? {
+            return Ok(planned);
+        }
+
+        let (source, available) = match &select.table {
+            Some(table) => {
+                let columns = catalog.table(table)?.column_names();
+                let scan = PhysicalPlan::TableScan {
+                    table: table.clone(),
+                    columns: read_columns(&select, &columns),
+                    filter: None,
+                    offset: 0,
+                };
+                (scan, columns)
+            }
+            None => (PhysicalPlan::Values { rows: 1 }, Vec::new()),
+        };
+
+        let mut plan = match select.where_clause.clone() {
+            None => source,
+            Some(predicate) => {
+                let mut subqueries = Vec::new();
+                plan_subqueries(&predicate, &available, catalog, &mut subqueries)?;
+                match source {
+                    _ if !subqueries.is_empty() => {
+                        PhysicalPlan::SemiJoin { input: Box::new(source), subqueries, predicate }
+                    }
+                    PhysicalPlan::TableScan { table, columns, .. } => {
+                        let predicate = order_conjuncts(predicate, &table, catalog);
+                        match lookup_keys(&predicate, &table, catalog) {
+                            Some(keys) => PhysicalPlan::KeyLookup { table, columns, keys, filter: Some(predicate) },
+                            None => PhysicalPlan::TableScan { table, columns, filter: Some(predicate), offset: 0 },
+                        }
+                    }
+                    source => PhysicalPlan::Filter { input: Box::new(source), predicate },
+                }
+            }
+        };
+
+        let mut exprs = Vec::new();
+        for item in &select.columns {
+            match item {
+                SelectItem::Wildcard => {
+                    if select.table.is_none() {
+                        return Err(QueryError::Plan("SELECT * requires a FROM clause".to_string()));
+                    }
+                    exprs.extend(available.iter().map(|c| (Expr::Column(c.clone()), c.clone())));
+                }
+                SelectItem::Expr { expr, alias } => {
+                    let name = alias.clone().unwrap_or_else(|| output_name(expr));
+                    exprs.push((expr.clone(), name));
+                }
+            }
+        }
+
+        // ORDER BY may name an output alias; sort on the aliased expression
+        let mut order_by = select.order_by;
+        for key in &mut order_by {
+            if let Expr::Column(name) = &key.expr {
+                if !available.contains(name) {
+                    if let Some((expr, _)) = exprs.iter().find(|(_, alias)| alias == name) {
+                        key.expr = expr.clone();
+                    }
+                }
+            }
+        }
+
+        let grouped = !select.group_by.is_empty()
+            || select.having.is_some()
+            || exprs.iter().any(|(expr, _)| contains_aggregate(expr))
+            || order_by.iter().any(|key| contains_aggregate(&key.expr));
+
+        let available = if grouped {
+            // Everything after the aggregation reads the grouping and aggregate columns
+            let mut grouping = Grouping::new(select.group_by, &available)?;
+            let having = select.having.map(|having| grouping.rewrite(&having)).transpose()?;
+            for (expr, _) in &mut exprs {
+                *expr = grouping.rewrite(expr)?;
+            }
+            for key in &mut order_by {
+                key.expr = grouping.rewrite(&key.expr)?;
+            }
+
+            let columns = grouping.columns();
+            plan = PhysicalPlan::HashAggregate {
+                input: Box::new(plan),
+                group_by: grouping.group_by,
+                aggregates: grouping.aggregates,
+            };
+            if let Some(predicate) = having {
+                plan = PhysicalPlan::Filter { input: Box::new(plan), predicate };
+            }
+            columns
+        } else {
+            for (expr, _) in &exprs {
+                check_columns(expr, &available)?;
+            }
+            for key in &order_by {
+                check_columns(&key.expr, &available)?;
+            }
+            available
+        };
+
+        if !order_by.is_empty() {
+            plan = PhysicalPlan::Sort { input: Box::new(plan), order_by };
+        }
+
+        if let Some(offset) = select.offset.filter(|&offset| offset > 0) {
+            plan = match plan {
+                // An unfiltered scan emits every row in key order, so it can
+                // pass over the skipped ones without decoding them
+                PhysicalPlan::TableScan { table, columns, filter: None, .. } => {
+                    PhysicalPlan::TableScan { table, columns, filter: None, offset }
+                }
+                plan => PhysicalPlan::Offset { input: Box::new(plan), offset },
+            };
+        }
+
+        if let Some(limit) = select.limit {
+            plan = PhysicalPlan::Limit { input: Box::new(plan), limit };
+        }
+
+        let names = exprs.iter().map(|(_, name)| name.clone()).collect();
+        let is_identity = exprs.len() == available.len()
+            && exprs.iter().zip(&available).all(|((expr, name), column)| {
+                name == column && *expr == Expr::Column(column.clone())
+            });
+        if is_identity {
+            return Ok((plan, names));
+        }
+
+        Ok((PhysicalPlan::Project { input: Box::new(plan), exprs }, names))
+    }
+
+    /// A plain `SELECT COUNT(*) FROM t` reads the row counter. Anything that
+    /// filters or groups rows takes the general path.
+    fn plan_table_count(select: &SelectStatement, catalog: &Catalog) -> Result<Option<(PhysicalPlan, Vec<String>)>> {
+        let (Some(table), [SelectItem::Expr { expr, alias }]) = (&select.table, select.columns.as_slice()) else {
+            return Ok(None);
+        };
+        let eligible = matches!(expr, Expr::Aggregate { func: AggregateFunc::Count, arg: None })
+            && select.where_clause.is_none()
+            && select.group_by.is_empty()
+            && select.having.is_none()
+            && select.order_by.is_empty();
+        if !eligible {
+            return Ok(None);
+        }
+
+        catalog.table(table)?;
+        let name = alias.clone().unwrap_or_else(|| output_name(expr));
+        let mut plan = PhysicalPlan::TableCount { table: table.clone(), name: name.clone() };
+        if let Some(offset) = select.offset.filter(|&offset| offset > 0) {
+            plan = PhysicalPlan::Offset { input: Box::new(plan), offset };
+        }
+        if let Some(limit) = select.limit {
+            plan = PhysicalPlan::Limit { input: Box::new(plan), limit };
+        }
+        Ok(Some((plan, vec![name])))
+    }
+
+    fn plan_catalog_view(view: CatalogView, where_clause: Option<Expr>) -> Result<PhysicalPlan> {
+        let mut plan = PhysicalPlan::CatalogScan { view: view.clone() };
+        if let Some(predicate) = where_clause {
+            check_columns(&predicate, &view.columns())?;
+            plan = PhysicalPlan::Filter { input: Box::new(plan), predicate };
+        }
+        Ok(plan)
+    }
+}
+
+/// Reorder the ANDed terms of a scan's filter so the ones the column
+/// statistics expect to reject the most rows run first. Only comparisons
+/// of a column with a literal and NULL tests on a column move, ahead of the
+/// other terms, which keep their order so that guards such as `x <> 0` in
+/// `x <> 0 AND 10 / x > 1` still run before the terms they protect.
+fn order_conjuncts(predicate: Expr, table: &str, catalog: &Catalog) -> Expr {
+    let Some(schema) = catalog.get_table(table) else {
+        return predicate;
+    };
+    let stats = catalog.stats(schema.id);
+    let Some(analysis) = stats.analysis() else {
+        return predicate;
+    };
+    let mut terms = Vec::new();
+    split_conjuncts(predicate, &mut terms);
+    if terms.len() < 2 {
+        return terms.pop().expect("a predicate has a term");
+    }
+
+    let rows = stats.row_count();
+    let (simple, rest): (Vec<_>, Vec<_>) = terms.into_iter().partition(is_simple_term);
+    let mut simple: Vec<(f64, Expr)> = simple.into_iter()
+        .map(|term| (analysis.selectivity(&schema, &term, rows), term))
+        .collect();
+    simple.sort_by(|a, b| a.0.total_cmp(&b.0));
+    simple.into_iter()
+        .map(|(_, term)| term)
+        .chain(rest)
+        .reduce(|left, right| Expr::Binary { left: Box::new(left), op: BinaryOp::And, right: Box::new(right) })
+        .expect("a predicate has a term")
+}
+
+/// Primary keys a predicate limits `table` to, when one of its ANDed terms
+/// is `pk IN (literal, ...)` on a single-column primary key. NULLs match no
+/// row and are left out. None if any literal would not compare equal to the
+/// key it is stored as.
+fn lookup_keys(predicate: &Expr, table: &str, catalog: &Catalog) -> Option<Vec<Value>> {
+    let schema = catalog.get_table(table)?;
+    let [key] = schema.primary_key.as_slice() else {
+        return None;
+    };
+    let data_type = schema.column(key)?.data_type;
+    let mut terms = Vec::new();
+    split_conjuncts(predicate.clone(), &mut terms);
+    let list = terms.iter().find_map(|term| match term {
+        Expr::InList { expr, list, negated: false } if matches!(&**expr, Expr::Column(c) if c == key) => Some(list),
+        _ => None,
+    })?;
+
+    let mut keys = Vec::new();
+    for item in list {
+        let Expr::Literal(literal) = item else {
+            return None;
+        };
+        let value = Value::from_literal(literal);
+        if value.is_null() {
+            continue;
+        }
+        let stored = value.clone().cast_to(data_type).ok()?;
+        if !matches!(value.sql_cmp(&stored), Ok(Some(Ordering::Equal))) {
+            return None;
+        }
+        keys.push(stored);
+    }
+    Some(keys)
+}
+
+fn split_conjuncts(expr: Expr, terms: &mut Vec<Expr>) {
+    match expr {
+        Expr::Binary { left, op: BinaryOp::And, right } => {
+            split_conjuncts(*left, terms);
+            split_conjuncts(*right, terms);
+        }
+        expr => terms.push(expr),
+    }
+}
+
+fn is_simple_term(expr: &Expr) -> bool {
+    match expr {
+        Expr::IsNull { expr, .. } => matches!(**expr, Expr::Column(_)),
+        Expr::Binary { left, op, right } => {
+            matches!(op, BinaryOp::Eq | BinaryOp::NotEq | BinaryOp::Lt | BinaryOp::LtEq | BinaryOp::Gt | BinaryOp::GtEq)
+                && matches!((&**left, &**right), (Expr::Column(_), Expr::Literal(_)) | (Expr::Literal(_), Expr::Column(_)))
+        }
+        _ => false,
+    }
+}
+
+/// Estimated rows of `table` that pass `filter`
+fn scanned_rows(catalog: &Catalog, table: &str, filter: Option<&Expr>) -> f64 {
+    let Some(schema) = catalog.get_table(table) else {
+        return 0.0;
+    };
+    let stats = catalog.stats(schema.id);
+    let rows = stats.row_count();
+    let selectivity = match (filter, stats.analysis()) {
+        (None, _) => 1.0,
+        (Some(filter), Some(analysis)) => analysis.selectivity(&schema, filter, rows),
+        (Some(_), None) => DEFAULT_SELECTIVITY,
+    };
+    rows as f64 * selectivity
+}
+
+/// Positions of the columns an INSERT lists, or of every column if it
+/// lists none
+fn insert_targets(schema: &TableSchema, columns: &[String]) -> Result<Vec<usize>> {
+    if columns.is_empty() {
+        return Ok((0..schema.columns.len()).collect());
+    }
+    let mut targets = Vec::with_capacity(columns.len());
+    for name in columns {
+        let position = schema.column_position(name)
+            .ok_or_else(|| QueryError::ColumnNotFound(name.clone()))?;
+        if targets.contains(&position) {
+            return Err(QueryError::Invalid(format!("column {} specified more than once", name)));
+        }
+        targets.push(position);
+    }
+    Ok(targets)
+}
+
+/// Name of an unaliased result column
+fn output_name(expr: &Expr) -> String {
+    match expr {
+        Expr::Column(name) => name.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// The columns of `columns` that `select` reads anywhere, in table order. The
+/// scan only needs to decode these.
+fn read_columns(select: &SelectStatement, columns: &[String]) -> Vec<String> {
+    let mut referenced = HashSet::new();
+    for item in &select.columns {
+        match item {
+            SelectItem::Wildcard => return columns.to_vec(),
+            SelectItem::Expr { expr, .. } => column_refs(expr, &mut referenced),
+        }
+    }
+    let clauses = select.where_clause.iter()
+        .chain(&select.group_by)
+        .chain(&select.having)
+        .chain(select.order_by.iter().map(|key| &key.expr));
+    for expr in clauses {
+        column_refs(expr, &mut referenced);
+    }
+    columns.iter().filter(|column| referenced.contains(column.as_str())).cloned().collect()
+}
+
+/// Collect the names of the columns `expr` reads. A subquery's own columns
+/// belong to its table and are left out.
+fn column_refs<'a>(expr: &'a Expr, out: &mut HashSet<&'a str>) {
+    match expr {
+        Expr::Column(name) => {
+            out.insert(name);
+        }
+        Expr::Literal(_) | Expr::Default | Expr::Parameter(_) => {}
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } | Expr::InSubquery { expr, .. } => column_refs(expr, out),
+        Expr::Binary { left, right, .. } => {
+            column_refs(left, out);
+            column_refs(right, out);
+        }
+        Expr::Aggregate { arg, .. } => {
+            if let Some(arg) = arg {
+                column_refs(arg, out);
+            }
+        }
+        Expr::Function { args, .. } => {
+            for arg in args {
+                column_refs(arg, out);
+            }
+        }
+        Expr::InList { expr, list, .. } => {
+            column_refs(expr, out);
+            for item in list {
+                column_refs(item, out);
+            }
+        }
+    }
+}
+
+/// Fail if `expr` references a column outside `available`
+fn check_columns(expr: &Expr, available: &[String]) -> Result<()> {
+    match expr {
+        Expr::Column(name) if !available.contains(name) => Err(QueryError::ColumnNotFound(name.clone())),
+        Expr::Column(_) | Expr::Literal(_) => Ok(()),
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => check_columns(expr, available),
+        Expr::Binary { left, right, .. } => {
+            check_columns(left, available)?;
+            check_columns(right, available)
+        }
+        Expr::InList { expr, list, .. } => {
+            check_columns(expr, available)?;
+            list.iter().try_for_each(|item| check_columns(item, available))
+        }
+        Expr::InSubquery { .. } => Err(QueryError::Plan(
+            "subqueries are only supported in the WHERE clause of a SELECT".to_string(),
+        )),
+        Expr::Default => Err(QueryError::Plan("DEFAULT is only allowed as an INSERT value".to_string())),
+        Expr::Parameter(n) => Err(QueryError::Plan(format!("no value was bound to parameter ${}", n))),
+        Expr::Aggregate { .. } => Err(QueryError::Plan(format!(
+            "aggregate function {} is not allowed here", expr
+        ))),
+        Expr::Function { name, args } => {
+            for arg in args {
+                check_columns(arg, available)?;
+            }
+            check_function(name, args)
+        }
+    }
+}
+
+/// Resolve a scalar function and check its arguments against their types,
+/// where those are known before execution
+fn check_function(name: &str, args: &[Expr]) -> Result<()> {
+    let arg_types: Vec<_> = args.iter().map(static_type).collect();
+    functions::lookup(name)?.check_args(&arg_types)
+}
+
+/// Type of `expr` when it can be determined without reading any rows
+fn static_type(expr: &Expr) -> Option<DataType> {
+    expr_type(expr, &[])
+}
+
+/// Type of `expr` over rows with `columns`, if it can be known without
+/// evaluating it
+fn expr_type(expr: &Expr, columns: &[(String, Option<DataType>)]) -> Option<DataType> {
+    let static_type = |expr| expr_type(expr, columns);
+    match expr {
+        Expr::Column(name) => columns.iter().find(|(column, _)| column == name)?.1,
+        Expr::Literal(Literal::Null) => None,
+        Expr::Literal(Literal::Integer(_)) => Some(DataType::Integer),
+        Expr::Literal(Literal::Float(_)) => Some(DataType::Float),
+        Expr::Literal(Literal::String(_)) => Some(DataType::Text),
+        Expr::Literal(Literal::Boolean(_)) => Some(DataType::Boolean),
+        Expr::Literal(Literal::Timestamp(_)) => Some(DataType::Timestamp),
+        Expr::Literal(Literal::Blob(_)) => Some(DataType::Blob),
+        Expr::Unary { op: UnaryOp::Not, .. } | Expr::IsNull { .. } | Expr::InSubquery { .. } | Expr::InList { .. } => {
+            Some(DataType::Boolean)
+        }
+        Expr::Unary { expr, .. } => static_type(expr),
+        Expr::Binary { left, op, right } => match op {
+            BinaryOp::Concat => Some(DataType::Text),
+            BinaryOp::Plus | BinaryOp::Minus | BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Modulo => {
+                match (static_type(left)?, static_type(right)?) {
+                    (DataType::Integer, DataType::Integer) => Some(DataType::Integer),
+                    (DataType::Timestamp, DataType::Timestamp) => Some(DataType::Float),
+                    (DataType::Timestamp, _) | (_, DataType::Timestamp) => Some(DataType::Timestamp),
+                    _ => Some(DataType::Float),
+                }
+            }
+            _ => Some(DataType::Boolean),
+        },
+        Expr::Function { name, args } => {
+            let arg_types: Vec<_> = args.iter().map(static_type).collect();
+            functions::lookup(name).ok()?.return_type(&arg_types)
+        }
+        Expr::Aggregate { func: AggregateFunc::Count, .. } => Some(DataType::Integer),
+        Expr::Aggregate { .. } | Expr::Default | Expr::Parameter(_) => None,
+    }
+}
+
+fn contains_aggregate(expr: &Expr) -> bool {
+    match expr {
+        Expr::Aggregate { .. } => true,
+        Expr::Column(_) | Expr::Literal(_) | Expr::InSubquery { .. } | Expr::Default | Expr::Parameter(_) => false,
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => contains_aggregate(expr),
+        Expr::Binary { left, right, .. } => contains_aggregate(left) || contains_aggregate(right),
+        Expr::Function { args, .. } => args.iter().any(contains_aggregate),
+        Expr::InList { expr, list, .. } => contains_aggregate(expr) || list.iter().any(contains_aggregate),
+    }
+}
+
+/// Maps expressions over input rows onto the output of a HashAggregate:
+/// grouping expressions and aggregate calls become references to its columns
+struct Grouping<'a> {
+    input_columns: &'a [String],
+    group_by: Vec<(Expr, String)>,
+    aggregates: Vec<AggregateExpr>,
+}
+
+impl<'a> Grouping<'a> {
+    fn new(group_by: Vec<Expr>, input_columns: &'a [String]) -> Result<Self> {
+        let group_by = group_by.into_iter()
+            .map(|expr| {
+                check_columns(&expr, input_columns)?;
+                let name = output_name(&expr);
+                Ok((expr, name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { input_columns, group_by, aggregates: Vec::new() })
+    }
+
+    /// Output columns of the aggregation
+    fn columns(&self) -> Vec<String> {
+        self.group_by.iter().map(|(_, name)| name.clone())
+            .chain(self.aggregates.iter().map(|a| a.name.clone()))
+            .collect()
+    }
+
+    /// Rewrite `expr` to read the aggregation output, registering any
+    /// aggregates it uses. Fails on input columns that are neither grouped
+    /// nor aggregated.
+    fn rewrite(&mut self, expr: &Expr) -> Result<Expr> {
+        if let Some((_, name)) = self.group_by.iter().find(|(grouped, _)| grouped == expr) {
+            return Ok(Expr::Column(name.clone()));
+        }
+
+        match expr {
+            Expr::Aggregate { func, arg } => {
+                if let Some(arg) = arg {
+                    check_columns(arg, self.input_columns)?;
+                }
+                let name = expr.to_string();
+                if !self.aggregates.iter().any(|a| a.name == name) {
+                    self.aggregates.push(AggregateExpr {
+                        func: *func,
+                        arg: arg.as_deref().cloned(),
+                        name: name.clone(),
+                    });
+                }
+                Ok(Expr::Column(name))
+            }
+            Expr::Column(name) if self.input_columns.contains(name) => Err(QueryError::Plan(format!(
+                "column {} must appear in the GROUP BY clause or be used in an aggregate function", name
+            ))),
+            Expr::Column(_) | Expr::Literal(_) | Expr::InSubquery { .. } | Expr::Default | Expr::Parameter(_) => {
+                check_columns(expr, &[]).map(|_| expr.clone())
+            }
+            Expr::Unary { op, expr } => Ok(Expr::Unary { op: *op, expr: Box::new(self.rewrite(expr)?) }),
+            Expr::IsNull { expr, negated } => {
+                Ok(Expr::IsNull { expr: Box::new(self.rewrite(expr)?), negated: *negated })
+            }
+            Expr::Binary { left, op, right } => Ok(Expr::Binary {
+                left: Box::new(self.rewrite(left)?),
+                op: *op,
+                right: Box::new(self.rewrite(right)?),
+            }),
+            Expr::Function { name, args } => {
+                check_function(name, args)?;
+                let args = args.iter().map(|arg| self.rewrite(arg)).collect::<Result<Vec<_>>>()?;
+                Ok(Expr::Function { name: name.clone(), args })
+            }
+            Expr::InList { expr, list, negated } => Ok(Expr::InList {
+                expr: Box::new(self.rewrite(expr)?),
+                list: list.iter().map(|item| self.rewrite(item)).collect::<Result<Vec<_>>>()?,
+                negated: *negated,
+            }),
+        }
+    }
+}
+
+/// Check a WHERE predicate like `check_columns`, planning each
+/// `IN (SELECT ...)` subquery on its own and appending it to `subqueries` in
+/// the order the executor's evaluation expects
+fn plan_subqueries(
+    expr: &Expr,
+    available: &[String],
+    catalog: &Catalog,
+    subqueries: &mut Vec<PhysicalPlan>,
+) -> Result<()> {
+    match expr {
+        Expr::InSubquery { expr, subquery, .. } => {
+            let (plan, columns) = match QueryPlanner::plan_query((**subquery).clone(), catalog) {
+                Err(QueryError::ColumnNotFound(name)) if available.contains(&name) => {
+                    return Err(QueryError::Plan(format!(
+                        "correlated subqueries are not supported yet (column {} belongs to the outer query)",
+                        name
+                    )));
+                }
+                result => result?,
+            };
+            if columns.len() != 1 {
+                return Err(QueryError::Plan(format!(
+                    "subquery must return exactly one column, got {}", columns.len()
+                )));
+            }
+            subqueries.push(plan);
+            plan_subqueries(expr, available, catalog, subqueries)
+        }
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => {
+            plan_subqueries(expr, available, catalog, subqueries)
+        }
+        Expr::Binary { left, right, .. } => {
+            plan_subqueries(left, available, catalog, subqueries)?;
+            plan_subqueries(right, available, catalog, subqueries)
+        }
+        Expr::Function { name, args } => {
+            for arg in args {
+                plan_subqueries(arg, available, catalog, subqueries)?;
+            }
+            check_function(name, args)
+        }
+        Expr::InList { expr, list, .. } => {
+            plan_subqueries(expr, available, catalog, subqueries)?;
+            for item in list {
+                plan_subqueries(item, available, catalog, subqueries)?;
+            }
+            Ok(())
+        }
+        Expr::Column(_) | Expr::Literal(_) | Expr::Aggregate { .. } | Expr::Default | Expr::Parameter(_) => {
+            check_columns(expr, available)
         }
     }
 }
@@ -38,24 +1047,62 @@ impl QueryPlanner {
 #[cfg(test)]
 mod tests {
     use super::*;
-    
-    #[test]
-    fn test_plan_simple_select() {
-        let statement = SqlStatement::Select {
-            columns: vec!["*".to_string()],
-            table: "users".to_string(),
-            where_clause: None,
-        };
-        
-        let plan = QueryPlanner::plan(statement).unwrap();
-        
+    use crate::parser::SqlParser;
+    use nextdb_storage::{Durability, LSMTree, StorageConfig};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    async fn catalog_with_users(temp_dir: &TempDir) -> Catalog {
+        let config = StorageConfig {
+            data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+            wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+            durability: Durability::NoSync,
+            ..Default::default()
+        };
+        let storage = Arc::new(LSMTree::open(config).await.unwrap());
+        let catalog = Catalog::open(storage).await.unwrap();
+        let SqlStatement::CreateTable { name, columns, primary_key, .. } =
+            SqlParser::parse("CREATE TABLE users (id INT PRIMARY KEY, name TEXT)").unwrap()
+        else {
+            unreachable!()
+        };
+        catalog.create_table(&name, &columns, &primary_key, false).await.unwrap();
+        catalog
+    }
+
+    #[tokio::test]
+    async fn test_plan_simple_select() {
+        let temp_dir = TempDir::new().unwrap();
+        let catalog = catalog_with_users(&temp_dir).await;
+
+        let plan = QueryPlanner::plan(SqlParser::parse("SELECT * FROM users").unwrap(), &catalog).unwrap();
+
         match plan {
-            PhysicalPlan::TableScan { table, columns, filter } => {
+            PhysicalPlan::TableScan { table, columns, filter, .. } => {
                 assert_eq!(table, "users");
-                assert_eq!(columns, vec!["*".to_string()]);
+                assert_eq!(columns, vec!["id", "name"]);
                 assert_eq!(filter, None);
             }
             _ => panic!("Expected TableScan plan"),
         }
+
+        // The scan under a projection reads only what the query uses
+        let plan = QueryPlanner::plan(SqlParser::parse("SELECT UPPER(name) FROM users").unwrap(), &catalog).unwrap();
+        match plan.children()[0] {
+            PhysicalPlan::TableScan { columns, .. } => assert_eq!(columns, &vec!["name"]),
+            other => panic!("Expected TableScan plan, got {:?}", other),
+        }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_plan_rejects_unknown_names() {
+        let temp_dir = TempDir::new().unwrap();
+        let catalog = catalog_with_users(&temp_dir).await;
+
+        let plan = |sql: &str| QueryPlanner::plan(SqlParser::parse(sql).unwrap(), &catalog);
+        assert!(matches!(plan("SELECT * FROM missing"), Err(QueryError::TableNotFound(_))));
+        assert!(matches!(plan("SELECT age FROM users"), Err(QueryError::ColumnNotFound(_))));
+        assert!(matches!(plan("DESCRIBE missing"), Err(QueryError::TableNotFound(_))));
+        assert!(matches!(plan("SHOW TABLES WHERE owner = 'x'"), Err(QueryError::ColumnNotFound(_))));
+    }
+}
@@ -0,0 +1,66 @@
+//! Writes ordered by a replicated log, for executors in a cluster.
+//!
+//! An executor with a `Replicator` writes nothing to storage itself. Row
+//! changes, once checked, go to the replicator as the batch of puts and
+//! deletes they come to; schema changes and ANALYZE go as the statement.
+//! The replicator commits each write to its log and hands it to
+//! `QueryExecutor::apply_replicated` on every replica, in log order. Only
+//! then does the statement that wrote it return.
+//!
+//! Schema changes run again on each replica, so they must come out the
+//! same everywhere. They do, as they depend on nothing but the catalog and
+//! the data, which every replica has in the same state at that point.
+
+use crate::{ast::SqlStatement, error::Result, result::ResultSet};
+use futures::future::BoxFuture;
+use nextdb_storage::WriteOp;
+use serde::{Deserialize, Serialize};
+
+/// Applied-index record kept under `encoding::meta_key`
+pub(crate) const APPLIED_INDEX: &str = "replication.applied_index";
+
+/// A write as the replicated log carries it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ReplicatedWrite {
+    /// Keys to put, with their values, or delete, in one atomic batch
+    Batch(Vec<(Vec<u8>, Option<Vec<u8>>)>),
+    /// A statement to run on each replica
+    Schema(SqlStatement),
+}
+
+impl ReplicatedWrite {
+    pub fn batch(ops: Vec<WriteOp>) -> Self {
+        Self::Batch(ops.into_iter()
+            .map(|op| match op {
+                WriteOp::Put { key, value } => (key, Some(value)),
+                WriteOp::Delete { key } => (key, None),
+            })
+            .collect())
+    }
+
+    pub(crate) fn into_ops(writes: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> Vec<WriteOp> {
+        writes.into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => WriteOp::Put { key, value },
+                None => WriteOp::Delete { key },
+            })
+            .collect()
+    }
+}
+
+/// Orders the writes of every replica's executor
+pub trait Replicator: Send + Sync {
+    /// Commit `write` and return its result once this replica has applied
+    /// it, or fail if it could not be committed, as when this replica does
+    /// not lead
+    fn replicate(&self, write: ReplicatedWrite) -> BoxFuture<'_, Result<ResultSet>>;
+
+    /// Wait until reads on this replica see every write committed before
+    /// the call, if the replicator is set up for linearizable reads
+    fn read_barrier(&self) -> BoxFuture<'_, Result<()>>;
+
+    /// Wait until reads on this replica see every write committed before
+    /// the call, however the replicator is set up, as for a read that asks
+    /// to be linearizable
+    fn confirm_reads(&self) -> BoxFuture<'_, Result<()>>;
+}
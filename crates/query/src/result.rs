@@ -0,0 +1,210 @@
+//! Query results and their JSON form.
+//!
+//! Results serialize with natively typed values (see `Value::to_json`), so
+//! API clients can tell `1` from `"1"` and NULL from the string "null". The
+//! type of every column travels with the result; it is what tells a
+//! timestamp apart from text when reading one back.
+
+use crate::{
+    ast::DataType,
+    value::Value,
+};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+
+/// Name and type of one result column
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnMeta {
+    pub name: String,
+    /// SQL type of the column, or None if it could not be determined, as for
+    /// an expression that is NULL in every row
+    #[serde(with = "sql_type_name")]
+    pub data_type: Option<DataType>,
+}
+
+impl ColumnMeta {
+    pub fn new(name: impl Into<String>, data_type: Option<DataType>) -> Self {
+        Self { name: name.into(), data_type }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "WireResultSet")]
+pub struct ResultSet {
+    pub columns: Vec<ColumnMeta>,
+    pub rows: Vec<Vec<Value>>,
+    /// Rows inserted, updated or deleted by a DML statement
+    pub rows_affected: Option<u64>,
+}
+
+impl ResultSet {
+    /// A result with `rows`. Columns without a known type take the type of
+    /// their first non-NULL value.
+    pub fn new(mut columns: Vec<ColumnMeta>, rows: Vec<Vec<Value>>) -> Self {
+        for (i, column) in columns.iter_mut().enumerate() {
+            if column.data_type.is_none() {
+                column.data_type = rows.iter().find_map(|row| row.get(i).and_then(Value::data_type));
+            }
+        }
+        Self { columns, rows, rows_affected: None }
+    }
+
+    pub(crate) fn affected(count: u64) -> Self {
+        Self { columns: Vec::new(), rows: Vec::new(), rows_affected: Some(count) }
+    }
+
+    pub(crate) fn empty() -> Self {
+        Self { columns: Vec::new(), rows: Vec::new(), rows_affected: None }
+    }
+
+    pub fn column_names(&self) -> Vec<&str> {
+        self.columns.iter().map(|column| column.name.as_str()).collect()
+    }
+
+    /// Rows with every value rendered as display text
+    pub fn text_rows(&self) -> Vec<Vec<String>> {
+        self.rows.iter()
+            .map(|row| row.iter().map(Value::to_string).collect())
+            .collect()
+    }
+}
+
+impl Serialize for ResultSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let rows: Vec<Vec<serde_json::Value>> = self.rows.iter()
+            .map(|row| row.iter().map(Value::to_json).collect())
+            .collect();
+
+        let mut state = serializer.serialize_struct("ResultSet", 3)?;
+        state.serialize_field("columns", &self.columns)?;
+        state.serialize_field("rows", &rows)?;
+        state.serialize_field("rows_affected", &self.rows_affected)?;
+        state.end()
+    }
+}
+
+/// A `ResultSet` as it appears in JSON, before values are read with their
+/// column types
+#[derive(Deserialize)]
+struct WireResultSet {
+    columns: Vec<ColumnMeta>,
+    rows: Vec<Vec<serde_json::Value>>,
+    rows_affected: Option<u64>,
+}
+
+impl TryFrom<WireResultSet> for ResultSet {
+    type Error = String;
+
+    fn try_from(wire: WireResultSet) -> std::result::Result<Self, String> {
+        let rows = wire.rows.iter()
+            .map(|row| {
+                if row.len() != wire.columns.len() {
+                    return Err(format!("row has {} values but result has {} columns", row.len(), wire.columns.len()));
+                }
+                row.iter()
+                    .zip(&wire.columns)
+                    .map(|(json, column)| Value::from_json(json, column.data_type).map_err(|e| e.to_string()))
+                    .collect()
+            })
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(Self { columns: wire.columns, rows, rows_affected: wire.rows_affected })
+    }
+}
+
+/// Column types as their SQL names ("INTEGER", "TIMESTAMP", ...) rather than
+/// the variant names the catalog persists
+mod sql_type_name {
+    use crate::ast::DataType;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data_type: &Option<DataType>, serializer: S) -> Result<S::Ok, S::Error> {
+        match data_type {
+            Some(data_type) => serializer.collect_str(data_type),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DataType>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|name| DataType::from_name(&name).ok_or_else(|| D::Error::custom(format!("unknown data type {}", name))))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::parse_timestamp;
+    use serde_json::json;
+
+    #[test]
+    fn test_json_round_trip() {
+        let timestamp = parse_timestamp("2024-03-10T07:30:00.25Z").unwrap();
+        let columns = vec![
+            ColumnMeta::new("i", Some(DataType::Integer)),
+            ColumnMeta::new("f", Some(DataType::Float)),
+            ColumnMeta::new("t", Some(DataType::Text)),
+            ColumnMeta::new("b", Some(DataType::Boolean)),
+            ColumnMeta::new("x", Some(DataType::Blob)),
+            ColumnMeta::new("ts", Some(DataType::Timestamp)),
+            ColumnMeta::new("n", None),
+        ];
+        let rows = vec![
+            vec![
+                Value::Integer(1), Value::Float(1.0), Value::Text("1".to_string()), Value::Boolean(true),
+                Value::Blob(vec![0, 159, 255]), Value::Timestamp(timestamp), Value::Null,
+            ],
+            vec![
+                Value::Integer(i64::MIN), Value::Float(f64::NAN), Value::Text("null".to_string()), Value::Null,
+                Value::Blob(Vec::new()), Value::Null, Value::Null,
+            ],
+            vec![
+                Value::Null, Value::Float(f64::NEG_INFINITY), Value::Text("2024-01-01".to_string()),
+                Value::Boolean(false), Value::Null, Value::Timestamp(-1), Value::Null,
+            ],
+        ];
+        let result = ResultSet::new(columns, rows);
+
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["columns"][0], json!({ "name": "i", "data_type": "INTEGER" }));
+        assert_eq!(json["columns"][6], json!({ "name": "n", "data_type": null }));
+        assert_eq!(json["rows"][0], json!([1, 1.0, "1", true, { "$base64": "AJ//" }, "2024-03-10T07:30:00.250Z", null]));
+        assert_eq!(json["rows"][1][1], json!({ "$float": "NaN" }));
+        assert_eq!(json["rows"][1][2], json!("null"));
+        assert_eq!(json["rows"][1][4], json!({ "$base64": "" }));
+        assert_eq!(json["rows"][2][1], json!({ "$float": "-inf" }));
+        assert_eq!(json["rows_affected"], json!(null));
+
+        let decoded: ResultSet = serde_json::from_str(&json.to_string()).unwrap();
+        assert_eq!(decoded.columns, result.columns);
+        assert_eq!(decoded.text_rows(), result.text_rows());
+        for (decoded, original) in decoded.rows.iter().flatten().zip(result.rows.iter().flatten()) {
+            assert_eq!(decoded.data_type(), original.data_type());
+        }
+        assert_eq!(decoded.rows[0][0], Value::Integer(1));
+        assert_eq!(decoded.rows[0][1], Value::Float(1.0));
+        assert_eq!(decoded.rows[0][5], Value::Timestamp(timestamp));
+        assert_eq!(decoded.rows[2][2], Value::Text("2024-01-01".to_string()));
+    }
+
+    #[test]
+    fn test_column_types_and_bad_json() {
+        // Untyped columns pick up the type of their first non-NULL value
+        let result = ResultSet::new(
+            vec![ColumnMeta::new("a", None), ColumnMeta::new("b", None)],
+            vec![vec![Value::Null, Value::Null], vec![Value::Float(0.5), Value::Null]],
+        );
+        assert_eq!(result.columns[0].data_type, Some(DataType::Float));
+        assert_eq!(result.columns[1].data_type, None);
+
+        for bad in [
+            json!({ "columns": [{ "name": "a", "data_type": "INTEGER" }], "rows": [["1"]], "rows_affected": null }),
+            json!({ "columns": [{ "name": "a", "data_type": "INTEGER" }], "rows": [[1.5]], "rows_affected": null }),
+            json!({ "columns": [{ "name": "a", "data_type": "BLOB" }], "rows": [[{ "$base64": "!!" }]], "rows_affected": null }),
+            json!({ "columns": [{ "name": "a", "data_type": "UUID" }], "rows": [], "rows_affected": null }),
+            json!({ "columns": [{ "name": "a", "data_type": null }], "rows": [[1, 2]], "rows_affected": null }),
+        ] {
+            assert!(serde_json::from_value::<ResultSet>(bad.clone()).is_err(), "{} should not decode", bad);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Client sessions and the transactions open in them.
+//!
+//! Outside a transaction every statement commits on its own. Between BEGIN
+//! and COMMIT a session's writes are buffered in the `TransactionManager` and
+//! applied in one storage batch when it commits, so other sessions see all of
+//! them or none. Reads inside the transaction see its own buffered writes on
+//! top of committed data: the latest committed data at READ COMMITTED and
+//! below, or the data as of BEGIN at REPEATABLE READ and SERIALIZABLE.
+
+use crate::error::{QueryError, Result};
+use nextdb_storage::LSMTree;
+use nextdb_transaction::{TransactionId, TransactionManager};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Identifies a client session, as assigned by the server. A session has at
+/// most one open transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId(pub u64);
+
+/// State of a session's open transaction that the executor keeps beside the
+/// manager's write set
+#[derive(Debug)]
+pub(crate) struct OpenTransaction {
+    pub(crate) id: TransactionId,
+    /// A statement failed, so only ROLLBACK is accepted
+    pub(crate) failed: bool,
+    /// Net rows added to each table written, by table id
+    pub(crate) table_writes: HashMap<u64, i64>,
+    /// Row keys the transaction inserted that did not exist when it checked;
+    /// they must still not exist when it commits
+    pub(crate) new_keys: HashMap<Vec<u8>, Claim>,
+    /// Unique index values claimed by rows the transaction wrote, as index
+    /// key prefixes; no other row may hold them when it commits
+    pub(crate) unique_prefixes: HashMap<Vec<u8>, Claim>,
+}
+
+/// A primary key or unique value taken by a row, described as the
+/// constraint another row holding it would violate
+#[derive(Debug, Clone)]
+pub(crate) struct Claim {
+    pub(crate) constraint: String,
+    pub(crate) value: String,
+}
+
+impl Claim {
+    pub(crate) fn violation(&self) -> QueryError {
+        QueryError::ConstraintViolation { constraint: self.constraint.clone(), value: self.value.clone() }
+    }
+}
+
+impl OpenTransaction {
+    pub(crate) fn new(id: TransactionId) -> Self {
+        Self {
+            id,
+            failed: false,
+            table_writes: HashMap::new(),
+            new_keys: HashMap::new(),
+            unique_prefixes: HashMap::new(),
+        }
+    }
+}
+
+/// Storage as one statement sees it: committed data, or inside a transaction
+/// that data overlaid with what the transaction has written
+#[derive(Clone)]
+pub(crate) struct ReadView {
+    storage: Arc<LSMTree>,
+    txn: Option<TransactionView>,
+    // Rows read by table scans through this view and its clones
+    examined: Arc<AtomicU64>,
+}
+
+#[derive(Clone)]
+struct TransactionView {
+    manager: Arc<TransactionManager>,
+    id: TransactionId,
+    table_writes: HashMap<u64, i64>,
+}
+
+impl ReadView {
+    pub(crate) fn committed(storage: Arc<LSMTree>) -> Self {
+        Self { storage, txn: None, examined: Arc::default() }
+    }
+
+    pub(crate) fn transaction(storage: Arc<LSMTree>, manager: Arc<TransactionManager>, txn: &OpenTransaction) -> Self {
+        let view = TransactionView { manager, id: txn.id, table_writes: txn.table_writes.clone() };
+        Self { storage, txn: Some(view), examined: Arc::default() }
+    }
+
+    pub(crate) fn record_examined(&self, rows: usize) {
+        self.examined.fetch_add(rows as u64, Ordering::Relaxed);
+    }
+
+    /// Rows table scans have read through the view
+    pub(crate) fn rows_examined(&self) -> u64 {
+        self.examined.load(Ordering::Relaxed)
+    }
+
+    /// Rows the transaction has added to a table, to adjust its stored count
+    pub(crate) fn row_delta(&self, table_id: u64) -> i64 {
+        self.txn.as_ref().and_then(|txn| txn.table_writes.get(&table_id).copied()).unwrap_or(0)
+    }
+
+    pub(crate) async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(txn) = &self.txn {
+            if let Some(value) = txn.manager.buffered(txn.id, key) {
+                return Ok(value);
+            }
+            if let Some(value) = txn.manager.snapshot_version(txn.id, key) {
+                return Ok(value);
+            }
+        }
+        Ok(self.storage.get(key).await?)
+    }
+
+    /// Like `get` for each of `keys`, in the same order, reading storage in
+    /// one batch
+    pub(crate) async fn multi_get(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        let Some(txn) = &self.txn else {
+            return Ok(self.storage.multi_get(keys).await?);
+        };
+
+        let overlay: Vec<Option<Option<Vec<u8>>>> = keys.iter()
+            .map(|key| txn.manager.buffered(txn.id, key).or_else(|| txn.manager.snapshot_version(txn.id, key)))
+            .collect();
+        let missing: Vec<Vec<u8>> = keys.iter().zip(&overlay)
+            .filter(|(_, value)| value.is_none())
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut stored = self.storage.multi_get(&missing).await?.into_iter();
+        Ok(overlay.into_iter()
+            .map(|value| value.unwrap_or_else(|| stored.next().expect("one stored value per missing key")))
+            .collect())
+    }
+
+    /// Like `LSMTree::scan`: up to `limit` live entries in `start..end`, and
+    /// fewer only when the range is exhausted
+    pub(crate) async fn scan(&self, start: &[u8], end: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let Some(txn) = &self.txn else {
+            return Ok(self.storage.scan(start, end, limit).await?);
+        };
+
+        let mut entries = Vec::new();
+        let mut cursor = start.to_vec();
+        loop {
+            let page = self.storage.scan(&cursor, end, limit).await?;
+            let exhausted = page.len() < limit;
+            // Overlay the transaction's view on the same range the page covers
+            let page_end = match page.last() {
+                Some((last, _)) if !exhausted => [last.as_slice(), &[0]].concat(),
+                _ => end.to_vec(),
+            };
+            let mut merged: BTreeMap<Vec<u8>, Vec<u8>> = page.into_iter().collect();
+            let overlay = txn.manager.snapshot_range(txn.id, &cursor, &page_end).into_iter()
+                .chain(txn.manager.buffered_range(txn.id, &cursor, &page_end));
+            for (key, value) in overlay {
+                match value {
+                    Some(value) => merged.insert(key, value),
+                    None => merged.remove(&key),
+                };
+            }
+            entries.extend(merged);
+
+            if exhausted || entries.len() >= limit {
+                entries.truncate(limit);
+                return Ok(entries);
+            }
+            cursor = page_end;
+        }
+    }
+}
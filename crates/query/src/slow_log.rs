@@ -0,0 +1,152 @@
+//! Log of statements that ran longer than a threshold.
+//!
+//! Each slow statement is logged as a warning under the `nextdb::slow_query`
+//! target with its SQL's fingerprint (see `lexer::fingerprint`), execution
+//! time, the rows its table scans read and its plan. At most
+//! `max_per_second` are logged each second; the rest are counted and the
+//! count is reported with the next one logged. The most recent slow
+//! statements are also kept for inspection, with their full SQL.
+
+use crate::lexer::fingerprint;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Slow statements kept for `SlowQueryLog::recent`
+const RECENT: usize = 64;
+
+/// Settings for the slow query log
+#[derive(Debug, Clone)]
+pub struct SlowQueryConfig {
+    /// Statements taking at least this long are slow
+    pub threshold: Duration,
+    /// Most slow statements logged in any one second
+    pub max_per_second: u32,
+}
+
+impl Default for SlowQueryConfig {
+    fn default() -> Self {
+        Self {
+            threshold: Duration::from_millis(100),
+            max_per_second: 10,
+        }
+    }
+}
+
+/// A statement that ran longer than the threshold
+#[derive(Debug, Clone)]
+pub struct SlowQuery {
+    /// The statement as SQL, or None for a plan executed directly
+    pub sql: Option<String>,
+    pub duration: Duration,
+    /// Rows read from storage by the statement's table scans
+    pub rows_examined: u64,
+    /// The plan as EXPLAIN shows it
+    pub plan: Vec<String>,
+    pub succeeded: bool,
+}
+
+pub struct SlowQueryLog {
+    config: Mutex<SlowQueryConfig>,
+    window: Mutex<Window>,
+    recent: Mutex<VecDeque<SlowQuery>>,
+}
+
+/// Slow statements seen in the current second
+struct Window {
+    started: Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+impl SlowQueryLog {
+    pub fn new(config: SlowQueryConfig) -> Self {
+        Self {
+            config: Mutex::new(config),
+            window: Mutex::new(Window { started: Instant::now(), logged: 0, suppressed: 0 }),
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn threshold(&self) -> Duration {
+        self.config.lock().threshold
+    }
+
+    /// Use `config` for the statements that finish from now on
+    pub fn set_config(&self, config: SlowQueryConfig) {
+        *self.config.lock() = config;
+    }
+
+    /// Record a statement that took `duration`, building its entry with
+    /// `query` only if it was slow
+    pub(crate) fn observe(&self, duration: Duration, query: impl FnOnce() -> SlowQuery) {
+        if duration < self.threshold() {
+            return;
+        }
+        let query = query();
+
+        let max_per_second = self.config.lock().max_per_second;
+        let suppressed = {
+            let mut window = self.window.lock();
+            if window.started.elapsed() >= Duration::from_secs(1) {
+                window.started = Instant::now();
+                window.logged = 0;
+            }
+            if window.logged < max_per_second {
+                window.logged += 1;
+                Some(std::mem::take(&mut window.suppressed))
+            } else {
+                window.suppressed += 1;
+                None
+            }
+        };
+        if let Some(suppressed) = suppressed {
+            let skipped = if suppressed > 0 { format!(" ({} slow queries not logged)", suppressed) } else { String::new() };
+            tracing::warn!(
+                target: "nextdb::slow_query",
+                "Slow query took {:.3} ms, examined {} rows{}: {} | plan: {}",
+                query.duration.as_secs_f64() * 1000.0,
+                query.rows_examined,
+                skipped,
+                query.sql.as_deref().map_or("<plan>".to_string(), fingerprint),
+                query.plan.iter().map(|line| line.trim()).collect::<Vec<_>>().join(" <- "),
+            );
+        }
+
+        let mut recent = self.recent.lock();
+        if recent.len() == RECENT {
+            recent.pop_front();
+        }
+        recent.push_back(query);
+    }
+
+    /// The most recent slow statements, oldest first, whether or not they
+    /// were logged
+    pub fn recent(&self) -> Vec<SlowQuery> {
+        self.recent.lock().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logging_is_rate_limited() {
+        let log = SlowQueryLog::new(SlowQueryConfig { threshold: Duration::from_millis(5), max_per_second: 2 });
+        let query = |sql: &str| {
+            let sql = sql.to_string();
+            move || SlowQuery { sql: Some(sql), duration: Duration::from_millis(10), rows_examined: 0, plan: Vec::new(), succeeded: true }
+        };
+        log.observe(Duration::from_millis(1), query("fast"));
+        for i in 0..4 {
+            log.observe(Duration::from_millis(10), query(&format!("slow {}", i)));
+        }
+
+        let window = log.window.lock();
+        assert_eq!((window.logged, window.suppressed), (2, 2));
+        drop(window);
+        let recent: Vec<_> = log.recent().into_iter().map(|query| query.sql.unwrap()).collect();
+        assert_eq!(recent, vec!["slow 0", "slow 1", "slow 2", "slow 3"]);
+    }
+}
@@ -0,0 +1,320 @@
+//! ORDER BY for inputs larger than memory.
+//!
+//! Rows are buffered, each with its evaluated sort keys in front, until the
+//! memory budget is used up; the buffer is then sorted and written out as a
+//! run. Input that fits the budget is sorted in memory and never touches
+//! disk. Otherwise the runs are combined with a k-way merge, taking several
+//! passes when there are more runs than can be read at once within budget.
+//! Ties keep input order, in memory and across runs alike.
+
+use crate::{
+    ast::OrderByExpr,
+    error::Result,
+    eval,
+    executor::{OperatorStats, Row, RowStream},
+    spill::{self, MemoryBudget, RunReader, RunWriter, SpillConfig, SpillRun},
+    value::Value,
+};
+use futures::stream::{StreamExt, TryStreamExt};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+
+// Most runs merged at once, however large the budget
+const MAX_FAN_IN: usize = 64;
+
+/// Sort `input`, whose rows have `columns`, by `order_by`. Rows buffered in
+/// memory also count towards `query`, the memory of the whole query.
+pub(crate) fn sort_stream(
+    mut input: RowStream,
+    order_by: Vec<OrderByExpr>,
+    columns: Vec<String>,
+    config: SpillConfig,
+    query: Option<Arc<MemoryBudget>>,
+    stats: Arc<OperatorStats>,
+) -> RowStream {
+    let width = order_by.len();
+    let descending: Arc<[bool]> = order_by.iter().map(|key| key.descending).collect();
+
+    let rows = async_stream::try_stream! {
+        let budget = Arc::new(MemoryBudget::new(config.memory_budget).within(query));
+        let mut buffer: Vec<Row> = Vec::new();
+        let mut runs: Vec<SpillRun> = Vec::new();
+
+        while let Some(row) = input.try_next().await? {
+            let mut keyed = order_by.iter()
+                .map(|key| eval::eval(&key.expr, &columns, &row))
+                .collect::<Result<Row>>()?;
+            keyed.extend(row);
+            budget.reserve(spill::row_size(&keyed));
+            buffer.push(keyed);
+
+            if budget.is_exceeded() {
+                runs.push(write_run(&mut buffer, &descending, &config, &budget, &stats).await?);
+                budget.check()?;
+            }
+        }
+
+        if runs.is_empty() {
+            sort_keyed(&mut buffer, &descending);
+            stats.record_peak_memory(budget.peak());
+            for mut row in buffer {
+                yield row.split_off(width);
+            }
+        } else {
+            if !buffer.is_empty() {
+                runs.push(write_run(&mut buffer, &descending, &config, &budget, &stats).await?);
+            }
+
+            // Each run being merged holds about one decoded block in memory
+            let fan_in = (budget.limit() / (4 * spill::BLOCK_BYTES)).clamp(2, MAX_FAN_IN);
+            while runs.len() > fan_in {
+                let mut merged = Vec::with_capacity(runs.len().div_ceil(fan_in));
+                let mut pending = runs.into_iter().peekable();
+                while pending.peek().is_some() {
+                    let group: Vec<SpillRun> = pending.by_ref().take(fan_in).collect();
+                    let mut merge = Merge::open(group, &descending, &budget).await?;
+                    let mut writer = RunWriter::create(&config).await?;
+                    while let Some(row) = merge.next().await? {
+                        writer.push(&row).await?;
+                    }
+                    let run = writer.finish().await?;
+                    stats.record_spill(run.rows(), run.bytes());
+                    merged.push(run);
+                }
+                runs = merged;
+            }
+
+            let mut merge = Merge::open(runs, &descending, &budget).await?;
+            stats.record_peak_memory(budget.peak());
+            while let Some(mut row) = merge.next().await? {
+                yield row.split_off(width);
+            }
+            stats.record_peak_memory(budget.peak());
+        }
+    };
+    rows.boxed()
+}
+
+/// Sort the buffered rows into a new run and empty the buffer
+async fn write_run(
+    buffer: &mut Vec<Row>,
+    descending: &[bool],
+    config: &SpillConfig,
+    budget: &MemoryBudget,
+    stats: &OperatorStats,
+) -> Result<SpillRun> {
+    sort_keyed(buffer, descending);
+    let mut writer = RunWriter::create(config).await?;
+    for row in buffer.iter() {
+        writer.push(row).await?;
+    }
+    let run = writer.finish().await?;
+
+    let held: usize = buffer.iter().map(|row| spill::row_size(row)).sum();
+    buffer.clear();
+    stats.record_peak_memory(budget.peak());
+    budget.release(held);
+    stats.record_spill(run.rows(), run.bytes());
+    Ok(run)
+}
+
+fn sort_keyed(rows: &mut [Row], descending: &[bool]) {
+    rows.sort_by(|a, b| compare_keys(a, b, descending));
+}
+
+/// Order of two keyed rows by their leading sort keys. NULLs sort last
+/// ascending and first descending.
+fn compare_keys(a: &[Value], b: &[Value], descending: &[bool]) -> Ordering {
+    a.iter().zip(b).zip(descending)
+        .map(|((x, y), descending)| {
+            let ordering = x.sort_cmp(y);
+            if *descending { ordering.reverse() } else { ordering }
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// K-way merge of sorted runs
+struct Merge {
+    readers: Vec<RunReader>,
+    heap: BinaryHeap<MergeHead>,
+}
+
+impl Merge {
+    async fn open(runs: Vec<SpillRun>, descending: &Arc<[bool]>, budget: &Arc<MemoryBudget>) -> Result<Self> {
+        let mut readers = Vec::with_capacity(runs.len());
+        let mut heap = BinaryHeap::with_capacity(runs.len());
+        for (run, spilled) in runs.into_iter().enumerate() {
+            let mut reader = spilled.open(budget.clone()).await?;
+            if let Some(row) = reader.next().await? {
+                heap.push(MergeHead { row, run, descending: descending.clone() });
+            }
+            readers.push(reader);
+        }
+        Ok(Self { readers, heap })
+    }
+
+    async fn next(&mut self) -> Result<Option<Row>> {
+        let Some(head) = self.heap.pop() else {
+            return Ok(None);
+        };
+        if let Some(row) = self.readers[head.run].next().await? {
+            self.heap.push(MergeHead { row, run: head.run, descending: head.descending.clone() });
+        }
+        Ok(Some(head.row))
+    }
+}
+
+/// The next row of one run. Ordered so the max-heap pops the smallest row,
+/// and among equal rows the one from the earliest run.
+struct MergeHead {
+    row: Row,
+    run: usize,
+    descending: Arc<[bool]>,
+}
+
+impl Ord for MergeHead {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_keys(&self.row, &other.row, &self.descending)
+            .then(self.run.cmp(&other.run))
+            .reverse()
+    }
+}
+
+impl PartialOrd for MergeHead {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for MergeHead {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeHead {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Expr;
+    use crate::error::QueryError;
+    use futures::stream;
+    use std::sync::atomic::Ordering as AtomicOrdering;
+    use tempfile::TempDir;
+
+    fn config(dir: &TempDir, memory_budget: usize) -> SpillConfig {
+        SpillConfig { memory_budget, dir: dir.path().to_path_buf() }
+    }
+
+    fn order_by(column: &str, descending: bool) -> Vec<OrderByExpr> {
+        vec![OrderByExpr { expr: Expr::Column(column.to_string()), descending }]
+    }
+
+    fn columns() -> Vec<String> {
+        vec!["k".to_string(), "seq".to_string(), "payload".to_string()]
+    }
+
+    /// Rows with pseudo-random keys that repeat, so ties are common
+    fn synthetic_rows(count: i64) -> impl Iterator<Item = Row> {
+        (0..count).map(|seq| {
+            let k = (seq * 7_919 + 13) % 10_007;
+            let k = if seq % 97 == 0 { Value::Null } else { Value::Integer(k) };
+            vec![k, Value::Integer(seq), Value::Text(format!("row-{:08}", seq))]
+        })
+    }
+
+    fn files_in(dir: &TempDir) -> usize {
+        std::fs::read_dir(dir.path()).unwrap().count()
+    }
+
+    #[tokio::test]
+    async fn test_external_sort_large_input() {
+        let dir = TempDir::new().unwrap();
+        let budget = 256 * 1024;
+        let count = 300_000;
+        let stats = Arc::new(OperatorStats::default());
+
+        let input = stream::iter(synthetic_rows(count).map(Ok)).boxed();
+        let sorted: Vec<Row> = sort_stream(input, order_by("k", false), columns(), config(&dir, budget), None, stats.clone())
+            .try_collect()
+            .await
+            .unwrap();
+
+        assert_eq!(sorted.len(), count as usize);
+        for pair in sorted.windows(2) {
+            let ordering = pair[0][0].sort_cmp(&pair[1][0]);
+            assert!(ordering.is_lt() || (ordering.is_eq() && pair[0][1].sort_cmp(&pair[1][1]).is_lt()),
+                "out of order: {:?} then {:?}", pair[0], pair[1]);
+        }
+        assert!(sorted[count as usize - 1][0].is_null());
+        assert_eq!(sorted[0][2], Value::Text(format!("row-{:08}", match &sorted[0][1] {
+            Value::Integer(seq) => *seq,
+            other => panic!("unexpected {:?}", other),
+        })));
+
+        // Many runs, merged in more than one pass, without exceeding the
+        // budget by more than the row that triggered a spill
+        let runs = stats.spilled_runs.load(AtomicOrdering::Relaxed);
+        assert!(runs > 64, "only {} runs", runs);
+        assert!(stats.spilled_rows.load(AtomicOrdering::Relaxed) > count as u64);
+        let peak = stats.peak_memory.load(AtomicOrdering::Relaxed) as usize;
+        assert!(peak > budget / 2 && peak <= budget + 1024, "peak memory {}", peak);
+        assert_eq!(files_in(&dir), 0);
+    }
+
+    #[tokio::test]
+    async fn test_sort_in_memory_and_descending() {
+        let dir = TempDir::new().unwrap();
+        let stats = Arc::new(OperatorStats::default());
+        let input = stream::iter(synthetic_rows(1_000).map(Ok)).boxed();
+        let sorted: Vec<Row> = sort_stream(input, order_by("seq", true), columns(), config(&dir, 1 << 20), None, stats.clone())
+            .try_collect()
+            .await
+            .unwrap();
+
+        let seqs: Vec<Value> = sorted.iter().map(|row| row[1].clone()).collect();
+        assert_eq!(seqs, (0..1_000).rev().map(Value::Integer).collect::<Vec<_>>());
+        assert_eq!(stats.spilled_runs.load(AtomicOrdering::Relaxed), 0);
+
+        // Descending with spills puts NULL keys first
+        let input = stream::iter(synthetic_rows(20_000).map(Ok)).boxed();
+        let sorted: Vec<Row> = sort_stream(input, order_by("k", true), columns(), config(&dir, 64 * 1024), None, stats.clone())
+            .try_collect()
+            .await
+            .unwrap();
+        assert!(stats.spilled_runs.load(AtomicOrdering::Relaxed) > 0);
+        assert!(sorted[0][0].is_null());
+        assert_eq!(sorted.iter().filter(|row| row[0].is_null()).count(), 20_000_usize.div_ceil(97));
+        assert!(sorted.windows(2).all(|pair| pair[0][0].sort_cmp(&pair[1][0]).is_ge() || pair[0][0].is_null()));
+    }
+
+    #[tokio::test]
+    async fn test_spill_files_removed_on_error_and_cancel() {
+        let dir = TempDir::new().unwrap();
+
+        // The input fails after enough rows to have spilled
+        let failing = stream::iter(synthetic_rows(50_000).map(Ok))
+            .chain(stream::once(async { Err(QueryError::Execution("input failed".to_string())) }))
+            .boxed();
+        let stats = Arc::new(OperatorStats::default());
+        let result: Result<Vec<Row>> = sort_stream(failing, order_by("k", false), columns(), config(&dir, 64 * 1024), None, stats.clone())
+            .try_collect()
+            .await;
+        assert!(result.is_err());
+        assert!(stats.spilled_runs.load(AtomicOrdering::Relaxed) > 0);
+        assert_eq!(files_in(&dir), 0);
+
+        // The consumer stops reading halfway through the merge
+        let input = stream::iter(synthetic_rows(50_000).map(Ok)).boxed();
+        let mut sorted = sort_stream(input, order_by("k", false), columns(), config(&dir, 64 * 1024), None, stats);
+        for _ in 0..10 {
+            sorted.try_next().await.unwrap().unwrap();
+        }
+        assert!(files_in(&dir) > 0);
+        drop(sorted);
+        assert_eq!(files_in(&dir), 0);
+    }
+}
@@ -0,0 +1,263 @@
+//! Temporary on-disk storage for operators whose input outgrows memory.
+//!
+//! Rows are spilled as runs: files of LZ4-compressed blocks of encoded rows,
+//! read back in the order they were written. A run's file is deleted when the
+//! run or its reader is dropped, so files never outlive the query that wrote
+//! them, whether it completes, fails or is cancelled by dropping its stream.
+
+use crate::{
+    error::{QueryError, Result},
+    encoding,
+    executor::Row,
+    value::Value,
+};
+use nextdb_storage::compression::{self, CompressionType};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tempfile::TempPath;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+// Encoded bytes gathered into one block before it is compressed and written
+pub(crate) const BLOCK_BYTES: usize = 16 * 1024;
+
+/// Settings for operators that spill to disk
+#[derive(Debug, Clone)]
+pub struct SpillConfig {
+    /// Memory one sort or aggregation may hold in buffered rows or groups,
+    /// as measured by `Value::size_hint`, before it spills them
+    pub memory_budget: usize,
+    /// Directory for temporary run files
+    pub dir: PathBuf,
+}
+
+impl Default for SpillConfig {
+    fn default() -> Self {
+        Self {
+            memory_budget: 64 * 1024 * 1024,
+            dir: std::env::temp_dir(),
+        }
+    }
+}
+
+/// Memory held by one operator, checked against its budget and, when the
+/// query has a memory limit, counted towards the query's too
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    query: Option<Arc<MemoryBudget>>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, used: AtomicUsize::new(0), peak: AtomicUsize::new(0), query: None }
+    }
+
+    /// Count this operator's memory towards `query` as well
+    pub fn within(mut self, query: Option<Arc<MemoryBudget>>) -> Self {
+        self.query = query;
+        self
+    }
+
+    pub fn reserve(&self, bytes: usize) {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.peak.fetch_max(used, Ordering::Relaxed);
+        if let Some(query) = &self.query {
+            query.reserve(bytes);
+        }
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+        if let Some(query) = &self.query {
+            query.release(bytes);
+        }
+    }
+
+    /// Whether the operator or its query holds more than its limit
+    pub fn is_exceeded(&self) -> bool {
+        self.used() > self.limit || self.query.as_ref().is_some_and(|query| query.is_exceeded())
+    }
+
+    /// Fail if the query holds more than its limit, for operators that
+    /// cannot spill or have spilled what they could
+    pub fn check(&self) -> Result<()> {
+        match &self.query {
+            Some(query) if query.is_exceeded() => Err(QueryError::Execution(format!(
+                "out of memory: the query needs more than {} bytes", query.limit
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// The smaller of the operator's limit and its query's
+    pub fn limit(&self) -> usize {
+        self.query.as_ref().map_or(self.limit, |query| self.limit.min(query.limit))
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Most memory held at once
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for MemoryBudget {
+    // An operator dropped before it finished gives its memory back to the
+    // query, which may carry on reading other operators
+    fn drop(&mut self) {
+        if let Some(query) = &self.query {
+            query.release(self.used());
+        }
+    }
+}
+
+pub(crate) fn row_size(row: &[Value]) -> usize {
+    row.iter().map(Value::size_hint).sum()
+}
+
+/// Writes rows to a new run file
+pub(crate) struct RunWriter {
+    file: BufWriter<File>,
+    path: TempPath,
+    block: Vec<u8>,
+    rows: u64,
+    bytes: u64,
+}
+
+impl RunWriter {
+    pub(crate) async fn create(config: &SpillConfig) -> Result<Self> {
+        let (file, path) = tempfile::Builder::new()
+            .prefix("nextdb-spill-")
+            .tempfile_in(&config.dir)?
+            .into_parts();
+        Ok(Self {
+            file: BufWriter::new(File::from_std(file)),
+            path,
+            block: Vec::with_capacity(BLOCK_BYTES),
+            rows: 0,
+            bytes: 0,
+        })
+    }
+
+    pub(crate) async fn push(&mut self, row: &[Value]) -> Result<()> {
+        let columns: Vec<(u32, &Value)> = row.iter().enumerate().map(|(i, v)| (i as u32, v)).collect();
+        let encoded = encoding::encode_row(&columns);
+        self.block.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+        self.block.extend_from_slice(&encoded);
+        self.rows += 1;
+        if self.block.len() >= BLOCK_BYTES {
+            self.write_block().await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn finish(mut self) -> Result<SpillRun> {
+        self.write_block().await?;
+        self.file.flush().await?;
+        Ok(SpillRun { path: self.path, rows: self.rows, bytes: self.bytes })
+    }
+
+    async fn write_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let compressed = compression::compress(&self.block, &CompressionType::LZ4)?;
+        self.file.write_u32(compressed.len() as u32).await?;
+        self.file.write_all(&compressed).await?;
+        self.bytes += 4 + compressed.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+/// A finished run file
+pub(crate) struct SpillRun {
+    path: TempPath,
+    rows: u64,
+    bytes: u64,
+}
+
+impl SpillRun {
+    pub(crate) fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Size of the file on disk
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Read the run back one block at a time. Decoded rows count against
+    /// `budget` until they are returned.
+    pub(crate) async fn open(self, budget: Arc<MemoryBudget>) -> Result<RunReader> {
+        let file = File::open(&self.path).await?;
+        Ok(RunReader {
+            file: BufReader::new(file),
+            _path: self.path,
+            rows: VecDeque::new(),
+            held: 0,
+            budget,
+        })
+    }
+}
+
+pub(crate) struct RunReader {
+    file: BufReader<File>,
+    // Deletes the file once the reader is done with it
+    _path: TempPath,
+    rows: VecDeque<Row>,
+    held: usize,
+    budget: Arc<MemoryBudget>,
+}
+
+impl RunReader {
+    pub(crate) async fn next(&mut self) -> Result<Option<Row>> {
+        if self.rows.is_empty() && !self.read_block().await? {
+            return Ok(None);
+        }
+        let row = self.rows.pop_front().expect("block has rows");
+        let size = row_size(&row);
+        self.held -= size;
+        self.budget.release(size);
+        Ok(Some(row))
+    }
+
+    async fn read_block(&mut self) -> Result<bool> {
+        let length = match self.file.read_u32().await {
+            Ok(length) => length as usize,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let mut compressed = vec![0u8; length];
+        self.file.read_exact(&mut compressed).await?;
+        let block = compression::decompress(&compressed, &CompressionType::LZ4)?;
+
+        let mut rest = block.as_slice();
+        while !rest.is_empty() {
+            let truncated = || QueryError::Execution("truncated row in spill file".to_string());
+            let length = u32::from_le_bytes(rest.get(..4).ok_or_else(truncated)?.try_into().unwrap()) as usize;
+            let encoded = rest.get(4..4 + length).ok_or_else(truncated)?;
+            let row: Row = encoding::decode_row(encoded)?.into_iter().map(|(_, value)| value).collect();
+            let size = row_size(&row);
+            self.held += size;
+            self.budget.reserve(size);
+            self.rows.push_back(row);
+            rest = &rest[4 + length..];
+        }
+        Ok(!self.rows.is_empty())
+    }
+}
+
+impl Drop for RunReader {
+    fn drop(&mut self) {
+        self.budget.release(self.held);
+    }
+}
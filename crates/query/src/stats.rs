@@ -0,0 +1,449 @@
+//! Column statistics for the planner's row estimates.
+//!
+//! `ANALYZE t` scans the table and records, per column, its smallest and
+//! largest values, its NULLs, an estimate of its distinct values and an
+//! equi-depth histogram built from a sample of the rows. Writes afterwards
+//! widen the bounds of the values they add; the rest is kept until the
+//! table is analyzed again.
+
+use crate::{
+    ast::{BinaryOp, Expr, UnaryOp},
+    catalog::TableSchema,
+    encoding,
+    value::Value,
+};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// Selectivity assumed for a predicate the statistics say nothing about
+pub const DEFAULT_SELECTIVITY: f64 = 1.0 / 3.0;
+
+// Smallest hashes kept per column to estimate its distinct values
+const SKETCH_SIZE: usize = 1024;
+
+// Rows sampled to build histograms
+const SAMPLE_SIZE: usize = 4096;
+
+// Buckets per histogram, each holding about as many sampled values
+const HISTOGRAM_BUCKETS: usize = 32;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ColumnStats {
+    /// Smallest and largest non-NULL values. Writes since the last ANALYZE
+    /// widen them but never narrow them.
+    pub min: Option<Value>,
+    pub max: Option<Value>,
+    pub nulls: u64,
+    /// Estimated number of distinct non-NULL values
+    pub distinct: u64,
+    /// Bounds of equi-depth buckets over the non-NULL values, smallest
+    /// first: about as many values fall between each adjacent pair
+    #[serde(default)]
+    pub histogram: Vec<Value>,
+}
+
+impl ColumnStats {
+    /// Estimated fraction of the non-NULL values below `value`, from the
+    /// histogram or, without one, from where `value` falls between the bounds
+    fn fraction_below(&self, value: &Value) -> Option<f64> {
+        let bounds = &self.histogram;
+        if bounds.len() >= 2 {
+            let below = |bound: &Value| value.sort_cmp(bound) == Ordering::Greater;
+            let bucket = bounds.partition_point(below);
+            if bucket == 0 {
+                return Some(0.0);
+            }
+            if bucket == bounds.len() {
+                return Some(1.0);
+            }
+            let (low, high) = (&bounds[bucket - 1], &bounds[bucket]);
+            let within = match (numeric(value), numeric(low), numeric(high)) {
+                (Some(value), Some(low), Some(high)) if high > low => (value - low) / (high - low),
+                _ => 0.5,
+            };
+            return Some((bucket - 1) as f64 / (bounds.len() - 1) as f64 + within / (bounds.len() - 1) as f64);
+        }
+        match (numeric(value), self.min.as_ref().and_then(numeric), self.max.as_ref().and_then(numeric)) {
+            (Some(value), Some(min), Some(max)) if max > min => Some((value - min) / (max - min)),
+            (Some(value), Some(min), Some(_)) => Some(if value < min { 0.0 } else { 1.0 }),
+            _ => None,
+        }
+    }
+
+    /// Estimated fraction of the non-NULL values equal to `value`, for a
+    /// value common enough to fill at least a whole histogram bucket
+    fn frequency(&self, value: &Value) -> Option<f64> {
+        let bounds = &self.histogram;
+        let equal = bounds.iter().filter(|bound| value.sort_cmp(bound) == Ordering::Equal).count();
+        (equal >= 2).then(|| (equal - 1) as f64 / (bounds.len() - 1) as f64)
+    }
+
+    fn widen(&mut self, value: &Value) {
+        // JSON cannot hold NaN or infinities, and they make poor bounds anyway
+        if value.is_null() || matches!(value, Value::Float(f) if !f.is_finite()) {
+            return;
+        }
+        if self.min.as_ref().is_none_or(|min| value.sort_cmp(min) == Ordering::Less) {
+            self.min = Some(value.clone());
+        }
+        if self.max.as_ref().is_none_or(|max| value.sort_cmp(max) == Ordering::Greater) {
+            self.max = Some(value.clone());
+        }
+    }
+}
+
+/// Statistics of a table as of its last ANALYZE
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Analysis {
+    /// Rows when the table was analyzed
+    pub rows: u64,
+    /// By column id. Columns added since have none.
+    pub columns: BTreeMap<u32, ColumnStats>,
+}
+
+impl Analysis {
+    /// Widen the column bounds to cover a newly written row of `schema`
+    pub fn observe(&mut self, schema: &TableSchema, row: &[Value]) {
+        for (column, value) in schema.columns.iter().zip(row) {
+            if let Some(stats) = self.columns.get_mut(&column.id) {
+                stats.widen(value);
+            }
+        }
+    }
+
+    /// Estimated fraction of the rows of `schema` that pass `predicate`,
+    /// for a table currently holding `rows` rows
+    pub fn selectivity(&self, schema: &TableSchema, predicate: &Expr, rows: u64) -> f64 {
+        let selectivity = match predicate {
+            Expr::Binary { op: BinaryOp::And, .. } => {
+                let mut terms = Vec::new();
+                conjuncts(predicate, &mut terms);
+                // Terms are taken as independent, except that a lower and an
+                // upper bound on the same column are taken together
+                let mut taken = vec![false; terms.len()];
+                let mut selectivity = 1.0;
+                for i in 0..terms.len() {
+                    if taken[i] {
+                        continue;
+                    }
+                    let range = (i + 1..terms.len())
+                        .filter(|&j| !taken[j])
+                        .find_map(|j| Some((j, self.range(schema, terms[i], terms[j], rows)?)));
+                    selectivity *= match range {
+                        Some((j, range)) => {
+                            taken[j] = true;
+                            range
+                        }
+                        None => self.selectivity(schema, terms[i], rows),
+                    };
+                }
+                selectivity
+            }
+            Expr::Binary { left, op: BinaryOp::Or, right } => {
+                let (a, b) = (self.selectivity(schema, left, rows), self.selectivity(schema, right, rows));
+                a + b - a * b
+            }
+            Expr::Unary { op: UnaryOp::Not, expr } => 1.0 - self.selectivity(schema, expr, rows),
+            Expr::IsNull { expr, negated } => match self.column(schema, expr) {
+                Some(stats) if *negated => 1.0 - self.null_fraction(stats),
+                Some(stats) => self.null_fraction(stats),
+                None => DEFAULT_SELECTIVITY,
+            },
+            Expr::Binary { .. } => match comparison(predicate) {
+                Some((column, op, value)) => self.compare(schema, column, op, &value, rows),
+                None => DEFAULT_SELECTIVITY,
+            },
+            // As many equality tests, which match disjoint rows
+            Expr::InList { expr, list, negated } => {
+                let matched: f64 = list.iter()
+                    .map(|item| {
+                        let equals = Expr::Binary { left: expr.clone(), op: BinaryOp::Eq, right: Box::new(item.clone()) };
+                        self.selectivity(schema, &equals, rows)
+                    })
+                    .sum();
+                if *negated { 1.0 - matched.min(1.0) } else { matched }
+            }
+            _ => DEFAULT_SELECTIVITY,
+        };
+        selectivity.clamp(0.0, 1.0)
+    }
+
+    /// Selectivity of `left AND right` when they bound the same column from
+    /// below and above, which are far from independent
+    fn range(&self, schema: &TableSchema, left: &Expr, right: &Expr, rows: u64) -> Option<f64> {
+        let (left_column, left_op, left_value) = comparison(left)?;
+        let (right_column, right_op, right_value) = comparison(right)?;
+        let lower = |op| matches!(op, BinaryOp::Gt | BinaryOp::GtEq);
+        let upper = |op| matches!(op, BinaryOp::Lt | BinaryOp::LtEq);
+        let bounded = (lower(left_op) && upper(right_op)) || (upper(left_op) && lower(right_op));
+        if left_column != right_column || !bounded {
+            return None;
+        }
+        let stats = self.column(schema, left_column)?;
+        // Rows at or above the lower bound plus rows at or below the upper
+        // one count those in between twice and every other non-NULL row once
+        let at_least = self.compare(schema, left_column, left_op, &left_value, rows);
+        let at_most = self.compare(schema, right_column, right_op, &right_value, rows);
+        Some((at_least + at_most - (1.0 - self.null_fraction(stats))).max(0.0))
+    }
+
+    /// Selectivity of `column <op> value`
+    fn compare(&self, schema: &TableSchema, column: &Expr, op: BinaryOp, value: &Value, rows: u64) -> f64 {
+        let Some(stats) = self.column(schema, column) else {
+            return DEFAULT_SELECTIVITY;
+        };
+        if value.is_null() {
+            return 0.0;
+        }
+        let non_null = 1.0 - self.null_fraction(stats);
+        let (Some(min), Some(max)) = (&stats.min, &stats.max) else {
+            return 0.0;
+        };
+        let outside = value.sql_cmp(min).ok().flatten() == Some(Ordering::Less)
+            || value.sql_cmp(max).ok().flatten() == Some(Ordering::Greater);
+        // A column whose values were all distinct when analyzed likely still is
+        let distinct = if stats.distinct + stats.nulls >= self.rows {
+            rows.max(stats.distinct)
+        } else {
+            stats.distinct
+        };
+        let equal = if outside {
+            0.0
+        } else {
+            non_null * stats.frequency(value).unwrap_or(0.0).max(1.0 / distinct.max(1) as f64)
+        };
+        match op {
+            BinaryOp::Eq => equal,
+            BinaryOp::NotEq => non_null - equal,
+            BinaryOp::Lt | BinaryOp::LtEq | BinaryOp::Gt | BinaryOp::GtEq => {
+                let Some(below) = stats.fraction_below(value) else {
+                    return DEFAULT_SELECTIVITY;
+                };
+                let below = below.clamp(0.0, 1.0);
+                let below = match op {
+                    BinaryOp::Lt => below,
+                    BinaryOp::LtEq => below + equal / non_null.max(f64::EPSILON),
+                    BinaryOp::Gt => 1.0 - below - equal / non_null.max(f64::EPSILON),
+                    _ => 1.0 - below,
+                };
+                non_null * below.clamp(0.0, 1.0)
+            }
+            _ => DEFAULT_SELECTIVITY,
+        }
+    }
+
+    fn column(&self, schema: &TableSchema, expr: &Expr) -> Option<&ColumnStats> {
+        let Expr::Column(name) = expr else {
+            return None;
+        };
+        self.columns.get(&schema.column(name)?.id)
+    }
+
+    fn null_fraction(&self, stats: &ColumnStats) -> f64 {
+        if self.rows == 0 {
+            return 0.0;
+        }
+        stats.nulls as f64 / self.rows as f64
+    }
+}
+
+fn conjuncts<'a>(expr: &'a Expr, terms: &mut Vec<&'a Expr>) {
+    match expr {
+        Expr::Binary { left, op: BinaryOp::And, right } => {
+            conjuncts(left, terms);
+            conjuncts(right, terms);
+        }
+        expr => terms.push(expr),
+    }
+}
+
+/// `column <op> value` in either order, with the operator as if the column
+/// came first
+fn comparison(expr: &Expr) -> Option<(&Expr, BinaryOp, Value)> {
+    let Expr::Binary { left, op, right } = expr else {
+        return None;
+    };
+    match (&**left, &**right) {
+        (Expr::Literal(_), Expr::Literal(_)) => None,
+        (column, Expr::Literal(literal)) => Some((column, *op, Value::from_literal(literal))),
+        (Expr::Literal(literal), column) => Some((column, flip(*op)?, Value::from_literal(literal))),
+        _ => None,
+    }
+}
+
+/// The operator that gives the same result with its operands swapped
+fn flip(op: BinaryOp) -> Option<BinaryOp> {
+    Some(match op {
+        BinaryOp::Eq | BinaryOp::NotEq => op,
+        BinaryOp::Lt => BinaryOp::Gt,
+        BinaryOp::LtEq => BinaryOp::GtEq,
+        BinaryOp::Gt => BinaryOp::Lt,
+        BinaryOp::GtEq => BinaryOp::LtEq,
+        _ => return None,
+    })
+}
+
+/// Position of a value on a number line, for interpolating between bounds
+fn numeric(value: &Value) -> Option<f64> {
+    match value {
+        Value::Integer(i) | Value::Timestamp(i) => Some(*i as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+/// Gathers an `Analysis` from every row of a table
+pub(crate) struct Collector {
+    rows: u64,
+    columns: Vec<(u32, ColumnStats, DistinctSketch)>,
+    // The rows whose ordinals hash lowest, which makes a uniform sample
+    // that is the same on every run
+    sample: BTreeMap<u64, Vec<Value>>,
+}
+
+impl Collector {
+    pub(crate) fn new(schema: &TableSchema) -> Self {
+        let columns = schema.columns.iter()
+            .map(|column| (column.id, ColumnStats::default(), DistinctSketch::default()))
+            .collect();
+        Self { rows: 0, columns, sample: BTreeMap::new() }
+    }
+
+    /// Add a row with a value for every column of the schema, in order
+    pub(crate) fn add(&mut self, row: &[Value]) {
+        let priority = hash(&self.rows.to_be_bytes());
+        self.rows += 1;
+        for ((_, stats, sketch), value) in self.columns.iter_mut().zip(row) {
+            if value.is_null() {
+                stats.nulls += 1;
+            } else {
+                stats.widen(value);
+                sketch.insert(value);
+            }
+        }
+        if self.sample.len() < SAMPLE_SIZE || self.sample.last_key_value().is_some_and(|(&last, _)| priority < last) {
+            self.sample.insert(priority, row.to_vec());
+            if self.sample.len() > SAMPLE_SIZE {
+                self.sample.pop_last();
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> Analysis {
+        let sample: Vec<Vec<Value>> = self.sample.into_values().collect();
+        let columns = self.columns.into_iter()
+            .enumerate()
+            .map(|(position, (id, stats, sketch))| {
+                let mut values: Vec<&Value> = sample.iter()
+                    .filter_map(|row| row.get(position))
+                    .filter(|value| !value.is_null())
+                    .collect();
+                values.sort_by(|a, b| a.sort_cmp(b));
+                let stats = ColumnStats { distinct: sketch.estimate(), histogram: histogram(&values), ..stats };
+                (id, stats)
+            })
+            .collect();
+        Analysis { rows: self.rows, columns }
+    }
+}
+
+/// Equi-depth bucket bounds over sorted `values`
+fn histogram(values: &[&Value]) -> Vec<Value> {
+    if values.len() < 2 {
+        return Vec::new();
+    }
+    let buckets = HISTOGRAM_BUCKETS.min(values.len() - 1);
+    (0..=buckets)
+        .map(|bucket| values[(bucket * (values.len() - 1) + buckets / 2) / buckets].clone())
+        // JSON cannot hold NaN or infinities
+        .filter(|value| !matches!(value, Value::Float(f) if !f.is_finite()))
+        .collect()
+}
+
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// K-minimum-values sketch: keeps the smallest hashes of the values seen.
+/// Exact up to `SKETCH_SIZE` distinct values; beyond that, the larger the
+/// k-th smallest hash, the fewer values there are.
+#[derive(Default)]
+struct DistinctSketch {
+    hashes: BTreeSet<u64>,
+}
+
+impl DistinctSketch {
+    fn insert(&mut self, value: &Value) {
+        let mut bytes = Vec::new();
+        encoding::encode_value(value, &mut bytes);
+        let hash = hash(&bytes);
+
+        if self.hashes.len() < SKETCH_SIZE {
+            self.hashes.insert(hash);
+        } else if self.hashes.last().is_some_and(|&largest| hash < largest) && self.hashes.insert(hash) {
+            self.hashes.pop_last();
+        }
+    }
+
+    fn estimate(&self) -> u64 {
+        match self.hashes.last() {
+            Some(&largest) if self.hashes.len() == SKETCH_SIZE => {
+                let fraction = largest as f64 / u64::MAX as f64;
+                ((SKETCH_SIZE - 1) as f64 / fraction).round() as u64
+            }
+            _ => self.hashes.len() as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distinct_estimate() {
+        let mut sketch = DistinctSketch::default();
+        for i in 0..100 {
+            sketch.insert(&Value::Integer(i % 10));
+        }
+        assert_eq!(sketch.estimate(), 10);
+
+        let mut sketch = DistinctSketch::default();
+        for i in 0..200_000 {
+            sketch.insert(&Value::Integer(i % 50_000));
+        }
+        let estimate = sketch.estimate() as f64;
+        assert!((estimate - 50_000.0).abs() < 50_000.0 * 0.1, "{}", estimate);
+    }
+
+    #[test]
+    fn test_histogram_fractions() {
+        // Skewed: half the values are below 10, the rest spread up to 10000
+        let values: Vec<Value> = (0..5000).map(|i| Value::Integer(i % 10))
+            .chain((0..5000).map(|i| Value::Integer(10 + i * 2)))
+            .collect();
+        let mut sorted: Vec<&Value> = values.iter().collect();
+        sorted.sort_by(|a, b| a.sort_cmp(b));
+        let stats = ColumnStats {
+            min: Some(Value::Integer(0)),
+            max: Some(Value::Integer(10_008)),
+            histogram: histogram(&sorted),
+            ..ColumnStats::default()
+        };
+        assert_eq!(stats.histogram.len(), HISTOGRAM_BUCKETS + 1);
+        let below = |i| stats.fraction_below(&Value::Integer(i)).unwrap();
+        assert!((below(10) - 0.5).abs() < 0.05, "{}", below(10));
+        assert!((below(5010) - 0.75).abs() < 0.05, "{}", below(5010));
+        assert_eq!(below(-1), 0.0);
+        assert_eq!(below(20_000), 1.0);
+
+        // Text has no interpolation, but buckets still place it
+        let words: Vec<Value> = (0..100).map(|i| Value::Text(format!("w{:03}", i))).collect();
+        let stats = ColumnStats { histogram: histogram(&words.iter().collect::<Vec<_>>()), ..ColumnStats::default() };
+        assert!((stats.fraction_below(&Value::Text("w025".to_string())).unwrap() - 0.25).abs() < 0.05);
+    }
+}
@@ -0,0 +1,292 @@
+use crate::{
+    error::{Result, QueryError},
+    ast::{DataType, Literal},
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Key of the JSON object wrapping a base64-encoded BLOB
+pub const JSON_BLOB_KEY: &str = "$base64";
+/// Key of the JSON object wrapping a NaN or infinite FLOAT, which JSON
+/// numbers cannot represent
+pub const JSON_FLOAT_KEY: &str = "$float";
+
+/// A single typed SQL value
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Float(f64),
+    Text(String),
+    Boolean(bool),
+    Blob(Vec<u8>),
+    /// Microseconds since the Unix epoch, always UTC. Offsets in input
+    /// strings are applied on parse and not kept, so comparisons are on the
+    /// absolute instant and unaffected by daylight saving transitions.
+    Timestamp(#[serde(with = "iso_timestamp")] i64),
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// Type of the value, or None for NULL
+    pub fn data_type(&self) -> Option<DataType> {
+        match self {
+            Value::Null => None,
+            Value::Integer(_) => Some(DataType::Integer),
+            Value::Float(_) => Some(DataType::Float),
+            Value::Text(_) => Some(DataType::Text),
+            Value::Boolean(_) => Some(DataType::Boolean),
+            Value::Blob(_) => Some(DataType::Blob),
+            Value::Timestamp(_) => Some(DataType::Timestamp),
+        }
+    }
+
+    pub fn from_literal(literal: &Literal) -> Value {
+        match literal {
+            Literal::Null => Value::Null,
+            Literal::Integer(i) => Value::Integer(*i),
+            Literal::Float(f) => Value::Float(*f),
+            Literal::String(s) => Value::Text(s.clone()),
+            Literal::Boolean(b) => Value::Boolean(*b),
+            Literal::Timestamp(micros) => Value::Timestamp(*micros),
+            Literal::Blob(bytes) => Value::Blob(bytes.clone()),
+        }
+    }
+
+    /// The literal `from_literal` reads back as this value
+    pub fn to_literal(&self) -> Literal {
+        match self {
+            Value::Null => Literal::Null,
+            Value::Integer(i) => Literal::Integer(*i),
+            Value::Float(f) => Literal::Float(*f),
+            Value::Text(s) => Literal::String(s.clone()),
+            Value::Boolean(b) => Literal::Boolean(*b),
+            Value::Blob(bytes) => Literal::Blob(bytes.clone()),
+            Value::Timestamp(micros) => Literal::Timestamp(*micros),
+        }
+    }
+
+    /// Convert the value for storage in a column of `data_type`. Only lossless
+    /// widenings are applied implicitly.
+    pub fn cast_to(self, data_type: DataType) -> Result<Value> {
+        match (self, data_type) {
+            (Value::Null, _) => Ok(Value::Null),
+            (Value::Integer(i), DataType::Float) => Ok(Value::Float(i as f64)),
+            (Value::Text(s), DataType::Blob) => Ok(Value::Blob(s.into_bytes())),
+            (Value::Text(s), DataType::Timestamp) => parse_timestamp(&s).map(Value::Timestamp),
+            (value, data_type) if value.data_type() == Some(data_type) => Ok(value),
+            (value, data_type) => Err(QueryError::Execution(format!(
+                "cannot store {} value {} in {} column",
+                value.type_name(), value, data_type
+            ))),
+        }
+    }
+
+    /// SQL comparison. Returns None when either side is NULL.
+    pub fn sql_cmp(&self, other: &Value) -> Result<Option<Ordering>> {
+        let ordering = match (self, other) {
+            (Value::Null, _) | (_, Value::Null) => return Ok(None),
+            (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+            (Value::Integer(a), Value::Float(b)) => (*a as f64).total_cmp(b),
+            (Value::Float(a), Value::Integer(b)) => a.total_cmp(&(*b as f64)),
+            (Value::Float(a), Value::Float(b)) => a.total_cmp(b),
+            (Value::Text(a), Value::Text(b)) => a.cmp(b),
+            (Value::Boolean(a), Value::Boolean(b)) => a.cmp(b),
+            (Value::Blob(a), Value::Blob(b)) => a.cmp(b),
+            (Value::Timestamp(a), Value::Timestamp(b)) => a.cmp(b),
+            // Text compared with a timestamp is read as an ISO-8601 literal
+            (Value::Timestamp(a), Value::Text(b)) => a.cmp(&parse_timestamp(b)?),
+            (Value::Text(a), Value::Timestamp(b)) => parse_timestamp(a)?.cmp(b),
+            (a, b) => {
+                return Err(QueryError::Execution(format!(
+                    "cannot compare {} with {}", a.type_name(), b.type_name()
+                )))
+            }
+        };
+        Ok(Some(ordering))
+    }
+
+    /// Total order used for sorting: NULLs last, mismatched types by type name
+    pub fn sort_cmp(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Value::Null, Value::Null) => Ordering::Equal,
+            (Value::Null, _) => Ordering::Greater,
+            (_, Value::Null) => Ordering::Less,
+            (a, b) => a.sql_cmp(b)
+                .ok()
+                .flatten()
+                .unwrap_or_else(|| a.type_name().cmp(b.type_name())),
+        }
+    }
+
+    /// Approximate memory used by the value, for enforcing result size limits
+    pub fn size_hint(&self) -> usize {
+        std::mem::size_of::<Value>() + match self {
+            Value::Text(s) => s.len(),
+            Value::Blob(bytes) => bytes.len(),
+            _ => 0,
+        }
+    }
+
+    /// Natively typed JSON for API results: numbers, strings, booleans and
+    /// null as themselves, timestamps as ISO-8601 strings, and BLOBs and
+    /// non-finite floats wrapped in a marker object such as `{"$base64": "..."}`
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Null => serde_json::Value::Null,
+            Value::Integer(i) => json!(i),
+            Value::Float(v) if v.is_finite() => json!(v),
+            Value::Float(v) => json!({ JSON_FLOAT_KEY: v.to_string() }),
+            Value::Text(s) => json!(s),
+            Value::Boolean(b) => json!(b),
+            Value::Blob(bytes) => json!({ JSON_BLOB_KEY: BASE64.encode(bytes) }),
+            Value::Timestamp(micros) => json!(format_timestamp(*micros)),
+        }
+    }
+
+    /// Read back a value written by `to_json`. `data_type` is the column type;
+    /// without it, strings are read as TEXT.
+    pub fn from_json(json: &serde_json::Value, data_type: Option<DataType>) -> Result<Value> {
+        use serde_json::Value as Json;
+
+        let invalid = || QueryError::Execution(format!(
+            "invalid JSON {} for {} column", json, data_type.map_or("untyped".to_string(), |t| t.to_string())
+        ));
+        let marker = |key: &str| match json {
+            Json::Object(map) if map.len() == 1 => map.get(key).and_then(Json::as_str),
+            _ => None,
+        };
+
+        match (json, data_type) {
+            (Json::Null, _) => Ok(Value::Null),
+            (Json::Bool(b), None | Some(DataType::Boolean)) => Ok(Value::Boolean(*b)),
+            (Json::Number(n), None | Some(DataType::Integer)) if n.is_i64() => {
+                Ok(Value::Integer(n.as_i64().unwrap()))
+            }
+            (Json::Number(n), None | Some(DataType::Float)) => n.as_f64().map(Value::Float).ok_or_else(invalid),
+            (Json::String(s), None | Some(DataType::Text)) => Ok(Value::Text(s.clone())),
+            (Json::String(s), Some(DataType::Timestamp)) => parse_timestamp(s).map(Value::Timestamp),
+            (Json::Object(_), None | Some(DataType::Blob)) if marker(JSON_BLOB_KEY).is_some() => {
+                BASE64.decode(marker(JSON_BLOB_KEY).unwrap()).map(Value::Blob).map_err(|_| invalid())
+            }
+            (Json::Object(_), None | Some(DataType::Float)) if marker(JSON_FLOAT_KEY).is_some() => {
+                marker(JSON_FLOAT_KEY).unwrap().parse().map(Value::Float).map_err(|_| invalid())
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "NULL",
+            Value::Integer(_) => "INTEGER",
+            Value::Float(_) => "FLOAT",
+            Value::Text(_) => "TEXT",
+            Value::Boolean(_) => "BOOLEAN",
+            Value::Blob(_) => "BLOB",
+            Value::Timestamp(_) => "TIMESTAMP",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Float(v) => write!(f, "{}", v),
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Blob(bytes) => {
+                write!(f, "\\x")?;
+                for byte in bytes {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+            Value::Timestamp(micros) => write!(f, "{}", format_timestamp(*micros)),
+        }
+    }
+}
+
+/// Parse an ISO-8601 timestamp into microseconds since the epoch. Accepts
+/// RFC 3339 with an offset, or a date with optional time and no offset, which
+/// is taken to be UTC.
+pub fn parse_timestamp(text: &str) -> Result<i64> {
+    let text = text.trim();
+    let parsed = DateTime::parse_from_rfc3339(text)
+        .map(|dt| dt.naive_utc())
+        .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f"))
+        .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f"))
+        .or_else(|_| NaiveDate::parse_from_str(text, "%Y-%m-%d").map(|d| d.and_time(Default::default())));
+    parsed
+        .map(|dt| dt.and_utc().timestamp_micros())
+        .map_err(|_| QueryError::Execution(format!("invalid timestamp '{}'", text)))
+}
+
+/// Render microseconds since the epoch as RFC 3339 in UTC
+pub fn format_timestamp(micros: i64) -> String {
+    to_datetime(micros)
+        .map(|dt| dt.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+        .unwrap_or_else(|| format!("{}us", micros))
+}
+
+pub fn to_datetime(micros: i64) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp_micros(micros)
+}
+
+mod iso_timestamp {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(micros: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::format_timestamp(*micros))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        super::parse_timestamp(&text).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cast_and_compare() {
+        assert_eq!(Value::Integer(3).cast_to(DataType::Float).unwrap(), Value::Float(3.0));
+        assert!(Value::Text("x".to_string()).cast_to(DataType::Integer).is_err());
+
+        assert_eq!(Value::Integer(2).sql_cmp(&Value::Float(2.5)).unwrap(), Some(Ordering::Less));
+        assert_eq!(Value::Null.sql_cmp(&Value::Integer(1)).unwrap(), None);
+        assert!(Value::Integer(1).sql_cmp(&Value::Text("1".to_string())).is_err());
+
+        assert_eq!(Value::Null.sort_cmp(&Value::Integer(1)), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_timestamp_parse_and_serialize() {
+        let utc = parse_timestamp("2024-03-10T07:30:00Z").unwrap();
+        assert_eq!(parse_timestamp("2024-03-10 07:30:00").unwrap(), utc);
+        assert_eq!(parse_timestamp("2024-03-10T02:30:00-05:00").unwrap(), utc);
+        assert_eq!(parse_timestamp("2024-03-10").unwrap(), utc - 27_000_000_000);
+        assert!(parse_timestamp("2024-13-01").is_err());
+
+        let value = Value::Timestamp(utc + 250_000);
+        assert_eq!(value.to_string(), "2024-03-10T07:30:00.250Z");
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(json, r#"{"Timestamp":"2024-03-10T07:30:00.250Z"}"#);
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), value);
+
+        let text = Value::Text("2024-03-10 07:30:00".to_string());
+        assert_eq!(value.sql_cmp(&text).unwrap(), Some(Ordering::Greater));
+        assert_eq!(text.cast_to(DataType::Timestamp).unwrap(), Value::Timestamp(utc));
+    }
+}
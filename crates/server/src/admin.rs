@@ -0,0 +1,613 @@
+//! Maintenance endpoints for operators, under `/api/admin/*` and only for
+//! tokens with the admin scope:
+//!
+//! - `POST /api/admin/flush` writes the memtables out as L0 SSTables
+//! - `POST /api/admin/compact` merges every SSTable, or with a body such as
+//!   `{"start": "user/", "end": "user0"}` those holding keys in that range
+//! - `POST /api/admin/checkpoint` with `{"name": "nightly"}` writes a copy
+//!   of the storage to `<data_dir>/checkpoints/nightly`, which opens as a
+//!   data directory of its own
+//! - `GET /api/admin/lsm` lists the SSTables of each level
+//! - `POST /api/admin/wal/sync` syncs the WAL to disk
+//! - `POST /api/admin/backup` streams a backup archive of every live key,
+//!   in the format of `nextdb_storage::backup`
+//! - `POST /api/admin/restore` takes such an archive as its body, verifies
+//!   it by importing it into `<data_dir>/restore`, and leaves it there for
+//!   the next start to swap in for the live storage
+//!
+//! Each reports what it did and how long it took. Keys are shown and taken
+//! as text, with `\xNN` for bytes that are not printable ASCII and `\\`
+//! for a backslash.
+
+use crate::{server::{error_status, DatabaseState}, Config, Result};
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::{stream, StreamExt, TryStreamExt};
+use nextdb_query::QueryError;
+use nextdb_storage::{BackupInfo, CheckpointInfo, CompactionSummary, LSMTree, StorageConfig, StorageError};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, io, path::Path, sync::Arc, time::{Instant, SystemTime, UNIX_EPOCH}};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::{error, info};
+
+/// Bytes of a backup buffered between the export and the response
+const BACKUP_BUFFER: usize = 64 * 1024;
+
+#[derive(Serialize)]
+struct FlushResponse {
+    success: bool,
+    files_created: usize,
+    bytes_written: u64,
+    duration_ms: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CompactRequest {
+    start: Option<String>,
+    /// Exclusive
+    end: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CompactResponse {
+    success: bool,
+    #[serde(flatten)]
+    summary: CompactionSummary,
+    bytes_reclaimed: u64,
+    duration_ms: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CheckpointRequest {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct CheckpointResponse {
+    success: bool,
+    path: String,
+    #[serde(flatten)]
+    info: CheckpointInfo,
+    duration_ms: f64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LsmListing {
+    memtable_bytes: usize,
+    immutable_memtables: usize,
+    levels: Vec<LevelListing>,
+}
+
+#[derive(Serialize)]
+struct LevelListing {
+    level: usize,
+    size_bytes: u64,
+    files: Vec<FileListing>,
+}
+
+#[derive(Serialize)]
+struct FileListing {
+    file: String,
+    size_bytes: u64,
+    entries: u64,
+    smallest_key: Option<String>,
+    largest_key: Option<String>,
+}
+
+#[derive(Serialize)]
+struct WalSyncResponse {
+    success: bool,
+    duration_ms: f64,
+}
+
+#[derive(Serialize)]
+struct RestoreResponse {
+    success: bool,
+    #[serde(flatten)]
+    info: BackupInfo,
+    /// The restore only takes effect once the server restarts
+    restart_required: bool,
+    duration_ms: f64,
+}
+
+fn rejected(status: StatusCode, code: &'static str, message: String) -> Response {
+    let body = serde_json::json!({
+        "success": false,
+        "error": { "code": code, "message": message },
+    });
+    (status, Json(body)).into_response()
+}
+
+fn failed(error: impl Into<QueryError>) -> Response {
+    let error = error.into();
+    let (status, code) = error_status(&error);
+    rejected(status, code, error.to_string())
+}
+
+fn elapsed_ms(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
+}
+
+pub(crate) async fn flush(State(state): State<Arc<DatabaseState>>) -> Response {
+    let started = Instant::now();
+    let before: HashSet<String> = state.storage.sstables().await.into_iter().flatten().map(|file| file.file).collect();
+    if let Err(e) = state.storage.flush().await {
+        return failed(e);
+    }
+    let created: Vec<_> = state.storage.sstables().await.into_iter()
+        .flatten()
+        .filter(|file| !before.contains(&file.file))
+        .collect();
+    info!("Admin flush wrote {} SSTables", created.len());
+    Json(FlushResponse {
+        success: true,
+        files_created: created.len(),
+        bytes_written: created.iter().map(|file| file.size_bytes).sum(),
+        duration_ms: elapsed_ms(started),
+    }).into_response()
+}
+
+pub(crate) async fn compact(State(state): State<Arc<DatabaseState>>, body: Bytes) -> Response {
+    let range = if body.is_empty() {
+        CompactRequest { start: None, end: None }
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(range) => range,
+            Err(e) => return rejected(StatusCode::BAD_REQUEST, "invalid_request", e.to_string()),
+        }
+    };
+    let parse = |key: Option<String>| key.map(|key| unescape_key(&key).ok_or(key)).transpose();
+    let (start, end) = match (parse(range.start), parse(range.end)) {
+        (Ok(start), Ok(end)) => (start, end),
+        (Err(key), _) | (_, Err(key)) => {
+            return rejected(StatusCode::BAD_REQUEST, "invalid_request", format!("invalid escape in key '{}'", key));
+        }
+    };
+
+    let started = Instant::now();
+    // A full compaction goes through the executor, which refreshes column
+    // statistics after it
+    let summary = if start.is_none() && end.is_none() {
+        state.executor.compact().await
+    } else {
+        let start = start.unwrap_or_default();
+        state.storage.compact_range(&start, end.as_deref()).await.map_err(QueryError::from)
+    };
+    match summary {
+        Ok(summary) => Json(CompactResponse {
+            success: true,
+            bytes_reclaimed: summary.input_bytes.saturating_sub(summary.output_bytes),
+            summary,
+            duration_ms: elapsed_ms(started),
+        }).into_response(),
+        Err(e) => failed(e),
+    }
+}
+
+pub(crate) async fn checkpoint(State(state): State<Arc<DatabaseState>>, Json(request): Json<CheckpointRequest>) -> Response {
+    let name = request.name;
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        let message = "checkpoint names are letters, digits, '-', '_' and '.', not starting with '.'".to_string();
+        return rejected(StatusCode::BAD_REQUEST, "invalid_request", message);
+    }
+    let path = state.checkpoint_dir.join(&name);
+    if path.exists() {
+        return rejected(StatusCode::CONFLICT, "checkpoint_exists", format!("checkpoint '{}' already exists", name));
+    }
+
+    let started = Instant::now();
+    if let Err(e) = tokio::fs::create_dir_all(&state.checkpoint_dir).await {
+        return failed(e);
+    }
+    match state.storage.checkpoint(&path).await {
+        Ok(info) => Json(CheckpointResponse {
+            success: true,
+            path: path.to_string_lossy().to_string(),
+            info,
+            duration_ms: elapsed_ms(started),
+        }).into_response(),
+        Err(e) => failed(e),
+    }
+}
+
+pub(crate) async fn lsm(State(state): State<Arc<DatabaseState>>) -> Json<LsmListing> {
+    let stats = state.storage.stats().await;
+    let levels = state.storage.sstables().await.into_iter()
+        .enumerate()
+        .map(|(level, files)| LevelListing {
+            level,
+            size_bytes: files.iter().map(|file| file.size_bytes).sum(),
+            files: files.into_iter()
+                .map(|file| FileListing {
+                    file: file.file,
+                    size_bytes: file.size_bytes,
+                    entries: file.entries,
+                    smallest_key: file.smallest_key.as_deref().map(escape_key),
+                    largest_key: file.largest_key.as_deref().map(escape_key),
+                })
+                .collect(),
+        })
+        .collect();
+    Json(LsmListing {
+        memtable_bytes: stats.memtable_size,
+        immutable_memtables: stats.immutable_memtables,
+        levels,
+    })
+}
+
+pub(crate) async fn sync_wal(State(state): State<Arc<DatabaseState>>) -> Response {
+    let started = Instant::now();
+    match state.storage.sync_wal().await {
+        Ok(()) => Json(WalSyncResponse { success: true, duration_ms: elapsed_ms(started) }).into_response(),
+        Err(e) => failed(e),
+    }
+}
+
+/// Stream the archive as it is written. If the export fails partway the
+/// response is cut off, which a restore detects as a truncated archive.
+pub(crate) async fn backup(State(state): State<Arc<DatabaseState>>) -> Response {
+    let (writer, reader) = tokio::io::duplex(BACKUP_BUFFER);
+    let storage = state.storage.clone();
+    let export = tokio::spawn(async move { storage.export_backup(writer).await });
+
+    // The archive starts with its header, so an export that fails before
+    // writing anything can still get an error response
+    let mut chunks = ReaderStream::new(reader);
+    let Some(first) = chunks.next().await else {
+        return match export.await {
+            Ok(Err(e)) => failed(e),
+            _ => rejected(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "backup export stopped".to_string()),
+        };
+    };
+    let finish = stream::once(async move {
+        match export.await {
+            Ok(Ok(info)) => {
+                info!("Admin backup streamed {} entries at sequence {}", info.entries, info.sequence);
+                None
+            }
+            Ok(Err(e)) => {
+                error!("Backup export failed: {}", e);
+                Some(Err(io::Error::other(e)))
+            }
+            Err(e) => Some(Err(io::Error::other(e))),
+        }
+    }).filter_map(std::future::ready);
+    let body = Body::from_stream(stream::once(std::future::ready(first)).chain(chunks).chain(finish));
+
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let disposition = format!("attachment; filename=\"nextdb-{}.backup\"", seconds);
+    ([(header::CONTENT_TYPE, "application/octet-stream".to_string()), (header::CONTENT_DISPOSITION, disposition)], body).into_response()
+}
+
+/// Import the archive in the body into a staging directory, replacing any
+/// restore staged before. The live storage is untouched until the next
+/// start, when `apply_staged_restore` swaps it out.
+pub(crate) async fn restore(State(state): State<Arc<DatabaseState>>, body: Body) -> Response {
+    let Ok(_restoring) = state.restoring.try_lock() else {
+        return rejected(StatusCode::CONFLICT, "restore_in_progress", "another restore is running".to_string());
+    };
+    let started = Instant::now();
+    let partial = state.restore_dir.with_extension("partial");
+    if let Err(e) = remove_dir(&partial).await {
+        return failed(e);
+    }
+    let config = StorageConfig {
+        data_dir: partial.join("data").to_string_lossy().to_string(),
+        wal_dir: partial.join("wal").to_string_lossy().to_string(),
+        ..state.storage.config().clone()
+    };
+    let reader = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+
+    let imported = match LSMTree::import_backup(config, reader).await {
+        Ok((lsm, info)) => lsm.close().await.map(|()| info),
+        Err(e) => Err(e),
+    };
+    let info = match imported {
+        Ok(info) => info,
+        Err(e) => {
+            if let Err(e) = remove_dir(&partial).await {
+                error!("Cannot remove partial restore {}: {}", partial.display(), e);
+            }
+            return match e {
+                StorageError::Corruption(_) | StorageError::Serde(_) | StorageError::Config(_) => {
+                    rejected(StatusCode::BAD_REQUEST, "invalid_backup", e.to_string())
+                }
+                StorageError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    rejected(StatusCode::BAD_REQUEST, "invalid_backup", "backup archive is truncated".to_string())
+                }
+                e => failed(e),
+            };
+        }
+    };
+    let staged = async {
+        remove_dir(&state.restore_dir).await?;
+        tokio::fs::rename(&partial, &state.restore_dir).await
+    };
+    if let Err(e) = staged.await {
+        return failed(e);
+    }
+    info!("Admin restore staged {} entries in {}; restart to apply", info.entries, state.restore_dir.display());
+    Json(RestoreResponse {
+        success: true,
+        info,
+        restart_required: true,
+        duration_ms: elapsed_ms(started),
+    }).into_response()
+}
+
+async fn remove_dir(path: &Path) -> io::Result<()> {
+    match tokio::fs::remove_dir_all(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Swap a restore staged by `/api/admin/restore` in for the storage
+/// directories of `config`, moving the replaced ones to
+/// `<data_dir>/pre-restore`. Run before the storage opens; returns whether
+/// there was a restore. Safe to run again if a swap was cut short.
+pub(crate) fn apply_staged_restore(config: &Config) -> Result<bool> {
+    let data_dir = &config.server.data_dir;
+    let staged = data_dir.join("restore");
+    if !staged.exists() {
+        return Ok(false);
+    }
+    let replaced = data_dir.join("pre-restore");
+    std::fs::create_dir_all(&replaced)?;
+    for (name, live) in [("data", &config.storage.data_dir), ("wal", &config.storage.wal_dir)] {
+        let (from, live) = (staged.join(name), Path::new(live));
+        if !from.exists() {
+            continue;
+        }
+        if live.exists() {
+            let old = replaced.join(name);
+            if old.exists() {
+                std::fs::remove_dir_all(&old)?;
+            }
+            std::fs::rename(live, old)?;
+        }
+        std::fs::rename(from, live)?;
+    }
+    std::fs::remove_dir_all(&staged)?;
+    info!("Applied the staged restore; the replaced storage is in {}", replaced.display());
+    Ok(true)
+}
+
+fn escape_key(key: &[u8]) -> String {
+    let mut text = String::with_capacity(key.len());
+    for &byte in key {
+        match byte {
+            b'\\' => text.push_str("\\\\"),
+            b' '..=b'~' => text.push(byte as char),
+            _ => text.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    text
+}
+
+/// Reverse of `escape_key`, or None for an invalid escape
+fn unescape_key(text: &str) -> Option<Vec<u8>> {
+    let mut key = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            key.push(byte);
+            continue;
+        }
+        match bytes.next()? {
+            b'\\' => key.push(b'\\'),
+            b'x' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                key.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            _ => return None,
+        }
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, bytes, json_request, send, send_json};
+    use crate::{ApiToken, AuthConfig, DatabaseServer, ServerConfig};
+    use axum::{
+        body::Body,
+        http::{header, Method, Request},
+        Router,
+    };
+    use nextdb_query::QueryExecutor;
+    use nextdb_storage::{LSMTree, StorageConfig};
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    async fn call(app: &Router, method: Method, uri: &str, token: &str, body: Option<serde_json::Value>) -> (StatusCode, serde_json::Value) {
+        let request = json_request(method, uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(body.map_or(Body::empty(), |body| Body::from(body.to_string())))
+            .unwrap();
+        send_json(app, request).await
+    }
+
+    async fn insert(app: &Router, from: i64, to: i64) {
+        let values: Vec<String> = (from..to).map(|i| format!("({}, 'row {}')", i, i)).collect();
+        let sql = format!("INSERT INTO t VALUES {}", values.join(", "));
+        let (status, body) = call(app, Method::POST, "/api/query", "admin-token", Some(json!({ "sql": sql }))).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+
+    async fn level_files(app: &Router) -> Vec<usize> {
+        let (status, listing) = call(app, Method::GET, "/api/admin/lsm", "admin-token", None).await;
+        assert_eq!(status, StatusCode::OK, "{}", listing);
+        listing["levels"].as_array().unwrap().iter().map(|level| level["files"].as_array().unwrap().len()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_admin_endpoints() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            auth: Some(AuthConfig { tokens: ApiToken::parse_list("ops:admin:admin-token,app:rw:app-token").unwrap() }),
+            ..test_util::config(&temp_dir)
+        };
+        let app = DatabaseServer::with_config(config).await.unwrap().router();
+
+        // Read-write tokens cannot use them, admin tokens can also query
+        let (status, body) = call(&app, Method::POST, "/api/admin/flush", "app-token", None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["code"], "admin_required");
+        let sql = json!({ "sql": "CREATE TABLE t (id INT PRIMARY KEY, v TEXT)" });
+        assert_eq!(call(&app, Method::POST, "/api/query", "admin-token", Some(sql)).await.0, StatusCode::OK);
+
+        // Each flush adds an L0 file
+        for batch in 0..3 {
+            insert(&app, batch * 100, batch * 100 + 100).await;
+            let (status, body) = call(&app, Method::POST, "/api/admin/flush", "admin-token", None).await;
+            assert_eq!(status, StatusCode::OK, "{}", body);
+            assert_eq!(body["files_created"], 1);
+            assert!(body["bytes_written"].as_u64().unwrap() > 0);
+            assert!(body["duration_ms"].as_f64().is_some());
+        }
+        assert_eq!(level_files(&app).await[0], 3);
+
+        // Compacting merges them into the last level
+        let (status, body) = call(&app, Method::POST, "/api/admin/compact", "admin-token", None).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!((body["input_files"].as_u64(), body["output_files"].as_u64()), (Some(3), Some(1)));
+        assert!(body["bytes_reclaimed"].is_u64());
+        let files = level_files(&app).await;
+        assert_eq!((files[0], *files.last().unwrap()), (0, 1));
+
+        // and a range compaction takes in the files holding keys in it,
+        // named as the listing shows them, along with the overlapping last level
+        insert(&app, 300, 310).await;
+        call(&app, Method::POST, "/api/admin/flush", "admin-token", None).await;
+        let (_, listing) = call(&app, Method::GET, "/api/admin/lsm", "admin-token", None).await;
+        let l0 = &listing["levels"][0]["files"][0];
+        assert!(l0["smallest_key"].as_str().unwrap().contains("\\x"));
+        let range = json!({ "start": l0["smallest_key"], "end": format!("{}\\x00", l0["largest_key"].as_str().unwrap()) });
+        let (status, body) = call(&app, Method::POST, "/api/admin/compact", "admin-token", Some(range)).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!((body["input_files"].as_u64(), body["output_files"].as_u64()), (Some(2), Some(1)));
+        assert_eq!(level_files(&app).await[0], 0);
+        let (status, _) = call(&app, Method::POST, "/api/admin/compact", "admin-token", Some(json!({ "start": "\\x0" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // A checkpoint opens as a database of its own
+        insert(&app, 400, 405).await;
+        let (status, body) = call(&app, Method::POST, "/api/admin/checkpoint", "admin-token", Some(json!({ "name": "snap" }))).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["files"], 2);
+        for (name, status) in [("snap", StatusCode::CONFLICT), ("../escape", StatusCode::BAD_REQUEST), ("", StatusCode::BAD_REQUEST)] {
+            let (actual, body) = call(&app, Method::POST, "/api/admin/checkpoint", "admin-token", Some(json!({ "name": name }))).await;
+            assert_eq!(actual, status, "{}: {}", name, body);
+        }
+        let dir = temp_dir.path().join("checkpoints").join("snap");
+        assert_eq!(body["path"], dir.to_string_lossy().as_ref());
+        let storage = LSMTree::open(StorageConfig {
+            data_dir: dir.to_string_lossy().to_string(),
+            wal_dir: temp_dir.path().join("snap-wal").to_string_lossy().to_string(),
+            ..StorageConfig::default()
+        }).await.unwrap();
+        let copy = QueryExecutor::open(Arc::new(storage)).await.unwrap();
+        let count = copy.execute_sql("SELECT COUNT(*) FROM t").await.unwrap();
+        assert_eq!(count.rows, vec![vec![nextdb_query::Value::Integer(315)]]);
+
+        let (status, body) = call(&app, Method::POST, "/api/admin/wal/sync", "admin-token", None).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["success"], true);
+    }
+
+    async fn post_bytes(app: &Router, uri: &str, body: Vec<u8>) -> (StatusCode, axum::http::HeaderMap, Vec<u8>) {
+        let request = Request::post(uri)
+            .header(header::AUTHORIZATION, "Bearer admin-token")
+            .body(Body::from(body))
+            .unwrap();
+        let response = send(app, request).await;
+        let (status, headers) = (response.status(), response.headers().clone());
+        (status, headers, bytes(response).await)
+    }
+
+    async fn rows(app: &Router, sql: &str) -> serde_json::Value {
+        let (status, body) = call(app, Method::POST, "/api/query", "admin-token", Some(json!({ "sql": sql }))).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        body["result"]["rows"].clone()
+    }
+
+    #[tokio::test]
+    async fn test_backup_and_restore() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            auth: Some(AuthConfig { tokens: ApiToken::parse_list("ops:admin:admin-token").unwrap() }),
+            ..test_util::config(&temp_dir)
+        };
+        let server = DatabaseServer::with_config(config.clone()).await.unwrap();
+        let app = server.router();
+        rows(&app, "CREATE TABLE t (id INT PRIMARY KEY, v TEXT)").await;
+        insert(&app, 0, 500).await;
+        call(&app, Method::POST, "/api/admin/flush", "admin-token", None).await;
+        insert(&app, 500, 600).await;
+        rows(&app, "DELETE FROM t WHERE id < 10").await;
+        let expected = rows(&app, "SELECT COUNT(*), MIN(v), MAX(id) FROM t").await;
+        assert_eq!(expected, json!([[590, "row 10", 599]]));
+
+        let (status, headers, archive) = post_bytes(&app, "/api/admin/backup", Vec::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/octet-stream");
+        assert!(headers[header::CONTENT_DISPOSITION].to_str().unwrap().ends_with(".backup\""));
+
+        // Wipe the node and start it afresh
+        server.close().await.unwrap();
+        drop((server, app));
+        for dir in ["data", "wal"] {
+            std::fs::remove_dir_all(temp_dir.path().join(dir)).unwrap();
+        }
+        let server = DatabaseServer::with_config(config.clone()).await.unwrap();
+        let app = server.router();
+
+        // Broken archives are refused and leave nothing staged
+        for broken in [b"not a backup".to_vec(), archive[..archive.len() - 20].to_vec()] {
+            let (status, _, body) = post_bytes(&app, "/api/admin/restore", broken).await;
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!((status, &body["error"]["code"]), (StatusCode::BAD_REQUEST, &json!("invalid_backup")), "{}", body);
+            assert!(!temp_dir.path().join("restore").exists());
+            assert!(!temp_dir.path().join("restore.partial").exists());
+        }
+
+        // A restore is staged, and applied by the next start
+        let (status, _, body) = post_bytes(&app, "/api/admin/restore", archive.clone()).await;
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!((body["restart_required"].as_bool(), body["entries"].as_u64().map(|n| n > 590)), (Some(true), Some(true)));
+        rows(&app, "CREATE TABLE placeholder (id INT PRIMARY KEY)").await;
+        server.close().await.unwrap();
+        drop((server, app));
+
+        let app = DatabaseServer::with_config(config).await.unwrap().router();
+        assert_eq!(rows(&app, "SELECT COUNT(*), MIN(v), MAX(id) FROM t").await, expected);
+        let (status, _) = call(&app, Method::POST, "/api/query", "admin-token", Some(json!({ "sql": "SELECT * FROM placeholder" }))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        insert(&app, 600, 601).await;
+        assert!(!temp_dir.path().join("restore").exists());
+        assert!(temp_dir.path().join("pre-restore").join("data").exists());
+    }
+
+    #[test]
+    fn test_key_escapes() {
+        let key = b"\x01t\\ab\xff".to_vec();
+        assert_eq!(escape_key(&key), "\\x01t\\\\ab\\xff");
+        assert_eq!(unescape_key(&escape_key(&key)), Some(key));
+        for invalid in ["\\", "\\q", "\\x1", "\\xzz"] {
+            assert_eq!(unescape_key(invalid), None, "{}", invalid);
+        }
+    }
+}
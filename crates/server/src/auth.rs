@@ -0,0 +1,316 @@
+//! Bearer token authentication for the HTTP API.
+//!
+//! With tokens configured, every `/api/*` request must carry one of them in
+//! an `Authorization: Bearer <token>` header. The token's scope decides
+//! whether its queries may change data or schema, and whether it may use
+//! the `/api/admin/*` maintenance endpoints, and a token may be kept to
+//! some of the server's databases (see `databases`). The dashboard and
+//! `/health` stay open. Over mutual TLS the client's certificate is also
+//! in the request, as a `tls::ClientIdentity` extension.
+//!
+//! The same tokens guard the other listeners, which serve the default
+//! database only: binary protocol clients give one in `HELLO` (see
+//! `protocol`), and PostgreSQL clients as their password (see `postgres`).
+
+use crate::{databases::DEFAULT_DATABASE, Result, ServerError};
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use nextdb_query::SqlStatement;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, sync::Arc};
+
+/// What queries an API token may run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Queries that leave data and schema unchanged (see
+    /// `SqlStatement::is_read_only`)
+    ReadOnly,
+    ReadWrite,
+    /// Any query, and the `/api/admin/*` endpoints
+    Admin,
+}
+
+impl Scope {
+    pub fn allows(self, statement: &SqlStatement) -> bool {
+        self != Scope::ReadOnly || statement.is_read_only()
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ServerError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read_only" | "ro" => Ok(Scope::ReadOnly),
+            "read_write" | "rw" => Ok(Scope::ReadWrite),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(ServerError::Config(format!("unknown token scope '{}'", s))),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiToken {
+    /// Identifies the token in logs without revealing it
+    pub name: String,
+    pub token: String,
+    pub scope: Scope,
+    /// The databases the token may use, `default` naming the one served
+    /// outside `/api/db/{name}/`, or None for every database
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub databases: Option<Vec<String>>,
+}
+
+impl ApiToken {
+    /// Parse a comma-separated list of `name:scope:token` entries, such as
+    /// `dashboard:ro:s3cret,loader:rw:t0ken`
+    pub fn parse_list(spec: &str) -> Result<Vec<ApiToken>> {
+        spec.split(',').filter(|entry| !entry.trim().is_empty()).map(|entry| {
+            let mut parts = entry.trim().splitn(3, ':');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(name), Some(scope), Some(token)) if !token.is_empty() => Ok(ApiToken {
+                    name: name.to_string(),
+                    token: token.to_string(),
+                    scope: scope.parse()?,
+                    databases: None,
+                }),
+                _ => Err(ServerError::Config(format!("API token '{}' is not name:scope:token", entry))),
+            }
+        }).collect()
+    }
+}
+
+// Keeps tokens out of logged configs
+impl fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiToken")
+            .field("name", &self.name)
+            .field("token", &"<redacted>")
+            .field("scope", &self.scope)
+            .field("databases", &self.databases)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    pub tokens: Vec<ApiToken>,
+}
+
+impl AuthConfig {
+    /// The configured token matching `presented`. Every configured token is
+    /// compared in full, so the time taken does not reveal how much of one
+    /// matched.
+    pub fn authenticate(&self, presented: &str) -> Option<&ApiToken> {
+        self.tokens.iter().fold(None, |found, token| {
+            let matches = constant_time_eq(token.token.as_bytes(), presented.as_bytes());
+            found.or(matches.then_some(token))
+        })
+    }
+
+    /// Like `authenticate`, for a connection outside the HTTP API. Those
+    /// serve the default database only, so a token kept from it does not
+    /// match.
+    pub(crate) fn authenticate_connection(&self, presented: &str) -> Option<&ApiToken> {
+        self.authenticate(presented)
+            .filter(|token| DatabaseAccess(token.databases.clone()).allows(DEFAULT_DATABASE))
+    }
+}
+
+/// Whether `a` and `b` are equal, taking as long wherever they differ
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    std::hint::black_box(difference) == 0
+}
+
+/// Name of the token a request was authenticated with, kept as a request
+/// extension
+#[derive(Debug, Clone)]
+pub(crate) struct TokenName(pub(crate) String);
+
+/// Middleware for the `/api/*` routes. Rejects requests without a valid
+/// token or for a database the token is kept from, and passes the token's
+/// scope and name on to handlers as extensions. Without an auth config
+/// every request has the admin scope.
+pub(crate) async fn require_token(
+    State(auth): State<Option<Arc<AuthConfig>>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let scope = match &auth {
+        None => Scope::Admin,
+        Some(auth) => {
+            let presented = request.headers().get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "));
+            let Some(presented) = presented else {
+                return unauthorized("missing bearer token");
+            };
+            match auth.authenticate(presented.trim()) {
+                Some(token) => {
+                    let access = DatabaseAccess(token.databases.clone());
+                    if !access.allows(database_of(request.uri().path())) {
+                        return forbidden("database_forbidden", "the API token may not use this database");
+                    }
+                    request.extensions_mut().insert(TokenName(token.name.clone()));
+                    request.extensions_mut().insert(access);
+                    token.scope
+                }
+                None => return unauthorized("invalid bearer token"),
+            }
+        }
+    };
+    request.extensions_mut().insert(scope);
+    next.run(request).await
+}
+
+/// The databases the token of a request may use, kept as a request
+/// extension; None for every database
+#[derive(Debug, Clone)]
+pub(crate) struct DatabaseAccess(pub(crate) Option<Vec<String>>);
+
+impl DatabaseAccess {
+    pub(crate) fn allows(&self, database: &str) -> bool {
+        self.0.as_ref().is_none_or(|databases| databases.iter().any(|allowed| allowed == database))
+    }
+}
+
+/// The database an `/api/*` request at `path` is for
+fn database_of(path: &str) -> &str {
+    path.strip_prefix("/api/db/")
+        .and_then(|rest| rest.split('/').next())
+        .unwrap_or(DEFAULT_DATABASE)
+}
+
+/// Middleware for the `/api/admin/*` routes, after `require_token`.
+/// Rejects requests whose token lacks the admin scope.
+pub(crate) async fn require_admin(Extension(scope): Extension<Scope>, request: Request, next: Next) -> Response {
+    if scope != Scope::Admin {
+        return forbidden("admin_required", "the API token lacks the admin scope");
+    }
+    next.run(request).await
+}
+
+fn forbidden(code: &str, message: &str) -> Response {
+    let body = serde_json::json!({
+        "success": false,
+        "error": { "code": code, "message": message },
+    });
+    (StatusCode::FORBIDDEN, Json(body)).into_response()
+}
+
+fn unauthorized(message: &str) -> Response {
+    let body = serde_json::json!({
+        "success": false,
+        "error": { "code": "unauthorized", "message": message },
+    });
+    (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, json_request, send, send_json};
+    use crate::{DatabaseServer, ServerConfig};
+    use axum::body::Body;
+    use axum::http::Method;
+    use axum::Router;
+    use tempfile::TempDir;
+
+    async fn query(app: &Router, token: Option<&str>, sql: &str) -> (StatusCode, serde_json::Value) {
+        let mut request = json_request(Method::POST, "/api/query");
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        send_json(app, request.body(Body::from(serde_json::json!({ "sql": sql }).to_string())).unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn test_bearer_tokens() {
+        let temp_dir = TempDir::new().unwrap();
+        let tokens = ApiToken::parse_list("reader:ro:read-token, writer:rw:write-token").unwrap();
+        let config = ServerConfig {
+            auth: Some(AuthConfig { tokens }),
+            ..test_util::config(&temp_dir)
+        };
+        let app = DatabaseServer::with_config(config).await.unwrap().router();
+
+        for token in [None, Some("wrong-token"), Some("read-token-but-longer")] {
+            let (status, body) = query(&app, token, "SELECT 1").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{:?}", token);
+            assert_eq!(body["error"]["code"], "unauthorized");
+        }
+        let response = send(&app, Request::get("/api/status").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+
+        let (status, body) = query(&app, Some("write-token"), "CREATE TABLE t (id INT PRIMARY KEY)").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let (status, body) = query(&app, Some("write-token"), "INSERT INTO t VALUES (1)").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        // A read-only token can query but not write, and the write never runs
+        let (status, body) = query(&app, Some("read-token"), "INSERT INTO t VALUES (2)").await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+        assert_eq!(body["error"]["code"], "read_only_token");
+        let (status, body) = query(&app, Some("read-token"), "SELECT id FROM t").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["result"]["rows"], serde_json::json!([[1]]));
+
+        // Health checks and the dashboard need no token
+        for uri in ["/health", "/"] {
+            let response = send(&app, Request::get(uri).body(Body::empty()).unwrap()).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        }
+
+        assert!(ApiToken::parse_list("nameless").is_err());
+        assert!(ApiToken::parse_list("x:root:token").is_err());
+        assert_eq!(ApiToken::parse_list("x:admin:token").unwrap()[0].scope, Scope::Admin);
+        assert!(!format!("{:?}", AuthConfig { tokens: ApiToken::parse_list("a:rw:hidden").unwrap() }).contains("hidden"));
+    }
+
+    #[tokio::test]
+    async fn test_tokens_guard_the_binary_protocol() {
+        use crate::protocol::{self, read_frame, PROTOCOL_VERSION};
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::{TcpListener, TcpStream};
+
+        async fn exchange(stream: &mut TcpStream, request: protocol::Request) -> protocol::Response {
+            stream.write_all(&request.to_frame(1).encode()).await.unwrap();
+            let frame = read_frame(stream, usize::MAX).await.unwrap().unwrap();
+            protocol::Response::from_frame(&frame).unwrap()
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            auth: Some(AuthConfig { tokens: ApiToken::parse_list("reader:ro:read-token").unwrap() }),
+            ..test_util::config(&temp_dir)
+        };
+        let server = DatabaseServer::with_config(config).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { server.serve_protocol(listener).await });
+
+        // No way around the tokens of the HTTP API
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let reply = exchange(&mut stream, protocol::Request::Hello { version: PROTOCOL_VERSION, token: None }).await;
+        assert!(matches!(reply, protocol::Response::Error { code, .. } if code == "unauthorized"));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let hello = protocol::Request::Hello { version: PROTOCOL_VERSION, token: Some("read-token".to_string()) };
+        assert_eq!(exchange(&mut stream, hello).await, protocol::Response::Hello { version: PROTOCOL_VERSION });
+        let reply = exchange(&mut stream, protocol::Request::Put { key: b"k".to_vec(), value: b"v".to_vec() }).await;
+        assert!(matches!(reply, protocol::Response::Error { code, .. } if code == "read_only_token"));
+    }
+}
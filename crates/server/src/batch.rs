@@ -0,0 +1,357 @@
+//! Many statements in one HTTP request.
+//!
+//! `POST /api/batch` takes
+//!
+//! ```json
+//! {"statements": ["INSERT INTO t VALUES (1)", "INSERT INTO t VALUES (2)"], "transactional": true}
+//! ```
+//!
+//! and runs the statements in order, in a session of their own. Without
+//! `transactional` every statement runs whatever happened to the ones
+//! before it. With it they run in one transaction that is rolled back at
+//! the first error, and the statements after it are skipped; BEGIN, COMMIT
+//! and ROLLBACK are not allowed in such a batch.
+//!
+//! The response holds a result for each statement, with a `status` of
+//! `ok`, `error`, `skipped` or, for statements whose transaction was rolled
+//! back, `rolled_back`. Batches over `max_batch_statements` or
+//! `max_batch_bytes` are refused with 413.
+
+use crate::{
+    auth::Scope,
+    forward::NotLeader,
+    rate_limit::Client,
+    server::{error_status, DatabaseState, ErrorBody, RESULT_FORMAT},
+};
+use axum::{
+    body::Body,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use futures::TryStreamExt;
+use nextdb_query::{QueryError, ResultSet, SqlParser, SqlStatement};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Instant};
+use tracing::{info, warn};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchRequest {
+    statements: Vec<String>,
+    #[serde(default)]
+    transactional: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Ok,
+    Error,
+    /// Not run, because an earlier statement of a transactional batch failed
+    Skipped,
+    /// Ran, but its transaction was rolled back
+    RolledBack,
+}
+
+#[derive(Serialize)]
+struct StatementResult {
+    status: Status,
+    rows_affected: Option<u64>,
+    execution_time_ms: f64,
+    result: Option<ResultSet>,
+    error: Option<ErrorBody>,
+}
+
+#[derive(Serialize)]
+struct BatchResponse {
+    /// Whether every statement succeeded, and for a transactional batch
+    /// whether it committed
+    success: bool,
+    result_format: u32,
+    transactional: bool,
+    succeeded: usize,
+    failed: usize,
+    execution_time_ms: f64,
+    results: Vec<StatementResult>,
+    /// Why the batch as a whole failed, such as a COMMIT that conflicted
+    error: Option<ErrorBody>,
+}
+
+fn rejected(status: StatusCode, code: &'static str, message: String) -> Response {
+    let body = serde_json::json!({
+        "success": false,
+        "error": { "code": code, "message": message },
+    });
+    (status, Json(body)).into_response()
+}
+
+/// Handler for `POST /api/batch`
+pub(crate) async fn execute_batch(
+    State(state): State<Arc<DatabaseState>>,
+    Extension(scope): Extension<Scope>,
+    client: Option<Extension<Client>>,
+    body: Body,
+) -> Response {
+    let limits = state.batch_limits;
+    // Read no more of the body than a batch may hold
+    let mut bytes = Vec::new();
+    let mut chunks = body.into_data_stream();
+    loop {
+        match chunks.try_next().await {
+            Ok(Some(chunk)) if bytes.len() + chunk.len() > limits.max_bytes => {
+                let message = format!("the batch is larger than {} bytes", limits.max_bytes);
+                return rejected(StatusCode::PAYLOAD_TOO_LARGE, "batch_too_large", message);
+            }
+            Ok(Some(chunk)) => bytes.extend_from_slice(&chunk),
+            Ok(None) => break,
+            Err(e) => return rejected(StatusCode::BAD_REQUEST, "invalid_request", e.to_string()),
+        }
+    }
+    let request: BatchRequest = match serde_json::from_slice(&bytes) {
+        Ok(request) => request,
+        Err(e) => return rejected(StatusCode::BAD_REQUEST, "invalid_request", e.to_string()),
+    };
+    if request.statements.len() > limits.max_statements {
+        let message = format!("the batch has more than {} statements", limits.max_statements);
+        return rejected(StatusCode::PAYLOAD_TOO_LARGE, "batch_too_large", message);
+    }
+    info!("Executing a batch of {} statements", request.statements.len());
+
+    let parsed: Vec<_> = request.statements.iter().map(|sql| SqlParser::parse(sql)).collect();
+    let writes = parsed.iter().flatten().filter(|statement| !statement.is_read_only()).count();
+    if writes > 0 && scope == Scope::ReadOnly {
+        let message = "the API token is read-only and the batch writes".to_string();
+        return rejected(StatusCode::FORBIDDEN, "read_only_token", message);
+    }
+    let client = client.map_or(Client::Unknown, |Extension(client)| client);
+    let admitted = state.rate_limiter.as_ref().map(|limiter| limiter.admit_batch(&client, writes as u32));
+    let _permit = match admitted.transpose() {
+        Ok(permit) => permit,
+        Err(limited) => return limited.into_response(),
+    };
+
+    let started = Instant::now();
+    let session = state.new_session();
+    let executor = &state.executor;
+    let mut results = Vec::with_capacity(parsed.len());
+    let mut error = None;
+    if request.transactional {
+        if let Err(e) = executor.execute_statement_in(session, SqlStatement::Begin { isolation_level: None }).await {
+            error = Some(error_body(&e));
+        }
+    }
+    // Whether a transactional batch has failed, and runs nothing more
+    let mut aborted = error.is_some();
+    for statement in parsed {
+        if aborted {
+            results.push(StatementResult::skipped());
+            continue;
+        }
+        let statement_started = Instant::now();
+        let result = match statement {
+            Ok(SqlStatement::Begin { .. } | SqlStatement::Commit | SqlStatement::Rollback) if request.transactional => {
+                Err(QueryError::Invalid("a transactional batch cannot begin or end transactions".to_string()))
+            }
+            Ok(statement) => executor.execute_statement_in(session, statement).await,
+            Err(e) => {
+                executor.fail_transaction(session).await;
+                Err(e)
+            }
+        };
+        let elapsed = statement_started.elapsed();
+        state.query_metrics.record(elapsed, result.is_ok());
+        aborted = request.transactional && result.is_err();
+        results.push(StatementResult::new(result, elapsed.as_secs_f64() * 1000.0));
+    }
+
+    let failed = results.iter().filter(|result| result.status == Status::Error).count();
+    if request.transactional && error.is_none() {
+        let end = if failed == 0 { SqlStatement::Commit } else { SqlStatement::Rollback };
+        if let Err(e) = executor.execute_statement_in(session, end).await {
+            error = Some(error_body(&e));
+        }
+        if failed > 0 || error.is_some() {
+            for result in results.iter_mut().filter(|result| result.status == Status::Ok) {
+                result.status = Status::RolledBack;
+            }
+        }
+    }
+    // Roll back a transaction the batch left open
+    if let Err(e) = executor.end_session(session).await {
+        warn!("Failed to end batch session: {}", e);
+    }
+
+    let succeeded = results.iter().filter(|result| result.status == Status::Ok).count();
+    // A follower refuses writes; see `forward`
+    let not_leader = results.iter().filter_map(|result| result.error.as_ref()).chain(&error)
+        .any(|error| error.code == "not_leader");
+    let response = BatchResponse {
+        success: failed == 0 && error.is_none(),
+        result_format: RESULT_FORMAT,
+        transactional: request.transactional,
+        succeeded,
+        failed,
+        execution_time_ms: started.elapsed().as_secs_f64() * 1000.0,
+        results,
+        error,
+    };
+    let mut response = (StatusCode::OK, Json(response)).into_response();
+    if not_leader {
+        response.extensions_mut().insert(NotLeader);
+    }
+    response
+}
+
+fn error_body(error: &QueryError) -> ErrorBody {
+    ErrorBody { code: error_status(error).1, message: error.to_string() }
+}
+
+impl StatementResult {
+    fn new(result: nextdb_query::Result<ResultSet>, execution_time_ms: f64) -> Self {
+        let mut statement = Self { status: Status::Ok, rows_affected: None, execution_time_ms, result: None, error: None };
+        match result {
+            Ok(result) if result.rows_affected.is_some() => statement.rows_affected = result.rows_affected,
+            Ok(result) => statement.result = Some(result),
+            Err(e) => {
+                statement.status = Status::Error;
+                statement.error = Some(error_body(&e));
+            }
+        }
+        statement
+    }
+
+    fn skipped() -> Self {
+        Self { status: Status::Skipped, rows_affected: None, execution_time_ms: 0.0, result: None, error: None }
+    }
+}
+
+/// Limits on the size of a batch, from the server config
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BatchLimits {
+    pub(crate) max_statements: usize,
+    pub(crate) max_bytes: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{self, json_request, send_json};
+    use crate::{DatabaseServer, ServerConfig};
+    use axum::{
+        body::Body,
+        http::{Method, StatusCode},
+        Router,
+    };
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    async fn app(temp_dir: &TempDir) -> Router {
+        let config = ServerConfig {
+            max_batch_statements: 5,
+            max_batch_bytes: 1024,
+            ..test_util::config(temp_dir)
+        };
+        DatabaseServer::with_config(config).await.unwrap().router()
+    }
+
+    async fn post(app: &Router, body: String) -> (StatusCode, serde_json::Value) {
+        send_json(app, json_request(Method::POST, "/api/batch").body(Body::from(body)).unwrap()).await
+    }
+
+    async fn batch(app: &Router, statements: &[&str], transactional: bool) -> serde_json::Value {
+        let (status, body) = post(app, json!({ "statements": statements, "transactional": transactional }).to_string()).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        body
+    }
+
+    fn statuses(body: &serde_json::Value) -> Vec<&str> {
+        body["results"].as_array().unwrap().iter().map(|result| result["status"].as_str().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_transactional_batch_is_all_or_nothing() {
+        let temp_dir = TempDir::new().unwrap();
+        let app = app(&temp_dir).await;
+        batch(&app, &["CREATE TABLE t (id INT PRIMARY KEY, v TEXT)"], false).await;
+
+        let body = batch(&app, &[
+            "INSERT INTO t VALUES (1, 'a')",
+            "INSERT INTO t VALUES (2, 'b')",
+            "SELECT COUNT(*) FROM t",
+        ], true).await;
+        assert_eq!(body["success"], true, "{}", body);
+        assert_eq!(statuses(&body), vec!["ok", "ok", "ok"]);
+        assert_eq!(body["results"][0]["rows_affected"], 1);
+        assert_eq!(body["results"][2]["result"]["rows"], json!([[2]]));
+        assert_eq!((body["succeeded"].as_u64(), body["failed"].as_u64()), (Some(3), Some(0)));
+        assert!(body["execution_time_ms"].as_f64().unwrap() >= 0.0);
+
+        // A failure rolls back what came before it and skips the rest
+        let body = batch(&app, &[
+            "INSERT INTO t VALUES (3, 'c')",
+            "INSERT INTO t VALUES (1, 'dup')",
+            "INSERT INTO t VALUES (4, 'd')",
+        ], true).await;
+        assert_eq!(body["success"], false);
+        assert_eq!(statuses(&body), vec!["rolled_back", "error", "skipped"]);
+        assert_eq!(body["results"][1]["error"]["code"], "constraint_violation");
+        assert_eq!((body["succeeded"].as_u64(), body["failed"].as_u64()), (Some(0), Some(1)));
+
+        // as do statements that do not parse, and transaction control
+        for failing in ["SELEKT 1", "COMMIT"] {
+            let body = batch(&app, &["INSERT INTO t VALUES (5, 'e')", failing], true).await;
+            assert_eq!(statuses(&body), vec!["rolled_back", "error"], "{}", failing);
+        }
+        let body = batch(&app, &["SELECT id FROM t ORDER BY id"], false).await;
+        assert_eq!(body["results"][0]["result"]["rows"], json!([[1], [2]]));
+    }
+
+    #[tokio::test]
+    async fn test_batch_continues_past_errors() {
+        let temp_dir = TempDir::new().unwrap();
+        let app = app(&temp_dir).await;
+
+        let body = batch(&app, &[
+            "CREATE TABLE t (id INT PRIMARY KEY)",
+            "INSERT INTO t VALUES (1)",
+            "INSERT INTO t VALUES (1)",
+            "SELECT * FROM missing",
+            "INSERT INTO t VALUES (2)",
+        ], false).await;
+        assert_eq!(body["success"], false);
+        assert_eq!(body["transactional"], false);
+        assert_eq!(statuses(&body), vec!["ok", "ok", "error", "error", "ok"]);
+        assert_eq!(body["results"][3]["error"]["code"], "table_not_found");
+        assert_eq!((body["succeeded"].as_u64(), body["failed"].as_u64()), (Some(3), Some(2)));
+
+        // A transaction the batch opens and leaves open is rolled back
+        let body = batch(&app, &["BEGIN", "INSERT INTO t VALUES (3)"], false).await;
+        assert_eq!(body["success"], true, "{}", body);
+        let body = batch(&app, &["SELECT id FROM t ORDER BY id"], false).await;
+        assert_eq!(body["results"][0]["result"]["rows"], json!([[1], [2]]));
+    }
+
+    #[tokio::test]
+    async fn test_batch_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let app = app(&temp_dir).await;
+
+        let (status, body) = post(&app, json!({ "statements": vec!["SELECT 1"; 6] }).to_string()).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"]["code"], "batch_too_large");
+        let long = format!("SELECT '{}'", "x".repeat(1024));
+        let (status, body) = post(&app, json!({ "statements": [long] }).to_string()).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"]["code"], "batch_too_large");
+
+        let (status, body) = post(&app, json!({ "statements": vec!["SELECT 1"; 5] }).to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["succeeded"], 5);
+        for malformed in ["[\"SELECT 1\"]", "{\"statements\": [1]}", "{\"sql\": \"SELECT 1\"}"] {
+            let (status, body) = post(&app, malformed.to_string()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", malformed);
+            assert_eq!(body["error"]["code"], "invalid_request");
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Cluster membership for clients and dashboards.
+//!
+//! `/api/cluster/nodes` lists the voting members as the Raft node knows
+//! them: each one's ID, address, role and health. A standalone server lists
+//! itself alone, with no ID.
+
+use axum::{extract::State, Json};
+use nextdb_consensus::{RaftNode, RaftState};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+pub(crate) struct Topology {
+    raft: Option<Arc<RwLock<RaftNode>>>,
+    /// Where this server listens, for nodes without a configured address
+    address: String,
+}
+
+impl Topology {
+    pub(crate) fn new(raft: Option<Arc<RwLock<RaftNode>>>, address: String) -> Self {
+        Self { raft, address }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct NodeInfo {
+    node_id: Option<String>,
+    address: Option<String>,
+    /// "leader", "follower", "candidate", or "standalone" without Raft
+    role: &'static str,
+    /// "healthy", "unhealthy", or "unknown" for peers, whose health is not
+    /// tracked yet
+    health: &'static str,
+    /// Whether this is the node that answered
+    local: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Nodes {
+    /// Null without Raft
+    term: Option<u64>,
+    leader: Option<String>,
+    nodes: Vec<NodeInfo>,
+}
+
+pub(crate) fn role(state: &RaftState) -> &'static str {
+    match state {
+        RaftState::Leader => "leader",
+        RaftState::Follower => "follower",
+        RaftState::Candidate => "candidate",
+    }
+}
+
+pub(crate) async fn nodes(State(topology): State<Arc<Topology>>) -> Json<Nodes> {
+    let Some(raft) = &topology.raft else {
+        let node = NodeInfo {
+            node_id: None,
+            address: Some(topology.address.clone()),
+            role: "standalone",
+            health: "healthy",
+            local: true,
+        };
+        return Json(Nodes { term: None, leader: None, nodes: vec![node] });
+    };
+
+    let node = raft.read().await;
+    let nodes = node.members().into_iter().map(|member| NodeInfo {
+        node_id: Some(member.node_id.0.to_string()),
+        address: member.address.or_else(|| member.is_self.then(|| topology.address.clone())),
+        role: role(&member.state),
+        health: match member.healthy {
+            Some(true) => "healthy",
+            Some(false) => "unhealthy",
+            None => "unknown",
+        },
+        local: member.is_self,
+    }).collect();
+    Json(Nodes {
+        term: Some(node.current_term()),
+        leader: node.leader().map(|leader| leader.0.to_string()),
+        nodes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{self, get};
+    use crate::{Config, DatabaseServer, ServerConfig};
+    use axum::http::StatusCode;
+    use nextdb_consensus::{NodeId, RaftConfig};
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_single_node_leads() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = test_util::config(&temp_dir);
+        let node_id = NodeId::new();
+        let config = Config {
+            consensus: Some(RaftConfig {
+                node_id,
+                addresses: HashMap::from([(node_id, "db1.internal:8080".to_string())]),
+                ..RaftConfig::default()
+            }),
+            ..server.into()
+        };
+        let app = DatabaseServer::new(config).await.unwrap().router();
+
+        let (status, body) = get(&app, "/api/cluster/nodes").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let id = node_id.0.to_string();
+        assert_eq!((body["term"].as_u64(), body["leader"].as_str()), (Some(1), Some(id.as_str())));
+        assert_eq!(body["nodes"], serde_json::json!([{
+            "node_id": id,
+            "address": "db1.internal:8080",
+            "role": "leader",
+            "health": "healthy",
+            "local": true,
+        }]));
+        assert_eq!(get(&app, "/ready").await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_standalone() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = ServerConfig { port: 7070, ..test_util::config(&temp_dir) };
+        let app = DatabaseServer::with_config(config).await.unwrap().router();
+
+        let (status, body) = get(&app, "/api/cluster/nodes").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["leader"], serde_json::Value::Null);
+        let nodes = body["nodes"].as_array().unwrap();
+        assert_eq!((nodes.len(), &nodes[0]["role"], &nodes[0]["address"]), (1, &serde_json::json!("standalone"), &serde_json::json!("0.0.0.0:7070")));
+    }
+}
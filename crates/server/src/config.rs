@@ -1,16 +1,352 @@
+//! Node configuration, read from a TOML file and the environment.
+//!
+//! The file has a section per component: `[server]`, `[storage]`,
+//! `[consensus]` and `[transaction]`, each holding that component's config
+//! struct. Any key can be overridden by an environment variable named
+//! `NEXTDB_<SECTION>__<KEY>`, such as `NEXTDB_STORAGE__CACHE_SIZE_MB=512`,
+//! and command line flags override both. See `nextdb.example.toml`.
+
+use crate::{Result, ServerError};
+use nextdb_consensus::RaftConfig;
+use nextdb_storage::StorageConfig;
+use nextdb_transaction::TransactionConfig;
 use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Environment variables from before config files, and the keys they set.
+/// `NEXTDB_<SECTION>__<KEY>` variables take precedence over them.
+const ENV_ALIASES: &[(&str, &str)] = &[
+    ("NEXTDB_DATA_DIR", "server.data_dir"),
+    ("NEXTDB_PROTOCOL_PORT", "server.protocol_port"),
+    ("NEXTDB_SHUTDOWN_TIMEOUT_MS", "server.shutdown_timeout_ms"),
+];
 
+/// Everything a node is configured with
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    /// Unless set, the data files and WAL are kept under `server.data_dir`
+    pub storage: StorageConfig,
+    /// Run a Raft node with these settings, or None for a standalone server
+    pub consensus: Option<RaftConfig>,
+    pub transaction: TransactionConfig,
+}
+
+impl Config {
+    /// Read the config file at `path`, if any, then apply the
+    /// `NEXTDB_<SECTION>__<KEY>` variables in `env` and then `overrides`,
+    /// which are dotted keys such as `server.port` with their values.
+    /// Keys nothing sets keep their defaults, and the result is validated.
+    pub fn load(
+        path: Option<&Path>,
+        env: impl IntoIterator<Item = (String, String)>,
+        overrides: &[(&str, &str)],
+    ) -> Result<Config> {
+        let mut table = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| ServerError::Config(format!("cannot read {}: {}", path.display(), e)))?;
+                text.parse::<toml::Table>()
+                    .map_err(|e| ServerError::Config(format!("{}: {}", path.display(), e)))?
+            }
+            None => toml::Table::new(),
+        };
+
+        // Defaults, with every optional section present, tell what type
+        // each key's value should be read as
+        let mut template = Config { consensus: Some(RaftConfig::default()), ..Config::default() };
+        template.server.rate_limit = Some(Default::default());
+        template.server.load_shedding = Some(Default::default());
+        template.server.cors = Some(Default::default());
+        #[cfg(feature = "postgres")]
+        {
+            template.server.postgres = Some(Default::default());
+        }
+        let template = toml::Value::try_from(template).map_err(|e| ServerError::Config(e.to_string()))?;
+
+        let env: Vec<(String, String)> = env.into_iter().collect();
+        for (alias, key) in ENV_ALIASES {
+            if let Some((_, value)) = env.iter().find(|(name, _)| name == alias) {
+                set_key(&mut table, &template, key, value)?;
+            }
+        }
+        for (name, value) in &env {
+            if let Some(key) = name.strip_prefix("NEXTDB_").filter(|key| key.contains("__")) {
+                set_key(&mut table, &template, &key.to_lowercase().replace("__", "."), value)?;
+            }
+        }
+        for (key, value) in overrides {
+            set_key(&mut table, &template, key, value)?;
+        }
+
+        let storage = table.get("storage").and_then(toml::Value::as_table);
+        let placed = |key: &str| storage.is_some_and(|storage| storage.contains_key(key));
+        let (data_dir_set, wal_dir_set) = (placed("data_dir"), placed("wal_dir"));
+
+        let mut config: Config = serde_path_to_error::deserialize(toml::Value::Table(table))
+            .map_err(|e| ServerError::Config(format!("{}: {}", e.path(), e.inner())))?;
+        let derived = config.server.storage_config();
+        if !data_dir_set {
+            config.storage.data_dir = derived.data_dir;
+        }
+        if !wal_dir_set {
+            config.storage.wal_dir = derived.wal_dir;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check settings the types alone allow but the node cannot run with
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: String| Err(ServerError::Config(message));
+        let server = &self.server;
+        let storage = &self.storage;
+        let positive = [
+            ("server.stats_interval_ms", server.stats_interval_ms),
+            ("server.max_frame_bytes", server.max_frame_bytes as u64),
+            ("server.max_protocol_connections", server.max_protocol_connections as u64),
+            ("server.protocol_idle_timeout_ms", server.protocol_idle_timeout_ms),
+            ("server.max_batch_statements", server.max_batch_statements as u64),
+            ("server.max_batch_bytes", server.max_batch_bytes as u64),
+            ("server.cursor_idle_timeout_ms", server.cursor_idle_timeout_ms),
+            ("server.max_cursors_per_client", server.max_cursors_per_client as u64),
+            ("server.max_prepared_statements_per_client", server.max_prepared_statements_per_client as u64),
+            ("server.transaction_idle_timeout_ms", server.transaction_idle_timeout_ms),
+            ("server.slow_query_log_per_second", server.slow_query_log_per_second as u64),
+            ("storage.memtable_size_mb", storage.memtable_size_mb as u64),
+            ("storage.l0_compaction_trigger", storage.l0_compaction_trigger as u64),
+            ("storage.l0_compaction_bytes", storage.l0_compaction_bytes),
+            ("storage.max_levels", storage.max_levels as u64),
+            ("storage.target_file_size_mb", storage.target_file_size_mb as u64),
+            ("storage.max_immutable_memtables", storage.max_immutable_memtables as u64),
+        ];
+        if let Some((key, _)) = positive.iter().find(|(_, value)| *value == 0) {
+            return invalid(format!("{} must be greater than 0", key));
+        }
+        if let Some(limits) = &server.rate_limit {
+            let positive = [
+                ("requests_per_second", limits.requests_per_second),
+                ("request_burst", limits.request_burst.into()),
+                ("writes_per_second", limits.writes_per_second),
+                ("write_burst", limits.write_burst.into()),
+                ("max_concurrent_queries", limits.max_concurrent_queries as f64),
+                ("max_clients", limits.max_clients as f64),
+            ];
+            if let Some((key, _)) = positive.iter().find(|(_, value)| value.is_nan() || *value <= 0.0) {
+                return invalid(format!("server.rate_limit.{} must be greater than 0", key));
+            }
+        }
+        if let Some(limits) = &server.load_shedding {
+            let positive = [
+                ("max_concurrent_statements", limits.max_concurrent_statements as u64),
+                ("max_concurrent_requests", limits.max_concurrent_requests as u64),
+                ("max_queue_ms", limits.max_queue_ms),
+            ];
+            if let Some((key, _)) = positive.iter().find(|(_, value)| *value == 0) {
+                return invalid(format!("server.load_shedding.{} must be greater than 0", key));
+            }
+        }
+        if let Some(cors) = &server.cors {
+            // Building the layer checks the origins, methods and headers
+            drop(cors.layer()?);
+        }
+        if storage.l0_stall_trigger < storage.l0_compaction_trigger {
+            return invalid(format!(
+                "storage.l0_stall_trigger must be at least storage.l0_compaction_trigger ({})",
+                storage.l0_compaction_trigger
+            ));
+        }
+        if server.protocol_port.is_some_and(|port| port == server.port) {
+            return invalid(format!("server.protocol_port must differ from server.port ({})", server.port));
+        }
+        #[cfg(feature = "postgres")]
+        if let Some(postgres) = &server.postgres {
+            if postgres.port == server.port || Some(postgres.port) == server.protocol_port {
+                return invalid(format!("server.postgres.port ({}) is already used by another listener", postgres.port));
+            }
+            if server.auth.is_some() && postgres.password.is_some() {
+                return invalid("server.postgres.password cannot be used with server.auth, whose API tokens are the passwords".to_string());
+            }
+        }
+        if let Some(consensus) = &self.consensus {
+            if consensus.heartbeat_interval_ms == 0 || consensus.heartbeat_interval_ms >= consensus.election_timeout_ms {
+                return invalid(format!(
+                    "consensus.heartbeat_interval_ms must be greater than 0 and less than consensus.election_timeout_ms ({})",
+                    consensus.election_timeout_ms
+                ));
+            }
+            if consensus.peers.contains(&consensus.node_id) {
+                return invalid("consensus.peers must not include consensus.node_id".to_string());
+            }
+            if let Some(stranger) = consensus.addresses.keys().find(|id| **id != consensus.node_id && !consensus.peers.contains(id)) {
+                return invalid(format!("consensus.addresses names {}, which is neither consensus.node_id nor a peer", stranger.0));
+            }
+            if let Some(stranger) = consensus.peer_addresses.keys().find(|id| !consensus.peers.contains(id)) {
+                return invalid(format!("consensus.peer_addresses names {}, which is not a peer", stranger.0));
+            }
+            if consensus.join.is_some() && !consensus.peers.is_empty() {
+                return invalid("consensus.join is for a node starting without consensus.peers".to_string());
+            }
+            if (consensus.join.is_some() || !consensus.peers.is_empty()) && consensus.raft_address.is_none() {
+                return invalid("consensus.raft_address is required with consensus.peers or consensus.join".to_string());
+            }
+            if let Some(peer) = consensus.peers.iter().find(|peer| !consensus.peer_addresses.contains_key(peer)) {
+                return invalid(format!("consensus.peer_addresses has no address for peer {}", peer.0));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        ServerConfig::default().into()
+    }
+}
+
+/// Default settings for everything but the server, with the storage files
+/// under `server.data_dir`
+impl From<ServerConfig> for Config {
+    fn from(server: ServerConfig) -> Self {
+        Self {
+            storage: server.storage_config(),
+            server,
+            consensus: None,
+            transaction: TransactionConfig::default(),
+        }
+    }
+}
+
+/// Set the dotted `key` in `table` to `raw`, read as the type of the key's
+/// value in `template`. Keys without a default, such as optional ones, take
+/// `raw` as a TOML value if it is one and as a string otherwise.
+fn set_key(table: &mut toml::Table, template: &toml::Value, key: &str, raw: &str) -> Result<()> {
+    let expected = |what: &str| ServerError::Config(format!("{}: expected {}, found '{}'", key, what, raw));
+    let default = key.split('.').try_fold(template, |value, part| value.get(part));
+    let value = match default {
+        Some(toml::Value::String(_)) => toml::Value::String(raw.to_string()),
+        Some(toml::Value::Integer(_)) => toml::Value::Integer(raw.trim().parse().map_err(|_| expected("an integer"))?),
+        Some(toml::Value::Float(_)) => toml::Value::Float(raw.trim().parse().map_err(|_| expected("a number"))?),
+        Some(toml::Value::Boolean(_)) => toml::Value::Boolean(raw.trim().parse().map_err(|_| expected("true or false"))?),
+        _ => format!("value = {}", raw).parse::<toml::Table>().ok()
+            .and_then(|mut parsed| parsed.remove("value"))
+            .unwrap_or_else(|| toml::Value::String(raw.to_string())),
+    };
+
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().unwrap_or_default();
+    let mut table = table;
+    for (depth, part) in parts.iter().enumerate() {
+        let entry = table.entry(part.to_string()).or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        table = match entry {
+            toml::Value::Table(inner) => inner,
+            _ => return Err(ServerError::Config(format!("{} is not a section", parts[..=depth].join(".")))),
+        };
+    }
+    table.insert(last.to_string(), value);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ServerConfig {
     pub bind_address: String,
     pub port: u16,
+    /// Directory holding the database's data files and write-ahead log
+    pub data_dir: PathBuf,
+    /// How often the stats reported by the API are refreshed
+    pub stats_interval_ms: u64,
+    /// Port of the binary protocol listener (see `protocol`), or None to
+    /// serve HTTP only
+    pub protocol_port: Option<u16>,
+    /// Largest binary protocol frame accepted from a client, and with the
+    /// `postgres` feature the largest PostgreSQL message
+    pub max_frame_bytes: usize,
+    /// Most binary protocol connections open at once
+    pub max_protocol_connections: usize,
+    /// How long a binary protocol connection may send nothing before it is
+    /// closed
+    pub protocol_idle_timeout_ms: u64,
+    /// Tokens the HTTP API and the other listeners require (see `auth`), or
+    /// None to leave them open
+    pub auth: Option<crate::auth::AuthConfig>,
+    /// Serve HTTPS, and the binary protocol over TLS, with this certificate
+    /// (see `tls`), or None for plain HTTP and TCP
+    pub tls: Option<crate::tls::TlsConfig>,
+    /// Per-client limits on the HTTP API (see `rate_limit`), or None for
+    /// no limits
+    pub rate_limit: Option<crate::rate_limit::RateLimitConfig>,
+    /// Limits on requests handled at once across all clients (see
+    /// `load_shed`), or None to take every request
+    pub load_shedding: Option<crate::load_shed::LoadSheddingConfig>,
+    /// Origins other than the server's own allowed to call the HTTP API
+    /// (see `cors`), or None for same-origin only
+    pub cors: Option<crate::cors::CorsConfig>,
+    /// How long in-flight HTTP requests get to finish after a shutdown
+    /// signal before they are cut off
+    pub shutdown_timeout_ms: u64,
+    /// How long `/ready` reuses the result of its checks
+    pub readiness_cache_ms: u64,
+    /// Serve the dashboard from this directory rather than the copy built
+    /// into the server (see `dashboard`), for working on it
+    pub dashboard_dir: Option<PathBuf>,
+    /// Recent changes kept for `/api/watch` clients to replay with `from_ts`
+    pub watch_history: usize,
+    /// Most statements one `/api/batch` request may hold
+    pub max_batch_statements: usize,
+    /// Largest `/api/batch` request body
+    pub max_batch_bytes: usize,
+    /// How long a `/api/query` cursor is kept without being read
+    pub cursor_idle_timeout_ms: u64,
+    /// Most cursors one client may hold open at once
+    pub max_cursors_per_client: usize,
+    /// Most statements prepared with `/api/prepare` one client may keep,
+    /// or over the binary protocol one connection, before its least
+    /// recently used is forgotten
+    pub max_prepared_statements_per_client: usize,
+    /// How long a transaction begun with `/api/txn/begin` is kept open
+    /// without a request before it is rolled back
+    pub transaction_idle_timeout_ms: u64,
+    /// Statements running at least this long are logged with their plan,
+    /// or with 0 none are
+    pub slow_query_threshold_ms: u64,
+    /// Most slow statements logged each second
+    pub slow_query_log_per_second: u32,
+    /// Memory one query may hold in sort buffers, aggregation groups and
+    /// subquery sets before it spills, or fails if it cannot; 0 for no limit
+    pub max_query_memory_bytes: usize,
+    /// Tracing filter directives such as `info` or `nextdb_storage=debug`,
+    /// used instead of `RUST_LOG` when set
+    pub log_level: Option<String>,
+    /// PostgreSQL wire protocol listener, or None to not serve it
+    #[cfg(feature = "postgres")]
+    pub postgres: Option<crate::postgres::PostgresConfig>,
 }
 
 impl ServerConfig {
     pub fn new(port: u16) -> Self {
         Self {
-            bind_address: "0.0.0.0".to_string(),
             port,
+            ..Self::default()
+        }
+    }
+
+    pub fn readiness_cache(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.readiness_cache_ms)
+    }
+
+    /// `bind_address:port`, where the HTTP API listens
+    pub fn listen_address(&self) -> String {
+        format!("{}:{}", self.bind_address, self.port)
+    }
+
+    /// Storage settings with the data files and WAL under `data_dir`
+    pub fn storage_config(&self) -> StorageConfig {
+        StorageConfig {
+            data_dir: self.data_dir.join("data").to_string_lossy().to_string(),
+            wal_dir: self.data_dir.join("wal").to_string_lossy().to_string(),
+            ..StorageConfig::default()
         }
     }
 }
@@ -20,6 +356,166 @@ impl Default for ServerConfig {
         Self {
             bind_address: "0.0.0.0".to_string(),
             port: 8080,
+            data_dir: PathBuf::from("./nextdb-data"),
+            stats_interval_ms: 1000,
+            protocol_port: None,
+            max_frame_bytes: 16 * 1024 * 1024,
+            max_protocol_connections: 1024,
+            protocol_idle_timeout_ms: 300_000,
+            auth: None,
+            tls: None,
+            rate_limit: None,
+            load_shedding: None,
+            cors: None,
+            shutdown_timeout_ms: 30_000,
+            readiness_cache_ms: 1000,
+            dashboard_dir: None,
+            watch_history: 10_000,
+            max_batch_statements: 1000,
+            max_batch_bytes: 4 * 1024 * 1024,
+            cursor_idle_timeout_ms: 60_000,
+            max_cursors_per_client: 16,
+            max_prepared_statements_per_client: 64,
+            transaction_idle_timeout_ms: 60_000,
+            slow_query_threshold_ms: 1000,
+            slow_query_log_per_second: 10,
+            max_query_memory_bytes: 256 * 1024 * 1024,
+            log_level: None,
+            #[cfg(feature = "postgres")]
+            postgres: None,
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn load_error(file: &str, vars: &[(&str, &str)]) -> String {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("nextdb.toml");
+        std::fs::write(&path, file).unwrap();
+        Config::load(Some(&path), env(vars), &[]).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_config_precedence() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("nextdb.toml");
+        std::fs::write(&path, r#"
+            [server]
+            port = 9000
+            data_dir = "/srv/nextdb"
+
+            [storage]
+            memtable_size_mb = 32
+            cache_size_mb = 128
+
+            [consensus]
+            election_timeout_ms = 300
+        "#).unwrap();
+
+        // The file alone, with defaults for the rest
+        let config = Config::load(Some(&path), Vec::new(), &[]).unwrap();
+        assert_eq!((config.server.port, config.storage.cache_size_mb), (9000, 128));
+        assert_eq!(config.server.shutdown_timeout_ms, 30_000);
+        assert_eq!(config.storage.data_dir, "/srv/nextdb/data");
+        assert_eq!(config.storage.wal_dir, "/srv/nextdb/wal");
+        assert_eq!(config.consensus.as_ref().unwrap().election_timeout_ms, 300);
+        assert!(!config.transaction.commit_wait);
+
+        // The environment beats the file, and command line flags beat both
+        let vars = env(&[
+            ("NEXTDB_SERVER__PORT", "9001"),
+            ("NEXTDB_STORAGE__CACHE_SIZE_MB", "512"),
+            ("NEXTDB_STORAGE__WAL_DIR", "/fast/wal"),
+            ("NEXTDB_TRANSACTION__COMMIT_WAIT", "true"),
+            ("NEXTDB_DATA_DIR", "/ignored"),
+            ("NEXTDB_SERVER__DATA_DIR", "/var/lib/nextdb"),
+            ("HOME", "/root"),
+        ]);
+        let config = Config::load(Some(&path), vars.clone(), &[]).unwrap();
+        assert_eq!((config.server.port, config.storage.cache_size_mb), (9001, 512));
+        assert_eq!(config.storage.memtable_size_mb, 32);
+        assert_eq!(config.storage.data_dir, "/var/lib/nextdb/data");
+        assert_eq!(config.storage.wal_dir, "/fast/wal");
+        assert!(config.transaction.commit_wait);
+        let config = Config::load(Some(&path), vars, &[("server.port", "9002")]).unwrap();
+        assert_eq!(config.server.port, 9002);
+
+        // Variables that predate config files still work
+        let config = Config::load(None, env(&[("NEXTDB_DATA_DIR", "/data"), ("NEXTDB_PROTOCOL_PORT", "7000")]), &[]).unwrap();
+        assert_eq!((config.server.protocol_port, config.storage.data_dir.as_str()), (Some(7000), "/data/data"));
+        assert!(config.consensus.is_none());
+    }
+
+    #[test]
+    fn test_example_config_matches_defaults() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../nextdb.example.toml");
+        let example = Config::load(Some(&path), Vec::new(), &[]).unwrap();
+        let defaults = Config::default();
+        assert_eq!(toml::Value::try_from(&example).unwrap(), toml::Value::try_from(&defaults).unwrap());
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let cases = [
+            ("[server]\nport = \"http\"", vec![], "server.port: invalid type"),
+            ("[server]\nport = 70000", vec![], "server.port"),
+            ("[storage]\ncache_size = 1", vec![], "storage.cache_size: unknown field `cache_size`"),
+            ("[storge]", vec![], "storge: unknown field `storge`"),
+            ("[storage]\ncompression = \"brotli\"", vec![], "storage.compression: unknown variant `brotli`"),
+            ("[server\nport = 1", vec![], "nextdb.toml: TOML parse error"),
+            ("", vec![("NEXTDB_STORAGE__CACHE_SIZE_MB", "lots")], "storage.cache_size_mb: expected an integer, found 'lots'"),
+            ("[server]\nport = 1", vec![("NEXTDB_SERVER__PORT__NUMBER", "1")], "server.port is not a section"),
+            ("[storage]\nmemtable_size_mb = 0", vec![], "storage.memtable_size_mb must be greater than 0"),
+            ("[storage]\nl0_stall_trigger = 2", vec![], "storage.l0_stall_trigger must be at least storage.l0_compaction_trigger (4)"),
+            ("[server]\nprotocol_port = 8080", vec![], "server.protocol_port must differ from server.port (8080)"),
+            ("[server.rate_limit]\nwrites_per_second = 0", vec![], "server.rate_limit.writes_per_second must be greater than 0"),
+            ("", vec![("NEXTDB_SERVER__RATE_LIMIT__REQUEST_BURST", "-1")], "server.rate_limit.request_burst: invalid value"),
+            ("[server.load_shedding]\nmax_concurrent_statements = 0", vec![], "server.load_shedding.max_concurrent_statements must be greater than 0"),
+            ("[consensus]\nheartbeat_interval_ms = 500", vec![], "consensus.heartbeat_interval_ms must be greater than 0"),
+            ("[server.cors]\nallow_any_origin = true\nallow_credentials = true", vec![], "server.cors.allow_credentials cannot be combined with allow_any_origin"),
+            ("", vec![("NEXTDB_SERVER__CORS__ALLOWED_ORIGINS", "[\"*\"]")], "server.cors.allowed_origins: '*'"),
+            ("[consensus]\nnode_id = \"not-a-uuid\"", vec![], "consensus.node_id"),
+        ];
+        for (file, vars, expected) in cases {
+            let error = load_error(file, &vars);
+            assert!(error.contains(expected), "{:?}: {}", file, error);
+        }
+        #[cfg(feature = "postgres")]
+        {
+            let auth = "[[server.auth.tokens]]\nname = \"loader\"\ntoken = \"t0ken\"\nscope = \"read_write\"\n";
+            let error = load_error(&format!("{}[server.postgres]\npassword = \"secret\"", auth), &[]);
+            assert!(error.contains("server.postgres.password cannot be used with server.auth"), "{}", error);
         }
+
+        let id = nextdb_consensus::NodeId::new().0;
+        let error = load_error(&format!("[consensus]\nnode_id = \"{}\"\npeers = [\"{}\"]", id, id), &[]);
+        assert!(error.contains("consensus.peers must not include consensus.node_id"), "{}", error);
+        let (peer, stranger) = (nextdb_consensus::NodeId::new().0, nextdb_consensus::NodeId::new().0);
+        let cluster = format!("[consensus]\nnode_id = \"{}\"\npeers = [\"{}\"]\nraft_address = \"db1:9090\"\n[consensus.peer_addresses]\n\"{}\" = \"db2:9090\"\n", id, peer, peer);
+        let file = format!("{}[consensus.addresses]\n\"{}\" = \"db1:8080\"\n\"{}\" = \"db2:8080\"\n", cluster, id, peer);
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::write(temp_dir.path().join("nextdb.toml"), &file).unwrap();
+        let consensus = Config::load(Some(&temp_dir.path().join("nextdb.toml")), Vec::new(), &[]).unwrap().consensus.unwrap();
+        assert_eq!(consensus.addresses[&nextdb_consensus::NodeId(peer)], "db2:8080");
+        let error = load_error(&format!("{}\"{}\" = \"db3:8080\"\n", file, stranger), &[]);
+        assert!(error.contains(&format!("consensus.addresses names {}", stranger)), "{}", error);
+        let error = load_error(&format!("{}\"{}\" = \"db3:9090\"\n", cluster, stranger), &[]);
+        assert!(error.contains(&format!("consensus.peer_addresses names {}, which is not a peer", stranger)), "{}", error);
+        let error = load_error(&format!("[consensus]\npeers = [\"{}\"]\nraft_address = \"db1:9090\"", peer), &[]);
+        assert!(error.contains(&format!("consensus.peer_addresses has no address for peer {}", peer)), "{}", error);
+        let error = load_error(&format!("[consensus]\npeers = [\"{}\"]", peer), &[]);
+        assert!(error.contains("consensus.raft_address is required"), "{}", error);
+        let error = load_error("[consensus]\njoin = \"db1:9090\"", &[]);
+        assert!(error.contains("consensus.raft_address is required"), "{}", error);
+        let error = load_error(&cluster.replace("[consensus.peer_addresses]", "join = \"db3:9090\"\n[consensus.peer_addresses]"), &[]);
+        assert!(error.contains("consensus.join is for a node starting without consensus.peers"), "{}", error);
+        assert!(Config::load(Some(Path::new("/nonexistent/nextdb.toml")), Vec::new(), &[]).unwrap_err().to_string().contains("cannot read"));
     }
-}
\ No newline at end of file
+}
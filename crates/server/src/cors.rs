@@ -0,0 +1,207 @@
+//! Cross-origin access to the HTTP API.
+//!
+//! Without a `[server.cors]` section the server sends no CORS headers, so
+//! browsers only let pages from its own origin, such as the dashboard, call
+//! the API. The section lists the other origins allowed, either exactly
+//! (`https://app.example.com`) or as every subdomain of one
+//! (`https://*.example.com`). Any origin at all is allowed only with
+//! `allow_any_origin`, which cannot be combined with credentials.
+
+use crate::{Result, ServerError};
+use axum::http::{HeaderName, HeaderValue, Method};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API, such as `https://app.example.com`
+    /// or `https://*.example.com` for any subdomain of example.com
+    pub allowed_origins: Vec<String>,
+    /// Allow every origin, whatever `allowed_origins` says
+    pub allow_any_origin: bool,
+    pub allowed_methods: Vec<String>,
+    /// Request headers cross-origin callers may send
+    pub allowed_headers: Vec<String>,
+    /// Let browsers send cookies and credentials with cross-origin requests
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allow_any_origin: false,
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec!["authorization".to_string(), "content-type".to_string()],
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+impl CorsConfig {
+    /// The layer adding the headers this config calls for, or an error if
+    /// the config is invalid
+    pub(crate) fn layer(&self) -> Result<CorsLayer> {
+        let invalid = |message: String| ServerError::Config(format!("server.cors.{}", message));
+        if self.allow_credentials && self.allow_any_origin {
+            return Err(invalid("allow_credentials cannot be combined with allow_any_origin".to_string()));
+        }
+        let origins = self.allowed_origins.iter()
+            .map(|origin| OriginPattern::parse(origin).ok_or_else(|| invalid(format!(
+                "allowed_origins: '{}' is not scheme://host[:port], with an optional *. before the host",
+                origin
+            ))))
+            .collect::<Result<Vec<_>>>()?;
+        let methods = self.allowed_methods.iter()
+            .map(|method| method.parse::<Method>().map_err(|_| invalid(format!("allowed_methods: '{}' is not a method", method))))
+            .collect::<Result<Vec<_>>>()?;
+        let headers = self.allowed_headers.iter()
+            .map(|header| header.parse::<HeaderName>().map_err(|_| invalid(format!("allowed_headers: '{}' is not a header name", header))))
+            .collect::<Result<Vec<_>>>()?;
+
+        let allow_origin = if self.allow_any_origin {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+                origin.to_str().is_ok_and(|origin| origins.iter().any(|pattern| pattern.matches(origin)))
+            })
+        };
+        Ok(CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(self.allow_credentials)
+            .max_age(Duration::from_secs(self.max_age_secs)))
+    }
+}
+
+/// An allowed origin, or with `subdomains` every origin whose host ends
+/// in `.host`
+#[derive(Debug)]
+struct OriginPattern {
+    scheme: String,
+    subdomains: bool,
+    /// Host and port, lowercase
+    host: String,
+}
+
+impl OriginPattern {
+    fn parse(pattern: &str) -> Option<Self> {
+        let (scheme, host) = pattern.to_ascii_lowercase().split_once("://")
+            .map(|(scheme, host)| (scheme.to_string(), host.to_string()))?;
+        let (subdomains, host) = match host.strip_prefix("*.") {
+            Some(host) => (true, host.to_string()),
+            None => (false, host),
+        };
+        let valid_host = !host.is_empty() && !host.contains(['/', '*', '@', '?', '#']);
+        (!scheme.is_empty() && valid_host).then_some(Self { scheme, subdomains, host })
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        let Some((scheme, host)) = origin.split_once("://") else {
+            return false;
+        };
+        if scheme != self.scheme {
+            return false;
+        }
+        if !self.subdomains {
+            return host == self.host;
+        }
+        host.strip_suffix(&self.host)
+            .and_then(|subdomain| subdomain.strip_suffix('.'))
+            .is_some_and(|subdomain| !subdomain.is_empty() && !subdomain.contains(['/', ':', '@']))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, send};
+    use crate::{DatabaseServer, ServerConfig};
+    use axum::{body::Body, http::{header, Request, StatusCode}, Router};
+    use tempfile::TempDir;
+
+    async fn app(temp_dir: &TempDir, cors: Option<CorsConfig>) -> Router {
+        let config = ServerConfig { cors, ..test_util::config(temp_dir) };
+        DatabaseServer::with_config(config).await.unwrap().router()
+    }
+
+    async fn preflight(app: &Router, origin: &str) -> axum::response::Response {
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/api/query")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+            .body(Body::empty())
+            .unwrap();
+        send(app, request).await
+    }
+
+    fn allowed_origin(response: &axum::response::Response) -> Option<&str> {
+        response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).map(|value| value.to_str().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_allowed_origins() {
+        let temp_dir = TempDir::new().unwrap();
+        let cors = CorsConfig {
+            allowed_origins: vec!["https://app.example.com".to_string(), "https://*.example.org".to_string()],
+            allow_credentials: true,
+            max_age_secs: 120,
+            ..CorsConfig::default()
+        };
+        let app = app(&temp_dir, Some(cors)).await;
+
+        for origin in ["https://app.example.com", "https://eu.dash.example.org", "HTTPS://Console.Example.org"] {
+            let response = preflight(&app, origin).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(allowed_origin(&response), Some(origin));
+            let headers = response.headers();
+            assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+            assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET,POST");
+            assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS], "authorization,content-type");
+            assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "120");
+        }
+        for origin in ["https://evil.com", "http://app.example.com", "https://example.org", "https://app.example.com.evil.com", "https://evilexample.org"] {
+            assert_eq!(allowed_origin(&preflight(&app, origin).await), None, "{}", origin);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_same_origin_by_default() {
+        let temp_dir = TempDir::new().unwrap();
+        let app = app(&temp_dir, None).await;
+        assert_eq!(allowed_origin(&preflight(&app, "https://evil.com").await), None);
+
+        // The dashboard's own requests are unaffected
+        let response = send(&app, Request::get("/api/status").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let cors = CorsConfig { allow_any_origin: true, ..CorsConfig::default() };
+        let app = self::app(&temp_dir, Some(cors)).await;
+        assert_eq!(allowed_origin(&preflight(&app, "https://anywhere.net").await), Some("*"));
+    }
+
+    #[test]
+    fn test_invalid_configs() {
+        let cases = [
+            (CorsConfig { allow_any_origin: true, allow_credentials: true, ..CorsConfig::default() }, "allow_credentials cannot be combined"),
+            (CorsConfig { allowed_origins: vec!["*".to_string()], ..CorsConfig::default() }, "allowed_origins: '*'"),
+            (CorsConfig { allowed_origins: vec!["app.example.com".to_string()], ..CorsConfig::default() }, "allowed_origins"),
+            (CorsConfig { allowed_origins: vec!["https://app.example.com/".to_string()], ..CorsConfig::default() }, "allowed_origins"),
+            (CorsConfig { allowed_methods: vec!["GE T".to_string()], ..CorsConfig::default() }, "allowed_methods: 'GE T'"),
+            (CorsConfig { allowed_headers: vec!["x header".to_string()], ..CorsConfig::default() }, "allowed_headers: 'x header'"),
+        ];
+        for (cors, expected) in cases {
+            let error = cors.layer().unwrap_err().to_string();
+            assert!(error.contains(expected), "{:?}: {}", cors, error);
+        }
+    }
+}
@@ -0,0 +1,314 @@
+//! Cursors paging through `/api/query` results.
+//!
+//! A query sent with `max_rows` returns at most that many rows. If more
+//! remain, the response carries an opaque `cursor` that `POST
+//! /api/query/next` continues from, reading from the same snapshot (see
+//! `nextdb_query::QueryCursor`). A cursor expires after
+//! `cursor_idle_timeout_ms` without use, only the client that opened it may
+//! use it, and each client may hold `max_cursors_per_client` at once. A
+//! client done with a cursor before its last page closes it with `POST
+//! /api/query/close`. Shutting down closes every cursor.
+
+use crate::rate_limit::Client;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use nextdb_query::QueryCursor;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{
+    collections::HashMap,
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CursorLimits {
+    pub(crate) idle_timeout: Duration,
+    pub(crate) per_client: usize,
+}
+
+struct Entry {
+    client: Client,
+    cursor: Arc<tokio::sync::Mutex<QueryCursor>>,
+    /// Page size of the request that opened it, for pages that give none
+    page_rows: usize,
+    last_used: Instant,
+}
+
+/// Why a cursor could not be kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Refused {
+    TooMany,
+    ShuttingDown,
+}
+
+impl IntoResponse for Refused {
+    fn into_response(self) -> Response {
+        let (status, code, message) = match self {
+            Refused::TooMany => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "too_many_cursors",
+                "the client holds as many cursors as it may; read them to the end or let them expire",
+            ),
+            Refused::ShuttingDown => (StatusCode::SERVICE_UNAVAILABLE, "shutting_down", "the server is shutting down"),
+        };
+        let body = serde_json::json!({
+            "success": false,
+            "error": { "code": code, "message": message },
+        });
+        (status, Json(body)).into_response()
+    }
+}
+
+pub(crate) struct Cursors {
+    entries: Mutex<HashMap<String, Entry>>,
+    limits: CursorLimits,
+    // Set on shutdown, after which no cursor opens
+    closed: AtomicBool,
+}
+
+impl Cursors {
+    /// A registry that closes idle cursors in the background
+    pub(crate) fn start(limits: CursorLimits) -> Arc<Self> {
+        let cursors = Arc::new(Self { entries: Mutex::new(HashMap::new()), limits, closed: AtomicBool::new(false) });
+        tokio::spawn(sweep(Arc::downgrade(&cursors), limits.idle_timeout));
+        cursors
+    }
+
+    /// Hold `cursor` for `client`, returning its token. A refused cursor is
+    /// closed.
+    pub(crate) async fn insert(&self, client: Client, mut cursor: QueryCursor, page_rows: usize) -> Result<String, Refused> {
+        self.expire().await;
+        let refused = {
+            let mut entries = self.entries.lock().unwrap();
+            let held = entries.values().filter(|entry| entry.client == client).count();
+            if self.closed.load(Ordering::SeqCst) {
+                Refused::ShuttingDown
+            } else if held >= self.limits.per_client {
+                Refused::TooMany
+            } else {
+                let token = new_token();
+                let cursor = Arc::new(tokio::sync::Mutex::new(cursor));
+                entries.insert(token.clone(), Entry { client, cursor, page_rows, last_used: Instant::now() });
+                return Ok(token);
+            }
+        };
+        if let Err(e) = cursor.close().await {
+            warn!("Cannot close cursor: {}", e);
+        }
+        Err(refused)
+    }
+
+    /// The cursor `token` names and its page size, if `client` opened it
+    /// and it has not expired
+    pub(crate) async fn get(&self, token: &str, client: &Client) -> Option<(Arc<tokio::sync::Mutex<QueryCursor>>, usize)> {
+        self.expire().await;
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(token).filter(|entry| entry.client == *client)?;
+        entry.last_used = Instant::now();
+        Some((entry.cursor.clone(), entry.page_rows))
+    }
+
+    /// Close the cursor `token` names, as once its last page is read
+    pub(crate) async fn remove(&self, token: &str) {
+        let entry = self.entries.lock().unwrap().remove(token);
+        if let Some(entry) = entry {
+            close(entry).await;
+        }
+    }
+
+    /// Close the cursor `token` names if `client` opened it, returning
+    /// whether it did
+    pub(crate) async fn close(&self, token: &str, client: &Client) -> bool {
+        let entry = {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.get(token) {
+                Some(entry) if entry.client == *client => entries.remove(token),
+                _ => None,
+            }
+        };
+        match entry {
+            Some(entry) => {
+                close(entry).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Close every cursor for good, returning how many were open
+    pub(crate) async fn close_all(&self) -> usize {
+        self.closed.store(true, Ordering::SeqCst);
+        let entries: Vec<Entry> = self.entries.lock().unwrap().drain().map(|(_, entry)| entry).collect();
+        let count = entries.len();
+        for entry in entries {
+            close(entry).await;
+        }
+        count
+    }
+
+    async fn expire(&self) {
+        let idle_timeout = self.limits.idle_timeout;
+        let expired: Vec<Entry> = {
+            let mut entries = self.entries.lock().unwrap();
+            let tokens: Vec<String> = entries.iter()
+                .filter(|(_, entry)| entry.last_used.elapsed() >= idle_timeout)
+                .map(|(token, _)| token.clone())
+                .collect();
+            tokens.iter().filter_map(|token| entries.remove(token)).collect()
+        };
+        for entry in expired {
+            close(entry).await;
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+async fn close(entry: Entry) {
+    if let Err(e) = entry.cursor.lock().await.close().await {
+        warn!("Cannot close cursor: {}", e);
+    }
+}
+
+async fn sweep(cursors: Weak<Cursors>, idle_timeout: Duration) {
+    let mut interval = tokio::time::interval((idle_timeout / 2).max(Duration::from_millis(100)));
+    loop {
+        interval.tick().await;
+        let Some(cursors) = cursors.upgrade() else {
+            return;
+        };
+        cursors.expire().await;
+    }
+}
+
+/// 128 random bits in hex
+pub(crate) fn new_token() -> String {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new().fill(&mut bytes).expect("the system random source failed");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{self, post};
+    use crate::{DatabaseServer, ServerConfig};
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    async fn server(temp_dir: &TempDir, cursor_idle_timeout_ms: u64) -> DatabaseServer {
+        let config = ServerConfig {
+            cursor_idle_timeout_ms,
+            max_cursors_per_client: 2,
+            ..test_util::config(temp_dir)
+        };
+        let server = DatabaseServer::with_config(config).await.unwrap();
+        let app = server.router();
+        post(&app, "/api/query", json!({ "sql": "CREATE TABLE t (id INT PRIMARY KEY, v TEXT)" })).await;
+        for chunk in (0..2345).collect::<Vec<i64>>().chunks(500) {
+            let values: Vec<String> = chunk.iter().map(|i| format!("({}, 'row {}')", i, i)).collect();
+            let sql = format!("INSERT INTO t VALUES {}", values.join(", "));
+            assert_eq!(post(&app, "/api/query", json!({ "sql": sql })).await.0, StatusCode::OK);
+        }
+        server
+    }
+
+    fn ids(page: &Value) -> Vec<i64> {
+        page["result"]["rows"].as_array().unwrap().iter().map(|row| row[0].as_i64().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_paging_through_a_result() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = server(&temp_dir, 60_000).await;
+        let app = server.router();
+
+        let (status, page) = post(&app, "/api/query", json!({ "sql": "SELECT id, v FROM t", "max_rows": 500 })).await;
+        assert_eq!(status, StatusCode::OK, "{}", page);
+        let mut seen = ids(&page);
+        assert_eq!(seen.len(), 500);
+        let cursor = page["cursor"].as_str().unwrap().to_string();
+        assert_eq!(cursor.len(), 32);
+
+        // Rows written meanwhile are not in later pages, which may be sized
+        // anew
+        post(&app, "/api/query", json!({ "sql": "INSERT INTO t VALUES (5000, 'late')" })).await;
+        post(&app, "/api/query", json!({ "sql": "DELETE FROM t WHERE id = 2000" })).await;
+        let mut pages = 1;
+        loop {
+            let max_rows = if pages % 2 == 0 { json!(null) } else { json!(300) };
+            let (status, page) = post(&app, "/api/query/next", json!({ "cursor": cursor, "max_rows": max_rows })).await;
+            assert_eq!(status, StatusCode::OK, "{}", page);
+            assert_eq!(page["result"]["columns"][1]["name"], "v");
+            seen.extend(ids(&page));
+            pages += 1;
+            if page["cursor"].is_null() {
+                break;
+            }
+            assert_eq!(page["cursor"], cursor);
+        }
+        assert_eq!(seen, (0..2345).collect::<Vec<_>>());
+        assert_eq!(pages, 6);
+
+        // A cursor read to the end is gone
+        let (status, body) = post(&app, "/api/query/next", json!({ "cursor": cursor })).await;
+        assert_eq!((status, &body["error"]["code"]), (StatusCode::GONE, &json!("cursor_expired")));
+        assert!(!server.state().executor.transactions().has_snapshots());
+
+        // Results that fit a page, and statements other than SELECT, get no cursor
+        let (_, page) = post(&app, "/api/query", json!({ "sql": "SELECT id FROM t WHERE id < 3", "max_rows": 3 })).await;
+        assert_eq!((ids(&page), &page["cursor"]), (vec![0, 1, 2], &Value::Null));
+        let (status, page) = post(&app, "/api/query", json!({ "sql": "DELETE FROM t WHERE id = 1", "max_rows": 1 })).await;
+        assert_eq!((status, &page["rows_affected"], &page["cursor"]), (StatusCode::OK, &json!(1), &Value::Null));
+        let (status, page) = post(&app, "/api/query", json!({ "sql": "SELECT id FROM t", "max_rows": 0 })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", page);
+        assert_eq!(server.state().cursors.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_cursor_expiry_and_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = server(&temp_dir, 100).await;
+        let app = server.router();
+        let open = || post(&app, "/api/query", json!({ "sql": "SELECT id FROM t", "max_rows": 10 }));
+
+        let (_, page) = open().await;
+        let idle = page["cursor"].as_str().unwrap().to_string();
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let (status, body) = post(&app, "/api/query/next", json!({ "cursor": idle })).await;
+        assert_eq!((status, &body["error"]["code"]), (StatusCode::GONE, &json!("cursor_expired")));
+        let (status, body) = post(&app, "/api/query/next", json!({ "cursor": "0123" })).await;
+        assert_eq!((status, &body["error"]["code"]), (StatusCode::GONE, &json!("cursor_expired")));
+
+        // Each client holds two at most
+        let (_, first) = open().await;
+        open().await;
+        let (status, body) = open().await;
+        assert_eq!((status, &body["error"]["code"]), (StatusCode::TOO_MANY_REQUESTS, &json!("too_many_cursors")));
+        assert_eq!(server.state().cursors.len(), 2);
+
+        // Closing one makes room for another, and only closes it once
+        let (status, _) = post(&app, "/api/query/close", json!({ "cursor": first["cursor"] })).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = post(&app, "/api/query/close", json!({ "cursor": first["cursor"] })).await;
+        assert_eq!((status, &body["error"]["code"]), (StatusCode::GONE, &json!("cursor_expired")));
+        let (status, last) = open().await;
+        assert_eq!(status, StatusCode::OK, "{}", last);
+
+        // Shutting down closes them
+        server.close().await.unwrap();
+        assert!(!server.state().executor.transactions().has_snapshots());
+        let (status, body) = post(&app, "/api/query/next", json!({ "cursor": last["cursor"] })).await;
+        assert_eq!((status, &body["error"]["code"]), (StatusCode::GONE, &json!("cursor_expired")));
+        let (status, body) = open().await;
+        assert_eq!((status, &body["error"]["code"]), (StatusCode::SERVICE_UNAVAILABLE, &json!("shutting_down")));
+    }
+}
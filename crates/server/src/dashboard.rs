@@ -0,0 +1,100 @@
+//! The web dashboard.
+//!
+//! Its files are built into the binary: `web/dashboard.html` is served at
+//! `/` and the files in `ASSETS` under `/static/`. With `dashboard_dir` set
+//! they are read from that directory on each request instead, the page from
+//! `dashboard.html` and the rest from `static/`, so the dashboard can be
+//! worked on without rebuilding the server. Nothing is written to disk.
+
+use axum::{
+    extract::Path,
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use tower_http::services::{ServeDir, ServeFile};
+
+const DASHBOARD: &str = include_str!("../../../web/dashboard.html");
+
+struct Asset {
+    path: &'static str,
+    content_type: &'static str,
+    body: &'static [u8],
+}
+
+/// Files served under `/static/`. `index.html` is the dashboard again, where
+/// servers used to write a copy of it.
+const ASSETS: &[Asset] = &[
+    Asset { path: "index.html", content_type: "text/html; charset=utf-8", body: DASHBOARD.as_bytes() },
+];
+
+/// Routes for the dashboard page and its assets, embedded or from `dir`
+pub(crate) fn routes<S: Clone + Send + Sync + 'static>(dir: Option<&std::path::Path>) -> Router<S> {
+    match dir {
+        None => Router::new()
+            .route("/", get(dashboard))
+            .route("/static/*path", get(asset)),
+        Some(dir) => Router::new()
+            .route_service("/", ServeFile::new(dir.join("dashboard.html")))
+            .nest_service("/static", ServeDir::new(dir.join("static"))),
+    }
+}
+
+async fn dashboard() -> Html<&'static str> {
+    Html(DASHBOARD)
+}
+
+async fn asset(Path(path): Path<String>) -> Response {
+    match ASSETS.iter().find(|asset| asset.path == path) {
+        Some(asset) => ([(header::CONTENT_TYPE, asset.content_type)], asset.body).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{bytes, send};
+    use axum::body::Body;
+    use axum::http::Request;
+    use tempfile::TempDir;
+
+    async fn get(app: &Router, uri: &str) -> (StatusCode, Option<String>, Vec<u8>) {
+        let response = send(app, Request::get(uri).body(Body::empty()).unwrap()).await;
+        let status = response.status();
+        let content_type = response.headers().get(header::CONTENT_TYPE).map(|value| value.to_str().unwrap().to_string());
+        (status, content_type, bytes(response).await)
+    }
+
+    #[tokio::test]
+    async fn test_embedded_dashboard() {
+        let app = routes(None);
+        let html = Some("text/html; charset=utf-8".to_string());
+        assert_eq!(get(&app, "/").await, (StatusCode::OK, html.clone(), DASHBOARD.as_bytes().to_vec()));
+        assert_eq!(get(&app, "/static/index.html").await, (StatusCode::OK, html, DASHBOARD.as_bytes().to_vec()));
+        assert_eq!(get(&app, "/static/missing.js").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(get(&app, "/static/../Cargo.toml").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_from_directory() {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("static")).unwrap();
+        std::fs::write(dir.path().join("dashboard.html"), "<h1>dev</h1>").unwrap();
+        std::fs::write(dir.path().join("static/app.js"), "run()").unwrap();
+        let app = routes(Some(dir.path()));
+
+        let (status, content_type, body) = get(&app, "/").await;
+        assert_eq!((status, body.as_slice()), (StatusCode::OK, &b"<h1>dev</h1>"[..]));
+        assert!(content_type.unwrap().starts_with("text/html"));
+        let (status, content_type, body) = get(&app, "/static/app.js").await;
+        assert_eq!((status, body.as_slice()), (StatusCode::OK, &b"run()"[..]));
+        assert!(content_type.unwrap().contains("javascript"));
+
+        // Edits show up without a restart
+        std::fs::write(dir.path().join("dashboard.html"), "<h1>edited</h1>").unwrap();
+        assert_eq!(get(&app, "/").await.2, b"<h1>edited</h1>");
+        assert_eq!(get(&app, "/static/missing.js").await.0, StatusCode::NOT_FOUND);
+    }
+}
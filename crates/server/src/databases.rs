@@ -0,0 +1,268 @@
+//! Named databases beside the default one.
+//!
+//! `CREATE DATABASE name` gives the server another database: an LSM tree of
+//! its own under `data_dir/databases/name`, with its own catalog, so no
+//! table, row or table id is shared with another database. Requests reach
+//! it at `/api/db/name/query`. `/api/query` and every other endpoint serve
+//! the default database, which `/api/db/default/query` reaches too.
+//! `DROP DATABASE name` closes it and deletes its directory.
+//!
+//! Named databases share the node's settings, each with a memtable and
+//! block cache of its own. They are not replicated, so a cluster has only
+//! the default database, and the status and admin endpoints cover only it.
+
+use crate::{server, Config, Result};
+use nextdb_query::{QueryError, QueryExecutor, ResultSet};
+use nextdb_storage::LSMTree;
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
+use tracing::info;
+
+/// Name `/api/db/{name}/...` takes for the database the other endpoints serve
+pub(crate) const DEFAULT_DATABASE: &str = "default";
+const MAX_NAME_LEN: usize = 63;
+
+pub(crate) struct Database {
+    storage: Arc<LSMTree>,
+    pub(crate) executor: QueryExecutor,
+}
+
+pub(crate) struct Databases {
+    /// Holds a directory per named database
+    dir: PathBuf,
+    /// Settings each database opens with, but for its directories
+    config: Config,
+    clustered: bool,
+    open: tokio::sync::RwLock<BTreeMap<String, Arc<Database>>>,
+}
+
+impl Databases {
+    /// Open the named databases under `config.server.data_dir`
+    pub(crate) async fn open(config: &Config) -> Result<Self> {
+        let databases = Self {
+            dir: config.server.data_dir.join("databases"),
+            config: config.clone(),
+            clustered: config.consensus.is_some(),
+            open: Default::default(),
+        };
+        let entries = match std::fs::read_dir(&databases.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(databases),
+            Err(e) => return Err(e.into()),
+        };
+        let mut open = BTreeMap::new();
+        for entry in entries {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if entry.file_type()?.is_dir() && check_name(&name).is_ok() {
+                open.insert(name.clone(), Arc::new(databases.open_database(&name).await?));
+            }
+        }
+        if !open.is_empty() {
+            info!("Opened {} named databases", open.len());
+        }
+        *databases.open.write().await = open;
+        Ok(databases)
+    }
+
+    async fn open_database(&self, name: &str) -> nextdb_query::Result<Database> {
+        let dir = self.dir.join(name);
+        // Made here, so that an ephemeral store deletes no more than its own
+        std::fs::create_dir_all(&dir)?;
+        let mut config = self.config.storage.clone();
+        config.data_dir = dir.join("data").to_string_lossy().to_string();
+        config.wal_dir = dir.join("wal").to_string_lossy().to_string();
+        let storage = Arc::new(LSMTree::open(config).await?);
+        storage.spawn_memtable_flusher();
+        let executor = server::open_executor(storage.clone(), &self.config).await?;
+        Ok(Database { storage, executor })
+    }
+
+    /// The named database `name`
+    pub(crate) async fn get(&self, name: &str) -> nextdb_query::Result<Arc<Database>> {
+        self.open.read().await.get(name).cloned().ok_or_else(|| QueryError::DatabaseNotFound(name.to_string()))
+    }
+
+    pub(crate) async fn create(&self, name: &str, if_not_exists: bool) -> nextdb_query::Result<ResultSet> {
+        if self.clustered {
+            return Err(QueryError::Invalid("named databases are not replicated, so a cluster has only the default one".to_string()));
+        }
+        check_name(name)?;
+        let mut open = self.open.write().await;
+        if name == DEFAULT_DATABASE || open.contains_key(name) {
+            if if_not_exists {
+                return Ok(ResultSet::new(Vec::new(), Vec::new()));
+            }
+            return Err(QueryError::DatabaseExists(name.to_string()));
+        }
+        let database = match self.open_database(name).await {
+            Ok(database) => database,
+            Err(e) => {
+                // Left behind, the directory would open as the database
+                let _ = std::fs::remove_dir_all(self.dir.join(name));
+                return Err(e);
+            }
+        };
+        open.insert(name.to_string(), Arc::new(database));
+        info!("Created database {}", name);
+        Ok(ResultSet::new(Vec::new(), Vec::new()))
+    }
+
+    /// Close the database and delete its data. Queries still running in it
+    /// fail.
+    pub(crate) async fn drop(&self, name: &str, if_exists: bool) -> nextdb_query::Result<ResultSet> {
+        if name == DEFAULT_DATABASE {
+            return Err(QueryError::Invalid("the default database cannot be dropped".to_string()));
+        }
+        let mut open = self.open.write().await;
+        let Some(database) = open.remove(name) else {
+            if if_exists {
+                return Ok(ResultSet::new(Vec::new(), Vec::new()));
+            }
+            return Err(QueryError::DatabaseNotFound(name.to_string()));
+        };
+        database.executor.end_all_sessions().await?;
+        database.storage.close().await?;
+        match std::fs::remove_dir_all(self.dir.join(name)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        info!("Dropped database {}", name);
+        Ok(ResultSet::new(Vec::new(), Vec::new()))
+    }
+
+    /// Roll back open transactions and close every named database
+    pub(crate) async fn close(&self) -> nextdb_query::Result<()> {
+        for database in self.open.read().await.values() {
+            database.executor.end_all_sessions().await?;
+            database.storage.close().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Names are lowercase letters, digits and underscores, as unquoted SQL
+/// identifiers come out, and name a directory safely
+fn check_name(name: &str) -> nextdb_query::Result<()> {
+    let valid = name.len() <= MAX_NAME_LEN
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid {
+        return Err(QueryError::Invalid(format!(
+            "database name {:?} must be at most {} lowercase letters, digits and underscores, not starting with a digit",
+            name, MAX_NAME_LEN
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{config, json_request, send_json};
+    use crate::{ApiToken, AuthConfig, DatabaseServer, Scope, ServerConfig};
+    use axum::body::Body;
+    use axum::http::{header, Method, StatusCode};
+    use axum::Router;
+    use serde_json::{json, Value};
+    use tempfile::TempDir;
+
+    async fn query(app: &Router, uri: &str, token: Option<&str>, sql: &str) -> (StatusCode, Value) {
+        let mut request = json_request(Method::POST, uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        send_json(app, request.body(Body::from(json!({ "sql": sql }).to_string())).unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn test_databases_are_isolated() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = DatabaseServer::with_config(config(&temp_dir)).await.unwrap();
+        let app = server.router();
+
+        for sql in ["CREATE DATABASE sales", "CREATE DATABASE support", "CREATE DATABASE IF NOT EXISTS sales"] {
+            let (status, body) = query(&app, "/api/query", None, sql).await;
+            assert_eq!(status, StatusCode::OK, "{}: {}", sql, body);
+        }
+        for (database, sql) in [
+            ("sales", "CREATE TABLE t (id INT PRIMARY KEY, amount INT)"),
+            ("sales", "INSERT INTO t VALUES (1, 100), (2, 200)"),
+            ("support", "CREATE TABLE t (id INT PRIMARY KEY, subject TEXT)"),
+            ("support", "INSERT INTO t VALUES (7, 'login')"),
+        ] {
+            let (status, body) = query(&app, &format!("/api/db/{}/query", database), None, sql).await;
+            assert_eq!(status, StatusCode::OK, "{}: {}", sql, body);
+        }
+
+        // Each database has its own catalog and rows, and the default one neither
+        let (_, body) = query(&app, "/api/db/sales/query", None, "SELECT * FROM t ORDER BY id").await;
+        assert_eq!(body["result"]["rows"], json!([[1, 100], [2, 200]]));
+        let (_, body) = query(&app, "/api/db/support/query", None, "SELECT * FROM t").await;
+        assert_eq!(body["result"]["rows"], json!([[7, "login"]]));
+        let (status, body) = query(&app, "/api/db/default/query", None, "SELECT * FROM t").await;
+        assert_eq!((status, body["error"]["code"].clone()), (StatusCode::NOT_FOUND, json!("table_not_found")));
+
+        for (uri, sql, status, code) in [
+            ("/api/db/missing/query", "SELECT 1", StatusCode::NOT_FOUND, "database_not_found"),
+            ("/api/db/sales/query", "SELECT * FROM support.t", StatusCode::BAD_REQUEST, "parse_error"),
+            ("/api/query", "CREATE DATABASE sales", StatusCode::CONFLICT, "database_exists"),
+            ("/api/query", "CREATE DATABASE \"Bad/Name\"", StatusCode::BAD_REQUEST, "invalid_query"),
+            ("/api/query", "DROP DATABASE default", StatusCode::BAD_REQUEST, "invalid_query"),
+        ] {
+            let (actual, body) = query(&app, uri, None, sql).await;
+            assert_eq!((actual, body["error"]["code"].as_str()), (status, Some(code)), "{}: {}", sql, body);
+        }
+
+        // Dropping one leaves the other as it was, through a restart too
+        let (status, body) = query(&app, "/api/db/support/query", None, "DROP DATABASE sales").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert!(!temp_dir.path().join("databases/sales").exists());
+        let (status, _) = query(&app, "/api/db/sales/query", None, "SELECT * FROM t").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        server.close().await.unwrap();
+
+        let app = DatabaseServer::with_config(config(&temp_dir)).await.unwrap().router();
+        let (_, body) = query(&app, "/api/db/support/query", None, "SELECT * FROM t").await;
+        assert_eq!(body["result"]["rows"], json!([[7, "login"]]));
+        let (status, _) = query(&app, "/api/db/sales/query", None, "SELECT 1").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_tokens_kept_to_databases() {
+        let temp_dir = TempDir::new().unwrap();
+        let token = |name: &str, scope, databases: Option<&[&str]>| ApiToken {
+            name: name.to_string(),
+            token: format!("{}-token", name),
+            scope,
+            databases: databases.map(|databases| databases.iter().map(|d| d.to_string()).collect()),
+        };
+        let tokens = vec![
+            token("root", Scope::Admin, None),
+            token("sales", Scope::ReadWrite, Some(&["sales"])),
+            token("sales_admin", Scope::Admin, Some(&["sales"])),
+        ];
+        let config = ServerConfig { auth: Some(AuthConfig { tokens }), ..config(&temp_dir) };
+        let app = DatabaseServer::with_config(config).await.unwrap().router();
+
+        for sql in ["CREATE DATABASE sales", "CREATE DATABASE support"] {
+            let (status, body) = query(&app, "/api/query", Some("root-token"), sql).await;
+            assert_eq!(status, StatusCode::OK, "{}", body);
+        }
+        let (status, body) = query(&app, "/api/db/sales/query", Some("sales-token"), "CREATE TABLE t (id INT)").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        for (uri, token, sql, code) in [
+            ("/api/db/support/query", "sales-token", "SELECT 1", "database_forbidden"),
+            ("/api/query", "sales-token", "SELECT 1", "database_forbidden"),
+            ("/api/db/sales/query", "sales-token", "DROP DATABASE sales", "admin_required"),
+            ("/api/db/sales/query", "sales_admin-token", "DROP DATABASE support", "database_forbidden"),
+        ] {
+            let (status, body) = query(&app, uri, Some(token), sql).await;
+            assert_eq!((status, body["error"]["code"].as_str()), (StatusCode::FORBIDDEN, Some(code)), "{}: {}", sql, body);
+        }
+        let (status, body) = query(&app, "/api/db/sales/query", Some("sales_admin-token"), "DROP DATABASE sales").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+}
@@ -13,6 +13,18 @@ pub enum ServerError {
     
     #[error("Configuration error: {0}")]
     Config(String),
+    
+    #[error("Storage error: {0}")]
+    Storage(#[from] nextdb_storage::StorageError),
+    
+    #[error("Query error: {0}")]
+    Query(#[from] nextdb_query::QueryError),
+    
+    #[error("Consensus error: {0}")]
+    Consensus(#[from] nextdb_consensus::ConsensusError),
+    
+    #[error("TLS error: {0}")]
+    Tls(#[from] crate::tls::TlsError),
 }
 
 pub type Result<T> = std::result::Result<T, ServerError>;
\ No newline at end of file
@@ -0,0 +1,331 @@
+//! Requests a follower cannot serve itself, in cluster mode.
+//!
+//! Only the leader writes. A follower sends `/api/query` and `/api/batch`
+//! requests with statements that write, and writes of keys (see `kv`), to
+//! the leader, without running them against its own copy, which may not
+//! have caught up with the schema they expect. So it does with any that fail with `not_leader`, as when it lost
+//! the lead meanwhile. It sends the client to the leader with 307 and a
+//! `Location` at the leader's client address, or, with
+//! `consensus.forward_writes` set, passes the request on to the leader over
+//! the Raft transport and answers with the leader's response. If no leader
+//! is known, the request runs here and fails with `not_leader`.
+//!
+//! Reads take `?consistency=local` (the default), `leader` or
+//! `linearizable`. A local read is served by the node it is sent to; from a
+//! follower, unless it reads linearizably anyway
+//! (`consensus.linearizable_reads`), the response carries a `Warning: 110`
+//! header as it may lag the leader. The others, sent to a follower, go to
+//! the leader like a write. A leader read is served from what the leader
+//! has applied, which a leader deposed without knowing it yet may not be up
+//! to date with. A linearizable read waits for a majority to confirm the
+//! node still leads and for its commits to apply first (ReadIndex).
+//!
+//! The leader authenticates and rate limits a forwarded request as its own,
+//! from the headers it came with; it sees no client address.
+
+use crate::server::{error_status, DatabaseState, ErrorBody};
+use axum::{
+    body::{self, Body},
+    extract::{Request, State},
+    http::{header, request::Parts, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use nextdb_consensus::Transport;
+use nextdb_query::{QueryError, SqlParser};
+use serde::{Deserialize, Serialize};
+use std::{sync::{Arc, RwLock}, time::Duration};
+use tower::ServiceExt;
+use tracing::{info, warn};
+
+/// How long a follower waits for the leader to answer a forwarded request,
+/// a little longer than the leader lets the write take
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(6);
+/// Headers that describe one connection, not the request
+const HOP_BY_HOP: &[&str] = &["connection", "host", "keep-alive", "content-length", "transfer-encoding", "te", "trailer", "upgrade"];
+const STALE_WARNING: &str = "110 - \"served by a follower, which may lag the leader\"";
+
+/// What a node knows of the leader of its cluster
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Leader {
+    /// Whether this node leads
+    pub(crate) is_self: bool,
+    /// Where clients reach the leader, if a leader and its address are known
+    pub(crate) api_address: Option<String>,
+    pub(crate) raft_address: Option<String>,
+}
+
+impl Leader {
+    /// Whether another node is known to lead
+    fn elsewhere(&self) -> bool {
+        !self.is_self && (self.api_address.is_some() || self.raft_address.is_some())
+    }
+}
+
+pub(crate) struct Leadership {
+    forward_writes: bool,
+    /// Whether local reads wait for the leader's commits, and are not stale
+    linearizable_reads: bool,
+    /// Scheme of the URLs clients are redirected to
+    scheme: &'static str,
+    /// Largest body passed on, that of the largest request a route accepts
+    max_body_bytes: usize,
+    /// Kept current by the Raft driver
+    leader: RwLock<Leader>,
+}
+
+impl Leadership {
+    pub(crate) fn new(forward_writes: bool, linearizable_reads: bool, tls: bool, max_body_bytes: usize) -> Self {
+        Self {
+            forward_writes,
+            linearizable_reads,
+            scheme: if tls { "https" } else { "http" },
+            max_body_bytes,
+            leader: RwLock::new(Leader::default()),
+        }
+    }
+
+    pub(crate) fn leader(&self) -> Leader {
+        self.leader.read().unwrap().clone()
+    }
+
+    /// Record what Raft says of the leader now
+    pub(crate) fn observe(&self, leader: Leader) {
+        let mut current = self.leader.write().unwrap();
+        if *current != leader {
+            match (&leader.is_self, &leader.api_address) {
+                (true, _) => info!("This node leads the cluster"),
+                (false, Some(address)) => info!("Following the leader at {}", address),
+                (false, None) => info!("No leader is known"),
+            }
+            *current = leader;
+        }
+    }
+
+    /// Send the refused request to the leader: pass it on, or redirect the
+    /// client. `refused` is the local answer, if the request ran.
+    async fn to_leader(&self, parts: &Parts, body: &[u8], refused: Option<Response>) -> Response {
+        let leader = self.leader();
+        let unavailable = || refused.unwrap_or_else(no_leader);
+        if self.forward_writes {
+            let Some(address) = leader.raft_address.filter(|_| !leader.is_self) else {
+                return unavailable();
+            };
+            return match forward(&address, parts, body).await {
+                Ok(response) => response,
+                Err(message) => {
+                    warn!("Cannot forward a request to the leader at {}: {}", address, message);
+                    let error = ErrorBody { code: "leader_unreachable", message };
+                    (StatusCode::BAD_GATEWAY, Json(serde_json::json!({ "success": false, "error": error }))).into_response()
+                }
+            };
+        }
+        let Some(address) = leader.api_address.filter(|_| !leader.is_self) else {
+            return unavailable();
+        };
+        let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+        let location = format!("{}://{}{}", self.scheme, address, path);
+        let Ok(location) = HeaderValue::from_str(&location) else {
+            return unavailable();
+        };
+        let body = serde_json::json!({
+            "success": false,
+            "error": ErrorBody { code: "not_leader", message: format!("Not the leader; the leader is at {}", address) },
+            "leader": address,
+        });
+        (StatusCode::TEMPORARY_REDIRECT, [(header::LOCATION, location)], Json(body)).into_response()
+    }
+}
+
+fn no_leader() -> Response {
+    let error = ErrorBody { code: "not_leader", message: "Not the leader, and no leader is known".to_string() };
+    let body = serde_json::json!({ "success": false, "retryable": true, "error": error });
+    (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response()
+}
+
+/// Marks a response refused because this node does not lead
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct NotLeader;
+
+/// Marks a request a follower passed on, so it is not passed on again
+#[derive(Debug, Clone, Copy)]
+struct Forwarded;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Consistency {
+    Local,
+    /// Served by the leader, from what it has applied
+    Leader,
+    Linearizable,
+}
+
+impl Consistency {
+    fn of(parts: &Parts) -> Result<Self, String> {
+        let requested = parts.uri.query().unwrap_or_default().split('&')
+            .find_map(|pair| pair.strip_prefix("consistency="));
+        match requested {
+            None | Some("local") => Ok(Consistency::Local),
+            Some("leader") => Ok(Consistency::Leader),
+            Some("linearizable") => Ok(Consistency::Linearizable),
+            Some(other) => Err(format!("consistency must be local, leader or linearizable, not {}", other)),
+        }
+    }
+}
+
+/// Middleware for the routes that write: sends requests this node cannot
+/// serve to the leader
+pub(crate) async fn follow_leader(State(state): State<Arc<DatabaseState>>, request: Request, next: Next) -> Response {
+    let (parts, body) = request.into_parts();
+    let consistency = match Consistency::of(&parts) {
+        Ok(consistency) => consistency,
+        Err(message) => {
+            let error = ErrorBody { code: "invalid_request", message };
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "success": false, "error": error }))).into_response();
+        }
+    };
+    let Some(leadership) = state.leadership.as_ref().filter(|_| parts.extensions.get::<Forwarded>().is_none()) else {
+        if let Err(refused) = confirm(&state, consistency).await {
+            return refused;
+        }
+        return next.run(Request::from_parts(parts, body)).await;
+    };
+    let body = match body::to_bytes(body, leadership.max_body_bytes).await {
+        Ok(body) => body,
+        Err(e) => {
+            let error = ErrorBody { code: "invalid_request", message: e.to_string() };
+            return (StatusCode::PAYLOAD_TOO_LARGE, Json(serde_json::json!({ "success": false, "error": error }))).into_response();
+        }
+    };
+
+    let leader = leadership.leader();
+    if (consistency != Consistency::Local && !leader.is_self) || (leader.elsewhere() && writes(&parts, &body)) {
+        return leadership.to_leader(&parts, &body, None).await;
+    }
+    if let Err(refused) = confirm(&state, consistency).await {
+        return match refused.extensions().get::<NotLeader>() {
+            Some(_) => leadership.to_leader(&parts, &body, Some(refused)).await,
+            None => refused,
+        };
+    }
+    let mut response = next.run(Request::from_parts(parts.clone(), Body::from(body.clone()))).await;
+    if response.extensions().get::<NotLeader>().is_some() {
+        return leadership.to_leader(&parts, &body, Some(response)).await;
+    }
+    if !leader.is_self && !leadership.linearizable_reads && response.status().is_success() {
+        response.headers_mut().insert(header::WARNING, HeaderValue::from_static(STALE_WARNING));
+    }
+    response
+}
+
+/// For a linearizable request, wait until this node has confirmed it still
+/// leads and applied every write committed before the request
+async fn confirm(state: &DatabaseState, consistency: Consistency) -> Result<(), Response> {
+    if consistency != Consistency::Linearizable {
+        return Ok(());
+    }
+    state.executor.confirm_reads().await.map_err(|e| {
+        let (status, code) = error_status(&e);
+        let error = ErrorBody { code, message: e.to_string() };
+        let mut response = (status, Json(serde_json::json!({ "success": false, "error": error }))).into_response();
+        if matches!(e, QueryError::NotLeader { .. }) {
+            response.extensions_mut().insert(NotLeader);
+        }
+        response
+    })
+}
+
+/// SQL of a `/api/query` or `/api/batch` body
+#[derive(Deserialize)]
+struct Statements {
+    sql: Option<String>,
+    #[serde(default)]
+    statements: Vec<String>,
+}
+
+/// Whether the request writes: a `PUT` or `DELETE` of a key (see `kv`), or
+/// any statement that writes. Ones that do not parse fail wherever they run.
+fn writes(parts: &Parts, body: &[u8]) -> bool {
+    if parts.method == Method::PUT || parts.method == Method::DELETE {
+        return true;
+    }
+    let Ok(request) = serde_json::from_slice::<Statements>(body) else {
+        return false;
+    };
+    request.sql.iter().chain(&request.statements)
+        .filter_map(|sql| SqlParser::parse(sql).ok())
+        .any(|statement| !statement.is_read_only())
+}
+
+#[derive(Serialize, Deserialize)]
+struct ForwardedRequest {
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ForwardedResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+fn end_to_end(headers: &axum::http::HeaderMap) -> Vec<(String, String)> {
+    headers.iter()
+        .filter(|(name, _)| !HOP_BY_HOP.contains(&name.as_str()))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+/// Pass the request on to the leader at its Raft `address`
+async fn forward(address: &str, parts: &Parts, body: &[u8]) -> Result<Response, String> {
+    let request = ForwardedRequest {
+        method: parts.method.to_string(),
+        uri: parts.uri.path_and_query().map_or("/", |path| path.as_str()).to_string(),
+        headers: end_to_end(&parts.headers),
+        body: String::from_utf8(body.to_vec()).map_err(|_| "the request body is not UTF-8".to_string())?,
+    };
+    let request = serde_json::to_string(&request).map_err(|e| e.to_string())?;
+    let response = tokio::time::timeout(FORWARD_TIMEOUT, Transport::forward(address, request)).await
+        .map_err(|_| format!("no answer within {}ms", FORWARD_TIMEOUT.as_millis()))?
+        .map_err(|e| e.to_string())?;
+    let response: ForwardedResponse = serde_json::from_str(&response).map_err(|e| e.to_string())?;
+
+    let mut builder = Response::builder().status(response.status);
+    for (name, value) in &response.headers {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+    builder.body(Body::from(response.body)).map_err(|e| e.to_string())
+}
+
+/// Answer a request a follower passed on, as `router` would have
+pub(crate) async fn serve_forwarded(router: Router, request: String) -> String {
+    let response = match build_request(&request) {
+        Ok(request) => router.oneshot(request).await.into_response(),
+        Err(message) => {
+            let error = ErrorBody { code: "invalid_request", message };
+            (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "success": false, "error": error }))).into_response()
+        }
+    };
+    let (parts, body) = response.into_parts();
+    let body = body::to_bytes(body, usize::MAX).await.unwrap_or_default();
+    let response = ForwardedResponse {
+        status: parts.status.as_u16(),
+        headers: end_to_end(&parts.headers),
+        body: String::from_utf8_lossy(&body).into_owned(),
+    };
+    serde_json::to_string(&response).unwrap_or_default()
+}
+
+fn build_request(request: &str) -> Result<Request, String> {
+    let request: ForwardedRequest = serde_json::from_str(request).map_err(|e| e.to_string())?;
+    let method = Method::from_bytes(request.method.as_bytes()).map_err(|e| e.to_string())?;
+    let mut builder = Request::builder().method(method).uri(request.uri.as_str()).extension(Forwarded);
+    for (name, value) in &request.headers {
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| e.to_string())?;
+        builder = builder.header(name, value.as_str());
+    }
+    builder.body(Body::from(request.body)).map_err(|e| e.to_string())
+}
@@ -0,0 +1,212 @@
+//! Liveness and readiness probes.
+//!
+//! `/health` answers as long as the process runs and its runtime still
+//! schedules tasks. `/ready` checks that the node can serve queries: storage
+//! answers a read, the WAL directory takes writes, the Raft node, if any,
+//! knows a leader, and the catalog is loaded. A readiness report is reused
+//! for `readiness_cache_ms`, so frequent probes add no load.
+
+use crate::server::DatabaseState;
+use axum::{extract::State, http::StatusCode, Json};
+use nextdb_consensus::RaftNode;
+use nextdb_storage::Durability;
+use serde::Serialize;
+use std::{collections::BTreeMap, path::Path, sync::Arc, time::{Duration, Instant}};
+use tokio::{sync::{Mutex, RwLock}, time::timeout};
+
+/// How long each check may take before it counts as failed
+const CHECK_DEADLINE: Duration = Duration::from_secs(1);
+
+/// Key the storage check reads
+const PROBE_KEY: &[u8] = b"\0ready-probe";
+
+/// File the WAL check creates and removes in the WAL directory
+const PROBE_FILE: &str = ".ready-probe";
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Check {
+    ok: bool,
+    detail: String,
+}
+
+impl Check {
+    fn pass(detail: impl Into<String>) -> Self {
+        Self { ok: true, detail: detail.into() }
+    }
+
+    fn fail(detail: impl Into<String>) -> Self {
+        Self { ok: false, detail: detail.into() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Report {
+    /// "ready", or "not_ready" if any check failed
+    status: &'static str,
+    /// Names of the failed checks
+    failed: Vec<&'static str>,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+pub(crate) struct Readiness {
+    state: Arc<DatabaseState>,
+    raft: Option<Arc<RwLock<RaftNode>>>,
+    cache_for: Duration,
+    // The last report and when it was made. Held while checks run, so
+    // concurrent probes wait for one round of checks.
+    last: Mutex<Option<(Instant, Arc<Report>)>>,
+}
+
+impl Readiness {
+    pub(crate) fn new(state: Arc<DatabaseState>, raft: Option<Arc<RwLock<RaftNode>>>, cache_for: Duration) -> Self {
+        Self { state, raft, cache_for, last: Mutex::new(None) }
+    }
+
+    /// The last report if it is recent enough, otherwise a new one
+    async fn report(&self) -> Arc<Report> {
+        let mut last = self.last.lock().await;
+        if let Some((at, report)) = &*last {
+            if at.elapsed() < self.cache_for {
+                return report.clone();
+            }
+        }
+        let report = Arc::new(self.check().await);
+        *last = Some((Instant::now(), report.clone()));
+        report
+    }
+
+    async fn check(&self) -> Report {
+        let mut checks = BTreeMap::new();
+        checks.insert("storage", self.check_storage().await);
+        checks.insert("wal", self.check_wal().await);
+        if let Some(raft) = &self.raft {
+            checks.insert("consensus", check_consensus(raft).await);
+        }
+        let tables = self.state.executor.catalog().tables().len();
+        checks.insert("catalog", Check::pass(format!("{} tables", tables)));
+
+        let failed: Vec<_> = checks.iter().filter(|(_, check)| !check.ok).map(|(name, _)| *name).collect();
+        let status = if failed.is_empty() { "ready" } else { "not_ready" };
+        Report { status, failed, checks }
+    }
+
+    async fn check_storage(&self) -> Check {
+        let started = Instant::now();
+        match timeout(CHECK_DEADLINE, self.state.storage.get(PROBE_KEY)).await {
+            Ok(Ok(_)) => Check::pass(format!("read in {:?}", started.elapsed())),
+            Ok(Err(e)) => Check::fail(format!("read failed: {}", e)),
+            Err(_) => Check::fail(format!("read took over {:?}", CHECK_DEADLINE)),
+        }
+    }
+
+    async fn check_wal(&self) -> Check {
+        let config = self.state.storage.config();
+        if config.durability == Durability::Memory {
+            return Check::pass("kept in memory");
+        }
+        let probe = Path::new(&config.wal_dir).join(PROBE_FILE);
+        let write = async {
+            tokio::fs::write(&probe, b"").await?;
+            tokio::fs::remove_file(&probe).await
+        };
+        match timeout(CHECK_DEADLINE, write).await {
+            Ok(Ok(())) => Check::pass(format!("{} is writable", config.wal_dir)),
+            Ok(Err(e)) => Check::fail(format!("cannot write to {}: {}", config.wal_dir, e)),
+            Err(_) => Check::fail(format!("writing to {} took over {:?}", config.wal_dir, CHECK_DEADLINE)),
+        }
+    }
+}
+
+async fn check_consensus(raft: &RwLock<RaftNode>) -> Check {
+    let Ok(node) = timeout(CHECK_DEADLINE, raft.read()).await else {
+        return Check::fail(format!("Raft node busy for over {:?}", CHECK_DEADLINE));
+    };
+    if node.is_stopped() {
+        return Check::fail("Raft node stopped");
+    }
+    match node.leader() {
+        Some(_) if node.is_leader() => Check::pass(format!("leading term {}", node.current_term())),
+        Some(leader) => Check::pass(format!("following {} in term {}", leader.0, node.current_term())),
+        None => Check::fail(format!("no known leader in term {}", node.current_term())),
+    }
+}
+
+/// Liveness: fails only if the runtime cannot run a new task in time
+pub(crate) async fn health() -> (StatusCode, Json<serde_json::Value>) {
+    match timeout(CHECK_DEADLINE, tokio::spawn(async {})).await {
+        Ok(Ok(())) => (StatusCode::OK, Json(serde_json::json!({ "status": "ok" }))),
+        _ => (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({ "status": "unresponsive" }))),
+    }
+}
+
+/// Readiness: 200 if every check passed, otherwise 503
+pub(crate) async fn ready(State(readiness): State<Arc<Readiness>>) -> (StatusCode, Json<Report>) {
+    let report = readiness.report().await;
+    let status = if report.failed.is_empty() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(report.as_ref().clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{self, get};
+    use crate::{DatabaseServer, ServerConfig};
+    use axum::http::StatusCode;
+    use nextdb_consensus::{NodeId, RaftConfig, RaftNode};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    async fn server(temp_dir: &TempDir, readiness_cache_ms: u64) -> DatabaseServer {
+        let config = ServerConfig {
+            readiness_cache_ms,
+            ..test_util::config(temp_dir)
+        };
+        DatabaseServer::with_config(config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_readiness_checks() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = server(&temp_dir, 0).await;
+        let app = server.router();
+
+        assert_eq!(get(&app, "/health").await.0, StatusCode::OK);
+        let (status, body) = get(&app, "/ready").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["status"], "ready");
+        let checks: Vec<_> = body["checks"].as_object().unwrap().keys().cloned().collect();
+        assert_eq!(checks, ["catalog", "storage", "wal"]);
+
+        // A Raft node that knows of no leader is not ready until it hears
+        // from one
+        let peer = NodeId::new();
+        let config = RaftConfig { peers: vec![peer], ..RaftConfig::default() };
+        let raft = Arc::new(tokio::sync::RwLock::new(RaftNode::new(config)));
+        let app = server.with_raft_node(raft.clone()).router();
+        let (status, body) = get(&app, "/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", body);
+        assert_eq!(body["failed"], serde_json::json!(["consensus"]));
+        assert_eq!(body["checks"]["consensus"]["ok"], false);
+        raft.write().await.observe_leader(1, peer);
+        assert_eq!(get(&app, "/ready").await.0, StatusCode::OK);
+
+        // Nor is a node whose WAL directory cannot be written
+        std::fs::remove_dir_all(temp_dir.path().join("wal")).unwrap();
+        let (status, body) = get(&app, "/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", body);
+        assert_eq!(body["failed"], serde_json::json!(["wal"]));
+        assert!(body["checks"]["wal"]["detail"].as_str().unwrap().contains("cannot write"));
+        assert_eq!(get(&app, "/health").await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_readiness_cached() {
+        let temp_dir = TempDir::new().unwrap();
+        let app = server(&temp_dir, 60_000).await.router();
+
+        assert_eq!(get(&app, "/ready").await.0, StatusCode::OK);
+        std::fs::remove_dir_all(temp_dir.path().join("wal")).unwrap();
+        let (status, body) = get(&app, "/ready").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert!(!temp_dir.path().join("wal").exists());
+    }
+}
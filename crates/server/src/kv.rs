@@ -0,0 +1,353 @@
+//! Raw keys and values over the HTTP API, without SQL.
+//!
+//! - `GET /api/kv/{key}` answers `{"value": "..."}`, or 404 with the code
+//!   `key_not_found`.
+//! - `PUT /api/kv/{key}` with `{"value": "..."}` stores the value.
+//! - `DELETE /api/kv/{key}` removes the key, whether or not it was there.
+//! - `GET /api/kv?prefix=...&after=...&limit=...` answers the entries whose
+//!   keys start with `prefix` and come after `after`, in key order, as
+//!   `{"entries": [{"key": "...", "value": "..."}], "next": "..."}`. At most
+//!   `limit`, and never more than `MAX_KV_PAGE`, are returned at once;
+//!   `next` is the `after` to ask with for the rest, and null at the end.
+//! - `POST /api/kv/multi-get` with `{"keys": [...]}` answers
+//!   `{"values": [...]}` in the same order, null for a missing key.
+//!
+//! Keys in the path and the query string are base64url without padding,
+//! and keys and values in JSON bodies are standard base64. The keys are
+//! those of the TCP protocol's `GET`, `PUT` and `DELETE` (see `protocol`):
+//! ones in the SQL namespace fail with `invalid_key`, and scans skip them.
+//! Writes go through the replicated log, and to the leader in cluster mode,
+//! like those of SQL statements; reads take `?consistency=` (see `forward`).
+
+use crate::{
+    auth::Scope,
+    forward::NotLeader,
+    rate_limit::{Client, Limited},
+    server::{error_status, DatabaseState, ErrorBody},
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use base64::{
+    engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL},
+    Engine,
+};
+use nextdb_query::{encoding, QueryError};
+use nextdb_storage::WriteOp;
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::OwnedSemaphorePermit;
+
+/// Most entries a scan returns, and keys a multi-get reads, at once
+const MAX_KV_PAGE: usize = 1000;
+/// The SQL namespace is the keys from the first of these up to the second
+const SQL_NAMESPACE: &[u8] = &[0x01];
+const AFTER_SQL_NAMESPACE: &[u8] = &[0x02];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PutRequest {
+    value: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ScanParams {
+    #[serde(default)]
+    prefix: String,
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MultiGetRequest {
+    keys: Vec<String>,
+}
+
+fn failure(status: StatusCode, code: &'static str, message: String) -> Response {
+    (status, Json(serde_json::json!({ "success": false, "error": ErrorBody { code, message } }))).into_response()
+}
+
+fn query_failure(error: QueryError) -> Response {
+    let (status, code) = error_status(&error);
+    let mut response = failure(status, code, error.to_string());
+    // A follower refuses writes; see `forward`
+    if matches!(error, QueryError::NotLeader { .. }) {
+        response.extensions_mut().insert(NotLeader);
+    }
+    response
+}
+
+fn invalid_key(message: String) -> Response {
+    failure(StatusCode::BAD_REQUEST, "invalid_key", message)
+}
+
+fn read_only() -> Response {
+    let message = "the API token is read-only and the request writes".to_string();
+    failure(StatusCode::FORBIDDEN, "read_only_token", message)
+}
+
+/// A raw key from `encoded`, or why it is not one
+fn decode_key(encoded: &str, url: bool) -> Result<Vec<u8>, String> {
+    let engine = if url { &BASE64_URL } else { &BASE64 };
+    let key = engine.decode(encoded).map_err(|e| format!("key {:?} is not base64: {}", encoded, e))?;
+    if encoding::is_sql_key(&key) {
+        return Err("key is in the SQL namespace".to_string());
+    }
+    Ok(key)
+}
+
+fn admit(state: &DatabaseState, client: Option<Extension<Client>>, writes: u32) -> Result<Option<OwnedSemaphorePermit>, Limited> {
+    let client = client.map_or(Client::Unknown, |Extension(client)| client);
+    let admitted = state.rate_limiter.as_ref().map(|limiter| limiter.admit_batch(&client, writes));
+    admitted.transpose()
+}
+
+/// Handler for `GET /api/kv/{key}`
+pub(crate) async fn get(
+    State(state): State<Arc<DatabaseState>>,
+    client: Option<Extension<Client>>,
+    Path(key): Path<String>,
+) -> Response {
+    let key = match decode_key(&key, true) {
+        Ok(key) => key,
+        Err(message) => return invalid_key(message),
+    };
+    let _permit = match admit(&state, client, 0) {
+        Ok(permit) => permit,
+        Err(limited) => return limited.into_response(),
+    };
+    match state.storage.get(&key).await {
+        Ok(Some(value)) => Json(serde_json::json!({ "value": BASE64.encode(value) })).into_response(),
+        Ok(None) => failure(StatusCode::NOT_FOUND, "key_not_found", "no such key".to_string()),
+        Err(e) => query_failure(e.into()),
+    }
+}
+
+/// Handler for `PUT /api/kv/{key}`
+pub(crate) async fn put(
+    State(state): State<Arc<DatabaseState>>,
+    Extension(scope): Extension<Scope>,
+    client: Option<Extension<Client>>,
+    Path(key): Path<String>,
+    Json(req): Json<PutRequest>,
+) -> Response {
+    if scope == Scope::ReadOnly {
+        return read_only();
+    }
+    let key = match decode_key(&key, true) {
+        Ok(key) => key,
+        Err(message) => return invalid_key(message),
+    };
+    let value = match BASE64.decode(&req.value) {
+        Ok(value) => value,
+        Err(e) => return failure(StatusCode::BAD_REQUEST, "invalid_request", format!("the value is not base64: {}", e)),
+    };
+    write(&state, client, WriteOp::Put { key, value }).await
+}
+
+/// Handler for `DELETE /api/kv/{key}`
+pub(crate) async fn delete(
+    State(state): State<Arc<DatabaseState>>,
+    Extension(scope): Extension<Scope>,
+    client: Option<Extension<Client>>,
+    Path(key): Path<String>,
+) -> Response {
+    if scope == Scope::ReadOnly {
+        return read_only();
+    }
+    let key = match decode_key(&key, true) {
+        Ok(key) => key,
+        Err(message) => return invalid_key(message),
+    };
+    write(&state, client, WriteOp::Delete { key }).await
+}
+
+async fn write(state: &DatabaseState, client: Option<Extension<Client>>, op: WriteOp) -> Response {
+    let _permit = match admit(state, client, 1) {
+        Ok(permit) => permit,
+        Err(limited) => return limited.into_response(),
+    };
+    match state.executor.write(vec![op]).await {
+        Ok(()) => Json(serde_json::json!({ "success": true })).into_response(),
+        Err(e) => query_failure(e),
+    }
+}
+
+/// Handler for `GET /api/kv`
+pub(crate) async fn scan(
+    State(state): State<Arc<DatabaseState>>,
+    client: Option<Extension<Client>>,
+    Query(params): Query<ScanParams>,
+) -> Response {
+    let prefix = match BASE64_URL.decode(&params.prefix) {
+        Ok(prefix) => prefix,
+        Err(e) => return invalid_key(format!("the prefix is not base64: {}", e)),
+    };
+    let start = match params.after.as_deref().map(|after| decode_key(after, true)).transpose() {
+        // Just past the last key returned
+        Ok(Some(mut after)) => {
+            after.push(0);
+            after.max(prefix.clone())
+        }
+        Ok(None) => prefix.clone(),
+        Err(message) => return invalid_key(message),
+    };
+    let end = match prefix.is_empty() {
+        // Past any key a write accepts
+        true => vec![u8::MAX; state.storage.config().max_key_size_bytes + 1],
+        false => encoding::prefix_end(&prefix),
+    };
+    let limit = params.limit.unwrap_or(MAX_KV_PAGE).clamp(1, MAX_KV_PAGE);
+    let _permit = match admit(&state, client, 0) {
+        Ok(permit) => permit,
+        Err(limited) => return limited.into_response(),
+    };
+
+    // The raw keys in range are those before the SQL namespace, then those
+    // after it. One more entry than asked for tells whether there are more.
+    let ranges = [
+        (start.clone(), end.clone().min(SQL_NAMESPACE.to_vec())),
+        (start.max(AFTER_SQL_NAMESPACE.to_vec()), end),
+    ];
+    let mut entries = Vec::new();
+    for (start, end) in ranges {
+        if start >= end || entries.len() > limit {
+            continue;
+        }
+        match state.storage.scan(&start, &end, limit + 1 - entries.len()).await {
+            Ok(found) => entries.extend(found),
+            Err(e) => return query_failure(e.into()),
+        }
+    }
+    let next = (entries.len() > limit).then(|| {
+        entries.truncate(limit);
+        BASE64_URL.encode(&entries[limit - 1].0)
+    });
+    let entries: Vec<_> = entries.iter()
+        .map(|(key, value)| serde_json::json!({ "key": BASE64.encode(key), "value": BASE64.encode(value) }))
+        .collect();
+    Json(serde_json::json!({ "entries": entries, "next": next })).into_response()
+}
+
+/// Handler for `POST /api/kv/multi-get`
+pub(crate) async fn multi_get(
+    State(state): State<Arc<DatabaseState>>,
+    client: Option<Extension<Client>>,
+    Json(req): Json<MultiGetRequest>,
+) -> Response {
+    if req.keys.len() > MAX_KV_PAGE {
+        let message = format!("a multi-get reads at most {} keys", MAX_KV_PAGE);
+        return failure(StatusCode::PAYLOAD_TOO_LARGE, "batch_too_large", message);
+    }
+    let keys = match req.keys.iter().map(|key| decode_key(key, false)).collect::<Result<Vec<_>, _>>() {
+        Ok(keys) => keys,
+        Err(message) => return invalid_key(message),
+    };
+    let _permit = match admit(&state, client, 0) {
+        Ok(permit) => permit,
+        Err(limited) => return limited.into_response(),
+    };
+    match state.storage.multi_get(&keys).await {
+        Ok(values) => {
+            let values: Vec<_> = values.into_iter().map(|value| value.map(|value| BASE64.encode(value))).collect();
+            Json(serde_json::json!({ "values": values })).into_response()
+        }
+        Err(e) => query_failure(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, json_request, send_json};
+    use crate::DatabaseServer;
+    use axum::{body::Body, http::Method, Router};
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    async fn app(temp_dir: &TempDir) -> Router {
+        DatabaseServer::with_config(test_util::config(temp_dir)).await.unwrap().router()
+    }
+
+    async fn send(app: &Router, method: Method, uri: &str, body: Option<serde_json::Value>) -> (StatusCode, serde_json::Value) {
+        let body = body.map_or(Body::empty(), |body| Body::from(body.to_string()));
+        send_json(app, json_request(method, uri).body(body).unwrap()).await
+    }
+
+    fn path(key: &[u8]) -> String {
+        format!("/api/kv/{}", BASE64_URL.encode(key))
+    }
+
+    async fn put(app: &Router, key: &[u8], value: &[u8]) {
+        let (status, body) = send(app, Method::PUT, &path(key), Some(json!({ "value": BASE64.encode(value) }))).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_keys_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let app = app(&temp_dir).await;
+        let (key, value) = (b"\x00k\xff/?", b"\x00\xc3\x28\xff");
+        put(&app, key, value).await;
+        let (status, body) = send(&app, Method::GET, &path(key), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(BASE64.decode(body["value"].as_str().unwrap()).unwrap(), value);
+
+        let (status, body) = send(&app, Method::POST, "/api/kv/multi-get", Some(json!({ "keys": [BASE64.encode(key), "bWlzc2luZw=="] }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["values"], json!([BASE64.encode(value), null]));
+
+        let (status, _) = send(&app, Method::DELETE, &path(key), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = send(&app, Method::GET, &path(key), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "key_not_found");
+
+        // SQL data cannot be read or written as raw keys
+        let (status, body) = send(&app, Method::GET, &path(&encoding::catalog_key("t")), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "invalid_key");
+        let (status, _) = send(&app, Method::PUT, &path(b"\x01x"), Some(json!({ "value": "" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_scan_pages_around_sql_data() {
+        let temp_dir = TempDir::new().unwrap();
+        let app = app(&temp_dir).await;
+        let (status, _) = send(&app, Method::POST, "/api/query", Some(json!({ "sql": "CREATE TABLE t (id INTEGER PRIMARY KEY)" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        let keys: Vec<&[u8]> = vec![b"\x00", b"\x00\x01", b"\x02", b"a", b"a\x00", b"a\xff", b"b", b"\xff\xff"];
+        for key in &keys {
+            put(&app, key, b"v").await;
+        }
+
+        // Every raw key, two at a time
+        let (mut scanned, mut after) = (Vec::new(), None::<String>);
+        loop {
+            let uri = match &after {
+                Some(after) => format!("/api/kv?limit=2&after={}", after),
+                None => "/api/kv?limit=2".to_string(),
+            };
+            let (status, body) = send(&app, Method::GET, &uri, None).await;
+            assert_eq!(status, StatusCode::OK, "{}", body);
+            let entries = body["entries"].as_array().unwrap();
+            assert!(entries.len() <= 2);
+            scanned.extend(entries.iter().map(|entry| BASE64.decode(entry["key"].as_str().unwrap()).unwrap()));
+            match body["next"].as_str() {
+                Some(next) => after = Some(next.to_string()),
+                None => break,
+            }
+        }
+        assert_eq!(scanned, keys);
+
+        let (_, body) = send(&app, Method::GET, &format!("/api/kv?prefix={}", BASE64_URL.encode("a")), None).await;
+        let found: Vec<_> = body["entries"].as_array().unwrap().iter().map(|entry| entry["key"].as_str().unwrap()).collect();
+        assert_eq!(found, [BASE64.encode("a"), BASE64.encode("a\x00"), BASE64.encode(b"a\xff")]);
+        assert!(body["next"].is_null());
+    }
+}
@@ -1,7 +1,39 @@
 pub mod server;
+mod admin;
+pub mod auth;
+mod batch;
+mod cluster;
+pub mod cors;
+mod cursor;
+mod dashboard;
+mod databases;
+mod health;
+mod forward;
+mod kv;
+mod prepared;
+mod reload;
+pub mod load_shed;
+pub mod tls;
+mod txn;
 pub mod config;
 pub mod error;
+pub mod metrics;
+pub mod protocol;
+pub mod rate_limit;
+mod replication;
+pub mod request_log;
+mod watch;
+mod websocket;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(test)]
+mod test_util;
 
-pub use server::DatabaseServer;
-pub use config::ServerConfig;
+pub use server::{DatabaseServer, Shutdown};
+pub use config::{Config, ServerConfig};
+pub use auth::{ApiToken, AuthConfig, Scope};
+pub use tls::{ClientIdentity, TlsConfig};
+pub use rate_limit::RateLimitConfig;
+pub use load_shed::LoadSheddingConfig;
+pub use cors::CorsConfig;
 pub use error::{ServerError, Result};
\ No newline at end of file
@@ -0,0 +1,285 @@
+//! Load shedding for the HTTP API.
+//!
+//! With a `[server.load_shedding]` section, at most `max_concurrent_statements`
+//! requests that run SQL (`/api/query`, `/api/query/next`, `/api/batch`,
+//! `/api/execute` and `/api/txn/{id}/query`) are handled at once, and at most
+//! `max_concurrent_requests` of the other `/api/*` requests. Requests over
+//! a limit wait in a queue of bounded length for up to `max_queue_ms`. A
+//! request finding its queue full, or still queued at the deadline, gets a
+//! 503 with `retryable: true` straight away, so an overloaded server
+//! answers quickly instead of letting latency grow without bound.
+//! `/api/status` is never queued, so the server can always be watched.
+
+use crate::server::DatabaseState;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoadSheddingConfig {
+    /// Requests running SQL handled at once
+    pub max_concurrent_statements: usize,
+    /// Requests running SQL waiting for a slot before more are shed
+    pub max_queued_statements: usize,
+    /// Other API requests handled at once
+    pub max_concurrent_requests: usize,
+    /// Other API requests waiting for a slot before more are shed
+    pub max_queued_requests: usize,
+    /// How long a request may wait for a slot before it is shed
+    pub max_queue_ms: u64,
+}
+
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_statements: 64,
+            max_queued_statements: 256,
+            max_concurrent_requests: 256,
+            max_queued_requests: 1024,
+            max_queue_ms: 1000,
+        }
+    }
+}
+
+/// Why a request was shed
+#[derive(Debug)]
+pub(crate) struct Shed {
+    pub(crate) code: &'static str,
+    pub(crate) message: &'static str,
+}
+
+impl IntoResponse for Shed {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({
+            "success": false,
+            "retryable": true,
+            "error": { "code": self.code, "message": self.message },
+        });
+        (StatusCode::SERVICE_UNAVAILABLE, [(header::RETRY_AFTER, "1")], Json(body)).into_response()
+    }
+}
+
+/// Slots for one kind of request and the queue in front of them
+struct Pool {
+    slots: Arc<Semaphore>,
+    max_concurrent: usize,
+    max_queued: usize,
+    queued: AtomicUsize,
+    /// Most requests ever handled at once
+    #[cfg(test)]
+    peak: AtomicUsize,
+}
+
+impl Pool {
+    fn new(max_concurrent: usize, max_queued: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            max_queued,
+            queued: AtomicUsize::new(0),
+            #[cfg(test)]
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    /// A slot, taken now or after waiting up to `max_queue` in the queue
+    async fn admit(&self, max_queue: Duration) -> Result<OwnedSemaphorePermit, Shed> {
+        let permit = match self.slots.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if self.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+                    self.queued.fetch_sub(1, Ordering::SeqCst);
+                    return Err(Shed { code: "queue_full", message: "the server is overloaded and its queue is full" });
+                }
+                let waited = tokio::time::timeout(max_queue, self.slots.clone().acquire_owned()).await;
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                match waited {
+                    Ok(permit) => permit.expect("the semaphore is never closed"),
+                    Err(_) => {
+                        return Err(Shed { code: "queue_timeout", message: "the server is overloaded and the request waited too long" });
+                    }
+                }
+            }
+        };
+        #[cfg(test)]
+        self.peak.fetch_max(self.in_flight(), Ordering::SeqCst);
+        Ok(permit)
+    }
+
+    fn in_flight(&self) -> usize {
+        self.max_concurrent - self.slots.available_permits()
+    }
+
+    fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+}
+
+/// Requests in flight and queued, by kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Load {
+    pub(crate) statements_in_flight: usize,
+    pub(crate) statements_queued: usize,
+    pub(crate) requests_in_flight: usize,
+    pub(crate) requests_queued: usize,
+}
+
+pub(crate) struct LoadShedder {
+    statements: Pool,
+    requests: Pool,
+    max_queue: Duration,
+}
+
+impl LoadShedder {
+    pub(crate) fn new(config: &LoadSheddingConfig) -> Self {
+        Self {
+            statements: Pool::new(config.max_concurrent_statements, config.max_queued_statements),
+            requests: Pool::new(config.max_concurrent_requests, config.max_queued_requests),
+            max_queue: Duration::from_millis(config.max_queue_ms),
+        }
+    }
+
+    /// A slot for a binary protocol request running SQL, as for `/api/query`
+    pub(crate) async fn admit_statement(&self) -> Result<OwnedSemaphorePermit, Shed> {
+        self.statements.admit(self.max_queue).await
+    }
+
+    /// A slot for any other binary protocol request
+    pub(crate) async fn admit_request(&self) -> Result<OwnedSemaphorePermit, Shed> {
+        self.requests.admit(self.max_queue).await
+    }
+
+    pub(crate) fn load(&self) -> Load {
+        Load {
+            statements_in_flight: self.statements.in_flight(),
+            statements_queued: self.statements.queued(),
+            requests_in_flight: self.requests.in_flight(),
+            requests_queued: self.requests.queued(),
+        }
+    }
+}
+
+/// Middleware for the `/api/*` routes, before authentication and rate
+/// limiting, so a shed request costs as little as possible
+pub(crate) async fn shed_load(State(state): State<Arc<DatabaseState>>, request: Request, next: Next) -> Response {
+    let Some(shedder) = &state.load_shedder else {
+        return next.run(request).await;
+    };
+    let route = request.extensions().get::<MatchedPath>().map(MatchedPath::as_str);
+    let pool = match route {
+        Some("/api/status") => return next.run(request).await,
+        Some("/api/query" | "/api/query/next" | "/api/batch" | "/api/execute" | "/api/txn/:id/query") => &shedder.statements,
+        _ => &shedder.requests,
+    };
+    let _permit = match pool.admit(shedder.max_queue).await {
+        Ok(permit) => permit,
+        Err(shed) => return shed.into_response(),
+    };
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, get, query};
+    use crate::{DatabaseServer, ServerConfig};
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    // More workers than slots, since queries keep their worker busy
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_excess_statements_are_shed() {
+        let temp_dir = TempDir::new().unwrap();
+        let limits = LoadSheddingConfig {
+            max_concurrent_statements: 2,
+            max_queued_statements: 4,
+            max_queue_ms: 200,
+            ..LoadSheddingConfig::default()
+        };
+        let config = ServerConfig {
+            load_shedding: Some(limits),
+            ..test_util::config(&temp_dir)
+        };
+        let server = DatabaseServer::with_config(config).await.unwrap();
+        let app = server.router();
+        query(&app, "CREATE TABLE t (id INT PRIMARY KEY, v TEXT)").await;
+        let values: Vec<String> = (0..2000).map(|i| format!("({}, 'value {}')", i, i)).collect();
+        let (status, body) = query(&app, &format!("INSERT INTO t VALUES {}", values.join(", "))).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        // Each different, so none come from the result cache
+        let requests: Vec<_> = (0..300).map(|i| {
+            let app = app.clone();
+            let slow = format!("SELECT v FROM t WHERE id IN (SELECT id FROM t WHERE id % 2 = 0) AND v <> 'value {}' ORDER BY v DESC", i);
+            tokio::spawn(async move {
+                let started = Instant::now();
+                let (status, body) = query(&app, &slow).await;
+                (status, body, started.elapsed())
+            })
+        }).collect();
+        let mut served = 0;
+        let mut shed = 0;
+        for request in requests {
+            let (status, body, elapsed) = request.await.unwrap();
+            match status {
+                StatusCode::OK => served += 1,
+                StatusCode::SERVICE_UNAVAILABLE => {
+                    shed += 1;
+                    assert_eq!(body["retryable"], true);
+                    let code = body["error"]["code"].as_str().unwrap();
+                    assert!(code == "queue_full" || code == "queue_timeout", "{}", body);
+                    // Turned away at once, or when the queue deadline passed
+                    assert!(elapsed < Duration::from_secs(2), "shed after {:?}", elapsed);
+                }
+                status => panic!("unexpected {}: {}", status, body),
+            }
+        }
+        let shedder = server.state().load_shedder.as_ref().unwrap();
+        assert_eq!(shedder.statements.peak.load(Ordering::SeqCst), 2);
+        assert!(served >= 2 && shed > 0, "{} served, {} shed", served, shed);
+        assert_eq!(served + shed, 300);
+
+        // Nothing is left in flight or queued, and status was never limited
+        assert_eq!(shedder.load(), Load { statements_in_flight: 0, statements_queued: 0, requests_in_flight: 0, requests_queued: 0 });
+        server.collect_stats().await;
+        let status = get(&app, "/api/status").await.1;
+        assert_eq!(status["query"]["statements_in_flight"], 0);
+        assert_eq!(status["query"]["statements_queued"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_queue_deadline() {
+        let pool = Pool::new(1, 1);
+        let held = pool.admit(Duration::ZERO).await.unwrap();
+
+        // One request may queue, and is shed at the deadline
+        let started = Instant::now();
+        let (waiting, full) = tokio::join!(pool.admit(Duration::from_millis(50)), async {
+            tokio::task::yield_now().await;
+            pool.admit(Duration::from_millis(50)).await
+        });
+        assert_eq!(full.unwrap_err().code, "queue_full");
+        assert_eq!(waiting.unwrap_err().code, "queue_timeout");
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!((pool.in_flight(), pool.queued()), (1, 0));
+
+        // A request queued when a slot frees up takes it
+        let (next, ()) = tokio::join!(pool.admit(Duration::from_secs(5)), async {
+            tokio::task::yield_now().await;
+            drop(held);
+        });
+        assert!(next.is_ok());
+        assert_eq!(pool.peak.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,105 @@
+//! Counters the server keeps about the queries it runs.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Bucket `i` counts latencies below 2^i microseconds; the last one takes the rest
+const BUCKETS: usize = 32;
+
+/// Query counts and a latency histogram with power-of-two buckets
+#[derive(Debug)]
+pub struct QueryMetrics {
+    total: AtomicU64,
+    failed: AtomicU64,
+    latency: Mutex<LatencyHistogram>,
+}
+
+#[derive(Debug)]
+struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum_micros: u64,
+}
+
+impl QueryMetrics {
+    pub fn new() -> Self {
+        Self {
+            total: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            latency: Mutex::new(LatencyHistogram { buckets: [0; BUCKETS], count: 0, sum_micros: 0 }),
+        }
+    }
+
+    pub fn record(&self, latency: Duration, succeeded: bool) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        if !succeeded {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        let mut histogram = self.latency.lock().unwrap();
+        histogram.buckets[bucket.min(BUCKETS - 1)] += 1;
+        histogram.count += 1;
+        histogram.sum_micros = histogram.sum_micros.saturating_add(micros);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Mean latency in milliseconds, or None before the first query
+    pub fn mean_latency_ms(&self) -> Option<f64> {
+        let histogram = self.latency.lock().unwrap();
+        (histogram.count > 0).then(|| histogram.sum_micros as f64 / histogram.count as f64 / 1000.0)
+    }
+
+    /// Upper bound in milliseconds of the bucket holding the `quantile`
+    /// latency, or None before the first query
+    pub fn latency_quantile_ms(&self, quantile: f64) -> Option<f64> {
+        let histogram = self.latency.lock().unwrap();
+        let rank = ((histogram.count as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in histogram.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some((1u64 << i) as f64 / 1000.0);
+            }
+        }
+        None
+    }
+}
+
+impl Default for QueryMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let metrics = QueryMetrics::new();
+        assert_eq!(metrics.mean_latency_ms(), None);
+        assert_eq!(metrics.latency_quantile_ms(0.99), None);
+
+        for _ in 0..99 {
+            metrics.record(Duration::from_micros(100), true);
+        }
+        metrics.record(Duration::from_millis(50), false);
+
+        assert_eq!((metrics.total(), metrics.failed()), (100, 1));
+        assert!((metrics.mean_latency_ms().unwrap() - 0.599).abs() < 1e-9);
+        // 100us falls in the bucket below 128us, 50ms in the one below 65.536ms
+        assert_eq!(metrics.latency_quantile_ms(0.5), Some(0.128));
+        assert_eq!(metrics.latency_quantile_ms(0.99), Some(0.128));
+        assert_eq!(metrics.latency_quantile_ms(1.0), Some(65.536));
+    }
+}
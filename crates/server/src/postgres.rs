@@ -0,0 +1,818 @@
+//! A subset of the PostgreSQL wire protocol (version 3.0), so psql and
+//! PostgreSQL drivers can connect.
+//!
+//! Supported are startup without TLS (an SSL request is declined), trust or
+//! cleartext password authentication, the simple query cycle and
+//! termination. With `auth` configured the password must be one of the API
+//! tokens, whose scope applies as on the HTTP API, and a read-only token's
+//! writes fail with SQLSTATE 25006. Each connection runs in its own session, so BEGIN, COMMIT
+//! and ROLLBACK work as they do in PostgreSQL. The extended query protocol
+//! (Parse, Bind, Execute...) is answered with an error until Sync.
+//!
+//! Every value is sent in text format. Column types map to PostgreSQL types:
+//! INTEGER to int8, FLOAT to float8, TEXT to text, BOOLEAN to bool, BLOB to
+//! bytea and TIMESTAMP to timestamptz; a column of unknown type is text.
+
+use crate::auth::{constant_time_eq, AuthConfig, Scope};
+use crate::server::DatabaseState;
+use nextdb_query::{
+    ast::DataType,
+    value::to_datetime,
+    QueryError, ResultSet, SessionId, SqlParser, SqlStatement, Value,
+};
+use nextdb_storage::StorageError;
+use nextdb_transaction::TransactionError;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::Arc;
+use std::time::Instant;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+const PROTOCOL_VERSION_3: i32 = 196608;
+const SSL_REQUEST: i32 = 80877103;
+const GSSENC_REQUEST: i32 = 80877104;
+const CANCEL_REQUEST: i32 = 80877102;
+
+/// Reported as `server_version`; clients adapt what they send to it
+const SERVER_VERSION: &str = "14.0";
+
+const OID_BOOL: i32 = 16;
+const OID_BYTEA: i32 = 17;
+const OID_INT8: i32 = 20;
+const OID_TEXT: i32 = 25;
+const OID_FLOAT8: i32 = 701;
+const OID_TIMESTAMPTZ: i32 = 1184;
+
+/// Settings of the PostgreSQL protocol listener
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostgresConfig {
+    pub port: u16,
+    /// Password every user must give, sent in cleartext; None trusts any
+    /// client that connects. Not allowed with `auth`, whose API tokens are
+    /// the passwords instead.
+    pub password: Option<String>,
+}
+
+impl Default for PostgresConfig {
+    fn default() -> Self {
+        Self { port: 5433, password: None }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PostgresError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Protocol violation: {0}")]
+    Protocol(String),
+
+    #[error("Message of {length} bytes exceeds the limit of {max}")]
+    MessageTooLarge { length: usize, max: usize },
+}
+
+fn violation(message: impl Into<String>) -> PostgresError {
+    PostgresError::Protocol(message.into())
+}
+
+/// A message from the client after startup
+#[derive(Debug)]
+enum FrontendMessage {
+    Query(String),
+    Password(String),
+    Sync,
+    Terminate,
+    /// A message of the extended query protocol or another unsupported one
+    Unsupported(u8),
+}
+
+impl FrontendMessage {
+    fn decode(tag: u8, body: &[u8]) -> Result<Self, PostgresError> {
+        Ok(match tag {
+            b'Q' => FrontendMessage::Query(read_cstring(body)?),
+            b'p' => FrontendMessage::Password(read_cstring(body)?),
+            b'S' => FrontendMessage::Sync,
+            b'X' => FrontendMessage::Terminate,
+            other => FrontendMessage::Unsupported(other),
+        })
+    }
+}
+
+/// A message from the server
+enum BackendMessage<'a> {
+    AuthenticationOk,
+    AuthenticationCleartextPassword,
+    ParameterStatus(&'a str, &'a str),
+    BackendKeyData { process_id: i32, secret_key: i32 },
+    ReadyForQuery(TransactionStatus),
+    RowDescription(&'a ResultSet),
+    DataRow(&'a [Value]),
+    CommandComplete(&'a str),
+    EmptyQueryResponse,
+    ErrorResponse { severity: &'a str, code: &'a str, message: &'a str },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TransactionStatus {
+    Idle,
+    InTransaction,
+    Failed,
+}
+
+impl BackendMessage<'_> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let (tag, body) = match self {
+            BackendMessage::AuthenticationOk => (b'R', 0i32.to_be_bytes().to_vec()),
+            BackendMessage::AuthenticationCleartextPassword => (b'R', 3i32.to_be_bytes().to_vec()),
+            BackendMessage::ParameterStatus(name, value) => {
+                let mut body = Vec::new();
+                put_cstring(&mut body, name);
+                put_cstring(&mut body, value);
+                (b'S', body)
+            }
+            BackendMessage::BackendKeyData { process_id, secret_key } => {
+                (b'K', [process_id.to_be_bytes(), secret_key.to_be_bytes()].concat())
+            }
+            BackendMessage::ReadyForQuery(status) => {
+                let status = match status {
+                    TransactionStatus::Idle => b'I',
+                    TransactionStatus::InTransaction => b'T',
+                    TransactionStatus::Failed => b'E',
+                };
+                (b'Z', vec![status])
+            }
+            BackendMessage::RowDescription(result) => {
+                let mut body = (result.columns.len() as i16).to_be_bytes().to_vec();
+                for column in &result.columns {
+                    let (oid, size) = type_oid(column.data_type);
+                    put_cstring(&mut body, &column.name);
+                    body.extend_from_slice(&0i32.to_be_bytes()); // table oid
+                    body.extend_from_slice(&0i16.to_be_bytes()); // column number
+                    body.extend_from_slice(&oid.to_be_bytes());
+                    body.extend_from_slice(&size.to_be_bytes());
+                    body.extend_from_slice(&(-1i32).to_be_bytes()); // type modifier
+                    body.extend_from_slice(&0i16.to_be_bytes()); // text format
+                }
+                (b'T', body)
+            }
+            BackendMessage::DataRow(values) => {
+                let mut body = (values.len() as i16).to_be_bytes().to_vec();
+                for value in values.iter() {
+                    match text_value(value) {
+                        Some(text) => {
+                            body.extend_from_slice(&(text.len() as i32).to_be_bytes());
+                            body.extend_from_slice(text.as_bytes());
+                        }
+                        None => body.extend_from_slice(&(-1i32).to_be_bytes()),
+                    }
+                }
+                (b'D', body)
+            }
+            BackendMessage::CommandComplete(command_tag) => {
+                let mut body = Vec::new();
+                put_cstring(&mut body, command_tag);
+                (b'C', body)
+            }
+            BackendMessage::EmptyQueryResponse => (b'I', Vec::new()),
+            BackendMessage::ErrorResponse { severity, code, message } => {
+                let mut body = Vec::new();
+                for (field, value) in [(b'S', severity), (b'V', severity), (b'C', code), (b'M', message)] {
+                    body.push(field);
+                    put_cstring(&mut body, value);
+                }
+                body.push(0);
+                (b'E', body)
+            }
+        };
+        out.push(tag);
+        out.extend_from_slice(&((body.len() + 4) as i32).to_be_bytes());
+        out.extend_from_slice(&body);
+    }
+}
+
+fn put_cstring(out: &mut Vec<u8>, text: &str) {
+    out.extend_from_slice(text.as_bytes());
+    out.push(0);
+}
+
+fn read_cstring(body: &[u8]) -> Result<String, PostgresError> {
+    let end = body.iter().position(|&b| b == 0).ok_or_else(|| violation("string is not terminated"))?;
+    String::from_utf8(body[..end].to_vec()).map_err(|_| violation("string is not valid UTF-8"))
+}
+
+/// PostgreSQL type OID and size of a column type
+fn type_oid(data_type: Option<DataType>) -> (i32, i16) {
+    match data_type {
+        Some(DataType::Integer) => (OID_INT8, 8),
+        Some(DataType::Float) => (OID_FLOAT8, 8),
+        Some(DataType::Boolean) => (OID_BOOL, 1),
+        Some(DataType::Blob) => (OID_BYTEA, -1),
+        Some(DataType::Timestamp) => (OID_TIMESTAMPTZ, 8),
+        Some(DataType::Text) | None => (OID_TEXT, -1),
+    }
+}
+
+/// A value in PostgreSQL's text format, or None for NULL
+fn text_value(value: &Value) -> Option<String> {
+    Some(match value {
+        Value::Null => return None,
+        Value::Boolean(b) => if *b { "t" } else { "f" }.to_string(),
+        Value::Float(f) if f.is_nan() => "NaN".to_string(),
+        Value::Float(f) if f.is_infinite() => if *f > 0.0 { "Infinity" } else { "-Infinity" }.to_string(),
+        Value::Timestamp(micros) => match to_datetime(*micros) {
+            Some(at) => at.format("%Y-%m-%d %H:%M:%S%.f+00").to_string(),
+            None => value.to_string(),
+        },
+        // Integers, floats, text and bytea in hex format print the same
+        _ => value.to_string(),
+    })
+}
+
+/// SQLSTATE code for a failed query
+fn sqlstate(error: &QueryError) -> &'static str {
+    match error {
+        QueryError::Parse(_) => "42601",
+        QueryError::Plan(_) | QueryError::Invalid(_) => "42000",
+        QueryError::Execution(_) => "22000",
+        QueryError::TableNotFound(_) => "42P01",
+        QueryError::ColumnNotFound(_) => "42703",
+        QueryError::TableExists(_) => "42P07",
+        QueryError::DatabaseNotFound(_) => "3D000",
+        QueryError::DatabaseExists(_) => "42P04",
+        QueryError::ConstraintViolation { .. } => "23505",
+        QueryError::Transaction(TransactionError::LockTimeout) => "55P03",
+        QueryError::Transaction(TransactionError::Deadlock) => "40P01",
+        QueryError::Transaction(TransactionError::Conflict) => "40001",
+        QueryError::Transaction(TransactionError::Aborted) => "25P02",
+        QueryError::Transaction(_) => "25000",
+        QueryError::NotLeader { .. } => "25006",
+        QueryError::Replication(_) => "08006",
+        QueryError::Storage(StorageError::Corruption(_)) => "XX001",
+        QueryError::Storage(StorageError::Closed) => "57P01",
+        QueryError::Storage(StorageError::OutOfSpace(_)) => "53100",
+        QueryError::Storage(StorageError::KeyTooLarge { .. }) => "54000",
+        QueryError::Storage(_) | QueryError::Io(_) => "XX000",
+    }
+}
+
+/// Tag reported when `statement` completes with `result`
+fn command_tag(statement: &SqlStatement, result: &ResultSet) -> String {
+    let affected = result.rows_affected.unwrap_or(0);
+    match statement {
+        SqlStatement::Insert { .. } => format!("INSERT 0 {}", affected),
+        SqlStatement::Update { .. } => format!("UPDATE {}", affected),
+        SqlStatement::Delete { .. } => format!("DELETE {}", affected),
+        SqlStatement::CreateTable { .. } => "CREATE TABLE".to_string(),
+        SqlStatement::DropTable { .. } => "DROP TABLE".to_string(),
+        SqlStatement::CreateDatabase { .. } => "CREATE DATABASE".to_string(),
+        SqlStatement::DropDatabase { .. } => "DROP DATABASE".to_string(),
+        SqlStatement::CreateIndex { .. } => "CREATE INDEX".to_string(),
+        SqlStatement::AlterTable { .. } => "ALTER TABLE".to_string(),
+        SqlStatement::Begin { .. } => "BEGIN".to_string(),
+        SqlStatement::Commit => "COMMIT".to_string(),
+        SqlStatement::Rollback => "ROLLBACK".to_string(),
+        SqlStatement::Select(_)
+        | SqlStatement::ShowTables { .. }
+        | SqlStatement::ShowColumns { .. }
+        | SqlStatement::Explain { .. }
+        | SqlStatement::Analyze { .. } => format!("SELECT {}", result.rows.len()),
+    }
+}
+
+/// Whether `statement` returns rows, and so is described before them
+fn returns_rows(statement: &SqlStatement) -> bool {
+    matches!(statement,
+        SqlStatement::Select(_) | SqlStatement::ShowTables { .. } | SqlStatement::ShowColumns { .. }
+            | SqlStatement::Explain { .. } | SqlStatement::Analyze { .. })
+}
+
+/// Accept PostgreSQL connections on `listener` until it fails
+pub(crate) async fn serve(
+    listener: TcpListener,
+    state: Arc<DatabaseState>,
+    config: PostgresConfig,
+    auth: Option<Arc<AuthConfig>>,
+    max_message_bytes: usize,
+) -> io::Result<()> {
+    let config = Arc::new(config);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let state = state.clone();
+        let config = config.clone();
+        let auth = auth.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, state, &config, auth.as_deref(), max_message_bytes).await {
+                debug!("PostgreSQL connection from {} closed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    state: Arc<DatabaseState>,
+    config: &PostgresConfig,
+    auth: Option<&AuthConfig>,
+    max_message_bytes: usize,
+) -> Result<(), PostgresError> {
+    stream.set_nodelay(true)?;
+    let (reader, writer) = stream.into_split();
+    let mut connection = Connection {
+        reader: BufReader::new(reader),
+        writer: BufWriter::new(writer),
+        out: Vec::new(),
+        session: state.new_session(),
+        state,
+        scope: Scope::Admin,
+        max_message_bytes,
+    };
+    let result = connection.run(config, auth).await;
+    if let Err(e) = connection.state.executor.end_session(connection.session).await {
+        warn!("Failed to end PostgreSQL session: {}", e);
+    }
+    result
+}
+
+struct Connection<R, W> {
+    reader: BufReader<R>,
+    writer: BufWriter<W>,
+    /// Messages waiting to be written
+    out: Vec<u8>,
+    state: Arc<DatabaseState>,
+    session: SessionId,
+    /// Of the API token given as the password, or admin without `auth`
+    scope: Scope,
+    max_message_bytes: usize,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Connection<R, W> {
+    async fn run(&mut self, config: &PostgresConfig, auth: Option<&AuthConfig>) -> Result<(), PostgresError> {
+        if !self.startup(config, auth).await? {
+            return Ok(());
+        }
+
+        // After an unsupported extended protocol message, the rest of its
+        // cycle is skipped until Sync, as after an error in PostgreSQL
+        let mut skipping = false;
+        loop {
+            let Some(message) = self.read_message().await? else {
+                return Ok(());
+            };
+            match message {
+                FrontendMessage::Query(sql) => {
+                    self.simple_query(&sql).await;
+                    self.ready_for_query();
+                }
+                FrontendMessage::Sync => {
+                    skipping = false;
+                    self.ready_for_query();
+                }
+                FrontendMessage::Terminate => return Ok(()),
+                FrontendMessage::Unsupported(_) if skipping => {}
+                FrontendMessage::Unsupported(tag) => {
+                    skipping = true;
+                    let message = format!("message type '{}' is not supported; only simple queries are", tag as char);
+                    self.send(BackendMessage::ErrorResponse { severity: "ERROR", code: "0A000", message: &message });
+                }
+                FrontendMessage::Password(_) => return Err(self.fatal("08P01", "unexpected password message").await),
+            }
+            self.flush().await?;
+        }
+    }
+
+    /// Read the startup packet and authenticate. False if the client only
+    /// wanted to cancel a query.
+    async fn startup(&mut self, config: &PostgresConfig, auth: Option<&AuthConfig>) -> Result<bool, PostgresError> {
+        let parameters = loop {
+            let body = self.read_startup_packet().await?;
+            if body.len() < 4 {
+                return Err(violation("startup packet is too short"));
+            }
+            let code = i32::from_be_bytes(body[..4].try_into().unwrap());
+            match code {
+                // No TLS or GSSAPI encryption: the client goes on in plaintext
+                SSL_REQUEST | GSSENC_REQUEST => {
+                    self.writer.write_all(b"N").await?;
+                    self.writer.flush().await?;
+                }
+                CANCEL_REQUEST => return Ok(false),
+                PROTOCOL_VERSION_3 => break body[4..].to_vec(),
+                other => {
+                    let message = format!("unsupported frontend protocol {}.{}", other >> 16, other & 0xffff);
+                    return Err(self.fatal("0A000", &message).await);
+                }
+            }
+        };
+        let user = parameters.split(|&b| b == 0)
+            .collect::<Vec<_>>()
+            .chunks(2)
+            .find(|pair| pair[0] == b"user")
+            .and_then(|pair| pair.get(1))
+            .map(|user| String::from_utf8_lossy(user).into_owned())
+            .unwrap_or_default();
+
+        if auth.is_some() || config.password.is_some() {
+            self.send(BackendMessage::AuthenticationCleartextPassword);
+            self.flush().await?;
+            let given = match self.read_message().await? {
+                Some(FrontendMessage::Password(given)) => given,
+                Some(_) => return Err(self.fatal("08P01", "expected a password message").await),
+                None => return Ok(false),
+            };
+            let scope = match (auth, &config.password) {
+                (Some(auth), _) => auth.authenticate_connection(&given).map(|token| token.scope),
+                (None, Some(password)) => constant_time_eq(given.as_bytes(), password.as_bytes()).then_some(Scope::Admin),
+                (None, None) => unreachable!("a password was asked for"),
+            };
+            let Some(scope) = scope else {
+                let message = format!("password authentication failed for user \"{}\"", user);
+                return Err(self.fatal("28P01", &message).await);
+            };
+            self.scope = scope;
+        }
+
+        self.send(BackendMessage::AuthenticationOk);
+        for (name, value) in [
+            ("server_version", SERVER_VERSION),
+            ("server_encoding", "UTF8"),
+            ("client_encoding", "UTF8"),
+            ("DateStyle", "ISO, MDY"),
+            ("TimeZone", "UTC"),
+            ("integer_datetimes", "on"),
+            ("standard_conforming_strings", "on"),
+        ] {
+            self.send(BackendMessage::ParameterStatus(name, value));
+        }
+        self.send(BackendMessage::BackendKeyData { process_id: self.session.0 as i32, secret_key: 0 });
+        self.ready_for_query();
+        self.flush().await?;
+        Ok(true)
+    }
+
+    /// Run each statement of a simple query, stopping at the first error
+    async fn simple_query(&mut self, sql: &str) {
+        let statements = match SqlParser::parse_many(sql) {
+            Ok(statements) => statements,
+            Err(e) => {
+                self.state.executor.fail_transaction(self.session).await;
+                self.send_error(&e);
+                return;
+            }
+        };
+        if statements.is_empty() {
+            self.send(BackendMessage::EmptyQueryResponse);
+            return;
+        }
+
+        for statement in statements {
+            if !self.scope.allows(&statement) {
+                self.state.executor.fail_transaction(self.session).await;
+                let message = "the API token is read-only and the query writes";
+                self.send(BackendMessage::ErrorResponse { severity: "ERROR", code: "25006", message });
+                return;
+            }
+            let started = Instant::now();
+            let result = self.state.executor.execute_statement_in(self.session, statement.clone()).await;
+            self.state.query_metrics.record(started.elapsed(), result.is_ok());
+            match result {
+                Ok(result) => {
+                    if returns_rows(&statement) {
+                        self.send(BackendMessage::RowDescription(&result));
+                        for row in &result.rows {
+                            self.send(BackendMessage::DataRow(row));
+                        }
+                    }
+                    self.send(BackendMessage::CommandComplete(&command_tag(&statement, &result)));
+                }
+                Err(e) => {
+                    self.send_error(&e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn ready_for_query(&mut self) {
+        let executor = &self.state.executor;
+        let status = if executor.session_transaction_failed(self.session) {
+            TransactionStatus::Failed
+        } else if executor.session_transaction(self.session).is_some() {
+            TransactionStatus::InTransaction
+        } else {
+            TransactionStatus::Idle
+        };
+        self.send(BackendMessage::ReadyForQuery(status));
+    }
+
+    fn send(&mut self, message: BackendMessage) {
+        message.encode(&mut self.out);
+    }
+
+    fn send_error(&mut self, error: &QueryError) {
+        let message = error.to_string();
+        self.send(BackendMessage::ErrorResponse { severity: "ERROR", code: sqlstate(error), message: &message });
+    }
+
+    /// Send a fatal error, which ends the connection, and return it
+    async fn fatal(&mut self, code: &str, message: &str) -> PostgresError {
+        self.send(BackendMessage::ErrorResponse { severity: "FATAL", code, message });
+        if let Err(e) = self.flush().await {
+            return e.into();
+        }
+        violation(message)
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.writer.write_all(&self.out).await?;
+        self.out.clear();
+        self.writer.flush().await
+    }
+
+    async fn read_startup_packet(&mut self) -> Result<Vec<u8>, PostgresError> {
+        let length = self.reader.read_i32().await?;
+        self.read_body(length).await
+    }
+
+    /// The next message, or None if the client disconnected between messages
+    async fn read_message(&mut self) -> Result<Option<FrontendMessage>, PostgresError> {
+        let tag = match self.reader.read_u8().await {
+            Ok(tag) => tag,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let length = self.reader.read_i32().await?;
+        let body = self.read_body(length).await?;
+        FrontendMessage::decode(tag, &body).map(Some)
+    }
+
+    /// Read the rest of a message whose length, counting itself, is `length`
+    async fn read_body(&mut self, length: i32) -> Result<Vec<u8>, PostgresError> {
+        let length = usize::try_from(length).ok()
+            .and_then(|length| length.checked_sub(4))
+            .ok_or_else(|| violation(format!("invalid message length {}", length)))?;
+        if length > self.max_message_bytes {
+            let error = PostgresError::MessageTooLarge { length, max: self.max_message_bytes };
+            return Err(self.fatal("54000", &error.to_string()).await);
+        }
+        let mut body = vec![0; length];
+        self.reader.read_exact(&mut body).await?;
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use crate::{ApiToken, DatabaseServer, ServerConfig};
+    use std::net::SocketAddr;
+    use tempfile::TempDir;
+
+    /// A bare PostgreSQL client speaking the simple query protocol
+    struct TestClient {
+        stream: BufReader<TcpStream>,
+    }
+
+    /// A backend message as its tag and body
+    type Message = (u8, Vec<u8>);
+
+    impl TestClient {
+        async fn connect(addr: SocketAddr, password: Option<&str>) -> Result<Self, String> {
+            let mut client = Self { stream: BufReader::new(TcpStream::connect(addr).await.unwrap()) };
+
+            // Declined TLS first, as psql tries by default
+            client.send_startup(&SSL_REQUEST.to_be_bytes()).await;
+            assert_eq!(client.stream.read_u8().await.unwrap(), b'N');
+
+            let mut body = PROTOCOL_VERSION_3.to_be_bytes().to_vec();
+            for part in ["user", "tester", "database", "nextdb", ""] {
+                put_cstring(&mut body, part);
+            }
+            client.send_startup(&body).await;
+
+            let (tag, body) = client.receive().await;
+            if (tag, body.as_slice()) == (b'R', 3i32.to_be_bytes().as_slice()) {
+                let mut body = Vec::new();
+                put_cstring(&mut body, password.unwrap_or(""));
+                client.send(b'p', &body).await;
+                let (tag, body) = client.receive().await;
+                if tag == b'E' {
+                    return Err(error_field(&body, b'C'));
+                }
+                assert_eq!((tag, body), (b'R', 0i32.to_be_bytes().to_vec()));
+            } else {
+                assert_eq!((tag, body), (b'R', 0i32.to_be_bytes().to_vec()));
+            }
+
+            let messages = client.receive_until_ready().await;
+            assert!(messages.iter().any(|(tag, body)| *tag == b'S' && body.starts_with(b"server_version\0")));
+            assert_eq!(messages.last().unwrap(), &(b'Z', vec![b'I']));
+            Ok(client)
+        }
+
+        async fn send_startup(&mut self, body: &[u8]) {
+            let stream = self.stream.get_mut();
+            stream.write_all(&((body.len() + 4) as i32).to_be_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        }
+
+        async fn send(&mut self, tag: u8, body: &[u8]) {
+            let stream = self.stream.get_mut();
+            stream.write_all(&[tag]).await.unwrap();
+            stream.write_all(&((body.len() + 4) as i32).to_be_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        }
+
+        async fn receive(&mut self) -> Message {
+            let tag = self.stream.read_u8().await.unwrap();
+            let length = self.stream.read_i32().await.unwrap() as usize;
+            let mut body = vec![0; length - 4];
+            self.stream.read_exact(&mut body).await.unwrap();
+            (tag, body)
+        }
+
+        async fn receive_until_ready(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            loop {
+                let message = self.receive().await;
+                let ready = message.0 == b'Z';
+                messages.push(message);
+                if ready {
+                    return messages;
+                }
+            }
+        }
+
+        async fn query(&mut self, sql: &str) -> Vec<Message> {
+            let mut body = Vec::new();
+            put_cstring(&mut body, sql);
+            self.send(b'Q', &body).await;
+            self.receive_until_ready().await
+        }
+    }
+
+    fn error_field(body: &[u8], field: u8) -> String {
+        body.split(|&b| b == 0)
+            .find(|part| part.first() == Some(&field))
+            .map(|part| String::from_utf8_lossy(&part[1..]).into_owned())
+            .unwrap()
+    }
+
+    fn data_row(body: &[u8]) -> Vec<Option<String>> {
+        let count = i16::from_be_bytes(body[..2].try_into().unwrap());
+        let mut pos = 2;
+        (0..count)
+            .map(|_| {
+                let length = i32::from_be_bytes(body[pos..pos + 4].try_into().unwrap());
+                pos += 4;
+                if length < 0 {
+                    return None;
+                }
+                let text = String::from_utf8(body[pos..pos + length as usize].to_vec()).unwrap();
+                pos += length as usize;
+                Some(text)
+            })
+            .collect()
+    }
+
+    fn column_oids(body: &[u8]) -> Vec<(String, i32)> {
+        let count = i16::from_be_bytes(body[..2].try_into().unwrap());
+        let mut pos = 2;
+        (0..count)
+            .map(|_| {
+                let name = read_cstring(&body[pos..]).unwrap();
+                pos += name.len() + 1;
+                let oid = i32::from_be_bytes(body[pos + 6..pos + 10].try_into().unwrap());
+                pos += 18;
+                (name, oid)
+            })
+            .collect()
+    }
+
+    fn command_complete(messages: &[Message]) -> Vec<String> {
+        messages.iter()
+            .filter(|(tag, _)| *tag == b'C')
+            .map(|(_, body)| read_cstring(body).unwrap())
+            .collect()
+    }
+
+    async fn start(temp_dir: &TempDir, password: Option<&str>) -> SocketAddr {
+        listen(ServerConfig {
+            postgres: Some(PostgresConfig { port: 0, password: password.map(str::to_string) }),
+            ..test_util::config(temp_dir)
+        }).await
+    }
+
+    async fn listen(config: ServerConfig) -> SocketAddr {
+        let server = DatabaseServer::with_config(config).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { server.serve_postgres(listener).await });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_simple_query_cycle() {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = start(&temp_dir, None).await;
+        let mut client = TestClient::connect(addr, None).await.unwrap();
+
+        let messages = client.query("CREATE TABLE t (id INT PRIMARY KEY, name TEXT, score FLOAT, ok BOOLEAN, at TIMESTAMP)").await;
+        assert_eq!(command_complete(&messages), vec!["CREATE TABLE"]);
+        let messages = client.query(
+            "INSERT INTO t VALUES (1, 'one', 1.5, TRUE, '2024-01-02T03:04:05Z'), (2, NULL, NULL, FALSE, NULL)",
+        ).await;
+        assert_eq!(command_complete(&messages), vec!["INSERT 0 2"]);
+
+        let messages = client.query("SELECT id, name, score, ok, at FROM t ORDER BY id").await;
+        let tags: Vec<u8> = messages.iter().map(|(tag, _)| *tag).collect();
+        assert_eq!(tags, b"TDDCZ");
+        assert_eq!(column_oids(&messages[0].1), vec![
+            ("id".to_string(), OID_INT8),
+            ("name".to_string(), OID_TEXT),
+            ("score".to_string(), OID_FLOAT8),
+            ("ok".to_string(), OID_BOOL),
+            ("at".to_string(), OID_TIMESTAMPTZ),
+        ]);
+        let text = |s: &str| Some(s.to_string());
+        assert_eq!(data_row(&messages[1].1),
+            vec![text("1"), text("one"), text("1.5"), text("t"), text("2024-01-02 03:04:05+00")]);
+        assert_eq!(data_row(&messages[2].1), vec![text("2"), None, None, text("f"), None]);
+        assert_eq!(command_complete(&messages), vec!["SELECT 2"]);
+
+        // Several statements in one query, and an empty one
+        let messages = client.query("UPDATE t SET score = 2.0 WHERE id = 2; DELETE FROM t WHERE id = 1;").await;
+        assert_eq!(command_complete(&messages), vec!["UPDATE 1", "DELETE 1"]);
+        let messages = client.query(" ; ").await;
+        assert_eq!(messages.iter().map(|(tag, _)| *tag).collect::<Vec<_>>(), b"IZ");
+
+        // Errors carry a SQLSTATE, and fail an open transaction until ROLLBACK
+        let messages = client.query("SELECT * FROM missing").await;
+        assert_eq!((messages[0].0, error_field(&messages[0].1, b'C')), (b'E', "42P01".to_string()));
+        assert_eq!(messages.last().unwrap(), &(b'Z', vec![b'I']));
+
+        assert_eq!(client.query("BEGIN").await.last().unwrap(), &(b'Z', vec![b'T']));
+        let messages = client.query("INSERT INTO t VALUES (2, 'dup', 0.0, TRUE, NULL)").await;
+        assert_eq!(error_field(&messages[0].1, b'C'), "23505");
+        assert_eq!(messages.last().unwrap(), &(b'Z', vec![b'E']));
+        let messages = client.query("SELEKT").await;
+        assert_eq!(error_field(&messages[0].1, b'C'), "42601");
+        assert_eq!(messages.last().unwrap(), &(b'Z', vec![b'E']));
+        let messages = client.query("ROLLBACK").await;
+        assert_eq!(command_complete(&messages), vec!["ROLLBACK"]);
+        assert_eq!(messages.last().unwrap(), &(b'Z', vec![b'I']));
+
+        // The extended protocol is refused, once, until Sync
+        client.send(b'P', b"\0SELECT 1\0\0\0").await;
+        client.send(b'B', b"\0\0\0\0\0\0\0\0").await;
+        client.send(b'S', b"").await;
+        let messages = client.receive_until_ready().await;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(error_field(&messages[0].1, b'C'), "0A000");
+
+        client.send(b'X', b"").await;
+        assert_eq!(client.stream.read_u8().await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_cleartext_password() {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = start(&temp_dir, Some("secret")).await;
+
+        assert_eq!(TestClient::connect(addr, Some("wrong")).await.err().as_deref(), Some("28P01"));
+        let mut client = TestClient::connect(addr, Some("secret")).await.unwrap();
+        let messages = client.query("SELECT 1 + 1").await;
+        assert_eq!(data_row(&messages[1].1), vec![Some("2".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn test_api_tokens_as_passwords() {
+        let temp_dir = TempDir::new().unwrap();
+        let tokens = ApiToken::parse_list("reader:ro:read-token, writer:rw:write-token").unwrap();
+        let addr = listen(ServerConfig {
+            auth: Some(AuthConfig { tokens }),
+            postgres: Some(PostgresConfig { port: 0, password: None }),
+            ..test_util::config(&temp_dir)
+        }).await;
+
+        for password in [None, Some("wrong-token")] {
+            assert_eq!(TestClient::connect(addr, password).await.err().as_deref(), Some("28P01"));
+        }
+        let mut writer = TestClient::connect(addr, Some("write-token")).await.unwrap();
+        assert_eq!(command_complete(&writer.query("CREATE TABLE t (id INT PRIMARY KEY)").await), vec!["CREATE TABLE"]);
+
+        // A read-only token's writes are refused, failing an open transaction
+        let mut reader = TestClient::connect(addr, Some("read-token")).await.unwrap();
+        let messages = reader.query("INSERT INTO t VALUES (1)").await;
+        assert_eq!(error_field(&messages[0].1, b'C'), "25006");
+        assert_eq!(reader.query("BEGIN").await.last().unwrap(), &(b'Z', vec![b'T']));
+        let messages = reader.query("SELECT id FROM t; DELETE FROM t").await;
+        assert_eq!(command_complete(&messages), vec!["SELECT 0"]);
+        assert_eq!(error_field(&messages[2].1, b'C'), "25006");
+        assert_eq!(messages.last().unwrap(), &(b'Z', vec![b'E']));
+    }
+}
@@ -0,0 +1,309 @@
+//! Prepared statements over the HTTP API.
+//!
+//! `POST /api/prepare` with `{"sql": "..."}` parses a statement that may
+//! take parameters `$1`, `$2`, ... and returns its `statement_id`, with the
+//! type of each parameter where it can be told from the column it is
+//! stored in or compared with. `POST /api/execute` with
+//! `{"statement_id": "...", "params": [...]}` binds the parameters, given as
+//! JSON values like those in query results, and answers like `/api/query`.
+//! `DELETE /api/prepare/{id}` forgets the statement.
+//!
+//! Statements belong to the client that prepared them, and each client
+//! keeps at most `max_prepared_statements_per_client`, the least recently
+//! used going first. Any CREATE, DROP or ALTER of a table invalidates the
+//! statements prepared before it: executing one then fails with 409 and the
+//! code `statement_invalidated`, and it must be prepared again.
+
+use crate::{
+    auth::Scope,
+    cursor::new_token,
+    rate_limit::Client,
+    request_log,
+    server::{respond, DatabaseState, ErrorBody},
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use nextdb_query::{ast::DataType, SqlParser, SqlStatement, Value};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PrepareRequest {
+    sql: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ExecuteRequest {
+    statement_id: String,
+    #[serde(default)]
+    params: Vec<serde_json::Value>,
+}
+
+#[derive(Clone)]
+struct Prepared {
+    id: String,
+    sql: String,
+    statement: SqlStatement,
+    types: Vec<Option<DataType>>,
+    /// Catalog version the statement was prepared against
+    catalog_version: u64,
+}
+
+/// Prepared statements by client, each client's least recently used first
+pub(crate) struct PreparedStatements {
+    clients: Mutex<HashMap<Client, VecDeque<Prepared>>>,
+    per_client: usize,
+}
+
+impl PreparedStatements {
+    pub(crate) fn new(per_client: usize) -> Self {
+        Self { clients: Mutex::new(HashMap::new()), per_client }
+    }
+
+    fn insert(&self, client: Client, prepared: Prepared) {
+        let mut clients = self.clients.lock().unwrap();
+        let statements = clients.entry(client).or_default();
+        if statements.len() >= self.per_client {
+            statements.pop_front();
+        }
+        statements.push_back(prepared);
+    }
+
+    /// The statement `id`, if `client` prepared it, marked as just used
+    fn get(&self, client: &Client, id: &str) -> Option<Prepared> {
+        let mut clients = self.clients.lock().unwrap();
+        let statements = clients.get_mut(client)?;
+        let position = statements.iter().position(|prepared| prepared.id == id)?;
+        let prepared = statements.remove(position).expect("position is in range");
+        statements.push_back(prepared.clone());
+        Some(prepared)
+    }
+
+    fn remove(&self, client: &Client, id: &str) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        let Some(statements) = clients.get_mut(client) else {
+            return false;
+        };
+        let before = statements.len();
+        statements.retain(|prepared| prepared.id != id);
+        let removed = statements.len() < before;
+        if statements.is_empty() {
+            clients.remove(client);
+        }
+        removed
+    }
+}
+
+fn failure(status: StatusCode, code: &'static str, message: String) -> Response {
+    (status, Json(serde_json::json!({ "success": false, "error": ErrorBody { code, message } }))).into_response()
+}
+
+fn not_found() -> Response {
+    let message = "no such statement was prepared by this client, or it was evicted".to_string();
+    failure(StatusCode::NOT_FOUND, "statement_not_found", message)
+}
+
+fn client_of(client: Option<Extension<Client>>) -> Client {
+    client.map_or(Client::Unknown, |Extension(client)| client)
+}
+
+/// Handler for `POST /api/prepare`
+pub(crate) async fn prepare(
+    State(state): State<Arc<DatabaseState>>,
+    Extension(scope): Extension<Scope>,
+    client: Option<Extension<Client>>,
+    Json(req): Json<PrepareRequest>,
+) -> Response {
+    request_log::record_sql(&req.sql);
+    let statement = match SqlParser::parse(&req.sql) {
+        Ok(statement) => statement,
+        Err(e) => return failure(StatusCode::BAD_REQUEST, "parse_error", e.to_string()),
+    };
+    if !scope.allows(&statement) {
+        let message = "the API token is read-only and the query writes".to_string();
+        return failure(StatusCode::FORBIDDEN, "read_only_token", message);
+    }
+
+    // Read before the types, so DDL in between invalidates the statement
+    let catalog = state.executor.catalog();
+    let catalog_version = catalog.version();
+    let types = statement.parameter_types(catalog);
+    let parameters: Vec<_> = types.iter().enumerate()
+        .map(|(i, data_type)| serde_json::json!({ "index": i + 1, "data_type": data_type.map(|t| t.to_string()) }))
+        .collect();
+    let id = new_token();
+    let prepared = Prepared { id: id.clone(), sql: req.sql, statement, types, catalog_version };
+    state.prepared.insert(client_of(client), prepared);
+    let body = serde_json::json!({ "success": true, "statement_id": id, "parameters": parameters });
+    (StatusCode::OK, Json(body)).into_response()
+}
+
+/// Handler for `POST /api/execute`
+pub(crate) async fn execute(
+    State(state): State<Arc<DatabaseState>>,
+    client: Option<Extension<Client>>,
+    Json(req): Json<ExecuteRequest>,
+) -> Response {
+    let client = client_of(client);
+    let Some(prepared) = state.prepared.get(&client, &req.statement_id) else {
+        return not_found();
+    };
+    if prepared.catalog_version != state.executor.catalog().version() {
+        state.prepared.remove(&client, &prepared.id);
+        let message = "a table changed since the statement was prepared; prepare it again".to_string();
+        return failure(StatusCode::CONFLICT, "statement_invalidated", message);
+    }
+    request_log::record_sql(&prepared.sql);
+
+    if req.params.len() != prepared.types.len() {
+        let message = format!("the statement takes {} parameters, {} given", prepared.types.len(), req.params.len());
+        return failure(StatusCode::BAD_REQUEST, "invalid_parameters", message);
+    }
+    let params: Result<Vec<Value>, String> = req.params.iter().zip(&prepared.types).enumerate()
+        .map(|(i, (json, data_type))| Value::from_json(json, *data_type).map_err(|e| format!("parameter ${}: {}", i + 1, e)))
+        .collect();
+    let statement = match params.map(|params| prepared.statement.bind(&params).map_err(|e| e.to_string())) {
+        Ok(Ok(statement)) => statement,
+        Ok(Err(message)) | Err(message) => return failure(StatusCode::BAD_REQUEST, "invalid_parameters", message),
+    };
+
+    let started = Instant::now();
+    let admitted = state.rate_limiter.as_ref().map(|limiter| limiter.admit_query(&client, &statement));
+    let _permit = match admitted.transpose() {
+        Ok(permit) => permit,
+        Err(limited) => return limited.into_response(),
+    };
+    let result = state.executor.execute_statement(statement).await;
+    respond(&state, started, result, None)
+}
+
+/// Handler for `DELETE /api/prepare/{id}`
+pub(crate) async fn deallocate(
+    State(state): State<Arc<DatabaseState>>,
+    Path(id): Path<String>,
+    client: Option<Extension<Client>>,
+) -> Response {
+    if !state.prepared.remove(&client_of(client), &id) {
+        return not_found();
+    }
+    (StatusCode::OK, Json(serde_json::json!({ "success": true }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{self, json_request, post, send_json};
+    use crate::{DatabaseServer, ServerConfig};
+    use axum::{body::Body, http::{Method, StatusCode}, Router};
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    async fn delete(app: &Router, uri: &str) -> StatusCode {
+        send_json(app, json_request(Method::DELETE, uri).body(Body::from("{}")).unwrap()).await.0
+    }
+
+    async fn prepare(app: &Router, sql: &str) -> serde_json::Value {
+        let (status, body) = post(app, "/api/prepare", json!({ "sql": sql })).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        body
+    }
+
+    async fn execute(app: &Router, id: &serde_json::Value, params: serde_json::Value) -> (StatusCode, serde_json::Value) {
+        post(app, "/api/execute", json!({ "statement_id": id, "params": params })).await
+    }
+
+    async fn app(per_client: usize) -> (TempDir, Router) {
+        let temp_dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            max_prepared_statements_per_client: per_client,
+            ..test_util::config(&temp_dir)
+        };
+        let app = DatabaseServer::with_config(config).await.unwrap().router();
+        let (status, body) = post(&app, "/api/query", json!({ "sql": "CREATE TABLE t (id INT PRIMARY KEY, name TEXT, data BLOB)" })).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        (temp_dir, app)
+    }
+
+    #[tokio::test]
+    async fn test_prepare_and_execute() {
+        let (_dir, app) = app(16).await;
+        let insert = prepare(&app, "INSERT INTO t VALUES ($1, $2, $3)").await;
+        assert_eq!(insert["parameters"], json!([
+            { "index": 1, "data_type": "INTEGER" },
+            { "index": 2, "data_type": "TEXT" },
+            { "index": 3, "data_type": "BLOB" },
+        ]));
+        for (id, name) in [(1, "one"), (2, "two"), (3, "it's")] {
+            let (status, body) = execute(&app, &insert["statement_id"], json!([id, name, { "$base64": "AAE=" }])).await;
+            assert_eq!((status, &body["rows_affected"]), (StatusCode::OK, &json!(1)), "{}", body);
+        }
+
+        let select = prepare(&app, "SELECT name, data FROM t WHERE id >= $1 ORDER BY id").await;
+        let (status, body) = execute(&app, &select["statement_id"], json!([2])).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["result"]["rows"], json!([["two", { "$base64": "AAE=" }], ["it's", { "$base64": "AAE=" }]]));
+
+        // Forgotten once deallocated
+        let uri = format!("/api/prepare/{}", select["statement_id"].as_str().unwrap());
+        assert_eq!(delete(&app, &uri).await, StatusCode::OK);
+        assert_eq!(delete(&app, &uri).await, StatusCode::NOT_FOUND);
+        let (status, body) = execute(&app, &select["statement_id"], json!([2])).await;
+        assert_eq!((status, body["error"]["code"].as_str()), (StatusCode::NOT_FOUND, Some("statement_not_found")));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_parameters() {
+        let (_dir, app) = app(16).await;
+        let insert = prepare(&app, "INSERT INTO t (id, name) VALUES ($1, $2)").await;
+        for params in [json!([1]), json!([1, "a", "b"]), json!(["1", "a"]), json!([1, 2]), json!([1.5, "a"])] {
+            let (status, body) = execute(&app, &insert["statement_id"], params.clone()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}: {}", params, body);
+            assert_eq!(body["error"]["code"], "invalid_parameters");
+        }
+        let (status, body) = execute(&app, &insert["statement_id"], json!([1, null])).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        let (status, body) = post(&app, "/api/prepare", json!({ "sql": "SELECT FROM" })).await;
+        assert_eq!((status, body["error"]["code"].as_str()), (StatusCode::BAD_REQUEST, Some("parse_error")));
+    }
+
+    #[tokio::test]
+    async fn test_least_recently_used_are_evicted() {
+        let (_dir, app) = app(2).await;
+        let first = prepare(&app, "SELECT * FROM t WHERE id = $1").await;
+        let second = prepare(&app, "SELECT name FROM t WHERE id = $1").await;
+        assert_eq!(execute(&app, &first["statement_id"], json!([1])).await.0, StatusCode::OK);
+        let third = prepare(&app, "SELECT data FROM t WHERE id = $1").await;
+
+        assert_eq!(execute(&app, &second["statement_id"], json!([1])).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(execute(&app, &first["statement_id"], json!([1])).await.0, StatusCode::OK);
+        assert_eq!(execute(&app, &third["statement_id"], json!([1])).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_invalidated_by_alter_table() {
+        let (_dir, app) = app(16).await;
+        let select = prepare(&app, "SELECT * FROM t WHERE id = $1").await;
+        assert_eq!(execute(&app, &select["statement_id"], json!([1])).await.0, StatusCode::OK);
+
+        let (status, body) = post(&app, "/api/query", json!({ "sql": "ALTER TABLE t ADD COLUMN extra INT" })).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let (status, body) = execute(&app, &select["statement_id"], json!([1])).await;
+        assert_eq!((status, body["error"]["code"].as_str()), (StatusCode::CONFLICT, Some("statement_invalidated")));
+
+        // Prepared again, it sees the new column
+        let select = prepare(&app, "SELECT * FROM t WHERE id = $1").await;
+        let (status, body) = execute(&app, &select["statement_id"], json!([1])).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["result"]["columns"].as_array().unwrap().len(), 4);
+    }
+}
@@ -0,0 +1,1068 @@
+//! Binary protocol for clients that need lower latency than HTTP and JSON.
+//!
+//! A client opens a TCP connection and exchanges frames with the server.
+//! Every frame has the same layout, with integers big-endian:
+//!
+//! ```text
+//! length: u32 | message type: u8 | request id: u32 | payload
+//! ```
+//!
+//! `length` counts the bytes after itself, so it is at least 5. The server
+//! rejects a frame longer than its `max_frame_bytes` without reading it.
+//!
+//! The first frame a client sends is `HELLO` with the highest protocol
+//! version it speaks, and with `auth` configured one of the API tokens. The
+//! server answers `HELLO` with the version the connection will use, or
+//! `ERROR` if it speaks none of the client's or the token is not valid.
+//! The token's scope applies as on the HTTP API: a read-only token may run
+//! only statements that leave data and schema unchanged, and `GET`.
+//!
+//! After that the client may send requests without waiting for earlier
+//! ones to finish. The server handles them in order, in one session per
+//! connection, and answers each with a frame carrying the request's id.
+//! A request that fails is answered with `ERROR` and the connection stays
+//! open. A frame the server cannot read is answered with `ERROR`, carrying
+//! request id 0 if the id could not be read, and the connection is closed.
+//!
+//! | Request        | Payload                  | Response                   |
+//! |----------------|--------------------------|----------------------------|
+//! | `HELLO` 0x00   | version: u16, [token]    | `HELLO` 0x80, version: u16 |
+//! | `QUERY` 0x01   | sql                      | `RESULT` 0x81              |
+//! | `PREPARE` 0x02 | sql                      | `PREPARED` 0x82, id: u32   |
+//! | `EXECUTE` 0x03 | statement id: u32        | `RESULT` 0x81              |
+//! | `GET` 0x04     | key                      | `VALUE` 0x83               |
+//! | `PUT` 0x05     | key, value               | `DONE` 0x84                |
+//! | `DELETE` 0x06  | key                      | `DONE` 0x84                |
+//! | `PING` 0x07    |                          | `PONG` 0x85                |
+//!
+//! Strings and byte strings are a u32 length followed by their bytes. A
+//! `VALUE` payload is a u8 that is 1 if the key was found, then the value.
+//! `ERROR` 0xFF carries an error code and a message, both strings; the codes
+//! are those of the HTTP API plus the protocol's own (`malformed_frame`,
+//! `frame_too_large`, `unsupported_version`, `unknown_statement`,
+//! `invalid_key`, `too_many_connections`, `idle_timeout`).
+//!
+//! With `tls` configured, connections are TLS with the certificate of the
+//! HTTP listener, and a client that does not start a handshake gets no
+//! answer.
+//!
+//! `QUERY`, `EXECUTE`, `GET`, `PUT` and `DELETE` count against the rate
+//! limits and load shedding of the HTTP API, `QUERY` and `EXECUTE` as
+//! requests running SQL. The server takes at most `max_protocol_connections`
+//! connections, turning more away with `too_many_connections`, and closes
+//! one that sends nothing for `protocol_idle_timeout_ms`. A connection keeps
+//! at most `max_prepared_statements_per_client` prepared statements,
+//! forgetting the least recently used first.
+//!
+//! A `RESULT` payload is:
+//!
+//! ```text
+//! has rows affected: u8 | [rows affected: u64]
+//! column count: u16 | per column: name, type: u8
+//! row count: u32 | per row: each value in column order
+//! ```
+//!
+//! Column types are 0 when unknown, then BOOLEAN 1, INTEGER 2, FLOAT 3,
+//! TEXT 4, BLOB 5 and TIMESTAMP 6. Values use the tagged encoding of stored
+//! rows (`nextdb_query::encoding::encode_value`): the same type numbers, or
+//! 0 for NULL, followed by the value, whose integers are little-endian.
+//!
+//! Raw keys are those outside the SQL namespace; `GET`, `PUT` and `DELETE`
+//! of a key holding SQL data fail with `invalid_key`.
+
+use crate::auth::{AuthConfig, Scope};
+use crate::rate_limit::Client;
+use crate::server::{error_status, DatabaseState};
+use nextdb_query::{
+    ast::DataType,
+    encoding::{self, decode_value, encode_value},
+    ColumnMeta, ResultSet, SessionId, SqlParser, SqlStatement,
+};
+use nextdb_storage::WriteOp;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::TcpListener;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, warn};
+
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Bytes of a frame after its length that are not payload
+const HEADER_LEN: usize = 5;
+
+const HELLO: u8 = 0x00;
+const QUERY: u8 = 0x01;
+const PREPARE: u8 = 0x02;
+const EXECUTE: u8 = 0x03;
+const GET: u8 = 0x04;
+const PUT: u8 = 0x05;
+const DELETE: u8 = 0x06;
+const PING: u8 = 0x07;
+
+const HELLO_REPLY: u8 = 0x80;
+const RESULT: u8 = 0x81;
+const PREPARED: u8 = 0x82;
+const VALUE: u8 = 0x83;
+const DONE: u8 = 0x84;
+const PONG: u8 = 0x85;
+const ERROR: u8 = 0xFF;
+
+#[derive(Error, Debug)]
+pub enum ProtocolError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Frame of {length} bytes exceeds the limit of {max}")]
+    FrameTooLarge { length: usize, max: usize },
+
+    #[error("Malformed frame: {0}")]
+    Malformed(String),
+
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedVersion(u16),
+
+    #[error("Missing or invalid API token")]
+    Unauthorized,
+
+    #[error("The server already has the {0} connections it allows")]
+    TooManyConnections(usize),
+
+    #[error("Nothing was sent for {0:?}")]
+    IdleTimeout(Duration),
+}
+
+impl ProtocolError {
+    fn code(&self) -> &'static str {
+        match self {
+            ProtocolError::Io(_) => "io_error",
+            ProtocolError::FrameTooLarge { .. } => "frame_too_large",
+            ProtocolError::Malformed(_) => "malformed_frame",
+            ProtocolError::UnsupportedVersion(_) => "unsupported_version",
+            ProtocolError::Unauthorized => "unauthorized",
+            ProtocolError::TooManyConnections(_) => "too_many_connections",
+            ProtocolError::IdleTimeout(_) => "idle_timeout",
+        }
+    }
+}
+
+fn malformed(message: impl Into<String>) -> ProtocolError {
+    ProtocolError::Malformed(message.into())
+}
+
+/// One frame, with its message type still undecoded
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub message_type: u8,
+    pub request_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        let length = (HEADER_LEN + self.payload.len()) as u32;
+        let mut out = Vec::with_capacity(4 + length as usize);
+        out.extend_from_slice(&length.to_be_bytes());
+        out.push(self.message_type);
+        out.extend_from_slice(&self.request_id.to_be_bytes());
+        out.extend_from_slice(&self.payload);
+        out
+    }
+}
+
+/// Read the next frame, or None if the connection closed between frames
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max_frame_bytes: usize) -> Result<Option<Frame>, ProtocolError> {
+    let mut length = [0; 4];
+    match reader.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let length = u32::from_be_bytes(length) as usize;
+    if length > max_frame_bytes {
+        return Err(ProtocolError::FrameTooLarge { length, max: max_frame_bytes });
+    }
+    if length < HEADER_LEN {
+        return Err(malformed(format!("frame length {} is shorter than its header", length)));
+    }
+
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header).await?;
+    let mut payload = vec![0; length - HEADER_LEN];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(Frame {
+        message_type: header[0],
+        request_id: u32::from_be_bytes(header[1..].try_into().unwrap()),
+        payload,
+    }))
+}
+
+/// A message from client to server
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    /// With the API token to authenticate with, if any
+    Hello { version: u16, token: Option<String> },
+    Query { sql: String },
+    Prepare { sql: String },
+    Execute { statement: u32 },
+    Get { key: Vec<u8> },
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+    Ping,
+}
+
+impl Request {
+    pub fn to_frame(&self, request_id: u32) -> Frame {
+        let mut payload = Vec::new();
+        let message_type = match self {
+            Request::Hello { version, token } => {
+                payload.extend_from_slice(&version.to_be_bytes());
+                if let Some(token) = token {
+                    put_bytes(&mut payload, token.as_bytes());
+                }
+                HELLO
+            }
+            Request::Query { sql } => {
+                put_bytes(&mut payload, sql.as_bytes());
+                QUERY
+            }
+            Request::Prepare { sql } => {
+                put_bytes(&mut payload, sql.as_bytes());
+                PREPARE
+            }
+            Request::Execute { statement } => {
+                payload.extend_from_slice(&statement.to_be_bytes());
+                EXECUTE
+            }
+            Request::Get { key } => {
+                put_bytes(&mut payload, key);
+                GET
+            }
+            Request::Put { key, value } => {
+                put_bytes(&mut payload, key);
+                put_bytes(&mut payload, value);
+                PUT
+            }
+            Request::Delete { key } => {
+                put_bytes(&mut payload, key);
+                DELETE
+            }
+            Request::Ping => PING,
+        };
+        Frame { message_type, request_id, payload }
+    }
+
+    pub fn from_frame(frame: &Frame) -> Result<Self, ProtocolError> {
+        let mut reader = PayloadReader { bytes: &frame.payload, pos: 0 };
+        let request = match frame.message_type {
+            HELLO => Request::Hello {
+                version: u16::from_be_bytes(reader.array()?),
+                token: if reader.is_empty() { None } else { Some(reader.string()?) },
+            },
+            QUERY => Request::Query { sql: reader.string()? },
+            PREPARE => Request::Prepare { sql: reader.string()? },
+            EXECUTE => Request::Execute { statement: u32::from_be_bytes(reader.array()?) },
+            GET => Request::Get { key: reader.bytes()?.to_vec() },
+            PUT => Request::Put { key: reader.bytes()?.to_vec(), value: reader.bytes()?.to_vec() },
+            DELETE => Request::Delete { key: reader.bytes()?.to_vec() },
+            PING => Request::Ping,
+            other => return Err(malformed(format!("unknown request type {:#04x}", other))),
+        };
+        reader.finish()?;
+        Ok(request)
+    }
+}
+
+/// A message from server to client
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Hello { version: u16 },
+    Result(ResultSet),
+    Prepared { statement: u32 },
+    Value(Option<Vec<u8>>),
+    Done,
+    Pong,
+    Error { code: String, message: String },
+}
+
+impl Response {
+    fn error(code: &str, message: impl ToString) -> Self {
+        Response::Error { code: code.to_string(), message: message.to_string() }
+    }
+
+    pub fn to_frame(&self, request_id: u32) -> Frame {
+        let mut payload = Vec::new();
+        let message_type = match self {
+            Response::Hello { version } => {
+                payload.extend_from_slice(&version.to_be_bytes());
+                HELLO_REPLY
+            }
+            Response::Result(result) => {
+                encode_result(result, &mut payload);
+                RESULT
+            }
+            Response::Prepared { statement } => {
+                payload.extend_from_slice(&statement.to_be_bytes());
+                PREPARED
+            }
+            Response::Value(value) => {
+                payload.push(value.is_some() as u8);
+                if let Some(value) = value {
+                    put_bytes(&mut payload, value);
+                }
+                VALUE
+            }
+            Response::Done => DONE,
+            Response::Pong => PONG,
+            Response::Error { code, message } => {
+                put_bytes(&mut payload, code.as_bytes());
+                put_bytes(&mut payload, message.as_bytes());
+                ERROR
+            }
+        };
+        Frame { message_type, request_id, payload }
+    }
+
+    pub fn from_frame(frame: &Frame) -> Result<Self, ProtocolError> {
+        let mut reader = PayloadReader { bytes: &frame.payload, pos: 0 };
+        let response = match frame.message_type {
+            HELLO_REPLY => Response::Hello { version: u16::from_be_bytes(reader.array()?) },
+            RESULT => Response::Result(decode_result(&mut reader)?),
+            PREPARED => Response::Prepared { statement: u32::from_be_bytes(reader.array()?) },
+            VALUE => match reader.array::<1>()? {
+                [0] => Response::Value(None),
+                _ => Response::Value(Some(reader.bytes()?.to_vec())),
+            },
+            DONE => Response::Done,
+            PONG => Response::Pong,
+            ERROR => Response::Error { code: reader.string()?, message: reader.string()? },
+            other => return Err(malformed(format!("unknown response type {:#04x}", other))),
+        };
+        reader.finish()?;
+        Ok(response)
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn encode_result(result: &ResultSet, out: &mut Vec<u8>) {
+    match result.rows_affected {
+        Some(count) => {
+            out.push(1);
+            out.extend_from_slice(&count.to_be_bytes());
+        }
+        None => out.push(0),
+    }
+    out.extend_from_slice(&(result.columns.len() as u16).to_be_bytes());
+    for column in &result.columns {
+        put_bytes(out, column.name.as_bytes());
+        out.push(type_code(column.data_type));
+    }
+    out.extend_from_slice(&(result.rows.len() as u32).to_be_bytes());
+    for row in &result.rows {
+        for value in row {
+            encode_value(value, out);
+        }
+    }
+}
+
+fn decode_result(reader: &mut PayloadReader) -> Result<ResultSet, ProtocolError> {
+    let rows_affected = match reader.array::<1>()? {
+        [0] => None,
+        _ => Some(u64::from_be_bytes(reader.array()?)),
+    };
+    let column_count = u16::from_be_bytes(reader.array()?) as usize;
+    let mut columns = Vec::with_capacity(column_count);
+    for _ in 0..column_count {
+        let name = reader.string()?;
+        let [code] = reader.array()?;
+        columns.push(ColumnMeta::new(name, code_type(code)?));
+    }
+    let row_count = u32::from_be_bytes(reader.array()?);
+    let mut rows = Vec::new();
+    for _ in 0..row_count {
+        let mut row = Vec::with_capacity(column_count);
+        for _ in 0..column_count {
+            let (value, used) = decode_value(&reader.bytes[reader.pos..]).map_err(|e| malformed(e.to_string()))?;
+            reader.pos += used;
+            row.push(value);
+        }
+        rows.push(row);
+    }
+    Ok(ResultSet { columns, rows, rows_affected })
+}
+
+fn type_code(data_type: Option<DataType>) -> u8 {
+    match data_type {
+        None => 0,
+        Some(DataType::Boolean) => 1,
+        Some(DataType::Integer) => 2,
+        Some(DataType::Float) => 3,
+        Some(DataType::Text) => 4,
+        Some(DataType::Blob) => 5,
+        Some(DataType::Timestamp) => 6,
+    }
+}
+
+fn code_type(code: u8) -> Result<Option<DataType>, ProtocolError> {
+    Ok(match code {
+        0 => None,
+        1 => Some(DataType::Boolean),
+        2 => Some(DataType::Integer),
+        3 => Some(DataType::Float),
+        4 => Some(DataType::Text),
+        5 => Some(DataType::Blob),
+        6 => Some(DataType::Timestamp),
+        other => return Err(malformed(format!("unknown column type {}", other))),
+    })
+}
+
+struct PayloadReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PayloadReader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], ProtocolError> {
+        let bytes = self.pos.checked_add(length)
+            .and_then(|end| self.bytes.get(self.pos..end))
+            .ok_or_else(|| malformed("payload is truncated"))?;
+        self.pos += length;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ProtocolError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], ProtocolError> {
+        let length = u32::from_be_bytes(self.array()?) as usize;
+        self.take(length)
+    }
+
+    fn string(&mut self) -> Result<String, ProtocolError> {
+        let bytes = self.bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| malformed("string is not valid UTF-8"))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    fn finish(&self) -> Result<(), ProtocolError> {
+        if self.pos != self.bytes.len() {
+            return Err(malformed(format!("{} unexpected bytes after payload", self.bytes.len() - self.pos)));
+        }
+        Ok(())
+    }
+}
+
+/// What the listener takes from the server config
+pub(crate) struct Settings {
+    /// Tokens `HELLO` must carry one of, or None to trust every client
+    pub(crate) auth: Option<Arc<AuthConfig>>,
+    pub(crate) max_frame_bytes: usize,
+    pub(crate) max_connections: usize,
+    pub(crate) idle_timeout: Duration,
+    /// Most prepared statements one connection keeps
+    pub(crate) max_statements: usize,
+    /// Accepts TLS connections with the HTTP listener's certificate, or
+    /// None for plain TCP
+    pub(crate) tls: Option<TlsAcceptor>,
+}
+
+/// Accept protocol connections on `listener` until it fails
+pub(crate) async fn serve(listener: TcpListener, state: Arc<DatabaseState>, settings: Settings) -> io::Result<()> {
+    let settings = Arc::new(settings);
+    let connections = Arc::new(Semaphore::new(settings.max_connections));
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let state = state.clone();
+        let settings = settings.clone();
+        let permit = connections.clone().try_acquire_owned();
+        tokio::spawn(async move {
+            let result = match (stream.set_nodelay(true), &settings.tls) {
+                (Err(e), _) => Err(e.into()),
+                (Ok(()), None) => handle_connection(stream, peer, state, settings, permit).await,
+                (Ok(()), Some(acceptor)) => match acceptor.accept(stream).await {
+                    Ok(stream) => handle_connection(stream, peer, state, settings, permit).await,
+                    Err(e) => Err(e.into()),
+                },
+            };
+            if let Err(e) = result {
+                debug!("Protocol connection from {} closed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    peer: SocketAddr,
+    state: Arc<DatabaseState>,
+    settings: Arc<Settings>,
+    permit: Result<OwnedSemaphorePermit, TryAcquireError>,
+) -> Result<(), ProtocolError> {
+    let Ok(_permit) = permit else {
+        return reject(&mut stream, 0, ProtocolError::TooManyConnections(settings.max_connections)).await;
+    };
+    let (reader, writer) = tokio::io::split(stream);
+    let mut connection = Connection {
+        session: state.new_session(),
+        state,
+        settings,
+        scope: Scope::Admin,
+        client: Client::Address(peer.ip()),
+        statements: VecDeque::new(),
+        next_statement: 1,
+    };
+    let result = connection.run(&mut BufReader::new(reader), &mut BufWriter::new(writer)).await;
+    if let Err(e) = connection.state.executor.end_session(connection.session).await {
+        warn!("Failed to end protocol session: {}", e);
+    }
+    result
+}
+
+/// Load shedding and rate limiting slots a request holds until it is
+/// answered
+type Admitted = (Option<OwnedSemaphorePermit>, Option<OwnedSemaphorePermit>);
+
+struct Connection {
+    state: Arc<DatabaseState>,
+    settings: Arc<Settings>,
+    session: SessionId,
+    /// Of the token given in `HELLO`, or admin without `auth`
+    scope: Scope,
+    /// Who the rate limits count the connection's requests against
+    client: Client,
+    /// Statements prepared on this connection with their ids, the least
+    /// recently used first
+    statements: VecDeque<(u32, SqlStatement)>,
+    next_statement: u32,
+}
+
+impl Connection {
+    async fn run<R, W>(&mut self, reader: &mut BufReader<R>, writer: &mut W) -> Result<(), ProtocolError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let settings = self.settings.clone();
+        let mut version = None;
+        loop {
+            let read = tokio::time::timeout(settings.idle_timeout, read_frame(reader, settings.max_frame_bytes)).await;
+            let (request_id, request) = match read {
+                Err(_) => return reject(writer, 0, ProtocolError::IdleTimeout(settings.idle_timeout)).await,
+                Ok(Ok(None)) => return Ok(()),
+                Ok(Ok(Some(frame))) => match Request::from_frame(&frame) {
+                    Ok(request) => (frame.request_id, request),
+                    Err(e) => return reject(writer, frame.request_id, e).await,
+                },
+                Ok(Err(ProtocolError::Io(e))) => return Err(e.into()),
+                Ok(Err(e)) => return reject(writer, 0, e).await,
+            };
+
+            let response = match (request, version) {
+                (Request::Hello { version: requested, token }, None) => {
+                    if requested == 0 {
+                        return reject(writer, request_id, ProtocolError::UnsupportedVersion(requested)).await;
+                    }
+                    if let Some(auth) = &settings.auth {
+                        let Some(token) = token.as_deref().and_then(|token| auth.authenticate_connection(token)) else {
+                            return reject(writer, request_id, ProtocolError::Unauthorized).await;
+                        };
+                        self.scope = token.scope;
+                        self.client = Client::Token(token.name.clone());
+                    }
+                    let agreed = requested.min(PROTOCOL_VERSION);
+                    version = Some(agreed);
+                    Response::Hello { version: agreed }
+                }
+                (_, None) => return reject(writer, request_id, malformed("expected HELLO first")).await,
+                (Request::Hello { .. }, Some(_)) => {
+                    return reject(writer, request_id, malformed("HELLO sent twice")).await;
+                }
+                (request, Some(_)) => self.handle(request).await,
+            };
+
+            writer.write_all(&response.to_frame(request_id).encode()).await?;
+            // Answer pipelined requests in one write once none are waiting
+            if reader.buffer().is_empty() {
+                writer.flush().await?;
+            }
+        }
+    }
+
+    async fn handle(&mut self, request: Request) -> Response {
+        match request {
+            Request::Hello { .. } => unreachable!("handled by the connection"),
+            Request::Query { sql } => {
+                let started = Instant::now();
+                match SqlParser::parse(&sql) {
+                    Ok(statement) => self.execute(statement, started).await,
+                    Err(e) => {
+                        self.state.executor.fail_transaction(self.session).await;
+                        self.state.query_metrics.record(started.elapsed(), false);
+                        Response::error(error_status(&e).1, e)
+                    }
+                }
+            }
+            Request::Prepare { sql } => match SqlParser::parse(&sql) {
+                Ok(statement) => Response::Prepared { statement: self.prepare(statement) },
+                Err(e) => Response::error(error_status(&e).1, e),
+            },
+            Request::Execute { statement: id } => match self.prepared(id) {
+                Some(statement) => self.execute(statement, Instant::now()).await,
+                None => Response::error("unknown_statement", format!("no prepared statement {}, or it was forgotten", id)),
+            },
+            Request::Get { key } => {
+                if let Err(response) = check_key(&key) {
+                    return response;
+                }
+                let _admitted = match self.admit(0, false).await {
+                    Ok(admitted) => admitted,
+                    Err(response) => return response,
+                };
+                match self.state.storage.get(&key).await {
+                    Ok(value) => Response::Value(value),
+                    Err(e) => Response::error("storage_error", e),
+                }
+            }
+            Request::Put { key, value } => match check_key(&key) {
+                Err(response) => response,
+                Ok(()) => self.write(WriteOp::Put { key, value }).await,
+            },
+            Request::Delete { key } => match check_key(&key) {
+                Err(response) => response,
+                Ok(()) => self.write(WriteOp::Delete { key }).await,
+            },
+            Request::Ping => Response::Pong,
+        }
+    }
+
+    /// Run `statement` in the connection's session, if its token allows
+    async fn execute(&self, statement: SqlStatement, started: Instant) -> Response {
+        if !self.scope.allows(&statement) {
+            return Response::error("read_only_token", "the API token is read-only and the query writes");
+        }
+        let _admitted = match self.admit(u32::from(!statement.is_read_only()), true).await {
+            Ok(admitted) => admitted,
+            Err(response) => return response,
+        };
+        let result = self.state.executor.execute_statement_in(self.session, statement).await;
+        self.state.query_metrics.record(started.elapsed(), result.is_ok());
+        query_response(result)
+    }
+
+    async fn write(&self, op: WriteOp) -> Response {
+        if self.scope == Scope::ReadOnly {
+            return Response::error("read_only_token", "the API token is read-only and the request writes");
+        }
+        let _admitted = match self.admit(1, false).await {
+            Ok(admitted) => admitted,
+            Err(response) => return response,
+        };
+        match self.state.executor.write(vec![op]).await {
+            Ok(()) => Response::Done,
+            Err(e) => Response::error(error_status(&e).1, e),
+        }
+    }
+
+    /// Take the slots a request needs under the server's load shedding and
+    /// rate limits, as one running SQL if `runs_sql`, counting `writes`
+    /// against the client's write limit
+    async fn admit(&self, writes: u32, runs_sql: bool) -> Result<Admitted, Response> {
+        let state = &self.state;
+        let shed = match &state.load_shedder {
+            Some(shedder) if runs_sql => Some(shedder.admit_statement().await),
+            Some(shedder) => Some(shedder.admit_request().await),
+            None => None,
+        };
+        let shed = shed.transpose().map_err(|shed| Response::error(shed.code, shed.message))?;
+        let limited = state.rate_limiter.as_ref().map(|limiter| {
+            limiter.admit_request(&self.client)?;
+            limiter.admit_batch(&self.client, writes)
+        });
+        let limited = limited.transpose().map_err(|limited| Response::error(limited.code, limited.message))?;
+        Ok((shed, limited))
+    }
+
+    /// Keep `statement` under a new id, forgetting the least recently used
+    /// statement if the connection holds as many as it may
+    fn prepare(&mut self, statement: SqlStatement) -> u32 {
+        if self.statements.len() >= self.settings.max_statements {
+            self.statements.pop_front();
+        }
+        let id = self.next_statement;
+        self.next_statement += 1;
+        self.statements.push_back((id, statement));
+        id
+    }
+
+    /// The statement prepared as `id`, marked as just used
+    fn prepared(&mut self, id: u32) -> Option<SqlStatement> {
+        let position = self.statements.iter().position(|(prepared, _)| *prepared == id)?;
+        let prepared = self.statements.remove(position).expect("position is in range");
+        let statement = prepared.1.clone();
+        self.statements.push_back(prepared);
+        Some(statement)
+    }
+}
+
+fn query_response(result: nextdb_query::Result<ResultSet>) -> Response {
+    match result {
+        Ok(result) => Response::Result(result),
+        Err(e) => Response::error(error_status(&e).1, e),
+    }
+}
+
+fn check_key(key: &[u8]) -> Result<(), Response> {
+    if encoding::is_sql_key(key) {
+        return Err(Response::error("invalid_key", "key is in the SQL namespace"));
+    }
+    Ok(())
+}
+
+/// Answer a frame that could not be handled with an error and close the
+/// connection
+async fn reject<W: AsyncWrite + Unpin>(writer: &mut W, request_id: u32, error: ProtocolError) -> Result<(), ProtocolError> {
+    let response = Response::error(error.code(), &error);
+    writer.write_all(&response.to_frame(request_id).encode()).await?;
+    writer.flush().await?;
+    Err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use crate::{ApiToken, DatabaseServer, RateLimitConfig, ServerConfig, TlsConfig};
+    use nextdb_query::Value;
+    use rustls::pki_types::ServerName;
+    use std::net::SocketAddr;
+    use tempfile::TempDir;
+    use tokio::net::TcpStream;
+
+    /// A bare client that writes frames and reads them back one at a time
+    struct TestClient {
+        stream: TcpStream,
+    }
+
+    impl TestClient {
+        async fn connect(addr: SocketAddr) -> Self {
+            Self { stream: TcpStream::connect(addr).await.unwrap() }
+        }
+
+        async fn handshake(addr: SocketAddr) -> Self {
+            let mut client = Self::connect(addr).await;
+            client.send(&[hello(None).to_frame(0)]).await;
+            assert_eq!(client.receive().await, Some((0, Response::Hello { version: PROTOCOL_VERSION })));
+            client
+        }
+
+        /// Send `request` and wait for its answer
+        async fn request(&mut self, request: Request) -> Response {
+            self.send(&[request.to_frame(1)]).await;
+            self.receive().await.unwrap().1
+        }
+
+        async fn send(&mut self, frames: &[Frame]) {
+            let bytes: Vec<u8> = frames.iter().flat_map(Frame::encode).collect();
+            self.send_raw(&bytes).await;
+        }
+
+        async fn send_raw(&mut self, bytes: &[u8]) {
+            self.stream.write_all(bytes).await.unwrap();
+        }
+
+        async fn receive(&mut self) -> Option<(u32, Response)> {
+            let frame = read_frame(&mut self.stream, usize::MAX).await.unwrap()?;
+            Some((frame.request_id, Response::from_frame(&frame).unwrap()))
+        }
+
+        /// Expect an error with `code`, then the server closing the connection
+        async fn expect_rejected(&mut self, code: &str) {
+            match self.receive().await {
+                Some((_, Response::Error { code: actual, .. })) => assert_eq!(actual, code),
+                other => panic!("expected {} error, got {:?}", code, other),
+            }
+            // Closed, or reset if the server left some of our bytes unread
+            match read_frame(&mut self.stream, usize::MAX).await {
+                Ok(None) | Err(ProtocolError::Io(_)) => {}
+                Ok(Some(frame)) => panic!("expected the connection to close, got {:?}", frame),
+                Err(e) => panic!("expected the connection to close, got {}", e),
+            }
+        }
+    }
+
+    fn hello(token: Option<&str>) -> Request {
+        Request::Hello { version: PROTOCOL_VERSION, token: token.map(str::to_string) }
+    }
+
+    fn error_code(response: &Response) -> &str {
+        match response {
+            Response::Error { code, .. } => code,
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    async fn start(temp_dir: &TempDir, max_frame_bytes: usize) -> SocketAddr {
+        listen(ServerConfig { max_frame_bytes, ..test_util::config(temp_dir) }).await
+    }
+
+    async fn listen(config: ServerConfig) -> SocketAddr {
+        let server = DatabaseServer::with_config(config).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { server.serve_protocol(listener).await });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = start(&temp_dir, 1 << 20).await;
+        let mut client = TestClient::handshake(addr).await;
+
+        // Everything is sent before any answer is read
+        let requests = [
+            Request::Put { key: b"greeting".to_vec(), value: b"hello".to_vec() },
+            Request::Query { sql: "CREATE TABLE t (id INT PRIMARY KEY, name TEXT, at TIMESTAMP)".to_string() },
+            Request::Query { sql: "INSERT INTO t VALUES (1, 'one', '2024-01-02T03:04:05Z'), (2, NULL, NULL)".to_string() },
+            Request::Prepare { sql: "SELECT id, name, at FROM t ORDER BY id".to_string() },
+            Request::Get { key: b"greeting".to_vec() },
+            Request::Execute { statement: 1 },
+            Request::Query { sql: "SELECT * FROM missing".to_string() },
+            Request::Execute { statement: 9 },
+            Request::Get { key: encoding::catalog_key("t") },
+            Request::Delete { key: b"greeting".to_vec() },
+            Request::Get { key: b"greeting".to_vec() },
+            Request::Ping,
+        ];
+        let frames: Vec<Frame> = requests.iter().enumerate()
+            .map(|(i, request)| request.to_frame(100 + i as u32))
+            .collect();
+        client.send(&frames).await;
+
+        let mut responses = Vec::new();
+        for _ in 0..requests.len() {
+            responses.push(client.receive().await.unwrap());
+        }
+        let ids: Vec<u32> = responses.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, (100..100 + requests.len() as u32).collect::<Vec<_>>());
+
+        let responses: Vec<Response> = responses.into_iter().map(|(_, response)| response).collect();
+        assert_eq!(responses[0], Response::Done);
+        assert!(matches!(&responses[2], Response::Result(result) if result.rows_affected == Some(2)));
+        assert_eq!(responses[3], Response::Prepared { statement: 1 });
+        assert_eq!(responses[4], Response::Value(Some(b"hello".to_vec())));
+
+        let Response::Result(result) = &responses[5] else { panic!("expected a result, got {:?}", responses[5]) };
+        assert_eq!(result.column_names(), vec!["id", "name", "at"]);
+        assert_eq!(result.columns[2].data_type, Some(DataType::Timestamp));
+        assert_eq!(result.rows, vec![
+            vec![Value::Integer(1), Value::Text("one".to_string()), Value::Timestamp(1_704_164_645_000_000)],
+            vec![Value::Integer(2), Value::Null, Value::Null],
+        ]);
+
+        // Failed requests are answered and the connection carries on
+        let codes: Vec<&str> = responses[6..9].iter()
+            .map(|response| match response {
+                Response::Error { code, .. } => code.as_str(),
+                other => panic!("expected an error, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(codes, vec!["table_not_found", "unknown_statement", "invalid_key"]);
+        assert_eq!(responses[9..], [Response::Done, Response::Value(None), Response::Pong]);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_rejected() {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = start(&temp_dir, 1024).await;
+
+        let mut client = TestClient::handshake(addr).await;
+        let sql = format!("SELECT '{}'", "x".repeat(2000));
+        client.send(&[Request::Query { sql }.to_frame(1)]).await;
+        client.expect_rejected("frame_too_large").await;
+
+        // A length alone is enough to be turned away
+        let mut client = TestClient::handshake(addr).await;
+        client.send_raw(&u32::MAX.to_be_bytes()).await;
+        client.expect_rejected("frame_too_large").await;
+
+        let mut client = TestClient::handshake(addr).await;
+        client.send(&[Request::Ping.to_frame(2)]).await;
+        assert_eq!(client.receive().await, Some((2, Response::Pong)));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_frames_close_connection() {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = start(&temp_dir, 1 << 20).await;
+
+        let truncated = Frame { message_type: PUT, request_id: 1, payload: vec![0, 0, 0, 9, b'k'] };
+        let trailing = Frame { message_type: PING, request_id: 1, payload: vec![0] };
+        let bad_utf8 = Frame { message_type: QUERY, request_id: 1, payload: vec![0, 0, 0, 2, 0xC3, 0x28] };
+        let unknown = Frame { message_type: 0x42, request_id: 1, payload: Vec::new() };
+        let hello_again = hello(None).to_frame(1);
+        for frame in [truncated, trailing, bad_utf8, unknown, hello_again] {
+            let mut client = TestClient::handshake(addr).await;
+            client.send(std::slice::from_ref(&frame)).await;
+            client.expect_rejected("malformed_frame").await;
+        }
+
+        // A length too short to hold the header
+        let mut client = TestClient::handshake(addr).await;
+        client.send_raw(&[0, 0, 0, 2, PING, 0]).await;
+        client.expect_rejected("malformed_frame").await;
+
+        // Requests before the handshake, or for no version the server speaks
+        let mut client = TestClient::connect(addr).await;
+        client.send(&[Request::Ping.to_frame(1)]).await;
+        client.expect_rejected("malformed_frame").await;
+        let mut client = TestClient::connect(addr).await;
+        client.send(&[Request::Hello { version: 0, token: None }.to_frame(1)]).await;
+        client.expect_rejected("unsupported_version").await;
+
+        // Newer clients are offered this server's version
+        let mut client = TestClient::connect(addr).await;
+        client.send(&[Request::Hello { version: PROTOCOL_VERSION + 1, token: None }.to_frame(7)]).await;
+        assert_eq!(client.receive().await, Some((7, Response::Hello { version: PROTOCOL_VERSION })));
+        client.send(&[Request::Ping.to_frame(8)]).await;
+        assert_eq!(client.receive().await, Some((8, Response::Pong)));
+    }
+
+    #[tokio::test]
+    async fn test_token_scopes() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut tokens = ApiToken::parse_list("reader:ro:read-token, writer:rw:write-token, other:rw:other-token").unwrap();
+        tokens[2].databases = Some(vec!["other".to_string()]);
+        let addr = listen(ServerConfig {
+            auth: Some(AuthConfig { tokens }),
+            ..test_util::config(&temp_dir)
+        }).await;
+
+        // No token, a wrong one, or one kept from the default database
+        for token in [None, Some("wrong-token"), Some("other-token")] {
+            let mut client = TestClient::connect(addr).await;
+            client.send(&[hello(token).to_frame(1)]).await;
+            client.expect_rejected("unauthorized").await;
+        }
+
+        let mut writer = TestClient::connect(addr).await;
+        writer.send(&[hello(Some("write-token")).to_frame(0)]).await;
+        assert_eq!(writer.receive().await, Some((0, Response::Hello { version: PROTOCOL_VERSION })));
+        let create = Request::Query { sql: "CREATE TABLE t (id INT PRIMARY KEY)".to_string() };
+        assert!(matches!(writer.request(create).await, Response::Result(_)));
+        let put = Request::Put { key: b"k".to_vec(), value: b"v".to_vec() };
+        assert_eq!(writer.request(put.clone()).await, Response::Done);
+
+        // A read-only token reads, and every way to write is refused
+        let mut reader = TestClient::connect(addr).await;
+        reader.send(&[hello(Some("read-token")).to_frame(0)]).await;
+        assert_eq!(reader.receive().await, Some((0, Response::Hello { version: PROTOCOL_VERSION })));
+        let insert = "INSERT INTO t VALUES (1)".to_string();
+        assert_eq!(error_code(&reader.request(Request::Query { sql: insert.clone() }).await), "read_only_token");
+        assert_eq!(reader.request(Request::Prepare { sql: insert }).await, Response::Prepared { statement: 1 });
+        assert_eq!(error_code(&reader.request(Request::Execute { statement: 1 }).await), "read_only_token");
+        assert_eq!(error_code(&reader.request(put).await), "read_only_token");
+        assert_eq!(error_code(&reader.request(Request::Delete { key: b"k".to_vec() }).await), "read_only_token");
+        assert_eq!(reader.request(Request::Get { key: b"k".to_vec() }).await, Response::Value(Some(b"v".to_vec())));
+        let Response::Result(result) = reader.request(Request::Query { sql: "SELECT id FROM t".to_string() }).await else {
+            panic!("expected a result");
+        };
+        assert!(result.rows.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connection_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let limits = RateLimitConfig { writes_per_second: 0.001, write_burst: 1, ..RateLimitConfig::default() };
+        let addr = listen(ServerConfig {
+            max_protocol_connections: 1,
+            protocol_idle_timeout_ms: 300,
+            max_prepared_statements_per_client: 2,
+            rate_limit: Some(limits),
+            ..test_util::config(&temp_dir)
+        }).await;
+
+        let mut client = TestClient::handshake(addr).await;
+        TestClient::connect(addr).await.expect_rejected("too_many_connections").await;
+
+        // The least recently used statement is forgotten past the limit
+        for id in 1..=3 {
+            let prepare = Request::Prepare { sql: format!("SELECT {}", id) };
+            assert_eq!(client.request(prepare).await, Response::Prepared { statement: id });
+        }
+        assert_eq!(error_code(&client.request(Request::Execute { statement: 1 }).await), "unknown_statement");
+        assert!(matches!(client.request(Request::Execute { statement: 2 }).await, Response::Result(_)));
+        let prepare = Request::Prepare { sql: "SELECT 4".to_string() };
+        assert_eq!(client.request(prepare).await, Response::Prepared { statement: 4 });
+        assert_eq!(error_code(&client.request(Request::Execute { statement: 3 }).await), "unknown_statement");
+        assert!(matches!(client.request(Request::Execute { statement: 2 }).await, Response::Result(_)));
+
+        // Writes count against the client's rate limit
+        let put = Request::Put { key: b"k".to_vec(), value: b"v".to_vec() };
+        assert_eq!(client.request(put.clone()).await, Response::Done);
+        assert_eq!(error_code(&client.request(put).await), "write_rate_limited");
+
+        // An idle connection is closed, making room for another once its
+        // session has ended
+        client.expect_rejected("idle_timeout").await;
+        for attempt in 0.. {
+            let mut client = TestClient::connect(addr).await;
+            client.send(&[hello(None).to_frame(0)]).await;
+            match read_frame(&mut client.stream, usize::MAX).await {
+                Ok(Some(frame)) if frame.message_type == HELLO_REPLY => break,
+                _ if attempt < 50 => tokio::time::sleep(Duration::from_millis(10)).await,
+                other => panic!("still turned away: {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        let temp_dir = TempDir::new().unwrap();
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let tls = TlsConfig {
+            cert_path: temp_dir.path().join("server.crt"),
+            key_path: temp_dir.path().join("server.key"),
+            client_ca_path: None,
+        };
+        std::fs::write(&tls.cert_path, generated.cert.pem()).unwrap();
+        std::fs::write(&tls.key_path, generated.key_pair.serialize_pem()).unwrap();
+        let addr = listen(ServerConfig { tls: Some(tls), ..test_util::config(&temp_dir) }).await;
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(generated.cert.der().clone()).unwrap();
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector.connect(ServerName::try_from("localhost").unwrap(), stream).await.unwrap();
+        let frames = [hello(None).to_frame(0), Request::Ping.to_frame(1)];
+        stream.write_all(&frames.iter().flat_map(Frame::encode).collect::<Vec<u8>>()).await.unwrap();
+        stream.flush().await.unwrap();
+        for expected in [Response::Hello { version: PROTOCOL_VERSION }, Response::Pong] {
+            let frame = read_frame(&mut stream, usize::MAX).await.unwrap().unwrap();
+            assert_eq!(Response::from_frame(&frame).unwrap(), expected);
+        }
+
+        // Frames sent in plaintext are never answered
+        let mut client = TestClient::connect(addr).await;
+        client.send(&[hello(None).to_frame(0)]).await;
+        assert!(!matches!(read_frame(&mut client.stream, 1 << 20).await, Ok(Some(_))));
+    }
+}
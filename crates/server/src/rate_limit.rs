@@ -0,0 +1,348 @@
+//! Per-client rate limiting for the HTTP API.
+//!
+//! Each client, known by its API token or else by its IP address, gets a
+//! token bucket for requests and a smaller one for statements that write.
+//! A request over either limit gets a 429 with a `Retry-After` header, and
+//! does not count against the client. Across all clients at most
+//! `max_concurrent_queries` queries run at once.
+
+use crate::{auth::TokenName, server::DatabaseState};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use nextdb_query::SqlStatement;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Requests each client may make per second, sustained
+    pub requests_per_second: f64,
+    /// Requests a client that has been idle may make at once
+    pub request_burst: u32,
+    /// Statements that write each client may run per second, sustained
+    pub writes_per_second: f64,
+    pub write_burst: u32,
+    /// Queries running at once across all clients
+    pub max_concurrent_queries: usize,
+    /// Clients whose limits are tracked; beyond this the one idle longest
+    /// is forgotten, and starts again with full buckets
+    pub max_clients: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_second: 100.0,
+            request_burst: 200,
+            writes_per_second: 20.0,
+            write_burst: 40,
+            max_concurrent_queries: 64,
+            max_clients: 10_000,
+        }
+    }
+}
+
+/// Who a request is counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Client {
+    /// Name of the API token the request carried
+    Token(String),
+    Address(IpAddr),
+    /// Neither is known, as for requests not from a socket
+    Unknown,
+}
+
+impl Client {
+    fn of(request: &Request) -> Self {
+        let extensions = request.extensions();
+        if let Some(TokenName(name)) = extensions.get::<TokenName>() {
+            return Client::Token(name.clone());
+        }
+        match extensions.get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(address)) => Client::Address(address.ip()),
+            None => Client::Unknown,
+        }
+    }
+}
+
+/// Why a request was turned away, and when to try again
+#[derive(Debug)]
+pub(crate) struct Limited {
+    pub(crate) code: &'static str,
+    pub(crate) message: &'static str,
+    retry_after: Duration,
+}
+
+impl IntoResponse for Limited {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({
+            "success": false,
+            "retryable": true,
+            "error": { "code": self.code, "message": self.message },
+        });
+        // Whole seconds, rounded up so a retry at that time succeeds
+        let seconds = self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0);
+        let headers = [(header::RETRY_AFTER, seconds.max(1).to_string())];
+        (StatusCode::TOO_MANY_REQUESTS, headers, Json(body)).into_response()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn full(capacity: u32, now: Instant) -> Self {
+        Self { tokens: capacity as f64, refilled: now }
+    }
+
+    /// Take `count` tokens, or say how long until there is one. With a
+    /// token left the bucket gives all `count`, going into debt that later
+    /// takes wait out, so a large batch is not refused forever.
+    fn take(&mut self, rate: f64, capacity: u32, count: u32, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity as f64);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= count as f64;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+}
+
+struct ClientState {
+    requests: Bucket,
+    writes: Bucket,
+    last_seen: Instant,
+}
+
+pub(crate) struct RateLimiter {
+    config: RwLock<RateLimitConfig>,
+    clients: Mutex<HashMap<Client, ClientState>>,
+    queries: RwLock<Arc<Semaphore>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        let queries = RwLock::new(Arc::new(Semaphore::new(config.max_concurrent_queries)));
+        Self { config: RwLock::new(config), clients: Mutex::new(HashMap::new()), queries }
+    }
+
+    /// Apply `config` to requests from now on. Clients keep the tokens in
+    /// their buckets, and queries already running hold their slots until
+    /// they finish without counting against the new `max_concurrent_queries`.
+    pub(crate) fn reconfigure(&self, config: RateLimitConfig) {
+        let mut current = self.config.write().unwrap();
+        if config.max_concurrent_queries != current.max_concurrent_queries {
+            *self.queries.write().unwrap() = Arc::new(Semaphore::new(config.max_concurrent_queries));
+        }
+        *current = config;
+    }
+
+    /// Count a request from `client`
+    pub(crate) fn admit_request(&self, client: &Client) -> Result<(), Limited> {
+        let config = self.config.read().unwrap().clone();
+        self.take(client, |state, now| state.requests.take(config.requests_per_second, config.request_burst, 1, now))
+            .map_err(|retry_after| Limited {
+                code: "rate_limited",
+                message: "too many requests from this client",
+                retry_after,
+            })
+    }
+
+    /// Count `statement` from `client` against the write limit if it
+    /// writes, and hold one of the concurrent query slots while it runs
+    pub(crate) fn admit_query(&self, client: &Client, statement: &SqlStatement) -> Result<OwnedSemaphorePermit, Limited> {
+        self.admit_batch(client, u32::from(!statement.is_read_only()))
+    }
+
+    /// Like `admit_query` for a batch of statements, `writes` of which
+    /// write. The batch holds a single query slot.
+    pub(crate) fn admit_batch(&self, client: &Client, writes: u32) -> Result<OwnedSemaphorePermit, Limited> {
+        let permit = self.queries.read().unwrap().clone().try_acquire_owned().map_err(|_| Limited {
+            code: "too_many_queries",
+            message: "the server is running as many queries as it allows",
+            retry_after: Duration::from_secs(1),
+        })?;
+        if writes > 0 {
+            let config = self.config.read().unwrap().clone();
+            self.take(client, |state, now| state.writes.take(config.writes_per_second, config.write_burst, writes, now))
+                .map_err(|retry_after| Limited {
+                    code: "write_rate_limited",
+                    message: "too many writes from this client",
+                    retry_after,
+                })?;
+        }
+        Ok(permit)
+    }
+
+    fn take(
+        &self,
+        client: &Client,
+        take: impl FnOnce(&mut ClientState, Instant) -> Result<(), Duration>,
+    ) -> std::result::Result<(), Duration> {
+        let now = Instant::now();
+        let config = self.config.read().unwrap().clone();
+        let mut clients = self.clients.lock().unwrap();
+        if !clients.contains_key(client) && clients.len() >= config.max_clients {
+            let idlest = clients.iter().min_by_key(|(_, state)| state.last_seen).map(|(client, _)| client.clone());
+            if let Some(idlest) = idlest {
+                clients.remove(&idlest);
+            }
+        }
+        let state = clients.entry(client.clone()).or_insert_with(|| ClientState {
+            requests: Bucket::full(config.request_burst, now),
+            writes: Bucket::full(config.write_burst, now),
+            last_seen: now,
+        });
+        state.last_seen = now;
+        take(state, now)
+    }
+
+    #[cfg(test)]
+    fn tracked_clients(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+}
+
+/// Middleware for the `/api/*` routes, after authentication. Counts each
+/// request against its client and passes the client on to handlers as an
+/// extension.
+pub(crate) async fn limit_requests(
+    State(state): State<Arc<DatabaseState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(limiter) = &state.rate_limiter {
+        let client = Client::of(&request);
+        if let Err(limited) = limiter.admit_request(&client) {
+            return limited.into_response();
+        }
+        request.extensions_mut().insert(client);
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, json, json_request, send};
+    use crate::{ApiToken, AuthConfig, DatabaseServer, ServerConfig};
+    use axum::{body::Body, http::Method, Router};
+    use nextdb_query::SqlParser;
+    use tempfile::TempDir;
+
+    async fn app(temp_dir: &TempDir, limits: RateLimitConfig, auth: Option<AuthConfig>) -> Router {
+        let config = ServerConfig {
+            rate_limit: Some(limits),
+            auth,
+            ..test_util::config(temp_dir)
+        };
+        DatabaseServer::with_config(config).await.unwrap().router()
+    }
+
+    async fn query(app: &Router, from: &str, token: Option<&str>, sql: &str) -> (StatusCode, Option<String>, serde_json::Value) {
+        let address: SocketAddr = format!("{}:5000", from).parse().unwrap();
+        let mut request = json_request(Method::POST, "/api/query").extension(ConnectInfo(address));
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = request.body(Body::from(serde_json::json!({ "sql": sql }).to_string())).unwrap();
+        let response = send(app, request).await;
+        let retry_after = response.headers().get(header::RETRY_AFTER).map(|value| value.to_str().unwrap().to_string());
+        let (status, body) = json(response).await;
+        (status, retry_after, body)
+    }
+
+    #[tokio::test]
+    async fn test_request_limit_per_address() {
+        let temp_dir = TempDir::new().unwrap();
+        let limits = RateLimitConfig { requests_per_second: 0.01, request_burst: 3, ..RateLimitConfig::default() };
+        let app = app(&temp_dir, limits, None).await;
+
+        // A burst up to the limit is served, and the next request is not
+        for _ in 0..3 {
+            let (status, _, body) = query(&app, "10.0.0.1", None, "SELECT 1").await;
+            assert_eq!(status, StatusCode::OK, "{}", body);
+        }
+        let (status, retry_after, body) = query(&app, "10.0.0.1", None, "SELECT 1").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["error"]["code"], "rate_limited");
+        assert_eq!(body["retryable"], true);
+        assert!(retry_after.unwrap().parse::<u64>().unwrap() >= 90);
+
+        // Other clients are unaffected
+        for _ in 0..3 {
+            assert_eq!(query(&app, "10.0.0.2", None, "SELECT 1").await.0, StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_limit_per_token() {
+        let temp_dir = TempDir::new().unwrap();
+        let limits = RateLimitConfig { writes_per_second: 0.01, write_burst: 2, ..RateLimitConfig::default() };
+        let tokens = ApiToken::parse_list("loader:rw:load-token,admin:rw:admin-token").unwrap();
+        let app = app(&temp_dir, limits, Some(AuthConfig { tokens })).await;
+
+        let (status, _, body) = query(&app, "10.0.0.1", Some("admin-token"), "CREATE TABLE t (id INT PRIMARY KEY)").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        for id in 0..2 {
+            let (status, _, body) = query(&app, "10.0.0.1", Some("load-token"), &format!("INSERT INTO t VALUES ({})", id)).await;
+            assert_eq!(status, StatusCode::OK, "{}", body);
+        }
+        let (status, retry_after, body) = query(&app, "10.0.0.1", Some("load-token"), "INSERT INTO t VALUES (2)").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["error"]["code"], "write_rate_limited");
+        assert!(retry_after.is_some());
+
+        // Reads stay within the request limit, and a client with another
+        // token from the same address has writes left
+        let (status, _, body) = query(&app, "10.0.0.1", Some("load-token"), "SELECT COUNT(*) FROM t").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["result"]["rows"], serde_json::json!([[2]]));
+        let (status, _, body) = query(&app, "10.0.0.1", Some("admin-token"), "INSERT INTO t VALUES (2)").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+
+    #[test]
+    fn test_concurrent_queries_and_idle_clients() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            requests_per_second: 0.01,
+            request_burst: 1,
+            max_concurrent_queries: 1,
+            max_clients: 2,
+            ..RateLimitConfig::default()
+        });
+        let select = SqlParser::parse("SELECT 1").unwrap();
+        let client = |n: u8| Client::Address(IpAddr::from([10, 0, 0, n]));
+
+        let running = limiter.admit_query(&client(1), &select).unwrap();
+        let limited = limiter.admit_query(&client(2), &select).unwrap_err();
+        assert_eq!(limited.code, "too_many_queries");
+        drop(running);
+        assert!(limiter.admit_query(&client(2), &select).is_ok());
+
+        // The client idle longest is forgotten to make room for a new one
+        assert!(limiter.admit_request(&client(1)).is_ok());
+        assert!(limiter.admit_request(&client(2)).is_ok());
+        assert!(limiter.admit_request(&client(1)).is_err());
+        assert!(limiter.admit_request(&client(3)).is_ok());
+        assert_eq!(limiter.tracked_clients(), 2);
+        assert!(limiter.admit_request(&client(2)).is_ok());
+    }
+}
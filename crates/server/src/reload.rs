@@ -0,0 +1,334 @@
+//! Reloading the configuration of a running server.
+//!
+//! On SIGHUP or `POST /api/admin/reload-config` the server loads its config
+//! again, the way it was loaded at startup, and applies what changed. Only
+//! these keys can change while the server runs:
+//!
+//! - `storage.cache_size_mb`, resizing the block cache
+//! - the keys of `[server.rate_limit]`
+//! - `server.slow_query_threshold_ms` and `server.slow_query_log_per_second`
+//! - `server.log_level`, where logging was set up to allow it
+//!
+//! Turning rate limiting or the slow query log on or off needs a restart,
+//! as does changing any other key. A reload that would is refused whole
+//! with 409 and the code `restart_required`, listing those keys, and one
+//! whose config does not load or validate with 400 and `invalid_config`;
+//! either way nothing is applied. A successful reload lists the keys it
+//! changed.
+
+use crate::{server::{DatabaseState, ErrorBody}, Config, Result};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use nextdb_query::SlowQueryConfig;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// Loads the config as the server was started with
+pub type ConfigLoader = dyn Fn() -> Result<Config> + Send + Sync;
+
+/// Replaces the tracing filter with the given directives, or with the
+/// default for None
+pub type LogFilter = dyn Fn(Option<&str>) -> std::result::Result<(), String> + Send + Sync;
+
+pub(crate) struct ConfigReload {
+    /// The config in effect, held while a reload runs
+    current: tokio::sync::Mutex<Config>,
+    load: Mutex<Option<Arc<ConfigLoader>>>,
+    log_filter: Mutex<Option<Arc<LogFilter>>>,
+}
+
+impl ConfigReload {
+    pub(crate) fn new(config: Config) -> Self {
+        Self { current: tokio::sync::Mutex::new(config), load: Mutex::new(None), log_filter: Mutex::new(None) }
+    }
+
+    pub(crate) fn set_loader(&self, load: Arc<ConfigLoader>) {
+        *self.load.lock().unwrap() = Some(load);
+    }
+
+    pub(crate) fn set_log_filter(&self, log_filter: Arc<LogFilter>) {
+        *self.log_filter.lock().unwrap() = Some(log_filter);
+    }
+}
+
+/// Why a reload was refused
+#[derive(Debug)]
+pub(crate) enum Refused {
+    /// The server has no way to load its config again
+    Unavailable,
+    Invalid(String),
+    RestartRequired(Vec<String>),
+}
+
+impl std::fmt::Display for Refused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Refused::Unavailable => write!(f, "the server was not started from a config it can load again"),
+            Refused::Invalid(message) => write!(f, "{}", message),
+            Refused::RestartRequired(keys) => write!(f, "changing {} needs a restart", keys.join(", ")),
+        }
+    }
+}
+
+impl IntoResponse for Refused {
+    fn into_response(self) -> Response {
+        let (status, code) = match &self {
+            Refused::Unavailable => (StatusCode::CONFLICT, "reload_unavailable"),
+            Refused::Invalid(_) => (StatusCode::BAD_REQUEST, "invalid_config"),
+            Refused::RestartRequired(_) => (StatusCode::CONFLICT, "restart_required"),
+        };
+        let mut body = serde_json::json!({
+            "success": false,
+            "error": ErrorBody { code, message: self.to_string() },
+        });
+        if let Refused::RestartRequired(keys) = &self {
+            body["keys"] = serde_json::json!(keys);
+        }
+        (status, Json(body)).into_response()
+    }
+}
+
+/// Load the config again and apply it, returning the keys that changed
+pub(crate) async fn reload(state: &DatabaseState) -> std::result::Result<Vec<String>, Refused> {
+    let reload = &state.config_reload;
+    let mut current = reload.current.lock().await;
+    let load = reload.load.lock().unwrap().clone().ok_or(Refused::Unavailable)?;
+    let config = load().map_err(|e| Refused::Invalid(e.to_string()))?;
+
+    let changed = changed_keys(&current, &config);
+    let boot_only: Vec<String> = changed.iter().filter(|key| !is_reloadable(key, &current, &config)).cloned().collect();
+    if !boot_only.is_empty() {
+        return Err(Refused::RestartRequired(boot_only));
+    }
+
+    // The only change that can fail goes first, so a failed reload
+    // applies nothing
+    if current.server.log_level != config.server.log_level {
+        if let Some(log_filter) = reload.log_filter.lock().unwrap().clone() {
+            log_filter(config.server.log_level.as_deref()).map_err(|e| Refused::Invalid(format!("server.log_level: {}", e)))?;
+        }
+    }
+    if current.storage.cache_size_mb != config.storage.cache_size_mb {
+        state.storage.resize_cache(config.storage.cache_size_mb * 1024 * 1024);
+    }
+    if let (Some(limiter), Some(limits)) = (&state.rate_limiter, &config.server.rate_limit) {
+        limiter.reconfigure(limits.clone());
+    }
+    if let Some(log) = state.executor.slow_query_log() {
+        log.set_config(SlowQueryConfig {
+            threshold: Duration::from_millis(config.server.slow_query_threshold_ms),
+            max_per_second: config.server.slow_query_log_per_second,
+        });
+    }
+    *current = config;
+    Ok(changed)
+}
+
+/// Reload on each SIGHUP, logging the outcome
+#[cfg(unix)]
+pub(crate) fn reload_on_hangup(state: Arc<DatabaseState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Cannot listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            info!("Reloading the config on SIGHUP");
+            log_outcome(&reload(&state).await);
+        }
+    });
+}
+
+fn log_outcome(outcome: &std::result::Result<Vec<String>, Refused>) {
+    match outcome {
+        Ok(changed) if changed.is_empty() => info!("Config reloaded, nothing changed"),
+        Ok(changed) => info!("Config reloaded, changed {}", changed.join(", ")),
+        Err(refused) => warn!("Config reload refused: {}", refused),
+    }
+}
+
+/// Handler for `POST /api/admin/reload-config`
+pub(crate) async fn reload_config(State(state): State<Arc<DatabaseState>>) -> Response {
+    let outcome = reload(&state).await;
+    log_outcome(&outcome);
+    match outcome {
+        Ok(changed) => (StatusCode::OK, Json(serde_json::json!({ "success": true, "changed": changed }))).into_response(),
+        Err(refused) => refused.into_response(),
+    }
+}
+
+fn is_reloadable(key: &str, old: &Config, new: &Config) -> bool {
+    match key {
+        "storage.cache_size_mb" | "server.slow_query_log_per_second" | "server.log_level" => true,
+        // Unless it turns the slow query log on or off
+        "server.slow_query_threshold_ms" => {
+            (old.server.slow_query_threshold_ms > 0) == (new.server.slow_query_threshold_ms > 0)
+        }
+        // Keys within the section, but not the section coming or going
+        _ => key.starts_with("server.rate_limit."),
+    }
+}
+
+/// Dotted keys whose values differ between `old` and `new`. A section
+/// added or removed is one key, not one for each of its keys.
+fn changed_keys(old: &Config, new: &Config) -> Vec<String> {
+    let (old, new) = (flatten(old), flatten(new));
+    let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    let changed: Vec<String> = keys.into_iter().filter(|key| old.get(*key) != new.get(*key)).cloned().collect();
+    changed.iter()
+        .filter(|key| !changed.iter().any(|section| key.starts_with(&format!("{}.", section))))
+        .cloned()
+        .collect()
+}
+
+fn flatten(config: &Config) -> BTreeMap<String, serde_json::Value> {
+    fn walk(prefix: &str, value: serde_json::Value, out: &mut BTreeMap<String, serde_json::Value>) {
+        match value {
+            serde_json::Value::Object(map) if !map.is_empty() => {
+                for (key, value) in map {
+                    let key = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+                    walk(&key, value, out);
+                }
+            }
+            value => {
+                out.insert(prefix.to_string(), value);
+            }
+        }
+    }
+    let mut out = BTreeMap::new();
+    walk("", serde_json::to_value(config).expect("the config serializes"), &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::post;
+    use crate::DatabaseServer;
+    use axum::Router;
+    use std::path::{Path, PathBuf};
+    use tempfile::TempDir;
+
+    fn write_config(path: &Path, data_dir: &Path, extra: &str) {
+        let text = format!("[server]\ndata_dir = {:?}\n{}", data_dir.display().to_string(), extra);
+        std::fs::write(path, text).unwrap();
+    }
+
+    async fn start(temp_dir: &TempDir, extra: &str) -> (PathBuf, DatabaseServer) {
+        let path = temp_dir.path().join("nextdb.toml");
+        write_config(&path, temp_dir.path(), extra);
+        let config = Config::load(Some(&path), Vec::new(), &[]).unwrap();
+        let loaded = path.clone();
+        let server = DatabaseServer::new(config).await.unwrap()
+            .with_config_loader(move || Config::load(Some(&loaded), Vec::new(), &[]));
+        (path, server)
+    }
+
+    async fn send(app: &Router, uri: &str) -> (StatusCode, serde_json::Value) {
+        post(app, uri, serde_json::json!({ "sql": "SELECT 1" })).await
+    }
+
+    #[tokio::test]
+    async fn test_reload_applies_reloadable_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let limits = "[server.rate_limit]\nrequests_per_second = 1000.0\nrequest_burst = 1000\n";
+        let (path, server) = start(&temp_dir, limits).await;
+        let app = server.router();
+        assert_eq!(server.state().storage.stats().await.cache.capacity_bytes, 256 * 1024 * 1024);
+        for _ in 0..3 {
+            assert_eq!(send(&app, "/api/query").await.0, StatusCode::OK);
+        }
+
+        // Nothing changed yet
+        let (status, body) = send(&app, "/api/admin/reload-config").await;
+        assert_eq!((status, &body["changed"]), (StatusCode::OK, &serde_json::json!([])));
+
+        let extra = "slow_query_threshold_ms = 50\n\
+            [storage]\ncache_size_mb = 8\n\
+            [server.rate_limit]\nrequests_per_second = 0.001\nrequest_burst = 1\n";
+        write_config(&path, temp_dir.path(), extra);
+        let (status, body) = send(&app, "/api/admin/reload-config").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["changed"], serde_json::json!([
+            "server.rate_limit.request_burst",
+            "server.rate_limit.requests_per_second",
+            "server.slow_query_threshold_ms",
+            "storage.cache_size_mb",
+        ]));
+        assert_eq!(server.state().storage.stats().await.cache.capacity_bytes, 8 * 1024 * 1024);
+        let slow_log = server.state().executor.slow_query_log().unwrap();
+        assert_eq!(slow_log.threshold(), Duration::from_millis(50));
+        // The bucket now holds at most the new burst of one request
+        assert_eq!(send(&app, "/api/query").await.0, StatusCode::OK);
+        let (status, body) = send(&app, "/api/query").await;
+        assert_eq!((status, body["error"]["code"].as_str()), (StatusCode::TOO_MANY_REQUESTS, Some("rate_limited")));
+    }
+
+    #[tokio::test]
+    async fn test_reload_refuses_boot_only_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let (path, server) = start(&temp_dir, "").await;
+        let app = server.router();
+
+        let moved = temp_dir.path().join("elsewhere");
+        write_config(&path, &moved, "[storage]\ncache_size_mb = 8\n[server.rate_limit]\n");
+        let (status, body) = send(&app, "/api/admin/reload-config").await;
+        assert_eq!((status, body["error"]["code"].as_str()), (StatusCode::CONFLICT, Some("restart_required")), "{}", body);
+        assert_eq!(body["keys"], serde_json::json!(["server.data_dir", "server.rate_limit", "storage.data_dir", "storage.wal_dir"]));
+        // None of it was applied, not even the cache size
+        assert_eq!(server.state().storage.stats().await.cache.capacity_bytes, 256 * 1024 * 1024);
+        assert!(server.state().rate_limiter.is_none());
+
+        write_config(&path, temp_dir.path(), "[storage]\nmemtable_size_mb = 0\n");
+        let (status, body) = send(&app, "/api/admin/reload-config").await;
+        assert_eq!((status, body["error"]["code"].as_str()), (StatusCode::BAD_REQUEST, Some("invalid_config")));
+        assert!(body["error"]["message"].as_str().unwrap().contains("storage.memtable_size_mb"), "{}", body);
+
+        // A server not started from a file cannot reload
+        let config = crate::ServerConfig { data_dir: temp_dir.path().join("other"), ..Default::default() };
+        let app = DatabaseServer::with_config(config).await.unwrap().router();
+        let (status, body) = send(&app, "/api/admin/reload-config").await;
+        assert_eq!((status, body["error"]["code"].as_str()), (StatusCode::CONFLICT, Some("reload_unavailable")));
+    }
+
+    #[tokio::test]
+    async fn test_reload_log_level() {
+        let temp_dir = TempDir::new().unwrap();
+        let (path, server) = start(&temp_dir, "").await;
+        let levels = Arc::new(Mutex::new(Vec::new()));
+        let seen = levels.clone();
+        let server = server.with_log_filter(move |level| match level {
+            Some("loud") => Err("unknown level".to_string()),
+            level => {
+                seen.lock().unwrap().push(level.map(str::to_string));
+                Ok(())
+            }
+        });
+        let app = server.router();
+
+        write_config(&path, temp_dir.path(), "log_level = \"debug\"\n");
+        assert_eq!(send(&app, "/api/admin/reload-config").await.0, StatusCode::OK);
+        write_config(&path, temp_dir.path(), "");
+        assert_eq!(send(&app, "/api/admin/reload-config").await.0, StatusCode::OK);
+        assert_eq!(*levels.lock().unwrap(), [Some("debug".to_string()), None]);
+
+        // A filter that does not parse fails the reload before anything applies
+        write_config(&path, temp_dir.path(), "log_level = \"loud\"\n[storage]\ncache_size_mb = 8\n");
+        let (status, body) = send(&app, "/api/admin/reload-config").await;
+        assert_eq!((status, body["error"]["code"].as_str()), (StatusCode::BAD_REQUEST, Some("invalid_config")));
+        assert_eq!(server.state().storage.stats().await.cache.capacity_bytes, 256 * 1024 * 1024);
+    }
+}
@@ -0,0 +1,649 @@
+//! Cluster mode: writes ordered by Raft.
+//!
+//! With `config.consensus` set, the executor hands every write to a
+//! `RaftReplicator`, and a driver task owns the Raft node. The driver
+//! proposes writes while this node leads, ticks the node, moves messages
+//! between it and the transport, and applies committed entries to the
+//! executor in log order, answering the statement that proposed each one.
+//! Reads stay local, or wait for a ReadIndex confirmation first when
+//! `linearizable_reads` is set or the read asks to be linearizable.
+//!
+//! A node started with `join` asks that member to add it, following
+//! redirects to the leader, until it is accepted.
+//!
+//! The driver also keeps `DatabaseState::leadership` current and answers
+//! the client requests followers pass on to it (see `forward`).
+
+use crate::forward::{self, Leader};
+use crate::server::DatabaseState;
+use crate::Result;
+use axum::Router;
+use futures::future::BoxFuture;
+use nextdb_consensus::{ConsensusError, Inbound, JoinResponse, MemberInfo, NodeId, RaftNode, Transport};
+use nextdb_query::{QueryError, ReplicatedWrite, Replicator, ResultSet};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{debug, error, info, warn};
+
+/// How long a statement waits for its write to commit and apply
+pub(crate) const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a joining node waits before asking again
+const JOIN_RETRY: Duration = Duration::from_secs(1);
+/// Writes and reads queued for the driver
+const QUEUE_LEN: usize = 1024;
+
+type QueryResult<T> = nextdb_query::Result<T>;
+
+enum Request {
+    /// A `ReplicatedWrite` as the log entry carries it
+    Write { data: Vec<u8>, reply: oneshot::Sender<QueryResult<ResultSet>> },
+    Read { reply: oneshot::Sender<QueryResult<()>> },
+}
+
+/// The executor's way to the driver
+pub(crate) struct RaftReplicator {
+    requests: mpsc::Sender<Request>,
+    linearizable_reads: bool,
+}
+
+impl RaftReplicator {
+    /// A replicator and the queue its driver takes requests from
+    pub(crate) fn new(linearizable_reads: bool) -> (Self, Requests) {
+        let (requests, receiver) = mpsc::channel(QUEUE_LEN);
+        (Self { requests, linearizable_reads }, Requests(receiver))
+    }
+
+    async fn call<T>(&self, request: impl FnOnce(oneshot::Sender<QueryResult<T>>) -> Request) -> QueryResult<T> {
+        let (reply, result) = oneshot::channel();
+        self.requests.send(request(reply)).await.map_err(|_| stopped())?;
+        match time::timeout(PROPOSAL_TIMEOUT, result).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(stopped()),
+            Err(_) => Err(QueryError::Replication(format!(
+                "no answer within {}ms; a write may still be applied",
+                PROPOSAL_TIMEOUT.as_millis()
+            ))),
+        }
+    }
+}
+
+impl Replicator for RaftReplicator {
+    fn replicate(&self, write: ReplicatedWrite) -> BoxFuture<'_, QueryResult<ResultSet>> {
+        Box::pin(async move {
+            let data = serde_json::to_vec(&write).map_err(|e| QueryError::Replication(e.to_string()))?;
+            self.call(|reply| Request::Write { data, reply }).await
+        })
+    }
+
+    fn read_barrier(&self) -> BoxFuture<'_, QueryResult<()>> {
+        match self.linearizable_reads {
+            true => self.confirm_reads(),
+            false => Box::pin(async { Ok(()) }),
+        }
+    }
+
+    fn confirm_reads(&self) -> BoxFuture<'_, QueryResult<()>> {
+        Box::pin(self.call(|reply| Request::Read { reply }))
+    }
+}
+
+/// Requests from a `RaftReplicator`, for `Replication::start`
+pub(crate) struct Requests(mpsc::Receiver<Request>);
+
+/// The running driver of a Raft node
+pub(crate) struct Replication {
+    raft: Arc<RwLock<RaftNode>>,
+    driver: tokio::sync::Mutex<Option<JoinHandle<()>>>,
+    join: Option<JoinHandle<()>>,
+    /// Answers requests forwarded from followers, once the server is built
+    router: Arc<OnceLock<Router>>,
+}
+
+impl Replication {
+    /// Drive `node`, applying its committed entries to `state.executor`.
+    /// `api_address` is where clients reach this server, told to the
+    /// cluster when joining it.
+    pub(crate) async fn start(mut node: RaftNode, state: Arc<DatabaseState>, requests: Requests, api_address: String) -> Result<Self> {
+        let applied = state.executor.applied_index().await?;
+        node.restore_applied(applied);
+        // The only voter needs no votes but its own, so it can lead at once
+        if node.membership().is_some_and(|members| members.len() == 1) {
+            node.campaign();
+        }
+        let (transport, inbound) = match &node.config().raft_address {
+            Some(address) => {
+                let (transport, inbound) = Transport::bind(address).await?;
+                info!("🗳️  Raft transport listening on {}", transport.local_addr());
+                (Some(transport), Some(inbound))
+            }
+            None => (None, None),
+        };
+        let joining = match (&node.config().join, node.membership()) {
+            (Some(address), None) => Some((address.clone(), MemberInfo {
+                node_id: node.id(),
+                raft_address: node.config().raft_address.clone(),
+                api_address: Some(node.config().addresses.get(&node.id()).cloned().unwrap_or(api_address)),
+            })),
+            _ => None,
+        };
+        let tick = Duration::from_millis((node.config().heartbeat_interval_ms / 2).max(1));
+
+        let raft = Arc::new(RwLock::new(node));
+        let learned = Arc::new(Mutex::new(HashMap::new()));
+        let router = Arc::new(OnceLock::new());
+        let join = joining.map(|(address, member)| tokio::spawn(join(raft.clone(), learned.clone(), address, member)));
+        let driver = Driver {
+            raft: raft.clone(),
+            state,
+            transport,
+            learned,
+            router: router.clone(),
+            applied,
+            writes: HashMap::new(),
+            reads: HashMap::new(),
+            applying: Vec::new(),
+        };
+        let driver = tokio::spawn(driver.run(requests.0, inbound, tick));
+        Ok(Self { raft, driver: tokio::sync::Mutex::new(Some(driver)), join, router })
+    }
+
+    pub(crate) fn raft(&self) -> &Arc<RwLock<RaftNode>> {
+        &self.raft
+    }
+
+    /// Answer requests followers forward to this node with `router`. Until
+    /// then they fail.
+    pub(crate) fn serve_forwarded(&self, router: Router) {
+        let _ = self.router.set(router);
+    }
+
+    /// Stop the node and wait for the driver to finish the entry it is
+    /// applying, failing writes and reads still waiting
+    pub(crate) async fn stop(&self) {
+        self.raft.write().await.stop();
+        if let Some(join) = &self.join {
+            join.abort();
+        }
+        if let Some(driver) = self.driver.lock().await.take() {
+            if let Err(e) = driver.await {
+                error!("Raft driver failed: {}", e);
+            }
+        }
+    }
+}
+
+struct Driver {
+    raft: Arc<RwLock<RaftNode>>,
+    state: Arc<DatabaseState>,
+    transport: Option<Transport>,
+    /// Raft addresses of the voters a joining node was told of, for
+    /// reaching them before its log has the membership
+    learned: Arc<Mutex<HashMap<NodeId, String>>>,
+    router: Arc<OnceLock<Router>>,
+    /// Index of the last entry applied
+    applied: u64,
+    /// Proposed writes by index, with the term they were proposed in
+    writes: HashMap<u64, (u64, oneshot::Sender<QueryResult<ResultSet>>)>,
+    /// Reads waiting for leadership to be confirmed, by read id
+    reads: HashMap<u64, oneshot::Sender<QueryResult<()>>>,
+    /// Confirmed reads waiting for the index they read at to apply
+    applying: Vec<(u64, oneshot::Sender<QueryResult<()>>)>,
+}
+
+impl Driver {
+    async fn run(mut self, mut requests: mpsc::Receiver<Request>, mut inbound: Option<mpsc::Receiver<Inbound>>, tick: Duration) {
+        let mut ticker = time::interval(tick);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => self.raft.write().await.tick(),
+                request = requests.recv() => match request {
+                    Some(request) => self.request(request).await,
+                    None => break,
+                },
+                Some(inbound) = recv(&mut inbound) => self.receive(inbound).await,
+            }
+            if self.raft.read().await.is_stopped() {
+                break;
+            }
+            self.flush().await;
+            self.observe_leader().await;
+        }
+        for (_, reply) in self.writes.drain() {
+            let _ = reply.1.send(Err(stopped()));
+        }
+        for reply in self.reads.drain().map(|(_, reply)| reply).chain(self.applying.drain(..).map(|(_, reply)| reply)) {
+            let _ = reply.send(Err(stopped()));
+        }
+    }
+
+    async fn request(&mut self, request: Request) {
+        let mut raft = self.raft.write().await;
+        match request {
+            Request::Write { data, reply } => {
+                match raft.propose(data).await {
+                    Ok(index) => {
+                        self.writes.insert(index, (raft.current_term(), reply));
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(refused(&raft, e)));
+                    }
+                }
+            }
+            Request::Read { reply } => match raft.read_index() {
+                Ok(id) => {
+                    self.reads.insert(id, reply);
+                }
+                Err(e) => {
+                    let _ = reply.send(Err(refused(&raft, e)));
+                }
+            },
+        }
+    }
+
+    async fn receive(&mut self, inbound: Inbound) {
+        match inbound {
+            Inbound::Message(message) => {
+                let mut raft = self.raft.write().await;
+                if let Err(e) = raft.step(message) {
+                    error!("Raft node {} cannot persist its state and stops: {}", raft.id().0, e);
+                    raft.stop();
+                }
+            }
+            Inbound::Join { member, reply } => {
+                let mut raft = self.raft.write().await;
+                let members = |raft: &RaftNode| raft.membership().unwrap_or_default().to_vec();
+                let response = if raft.member(member.node_id) == Some(&member) {
+                    JoinResponse::Accepted { members: members(&raft) }
+                } else if raft.is_leader() {
+                    let node_id = member.node_id;
+                    match raft.add_member(member) {
+                        Ok(index) => {
+                            info!("Adding Raft node {} to the cluster at index {}", node_id.0, index);
+                            JoinResponse::Accepted { members: members(&raft) }
+                        }
+                        Err(e) => JoinResponse::Rejected(e.to_string()),
+                    }
+                } else {
+                    let leader = raft.leader().and_then(|leader| raft.member(leader)).and_then(|leader| leader.raft_address.clone());
+                    JoinResponse::NotLeader { leader }
+                };
+                let _ = reply.send(response);
+            }
+            Inbound::Forward { request, reply } => {
+                // Answered apart, since a write waits for this driver to apply it
+                if let Some(router) = self.router.get().cloned() {
+                    tokio::spawn(async move {
+                        let _ = reply.send(forward::serve_forwarded(router, request).await);
+                    });
+                }
+            }
+        }
+    }
+
+    /// Where a node's Raft transport listens, as far as this node knows
+    fn raft_address(&self, raft: &RaftNode, node_id: NodeId) -> Option<String> {
+        raft.member(node_id)
+            .and_then(|member| member.raft_address.clone())
+            .or_else(|| raft.config().peer_addresses.get(&node_id).cloned())
+            .or_else(|| self.learned.lock().unwrap().get(&node_id).cloned())
+    }
+
+    /// Tell the server where the leader is now
+    async fn observe_leader(&self) {
+        let Some(leadership) = &self.state.leadership else {
+            return;
+        };
+        let raft = self.raft.read().await;
+        let leader = match raft.leader() {
+            Some(node_id) => Leader {
+                is_self: node_id == raft.id(),
+                api_address: raft.member(node_id).and_then(|member| member.api_address.clone())
+                    .or_else(|| raft.config().addresses.get(&node_id).cloned()),
+                raft_address: self.raft_address(&raft, node_id),
+            },
+            None => Leader::default(),
+        };
+        leadership.observe(leader);
+    }
+
+    /// Send what the node queued for its peers, then apply what it committed
+    /// and answer the reads it confirmed
+    async fn flush(&mut self) {
+        let (committed, confirmed) = {
+            let mut raft = self.raft.write().await;
+            for message in raft.take_messages() {
+                let address = self.raft_address(&raft, message.to);
+                match (&self.transport, address) {
+                    (Some(transport), Some(address)) => transport.send(&address, message),
+                    _ => debug!("No Raft address for node {}; dropping a message to it", message.to.0),
+                }
+            }
+            (raft.take_committed(), raft.take_reads())
+        };
+
+        for entry in committed {
+            // Nothing to apply for a leader's first entry or a membership change
+            let result = match entry.data.is_empty() {
+                true => None,
+                false => Some(self.apply(&entry.data, entry.index).await),
+            };
+            self.applied = entry.index;
+            if let Some((term, reply)) = self.writes.remove(&entry.index) {
+                let result = match (term == entry.term, result) {
+                    (true, Some(result)) => result,
+                    _ => Err(QueryError::Replication("the write was lost to a change of leader and not applied".to_string())),
+                };
+                let _ = reply.send(result);
+            }
+        }
+
+        for (id, index) in confirmed {
+            let Some(reply) = self.reads.remove(&id) else {
+                continue;
+            };
+            match index {
+                Some(index) => self.applying.push((index, reply)),
+                None => {
+                    let raft = self.raft.read().await;
+                    let _ = reply.send(Err(refused(&raft, ConsensusError::NotLeader)));
+                }
+            }
+        }
+        let applied = self.applied;
+        let (ready, waiting) = std::mem::take(&mut self.applying).into_iter().partition(|(index, _)| *index <= applied);
+        self.applying = waiting;
+        for (_, reply) in ready {
+            let _ = reply.send(Ok(()));
+        }
+    }
+
+    async fn apply(&mut self, data: &[u8], index: u64) -> QueryResult<ResultSet> {
+        let write: ReplicatedWrite = match serde_json::from_slice(data) {
+            Ok(write) => write,
+            Err(e) => {
+                let e = format!("entry {} cannot be decoded: {}", index, e);
+                self.fail(index, &e).await;
+                return Err(QueryError::Replication(e));
+            }
+        };
+        let batch = matches!(write, ReplicatedWrite::Batch(_));
+        let result = self.state.executor.apply_replicated(write, index).await;
+        // A statement fails alike on every replica, but a batch that cannot
+        // be written leaves this one behind for good
+        if let (true, Err(e)) = (batch, &result) {
+            self.fail(index, e.to_string()).await;
+        }
+        result
+    }
+
+    async fn fail(&self, index: u64, e: impl std::fmt::Display) {
+        error!("Raft node cannot apply entry {} and stops: {}", index, e);
+        let mut raft = self.raft.write().await;
+        raft.stop();
+    }
+}
+
+/// Ask the member at `address` to add `member`, following redirects, until
+/// it is accepted
+async fn join(raft: Arc<RwLock<RaftNode>>, learned: Arc<Mutex<HashMap<NodeId, String>>>, mut address: String, member: MemberInfo) {
+    loop {
+        if raft.read().await.membership().is_some() {
+            return;
+        }
+        match Transport::join(&address, member.clone()).await {
+            Ok(JoinResponse::Accepted { members }) => {
+                info!("Joined the cluster through {}", address);
+                learned.lock().unwrap().extend(members.into_iter()
+                    .filter_map(|member| Some((member.node_id, member.raft_address?))));
+                return;
+            }
+            Ok(JoinResponse::NotLeader { leader: Some(leader) }) if leader != address => {
+                debug!("{} does not lead; asking {}", address, leader);
+                address = leader;
+                continue;
+            }
+            Ok(JoinResponse::NotLeader { .. }) => debug!("{} knows no leader yet", address),
+            Ok(JoinResponse::Rejected(reason)) => warn!("{} refused to add this node: {}", address, reason),
+            Err(e) => warn!("Cannot ask {} to add this node: {}", address, e),
+        }
+        time::sleep(JOIN_RETRY).await;
+    }
+}
+
+async fn recv(inbound: &mut Option<mpsc::Receiver<Inbound>>) -> Option<Inbound> {
+    match inbound {
+        Some(inbound) => inbound.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The error for a write or read `raft` would not take
+fn refused(raft: &RaftNode, e: ConsensusError) -> QueryError {
+    match e {
+        ConsensusError::NotLeader => QueryError::NotLeader {
+            leader: raft.leader().and_then(|leader| raft.member(leader)).and_then(|leader| leader.api_address.clone()),
+        },
+        ConsensusError::Stopped => stopped(),
+        e => QueryError::Replication(e.to_string()),
+    }
+}
+
+fn stopped() -> QueryError {
+    QueryError::Replication("the Raft node is stopped".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{self, get, json, json_request, send};
+    use crate::{Config, DatabaseServer};
+    use axum::body::Body;
+    use axum::http::{header, HeaderMap, Method, StatusCode};
+    use nextdb_consensus::{NodeId, RaftConfig};
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+
+    fn free_address() -> String {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string()
+    }
+
+    async fn node(dir: &TempDir, raft_address: &str, join: Option<&str>) -> DatabaseServer {
+        let consensus = RaftConfig {
+            raft_address: Some(raft_address.to_string()),
+            join: join.map(str::to_string),
+            ..RaftConfig::default()
+        };
+        node_with(dir, consensus).await
+    }
+
+    async fn node_with(dir: &TempDir, consensus: RaftConfig) -> DatabaseServer {
+        let server = test_util::config(dir);
+        DatabaseServer::new(Config { consensus: Some(consensus), ..server.into() }).await.unwrap()
+    }
+
+    async fn post(server: &DatabaseServer, uri: &str, body: serde_json::Value) -> (StatusCode, HeaderMap, serde_json::Value) {
+        let request = json_request(Method::POST, uri).body(Body::from(body.to_string())).unwrap();
+        let response = send(&server.router(), request).await;
+        let headers = response.headers().clone();
+        let (status, body) = json(response).await;
+        (status, headers, body)
+    }
+
+    async fn query(server: &DatabaseServer, sql: &str) -> (StatusCode, serde_json::Value) {
+        let (status, _, body) = post(server, "/api/query", serde_json::json!({ "sql": sql })).await;
+        (status, body)
+    }
+
+    async fn rows(server: &DatabaseServer, sql: &str) -> serde_json::Value {
+        query(server, sql).await.1["result"]["rows"].clone()
+    }
+
+    async fn cluster(server: &DatabaseServer) -> serde_json::Value {
+        get(&server.router(), "/api/cluster/nodes").await.1
+    }
+
+    async fn leads(server: &DatabaseServer) -> bool {
+        cluster(server).await["nodes"][0]["role"] == "leader"
+    }
+
+    async fn until<F: Future<Output = bool>>(what: &str, mut check: impl FnMut() -> F) {
+        let deadline = Instant::now() + Duration::from_secs(15);
+        while !check().await {
+            assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_three_node_cluster() {
+        let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap()];
+        let addresses = [free_address(), free_address(), free_address()];
+        let a = node(&dirs[0], &addresses[0], None).await;
+        let b = node(&dirs[1], &addresses[1], Some(&addresses[0])).await;
+        let c = node(&dirs[2], &addresses[2], Some(&addresses[1])).await;
+        until("all three nodes are members", || async {
+            cluster(&a).await["nodes"].as_array().unwrap().len() == 3
+                && cluster(&c).await["nodes"].as_array().unwrap().len() == 3
+        }).await;
+        assert!(leads(&a).await);
+
+        let (status, body) = query(&a, "CREATE TABLE t (id INT PRIMARY KEY, v TEXT)").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let (status, body) = query(&a, "INSERT INTO t VALUES (1, 'a')").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        until("B reads the row written on A", || async { rows(&b, "SELECT * FROM t").await == serde_json::json!([[1, "a"]]) }).await;
+
+        // Followers send writes to the leader
+        let (status, body) = query(&b, "INSERT INTO t VALUES (2, 'b')").await;
+        assert_eq!((status, &body["error"]["code"]), (StatusCode::TEMPORARY_REDIRECT, &serde_json::json!("not_leader")), "{}", body);
+
+        // The others elect a leader of their own and carry on without A
+        a.close().await.unwrap();
+        drop(a);
+        until("B or C leads", || async { leads(&b).await || leads(&c).await }).await;
+        let (leader, follower) = if leads(&b).await { (&b, &c) } else { (&c, &b) };
+        let (status, body) = query(leader, "INSERT INTO t VALUES (2, 'b')").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let both = serde_json::json!([[1, "a"], [2, "b"]]);
+        until("the follower reads both rows", || async { rows(follower, "SELECT * FROM t ORDER BY id").await == both }).await;
+
+        // A comes back as a member, and catches up
+        let mut restarted = None;
+        while restarted.is_none() {
+            let server = test_util::config(&dirs[0]);
+            let consensus = RaftConfig { raft_address: Some(addresses[0].clone()), ..RaftConfig::default() };
+            // The old transport may not have let go of the address yet
+            match DatabaseServer::new(Config { consensus: Some(consensus), ..server.into() }).await {
+                Ok(server) => restarted = Some(server),
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        }
+        let a = restarted.unwrap();
+        until("A catches up", || async { rows(&a, "SELECT * FROM t ORDER BY id").await == both }).await;
+        assert_eq!(cluster(&a).await["nodes"].as_array().unwrap().len(), 3);
+        for server in [&a, &b, &c] {
+            server.close().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_linearizable_reads_confirm_leadership() {
+        let dir = TempDir::new().unwrap();
+        let a = node(&dir, &free_address(), None).await;
+        until("A leads", || async { leads(&a).await }).await;
+        query(&a, "CREATE TABLE t (id INT PRIMARY KEY)").await;
+        query(&a, "INSERT INTO t VALUES (1)").await;
+        let reads = || async {
+            a.collect_stats().await;
+            get(&a.router(), "/api/consensus/stats").await.1["linearizable_reads"].as_u64().unwrap()
+        };
+
+        // The same read, with ReadIndex only when it asks to be linearizable
+        let select = serde_json::json!({ "sql": "SELECT id FROM t" });
+        let before = reads().await;
+        for consistency in ["local", "leader", "linearizable"] {
+            let (status, _, body) = post(&a, &format!("/api/query?consistency={}", consistency), select.clone()).await;
+            assert_eq!((status, &body["result"]["rows"]), (StatusCode::OK, &serde_json::json!([[1]])), "{}", body);
+        }
+        assert_eq!(reads().await, before + 1);
+        a.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_writes_sent_to_followers() {
+        let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap()];
+        let raft_addresses = [free_address(), free_address(), free_address()];
+        let ids = [NodeId::new(), NodeId::new(), NodeId::new()];
+        let api_addresses = ["a.test:8080", "b.test:8080", "c.test:8080"];
+        let consensus = |i: usize, forward_writes: bool| RaftConfig {
+            node_id: ids[i],
+            raft_address: Some(raft_addresses[i].clone()),
+            join: (i > 0).then(|| raft_addresses[0].clone()),
+            addresses: HashMap::from([(ids[i], api_addresses[i].to_string())]),
+            forward_writes,
+            ..RaftConfig::default()
+        };
+        let a = node_with(&dirs[0], consensus(0, false)).await;
+        // B passes writes on to the leader, C redirects clients there
+        let b = node_with(&dirs[1], consensus(1, true)).await;
+        let c = node_with(&dirs[2], consensus(2, false)).await;
+        let leader = ids[0].0.to_string();
+        until("B and C follow A", || async {
+            cluster(&b).await["leader"] == leader.as_str() && cluster(&c).await["leader"] == leader.as_str()
+        }).await;
+        let (status, body) = query(&a, "CREATE TABLE t (id INT PRIMARY KEY, v TEXT)").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        let (status, body) = query(&b, "INSERT INTO t VALUES (1, 'proxied')").await;
+        assert_eq!((status, &body["rows_affected"]), (StatusCode::OK, &serde_json::json!(1)), "{}", body);
+        let batch = serde_json::json!({ "statements": ["INSERT INTO t VALUES (2, 'batch')"] });
+        let (status, _, body) = post(&b, "/api/batch", batch).await;
+        assert_eq!((status, &body["succeeded"]), (StatusCode::OK, &serde_json::json!(1)), "{}", body);
+        assert_eq!(rows(&a, "SELECT id FROM t ORDER BY id").await, serde_json::json!([[1], [2]]));
+
+        // A client following the redirect writes on A
+        let insert = serde_json::json!({ "sql": "INSERT INTO t VALUES (3, 'redirected')" });
+        let (status, headers, body) = post(&c, "/api/query", insert.clone()).await;
+        assert_eq!(status, StatusCode::TEMPORARY_REDIRECT, "{}", body);
+        assert_eq!((&body["error"]["code"], &body["leader"]), (&serde_json::json!("not_leader"), &serde_json::json!("a.test:8080")));
+        let location = headers[header::LOCATION].to_str().unwrap();
+        assert_eq!(location, "http://a.test:8080/api/query");
+        let servers = [&a, &b, &c];
+        let target = api_addresses.iter().position(|address| location.contains(address)).unwrap();
+        let (status, _, body) = post(servers[target], "/api/query", insert).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        // Local reads on a follower may be stale; linearizable ones go to
+        // the leader
+        let all = serde_json::json!([[1], [2], [3]]);
+        let select = serde_json::json!({ "sql": "SELECT id FROM t ORDER BY id" });
+        until("C reads every row", || async { rows(&c, "SELECT id FROM t ORDER BY id").await == all }).await;
+        let (status, headers, _) = post(&c, "/api/query?consistency=local", select.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers[header::WARNING].to_str().unwrap().starts_with("110"));
+        let (status, headers, _) = post(&a, "/api/query", select.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!headers.contains_key(header::WARNING));
+        let (status, headers, _) = post(&c, "/api/query?consistency=linearizable", select.clone()).await;
+        assert_eq!(status, StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(headers[header::LOCATION], "http://a.test:8080/api/query?consistency=linearizable");
+        let (status, headers, body) = post(&b, "/api/query?consistency=linearizable", select.clone()).await;
+        assert_eq!((status, &body["result"]["rows"]), (StatusCode::OK, &all), "{}", body);
+        assert!(!headers.contains_key(header::WARNING));
+        let (status, headers, _) = post(&c, "/api/query?consistency=leader", select.clone()).await;
+        assert_eq!(status, StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(headers[header::LOCATION], "http://a.test:8080/api/query?consistency=leader");
+        let (status, _, body) = post(&b, "/api/query?consistency=eventual", select).await;
+        assert_eq!((status, &body["error"]["code"]), (StatusCode::BAD_REQUEST, &serde_json::json!("invalid_request")));
+
+        for server in [&a, &b, &c] {
+            server.close().await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! Request ids and a log entry for every HTTP request.
+//!
+//! Each request gets an id, taken from its `x-request-id` header when the
+//! client sent a usable one and made up otherwise, and the response carries
+//! it back in the same header. The request runs in a `request` span holding
+//! the id, so whatever is logged while it runs, the slow query log
+//! included, can be tied to it. When it finishes an info event is logged
+//! under the `nextdb::request` target, in the same span, which by then also
+//! records the status, duration, rows returned and the fingerprint of the
+//! SQL (see `nextdb_query::fingerprint`). The SQL itself is never logged,
+//! since its literals may be personal data.
+
+use crate::cursor::new_token;
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::time::Instant;
+use tracing::{field, info, info_span, Instrument, Span};
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request id taken from a client
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Middleware giving each request an id and its `request` span
+pub(crate) async fn log_requests(request: Request, next: Next) -> Response {
+    let id = request.headers().get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| usable(id))
+        .map_or_else(new_token, str::to_string);
+    let method = request.method().clone();
+    // The route pattern rather than the path, which may hold ids
+    let route = request.extensions().get::<MatchedPath>()
+        .map_or_else(|| request.uri().path().to_string(), |path| path.as_str().to_string());
+    let span = info_span!(
+        "request",
+        request_id = %id,
+        method = %method,
+        route = %route,
+        status = field::Empty,
+        duration_ms = field::Empty,
+        rows = field::Empty,
+        sql = field::Empty,
+    );
+
+    let started = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+    let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+    span.record("status", response.status().as_u16());
+    span.record("duration_ms", duration_ms);
+    span.in_scope(|| info!(target: "nextdb::request", "{} {} {} in {:.3} ms", method, route, response.status().as_u16(), duration_ms));
+
+    let id = HeaderValue::from_str(&id).expect("request ids are visible ASCII");
+    response.headers_mut().insert(REQUEST_ID_HEADER, id);
+    response
+}
+
+/// Whether a client's request id can be logged and sent back as is
+fn usable(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+/// Record the fingerprint of the SQL the current request runs
+pub(crate) fn record_sql(sql: &str) {
+    Span::current().record("sql", nextdb_query::fingerprint(sql));
+}
+
+/// Record the rows the current request returns
+pub(crate) fn record_rows(rows: usize) {
+    Span::current().record("rows", rows);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, bytes, json_request, send};
+    use crate::{DatabaseServer, ServerConfig};
+    use axum::body::Body;
+    use axum::http::{Method, StatusCode};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Subscriber};
+    use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+
+    type Fields = HashMap<String, String>;
+
+    #[derive(Debug, Clone)]
+    struct Captured {
+        target: String,
+        fields: Fields,
+        /// Fields of the `request` span the event was logged in
+        request: Option<Fields>,
+    }
+
+    /// Records every span's fields and every event
+    #[derive(Clone, Default)]
+    struct Capture {
+        spans: Arc<Mutex<HashMap<Id, Fields>>>,
+        events: Arc<Mutex<Vec<Captured>>>,
+    }
+
+    impl Capture {
+        /// The events logged since the last call
+        fn drain(&self) -> Vec<Captured> {
+            std::mem::take(&mut *self.events.lock().unwrap())
+        }
+    }
+
+    /// The first of `events` logged under `target`
+    fn logged(events: &[Captured], target: &str) -> Captured {
+        events.iter().find(|event| event.target == target).cloned()
+            .unwrap_or_else(|| panic!("nothing logged under {}: {:?}", target, events))
+    }
+
+    struct Visitor<'a>(&'a mut Fields);
+
+    impl field::Visit for Visitor<'_> {
+        fn record_str(&mut self, field: &field::Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &field::Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Capture {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _: Context<'_, S>) {
+            let mut fields = Fields::new();
+            attrs.record(&mut Visitor(&mut fields));
+            self.spans.lock().unwrap().insert(id.clone(), fields);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, _: Context<'_, S>) {
+            if let Some(fields) = self.spans.lock().unwrap().get_mut(id) {
+                values.record(&mut Visitor(fields));
+            }
+        }
+
+        fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+            let mut fields = Fields::new();
+            event.record(&mut Visitor(&mut fields));
+            let request = ctx.event_scope(event)
+                .and_then(|mut scope| scope.find(|span| span.name() == "request"))
+                .and_then(|span| self.spans.lock().unwrap().get(&span.id()).cloned());
+            self.events.lock().unwrap().push(Captured { target: event.metadata().target().to_string(), fields, request });
+        }
+    }
+
+    async fn post(app: &axum::Router, uri: &str, request_id: Option<&str>, body: serde_json::Value) -> (StatusCode, String) {
+        let mut request = json_request(Method::POST, uri);
+        if let Some(id) = request_id {
+            request = request.header(REQUEST_ID_HEADER, id);
+        }
+        let response = send(app, request.body(Body::from(body.to_string())).unwrap()).await;
+        let status = response.status();
+        let id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+        bytes(response).await;
+        (status, id)
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_logged_with_their_id() {
+        let capture = Capture::default();
+        let _subscriber = tracing::subscriber::set_default(tracing_subscriber::registry().with(capture.clone()));
+        let temp_dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            slow_query_threshold_ms: 5,
+            ..test_util::config(&temp_dir)
+        };
+        let app = DatabaseServer::with_config(config).await.unwrap().router();
+        let sql = |sql: &str| serde_json::json!({ "sql": sql });
+
+        post(&app, "/api/query", None, sql("CREATE TABLE t (id INT PRIMARY KEY, v TEXT)")).await;
+        let values: Vec<String> = (0..5000).map(|i| format!("({}, 'secret {}')", i, i)).collect();
+        post(&app, "/api/query", None, sql(&format!("INSERT INTO t VALUES {}", values.join(", ")))).await;
+        capture.drain();
+
+        // The client's id is kept, and the span has the request's outcome
+        let slow = "SELECT v FROM t WHERE id IN (SELECT id FROM t WHERE id % 2 = 0) AND v <> 'secret 8' ORDER BY v";
+        let (status, id) = post(&app, "/api/query", Some("req-42"), sql(slow)).await;
+        assert_eq!((status, id.as_str()), (StatusCode::OK, "req-42"));
+        let events = capture.drain();
+        let finished = logged(&events, "nextdb::request");
+        assert!(finished.fields["message"].starts_with("POST /api/query 200 in "), "{:?}", finished);
+        let request = finished.request.unwrap();
+        assert_eq!(request["request_id"], "req-42");
+        assert_eq!((request["method"].as_str(), request["route"].as_str()), ("POST", "/api/query"));
+        assert_eq!((request["status"].as_str(), request["rows"].as_str()), ("200", "2499"));
+        assert!(request["duration_ms"].parse::<f64>().unwrap() >= 5.0);
+        assert_eq!(
+            request["sql"],
+            "SELECT v FROM t WHERE id IN (SELECT id FROM t WHERE id % ? = ?) AND v <> ? ORDER BY v"
+        );
+
+        // The slow query log entry is in the same request, with the plan
+        // and the fingerprint of the SQL
+        let slow = logged(&events, "nextdb::slow_query");
+        assert_eq!(slow.request.unwrap()["request_id"], "req-42");
+        let message = &slow.fields["message"];
+        assert!(message.contains("| plan: Project v <- Sort v <- "), "{}", message);
+        assert!(message.contains(": SELECT v FROM t WHERE (id IN (SELECT id FROM t WHERE (id % ?) = ?)) AND (v <> ?) ORDER BY v |"), "{}", message);
+
+        // Without a usable id one is made up, also for failed requests
+        let (status, id) = post(&app, "/api/query", Some("bad id"), sql("SELECT * FROM missing")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(id.len(), 32);
+        let request = logged(&capture.drain(), "nextdb::request").request.unwrap();
+        assert_eq!((request["request_id"].as_str(), request["status"].as_str()), (id.as_str(), "404"));
+        assert!(!request.contains_key("rows"));
+
+        // Routes are logged by pattern
+        let (status, _) = post(&app, "/api/txn/abc/commit", None, serde_json::json!({})).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(logged(&capture.drain(), "nextdb::request").request.unwrap()["route"], "/api/txn/:id/commit");
+    }
+}
@@ -1,59 +1,129 @@
-use crate::{ServerConfig, Result};
+use crate::{admin, auth::{self, DatabaseAccess, Scope}, dashboard, batch::{self, BatchLimits}, cluster::{self, Topology}, cursor::{CursorLimits, Cursors}, databases::{Databases, DEFAULT_DATABASE}, forward::{self, Leadership, NotLeader}, health::{self, Readiness}, kv, load_shed::{self, LoadShedder}, metrics::QueryMetrics, prepared::{self, PreparedStatements}, protocol, reload::{self, ConfigReload}, rate_limit::{self, Client, RateLimiter}, replication::{RaftReplicator, Replication}, request_log, tls::TlsListener, txn::{self, Transactions}, watch::{self, ChangeHub}, Config, ServerConfig, ServerError, Result};
 use axum::{
-    extract::State,
+    extract::{Path, State},
     http::StatusCode,
-    response::{Html, Json},
-    routing::{get, post},
-    Router,
+    middleware,
+    response::{IntoResponse, Json, Response},
+    routing::{delete, get, post},
+    Extension, Router,
 };
+use nextdb_consensus::RaftNode;
+use nextdb_query::{QueryError, QueryExecutor, ResultSet, SessionId, SlowQueryConfig, SqlParser, SqlStatement};
+use nextdb_storage::{LSMTree, StorageError};
+use nextdb_transaction::{TransactionError, TransactionManager};
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::SystemTime};
-use tokio::net::TcpListener;
-use tower_http::{cors::CorsLayer, services::ServeDir};
-use tracing::info;
+use std::{net::SocketAddr, path::PathBuf, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::{Duration, Instant, SystemTime}};
+use tokio::{net::TcpListener, sync::Notify};
+use tower_http::cors::CorsLayer;
+use tracing::{error, info, warn};
+
+/// How the server stopped after a shutdown signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    /// Every in-flight request finished within `shutdown_timeout_ms`
+    Drained,
+    /// Requests still running when the timeout ran out were cut off
+    TimedOut,
+}
 
 #[derive(Clone)]
 pub struct DatabaseServer {
     config: ServerConfig,
     state: Arc<DatabaseState>,
+    raft: Option<Arc<tokio::sync::RwLock<RaftNode>>>,
+    /// The task driving `raft` in cluster mode
+    replication: Option<Arc<Replication>>,
+    readiness: Arc<Readiness>,
+    topology: Arc<Topology>,
+    /// Headers for origins `config.cors` allows, or None for same-origin only
+    cors: Option<CorsLayer>,
 }
 
-#[derive(Debug)]
-struct DatabaseState {
+pub(crate) struct DatabaseState {
     start_time: SystemTime,
-    port: u16,
+    pub(crate) storage: Arc<LSMTree>,
+    pub(crate) executor: QueryExecutor,
+    /// Named databases, created with CREATE DATABASE
+    pub(crate) databases: Databases,
+    pub(crate) query_metrics: QueryMetrics,
+    pub(crate) rate_limiter: Option<RateLimiter>,
+    pub(crate) load_shedder: Option<LoadShedder>,
+    pub(crate) changes: Arc<ChangeHub>,
+    pub(crate) batch_limits: BatchLimits,
+    /// Where `/api/admin/checkpoint` writes checkpoints
+    pub(crate) checkpoint_dir: PathBuf,
+    /// Where `/api/admin/restore` stages a restore for the next start
+    pub(crate) restore_dir: PathBuf,
+    pub(crate) restoring: tokio::sync::Mutex<()>,
+    pub(crate) cursors: Arc<Cursors>,
+    /// Transactions open across `/api/txn` requests
+    pub(crate) txns: Transactions,
+    /// Statements prepared with `/api/prepare`
+    pub(crate) prepared: PreparedStatements,
+    pub(crate) config_reload: ConfigReload,
+    /// Where the leader is, in cluster mode
+    pub(crate) leadership: Option<Leadership>,
+    next_session: AtomicU64,
+    // Query count and time of the previous collection, for the query rate
+    last_collected: Mutex<Option<(Instant, u64)>>,
     storage_stats: tokio::sync::RwLock<StorageStats>,
     consensus_stats: tokio::sync::RwLock<ConsensusStats>,
     query_stats: tokio::sync::RwLock<QueryStats>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+// Fields the server cannot measure yet are null rather than made up
+
+#[derive(Debug, Clone, Default, Serialize)]
 struct StorageStats {
     memtable_size: u64,
     sstable_count: u32,
-    cache_hit_rate: f64,
+    /// Null until the block cache has served a lookup
+    cache_hit_rate: Option<f64>,
+    /// Null until the row cache has served a lookup, or without one
+    row_cache_hit_rate: Option<f64>,
+    /// Entries in memtables and SSTables, counting each overwritten version
+    /// and tombstone until compaction drops it
     total_keys: u64,
     total_size_bytes: u64,
-    compaction_count: u32,
+    /// Null: compactions are not run yet
+    compaction_count: Option<u32>,
+    /// Why writes are being delayed right now, or null if they are not
+    write_stall_reason: Option<String>,
+    /// Total time writes have spent stalled since the storage was opened
+    write_stall_micros: u64,
 }
 
 #[derive(Debug, Clone, Serialize)]
 struct ConsensusStats {
-    node_id: String,
-    role: String, // "leader", "follower", "candidate"
-    current_term: u64,
-    commit_index: u64,
+    node_id: Option<String>,
+    role: String, // "leader", "follower", "candidate", or "standalone" without Raft
+    current_term: Option<u64>,
+    commit_index: Option<u64>,
     cluster_size: u32,
-    healthy_nodes: u32,
+    /// Null: peer health is not tracked yet
+    healthy_nodes: Option<u32>,
+    /// Linearizable reads this node has started, each confirmed by a
+    /// majority before it is served
+    linearizable_reads: Option<u64>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Default, Serialize)]
 struct QueryStats {
     total_queries: u64,
+    failed_queries: u64,
+    /// Rate over the last collection interval
     queries_per_second: f64,
-    avg_latency_ms: f64,
-    p99_latency_ms: f64,
-    cache_hit_rate: f64,
+    avg_latency_ms: Option<f64>,
+    /// Upper bound of the latency histogram bucket holding the 99th percentile
+    p99_latency_ms: Option<f64>,
+    /// Null when the result cache is disabled or unused
+    cache_hit_rate: Option<f64>,
+    /// Requests running SQL and waiting to, null without load shedding
+    statements_in_flight: Option<u64>,
+    statements_queued: Option<u64>,
+    /// Other API requests handled and waiting, null without load shedding
+    requests_in_flight: Option<u64>,
+    requests_queued: Option<u64>,
 }
 
 #[derive(Serialize)]
@@ -69,124 +139,496 @@ struct SystemStatus {
 #[derive(Deserialize)]
 struct QueryRequest {
     sql: String,
+    /// Return a SELECT's rows this many at a time, with a cursor to the rest
+    max_rows: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct CloseCursorRequest {
+    cursor: String,
+}
+
+#[derive(Deserialize)]
+struct NextPageRequest {
+    cursor: String,
+    /// Defaults to the `max_rows` the cursor was opened with
+    max_rows: Option<usize>,
 }
 
+/// Shape of `QueryResponse::result`. Version 2 carries typed columns and
+/// natively typed JSON values; version 1 was a list of objects with every
+/// value as a string.
+pub(crate) const RESULT_FORMAT: u32 = 2;
+
 #[derive(Serialize)]
 struct QueryResponse {
     success: bool,
+    result_format: u32,
     rows_affected: Option<u64>,
     execution_time_ms: f64,
-    result: Option<serde_json::Value>,
-    error: Option<String>,
+    result: Option<ResultSet>,
+    /// Continues the result with `/api/query/next`; null once every row
+    /// has been returned
+    cursor: Option<String>,
+    error: Option<ErrorBody>,
+    /// Set when the statement did not run and may succeed if sent again
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    retryable: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ErrorBody {
+    /// Stable name of the kind of error, such as "table_not_found"
+    pub(crate) code: &'static str,
+    pub(crate) message: String,
 }
 
 impl DatabaseServer {
-    pub async fn new(port: u16) -> Result<Self> {
-        let config = ServerConfig::new(port);
+    /// Open the database and build a server over it. With
+    /// `config.consensus` set, the server runs in cluster mode: its writes
+    /// go through a Raft node kept under `data_dir/raft`.
+    pub async fn new(config: Config) -> Result<Self> {
+        config.validate()?;
+        admin::apply_staged_restore(&config)?;
+        let config_reload = ConfigReload::new(config.clone());
+        let storage = Arc::new(LSMTree::open(config.storage.clone()).await?);
+        storage.spawn_memtable_flusher();
+        let mut executor = open_executor(storage.clone(), &config).await?;
+        let databases = Databases::open(&config).await?;
+        let cluster = match config.consensus {
+            Some(mut consensus) => {
+                // Where the cluster sends clients looking for this node
+                consensus.addresses.entry(consensus.node_id).or_insert_with(|| config.server.listen_address());
+                let node = RaftNode::open(consensus, &config.server.data_dir.join("raft"))?;
+                let (replicator, requests) = RaftReplicator::new(node.config().linearizable_reads);
+                executor = executor.with_replicator(Arc::new(replicator));
+                Some((node, requests))
+            }
+            None => None,
+        };
+        let leadership = cluster.as_ref().map(|(node, _)| Leadership::new(
+            node.config().forward_writes,
+            node.config().linearizable_reads,
+            config.server.tls.is_some(),
+            config.server.max_batch_bytes,
+        ));
+        let changes = ChangeHub::start(storage.clone(), executor.catalog().clone(), config.server.watch_history);
         let state = Arc::new(DatabaseState {
             start_time: SystemTime::now(),
-            port,
+            storage,
+            executor,
+            databases,
+            query_metrics: QueryMetrics::new(),
+            rate_limiter: config.server.rate_limit.clone().map(RateLimiter::new),
+            load_shedder: config.server.load_shedding.as_ref().map(LoadShedder::new),
+            changes,
+            batch_limits: BatchLimits {
+                max_statements: config.server.max_batch_statements,
+                max_bytes: config.server.max_batch_bytes,
+            },
+            checkpoint_dir: config.server.data_dir.join("checkpoints"),
+            restore_dir: config.server.data_dir.join("restore"),
+            restoring: tokio::sync::Mutex::new(()),
+            cursors: Cursors::start(CursorLimits {
+                idle_timeout: Duration::from_millis(config.server.cursor_idle_timeout_ms),
+                per_client: config.server.max_cursors_per_client,
+            }),
+            txns: Transactions::new(Duration::from_millis(config.server.transaction_idle_timeout_ms)),
+            prepared: PreparedStatements::new(config.server.max_prepared_statements_per_client),
+            config_reload,
+            leadership,
+            next_session: AtomicU64::new(1),
+            last_collected: Mutex::new(None),
             storage_stats: tokio::sync::RwLock::new(StorageStats::default()),
             consensus_stats: tokio::sync::RwLock::new(ConsensusStats::default()),
             query_stats: tokio::sync::RwLock::new(QueryStats::default()),
         });
+        Transactions::start_sweeper(&state);
+
+        let replication = match cluster {
+            Some((node, requests)) => {
+                let replication = Replication::start(node, state.clone(), requests, config.server.listen_address()).await?;
+                Some(Arc::new(replication))
+            }
+            None => None,
+        };
+        let raft = replication.as_ref().map(|replication| replication.raft().clone());
+        let readiness = Arc::new(Readiness::new(state.clone(), raft.clone(), config.server.readiness_cache()));
+        let topology = Arc::new(Topology::new(raft.clone(), config.server.listen_address()));
+        let cors = config.server.cors.as_ref().map(|cors| cors.layer()).transpose()?;
+        let server = Self { config: config.server, state, raft, replication, readiness, topology, cors };
+        if let Some(replication) = &server.replication {
+            replication.serve_forwarded(server.router());
+        }
+        server.collect_stats().await;
+        Ok(server)
+    }
+
+    /// Open the database under `config.data_dir`, with default storage and
+    /// transaction settings and no Raft node
+    pub async fn with_config(config: ServerConfig) -> Result<Self> {
+        Self::new(config.into()).await
+    }
 
-        Ok(Self { config, state })
+    /// Load the config with `load` again on SIGHUP and on
+    /// `POST /api/admin/reload-config`, applying the changes that do not
+    /// need a restart (see `reload`)
+    pub fn with_config_loader(self, load: impl Fn() -> Result<Config> + Send + Sync + 'static) -> Self {
+        self.state.config_reload.set_loader(Arc::new(load));
+        self
     }
 
-    pub async fn start(self) -> Result<()> {
+    /// Apply a reloaded `log_level` with `set`, which replaces the tracing
+    /// filter or says why it cannot
+    pub fn with_log_filter(
+        self,
+        set: impl Fn(Option<&str>) -> std::result::Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.state.config_reload.set_log_filter(Arc::new(set));
+        self
+    }
+
+    /// Report consensus stats from `node`
+    pub fn with_raft_node(mut self, node: Arc<tokio::sync::RwLock<RaftNode>>) -> Self {
+        let cache_for = self.config.readiness_cache();
+        self.readiness = Arc::new(Readiness::new(self.state.clone(), Some(node.clone()), cache_for));
+        self.topology = Arc::new(Topology::new(Some(node.clone()), self.config.listen_address()));
+        self.raft = Some(node);
+        self
+    }
+
+    /// Serve until SIGINT or SIGTERM, then stop accepting connections, let
+    /// in-flight requests drain for up to `shutdown_timeout_ms` and `close`
+    pub async fn start(self) -> Result<Shutdown> {
         info!("🔥 NextDB Server starting on port {}", self.config.port);
 
-        // Create static file directory if it doesn't exist
-        tokio::fs::create_dir_all("web/static").await?;
-        
-        // Create the main HTML page
-        self.create_web_interface().await?;
+        // Fail before anything starts if the certificate is unusable
+        let tls = self.config.tls.as_ref().map(TlsListener::new).transpose()?;
 
-        let app = Router::new()
-            .route("/", get(serve_dashboard))
-            .route("/api/status", get(get_status))
-            .route("/api/query", post(execute_query))
-            .route("/api/storage/stats", get(get_storage_stats))
-            .route("/api/consensus/stats", get(get_consensus_stats))
-            .route("/api/query/stats", get(get_query_stats))
-            .nest_service("/static", ServeDir::new("web/static"))
-            .layer(CorsLayer::permissive())
-            .with_state(self.state.clone());
+        let app = self.router();
 
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.config.port)).await?;
+        let listener = TcpListener::bind(self.config.listen_address()).await?;
         
-        info!("🚀 NextDB Server running on http://localhost:{}", self.config.port);
-        info!("📊 Dashboard available at http://localhost:{}/", self.config.port);
-        info!("📡 API available at http://localhost:{}/api", self.config.port);
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        info!("🚀 NextDB Server running on {}://localhost:{}", scheme, self.config.port);
+        info!("📊 Dashboard available at {}://localhost:{}/", scheme, self.config.port);
+        info!("📡 API available at {}://localhost:{}/api", scheme, self.config.port);
 
-        // Start background tasks for simulation
-        self.start_simulation_tasks();
+        self.start_stats_collector();
+        #[cfg(unix)]
+        reload::reload_on_hangup(self.state.clone());
 
-        axum::serve(listener, app).await?;
+        if let Some(port) = self.config.protocol_port {
+            let listener = TcpListener::bind(format!("{}:{}", self.config.bind_address, port)).await?;
+            info!("⚡ Binary protocol listening on port {}", port);
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_protocol(listener).await {
+                    error!("Binary protocol listener failed: {}", e);
+                }
+            });
+        }
+
+        #[cfg(feature = "postgres")]
+        if let Some(postgres) = &self.config.postgres {
+            let listener = TcpListener::bind(format!("{}:{}", self.config.bind_address, postgres.port)).await?;
+            info!("🐘 PostgreSQL protocol listening on port {}", postgres.port);
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_postgres(listener).await {
+                    error!("PostgreSQL listener failed: {}", e);
+                }
+            });
+        }
+
+        let stop = Arc::new(Notify::new());
+        let stopping = {
+            let stop = stop.clone();
+            async move { stop.notified().await }
+        };
+        let serve = async {
+            match tls {
+                Some(tls) => tls.serve(listener, app, stopping).await,
+                None => {
+                    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+                    axum::serve(listener, app).with_graceful_shutdown(stopping).await
+                }
+            }
+        };
+        tokio::pin!(serve);
+
+        let outcome = tokio::select! {
+            result = &mut serve => {
+                result?;
+                Shutdown::Drained
+            }
+            () = shutdown_signal() => {
+                let timeout = Duration::from_millis(self.config.shutdown_timeout_ms);
+                info!("🛑 Shutting down, giving in-flight requests {:?} to finish", timeout);
+                stop.notify_one();
+                match tokio::time::timeout(timeout, &mut serve).await {
+                    Ok(result) => {
+                        result?;
+                        Shutdown::Drained
+                    }
+                    Err(_) => {
+                        warn!("Requests were still running after {:?} and were cut off", timeout);
+                        Shutdown::TimedOut
+                    }
+                }
+            }
+        };
 
+        self.close().await?;
+        info!("👋 NextDB Server stopped");
+        Ok(outcome)
+    }
+
+    /// Close query cursors, roll back open transactions, close the storage
+    /// engine so the next start need not replay the WAL, and stop the Raft
+    /// node. Queries that write fail from then on.
+    pub async fn close(&self) -> Result<()> {
+        let cursors = self.state.cursors.close_all().await;
+        if cursors > 0 {
+            info!("Closed {} query cursors", cursors);
+        }
+        self.state.txns.close();
+        let rolled_back = self.state.executor.end_all_sessions().await?;
+        if rolled_back > 0 {
+            info!("Rolled back {} open transactions", rolled_back);
+        }
+        // Nothing is applied to the storage engine once the node stops
+        match &self.replication {
+            Some(replication) => replication.stop().await,
+            None => {
+                if let Some(raft) = &self.raft {
+                    raft.write().await.stop();
+                }
+            }
+        }
+        self.state.storage.close().await?;
+        self.state.databases.close().await?;
         Ok(())
     }
 
-    async fn create_web_interface(&self) -> Result<()> {
-        let html_content = include_str!("../../../web/dashboard.html");
-        tokio::fs::write("web/static/index.html", html_content).await?;
-        info!("📄 Web dashboard created at web/static/index.html");
+    /// Serve the dashboard and HTTP API over TLS, with the certificate of
+    /// `config.tls`, to clients connecting on `listener`
+    pub async fn serve_https(&self, listener: TcpListener) -> Result<()> {
+        let tls = self.config.tls.as_ref()
+            .ok_or_else(|| ServerError::Config("serving HTTPS requires a TLS config".to_string()))?;
+        TlsListener::new(tls)?.serve(listener, self.router(), std::future::pending()).await?;
         Ok(())
     }
 
-    fn start_simulation_tasks(&self) {
-        let state = self.state.clone();
-        
-        // Simulate storage operations
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
-            loop {
-                interval.tick().await;
-                let mut stats = state.storage_stats.write().await;
-                stats.total_keys += 150;
-                stats.total_size_bytes += 4096 * 150;
-                stats.memtable_size = (stats.memtable_size + 2048) % 64000000;
-                stats.cache_hit_rate = 0.85 + (rand::random_f64() * 0.1);
-                if rand::random_f64() > 0.8 {
-                    stats.compaction_count += 1;
-                    stats.sstable_count += 1;
-                }
-            }
-        });
+    /// Serve the binary protocol to clients connecting on `listener`
+    pub async fn serve_protocol(&self, listener: TcpListener) -> Result<()> {
+        let settings = protocol::Settings {
+            auth: self.config.auth.clone().map(Arc::new),
+            max_frame_bytes: self.config.max_frame_bytes,
+            max_connections: self.config.max_protocol_connections,
+            idle_timeout: Duration::from_millis(self.config.protocol_idle_timeout_ms),
+            max_statements: self.config.max_prepared_statements_per_client,
+            tls: self.config.tls.as_ref().map(TlsListener::for_protocol).transpose()?.map(TlsListener::into_acceptor),
+        };
+        protocol::serve(listener, self.state.clone(), settings).await?;
+        Ok(())
+    }
+
+    /// Serve the PostgreSQL wire protocol to clients connecting on
+    /// `listener`, with the settings of `config.postgres`
+    #[cfg(feature = "postgres")]
+    pub async fn serve_postgres(&self, listener: TcpListener) -> Result<()> {
+        let config = self.config.postgres.clone().unwrap_or_default();
+        let auth = self.config.auth.clone().map(Arc::new);
+        crate::postgres::serve(listener, self.state.clone(), config, auth, self.config.max_frame_bytes).await?;
+        Ok(())
+    }
+
+    /// Routes for the dashboard, the HTTP API and the health probes, with
+    /// the API behind `config.auth` and CORS headers per `config.cors`
+    pub fn router(&self) -> Router {
+        let auth = self.config.auth.clone().map(Arc::new);
+        // For the routes that write, which followers send to the leader
+        let follow_leader = || middleware::from_fn_with_state(self.state.clone(), forward::follow_leader);
+        let admin = Router::new()
+            .route("/api/admin/flush", post(admin::flush))
+            .route("/api/admin/compact", post(admin::compact))
+            .route("/api/admin/checkpoint", post(admin::checkpoint))
+            .route("/api/admin/lsm", get(admin::lsm))
+            .route("/api/admin/wal/sync", post(admin::sync_wal))
+            .route("/api/admin/backup", post(admin::backup))
+            .route("/api/admin/restore", post(admin::restore))
+            .route("/api/admin/reload-config", post(reload::reload_config))
+            .route_layer(middleware::from_fn(auth::require_admin));
+        let api = Router::new()
+            .route("/api/status", get(get_status))
+            .route("/api/query", post(execute_query).route_layer(follow_leader()))
+            .route("/api/query/next", post(next_page))
+            .route("/api/db/:database/query", post(execute_database_query).route_layer(follow_leader()))
+            .route("/api/db/:database/query/next", post(next_page))
+            .route("/api/query/close", post(close_cursor))
+            .route("/api/db/:database/query/close", post(close_cursor))
+            .route("/api/batch", post(batch::execute_batch).route_layer(follow_leader()))
+            .route("/api/kv", get(kv::scan).route_layer(follow_leader()))
+            .route("/api/kv/multi-get", post(kv::multi_get).route_layer(follow_leader()))
+            .route("/api/kv/:key", get(kv::get).put(kv::put).delete(kv::delete).route_layer(follow_leader()))
+            .route("/api/prepare", post(prepared::prepare))
+            .route("/api/prepare/:id", delete(prepared::deallocate))
+            .route("/api/execute", post(prepared::execute))
+            .route("/api/txn/begin", post(txn::begin))
+            .route("/api/txn/:id/query", post(txn::query))
+            .route("/api/txn/:id/commit", post(txn::commit))
+            .route("/api/txn/:id/rollback", post(txn::rollback))
+            .route("/api/storage/stats", get(get_storage_stats))
+            .route("/api/consensus/stats", get(get_consensus_stats))
+            .route("/api/query/stats", get(get_query_stats))
+            .route("/api/watch", get(watch::watch))
+            .route("/api/cluster/nodes", get(cluster::nodes).with_state(self.topology.clone()))
+            .merge(admin)
+            .route_layer(middleware::from_fn_with_state(self.state.clone(), rate_limit::limit_requests))
+            .route_layer(middleware::from_fn_with_state(auth, auth::require_token))
+            .route_layer(middleware::from_fn_with_state(self.state.clone(), load_shed::shed_load));
+        let router = Router::new()
+            .merge(dashboard::routes(self.config.dashboard_dir.as_deref()))
+            .route("/health", get(health::health))
+            .merge(api)
+            .with_state(self.state.clone())
+            .merge(Router::new().route("/ready", get(health::ready)).with_state(self.readiness.clone()))
+            .route_layer(middleware::from_fn(request_log::log_requests));
+        match &self.cors {
+            Some(cors) => router.layer(cors.clone()),
+            None => router,
+        }
+    }
 
-        let state = self.state.clone();
-        // Simulate consensus operations
+    #[cfg(test)]
+    pub(crate) fn state(&self) -> &Arc<DatabaseState> {
+        &self.state
+    }
+
+    fn start_stats_collector(&self) {
+        let server = self.clone();
+        let period = Duration::from_millis(self.config.stats_interval_ms.max(1));
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(std::time::Duration::from_secs(3));
+            let mut interval = tokio::time::interval(period);
             loop {
                 interval.tick().await;
-                let mut stats = state.consensus_stats.write().await;
-                stats.current_term += if rand::random_f64() > 0.95 { 1 } else { 0 };
-                stats.commit_index += 1;
+                server.collect_stats().await;
             }
         });
+    }
 
-        let state = self.state.clone();
-        // Simulate query operations
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
-            loop {
-                interval.tick().await;
-                let mut stats = state.query_stats.write().await;
-                let new_queries = 50 + (rand::random::<u64>() % 100);
-                stats.total_queries += new_queries;
-                stats.queries_per_second = new_queries as f64;
-                stats.avg_latency_ms = 2.5 + (rand::random_f64() * 2.0);
-                stats.p99_latency_ms = 8.0 + (rand::random_f64() * 4.0);
-                stats.cache_hit_rate = 0.75 + (rand::random_f64() * 0.2);
+    /// Refresh the stats the `/api/*stats` endpoints report from the storage
+    /// engine, the Raft node and the query counters
+    pub(crate) async fn collect_stats(&self) {
+        let state = &self.state;
+
+        let lsm = state.storage.stats().await;
+        *state.storage_stats.write().await = StorageStats {
+            memtable_size: lsm.memtable_size as u64,
+            sstable_count: lsm.level_file_counts.iter().sum::<usize>() as u32,
+            cache_hit_rate: lsm.cache.hit_rate(),
+            row_cache_hit_rate: lsm.row_cache.and_then(|stats| stats.hit_rate()),
+            total_keys: lsm.memtable_entries + lsm.sstable_entries,
+            total_size_bytes: lsm.sstable_bytes + lsm.memtable_size as u64,
+            compaction_count: None,
+            write_stall_reason: lsm.write_stall_reason.map(|reason| reason.to_string()),
+            write_stall_micros: lsm.write_stall_micros,
+        };
+
+        if let Some(raft) = &self.raft {
+            let metrics = raft.read().await.metrics();
+            *state.consensus_stats.write().await = ConsensusStats {
+                node_id: Some(metrics.node_id.0.to_string()),
+                role: cluster::role(&metrics.state).to_string(),
+                current_term: Some(metrics.current_term),
+                commit_index: Some(metrics.commit_index),
+                cluster_size: metrics.cluster_size as u32,
+                healthy_nodes: None,
+                linearizable_reads: Some(metrics.reads_started),
+            };
+        }
+
+        let metrics = &state.query_metrics;
+        let total_queries = metrics.total();
+        let queries_per_second = {
+            let now = Instant::now();
+            let previous = state.last_collected.lock().unwrap().replace((now, total_queries));
+            match previous {
+                Some((at, count)) if now > at => (total_queries - count) as f64 / (now - at).as_secs_f64(),
+                _ => 0.0,
             }
+        };
+        let load = state.load_shedder.as_ref().map(LoadShedder::load);
+        *state.query_stats.write().await = QueryStats {
+            total_queries,
+            failed_queries: metrics.failed(),
+            queries_per_second,
+            avg_latency_ms: metrics.mean_latency_ms(),
+            p99_latency_ms: metrics.latency_quantile_ms(0.99),
+            cache_hit_rate: state.executor.result_cache().and_then(|cache| {
+                let stats = cache.stats();
+                let lookups = stats.hits + stats.misses;
+                (lookups > 0).then(|| stats.hits as f64 / lookups as f64)
+            }),
+            statements_in_flight: load.map(|load| load.statements_in_flight as u64),
+            statements_queued: load.map(|load| load.statements_queued as u64),
+            requests_in_flight: load.map(|load| load.requests_in_flight as u64),
+            requests_queued: load.map(|load| load.requests_queued as u64),
+        };
+    }
+}
+
+/// An executor over `storage` with the query settings of `config`
+pub(crate) async fn open_executor(storage: Arc<LSMTree>, config: &Config) -> nextdb_query::Result<QueryExecutor> {
+    let mut executor = QueryExecutor::open(storage).await?
+        .with_transaction_manager(Arc::new(TransactionManager::with_config(&config.transaction)));
+    if config.server.slow_query_threshold_ms > 0 {
+        executor = executor.with_slow_query_log(SlowQueryConfig {
+            threshold: Duration::from_millis(config.server.slow_query_threshold_ms),
+            max_per_second: config.server.slow_query_log_per_second,
         });
     }
+    if config.server.max_query_memory_bytes > 0 {
+        executor = executor.with_query_memory_limit(config.server.max_query_memory_bytes);
+    }
+    Ok(executor)
 }
 
-async fn serve_dashboard() -> Html<&'static str> {
-    Html(include_str!("../../../web/dashboard.html"))
+impl DatabaseState {
+    /// A session no other client has
+    pub(crate) fn new_session(&self) -> SessionId {
+        SessionId(self.next_session.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Resolves on ctrl-c (SIGINT) or, on Unix, SIGTERM
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Cannot listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Cannot listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = interrupt => {}
+        () = terminate => {}
+    }
 }
 
 async fn get_status(State(state): State<Arc<DatabaseState>>) -> Json<SystemStatus> {
@@ -206,45 +648,234 @@ async fn get_status(State(state): State<Arc<DatabaseState>>) -> Json<SystemStatu
 }
 
 async fn execute_query(
-    State(_state): State<Arc<DatabaseState>>,
+    State(state): State<Arc<DatabaseState>>,
+    Extension(scope): Extension<Scope>,
+    access: Option<Extension<DatabaseAccess>>,
+    client: Option<Extension<Client>>,
     Json(req): Json<QueryRequest>,
-) -> std::result::Result<Json<QueryResponse>, StatusCode> {
-    info!("Executing SQL query: {}", req.sql);
-    
-    // Simulate query execution
-    let execution_time = 1.5 + (rand::random_f64() * 3.0);
-    tokio::time::sleep(std::time::Duration::from_millis(execution_time as u64)).await;
-
-    let response = if req.sql.to_lowercase().contains("select") {
-        QueryResponse {
-            success: true,
-            rows_affected: None,
-            execution_time_ms: execution_time,
-            result: Some(serde_json::json!([
-                {"id": 1, "name": "Alice", "age": 30},
-                {"id": 2, "name": "Bob", "age": 25}
-            ])),
-            error: None,
-        }
-    } else if req.sql.to_lowercase().contains("insert") {
-        QueryResponse {
-            success: true,
-            rows_affected: Some(1),
-            execution_time_ms: execution_time,
-            result: None,
-            error: None,
-        }
-    } else {
-        QueryResponse {
+) -> Response {
+    run_query(&state, DEFAULT_DATABASE, scope, access, client, req).await
+}
+
+/// `/api/db/{database}/query`: a query against the named database
+async fn execute_database_query(
+    State(state): State<Arc<DatabaseState>>,
+    Path(database): Path<String>,
+    Extension(scope): Extension<Scope>,
+    access: Option<Extension<DatabaseAccess>>,
+    client: Option<Extension<Client>>,
+    Json(req): Json<QueryRequest>,
+) -> Response {
+    run_query(&state, &database, scope, access, client, req).await
+}
+
+async fn run_query(
+    state: &DatabaseState,
+    database: &str,
+    scope: Scope,
+    access: Option<Extension<DatabaseAccess>>,
+    client: Option<Extension<Client>>,
+    req: QueryRequest,
+) -> Response {
+    request_log::record_sql(&req.sql);
+
+    let started = Instant::now();
+    let refuse = |code, message: &str| {
+        let response = QueryResponse {
             success: false,
+            result_format: RESULT_FORMAT,
             rows_affected: None,
-            execution_time_ms: execution_time,
+            execution_time_ms: 0.0,
             result: None,
-            error: Some("Query not supported in simulation mode".to_string()),
+            cursor: None,
+            error: Some(ErrorBody { code, message: message.to_string() }),
+            retryable: false,
+        };
+        (StatusCode::FORBIDDEN, Json(response)).into_response()
+    };
+    let named = match database {
+        DEFAULT_DATABASE => None,
+        name => match state.databases.get(name).await {
+            Ok(named) => Some(named),
+            Err(e) => return respond(state, started, Err(e), None),
+        },
+    };
+    let executor = named.as_ref().map_or(&state.executor, |named| &named.executor);
+    let result = match SqlParser::parse(&req.sql) {
+        Ok(statement) if !scope.allows(&statement) => {
+            return refuse("read_only_token", "the API token is read-only and the query writes");
         }
+        Ok(SqlStatement::CreateDatabase { .. } | SqlStatement::DropDatabase { .. }) if scope != Scope::Admin => {
+            return refuse("admin_required", "creating and dropping databases needs the admin scope");
+        }
+        Ok(SqlStatement::CreateDatabase { name, .. } | SqlStatement::DropDatabase { name, .. })
+            if !access.as_ref().is_none_or(|Extension(access)| access.allows(&name)) =>
+        {
+            return refuse("database_forbidden", "the API token may not use this database");
+        }
+        Ok(SqlStatement::CreateDatabase { name, if_not_exists }) => state.databases.create(&name, if_not_exists).await,
+        Ok(SqlStatement::DropDatabase { name, if_exists }) => state.databases.drop(&name, if_exists).await,
+        Ok(_) if req.max_rows == Some(0) => Err(QueryError::Invalid("max_rows must be greater than 0".to_string())),
+        Ok(statement) => {
+            let client = client.map_or(Client::Unknown, |Extension(client)| client);
+            let admitted = state.rate_limiter.as_ref().map(|limiter| limiter.admit_query(&client, &statement));
+            let _permit = match admitted.transpose() {
+                Ok(permit) => permit,
+                Err(limited) => return limited.into_response(),
+            };
+            match req.max_rows {
+                Some(max_rows) if matches!(statement, SqlStatement::Select(_)) => {
+                    return first_page(state, executor, client, statement, max_rows, started).await;
+                }
+                _ => executor.execute_statement(statement).await,
+            }
+        }
+        Err(e) => Err(e),
+    };
+    respond(state, started, result, None)
+}
+
+/// Open a cursor for `statement` and return its first page, keeping the
+/// cursor if rows remain
+async fn first_page(
+    state: &DatabaseState,
+    executor: &QueryExecutor,
+    client: Client,
+    statement: SqlStatement,
+    max_rows: usize,
+    started: Instant,
+) -> Response {
+    let mut cursor = match executor.open_cursor(statement).await {
+        Ok(cursor) => cursor,
+        Err(e) => return respond(state, started, Err(e), None),
+    };
+    let page = cursor.next_page(max_rows).await;
+    if page.is_err() || cursor.is_exhausted() {
+        if let Err(e) = cursor.close().await {
+            warn!("Cannot close cursor: {}", e);
+        }
+        return respond(state, started, page, None);
+    }
+    match state.cursors.insert(client, cursor, max_rows).await {
+        Ok(token) => respond(state, started, page, Some(token)),
+        Err(refused) => refused.into_response(),
+    }
+}
+
+async fn next_page(
+    State(state): State<Arc<DatabaseState>>,
+    client: Option<Extension<Client>>,
+    Json(req): Json<NextPageRequest>,
+) -> Response {
+    let started = Instant::now();
+    let client = client.map_or(Client::Unknown, |Extension(client)| client);
+    let Some((cursor, page_rows)) = state.cursors.get(&req.cursor, &client).await else {
+        return cursor_expired();
+    };
+    if req.max_rows == Some(0) {
+        return respond(&state, started, Err(QueryError::Invalid("max_rows must be greater than 0".to_string())), None);
+    }
+
+    let (page, exhausted) = {
+        let mut cursor = cursor.lock().await;
+        let page = cursor.next_page(req.max_rows.unwrap_or(page_rows)).await;
+        (page, cursor.is_exhausted())
+    };
+    if page.is_err() || exhausted {
+        state.cursors.remove(&req.cursor).await;
+        return respond(&state, started, page, None);
+    }
+    respond(&state, started, page, Some(req.cursor))
+}
+
+/// Close a cursor before its last page is read
+async fn close_cursor(
+    State(state): State<Arc<DatabaseState>>,
+    client: Option<Extension<Client>>,
+    Json(req): Json<CloseCursorRequest>,
+) -> Response {
+    let client = client.map_or(Client::Unknown, |Extension(client)| client);
+    if !state.cursors.close(&req.cursor, &client).await {
+        return cursor_expired();
+    }
+    (StatusCode::OK, Json(serde_json::json!({ "success": true }))).into_response()
+}
+
+fn cursor_expired() -> Response {
+    let error = ErrorBody {
+        code: "cursor_expired",
+        message: "the cursor is unknown, was read to the end, or expired".to_string(),
     };
+    (StatusCode::GONE, Json(serde_json::json!({ "success": false, "error": error }))).into_response()
+}
+
+/// Record a query's outcome and build its response
+pub(crate) fn respond(state: &DatabaseState, started: Instant, result: std::result::Result<ResultSet, QueryError>, cursor: Option<String>) -> Response {
+    let elapsed = started.elapsed();
+    state.query_metrics.record(elapsed, result.is_ok());
+    let execution_time_ms = elapsed.as_secs_f64() * 1000.0;
 
-    Ok(Json(response))
+    let response = QueryResponse {
+        success: result.is_ok(),
+        result_format: RESULT_FORMAT,
+        rows_affected: None,
+        execution_time_ms,
+        result: None,
+        cursor: None,
+        error: None,
+        retryable: false,
+    };
+    match result {
+        Ok(result) if result.rows_affected.is_some() => {
+            (StatusCode::OK, Json(QueryResponse { rows_affected: result.rows_affected, ..response })).into_response()
+        }
+        Ok(result) => {
+            request_log::record_rows(result.rows.len());
+            (StatusCode::OK, Json(QueryResponse { result: Some(result), cursor, ..response })).into_response()
+        }
+        Err(e) => {
+            let (status, code) = error_status(&e);
+            let error = ErrorBody { code, message: e.to_string() };
+            // Writes are refused before they are proposed
+            let retryable = matches!(e, QueryError::NotLeader { .. });
+            let mut response = (status, Json(QueryResponse { error: Some(error), retryable, ..response })).into_response();
+            if retryable {
+                response.extensions_mut().insert(NotLeader);
+            }
+            response
+        }
+    }
+}
+
+/// HTTP status and error code for a failed query: 4xx for mistakes in the
+/// query or conflicts with the data, 5xx for failures of the server itself
+pub(crate) fn error_status(error: &QueryError) -> (StatusCode, &'static str) {
+    match error {
+        QueryError::Parse(_) => (StatusCode::BAD_REQUEST, "parse_error"),
+        QueryError::Plan(_) => (StatusCode::BAD_REQUEST, "plan_error"),
+        QueryError::Invalid(_) => (StatusCode::BAD_REQUEST, "invalid_query"),
+        QueryError::Execution(_) => (StatusCode::UNPROCESSABLE_ENTITY, "execution_error"),
+        QueryError::TableNotFound(_) => (StatusCode::NOT_FOUND, "table_not_found"),
+        QueryError::ColumnNotFound(_) => (StatusCode::NOT_FOUND, "column_not_found"),
+        QueryError::TableExists(_) => (StatusCode::CONFLICT, "table_exists"),
+        QueryError::DatabaseNotFound(_) => (StatusCode::NOT_FOUND, "database_not_found"),
+        QueryError::DatabaseExists(_) => (StatusCode::CONFLICT, "database_exists"),
+        QueryError::ConstraintViolation { .. } => (StatusCode::CONFLICT, "constraint_violation"),
+        QueryError::Transaction(TransactionError::LockTimeout) => {
+            (StatusCode::SERVICE_UNAVAILABLE, "lock_timeout")
+        }
+        QueryError::Transaction(TransactionError::Conflict | TransactionError::Deadlock) => {
+            (StatusCode::CONFLICT, "transaction_conflict")
+        }
+        QueryError::Transaction(_) => (StatusCode::CONFLICT, "transaction_error"),
+        QueryError::NotLeader { .. } => (StatusCode::SERVICE_UNAVAILABLE, "not_leader"),
+        QueryError::Replication(_) => (StatusCode::SERVICE_UNAVAILABLE, "replication_error"),
+        QueryError::Storage(StorageError::Corruption(_)) => (StatusCode::INTERNAL_SERVER_ERROR, "corruption"),
+        QueryError::Storage(StorageError::Closed) => (StatusCode::SERVICE_UNAVAILABLE, "shutting_down"),
+        QueryError::Storage(StorageError::OutOfSpace(_)) => (StatusCode::INSUFFICIENT_STORAGE, "out_of_space"),
+        QueryError::Storage(StorageError::KeyTooLarge { .. }) => (StatusCode::BAD_REQUEST, "key_too_large"),
+        QueryError::Storage(_) | QueryError::Io(_) => (StatusCode::INTERNAL_SERVER_ERROR, "storage_error"),
+    }
 }
 
 async fn get_storage_stats(State(state): State<Arc<DatabaseState>>) -> Json<StorageStats> {
@@ -259,62 +890,148 @@ async fn get_query_stats(State(state): State<Arc<DatabaseState>>) -> Json<QueryS
     Json(state.query_stats.read().await.clone())
 }
 
-impl Default for StorageStats {
+impl Default for ConsensusStats {
     fn default() -> Self {
         Self {
-            memtable_size: 1024 * 1024,
-            sstable_count: 5,
-            cache_hit_rate: 0.85,
-            total_keys: 10000,
-            total_size_bytes: 100 * 1024 * 1024,
-            compaction_count: 0,
+            node_id: None,
+            role: "standalone".to_string(),
+            current_term: None,
+            commit_index: None,
+            cluster_size: 1,
+            healthy_nodes: None,
+            linearizable_reads: None,
         }
     }
 }
 
-impl Default for ConsensusStats {
-    fn default() -> Self {
-        Self {
-            node_id: "node-001".to_string(),
-            role: "leader".to_string(),
-            current_term: 1,
-            commit_index: 0,
-            cluster_size: 3,
-            healthy_nodes: 3,
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, get, query};
+    use tempfile::TempDir;
+
+    async fn get_json(app: &Router, uri: &str) -> serde_json::Value {
+        let (status, body) = get(app, uri).await;
+        assert_eq!(status, StatusCode::OK);
+        body
     }
-}
 
-impl Default for QueryStats {
-    fn default() -> Self {
-        Self {
-            total_queries: 0,
-            queries_per_second: 0.0,
-            avg_latency_ms: 0.0,
-            p99_latency_ms: 0.0,
-            cache_hit_rate: 0.0,
+    async fn server(temp_dir: &TempDir) -> DatabaseServer {
+        DatabaseServer::with_config(test_util::config(temp_dir)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_query_endpoint_runs_sql() {
+        let temp_dir = TempDir::new().unwrap();
+        let app = server(&temp_dir).await.router();
+
+        let (status, body) = query(&app, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT, score FLOAT)").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["success"], true);
+
+        let (status, body) = query(&app, "INSERT INTO users VALUES (1, 'Ada', 9.5), (2, 'Grace', NULL)").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["rows_affected"], 2);
+        assert!(body["result"].is_null());
+
+        let (status, body) = query(&app, "SELECT id, name, score FROM users ORDER BY id").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["result_format"], RESULT_FORMAT);
+        assert!(body["execution_time_ms"].as_f64().unwrap() >= 0.0);
+        assert_eq!(body["result"]["rows"], serde_json::json!([[1, "Ada", 9.5], [2, "Grace", null]]));
+        assert_eq!(body["result"]["columns"][1], serde_json::json!({ "name": "name", "data_type": "TEXT" }));
+
+        // Errors carry a status and code for their kind
+        for (sql, status, code) in [
+            ("SELEKT 1", StatusCode::BAD_REQUEST, "parse_error"),
+            ("SELECT * FROM missing", StatusCode::NOT_FOUND, "table_not_found"),
+            ("INSERT INTO users VALUES (1, 'Dup', 0.0)", StatusCode::CONFLICT, "constraint_violation"),
+            ("SELECT 1 / 0", StatusCode::UNPROCESSABLE_ENTITY, "execution_error"),
+        ] {
+            let (actual, body) = query(&app, sql).await;
+            assert_eq!(actual, status, "{}: {}", sql, body);
+            assert_eq!(body["success"], false);
+            assert_eq!(body["error"]["code"], code, "{}: {}", sql, body);
+            assert!(!body["error"]["message"].as_str().unwrap().is_empty());
         }
     }
-}
 
-// Simple random number generation for simulation
-mod rand {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
-    use std::time::{SystemTime, UNIX_EPOCH};
-
-    pub fn random<T>() -> T
-    where
-        T: From<u64>,
-    {
-        let mut hasher = DefaultHasher::new();
-        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos().hash(&mut hasher);
-        T::from(hasher.finish())
+    #[tokio::test]
+    async fn test_stats_reflect_operations() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = server(&temp_dir).await;
+        let app = server.router();
+
+        // Nothing measured yet is reported as null, not invented
+        let status = get_json(&app, "/api/status").await;
+        assert_eq!(status["query"]["total_queries"], 0);
+        assert!(status["query"]["p99_latency_ms"].is_null());
+        assert!(status["storage"]["compaction_count"].is_null());
+        assert_eq!(status["consensus"]["role"], "standalone");
+
+        query(&app, "CREATE TABLE t (id INT PRIMARY KEY, v TEXT)").await;
+        query(&app, "INSERT INTO t VALUES (0, 'first')").await;
+        server.collect_stats().await;
+        let before = get_json(&app, "/api/storage/stats").await;
+
+        for i in 1..=5 {
+            query(&app, &format!("INSERT INTO t VALUES ({}, 'row')", i)).await;
+        }
+        query(&app, "SELECT * FROM missing").await;
+        server.collect_stats().await;
+
+        let storage = get_json(&app, "/api/storage/stats").await;
+        assert_eq!(storage["total_keys"].as_u64().unwrap(), before["total_keys"].as_u64().unwrap() + 5);
+        assert!(storage["memtable_size"].as_u64().unwrap() > before["memtable_size"].as_u64().unwrap());
+        assert_eq!(storage["sstable_count"], 0);
+
+        let queries = get_json(&app, "/api/query/stats").await;
+        assert_eq!(queries["total_queries"], 8);
+        assert_eq!(queries["failed_queries"], 1);
+        assert!(queries["avg_latency_ms"].as_f64().unwrap() > 0.0);
+        assert!(queries["p99_latency_ms"].as_f64().unwrap() > 0.0);
+
+        // With a Raft node its state is reported
+        let config = nextdb_consensus::RaftConfig {
+            node_id: nextdb_consensus::NodeId::new(),
+            peers: vec![nextdb_consensus::NodeId::new(), nextdb_consensus::NodeId::new()],
+            addresses: Default::default(),
+            election_timeout_ms: 150,
+            heartbeat_interval_ms: 50,
+            rng_seed: None,
+            ..Default::default()
+        };
+        let node_id = config.node_id.0.to_string();
+        let server = server.with_raft_node(Arc::new(tokio::sync::RwLock::new(RaftNode::new(config))));
+        server.collect_stats().await;
+        let consensus = get_json(&server.router(), "/api/consensus/stats").await;
+        assert_eq!(consensus["node_id"], node_id);
+        assert_eq!(consensus["role"], "follower");
+        assert_eq!((consensus["current_term"].as_u64(), consensus["cluster_size"].as_u64()), (Some(0), Some(3)));
+        assert!(consensus["healthy_nodes"].is_null());
     }
+    #[tokio::test]
+    async fn test_storage_stats_report_write_stalls() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config: Config = test_util::config(&temp_dir).into();
+        config.storage.l0_compaction_trigger = 1;
+        config.storage.l0_stall_trigger = 1;
+        let server = DatabaseServer::new(config).await.unwrap();
+        let app = server.router();
+
+        let storage = get_json(&app, "/api/storage/stats").await;
+        assert!(storage["write_stall_reason"].is_null());
+        assert_eq!(storage["write_stall_micros"], 0);
 
-    pub fn random_f64() -> f64 {
-        let mut hasher = DefaultHasher::new();
-        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos().hash(&mut hasher);
-        (hasher.finish() as f64) / (u64::MAX as f64)
+        // A checkpoint flushes without compacting, leaving one L0 file, which
+        // stalls the next write
+        query(&app, "CREATE TABLE t (id INT PRIMARY KEY)").await;
+        server.state.storage.checkpoint(temp_dir.path().join("checkpoint")).await.unwrap();
+        query(&app, "INSERT INTO t VALUES (1)").await;
+        server.collect_stats().await;
+
+        let storage = get_json(&app, "/api/storage/stats").await;
+        assert_eq!(storage["write_stall_reason"], "too many L0 files (1 >= 1)");
+        assert!(storage["write_stall_micros"].as_u64().unwrap() > 0);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,58 @@
+//! Helpers for tests that drive the HTTP API through a router, without a
+//! listener
+
+use crate::ServerConfig;
+use axum::body::{self, Body};
+use axum::http::{header, request, Method, Request, StatusCode};
+use axum::response::Response;
+use axum::Router;
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use tower::ServiceExt;
+
+/// Default settings, with the data kept under `temp_dir`
+pub(crate) fn config(temp_dir: &TempDir) -> ServerConfig {
+    ServerConfig { data_dir: temp_dir.path().to_path_buf(), ..ServerConfig::default() }
+}
+
+/// A `method` request to `uri` with a JSON content type, to add more
+/// headers and the body to
+pub(crate) fn json_request(method: Method, uri: &str) -> request::Builder {
+    Request::builder().method(method).uri(uri).header(header::CONTENT_TYPE, "application/json")
+}
+
+/// Send `request` through `app`
+pub(crate) async fn send(app: &Router, request: Request<Body>) -> Response {
+    app.clone().oneshot(request).await.unwrap()
+}
+
+/// The whole body of `response`
+pub(crate) async fn bytes(response: Response) -> Vec<u8> {
+    body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()
+}
+
+/// The status of `response` and its body parsed as JSON
+pub(crate) async fn json(response: Response) -> (StatusCode, Value) {
+    let status = response.status();
+    (status, serde_json::from_slice(&bytes(response).await).unwrap())
+}
+
+/// Send `request` through `app`, returning the status and JSON body
+pub(crate) async fn send_json(app: &Router, request: Request<Body>) -> (StatusCode, Value) {
+    json(send(app, request).await).await
+}
+
+/// POST `body` to `uri` as JSON
+pub(crate) async fn post(app: &Router, uri: &str, body: Value) -> (StatusCode, Value) {
+    send_json(app, json_request(Method::POST, uri).body(Body::from(body.to_string())).unwrap()).await
+}
+
+/// GET `uri`
+pub(crate) async fn get(app: &Router, uri: &str) -> (StatusCode, Value) {
+    send_json(app, Request::get(uri).body(Body::empty()).unwrap()).await
+}
+
+/// Run `sql` through `/api/query`
+pub(crate) async fn query(app: &Router, sql: &str) -> (StatusCode, Value) {
+    post(app, "/api/query", json!({ "sql": sql })).await
+}
@@ -0,0 +1,439 @@
+//! TLS for the HTTP and binary protocol listeners, with rustls.
+//!
+//! The certificate and key are loaded at startup, and loaded again on
+//! SIGHUP or when either file changes, so a renewed certificate takes
+//! effect without a restart. A reload that fails keeps the previous
+//! certificate. With `client_ca_path` set, clients must present a
+//! certificate issued by one of its CAs (mutual TLS); requests carry the
+//! verified certificate as a `ClientIdentity` extension, which the auth
+//! middleware and handlers can read.
+
+use axum::{extract::{ConnectInfo, Request}, Router};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+/// How often the certificate and key files are checked for changes
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first
+    pub cert_path: PathBuf,
+    /// PEM private key for the leaf certificate
+    pub key_path: PathBuf,
+    /// PEM certificates of the CAs client certificates must be issued by,
+    /// or None to not ask clients for certificates
+    pub client_ca_path: Option<PathBuf>,
+}
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Cannot read {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("Invalid PEM in {path}: {reason}")]
+    Pem { path: PathBuf, reason: String },
+
+    #[error("No certificates in {0}")]
+    NoCertificate(PathBuf),
+
+    #[error("Key {key} does not fit certificate {cert}: {source}")]
+    KeyMismatch { cert: PathBuf, key: PathBuf, source: rustls::Error },
+
+    #[error("Unusable client CA certificates in {path}: {reason}")]
+    ClientCa { path: PathBuf, reason: String },
+
+    #[error("TLS error: {0}")]
+    Rustls(#[from] rustls::Error),
+}
+
+pub type Result<T> = std::result::Result<T, TlsError>;
+
+/// The certificate a client authenticated its connection with
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    pub certificate: CertificateDer<'static>,
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|source| TlsError::Read { path: path.to_path_buf(), source })
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_slice_iter(&read(path)?)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Pem { path: path.to_path_buf(), reason: e.to_string() })?;
+    if certificates.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_path_buf()));
+    }
+    Ok(certificates)
+}
+
+// Modification times of the certificate and key files
+type FileTimes = (Option<SystemTime>, Option<SystemTime>);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Hands out the current certificate, which `reload` replaces
+#[derive(Debug)]
+struct ReloadableCert {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+    // As of the last load
+    loaded: Mutex<FileTimes>,
+}
+
+impl ReloadableCert {
+    fn load(config: &TlsConfig, provider: Arc<CryptoProvider>) -> Result<Self> {
+        let (key, loaded) = Self::read_key(config, &provider)?;
+        Ok(Self {
+            config: config.clone(),
+            provider,
+            current: RwLock::new(Arc::new(key)),
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    fn read_key(config: &TlsConfig, provider: &CryptoProvider) -> Result<(CertifiedKey, FileTimes)> {
+        let loaded = (modified(&config.cert_path), modified(&config.key_path));
+        let chain = read_certificates(&config.cert_path)?;
+        let key = PrivateKeyDer::from_pem_slice(&read(&config.key_path)?)
+            .map_err(|e| TlsError::Pem { path: config.key_path.clone(), reason: e.to_string() })?;
+        let key = CertifiedKey::from_der(chain, key, provider).map_err(|source| TlsError::KeyMismatch {
+            cert: config.cert_path.clone(),
+            key: config.key_path.clone(),
+            source,
+        })?;
+        Ok((key, loaded))
+    }
+
+    fn reload(&self) -> Result<()> {
+        let (key, loaded) = Self::read_key(&self.config, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        *self.loaded.lock().unwrap() = loaded;
+        Ok(())
+    }
+
+    fn files_changed(&self) -> bool {
+        let now = (modified(&self.config.cert_path), modified(&self.config.key_path));
+        *self.loaded.lock().unwrap() != now
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Accepts TLS connections with the certificate of a `TlsConfig`
+pub(crate) struct TlsListener {
+    acceptor: tokio_rustls::TlsAcceptor,
+    certificate: Arc<ReloadableCert>,
+}
+
+impl TlsListener {
+    /// Load the certificate, key and client CAs, failing if any is
+    /// unreadable or the key does not match the certificate
+    pub(crate) fn new(config: &TlsConfig) -> Result<Self> {
+        Self::with_alpn(config, vec![b"h2".to_vec(), b"http/1.1".to_vec()])
+    }
+
+    /// Like `new`, for the binary protocol, which negotiates no application
+    /// protocol
+    pub(crate) fn for_protocol(config: &TlsConfig) -> Result<Self> {
+        Self::with_alpn(config, Vec::new())
+    }
+
+    fn with_alpn(config: &TlsConfig, alpn_protocols: Vec<Vec<u8>>) -> Result<Self> {
+        let provider = provider();
+        let certificate = Arc::new(ReloadableCert::load(config, provider.clone())?);
+
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &config.client_ca_path {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in read_certificates(path)? {
+                    roots.add(certificate)
+                        .map_err(|e| TlsError::ClientCa { path: path.clone(), reason: e.to_string() })?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                    .build()
+                    .map_err(|e| TlsError::ClientCa { path: path.clone(), reason: e.to_string() })?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut server_config = builder.with_cert_resolver(certificate.clone());
+        server_config.alpn_protocols = alpn_protocols;
+
+        Ok(Self { acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(server_config)), certificate })
+    }
+
+    /// The acceptor for connections that are not HTTP, reloading the
+    /// certificate as it changes for as long as it is kept
+    pub(crate) fn into_acceptor(self) -> tokio_rustls::TlsAcceptor {
+        tokio::spawn(watch(Arc::downgrade(&self.certificate)));
+        self.acceptor
+    }
+
+    /// Serve `app` over TLS to clients connecting on `listener`, reloading
+    /// the certificate as it changes. Once `shutdown` resolves no more
+    /// connections are accepted, and this returns when the open ones have
+    /// finished their requests.
+    pub(crate) async fn serve(
+        self,
+        listener: TcpListener,
+        app: Router,
+        shutdown: impl Future<Output = ()>,
+    ) -> io::Result<()> {
+        tokio::spawn(watch(Arc::downgrade(&self.certificate)));
+        let graceful = GracefulShutdown::new();
+        tokio::pin!(shutdown);
+
+        loop {
+            let (stream, peer) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                () = &mut shutdown => break,
+            };
+            let acceptor = self.acceptor.clone();
+            let app = app.clone();
+            let watcher = graceful.watcher();
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!("TLS handshake with {} failed: {}", peer, e);
+                        return;
+                    }
+                };
+                let identity = stream.get_ref().1.peer_certificates()
+                    .and_then(|chain| chain.first())
+                    .map(|certificate| ClientIdentity { certificate: certificate.clone().into_owned() });
+                let service = app.map_request(move |mut request: Request<Incoming>| {
+                    request.extensions_mut().insert(ConnectInfo(peer));
+                    if let Some(identity) = &identity {
+                        request.extensions_mut().insert(identity.clone());
+                    }
+                    request
+                });
+
+                let builder = auto::Builder::new(TokioExecutor::new());
+                let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service));
+                if let Err(e) = watcher.watch(connection).await {
+                    debug!("HTTPS connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+
+        drop(listener);
+        graceful.shutdown().await;
+        Ok(())
+    }
+}
+
+/// Reload the certificate on SIGHUP or when its files change, until the
+/// listener is dropped
+async fn watch(certificate: Weak<ReloadableCert>) {
+    #[cfg(unix)]
+    let mut hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .map_err(|e| warn!("Cannot reload the TLS certificate on SIGHUP: {}", e))
+        .ok();
+    let mut poll = tokio::time::interval(RELOAD_POLL_INTERVAL);
+    poll.tick().await;
+
+    loop {
+        #[cfg(unix)]
+        let hangup = async {
+            match &mut hangups {
+                Some(hangups) => hangups.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let hangup = std::future::pending::<Option<()>>();
+
+        let signalled = tokio::select! {
+            _ = hangup => true,
+            _ = poll.tick() => false,
+        };
+        let Some(certificate) = certificate.upgrade() else {
+            break;
+        };
+        if signalled || certificate.files_changed() {
+            match certificate.reload() {
+                Ok(()) => info!("🔐 Reloaded TLS certificate from {}", certificate.config.cert_path.display()),
+                Err(e) => warn!("Keeping the current TLS certificate, reload failed: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Extension};
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::ServerName;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Self-signed certificate and key for localhost, written under `dir`
+    fn self_signed(dir: &Path, name: &str) -> (TlsConfig, CertificateDer<'static>) {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let config = TlsConfig {
+            cert_path: dir.join(format!("{}.crt", name)),
+            key_path: dir.join(format!("{}.key", name)),
+            client_ca_path: None,
+        };
+        std::fs::write(&config.cert_path, generated.cert.pem()).unwrap();
+        std::fs::write(&config.key_path, generated.key_pair.serialize_pem()).unwrap();
+        (config, generated.cert.der().clone())
+    }
+
+    fn app() -> Router {
+        Router::new().route("/whoami", get(|identity: Option<Extension<ClientIdentity>>| async move {
+            if identity.is_some() { "client certificate" } else { "anonymous" }
+        }))
+    }
+
+    async fn start(config: &TlsConfig) -> (u16, Weak<ReloadableCert>) {
+        let tls = TlsListener::new(config).unwrap();
+        let certificate = Arc::downgrade(&tls.certificate);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(tls.serve(listener, app(), std::future::pending()));
+        (port, certificate)
+    }
+
+    /// GET /whoami over TLS, trusting only `root`
+    async fn whoami(
+        port: u16,
+        root: &CertificateDer<'static>,
+        client: Option<(CertificateDer<'static>, PrivateKeyDer<'static>)>,
+    ) -> io::Result<String> {
+        let mut roots = RootCertStore::empty();
+        roots.add(root.clone()).unwrap();
+        let builder = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match client {
+            Some((certificate, key)) => builder.with_client_auth_cert(vec![certificate], key).unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+        let mut stream = connector.connect(ServerName::try_from("localhost").unwrap(), stream).await?;
+        stream.write_all(b"GET /whoami HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn test_https_round_trip_and_reload() {
+        let dir = TempDir::new().unwrap();
+        let (config, first) = self_signed(dir.path(), "server");
+        let (port, certificate) = start(&config).await;
+
+        let response = whoami(port, &first, None).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("anonymous"), "{}", response);
+
+        // A renewed certificate is served once its files change, without a restart
+        let (renewed, second) = self_signed(dir.path(), "renewed");
+        std::fs::copy(&renewed.cert_path, &config.cert_path).unwrap();
+        std::fs::copy(&renewed.key_path, &config.key_path).unwrap();
+        let certificate = certificate.upgrade().unwrap();
+        assert!(certificate.files_changed());
+        certificate.reload().unwrap();
+        assert!(!certificate.files_changed());
+        assert!(whoami(port, &second, None).await.unwrap().starts_with("HTTP/1.1 200"));
+        assert!(whoami(port, &first, None).await.is_err());
+
+        // A broken renewal keeps the working certificate
+        std::fs::write(&config.key_path, "not a key").unwrap();
+        assert!(certificate.reload().is_err());
+        assert!(whoami(port, &second, None).await.unwrap().starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn test_unusable_certificate_fails_fast() {
+        let dir = TempDir::new().unwrap();
+        let (config, _) = self_signed(dir.path(), "server");
+        let (other, _) = self_signed(dir.path(), "other");
+
+        let mismatched = TlsConfig { key_path: other.key_path, ..config.clone() };
+        assert!(matches!(TlsListener::new(&mismatched), Err(TlsError::KeyMismatch { .. })));
+        let missing = TlsConfig { cert_path: dir.path().join("missing.crt"), ..config.clone() };
+        assert!(matches!(TlsListener::new(&missing), Err(TlsError::Read { .. })));
+        let not_a_certificate = TlsConfig { cert_path: config.key_path.clone(), ..config };
+        assert!(matches!(TlsListener::new(&not_a_certificate), Err(TlsError::NoCertificate(_))));
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates_required() {
+        let dir = TempDir::new().unwrap();
+        let (mut config, server_certificate) = self_signed(dir.path(), "server");
+
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let ca_path = dir.path().join("ca.crt");
+        std::fs::write(&ca_path, ca.pem()).unwrap();
+        config.client_ca_path = Some(ca_path);
+
+        let issue = |issuer: &rcgen::Certificate, issuer_key: &KeyPair| {
+            let key = KeyPair::generate().unwrap();
+            let certificate = CertificateParams::new(vec!["client".to_string()]).unwrap()
+                .signed_by(&key, issuer, issuer_key)
+                .unwrap();
+            (certificate.der().clone(), PrivateKeyDer::try_from(key.serialize_der()).unwrap())
+        };
+        let (port, _certificate) = start(&config).await;
+
+        let response = whoami(port, &server_certificate, Some(issue(&ca, &ca_key))).await.unwrap();
+        assert!(response.ends_with("client certificate"), "{}", response);
+
+        // No certificate, or one from a CA the server does not trust
+        assert!(whoami(port, &server_certificate, None).await.is_err());
+        let rogue_key = KeyPair::generate().unwrap();
+        let rogue = CertificateParams::new(Vec::<String>::new()).unwrap().self_signed(&rogue_key).unwrap();
+        assert!(whoami(port, &server_certificate, Some(issue(&rogue, &rogue_key))).await.is_err());
+    }
+}
@@ -0,0 +1,552 @@
+//! Transactions held open across HTTP requests.
+//!
+//! `POST /api/txn/begin` opens a transaction and returns its
+//! `transaction_id`. The body may name an `isolation_level`, such as
+//! `{"isolation_level": "repeatable read"}`; without one it is read
+//! committed. `POST /api/txn/{id}/query` runs a statement in the
+//! transaction and answers like `/api/query`, and `POST /api/txn/{id}/commit`
+//! or `/rollback` ends it. Each transaction runs in a query session of its
+//! own, and requests for the same transaction wait for each other, so its
+//! statements never interleave. Only the client that began a transaction
+//! may use it.
+//!
+//! At `repeatable read` and above, a commit fails with 409 and the code
+//! `transaction_conflict` if another transaction has committed a write to a
+//! row this one writes since it began, and the transaction is rolled back.
+//!
+//! A transaction left idle for `transaction_idle_timeout_ms` is rolled
+//! back, and requests for it then fail with 410 and the code
+//! `transaction_expired`. How a transaction ended is remembered for as long
+//! again: a commit retried after its response was lost succeeds again
+//! without committing twice, and other requests for an ended transaction
+//! fail with 409 and `transaction_committed` or `transaction_rolled_back`.
+
+use crate::{
+    auth::Scope,
+    cursor::new_token,
+    rate_limit::Client,
+    request_log,
+    server::{error_status, respond, DatabaseState, ErrorBody},
+};
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use nextdb_query::{QueryError, QueryExecutor, SessionId, SqlParser, SqlStatement};
+use nextdb_transaction::IsolationLevel;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct BeginRequest {
+    isolation_level: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TxnQueryRequest {
+    sql: String,
+}
+
+/// How a transaction ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Committed,
+    RolledBack,
+    Expired,
+}
+
+struct Txn {
+    session: SessionId,
+    /// None while the transaction is open
+    outcome: Option<Outcome>,
+}
+
+struct Entry {
+    client: Client,
+    /// Held by the request working on the transaction
+    txn: Arc<tokio::sync::Mutex<Txn>>,
+    /// When a request last finished with it, or when it ended
+    last_used: Instant,
+}
+
+/// Why a request found no transaction to work on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Unavailable {
+    NotFound,
+    Ended(Outcome),
+    ShuttingDown,
+}
+
+impl IntoResponse for Unavailable {
+    fn into_response(self) -> Response {
+        let (status, code, message) = match self {
+            Unavailable::NotFound => (
+                StatusCode::NOT_FOUND,
+                "transaction_not_found",
+                "no such transaction was begun by this client, or it ended long ago",
+            ),
+            Unavailable::Ended(Outcome::Committed) => {
+                (StatusCode::CONFLICT, "transaction_committed", "the transaction has committed")
+            }
+            Unavailable::Ended(Outcome::RolledBack) => {
+                (StatusCode::CONFLICT, "transaction_rolled_back", "the transaction was rolled back")
+            }
+            Unavailable::Ended(Outcome::Expired) => (
+                StatusCode::GONE,
+                "transaction_expired",
+                "the transaction was idle for too long and was rolled back",
+            ),
+            Unavailable::ShuttingDown => (StatusCode::SERVICE_UNAVAILABLE, "shutting_down", "the server is shutting down"),
+        };
+        failure(status, ErrorBody { code, message: message.to_string() })
+    }
+}
+
+fn failure(status: StatusCode, error: ErrorBody) -> Response {
+    (status, Json(serde_json::json!({ "success": false, "error": error }))).into_response()
+}
+
+fn success(body: serde_json::Value) -> Response {
+    let mut body = body;
+    body["success"] = true.into();
+    (StatusCode::OK, Json(body)).into_response()
+}
+
+pub(crate) struct Transactions {
+    entries: Mutex<HashMap<String, Entry>>,
+    idle_timeout: Duration,
+    // Set on shutdown, after which no transaction begins
+    closed: AtomicBool,
+}
+
+impl Transactions {
+    pub(crate) fn new(idle_timeout: Duration) -> Self {
+        Self { entries: Mutex::new(HashMap::new()), idle_timeout, closed: AtomicBool::new(false) }
+    }
+
+    /// Expire idle transactions of `state` in the background
+    pub(crate) fn start_sweeper(state: &Arc<DatabaseState>) {
+        tokio::spawn(sweep(Arc::downgrade(state), state.txns.idle_timeout));
+    }
+
+    /// Hold the transaction open in `session` for `client`, returning its id
+    fn insert(&self, client: Client, session: SessionId) -> String {
+        let id = new_token();
+        let txn = Arc::new(tokio::sync::Mutex::new(Txn { session, outcome: None }));
+        self.entries.lock().unwrap().insert(id.clone(), Entry { client, txn, last_used: Instant::now() });
+        id
+    }
+
+    /// The transaction `id` names, if `client` began it
+    async fn get(&self, id: &str, client: &Client, executor: &QueryExecutor) -> Result<Arc<tokio::sync::Mutex<Txn>>, Unavailable> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(Unavailable::ShuttingDown);
+        }
+        self.expire(executor).await;
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(id).filter(|entry| entry.client == *client).ok_or(Unavailable::NotFound)?;
+        Ok(entry.txn.clone())
+    }
+
+    /// Note that a request is done with `id`, restarting its idle time
+    fn touch(&self, id: &str) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(id) {
+            entry.last_used = Instant::now();
+        }
+    }
+
+    /// Refuse transactions from now on. The executor rolls back the open
+    /// ones with its other sessions.
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    /// Roll back transactions idle past the timeout, skipping any a request
+    /// is working on, and forget those that ended as long ago
+    async fn expire(&self, executor: &QueryExecutor) {
+        let mut expired = Vec::new();
+        {
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|_, entry| {
+                if entry.last_used.elapsed() < self.idle_timeout {
+                    return true;
+                }
+                let Ok(mut txn) = entry.txn.clone().try_lock_owned() else {
+                    return true;
+                };
+                if txn.outcome.is_some() {
+                    return false;
+                }
+                txn.outcome = Some(Outcome::Expired);
+                entry.last_used = Instant::now();
+                expired.push(txn.session);
+                true
+            });
+        }
+        for session in expired {
+            if let Err(e) = executor.end_session(session).await {
+                warn!("Cannot roll back an expired transaction: {}", e);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+async fn sweep(state: Weak<DatabaseState>, idle_timeout: Duration) {
+    let mut interval = tokio::time::interval((idle_timeout / 2).max(Duration::from_millis(100)));
+    loop {
+        interval.tick().await;
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        state.txns.expire(&state.executor).await;
+    }
+}
+
+/// `read committed`, `REPEATABLE_READ` and the like
+fn parse_isolation_level(name: &str) -> Option<IsolationLevel> {
+    let words: Vec<String> = name.split([' ', '_', '-'])
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect();
+    match words.join(" ").as_str() {
+        "read uncommitted" => Some(IsolationLevel::ReadUncommitted),
+        "read committed" => Some(IsolationLevel::ReadCommitted),
+        "repeatable read" => Some(IsolationLevel::RepeatableRead),
+        "serializable" => Some(IsolationLevel::Serializable),
+        _ => None,
+    }
+}
+
+fn client_of(client: Option<Extension<Client>>) -> Client {
+    client.map_or(Client::Unknown, |Extension(client)| client)
+}
+
+/// Handler for `POST /api/txn/begin`
+pub(crate) async fn begin(
+    State(state): State<Arc<DatabaseState>>,
+    client: Option<Extension<Client>>,
+    body: Bytes,
+) -> Response {
+    let request: BeginRequest = if body.is_empty() {
+        BeginRequest::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => return failure(StatusCode::BAD_REQUEST, ErrorBody { code: "invalid_request", message: e.to_string() }),
+        }
+    };
+    let isolation_level = match request.isolation_level.as_deref().map(|name| (name, parse_isolation_level(name))) {
+        None => None,
+        Some((_, Some(level))) => Some(level),
+        Some((name, None)) => {
+            let message = format!("unknown isolation level '{}'", name);
+            return failure(StatusCode::BAD_REQUEST, ErrorBody { code: "invalid_request", message });
+        }
+    };
+    if state.txns.closed.load(Ordering::SeqCst) {
+        return Unavailable::ShuttingDown.into_response();
+    }
+    state.txns.expire(&state.executor).await;
+
+    let session = state.new_session();
+    if let Err(e) = state.executor.execute_statement_in(session, SqlStatement::Begin { isolation_level }).await {
+        let (status, code) = error_status(&e);
+        return failure(status, ErrorBody { code, message: e.to_string() });
+    }
+    let id = state.txns.insert(client_of(client), session);
+    info!("Began HTTP transaction {}", id);
+    success(serde_json::json!({ "transaction_id": id }))
+}
+
+/// Handler for `POST /api/txn/{id}/query`
+pub(crate) async fn query(
+    State(state): State<Arc<DatabaseState>>,
+    Path(id): Path<String>,
+    Extension(scope): Extension<Scope>,
+    client: Option<Extension<Client>>,
+    Json(req): Json<TxnQueryRequest>,
+) -> Response {
+    let client = client_of(client);
+    let txn = match state.txns.get(&id, &client, &state.executor).await {
+        Ok(txn) => txn,
+        Err(unavailable) => return unavailable.into_response(),
+    };
+    let txn = txn.lock().await;
+    if let Some(outcome) = txn.outcome {
+        return Unavailable::Ended(outcome).into_response();
+    }
+    request_log::record_sql(&req.sql);
+
+    let started = Instant::now();
+    let result = match SqlParser::parse(&req.sql) {
+        Ok(statement) if !scope.allows(&statement) => {
+            let message = "the API token is read-only and the query writes".to_string();
+            return failure(StatusCode::FORBIDDEN, ErrorBody { code: "read_only_token", message });
+        }
+        Ok(SqlStatement::Begin { .. } | SqlStatement::Commit | SqlStatement::Rollback) => Err(QueryError::Invalid(
+            "end the transaction with /commit or /rollback instead".to_string(),
+        )),
+        Ok(statement) => {
+            let admitted = state.rate_limiter.as_ref().map(|limiter| limiter.admit_query(&client, &statement));
+            let _permit = match admitted.transpose() {
+                Ok(permit) => permit,
+                Err(limited) => return limited.into_response(),
+            };
+            state.executor.execute_statement_in(txn.session, statement).await
+        }
+        Err(e) => {
+            state.executor.fail_transaction(txn.session).await;
+            Err(e)
+        }
+    };
+    state.txns.touch(&id);
+    respond(&state, started, result, None)
+}
+
+/// Handler for `POST /api/txn/{id}/commit`
+pub(crate) async fn commit(
+    State(state): State<Arc<DatabaseState>>,
+    Path(id): Path<String>,
+    client: Option<Extension<Client>>,
+) -> Response {
+    end(&state, &id, client_of(client), Outcome::Committed).await
+}
+
+/// Handler for `POST /api/txn/{id}/rollback`
+pub(crate) async fn rollback(
+    State(state): State<Arc<DatabaseState>>,
+    Path(id): Path<String>,
+    client: Option<Extension<Client>>,
+) -> Response {
+    end(&state, &id, client_of(client), Outcome::RolledBack).await
+}
+
+/// End the transaction `id` as `wanted`. Asking again for the outcome it
+/// already had succeeds without doing anything.
+async fn end(state: &DatabaseState, id: &str, client: Client, wanted: Outcome) -> Response {
+    let txn = match state.txns.get(id, &client, &state.executor).await {
+        Ok(txn) => txn,
+        Err(unavailable) => return unavailable.into_response(),
+    };
+    let mut txn = txn.lock().await;
+    match txn.outcome {
+        Some(outcome) if outcome == wanted => return success(serde_json::json!({})),
+        Some(outcome) => return Unavailable::Ended(outcome).into_response(),
+        None => {}
+    }
+
+    let statement = match wanted {
+        Outcome::Committed => SqlStatement::Commit,
+        _ => SqlStatement::Rollback,
+    };
+    // The session's transaction is over whether or not this succeeds
+    let result = state.executor.execute_statement_in(txn.session, statement).await;
+    txn.outcome = Some(if result.is_ok() { wanted } else { Outcome::RolledBack });
+    state.txns.touch(id);
+    match result {
+        Ok(_) => success(serde_json::json!({})),
+        Err(e) => {
+            let (status, code) = error_status(&e);
+            failure(status, ErrorBody { code, message: e.to_string() })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, post};
+    use crate::{DatabaseServer, ServerConfig};
+    use axum::Router;
+    use serde_json::{json, Value};
+    use tempfile::TempDir;
+
+    async fn server(temp_dir: &TempDir, transaction_idle_timeout_ms: u64) -> DatabaseServer {
+        let config = ServerConfig {
+            transaction_idle_timeout_ms,
+            ..test_util::config(temp_dir)
+        };
+        let server = DatabaseServer::with_config(config).await.unwrap();
+        let app = server.router();
+        post(&app, "/api/query", json!({ "sql": "CREATE TABLE accounts (id INT PRIMARY KEY, balance INT)" })).await;
+        post(&app, "/api/query", json!({ "sql": "INSERT INTO accounts VALUES (1, 100), (2, 50)" })).await;
+        server
+    }
+
+    async fn begin(app: &Router, body: Value) -> String {
+        let (status, body) = post(app, "/api/txn/begin", body).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        body["transaction_id"].as_str().unwrap().to_string()
+    }
+
+    async fn balances(app: &Router, uri: &str) -> Value {
+        let (status, body) = post(app, uri, json!({ "sql": "SELECT id, balance FROM accounts ORDER BY id" })).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        body["result"]["rows"].clone()
+    }
+
+    fn code(response: &(StatusCode, Value)) -> (StatusCode, &Value) {
+        (response.0, &response.1["error"]["code"])
+    }
+
+    #[tokio::test]
+    async fn test_transfer_commits_once() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = server(&temp_dir, 60_000).await;
+        let app = server.router();
+
+        let id = begin(&app, json!({ "isolation_level": "REPEATABLE_READ" })).await;
+        let query = format!("/api/txn/{}/query", id);
+        for sql in [
+            "UPDATE accounts SET balance = balance - 30 WHERE id = 1",
+            "UPDATE accounts SET balance = balance + 30 WHERE id = 2",
+        ] {
+            let (status, body) = post(&app, &query, json!({ "sql": sql })).await;
+            assert_eq!((status, &body["rows_affected"]), (StatusCode::OK, &json!(1)), "{}", body);
+        }
+
+        // Only the transaction sees its writes before it commits
+        assert_eq!(balances(&app, &query).await, json!([[1, 70], [2, 80]]));
+        assert_eq!(balances(&app, "/api/query").await, json!([[1, 100], [2, 50]]));
+
+        let commit = format!("/api/txn/{}/commit", id);
+        let (status, body) = post(&app, &commit, json!(null)).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(balances(&app, "/api/query").await, json!([[1, 70], [2, 80]]));
+
+        // A retried commit succeeds without committing again; nothing else
+        // runs in the transaction
+        assert_eq!(post(&app, &commit, json!(null)).await.0, StatusCode::OK);
+        assert_eq!(balances(&app, "/api/query").await, json!([[1, 70], [2, 80]]));
+        let sql = json!({ "sql": "UPDATE accounts SET balance = 0" });
+        assert_eq!(code(&post(&app, &query, sql).await), (StatusCode::CONFLICT, &json!("transaction_committed")));
+        let rollback = format!("/api/txn/{}/rollback", id);
+        assert_eq!(code(&post(&app, &rollback, json!(null)).await), (StatusCode::CONFLICT, &json!("transaction_committed")));
+        assert!(!server.state().executor.transactions().has_snapshots());
+    }
+
+    #[tokio::test]
+    async fn test_rollback_and_failed_statements() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = server(&temp_dir, 60_000).await;
+        let app = server.router();
+
+        let id = begin(&app, json!({})).await;
+        let query = format!("/api/txn/{}/query", id);
+        post(&app, &query, json!({ "sql": "INSERT INTO accounts VALUES (3, 10)" })).await;
+        let rollback = format!("/api/txn/{}/rollback", id);
+        assert_eq!(post(&app, &rollback, json!(null)).await.0, StatusCode::OK);
+        assert_eq!(post(&app, &rollback, json!(null)).await.0, StatusCode::OK);
+        let commit = format!("/api/txn/{}/commit", id);
+        assert_eq!(code(&post(&app, &commit, json!(null)).await), (StatusCode::CONFLICT, &json!("transaction_rolled_back")));
+        assert_eq!(balances(&app, "/api/query").await, json!([[1, 100], [2, 50]]));
+
+        // A failed statement leaves only rollback, and a commit rolls back
+        let id = begin(&app, json!({})).await;
+        let query = format!("/api/txn/{}/query", id);
+        post(&app, &query, json!({ "sql": "INSERT INTO accounts VALUES (3, 10)" })).await;
+        let duplicate = post(&app, &query, json!({ "sql": "INSERT INTO accounts VALUES (1, 0)" })).await;
+        assert_eq!(code(&duplicate), (StatusCode::CONFLICT, &json!("constraint_violation")));
+        let commit = format!("/api/txn/{}/commit", id);
+        assert_eq!(code(&post(&app, &commit, json!(null)).await), (StatusCode::CONFLICT, &json!("transaction_error")));
+        assert_eq!(code(&post(&app, &commit, json!(null)).await), (StatusCode::CONFLICT, &json!("transaction_rolled_back")));
+        assert_eq!(balances(&app, "/api/query").await, json!([[1, 100], [2, 50]]));
+
+        // Requests the API cannot take
+        let id = begin(&app, json!({})).await;
+        let query = format!("/api/txn/{}/query", id);
+        assert_eq!(code(&post(&app, &query, json!({ "sql": "COMMIT" })).await), (StatusCode::BAD_REQUEST, &json!("invalid_query")));
+        let unknown = post(&app, "/api/txn/begin", json!({ "isolation_level": "snapshot" })).await;
+        assert_eq!(code(&unknown), (StatusCode::BAD_REQUEST, &json!("invalid_request")));
+        let missing = post(&app, "/api/txn/0123/query", json!({ "sql": "SELECT 1" })).await;
+        assert_eq!(code(&missing), (StatusCode::NOT_FOUND, &json!("transaction_not_found")));
+
+        // Shutting down rolls back what is still open
+        server.close().await.unwrap();
+        let closed = post(&app, &query, json!({ "sql": "SELECT 1" })).await;
+        assert_eq!(code(&closed), (StatusCode::SERVICE_UNAVAILABLE, &json!("shutting_down")));
+    }
+
+    #[tokio::test]
+    async fn test_first_committer_wins() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = server(&temp_dir, 60_000).await;
+        let app = server.router();
+
+        let level = json!({ "isolation_level": "repeatable read" });
+        let ids = [begin(&app, level.clone()).await, begin(&app, level).await];
+        for (id, balance) in ids.iter().zip([10, 20]) {
+            let sql = format!("UPDATE accounts SET balance = {} WHERE id = 1", balance);
+            assert_eq!(post(&app, &format!("/api/txn/{}/query", id), json!({ "sql": sql })).await.0, StatusCode::OK);
+        }
+        assert_eq!(post(&app, &format!("/api/txn/{}/commit", ids[0]), json!(null)).await.0, StatusCode::OK);
+        let commit = format!("/api/txn/{}/commit", ids[1]);
+        assert_eq!(code(&post(&app, &commit, json!(null)).await), (StatusCode::CONFLICT, &json!("transaction_conflict")));
+        assert_eq!(code(&post(&app, &commit, json!(null)).await), (StatusCode::CONFLICT, &json!("transaction_rolled_back")));
+        assert_eq!(balances(&app, "/api/query").await, json!([[1, 10], [2, 50]]));
+    }
+
+    #[tokio::test]
+    async fn test_idle_transactions_expire() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = server(&temp_dir, 400).await;
+        let app = server.router();
+
+        let id = begin(&app, json!({ "isolation_level": "serializable" })).await;
+        let query = format!("/api/txn/{}/query", id);
+        post(&app, &query, json!({ "sql": "DELETE FROM accounts WHERE id = 1" })).await;
+        assert!(server.state().executor.transactions().has_snapshots());
+        // Past the timeout, but short of a sweep forgetting it as well
+        tokio::time::sleep(Duration::from_millis(650)).await;
+
+        let expired = post(&app, &query, json!({ "sql": "SELECT 1" })).await;
+        assert_eq!(code(&expired), (StatusCode::GONE, &json!("transaction_expired")));
+        let commit = format!("/api/txn/{}/commit", id);
+        assert_eq!(code(&post(&app, &commit, json!(null)).await), (StatusCode::GONE, &json!("transaction_expired")));
+        assert!(!server.state().executor.transactions().has_snapshots());
+        assert_eq!(balances(&app, "/api/query").await, json!([[1, 100], [2, 50]]));
+
+        // It is forgotten after as long again
+        tokio::time::sleep(Duration::from_millis(650)).await;
+        assert_eq!(code(&post(&app, &commit, json!(null)).await), (StatusCode::NOT_FOUND, &json!("transaction_not_found")));
+        assert_eq!(server.state().txns.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_requests_on_one_transaction_take_turns() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = server(&temp_dir, 60_000).await;
+        let app = server.router();
+
+        let id = begin(&app, json!({})).await;
+        let query = format!("/api/txn/{}/query", id);
+        let inserts: Vec<_> = (10..20)
+            .map(|i| post(&app, &query, json!({ "sql": format!("INSERT INTO accounts VALUES ({}, {})", i, i) })))
+            .collect();
+        for (status, body) in futures::future::join_all(inserts).await {
+            assert_eq!(status, StatusCode::OK, "{}", body);
+        }
+
+        // Concurrent commits: one commits, the other finds it committed
+        let commit = format!("/api/txn/{}/commit", id);
+        let (first, second) = tokio::join!(post(&app, &commit, json!(null)), post(&app, &commit, json!(null)));
+        assert_eq!((first.0, second.0), (StatusCode::OK, StatusCode::OK), "{} {}", first.1, second.1);
+        let (_, count) = post(&app, "/api/query", json!({ "sql": "SELECT COUNT(*) FROM accounts" })).await;
+        assert_eq!(count["result"]["rows"], json!([[12]]));
+    }
+}
@@ -0,0 +1,384 @@
+//! Live change feed over WebSocket.
+//!
+//! `GET /api/watch?prefix=users/&prefix=orders/` upgrades to a WebSocket
+//! that receives a JSON text message for each write under any of the given
+//! key prefixes, or under any key without one, in commit order:
+//!
+//! ```json
+//! {"key": "users/42", "type": "put", "commit_ts": 1700000000000}
+//! ```
+//!
+//! SQL rows are named `<table>/<primary key values>`, with the values joined
+//! by `/`, and raw keys by their text. Index entries and other internal keys
+//! are not reported. `commit_ts` is the write's time in milliseconds since
+//! the Unix epoch. With `from_ts`, changes since then that are still among
+//! the last `watch_history` come first.
+//!
+//! Writes never wait for watchers. A watcher that falls behind misses
+//! changes and is told so with `{"type": "lagged", "missed": <count>}`.
+//! Replaying from before the oldest change kept starts with
+//! `{"type": "history_truncated", "oldest_commit_ts": <ts>}`.
+
+use crate::{server::DatabaseState, websocket::{Message, WebSocket, WebSocketUpgrade}};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use nextdb_query::{encoding, Catalog};
+use nextdb_storage::{KVPair, LSMTree};
+use serde::Serialize;
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, warn};
+
+/// Changes a watcher may fall behind by before it misses some
+const WATCHER_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ChangeKind {
+    Put,
+    Delete,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct ChangeEvent {
+    key: String,
+    #[serde(rename = "type")]
+    kind: ChangeKind,
+    commit_ts: u64,
+}
+
+/// Fans the storage changefeed out to watchers and keeps recent changes
+/// for them to replay
+pub(crate) struct ChangeHub {
+    events: broadcast::Sender<Arc<ChangeEvent>>,
+    // Recent changes, and the commit time of the newest one dropped from
+    // them. Changes are published with this held, so a new watcher sees
+    // each one either in the replay or live, never both or neither.
+    history: Mutex<(VecDeque<Arc<ChangeEvent>>, Option<u64>)>,
+    history_len: usize,
+}
+
+impl ChangeHub {
+    /// Start following the writes to `storage`
+    pub(crate) fn start(storage: Arc<LSMTree>, catalog: Arc<Catalog>, history_len: usize) -> Arc<Self> {
+        let hub = Arc::new(Self {
+            events: broadcast::channel(WATCHER_BUFFER).0,
+            history: Mutex::new((VecDeque::new(), None)),
+            history_len,
+        });
+        tokio::spawn(hub.clone().follow(storage, catalog));
+        hub
+    }
+
+    async fn follow(self: Arc<Self>, storage: Arc<LSMTree>, catalog: Arc<Catalog>) {
+        let mut tables = HashMap::new();
+        // Sequence to resume from if the changefeed falls behind
+        let mut next = None;
+        loop {
+            let mut changes = match storage.changefeed(next).await {
+                Ok(changes) => changes,
+                Err(e) => {
+                    warn!("Cannot follow writes for watchers: {}", e);
+                    return;
+                }
+            };
+            loop {
+                match changes.next().await {
+                    Some(Ok(change)) => {
+                        next = Some(change.sequence + 1);
+                        if let Some(event) = describe(&change, &catalog, &mut tables) {
+                            self.publish(event);
+                        }
+                    }
+                    Some(Err(e)) => {
+                        warn!("Watchers fell behind the writes, catching up from the WAL: {}", e);
+                        break;
+                    }
+                    None => return,
+                }
+            }
+        }
+    }
+
+    fn publish(&self, event: ChangeEvent) {
+        let event = Arc::new(event);
+        let mut history = self.history.lock().unwrap();
+        let (recent, dropped) = &mut *history;
+        recent.push_back(event.clone());
+        while recent.len() > self.history_len {
+            *dropped = recent.pop_front().map(|event| event.commit_ts);
+        }
+        let _ = self.events.send(event);
+    }
+
+    /// Changes committed at or after `from_ts` still kept, whether older
+    /// ones were dropped, and a receiver for changes from then on
+    fn subscribe(&self, from_ts: Option<u64>) -> (Vec<Arc<ChangeEvent>>, bool, broadcast::Receiver<Arc<ChangeEvent>>) {
+        let history = self.history.lock().unwrap();
+        let (recent, dropped) = &*history;
+        let receiver = self.events.subscribe();
+        let Some(from_ts) = from_ts else {
+            return (Vec::new(), false, receiver);
+        };
+        let replay = recent.iter().filter(|event| event.commit_ts >= from_ts).cloned().collect();
+        let truncated = dropped.is_some_and(|dropped| dropped >= from_ts);
+        (replay, truncated, receiver)
+    }
+
+    /// Oldest change kept for replay
+    fn oldest(&self) -> Option<u64> {
+        self.history.lock().unwrap().0.front().map(|event| event.commit_ts)
+    }
+
+    /// Watchers connected
+    #[cfg(test)]
+    pub(crate) fn watchers(&self) -> usize {
+        self.events.receiver_count()
+    }
+}
+
+/// The event for a write, or None if it is to an internal key
+fn describe(change: &KVPair, catalog: &Catalog, tables: &mut HashMap<u64, String>) -> Option<ChangeEvent> {
+    let key = if encoding::is_sql_key(&change.key) {
+        let table = encoding::row_key_table(&change.key)?;
+        if !tables.contains_key(&table) {
+            tables.extend(catalog.tables().iter().map(|schema| (schema.id, schema.name.clone())));
+        }
+        let values = encoding::decode_key(encoding::primary_key_bytes(&change.key)).ok()?;
+        std::iter::once(tables.get(&table)?.clone())
+            .chain(values.iter().map(ToString::to_string))
+            .collect::<Vec<_>>()
+            .join("/")
+    } else {
+        String::from_utf8_lossy(&change.key).into_owned()
+    };
+    let kind = if change.value.is_some() { ChangeKind::Put } else { ChangeKind::Delete };
+    Some(ChangeEvent { key, kind, commit_ts: change.timestamp })
+}
+
+/// `GET /api/watch`
+pub(crate) async fn watch(
+    State(state): State<Arc<DatabaseState>>,
+    Query(params): Query<Vec<(String, String)>>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let mut prefixes = Vec::new();
+    let mut from_ts = None;
+    for (name, value) in params {
+        match name.as_str() {
+            "prefix" => prefixes.push(value),
+            "from_ts" => match value.parse::<u64>() {
+                Ok(ts) => from_ts = Some(ts),
+                Err(_) => return (StatusCode::BAD_REQUEST, "from_ts must be milliseconds since the Unix epoch").into_response(),
+            },
+            _ => return (StatusCode::BAD_REQUEST, format!("unknown parameter {}", name)).into_response(),
+        }
+    }
+    let hub = state.changes.clone();
+    upgrade.on_upgrade(move |socket| send_changes(socket, hub, prefixes, from_ts))
+}
+
+async fn send_changes(socket: WebSocket, hub: Arc<ChangeHub>, prefixes: Vec<String>, from_ts: Option<u64>) {
+    let (mut receiver, mut sender) = socket.split();
+    let (replay, truncated, mut changes) = hub.subscribe(from_ts);
+    let watched = |event: &ChangeEvent| prefixes.is_empty() || prefixes.iter().any(|prefix| event.key.starts_with(prefix));
+
+    // Read on a task of its own, so waiting for the client never holds up
+    // sending to it; it ends when the client closes the socket
+    let (control, mut incoming) = mpsc::channel(16);
+    let reader = tokio::spawn(async move {
+        while let Ok(Some(message)) = receiver.recv().await {
+            let close = message == Message::Close;
+            if control.send(message).await.is_err() || close {
+                break;
+            }
+        }
+    });
+
+    let result = async {
+        if truncated {
+            let notice = serde_json::json!({ "type": "history_truncated", "oldest_commit_ts": hub.oldest() });
+            sender.send(Message::Text(notice.to_string())).await?;
+        }
+        for event in replay.iter().filter(|event| watched(event)) {
+            sender.send(Message::Text(serde_json::to_string(&**event)?)).await?;
+        }
+        loop {
+            tokio::select! {
+                message = incoming.recv() => match message {
+                    Some(Message::Ping(payload)) => sender.send(Message::Pong(payload)).await?,
+                    Some(Message::Close) | None => {
+                        let _ = sender.send(Message::Close).await;
+                        return Ok(());
+                    }
+                    Some(_) => {}
+                },
+                change = changes.recv() => match change {
+                    Ok(event) if watched(&event) => sender.send(Message::Text(serde_json::to_string(&*event)?)).await?,
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        let notice = serde_json::json!({ "type": "lagged", "missed": missed });
+                        sender.send(Message::Text(notice.to_string())).await?;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+            }
+        }
+    };
+    if let Err(e) = result.await as std::io::Result<()> {
+        debug!("Watcher disconnected: {}", e);
+    }
+    reader.abort();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{websocket, DatabaseServer, ServerConfig};
+    use serde_json::{json, Value};
+    use std::{net::SocketAddr, time::Duration};
+    use tempfile::TempDir;
+    use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}};
+
+    async fn serve(server: &DatabaseServer) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = server.router().into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    /// Open a WebSocket to `path`, returning it with the handshake consumed
+    async fn connect(addr: SocketAddr, path: &str) -> BufReader<TcpStream> {
+        let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            path, addr
+        );
+        stream.get_mut().write_all(request.as_bytes()).await.unwrap();
+        let mut status = String::new();
+        stream.read_line(&mut status).await.unwrap();
+        assert!(status.starts_with("HTTP/1.1 101"), "{}", status);
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            headers.push(line);
+        }
+        let accept = headers.iter().find(|line| line.to_lowercase().starts_with("sec-websocket-accept:"));
+        assert_eq!(accept.map(|line| line[21..].trim()), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="), "{:?}", headers);
+        stream
+    }
+
+    async fn next_event(socket: &mut BufReader<TcpStream>) -> Value {
+        let message = tokio::time::timeout(Duration::from_secs(5), websocket::read_message(socket, false))
+            .await
+            .expect("an event in time")
+            .unwrap();
+        match message {
+            Some(websocket::Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+            other => panic!("expected a text message, got {:?}", other),
+        }
+    }
+
+    fn change(event: &Value) -> (String, String) {
+        (event["key"].as_str().unwrap().to_string(), event["type"].as_str().unwrap().to_string())
+    }
+
+    async fn query(addr: SocketAddr, sql: &str) {
+        let body = json!({ "sql": sql }).to_string();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "POST /api/query HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            addr, body.len(), body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut stream, &mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200") && response.contains("\"success\":true"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_watch_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = ServerConfig { data_dir: temp_dir.path().to_path_buf(), watch_history: 2, ..ServerConfig::default() };
+        let server = DatabaseServer::with_config(config).await.unwrap();
+        let addr = serve(&server).await;
+
+        query(addr, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT)").await;
+        query(addr, "CREATE TABLE orders (id INT PRIMARY KEY)").await;
+        let mut socket = connect(addr, "/api/watch?prefix=users/").await;
+
+        query(addr, "INSERT INTO users VALUES (1, 'ann'), (2, 'bob')").await;
+        query(addr, "INSERT INTO orders VALUES (7)").await;
+        query(addr, "DELETE FROM users WHERE id = 1").await;
+        query(addr, "UPDATE users SET name = 'bo' WHERE id = 2").await;
+        let mut events = Vec::new();
+        for _ in 0..4 {
+            events.push(next_event(&mut socket).await);
+        }
+        let changes: Vec<_> = events.iter().map(change).collect();
+        assert_eq!(changes, [
+            ("users/1".to_string(), "put".to_string()),
+            ("users/2".to_string(), "put".to_string()),
+            ("users/1".to_string(), "delete".to_string()),
+            ("users/2".to_string(), "put".to_string()),
+        ]);
+        assert!(events.windows(2).all(|pair| pair[0]["commit_ts"].as_u64() <= pair[1]["commit_ts"].as_u64()));
+
+        // A new watcher replays what is kept of the history, saying what it missed
+        let from_ts = events[0]["commit_ts"].as_u64().unwrap();
+        let mut replaying = connect(addr, &format!("/api/watch?from_ts={}", from_ts)).await;
+        assert_eq!(next_event(&mut replaying).await["type"], "history_truncated");
+        assert_eq!(change(&next_event(&mut replaying).await), ("users/1".to_string(), "delete".to_string()));
+        assert_eq!(change(&next_event(&mut replaying).await), ("users/2".to_string(), "put".to_string()));
+
+        // Closing a socket ends its subscription
+        assert_eq!(server.state().changes.watchers(), 2);
+        websocket::write_message(socket.get_mut(), websocket::Message::Close, Some([1, 2, 3, 4])).await.unwrap();
+        assert_eq!(websocket::read_message(&mut socket, false).await.unwrap(), Some(websocket::Message::Close));
+        drop(replaying);
+        for _ in 0..100 {
+            if server.state().changes.watchers() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.state().changes.watchers(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_watch_requires_token() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            auth: Some(crate::AuthConfig { tokens: vec![crate::ApiToken {
+                name: "ui".to_string(),
+                token: "s3cret".to_string(),
+                scope: crate::Scope::ReadOnly,
+                databases: None,
+            }] }),
+            ..ServerConfig::default()
+        };
+        let addr = serve(&DatabaseServer::with_config(config).await.unwrap()).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET /api/watch HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            addr
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status).await.unwrap();
+        assert!(status.starts_with("HTTP/1.1 401"), "{}", status);
+    }
+}
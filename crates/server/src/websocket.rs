@@ -0,0 +1,254 @@
+//! Server side of the WebSocket protocol (RFC 6455): the upgrade handshake
+//! and unfragmented messages, which is all `/api/watch` needs.
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    response::Response,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper_util::rt::TokioIo;
+use std::{future::Future, io};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tracing::debug;
+
+/// Appended to the client's key to prove the server speaks WebSocket
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message accepted from a client
+const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+/// Extracts a WebSocket upgrade request; rejects any other request with 400
+pub(crate) struct WebSocketUpgrade {
+    accept: String,
+    on_upgrade: OnUpgrade,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for WebSocketUpgrade {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let rejected = (StatusCode::BAD_REQUEST, "expected a WebSocket upgrade request");
+        let headers = &parts.headers;
+        if !has_token(headers, header::CONNECTION, "upgrade")
+            || !has_token(headers, header::UPGRADE, "websocket")
+            || headers.get(header::SEC_WEBSOCKET_VERSION).is_none_or(|version| version != "13")
+        {
+            return Err(rejected);
+        }
+        let key = headers.get(header::SEC_WEBSOCKET_KEY).ok_or(rejected)?;
+        let accept = accept_key(key.as_bytes());
+        let on_upgrade = parts.extensions.remove::<OnUpgrade>()
+            .ok_or((StatusCode::BAD_REQUEST, "the connection cannot be upgraded"))?;
+        Ok(Self { accept, on_upgrade })
+    }
+}
+
+impl WebSocketUpgrade {
+    /// Switch protocols, running `handle` on the socket once the response
+    /// has been sent
+    pub(crate) fn on_upgrade<F, Fut>(self, handle: F) -> Response
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        tokio::spawn(async move {
+            match self.on_upgrade.await {
+                Ok(upgraded) => handle(WebSocket::new(TokioIo::new(upgraded))).await,
+                Err(e) => debug!("WebSocket upgrade failed: {}", e),
+            }
+        });
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_ACCEPT, self.accept)
+            .body(Body::empty())
+            .expect("valid upgrade response")
+    }
+}
+
+/// Whether the comma-separated header `name` lists `token`
+fn has_token(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers.get_all(name).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| item.trim().eq_ignore_ascii_case(token))
+}
+
+/// `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`
+pub(crate) fn accept_key(key: &[u8]) -> String {
+    let mut context = ring::digest::Context::new(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY);
+    context.update(key);
+    context.update(HANDSHAKE_GUID.as_bytes());
+    BASE64.encode(context.finish())
+}
+
+/// An upgraded connection
+pub(crate) struct WebSocket {
+    io: TokioIo<Upgraded>,
+}
+
+impl WebSocket {
+    fn new(io: TokioIo<Upgraded>) -> Self {
+        Self { io }
+    }
+
+    /// Halves that receive and send independently
+    pub(crate) fn split(self) -> (Receiver, Sender) {
+        let (read, write) = tokio::io::split(self.io);
+        (Receiver { read }, Sender { write })
+    }
+}
+
+pub(crate) struct Receiver {
+    read: ReadHalf<TokioIo<Upgraded>>,
+}
+
+impl Receiver {
+    /// The next message from the client, or None once the connection ends
+    pub(crate) async fn recv(&mut self) -> io::Result<Option<Message>> {
+        read_message(&mut self.read, true).await
+    }
+}
+
+pub(crate) struct Sender {
+    write: WriteHalf<TokioIo<Upgraded>>,
+}
+
+impl Sender {
+    pub(crate) async fn send(&mut self, message: Message) -> io::Result<()> {
+        write_message(&mut self.write, message, None).await
+    }
+}
+
+/// Read one message. Clients must mask what they send and servers must not,
+/// so `masked` says which side is sending.
+pub(crate) async fn read_message<R: AsyncRead + Unpin>(read: &mut R, masked: bool) -> io::Result<Option<Message>> {
+    let mut header = [0; 2];
+    match read.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    if header[0] & 0x80 == 0 || header[0] & 0x0F == 0 {
+        return Err(invalid("fragmented messages are not supported"));
+    }
+    if (header[1] & 0x80 != 0) != masked {
+        return Err(invalid(if masked { "client messages must be masked" } else { "server messages must not be masked" }));
+    }
+
+    let length = match header[1] & 0x7F {
+        126 => read.read_u16().await? as u64,
+        127 => read.read_u64().await?,
+        length => length as u64,
+    };
+    if length > MAX_MESSAGE_BYTES as u64 {
+        return Err(invalid("message too large"));
+    }
+    let mut mask = [0; 4];
+    if masked {
+        read.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0; length as usize];
+    read.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Some(match header[0] & 0x0F {
+        OP_TEXT => Message::Text(String::from_utf8(payload).map_err(|_| invalid("text message is not UTF-8"))?),
+        OP_BINARY => Message::Binary(payload),
+        OP_CLOSE => Message::Close,
+        OP_PING => Message::Ping(payload),
+        OP_PONG => Message::Pong(payload),
+        _ => return Err(invalid("unknown opcode")),
+    }))
+}
+
+/// Write one message as a single frame, masked with `mask` if given
+pub(crate) async fn write_message<W: AsyncWrite + Unpin>(write: &mut W, message: Message, mask: Option<[u8; 4]>) -> io::Result<()> {
+    let (opcode, mut payload) = match message {
+        Message::Text(text) => (OP_TEXT, text.into_bytes()),
+        Message::Binary(bytes) => (OP_BINARY, bytes),
+        Message::Close => (OP_CLOSE, Vec::new()),
+        Message::Ping(bytes) => (OP_PING, bytes),
+        Message::Pong(bytes) => (OP_PONG, bytes),
+    };
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length @ 0..=125 => frame.push(mask_bit | length as u8),
+        length @ 126..=0xFFFF => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    if let Some(mask) = mask {
+        frame.extend_from_slice(&mask);
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    frame.extend_from_slice(&payload);
+    write.write_all(&frame).await?;
+    write.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // The example handshake from RFC 6455, section 1.3
+        assert_eq!(accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn test_message_round_trip() {
+        let long = "x".repeat(70_000);
+        for (message, mask) in [
+            (Message::Text("hello".to_string()), Some([1, 2, 3, 4])),
+            (Message::Binary(vec![0; 300]), None),
+            (Message::Ping(b"ping".to_vec()), Some([9, 8, 7, 6])),
+            (Message::Close, None),
+        ] {
+            let mut frame = Vec::new();
+            write_message(&mut frame, message.clone(), mask).await.unwrap();
+            let read = read_message(&mut frame.as_slice(), mask.is_some()).await.unwrap();
+            assert_eq!(read, Some(message));
+        }
+
+        let mut frame = Vec::new();
+        write_message(&mut frame, Message::Text(long), Some([1, 1, 1, 1])).await.unwrap();
+        assert!(read_message(&mut frame.as_slice(), true).await.is_err());
+        write_message(&mut frame, Message::Close, None).await.unwrap();
+        assert!(read_message(&mut &frame[frame.len() - 2..], true).await.is_err());
+        assert_eq!(read_message(&mut &[][..], true).await.unwrap(), None);
+    }
+}
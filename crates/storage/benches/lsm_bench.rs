@@ -1,3 +1,5 @@
+#![allow(clippy::field_reassign_with_default)]
+
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use nextdb_storage::{LSMTree, StorageConfig};
 use tempfile::TempDir;
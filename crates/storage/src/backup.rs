@@ -0,0 +1,274 @@
+//! Portable backup archives.
+//!
+//! An archive is a magic number followed by length-prefixed, CRC-checked
+//! frames: a header with the sequence number the backup was pinned at, the
+//! live entries in key order split into chunks, and an end frame carrying the
+//! entry count so a truncated archive is caught. Frames are JSON like WAL
+//! records, so an archive does not depend on the SSTable layout, compression
+//! settings or byte order of the machine that wrote it.
+//!
+//! A differential archive has the same layout but holds only the keys written
+//! since an earlier backup's sequence number, deletions included. A
+//! `BackupManifest` lists a full archive and the differential ones that
+//! follow it, which restore together in that order.
+
+use crate::error::{Result, StorageError};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const BACKUP_FORMAT_VERSION: u32 = 2;
+
+// Version 1 archives predate differential backups and are read as full ones
+const OLDEST_READABLE_VERSION: u32 = 1;
+
+const MAGIC: &[u8; 8] = b"NXDBBKUP";
+
+// Entries and approximate payload bytes per chunk frame
+const CHUNK_ENTRIES: usize = 1024;
+const CHUNK_BYTES: usize = 1024 * 1024;
+
+// Upper bound on a frame length, so a corrupt length prefix fails instead of
+// allocating gigabytes
+const MAX_FRAME_BYTES: u32 = 1 << 30;
+
+/// Summary of an exported or imported backup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupInfo {
+    /// For a differential backup, the sequence number of the backup it
+    /// builds on; None for a full backup
+    #[serde(default)]
+    pub since: Option<u64>,
+    /// Sequence number the backup was pinned at: it holds every write
+    /// acknowledged before the export started and none that began after.
+    /// Pass it as `since` to take the next differential backup.
+    pub sequence: u64,
+    /// Keys in the backup: live keys, plus deleted ones in a differential backup
+    pub entries: u64,
+}
+
+/// One key. A full backup only holds live keys since an import always starts
+/// from an empty store; a differential one also holds deletions as entries
+/// without a value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct BackupEntry {
+    pub(crate) key: Vec<u8>,
+    pub(crate) value: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum BackupFrame {
+    Header {
+        version: u32,
+        sequence: u64,
+        #[serde(default)]
+        since: Option<u64>,
+        created_at: u64,
+    },
+    Entries(Vec<BackupEntry>),
+    End { entries: u64 },
+}
+
+/// Writes an archive, buffering entries into chunk frames
+pub(crate) struct BackupWriter<W> {
+    writer: W,
+    chunk: Vec<BackupEntry>,
+    chunk_bytes: usize,
+    entries: u64,
+}
+
+impl<W: AsyncWrite + Unpin> BackupWriter<W> {
+    pub(crate) async fn start(mut writer: W, sequence: u64, since: Option<u64>) -> Result<Self> {
+        writer.write_all(MAGIC).await?;
+        let header = BackupFrame::Header {
+            version: BACKUP_FORMAT_VERSION,
+            sequence,
+            since,
+            created_at: crate::now_millis(),
+        };
+        write_frame(&mut writer, &header).await?;
+        Ok(Self { writer, chunk: Vec::new(), chunk_bytes: 0, entries: 0 })
+    }
+
+    pub(crate) async fn add(&mut self, entry: BackupEntry) -> Result<()> {
+        self.chunk_bytes += entry.key.len() + entry.value.as_ref().map_or(0, Vec::len);
+        self.chunk.push(entry);
+        self.entries += 1;
+        if self.chunk.len() >= CHUNK_ENTRIES || self.chunk_bytes >= CHUNK_BYTES {
+            self.write_chunk().await?;
+        }
+        Ok(())
+    }
+
+    /// Write the remaining entries and the end frame. Returns the entry count.
+    pub(crate) async fn finish(mut self) -> Result<u64> {
+        self.write_chunk().await?;
+        write_frame(&mut self.writer, &BackupFrame::End { entries: self.entries }).await?;
+        self.writer.flush().await?;
+        Ok(self.entries)
+    }
+
+    async fn write_chunk(&mut self) -> Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let frame = BackupFrame::Entries(std::mem::take(&mut self.chunk));
+        self.chunk_bytes = 0;
+        write_frame(&mut self.writer, &frame).await
+    }
+}
+
+/// Reads an archive chunk by chunk, verifying every frame
+pub(crate) struct BackupReader<R> {
+    reader: R,
+    sequence: u64,
+    since: Option<u64>,
+    entries: u64,
+    finished: bool,
+}
+
+impl<R: AsyncRead + Unpin> BackupReader<R> {
+    pub(crate) async fn open(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).await
+            .map_err(|_| StorageError::Corruption("not a backup archive: too short".to_string()))?;
+        if &magic != MAGIC {
+            return Err(StorageError::Corruption("not a backup archive: bad magic".to_string()));
+        }
+
+        match read_frame(&mut reader).await? {
+            BackupFrame::Header { version, sequence, since, .. }
+                if (OLDEST_READABLE_VERSION..=BACKUP_FORMAT_VERSION).contains(&version) =>
+            {
+                Ok(Self { reader, sequence, since, entries: 0, finished: false })
+            }
+            BackupFrame::Header { version, .. } => Err(StorageError::Corruption(format!(
+                "unsupported backup format version {}", version
+            ))),
+            _ => Err(StorageError::Corruption("backup archive does not start with a header".to_string())),
+        }
+    }
+
+    pub(crate) fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub(crate) fn since(&self) -> Option<u64> {
+        self.since
+    }
+
+    /// The next chunk of entries, or None once the end frame has been read
+    /// and its entry count matches
+    pub(crate) async fn next_chunk(&mut self) -> Result<Option<Vec<BackupEntry>>> {
+        if self.finished {
+            return Ok(None);
+        }
+        match read_frame(&mut self.reader).await? {
+            BackupFrame::Entries(entries) => {
+                self.entries += entries.len() as u64;
+                Ok(Some(entries))
+            }
+            BackupFrame::End { entries } if entries == self.entries => {
+                self.finished = true;
+                Ok(None)
+            }
+            BackupFrame::End { entries } => Err(StorageError::Corruption(format!(
+                "backup archive ends after {} entries but should hold {}", self.entries, entries
+            ))),
+            BackupFrame::Header { .. } => {
+                Err(StorageError::Corruption("unexpected header in backup archive".to_string()))
+            }
+        }
+    }
+}
+
+/// The archives that restore one store, in the order they apply: a full
+/// backup followed by differential backups, each continuing from the one
+/// before it. Saved as JSON alongside the archives it names.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub archives: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Archive file name, relative to the manifest's directory
+    pub file: String,
+    #[serde(flatten)]
+    pub info: BackupInfo,
+}
+
+impl BackupManifest {
+    /// Sequence number to take the next differential backup from
+    pub fn sequence(&self) -> Option<u64> {
+        self.archives.last().map(|entry| entry.info.sequence)
+    }
+
+    /// Append an archive, checking that it continues the chain
+    pub fn push(&mut self, file: impl Into<String>, info: BackupInfo) -> Result<()> {
+        check_chain(self.archives.last().map(|entry| &entry.info), &info)?;
+        self.archives.push(ManifestEntry { file: file.into(), info });
+        Ok(())
+    }
+
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let data = tokio::fs::read(path).await?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Write the manifest to `path`, replacing any previous version atomically
+    pub async fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let temp = path.with_extension("tmp");
+        tokio::fs::write(&temp, serde_json::to_vec_pretty(self)?).await?;
+        tokio::fs::rename(&temp, path).await?;
+        Ok(())
+    }
+}
+
+/// Check that `next` can be applied after `previous`: a chain starts with a
+/// full backup, and each differential backup must start no later than the
+/// point the previous backup reached, or writes in between would be lost.
+pub(crate) fn check_chain(previous: Option<&BackupInfo>, next: &BackupInfo) -> Result<()> {
+    match (previous, next.since) {
+        (None, None) => Ok(()),
+        (None, Some(_)) => Err(StorageError::Config(
+            "a backup chain must start with a full backup".to_string()
+        )),
+        (Some(_), None) => Err(StorageError::Config(
+            "a full backup can only start a backup chain".to_string()
+        )),
+        (Some(previous), Some(since)) if since > previous.sequence => Err(StorageError::Config(format!(
+            "differential backup since sequence {} does not continue a backup that ends at {}",
+            since, previous.sequence
+        ))),
+        (Some(_), Some(_)) => Ok(()),
+    }
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &BackupFrame) -> Result<()> {
+    let data = serde_json::to_vec(frame)?;
+    writer.write_u32(data.len() as u32).await?;
+    writer.write_u32(crc32fast::hash(&data)).await?;
+    writer.write_all(&data).await?;
+    Ok(())
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<BackupFrame> {
+    let truncated = |_| StorageError::Corruption("backup archive is truncated".to_string());
+
+    let length = reader.read_u32().await.map_err(truncated)?;
+    let crc = reader.read_u32().await.map_err(truncated)?;
+    if length > MAX_FRAME_BYTES {
+        return Err(StorageError::Corruption(format!("backup frame of {} bytes is too large", length)));
+    }
+
+    let mut data = vec![0u8; length as usize];
+    reader.read_exact(&mut data).await.map_err(truncated)?;
+    if crc32fast::hash(&data) != crc {
+        return Err(StorageError::Corruption("backup frame checksum mismatch".to_string()));
+    }
+    Ok(serde_json::from_slice(&data)?)
+}
@@ -0,0 +1,316 @@
+//! Encoding of SSTable data blocks.
+//!
+//! Keys within a block are prefix-compressed: each entry stores only the
+//! length of the prefix it shares with the previous key and the bytes that
+//! follow it. Every `RESTART_INTERVAL` entries a restart point stores its key
+//! in full, and the block ends with the offsets of its restart points, so a
+//! lookup binary searches the restart keys and decodes at most one interval.
+//!
+//! Entry layout, with lengths and numbers as LEB128 varints:
+//!
+//! ```text
+//! shared | unshared | key suffix | flags | sequence | [expires_at] | [value length | value]
+//! ```
+//!
+//! followed at the end of the block by each restart offset and the restart
+//! count as little-endian u32s.
+
+use crate::{
+    error::{Result, StorageError},
+    sstable::BlockEntry,
+};
+
+pub(crate) const RESTART_INTERVAL: usize = 16;
+
+const HAS_VALUE: u8 = 1;
+const HAS_EXPIRY: u8 = 2;
+
+/// Encodes entries, added in key order, into one block
+#[derive(Debug, Default)]
+pub(crate) struct BlockBuilder {
+    data: Vec<u8>,
+    restarts: Vec<u32>,
+    last_key: Vec<u8>,
+    entries: usize,
+}
+
+impl BlockBuilder {
+    pub(crate) fn add(&mut self, key: &[u8], value: Option<&[u8]>, sequence: u64, expires_at: Option<u64>) {
+        let shared = if self.entries.is_multiple_of(RESTART_INTERVAL) {
+            self.restarts.push(self.data.len() as u32);
+            0
+        } else {
+            self.last_key.iter().zip(key).take_while(|(a, b)| a == b).count()
+        };
+
+        put_varint(&mut self.data, shared as u64);
+        put_varint(&mut self.data, (key.len() - shared) as u64);
+        self.data.extend_from_slice(&key[shared..]);
+
+        let flags = if value.is_some() { HAS_VALUE } else { 0 }
+            | if expires_at.is_some() { HAS_EXPIRY } else { 0 };
+        self.data.push(flags);
+        put_varint(&mut self.data, sequence);
+        if let Some(expires_at) = expires_at {
+            put_varint(&mut self.data, expires_at);
+        }
+        if let Some(value) = value {
+            put_varint(&mut self.data, value.len() as u64);
+            self.data.extend_from_slice(value);
+        }
+
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.entries += 1;
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Size of the encoded block if it were finished now
+    pub(crate) fn size(&self) -> usize {
+        self.data.len() + 4 * (self.restarts.len() + 1)
+    }
+
+    /// The encoded block. The builder is left empty for the next one.
+    pub(crate) fn finish(&mut self) -> Vec<u8> {
+        let mut data = std::mem::take(&mut self.data);
+        for restart in &self.restarts {
+            data.extend_from_slice(&restart.to_le_bytes());
+        }
+        data.extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+        *self = Self { data: Vec::with_capacity(data.len()), ..Self::default() };
+        data
+    }
+}
+
+/// A decoded view of an encoded block
+pub(crate) struct Block<'a> {
+    data: &'a [u8],
+    restarts: Vec<u32>,
+}
+
+impl<'a> Block<'a> {
+    pub(crate) fn new(block: &'a [u8]) -> Result<Self> {
+        let corrupt = || StorageError::Corruption("Invalid block: bad restart array".to_string());
+
+        let count_at = block.len().checked_sub(4).ok_or_else(corrupt)?;
+        let count = u32::from_le_bytes(block[count_at..].try_into().unwrap()) as usize;
+        let restarts_at = count.checked_mul(4)
+            .and_then(|bytes| count_at.checked_sub(bytes))
+            .ok_or_else(corrupt)?;
+
+        let restarts: Vec<u32> = block[restarts_at..count_at]
+            .chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        if restarts.first().is_some_and(|first| *first != 0)
+            || restarts.windows(2).any(|pair| pair[0] >= pair[1])
+            || restarts.last().is_some_and(|last| *last as usize >= restarts_at)
+        {
+            return Err(corrupt());
+        }
+        Ok(Self { data: &block[..restarts_at], restarts })
+    }
+
+    /// The entry for `key`, if the block has one
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<BlockEntry>> {
+        match self.seek(key)?.next().transpose()? {
+            Some(entry) if entry.key == key => Ok(Some(entry)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Entries from the first one with a key >= `key`
+    pub(crate) fn seek(&self, key: &[u8]) -> Result<impl Iterator<Item = Result<BlockEntry>> + 'a> {
+        // Find the last restart point whose key is <= `key`. Restart keys are
+        // stored whole, so they compare without decoding their interval.
+        let (mut low, mut high) = (0, self.restarts.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.restart_key(mid)? <= key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let offset = match low.checked_sub(1) {
+            Some(restart) => self.restarts[restart] as usize,
+            None => 0,
+        };
+
+        let target = key.to_vec();
+        Ok(self.iter_from(offset)
+            .skip_while(move |entry| entry.as_ref().is_ok_and(|entry| entry.key < target)))
+    }
+
+    fn restart_key(&self, restart: usize) -> Result<&'a [u8]> {
+        let mut reader = Reader { data: self.data, pos: self.restarts[restart] as usize };
+        if reader.varint()? != 0 {
+            return Err(StorageError::Corruption("Invalid block: restart key is not whole".to_string()));
+        }
+        let length = reader.varint()? as usize;
+        reader.bytes(length)
+    }
+
+    fn iter_from(&self, offset: usize) -> BlockIter<'a> {
+        BlockIter { reader: Reader { data: self.data, pos: offset }, key: Vec::new(), failed: false }
+    }
+}
+
+struct BlockIter<'a> {
+    reader: Reader<'a>,
+    key: Vec<u8>,
+    failed: bool,
+}
+
+impl BlockIter<'_> {
+    fn decode(&mut self) -> Result<BlockEntry> {
+        let shared = self.reader.varint()? as usize;
+        let unshared = self.reader.varint()? as usize;
+        if shared > self.key.len() {
+            return Err(StorageError::Corruption("Invalid block: shared prefix too long".to_string()));
+        }
+        self.key.truncate(shared);
+        self.key.extend_from_slice(self.reader.bytes(unshared)?);
+
+        let flags = self.reader.bytes(1)?[0];
+        let sequence = self.reader.varint()?;
+        let expires_at = if flags & HAS_EXPIRY != 0 { Some(self.reader.varint()?) } else { None };
+        let value = if flags & HAS_VALUE != 0 {
+            let length = self.reader.varint()? as usize;
+            Some(self.reader.bytes(length)?.to_vec())
+        } else {
+            None
+        };
+        Ok(BlockEntry { key: self.key.clone(), value, sequence, expires_at })
+    }
+}
+
+impl Iterator for BlockIter<'_> {
+    type Item = Result<BlockEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.reader.pos >= self.reader.data.len() {
+            return None;
+        }
+        let entry = self.decode();
+        self.failed = entry.is_err();
+        Some(entry)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        let bytes = self.pos.checked_add(length)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| StorageError::Corruption("Invalid block: entry is truncated".to_string()))?;
+        self.pos += length;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.bytes(1)?[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(StorageError::Corruption("Invalid block: varint too long".to_string()))
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(builder: &mut BlockBuilder, entry: &BlockEntry) {
+        builder.add(&entry.key, entry.value.as_deref(), entry.sequence, entry.expires_at);
+    }
+
+    fn entry(i: usize) -> BlockEntry {
+        BlockEntry {
+            key: format!("users:profile:{:08}", i * 2).into_bytes(),
+            value: (!i.is_multiple_of(5)).then(|| format!("value-{}", i).into_bytes()),
+            sequence: 1_000 + i as u64,
+            expires_at: i.is_multiple_of(7).then_some(1_700_000_000_000 + i as u64),
+        }
+    }
+
+    #[test]
+    fn test_prefix_compressed_block() {
+        let entries: Vec<BlockEntry> = (0..100).map(entry).collect();
+        let mut builder = BlockBuilder::default();
+        for entry in &entries {
+            add(&mut builder, entry);
+        }
+        let size = builder.size();
+        let encoded = builder.finish();
+        assert_eq!(encoded.len(), size);
+        assert!(builder.is_empty());
+
+        // Shared prefixes are stored once per restart interval, so the block
+        // is smaller than its keys and values laid end to end
+        let raw: usize = entries.iter().map(|e| e.key.len() + e.value.as_ref().map_or(0, Vec::len)).sum();
+        assert!(encoded.len() < raw * 3 / 4, "{} encoded bytes for {} raw", encoded.len(), raw);
+        assert!(encoded.len() < serde_json::to_vec(&entries).unwrap().len() / 4);
+
+        let block = Block::new(&encoded).unwrap();
+        assert_eq!(block.restarts.len(), 100usize.div_ceil(RESTART_INTERVAL));
+        let decoded: Vec<BlockEntry> = block.seek(b"").unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(decoded.len(), entries.len());
+        for (decoded, original) in decoded.iter().zip(&entries) {
+            assert_eq!((&decoded.key, &decoded.value, decoded.sequence, decoded.expires_at),
+                (&original.key, &original.value, original.sequence, original.expires_at));
+        }
+
+        // Lookups at restart points, just after them, mid-interval and at the
+        // end of an interval, plus keys that fall between stored ones
+        for i in [0, 1, 8, 15, 16, 17, 31, 32, 50, 96, 99] {
+            let found = block.get(&entries[i].key).unwrap().unwrap();
+            assert_eq!(found.value, entries[i].value, "entry {}", i);
+            let between = format!("users:profile:{:08}", i * 2 + 1).into_bytes();
+            assert!(block.get(&between).unwrap().is_none());
+            let next = block.seek(&between).unwrap().next().map(|e| e.unwrap().key);
+            assert_eq!(next, entries.get(i + 1).map(|e| e.key.clone()));
+        }
+        assert!(block.get(b"a").unwrap().is_none());
+        assert_eq!(block.seek(b"a").unwrap().next().unwrap().unwrap().key, entries[0].key);
+        assert!(block.get(b"zzz").unwrap().is_none());
+        assert!(block.seek(b"zzz").unwrap().next().is_none());
+    }
+
+    #[test]
+    fn test_corrupt_block_rejected() {
+        let mut builder = BlockBuilder::default();
+        for i in 0..40 {
+            add(&mut builder, &entry(i));
+        }
+        let encoded = builder.finish();
+
+        assert!(Block::new(&encoded[..2]).is_err());
+        assert!(Block::new(&encoded[..encoded.len() - 1]).is_err());
+
+        // Cutting entries short is caught while decoding
+        let mut truncated = encoded[..30].to_vec();
+        truncated.extend_from_slice(&0u32.to_le_bytes());
+        truncated.extend_from_slice(&1u32.to_le_bytes());
+        assert!(Block::new(&truncated).unwrap().seek(b"").unwrap().any(|entry| entry.is_err()));
+    }
+}
@@ -1,96 +1,259 @@
+use std::borrow::Borrow;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
 use parking_lot::RwLock;
+use serde::Serialize;
 
-/// Simple LRU cache for hot data blocks
+/// Simple LRU cache for hot data blocks. Blocks are kept decompressed
+/// unless made `with_compressed_blocks`, when they are kept as the SSTable
+/// stores them and decompressed on each hit, so more fit in the capacity at
+/// the cost of CPU.
 pub struct BlockCache {
-    cache: RwLock<LRUCache>,
+    cache: RwLock<LRUCache<String, Vec<u8>>>,
+    compressed: bool,
+    hits: AtomicU64,
+    misses: AtomicU64,
 }
 
-struct LRUCache {
-    data: HashMap<String, CacheEntry>,
+/// Lookups served by the cache since it was created, and its current size
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub size_bytes: usize,
+    pub capacity_bytes: usize,
+}
+
+impl CacheStats {
+    /// Fraction of lookups that were hits, or None before the first lookup
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+    }
+}
+
+/// LRU cache of row values, above the block cache: the values point reads
+/// found in the SSTables, by whole key, so a hot key is read again without
+/// finding, decompressing and parsing its block. Keys the SSTables do not
+/// hold are cached too. A write to a key must `invalidate` it once it is in
+/// the memtable, and a change to SSTable values other than by a flush, such
+/// as a compaction filter's, must `clear` the cache.
+pub struct RowCache {
+    cache: RwLock<LRUCache<Vec<u8>, CachedRow>>,
+    /// Invalidations so far; a value read before one is not cached
+    epoch: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Clone)]
+struct CachedRow {
+    value: Option<Vec<u8>>,
+    expires_at: Option<u64>,
+}
+
+struct LRUCache<K, V> {
+    data: HashMap<K, CacheEntry<V>>,
     capacity: usize,
     current_size: usize,
-    access_order: Vec<String>,
+    access_order: Vec<K>,
 }
 
-struct CacheEntry {
-    value: Vec<u8>,
+struct CacheEntry<V> {
+    value: V,
     size: usize,
 }
 
-impl BlockCache {
-    pub fn new(capacity: usize) -> Self {
+impl<K: Hash + Eq + Clone, V> LRUCache<K, V> {
+    fn new(capacity: usize) -> Self {
         Self {
-            cache: RwLock::new(LRUCache {
-                data: HashMap::new(),
-                capacity,
-                current_size: 0,
-                access_order: Vec::new(),
-            }),
+            data: HashMap::new(),
+            capacity,
+            current_size: 0,
+            access_order: Vec::new(),
         }
     }
     
-    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
-        let mut cache = self.cache.write();
-        
-        if let Some(entry) = cache.data.get(key) {
-            let value = entry.value.clone();
-            
-            // Move to end (most recently used)
-            if let Some(pos) = cache.access_order.iter().position(|k| k == key) {
-                cache.access_order.remove(pos);
-            }
-            cache.access_order.push(key.to_string());
-            
-            return Some(value);
+    /// The entry for `key`, made the most recently used
+    fn get<Q: Hash + Eq + ?Sized>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        if !self.data.contains_key(key) {
+            return None;
         }
-        
-        None
+        if let Some(pos) = self.access_order.iter().position(|k| k.borrow() == key) {
+            let k = self.access_order.remove(pos);
+            self.access_order.push(k);
+        }
+        self.data.get(key).map(|entry| &entry.value)
     }
     
-    pub fn put(&self, key: String, value: Vec<u8>) {
-        let mut cache = self.cache.write();
-        let entry_size = key.len() + value.len();
-        
-        // Remove existing entry if present
-        if let Some(old_entry) = cache.data.remove(&key) {
-            cache.current_size -= key.len() + old_entry.size;
-            if let Some(pos) = cache.access_order.iter().position(|k| k == &key) {
-                cache.access_order.remove(pos);
+    /// Insert `value` taking `size` bytes, evicting the least recently used
+    /// entries to make room. A value larger than the capacity is not kept.
+    fn insert(&mut self, key: K, value: V, size: usize) {
+        self.remove(&key);
+        let room = self.capacity.saturating_sub(size);
+        self.evict_to(room);
+        if size <= self.capacity {
+            self.data.insert(key.clone(), CacheEntry { value, size });
+            self.access_order.push(key);
+            self.current_size += size;
+        }
+    }
+    
+    fn remove<Q: Hash + Eq + ?Sized>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+    {
+        if let Some(entry) = self.data.remove(key) {
+            self.current_size -= entry.size;
+            if let Some(pos) = self.access_order.iter().position(|k| k.borrow() == key) {
+                self.access_order.remove(pos);
             }
         }
-        
-        // Evict entries if necessary
-        while cache.current_size + entry_size > cache.capacity && !cache.access_order.is_empty() {
-            if let Some(lru_key) = cache.access_order.first().cloned() {
-                if let Some(entry) = cache.data.remove(&lru_key) {
-                    cache.current_size -= lru_key.len() + entry.size;
-                }
-                cache.access_order.remove(0);
-            } else {
-                break;
+    }
+    
+    fn clear(&mut self) {
+        self.data.clear();
+        self.access_order.clear();
+        self.current_size = 0;
+    }
+    
+    /// Evict the least recently used entries until at most `size` bytes remain
+    fn evict_to(&mut self, size: usize) {
+        let mut evicted = 0;
+        while self.current_size > size && evicted < self.access_order.len() {
+            if let Some(entry) = self.data.remove(&self.access_order[evicted]) {
+                self.current_size -= entry.size;
             }
+            evicted += 1;
         }
-        
-        // Insert new entry if it fits
-        if entry_size <= cache.capacity {
-            cache.data.insert(key.clone(), CacheEntry {
-                value,
-                size: entry_size,
-            });
-            cache.access_order.push(key);
-            cache.current_size += entry_size;
+        self.access_order.drain(..evicted);
+    }
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            cache: RwLock::new(LRUCache::new(capacity)),
+            compressed: false,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
         }
     }
     
+    /// Keep blocks compressed (see `BlockCache`)
+    pub fn with_compressed_blocks(mut self, compressed: bool) -> Self {
+        self.compressed = compressed;
+        self
+    }
+    
+    /// Whether blocks are kept compressed
+    pub fn holds_compressed_blocks(&self) -> bool {
+        self.compressed
+    }
+    
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let value = self.cache.write().get(key).cloned();
+        let counter = if value.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+    
+    pub fn put(&self, key: String, value: Vec<u8>) {
+        let size = key.len() + value.len();
+        self.cache.write().insert(key, value, size);
+    }
+    
     pub fn size(&self) -> usize {
         self.cache.read().current_size
     }
     
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.cache.read().data.len()
+    }
+    
+    /// Hold at most `capacity` bytes from now on, evicting the least
+    /// recently used entries over it
+    pub fn resize(&self, capacity: usize) {
+        let mut cache = self.cache.write();
+        cache.capacity = capacity;
+        cache.evict_to(capacity);
+    }
+    
     pub fn capacity(&self) -> usize {
         self.cache.read().capacity
     }
+    
+    pub fn stats(&self) -> CacheStats {
+        let cache = self.cache.read();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size_bytes: cache.current_size,
+            capacity_bytes: cache.capacity,
+        }
+    }
+}
+
+impl RowCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            cache: RwLock::new(LRUCache::new(capacity)),
+            epoch: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+    
+    /// The value of `key` at `now` if it is cached, None for a key the
+    /// SSTables do not hold or that has expired
+    pub fn get(&self, key: &[u8], now: u64) -> Option<Option<Vec<u8>>> {
+        let row = self.cache.write().get(key).cloned();
+        let counter = if row.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        row.map(|row| row.value.filter(|_| row.expires_at.is_none_or(|t| t > now)))
+    }
+    
+    /// Taken before a read whose value may be cached
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+    
+    /// Cache the value of `key` read since `epoch`, unless a key was
+    /// invalidated since, which may have been this one
+    pub fn put(&self, key: Vec<u8>, value: Option<Vec<u8>>, expires_at: Option<u64>, epoch: u64) {
+        let mut cache = self.cache.write();
+        if self.epoch() == epoch {
+            let size = key.len() + value.as_ref().map_or(0, Vec::len);
+            cache.insert(key, CachedRow { value, expires_at }, size);
+        }
+    }
+    
+    pub fn invalidate(&self, key: &[u8]) {
+        let mut cache = self.cache.write();
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        cache.remove(key);
+    }
+    
+    pub fn clear(&self) {
+        let mut cache = self.cache.write();
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        cache.clear();
+    }
+    
+    pub fn stats(&self) -> CacheStats {
+        let cache = self.cache.read();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size_bytes: cache.current_size,
+            capacity_bytes: cache.capacity,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -107,6 +270,11 @@ mod tests {
         
         // Test nonexistent key
         assert_eq!(cache.get("nonexistent"), None);
+        
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!(stats.hit_rate(), Some(0.5));
+        assert_eq!(stats.size_bytes, 10);
     }
     
     #[test]
@@ -129,5 +297,63 @@ mod tests {
         
         // key4 should be there (just inserted)
         assert_eq!(cache.get("key4"), Some(b"value4444444".to_vec()));
+    }    
+    #[test]
+    fn test_cache_size_after_replace_and_evict() {
+        let cache = BlockCache::new(30);
+        cache.put("key1".to_string(), b"value1".to_vec());
+        cache.put("key1".to_string(), b"v1".to_vec());
+        assert_eq!(cache.size(), 6);
+        
+        cache.put("key2".to_string(), b"value2value2".to_vec());
+        cache.put("key3".to_string(), b"value3".to_vec());
+        // key1 was evicted to make room
+        assert_eq!(cache.get("key1"), None);
+        assert_eq!(cache.size(), 26);
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_cache_resize() {
+        let cache = BlockCache::new(40);
+        cache.put("key1".to_string(), b"value1".to_vec());
+        cache.put("key2".to_string(), b"value2".to_vec());
+        cache.put("key3".to_string(), b"value3".to_vec());
+        cache.get("key1");
+        
+        // Shrinking evicts the least recently used first
+        cache.resize(20);
+        assert_eq!(cache.stats().capacity_bytes, 20);
+        assert_eq!(cache.size(), 20);
+        assert_eq!(cache.get("key2"), None);
+        assert!(cache.get("key1").is_some() && cache.get("key3").is_some());
+        
+        cache.resize(100);
+        cache.put("key4".to_string(), vec![0; 50]);
+        assert_eq!(cache.size(), 74);
+    }    
+    #[test]
+    fn test_row_cache_invalidation() {
+        let cache = RowCache::new(100);
+        let epoch = cache.epoch();
+        cache.put(b"k1".to_vec(), Some(b"v1".to_vec()), None, epoch);
+        cache.put(b"k2".to_vec(), None, None, epoch);
+        cache.put(b"k3".to_vec(), Some(b"v3".to_vec()), Some(1_000), epoch);
+        assert_eq!(cache.get(b"k1", 0), Some(Some(b"v1".to_vec())));
+        assert_eq!(cache.get(b"k2", 0), Some(None));
+        assert_eq!(cache.get(b"k3", 999), Some(Some(b"v3".to_vec())));
+        assert_eq!(cache.get(b"k3", 1_000), Some(None));
+        assert_eq!(cache.stats().size_bytes, 10);
+        
+        // A value read before an invalidation is not cached
+        let stale = cache.epoch();
+        cache.invalidate(b"k1");
+        assert_eq!(cache.get(b"k1", 0), None);
+        cache.put(b"k1".to_vec(), Some(b"old".to_vec()), None, stale);
+        assert_eq!(cache.get(b"k1", 0), None);
+        
+        cache.clear();
+        assert_eq!(cache.get(b"k2", 0), None);
+        assert_eq!(cache.stats().size_bytes, 0);
+        assert_eq!((cache.stats().hits, cache.stats().misses), (4, 3));
+    }
+}
@@ -0,0 +1,23 @@
+//! Hooks into compaction.
+
+/// What a `CompactionFilter` does with an entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Keep,
+    /// Drop the entry, so the key reads as absent unless written again
+    Remove,
+    /// Keep the entry with this value instead
+    Change(Vec<u8>),
+}
+
+/// Transforms or drops entries as compaction rewrites them, for migrating
+/// value formats or purging keys by a predicate without writing to each.
+///
+/// The filter sees each key's latest live value once per compaction, and
+/// never deletions, expired entries or writes still in memtables. Its
+/// decisions are not logged: changefeeds and differential backups do not
+/// see them, and recovering from the WAL after a crash replays the original
+/// writes.
+pub trait CompactionFilter: Send + Sync {
+    fn filter(&self, key: &[u8], value: &[u8]) -> Decision;
+}
@@ -1,7 +1,7 @@
 use crate::error::{Result, StorageError};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CompressionType {
     None,
     LZ4,
@@ -21,6 +21,19 @@ pub fn compress(data: &[u8], compression_type: &CompressionType) -> Result<Vec<u
     }
 }
 
+/// Compress one block unless it is smaller than `threshold` bytes, which is
+/// not worth the CPU. Returns the compression actually applied.
+pub fn compress_block(
+    data: &[u8],
+    compression_type: &CompressionType,
+    threshold: usize,
+) -> Result<(CompressionType, Vec<u8>)> {
+    if data.len() < threshold {
+        return Ok((CompressionType::None, data.to_vec()));
+    }
+    Ok((compression_type.clone(), compress(data, compression_type)?))
+}
+
 pub fn decompress(data: &[u8], compression_type: &CompressionType) -> Result<Vec<u8>> {
     match compression_type {
         CompressionType::None => Ok(data.to_vec()),
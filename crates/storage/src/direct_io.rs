@@ -0,0 +1,170 @@
+//! Appending to a file with direct IO (`O_DIRECT`), bypassing the page cache.
+//!
+//! Direct writes must start at a block-aligned offset, cover whole blocks and
+//! come from block-aligned memory. The file's data therefore ends in zero
+//! padding, and its logical length is kept in memory: each append rewrites
+//! the partial block at the end of the data together with the new bytes,
+//! padded to a whole block. Readers find the end of the data from its
+//! contents.
+
+use std::alloc::{self, Layout};
+use std::io;
+use std::path::Path;
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+/// Block size direct writes are aligned to. Covers devices with 512-byte
+/// and 4 KiB logical blocks.
+pub(crate) const ALIGNMENT: usize = 4096;
+
+pub(crate) struct DirectFile {
+    file: Arc<std::fs::File>,
+    // Starts with the bytes of the partial block at the end of the data.
+    // Only None while a write is in flight.
+    buffer: Option<AlignedBuffer>,
+    len: u64,
+}
+
+impl DirectFile {
+    /// Open `path` for direct appends after its first `len` bytes. Fails
+    /// with `Unsupported` where the platform or file system lacks direct IO.
+    pub(crate) fn open(path: &Path, len: u64, buffer_size: usize) -> io::Result<Self> {
+        let file = Arc::new(open_direct(path)?);
+
+        let tail = (len % ALIGNMENT as u64) as usize;
+        let mut buffer = AlignedBuffer::new(buffer_size.max(tail));
+        if tail > 0 {
+            // Read through the page cache, which has no alignment rules
+            use std::io::{Read, Seek, SeekFrom};
+            let mut reader = std::fs::File::open(path)?;
+            reader.seek(SeekFrom::Start(len - tail as u64))?;
+            reader.read_exact(&mut buffer.as_mut_slice()[..tail])?;
+        }
+        Ok(Self { file, buffer: Some(buffer), len })
+    }
+
+    /// Append `data`, with `sync` waiting until it is on stable storage
+    pub(crate) async fn append(&mut self, data: &[u8], sync: bool) -> io::Result<()> {
+        let tail = (self.len % ALIGNMENT as u64) as usize;
+        let start = self.len - tail as u64;
+        let end = tail + data.len();
+        let padded = end.div_ceil(ALIGNMENT) * ALIGNMENT;
+
+        let mut buffer = self.buffer.take().expect("no write in flight");
+        if buffer.capacity() < padded {
+            let mut larger = AlignedBuffer::new(padded);
+            larger.as_mut_slice()[..tail].copy_from_slice(&buffer.as_mut_slice()[..tail]);
+            buffer = larger;
+        }
+        let bytes = buffer.as_mut_slice();
+        bytes[tail..end].copy_from_slice(data);
+        bytes[end..padded].fill(0);
+
+        let file = self.file.clone();
+        let (buffer, result) = tokio::task::spawn_blocking(move || {
+            let result = write_all_at(&file, &buffer.as_slice()[..padded], start)
+                .and_then(|_| if sync { file.sync_data() } else { Ok(()) });
+            (buffer, result)
+        }).await.map_err(io::Error::other)?;
+        self.buffer = Some(buffer);
+        result?;
+
+        // Keep the new partial block at the front for the next append
+        self.len += data.len() as u64;
+        let new_tail = end % ALIGNMENT;
+        let bytes = self.buffer.as_mut().unwrap().as_mut_slice();
+        bytes.copy_within(end - new_tail..end, 0);
+        Ok(())
+    }
+
+    /// Wait until everything appended is on stable storage
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    pub(crate) fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(path: &Path) -> io::Result<std::fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+        .map_err(|e| match e.raw_os_error() {
+            // The file system does not support O_DIRECT
+            Some(libc::EINVAL) => io::Error::new(io::ErrorKind::Unsupported, e),
+            _ => e,
+        })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_path: &Path) -> io::Result<std::fs::File> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "direct IO is only supported on Linux"))
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &std::fs::File, bytes: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, bytes, offset)
+}
+
+#[cfg(not(unix))]
+fn write_all_at(_file: &std::fs::File, _bytes: &[u8], _offset: u64) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "direct IO is only supported on Linux"))
+}
+
+/// Zeroed heap memory aligned to `ALIGNMENT`, in whole blocks
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    capacity: usize,
+}
+
+// The buffer owns its memory like a Vec<u8> does, and only writes to it
+// through `&mut self`
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1).div_ceil(ALIGNMENT) * ALIGNMENT;
+        let layout = Self::layout(capacity);
+        // SAFETY: the layout has a non-zero size
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, capacity }
+    }
+
+    fn layout(capacity: usize) -> Layout {
+        Layout::from_size_align(capacity, ALIGNMENT).expect("valid buffer layout")
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: `ptr` points to `capacity` initialized bytes owned by self
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.capacity) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: as in `as_slice`, and `&mut self` makes the access unique
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.capacity) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `new` with this layout
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.capacity)) }
+    }
+}
@@ -3,7 +3,7 @@ use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum StorageError {
     #[error("IO error: {0}")]
-    Io(#[from] std::io::Error),
+    Io(std::io::Error),
     
     #[error("Serialization error: {0}")]
     Serde(#[from] serde_json::Error),
@@ -28,6 +28,34 @@ pub enum StorageError {
     
     #[error("Internal error: {0}")]
     Internal(String),
+    
+    /// The tree was closed with `LSMTree::close` and takes no more writes
+    #[error("Storage engine is closed")]
+    Closed,
+    
+    /// The disk is full or the quota used up. A tree whose write hits this
+    /// turns read-only and fails later writes with it too.
+    #[error("Out of disk space: {0}")]
+    OutOfSpace(String),
+    
+    /// A write's key is longer than `StorageConfig::max_key_size_bytes`
+    #[error("Key of {size} bytes exceeds the limit of {limit} bytes")]
+    KeyTooLarge { size: usize, limit: usize },
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(error: std::io::Error) -> Self {
+        if is_out_of_space(&error) {
+            StorageError::OutOfSpace(error.to_string())
+        } else {
+            StorageError::Io(error)
+        }
+    }
+}
+
+/// Whether `error` is ENOSPC or EDQUOT
+pub(crate) fn is_out_of_space(error: &std::io::Error) -> bool {
+    matches!(error.kind(), std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded)
 }
 
 pub type Result<T> = std::result::Result<T, StorageError>;
\ No newline at end of file
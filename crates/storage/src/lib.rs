@@ -1,31 +1,82 @@
 pub mod lsm;
+mod block;
+pub mod backup;
 pub mod wal;
+mod direct_io;
 pub mod memtable;
 pub mod sstable;
 pub mod cache;
 pub mod compression;
+pub mod compaction;
+pub mod merge;
 pub mod error;
 
 pub use error::{StorageError, Result};
-pub use lsm::LSMTree;
-pub use wal::WriteAheadLog;
+pub use lsm::{CheckpointInfo, CompactionSummary, LSMTree, LSMStats, SSTableInfo, SkippedSSTable, StallReason, WriteOp};
+pub use backup::{BackupInfo, BackupManifest, ManifestEntry};
+pub use wal::{Changefeed, Durability, WalOptions, WriteAheadLog};
 pub use memtable::MemTable;
 pub use sstable::SSTable;
-pub use cache::BlockCache;
+pub use cache::{BlockCache, CacheStats, RowCache};
+pub use compaction::{CompactionFilter, Decision};
+pub use merge::MergeOperator;
 
 use serde::{Deserialize, Serialize};
 
 /// Storage engine configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct StorageConfig {
     pub data_dir: String,
     pub wal_dir: String,
     pub memtable_size_mb: usize,
+    /// Number of L0 files at which L0 is compacted
     pub l0_compaction_trigger: usize,
+    /// Total size in bytes of the L0 files at which L0 is compacted, however
+    /// few files there are
+    pub l0_compaction_bytes: u64,
     pub max_levels: usize,
     pub target_file_size_mb: usize,
     pub compression: CompressionType,
+    /// SSTable blocks smaller than this many bytes are stored uncompressed
+    pub compression_threshold: usize,
     pub cache_size_mb: usize,
+    /// Keep blocks compressed in the block cache, decompressing them on
+    /// each hit, so more fit in `cache_size_mb` at the cost of CPU
+    pub cache_compressed_blocks: bool,
+    /// Size of the row cache, which holds the decoded values point reads
+    /// found in the SSTables, above the block cache (0 disables it)
+    pub row_cache_size_mb: usize,
+    /// Serve SSTable block reads from memory-mapped files instead of explicit reads
+    pub mmap_reads: bool,
+    /// Number of L0 files at which writes start being stalled
+    pub l0_stall_trigger: usize,
+    /// Number of unflushed immutable memtables at which writes start being stalled
+    pub max_immutable_memtables: usize,
+    /// Delay applied to each write while a stall condition holds
+    pub write_stall_delay_ms: u64,
+    /// How often the TTL sweeper removes expired entries from SSTables (0 disables it)
+    pub ttl_sweep_interval_ms: u64,
+    /// Flush the memtable once its oldest entry is this old, even if it is
+    /// not full, so a quiet tree does not keep writes only in the WAL for
+    /// long (0 disables it)
+    pub memtable_max_age_ms: u64,
+    /// Initial size of the buffer WAL records are assembled in
+    pub wal_buffer_size: usize,
+    /// Write the WAL with direct IO (O_DIRECT), bypassing the page cache.
+    /// Falls back to buffered writes where that is not supported.
+    pub wal_direct_io: bool,
+    /// What the WAL does to make writes survive a crash. Anything but
+    /// `Full` trades durability for speed and is meant for tests.
+    pub durability: Durability,
+    /// Delete the data and WAL directories on `LSMTree::close`, for tests
+    /// and caches. Only directories `open` created are deleted.
+    pub ephemeral: bool,
+    /// Open even if SSTables are damaged, leaving them out and listing them
+    /// in `LSMTree::skipped_sstables`, rather than failing
+    pub skip_corrupt_sstables: bool,
+    /// Longest key a write accepts; longer ones fail with `KeyTooLarge`
+    pub max_key_size_bytes: usize,
 }
 
 impl Default for StorageConfig {
@@ -35,10 +86,26 @@ impl Default for StorageConfig {
             wal_dir: "./wal".to_string(),
             memtable_size_mb: 64,
             l0_compaction_trigger: 4,
+            l0_compaction_bytes: 256 * 1024 * 1024,
             max_levels: 7,
             target_file_size_mb: 64,
             compression: CompressionType::LZ4,
+            compression_threshold: 256,
             cache_size_mb: 256,
+            cache_compressed_blocks: false,
+            row_cache_size_mb: 0,
+            mmap_reads: false,
+            l0_stall_trigger: 20,
+            max_immutable_memtables: 4,
+            write_stall_delay_ms: 1,
+            ttl_sweep_interval_ms: 60_000,
+            memtable_max_age_ms: 300_000,
+            wal_buffer_size: 64 * 1024,
+            wal_direct_io: false,
+            durability: Durability::Full,
+            ephemeral: false,
+            skip_corrupt_sstables: false,
+            max_key_size_bytes: 64 * 1024,
         }
     }
 }
@@ -52,6 +119,13 @@ pub struct KVPair {
     pub value: Option<Vec<u8>>, // None for deletions
     pub timestamp: u64,
     pub sequence: u64,
+    /// Expiry time in milliseconds since the Unix epoch, if the key has a TTL
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// The value is a merge operand (see `LSMTree::merge`) rather than the
+    /// key's new value
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub merge: bool,
 }
 
 impl KVPair {
@@ -61,6 +135,15 @@ impl KVPair {
             value: Some(value),
             timestamp,
             sequence,
+            expires_at: None,
+            merge: false,
+        }
+    }
+    
+    pub fn merge(key: Vec<u8>, operand: Vec<u8>, timestamp: u64, sequence: u64) -> Self {
+        Self {
+            merge: true,
+            ..Self::new(key, operand, timestamp, sequence)
         }
     }
     
@@ -70,10 +153,25 @@ impl KVPair {
             value: None,
             timestamp,
             sequence,
+            expires_at: None,
+            merge: false,
         }
     }
     
+    pub fn with_expiry(mut self, expires_at: u64) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+    
     pub fn is_deleted(&self) -> bool {
         self.value.is_none()
     }
+}
+
+/// Current wall-clock time in milliseconds since the Unix epoch
+pub(crate) fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
 }
\ No newline at end of file
@@ -1,29 +1,155 @@
 use crate::{
+    backup::{self, BackupEntry, BackupInfo, BackupManifest, BackupReader, BackupWriter},
     error::{Result, StorageError},
-    memtable::MemTable,
-    wal::WriteAheadLog,
-    sstable::{SSTable, SSTableBuilder},
-    cache::BlockCache,
-    compression::CompressionType,
-    StorageConfig, KVPair,
+    memtable::{ImmutableMemtables, MemTable, MemTableEntry},
+    wal::{Changefeed, Durability, WalOptions, WriteAheadLog},
+    sstable::{BlockEntry, SSTable, SSTableBuilder},
+    cache::{BlockCache, CacheStats, RowCache},
+    compaction::{CompactionFilter, Decision},
+    merge::MergeOperator,
+    StorageConfig, KVPair, now_millis,
 };
 
-use std::path::Path;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::RwLock;
 use parking_lot::Mutex;
 
+/// Why writes are currently being slowed down
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum StallReason {
+    TooManyL0Files { count: usize, limit: usize },
+    TooManyImmutableMemtables { count: usize, limit: usize },
+}
+
+impl fmt::Display for StallReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StallReason::TooManyL0Files { count, limit } => {
+                write!(f, "too many L0 files ({} >= {})", count, limit)
+            }
+            StallReason::TooManyImmutableMemtables { count, limit } => {
+                write!(f, "too many immutable memtables ({} >= {})", count, limit)
+            }
+        }
+    }
+}
+
+/// One write in an atomic batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOp {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+/// The order a scan visits keys in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+impl Direction {
+    /// Whether the scan visits `a` before `b`
+    fn precedes(self, a: &[u8], b: &[u8]) -> bool {
+        match self {
+            Direction::Forward => a < b,
+            Direction::Reverse => a > b,
+        }
+    }
+    
+    /// The cursor a scan continues from once it has visited `key`: the
+    /// start just after it, or the end at it in reverse
+    fn resume_after(self, mut key: Vec<u8>) -> Vec<u8> {
+        if self == Direction::Forward {
+            key.push(0);
+        }
+        key
+    }
+}
+
+/// Point-in-time engine statistics
+#[derive(Debug, Clone, Serialize)]
+pub struct LSMStats {
+    pub memtable_size: usize,
+    pub immutable_memtables: usize,
+    /// Entries in the active and immutable memtables, tombstones included
+    pub memtable_entries: u64,
+    pub level_file_counts: Vec<usize>,
+    /// Size of all SSTable files on disk
+    pub sstable_bytes: u64,
+    pub write_stall_reason: Option<StallReason>,
+    pub write_stall_count: u64,
+    pub write_stall_micros: u64,
+    pub sstable_entries: u64,
+    pub expired_entries_swept: u64,
+    /// WAL entries replayed when the tree was opened, none after a clean
+    /// `close`
+    pub wal_entries_replayed: u64,
+    pub cache: CacheStats,
+    /// None without a row cache
+    pub row_cache: Option<CacheStats>,
+}
+
+/// What a compaction did
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CompactionSummary {
+    /// SSTables merged, and their total size
+    pub input_files: usize,
+    pub input_bytes: u64,
+    /// SSTables written in their place, and their total size
+    pub output_files: usize,
+    pub output_bytes: u64,
+}
+
+/// An SSTable, as listed by `LSMTree::sstables`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SSTableInfo {
+    pub file: String,
+    pub size_bytes: u64,
+    pub entries: u64,
+    pub smallest_key: Option<Vec<u8>>,
+    /// None for files that predate recording it
+    pub largest_key: Option<Vec<u8>>,
+}
+
+/// An SSTable `open` left out because it is damaged, with
+/// `skip_corrupt_sstables` set
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SkippedSSTable {
+    pub file: String,
+    pub level: usize,
+    /// Why it could not be opened
+    pub error: String,
+}
+
+/// What `LSMTree::checkpoint` wrote
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CheckpointInfo {
+    pub files: usize,
+    pub bytes: u64,
+    /// Sequence number the checkpoint carries on from when opened
+    pub sequence: u64,
+}
+
 /// LSM-Tree storage engine implementation
 pub struct LSMTree {
     config: StorageConfig,
     sequence_number: AtomicU64,
+    // First sequence number of each write not yet in the memtable
+    in_flight_writes: Mutex<BTreeSet<u64>>,
     
     // Active memtable for writes
     active_memtable: Arc<RwLock<MemTable>>,
     
     // Immutable memtables waiting for flush
-    immutable_memtables: Arc<Mutex<Vec<Arc<MemTable>>>>,
+    immutable_memtables: Arc<Mutex<ImmutableMemtables>>,
     
     // Write-ahead log for durability
     wal: Arc<WriteAheadLog>,
@@ -33,21 +159,145 @@ pub struct LSMTree {
     
     // Block cache for hot data
     cache: Arc<BlockCache>,
+    // Decoded values of hot keys, above the block cache, if configured
+    row_cache: Option<RowCache>,
+    
+    // Cumulative write stall accounting
+    stall_count: AtomicU64,
+    stall_micros: AtomicU64,
+    
+    // Held by any task that rewrites SSTables (TTL sweeps, compaction) so two
+    // rewrites never race over the same files
+    maintenance_lock: tokio::sync::Mutex<()>,
+    // Serializes memtable flushes so each immutable memtable is written once
+    flush_lock: tokio::sync::Mutex<()>,
+    expired_swept: AtomicU64,
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    // Set by `close`, after which writes and SSTable rewrites are refused
+    closed: AtomicBool,
+    // Set when a write runs out of disk space, after which writes are refused
+    read_only: AtomicBool,
+    wal_entries_replayed: AtomicU64,
+    skipped_sstables: Mutex<Vec<SkippedSSTable>>,
+    // Directories `open` created, deleted by `close` if the store is ephemeral
+    created_dirs: Vec<PathBuf>,
+}
+
+/// Left in the data directory by `LSMTree::close`: every SSTable, by level,
+/// and the sequence number to carry on from
+#[derive(Serialize, Deserialize)]
+struct CleanShutdown {
+    sequence: u64,
+    levels: Vec<Vec<String>>,
+}
+
+const CLEAN_SHUTDOWN_FILE: &str = "CLEAN_SHUTDOWN";
+
+impl CleanShutdown {
+    fn new(levels: &[Vec<Arc<SSTable>>], sequence: u64) -> Self {
+        Self {
+            sequence,
+            levels: levels.iter()
+                .map(|level| level.iter().map(|table| file_name(table)).collect())
+                .collect(),
+        }
+    }
+    
+    /// Write the marker to `dir` atomically
+    async fn write(&self, dir: &Path) -> Result<()> {
+        let temp_path = dir.join(format!("{}.tmp", CLEAN_SHUTDOWN_FILE));
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, &serde_json::to_vec(self)?).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&temp_path, dir.join(CLEAN_SHUTDOWN_FILE)).await?;
+        sync_dir(dir)
+    }
+}
+
+/// The outermost directory `create_dir_all(path)` would create, if any
+fn outermost_missing(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+        .last()
+        .map(Path::to_path_buf)
+}
+
+fn file_name(table: &SSTable) -> String {
+    table.path().file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+/// Smallest and largest key of a file, the largest None if unbounded above
+fn key_bounds(table: &SSTable) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+    table.key_range().map(|(first, _)| (first.to_vec(), table.largest_key().map(<[u8]>::to_vec)))
+}
+
+/// `inputs` plus every file in `tables` whose key range overlaps theirs,
+/// repeated until no more files join, so that no file left out holds a
+/// version of a key merging them rewrites
+fn with_overlapping(tables: &[Arc<SSTable>], mut inputs: Vec<Arc<SSTable>>) -> Vec<Arc<SSTable>> {
+    let overlaps = |table: &SSTable, low: &[u8], high: Option<&[u8]>| {
+        key_bounds(table).is_some_and(|(first, last)| {
+            high.is_none_or(|high| first.as_slice() <= high) && last.is_none_or(|last| low <= last.as_slice())
+        })
+    };
+    while let Some((low, high)) = inputs.iter().filter_map(|table| key_bounds(table)).reduce(|(low, high), (first, last)| {
+        let high = match (high, last) {
+            (Some(high), Some(last)) => Some(high.max(last)),
+            _ => None,
+        };
+        (low.min(first), high)
+    }) {
+        let more: Vec<Arc<SSTable>> = tables.iter()
+            .filter(|table| !inputs.iter().any(|input| Arc::ptr_eq(input, table)))
+            .filter(|table| overlaps(table, &low, high.as_deref()))
+            .cloned()
+            .collect();
+        if more.is_empty() {
+            break;
+        }
+        inputs.extend(more);
+    }
+    inputs
 }
 
 impl LSMTree {
     pub async fn open(config: StorageConfig) -> Result<Self> {
+        Self::open_until(config, None).await
+    }
+    
+    /// Open the tree as it was at `timestamp` (milliseconds since the Unix
+    /// epoch), for recovering from an erroneous write: only WAL entries
+    /// written at or before it are replayed. Later entries are dropped from
+    /// the log for good, but the full log is kept beside it as
+    /// `wal-<timestamp>-<time of recovery>.log` in case they are wanted back.
+    pub async fn open_at(config: StorageConfig, timestamp: u64) -> Result<Self> {
+        Self::open_until(config, Some(timestamp)).await
+    }
+    
+    async fn open_until(config: StorageConfig, until: Option<u64>) -> Result<Self> {
         // Create directories if they don't exist
+        let mut created_dirs = Vec::new();
+        created_dirs.extend(outermost_missing(Path::new(&config.data_dir)));
         std::fs::create_dir_all(&config.data_dir)
             .map_err(|e| StorageError::Config(format!("Failed to create data dir: {}", e)))?;
-        std::fs::create_dir_all(&config.wal_dir)
-            .map_err(|e| StorageError::Config(format!("Failed to create WAL dir: {}", e)))?;
+        if config.durability != Durability::Memory {
+            created_dirs.extend(outermost_missing(Path::new(&config.wal_dir)));
+            std::fs::create_dir_all(&config.wal_dir)
+                .map_err(|e| StorageError::Config(format!("Failed to create WAL dir: {}", e)))?;
+        }
         
         // Initialize WAL
-        let wal = Arc::new(WriteAheadLog::open(&config.wal_dir).await?);
+        let wal_options = WalOptions {
+            buffer_size: config.wal_buffer_size,
+            direct_io: config.wal_direct_io,
+            durability: config.durability,
+        };
+        let wal = Arc::new(WriteAheadLog::open_with(&config.wal_dir, wal_options).await?);
         
-        // Initialize block cache
-        let cache = Arc::new(BlockCache::new(config.cache_size_mb * 1024 * 1024));
+        // Initialize the block and row caches
+        let cache = Arc::new(BlockCache::new(config.cache_size_mb * 1024 * 1024).with_compressed_blocks(config.cache_compressed_blocks));
+        let row_cache = (config.row_cache_size_mb > 0).then(|| RowCache::new(config.row_cache_size_mb * 1024 * 1024));
         
         // Initialize empty levels
         let levels = Arc::new(RwLock::new(vec![vec![]; config.max_levels]));
@@ -58,35 +308,77 @@ impl LSMTree {
         let lsm = Self {
             config,
             sequence_number: AtomicU64::new(0),
+            in_flight_writes: Mutex::new(BTreeSet::new()),
             active_memtable,
-            immutable_memtables: Arc::new(Mutex::new(Vec::new())),
+            immutable_memtables: Arc::new(Mutex::new(ImmutableMemtables::default())),
             wal,
             levels,
             cache,
+            row_cache,
+            stall_count: AtomicU64::new(0),
+            stall_micros: AtomicU64::new(0),
+            maintenance_lock: tokio::sync::Mutex::new(()),
+            flush_lock: tokio::sync::Mutex::new(()),
+            expired_swept: AtomicU64::new(0),
+            compaction_filter: None,
+            merge_operator: None,
+            closed: AtomicBool::new(false),
+            read_only: AtomicBool::new(false),
+            wal_entries_replayed: AtomicU64::new(0),
+            skipped_sstables: Mutex::new(Vec::new()),
+            created_dirs,
         };
         
-        // Recover from WAL if needed
-        lsm.recover_from_wal().await?;
+        // After a clean shutdown everything is in SSTables; otherwise recover
+        // from the WAL. Point-in-time recovery always replays it.
+        if !lsm.reopen_after_close(until.is_none()).await? {
+            lsm.recover_from_wal(until).await?;
+        }
         
         Ok(lsm)
     }
     
+    /// Run `filter` over the entries each compaction rewrites
+    pub fn with_compaction_filter(mut self, filter: Arc<dyn CompactionFilter>) -> Self {
+        self.compaction_filter = Some(filter);
+        self
+    }
+    
+    /// Fold the operands written by `merge` with `operator`
+    pub fn with_merge_operator(mut self, operator: Arc<dyn MergeOperator>) -> Self {
+        self.merge_operator = Some(operator);
+        self
+    }
+    
     pub async fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        let seq = self.sequence_number.fetch_add(1, Ordering::SeqCst);
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64;
+        self.put_with_expiry(key, value, None).await
+    }
+    
+    /// Write a key that reads as deleted once `ttl` has elapsed
+    pub async fn put_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis() + ttl.as_millis() as u64;
+        self.put_with_expiry(key, value, Some(expires_at)).await
+    }
+    
+    async fn put_with_expiry(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        self.check_key_size(&key)?;
+        self.stall_if_needed().await;
+        
+        let write = self.begin_write(1)?;
+        let seq = write.first;
+        let timestamp = now_millis();
             
-        let kv_pair = KVPair::new(key.clone(), value, timestamp, seq);
+        let mut kv_pair = KVPair::new(key.clone(), value, timestamp, seq);
+        kv_pair.expires_at = expires_at;
         
         // Write to WAL first for durability
-        self.wal.append(&kv_pair).await?;
+        self.wal.append(&kv_pair).await.map_err(|e| self.note_write_error(e))?;
         
         // Write to active memtable
         {
             let mut memtable = self.active_memtable.write().await;
-            memtable.put(key, kv_pair.value.clone().unwrap(), seq);
+            memtable.put_with_expiry(key.clone(), kv_pair.value.clone().unwrap(), seq, expires_at);
+            self.invalidate_row(&key);
             
             // Check if memtable is full
             if memtable.size() >= self.config.memtable_size_mb * 1024 * 1024 {
@@ -98,28 +390,145 @@ impl LSMTree {
         Ok(())
     }
     
+    /// Record `operand` to be folded into the key's value by the merge
+    /// operator, without reading the value. Fails if no operator is set.
+    pub async fn merge(&self, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        if self.merge_operator.is_none() {
+            return Err(StorageError::Config("merge requires a merge operator".to_string()));
+        }
+        self.check_key_size(&key)?;
+        self.stall_if_needed().await;
+        
+        let write = self.begin_write(1)?;
+        let seq = write.first;
+        let kv_pair = KVPair::merge(key.clone(), operand, now_millis(), seq);
+        
+        self.wal.append(&kv_pair).await.map_err(|e| self.note_write_error(e))?;
+        
+        {
+            let mut memtable = self.active_memtable.write().await;
+            memtable.merge(key.clone(), kv_pair.value.unwrap(), seq);
+            self.invalidate_row(&key);
+            
+            if memtable.size() >= self.config.memtable_size_mb * 1024 * 1024 {
+                drop(memtable);
+                self.rotate_memtable().await?;
+            }
+        }
+        
+        Ok(())
+    }
+    
     pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let now = now_millis();
+        // Taken before the memtables are read, so a value a write replaces
+        // meanwhile is not cached
+        let epoch = self.row_cache.as_ref().map(RowCache::epoch);
+        // Operands of merges above the version found, newest first
+        let mut operands = Vec::new();
+        
         // Check active memtable first
         {
             let memtable = self.active_memtable.read().await;
-            if let Some(value) = memtable.get(key) {
-                return Ok(value);
+            if let Some(value) = memtable.entry(key).and_then(|e| e.read_through(now, &mut operands)) {
+                return self.apply_merges(value, operands);
             }
         }
         
         // Check immutable memtables
         {
             let immutable = self.immutable_memtables.lock();
-            for memtable in immutable.iter().rev() {
-                if let Some(value) = memtable.get(key) {
-                    return Ok(value);
+            for memtable in immutable.newest_first() {
+                if let Some(value) = memtable.entry(key).and_then(|e| e.read_through(now, &mut operands)) {
+                    return self.apply_merges(value, operands);
+                }
+            }
+        }
+        
+        let value = match (&self.row_cache, epoch) {
+            (Some(rows), Some(epoch)) => self.cached_sstable_get(rows, key, now, epoch).await?,
+            _ => self.sstable_get(key).await?,
+        };
+        self.apply_merges(value, operands)
+    }
+    
+    /// Values of `keys`, in the same order, as `get` would return them.
+    /// Each memtable and the SSTable levels are locked once for the whole
+    /// batch rather than once per key.
+    pub async fn multi_get(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        let now = now_millis();
+        let mut operands = vec![Vec::new(); keys.len()];
+        // The version each key's merges apply to, once found
+        let mut found: Vec<Option<Option<Vec<u8>>>> = vec![None; keys.len()];
+        
+        {
+            let memtable = self.active_memtable.read().await;
+            for (i, key) in keys.iter().enumerate() {
+                found[i] = memtable.entry(key).and_then(|e| e.read_through(now, &mut operands[i]));
+            }
+        }
+        
+        {
+            let immutable = self.immutable_memtables.lock();
+            for memtable in immutable.newest_first() {
+                for (i, key) in keys.iter().enumerate() {
+                    if found[i].is_none() {
+                        found[i] = memtable.entry(key).and_then(|e| e.read_through(now, &mut operands[i]));
+                    }
                 }
             }
         }
         
-        // Check SSTables from newest to oldest
         let levels = self.levels.read().await;
-        for level in levels.iter() {
+        let mut values = Vec::with_capacity(keys.len());
+        for ((key, found), operands) in keys.iter().zip(found).zip(operands) {
+            let value = match found {
+                Some(value) => value,
+                None => self.levels_get(&levels, key).await?,
+            };
+            values.push(self.apply_merges(value, operands)?);
+        }
+        Ok(values)
+    }
+    
+    /// The key's value in the SSTables, which hold no merge operands
+    async fn sstable_get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let levels = self.levels.read().await;
+        self.levels_get(&levels, key).await
+    }
+    
+    /// `sstable_get` through the row cache, caching the value found unless
+    /// a key was invalidated since `epoch`
+    async fn cached_sstable_get(&self, rows: &RowCache, key: &[u8], now: u64, epoch: u64) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = rows.get(key, now) {
+            return Ok(value);
+        }
+        let entry = {
+            let levels = self.levels.read().await;
+            let mut found = None;
+            for sstable in levels.iter().flat_map(|level| level.iter().rev()) {
+                found = sstable.entry(key, &self.cache).await?;
+                if found.is_some() {
+                    break;
+                }
+            }
+            found
+        };
+        let (value, expires_at) = entry.map_or((None, None), |entry| (entry.value, entry.expires_at));
+        rows.put(key.to_vec(), value.clone(), expires_at, epoch);
+        Ok(value.filter(|_| expires_at.is_none_or(|t| t > now)))
+    }
+    
+    /// Drop the key's row cache entry, once a write to it is in the memtable
+    fn invalidate_row(&self, key: &[u8]) {
+        if let Some(rows) = &self.row_cache {
+            rows.invalidate(key);
+        }
+    }
+    
+    async fn levels_get(&self, levels: &[Vec<Arc<SSTable>>], key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // Check SSTables from newest to oldest
+        for level in levels {
             for sstable in level.iter().rev() {
                 if let Some(value) = sstable.get(key, &self.cache).await? {
                     return Ok(value);
@@ -130,8 +539,23 @@ impl LSMTree {
         Ok(None)
     }
     
+    /// Fold merge `operands`, newest first, into `value`
+    fn apply_merges(&self, value: Option<Vec<u8>>, mut operands: Vec<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        if operands.is_empty() {
+            return Ok(value);
+        }
+        let operator = self.merge_operator.as_ref()
+            .ok_or_else(|| StorageError::Config("merge operands found but no merge operator is set".to_string()))?;
+        operands.reverse();
+        Ok(Some(operator.merge(value.as_deref(), &operands)))
+    }
+    
     pub async fn delete(&self, key: &[u8]) -> Result<()> {
-        let seq = self.sequence_number.fetch_add(1, Ordering::SeqCst);
+        self.check_key_size(key)?;
+        self.stall_if_needed().await;
+        
+        let write = self.begin_write(1)?;
+        let seq = write.first;
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
@@ -140,12 +564,13 @@ impl LSMTree {
         let kv_pair = KVPair::delete(key.to_vec(), timestamp, seq);
         
         // Write tombstone to WAL
-        self.wal.append(&kv_pair).await?;
+        self.wal.append(&kv_pair).await.map_err(|e| self.note_write_error(e))?;
         
         // Write tombstone to memtable
         {
             let mut memtable = self.active_memtable.write().await;
             memtable.delete(key.to_vec(), seq);
+            self.invalidate_row(key);
             
             if memtable.size() >= self.config.memtable_size_mb * 1024 * 1024 {
                 drop(memtable);
@@ -156,6 +581,916 @@ impl LSMTree {
         Ok(())
     }
     
+    /// Apply `ops` atomically: they are logged as one WAL record and become
+    /// visible to readers together
+    pub async fn write_batch(&self, ops: Vec<WriteOp>) -> Result<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        for op in &ops {
+            match op {
+                WriteOp::Put { key, .. } | WriteOp::Delete { key } => self.check_key_size(key)?,
+            }
+        }
+        self.stall_if_needed().await;
+        
+        let write = self.begin_write(ops.len() as u64)?;
+        let timestamp = now_millis();
+        let kv_pairs: Vec<KVPair> = ops.into_iter()
+            .zip(write.first..)
+            .map(|(op, seq)| match op {
+                WriteOp::Put { key, value } => KVPair::new(key, value, timestamp, seq),
+                WriteOp::Delete { key } => KVPair::delete(key, timestamp, seq),
+            })
+            .collect();
+        
+        self.apply_batch(kv_pairs).await
+    }
+    
+    /// Fail with `KeyTooLarge` if `key` is over the configured limit
+    fn check_key_size(&self, key: &[u8]) -> Result<()> {
+        if key.len() > self.config.max_key_size_bytes {
+            return Err(StorageError::KeyTooLarge { size: key.len(), limit: self.config.max_key_size_bytes });
+        }
+        Ok(())
+    }
+    
+    /// Reserve `count` consecutive sequence numbers for a write. Until the
+    /// returned guard is dropped, after the write reaches the memtable,
+    /// backups are pinned below them. Fails once the tree is closed or
+    /// read-only.
+    fn begin_write(&self, count: u64) -> Result<InFlightWrite<'_>> {
+        let mut in_flight = self.in_flight_writes.lock();
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(StorageError::Closed);
+        }
+        if self.read_only.load(Ordering::SeqCst) {
+            return Err(StorageError::OutOfSpace("the storage engine turned read-only after running out of disk space".to_string()));
+        }
+        let first = self.sequence_number.fetch_add(count, Ordering::SeqCst);
+        in_flight.insert(first);
+        Ok(InFlightWrite { tree: self, first })
+    }
+    
+    /// Turn read-only if `error` is `OutOfSpace`, so no later write is
+    /// logged after a record that may be torn. Returns `error`.
+    fn note_write_error(&self, error: StorageError) -> StorageError {
+        if matches!(error, StorageError::OutOfSpace(_)) && !self.read_only.swap(true, Ordering::SeqCst) {
+            tracing::error!("Storage engine is now read-only: {}", error);
+        }
+        error
+    }
+    
+    /// Whether a write ran out of disk space, so writes are refused until
+    /// the tree is reopened. Reads carry on as before.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+    
+    /// Log `kv_pairs` as one WAL record and apply them under a single
+    /// memtable lock
+    async fn apply_batch(&self, kv_pairs: Vec<KVPair>) -> Result<()> {
+        self.wal.append_batch(&kv_pairs).await.map_err(|e| self.note_write_error(e))?;
+        
+        let mut memtable = self.active_memtable.write().await;
+        for kv_pair in kv_pairs {
+            let key = kv_pair.key.clone();
+            memtable.apply(kv_pair);
+            self.invalidate_row(&key);
+        }
+        if memtable.size() >= self.config.memtable_size_mb * 1024 * 1024 {
+            drop(memtable);
+            self.rotate_memtable().await?;
+        }
+        
+        Ok(())
+    }
+    
+    /// Live entries with `start <= key < end` in key order, at most `limit` of them.
+    /// Callers page through larger ranges by resuming just after the last key returned.
+    pub async fn scan(&self, start: &[u8], end: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut results = Vec::new();
+        let mut cursor = start.to_vec();
+
+        while results.len() < limit {
+            let (entries, next) = self.scan_page(&cursor, end, limit - results.len(), Direction::Forward).await?;
+            results.extend(entries);
+            match next {
+                Some(next) => cursor = next,
+                None => break,
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Like `scan`, in descending key order, for reading the last keys of a
+    /// range without the ones before them. Callers page through larger
+    /// ranges by resuming with the last key returned as `end`.
+    pub async fn scan_rev(&self, start: &[u8], end: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut results = Vec::new();
+        let mut cursor = end.to_vec();
+
+        while results.len() < limit {
+            let (entries, next) = self.scan_page(start, &cursor, limit - results.len(), Direction::Reverse).await?;
+            results.extend(entries);
+            match next {
+                Some(next) => cursor = next,
+                None => break,
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// One merge pass over every source, in `direction`. Each source contributes
+    /// at most `limit` entries, so the merged view is only complete up to the
+    /// nearest last key of any source that hit the limit; the returned cursor
+    /// resumes after it, as the start going forward or the end in reverse.
+    async fn scan_page(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        limit: usize,
+        direction: Direction,
+    ) -> Result<(Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>)> {
+        let mut merged: BTreeMap<Vec<u8>, ScanVersion> = BTreeMap::new();
+        let mut complete_until: Option<Vec<u8>> = None;
+
+        let mut absorb = |entries: Vec<(Vec<u8>, ScanVersion)>| {
+            if entries.len() == limit {
+                let last = &entries[entries.len() - 1].0;
+                if complete_until.as_ref().is_none_or(|bound| direction.precedes(last, bound)) {
+                    complete_until = Some(last.clone());
+                }
+            }
+            for (key, version) in entries {
+                match merged.get(&key) {
+                    Some((existing, _, _)) if *existing > version.0 => {}
+                    _ => {
+                        merged.insert(key, version);
+                    }
+                }
+            }
+        };
+
+        let now = now_millis();
+        let memtable_entries = |memtable: &MemTable| {
+            let version = |(key, entry): (&Vec<u8>, &MemTableEntry)| {
+                (key.clone(), (entry.sequence, entry.live_value(now), entry.merge.is_some()))
+            };
+            let range = memtable.range(start, end);
+            match direction {
+                Direction::Forward => range.take(limit).map(version).collect::<Vec<_>>(),
+                Direction::Reverse => range.rev().take(limit).map(version).collect::<Vec<_>>(),
+            }
+        };
+
+        absorb(memtable_entries(&*self.active_memtable.read().await));
+        let immutable: Vec<_> = self.immutable_memtables.lock().newest_first().cloned().collect();
+        for memtable in immutable {
+            absorb(memtable_entries(&memtable));
+        }
+
+        let sstables: Vec<Arc<SSTable>> = self.levels.read().await.iter().flatten().cloned().collect();
+        for sstable in sstables {
+            let entries = match direction {
+                Direction::Forward => sstable.scan(start, end, limit, &self.cache).await?,
+                Direction::Reverse => sstable.scan_rev(start, end, limit, &self.cache).await?,
+            };
+            absorb(entries.into_iter().map(|(key, value, sequence)| (key, (sequence, value, false))).collect());
+        }
+
+        let mut merged: Vec<(Vec<u8>, ScanVersion)> = merged.into_iter().collect();
+        if direction == Direction::Reverse {
+            merged.reverse();
+        }
+        let mut results = Vec::new();
+        for (key, (_, value, merges)) in merged {
+            if complete_until.as_ref().is_some_and(|bound| direction.precedes(bound, &key)) {
+                break;
+            }
+            // Rare enough to fold with a point lookup
+            let value = if merges { self.get(&key).await? } else { value };
+            if let Some(value) = value {
+                results.push((key, value));
+            }
+        }
+
+        if results.len() > limit {
+            results.truncate(limit);
+            let next = direction.resume_after(results[limit - 1].0.clone());
+            return Ok((results, Some(next)));
+        }
+
+        Ok((results, complete_until.map(|bound| direction.resume_after(bound))))
+    }
+
+    /// Stream every write as it is logged, for change-data-capture. With
+    /// `from`, writes already logged with a sequence of at least `from` come
+    /// first. Writes arrive in log order, so the sequences of concurrent
+    /// writers may interleave.
+    pub async fn changefeed(&self, from: Option<u64>) -> Result<Changefeed> {
+        self.wal.subscribe(from).await
+    }
+    
+    /// Reports why writes are being stalled, if they are
+    pub async fn write_stall_reason(&self) -> Option<StallReason> {
+        let immutable = self.immutable_memtables.lock().len();
+        if immutable >= self.config.max_immutable_memtables {
+            return Some(StallReason::TooManyImmutableMemtables {
+                count: immutable,
+                limit: self.config.max_immutable_memtables,
+            });
+        }
+        
+        let l0_files = self.levels.read().await[0].len();
+        if l0_files >= self.config.l0_stall_trigger {
+            return Some(StallReason::TooManyL0Files {
+                count: l0_files,
+                limit: self.config.l0_stall_trigger,
+            });
+        }
+        
+        None
+    }
+    
+    pub fn config(&self) -> &StorageConfig {
+        &self.config
+    }
+    
+    /// Total time writes have spent stalled since the tree was opened
+    pub fn total_stall_time(&self) -> Duration {
+        Duration::from_micros(self.stall_micros.load(Ordering::Relaxed))
+    }
+    
+    pub async fn stats(&self) -> LSMStats {
+        let (memtable_size, active_entries) = {
+            let memtable = self.active_memtable.read().await;
+            (memtable.size(), memtable.len())
+        };
+        let (immutable_memtables, immutable_entries) = {
+            let immutable = self.immutable_memtables.lock();
+            (immutable.len(), immutable.newest_first().map(|m| m.len()).sum::<usize>())
+        };
+        let (level_file_counts, sstable_entries, sstable_bytes) = {
+            let levels = self.levels.read().await;
+            let counts = levels.iter().map(|l| l.len()).collect();
+            let entries = levels.iter().flatten().map(|t| t.num_entries()).sum();
+            let bytes = levels.iter().flatten().map(|t| t.file_size()).sum();
+            (counts, entries, bytes)
+        };
+        
+        LSMStats {
+            memtable_size,
+            immutable_memtables,
+            memtable_entries: (active_entries + immutable_entries) as u64,
+            level_file_counts,
+            sstable_bytes,
+            write_stall_reason: self.write_stall_reason().await,
+            write_stall_count: self.stall_count.load(Ordering::Relaxed),
+            write_stall_micros: self.stall_micros.load(Ordering::Relaxed),
+            sstable_entries,
+            expired_entries_swept: self.expired_swept.load(Ordering::Relaxed),
+            wal_entries_replayed: self.wal_entries_replayed.load(Ordering::Relaxed),
+            cache: self.cache.stats(),
+            row_cache: self.row_cache.as_ref().map(RowCache::stats),
+        }
+    }
+    
+    /// Start a background task that calls `sweep_expired` every
+    /// `ttl_sweep_interval_ms`. The task exits once the tree is dropped.
+    pub fn spawn_ttl_sweeper(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        if self.config.ttl_sweep_interval_ms == 0 {
+            return None;
+        }
+        
+        let interval = Duration::from_millis(self.config.ttl_sweep_interval_ms);
+        let tree: Weak<Self> = Arc::downgrade(self);
+        Some(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let Some(tree) = tree.upgrade() else {
+                    break;
+                };
+                match tree.sweep_expired().await {
+                    Ok(0) => {}
+                    Ok(swept) => tracing::debug!("TTL sweep removed {} expired entries", swept),
+                    Err(e) => tracing::warn!("TTL sweep failed: {}", e),
+                }
+            }
+        }))
+    }
+    
+    /// Start a background task that flushes the memtable once its oldest
+    /// entry is `memtable_max_age_ms` old. The task exits once the tree is
+    /// dropped.
+    pub fn spawn_memtable_flusher(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        if self.config.memtable_max_age_ms == 0 {
+            return None;
+        }
+        
+        let max_age = Duration::from_millis(self.config.memtable_max_age_ms);
+        // Checked a few times per max age, so a memtable outlives it by little
+        let period = (max_age / 4).max(Duration::from_millis(10));
+        let tree: Weak<Self> = Arc::downgrade(self);
+        Some(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(period).await;
+                let Some(tree) = tree.upgrade() else {
+                    break;
+                };
+                if tree.closed.load(Ordering::SeqCst) || tree.is_read_only() {
+                    continue;
+                }
+                let age = tree.active_memtable.read().await.age();
+                if age.is_some_and(|age| age >= max_age) {
+                    tracing::debug!("Flushing a memtable {:?} old", age.unwrap());
+                    if let Err(e) = tree.flush().await {
+                        tracing::warn!("Timed memtable flush failed: {}", e);
+                    }
+                }
+            }
+        }))
+    }
+    
+    /// Physically remove expired entries from the active memtable and from
+    /// every SSTable holding one, without waiting for compaction to reach
+    /// those files. Returns the number of entries removed.
+    pub async fn sweep_expired(&self) -> Result<u64> {
+        let _maintenance = self.maintenance_lock.lock().await;
+        if self.closed.load(Ordering::SeqCst) {
+            return Ok(0);
+        }
+        let now = now_millis();
+        
+        let mut swept = self.active_memtable.write().await.expire(now) as u64;
+        
+        let snapshot: Vec<Vec<Arc<SSTable>>> = self.levels.read().await.clone();
+        for (level, tables) in snapshot.iter().enumerate() {
+            for (position, table) in tables.iter().enumerate() {
+                if table.earliest_expiry().is_none_or(|t| t > now) {
+                    continue;
+                }
+                
+                // Files that may hold older versions of this file's keys: earlier
+                // files in the same level and everything in deeper levels
+                let older: Vec<Arc<SSTable>> = tables[..position].iter()
+                    .chain(snapshot[level + 1..].iter().flatten())
+                    .cloned()
+                    .collect();
+                
+                let (replacement, removed) = self.rewrite_without_expired(table, &older, now).await?;
+                self.replace_sstable(level, table, replacement).await;
+                swept += removed;
+            }
+        }
+        
+        self.expired_swept.fetch_add(swept, Ordering::Relaxed);
+        Ok(swept)
+    }
+    
+    /// Merge every SSTable into one in the last level, keeping only the
+    /// latest version of each key. Deletions and expired entries have no
+    /// older versions left to hide and are dropped, and the compaction
+    /// filter, if any, decides what becomes of the rest.
+    pub async fn compact(&self) -> Result<CompactionSummary> {
+        let _maintenance = self.maintenance_lock.lock().await;
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(StorageError::Closed);
+        }
+        let inputs: Vec<Arc<SSTable>> = self.levels.read().await.iter().flatten().cloned().collect();
+        self.merge_sstables(inputs).await
+    }
+    
+    /// Like `compact`, for the SSTables with keys from `start` up to `end`
+    /// (exclusive, or unbounded if None). SSTables whose key ranges overlap
+    /// theirs are merged too, so that no file left out holds a version of
+    /// a key the merge rewrites.
+    pub async fn compact_range(&self, start: &[u8], end: Option<&[u8]>) -> Result<CompactionSummary> {
+        let _maintenance = self.maintenance_lock.lock().await;
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(StorageError::Closed);
+        }
+        let tables: Vec<Arc<SSTable>> = self.levels.read().await.iter().flatten().cloned().collect();
+        let inputs: Vec<Arc<SSTable>> = tables.iter()
+            .filter(|table| {
+                key_bounds(table).is_some_and(|(first, last)| {
+                    end.is_none_or(|end| first.as_slice() < end) && last.is_none_or(|last| start <= last.as_slice())
+                })
+            })
+            .cloned()
+            .collect();
+        let inputs = with_overlapping(&tables, inputs);
+        self.merge_sstables(inputs).await
+    }
+    
+    /// Whether L0 holds `l0_compaction_trigger` files or more, or
+    /// `l0_compaction_bytes` bytes or more, whichever comes first
+    async fn l0_needs_compaction(&self) -> bool {
+        let levels = self.levels.read().await;
+        let bytes: u64 = levels[0].iter().map(|table| table.file_size()).sum();
+        levels[0].len() >= self.config.l0_compaction_trigger || bytes >= self.config.l0_compaction_bytes
+    }
+    
+    /// Merge the L0 files, and every deeper file overlapping them, into the
+    /// last level. The caller holds the maintenance lock.
+    async fn compact_l0(&self) -> Result<CompactionSummary> {
+        let (tables, l0) = {
+            let levels = self.levels.read().await;
+            (levels.iter().flatten().cloned().collect::<Vec<_>>(), levels[0].clone())
+        };
+        self.merge_sstables(with_overlapping(&tables, l0)).await
+    }
+    
+    /// Merge `inputs` into one SSTable in the last level. No other SSTable
+    /// may hold any of their keys.
+    async fn merge_sstables(&self, inputs: Vec<Arc<SSTable>>) -> Result<CompactionSummary> {
+        if inputs.is_empty() {
+            return Ok(CompactionSummary::default());
+        }
+        
+        let mut latest: BTreeMap<Vec<u8>, BlockEntry> = BTreeMap::new();
+        for table in &inputs {
+            for entry in table.entries(&self.cache).await? {
+                match latest.get(&entry.key) {
+                    Some(newer) if newer.sequence > entry.sequence => {}
+                    _ => {
+                        latest.insert(entry.key.clone(), entry);
+                    }
+                }
+            }
+        }
+        
+        let now = now_millis();
+        let mut output: Option<SSTableBuilder> = None;
+        for (key, entry) in latest {
+            let Some(value) = entry.value.as_ref().filter(|_| !entry.is_expired(now)) else {
+                continue;
+            };
+            let value = match self.compaction_filter.as_ref().map(|filter| filter.filter(&key, value)) {
+                None | Some(Decision::Keep) => entry.value,
+                Some(Decision::Remove) => continue,
+                Some(Decision::Change(value)) => Some(value),
+            };
+            
+            let builder = match &mut output {
+                Some(builder) => builder,
+                None => {
+                    let file_number = self.sequence_number.fetch_add(1, Ordering::SeqCst);
+                    let file_path = Path::new(&self.config.data_dir).join(format!("{}.sst", file_number));
+                    let builder = SSTableBuilder::new(file_path, self.config.compression.clone())
+                        .await?
+                        .compression_threshold(self.config.compression_threshold)
+                        .mmap_reads(self.config.mmap_reads);
+                    output.insert(builder)
+                }
+            };
+            builder.add_with_expiry(&key, &value, entry.sequence, entry.expires_at).await?;
+        }
+        let output = match output {
+            Some(builder) => Some(Arc::new(builder.finish().await?)),
+            None => None,
+        };
+        let summary = CompactionSummary {
+            input_files: inputs.len(),
+            input_bytes: inputs.iter().map(|table| table.file_size()).sum(),
+            output_files: output.iter().count(),
+            output_bytes: output.iter().map(|table| table.file_size()).sum(),
+        };
+        
+        // Files flushed meanwhile are newer than the output and stay in L0
+        {
+            let mut levels = self.levels.write().await;
+            for level in levels.iter_mut() {
+                level.retain(|table| !inputs.iter().any(|input| Arc::ptr_eq(input, table)));
+            }
+            if let Some(output) = output {
+                levels.last_mut().expect("at least one level").push(output);
+            }
+        }
+        // The filter may have changed values the row cache holds
+        if let (Some(rows), Some(_)) = (&self.row_cache, &self.compaction_filter) {
+            rows.clear();
+        }
+        for table in &inputs {
+            if let Err(e) = std::fs::remove_file(table.path()) {
+                tracing::warn!("Failed to remove compacted SSTable {}: {}", table.path().display(), e);
+            }
+        }
+        
+        tracing::info!("Compacted {} SSTables", inputs.len());
+        Ok(summary)
+    }
+    
+    /// Copy `table` minus its expired entries. An expired entry becomes a
+    /// tombstone instead of vanishing if an older file still has the key,
+    /// since dropping it outright would resurrect the older value. Files
+    /// whose entries are all newer than it cannot hold such a value.
+    async fn rewrite_without_expired(
+        &self,
+        table: &SSTable,
+        older: &[Arc<SSTable>],
+        now: u64,
+    ) -> Result<(Option<Arc<SSTable>>, u64)> {
+        let mut kept = Vec::new();
+        let mut removed = 0;
+        
+        for mut entry in table.entries(&self.cache).await? {
+            if !entry.is_expired(now) {
+                kept.push(entry);
+                continue;
+            }
+            
+            removed += 1;
+            let mut shadows_older = false;
+            for candidate in older {
+                let in_range = candidate.key_range()
+                    .is_some_and(|(first, _)| first <= entry.key.as_slice());
+                let has_older = candidate.min_sequence().is_none_or(|oldest| oldest < entry.sequence);
+                if in_range && has_older && candidate.get(&entry.key, &self.cache).await?.is_some() {
+                    shadows_older = true;
+                    break;
+                }
+            }
+            if shadows_older {
+                entry.value = None;
+                entry.expires_at = None;
+                kept.push(entry);
+            }
+        }
+        
+        if kept.is_empty() {
+            return Ok((None, removed));
+        }
+        
+        let file_number = self.sequence_number.fetch_add(1, Ordering::SeqCst);
+        let file_path = Path::new(&self.config.data_dir).join(format!("{}.sst", file_number));
+        let mut builder = SSTableBuilder::new(file_path, self.config.compression.clone())
+            .await?
+            .compression_threshold(self.config.compression_threshold)
+            .mmap_reads(self.config.mmap_reads);
+        for entry in &kept {
+            builder.add_with_expiry(&entry.key, &entry.value, entry.sequence, entry.expires_at).await?;
+        }
+        
+        Ok((Some(Arc::new(builder.finish().await?)), removed))
+    }
+    
+    /// Swap `old` for `replacement` in `level` and delete the old file
+    async fn replace_sstable(&self, level: usize, old: &Arc<SSTable>, replacement: Option<Arc<SSTable>>) {
+        let swapped = {
+            let mut levels = self.levels.write().await;
+            match levels[level].iter().position(|t| Arc::ptr_eq(t, old)) {
+                Some(position) => {
+                    match &replacement {
+                        Some(table) => levels[level][position] = table.clone(),
+                        None => {
+                            levels[level].remove(position);
+                        }
+                    }
+                    true
+                }
+                None => false,
+            }
+        };
+        
+        // If the file disappeared meanwhile, the rewrite is the one to discard
+        let obsolete = if swapped { Some(old.path()) } else { replacement.as_ref().map(|t| t.path()) };
+        if let Some(path) = obsolete {
+            if let Err(e) = std::fs::remove_file(path) {
+                tracing::warn!("Failed to remove obsolete SSTable {}: {}", path.display(), e);
+            }
+        }
+    }
+    
+    /// Slow the caller down while the engine is behind on flushing/compaction
+    async fn stall_if_needed(&self) {
+        let Some(reason) = self.write_stall_reason().await else {
+            return;
+        };
+        
+        tracing::debug!("Stalling write: {}", reason);
+        let started = Instant::now();
+        tokio::time::sleep(Duration::from_millis(self.config.write_stall_delay_ms)).await;
+        
+        self.stall_count.fetch_add(1, Ordering::Relaxed);
+        self.stall_micros.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+    
+    /// Stream a consistent backup of every live key to `writer`. Writes may
+    /// continue meanwhile; the backup holds exactly the writes acknowledged
+    /// before it started. See `crate::backup` for the archive format.
+    pub async fn export_backup<W: AsyncWrite + Unpin>(&self, writer: W) -> Result<BackupInfo> {
+        self.export(writer, None).await
+    }
+    
+    /// Stream a differential backup to `writer`: every key written or
+    /// deleted since the backup of this store whose sequence number is
+    /// `since`. SSTables older than that are not read.
+    pub async fn export_differential_backup<W: AsyncWrite + Unpin>(&self, writer: W, since: u64) -> Result<BackupInfo> {
+        self.export(writer, Some(since)).await
+    }
+    
+    async fn export<W: AsyncWrite + Unpin>(&self, writer: W, since: Option<u64>) -> Result<BackupInfo> {
+        // Rewrites would delete SSTables the pinned view still reads from
+        let _maintenance = self.maintenance_lock.lock().await;
+        let view = self.pin_view(since.unwrap_or(0)).await;
+        
+        let mut backup = BackupWriter::start(writer, view.sequence, since).await?;
+        let now = now_millis();
+        let mut cursor = Vec::new();
+        loop {
+            let (entries, next) = view.page(&cursor, &self.cache).await?;
+            for (key, entry) in entries {
+                let value = match entry.merge {
+                    Some(_) => {
+                        let (value, operands) = view.lookup(&key, now, &self.cache).await?;
+                        self.apply_merges(value, operands)?
+                    }
+                    None => entry.live_value(now),
+                };
+                let include = match since {
+                    // Expired entries go out as deletions too, in case they
+                    // replaced a value the earlier backup holds
+                    Some(since) => entry.sequence >= since,
+                    None => value.is_some(),
+                };
+                if include {
+                    let expires_at = value.as_ref().and(entry.expires_at);
+                    backup.add(BackupEntry { key, value, expires_at }).await?;
+                }
+            }
+            match next {
+                Some(next) => cursor = next,
+                None => break,
+            }
+        }
+        
+        let entries = backup.finish().await?;
+        Ok(BackupInfo { since, sequence: view.sequence, entries })
+    }
+    
+    /// Build a new store in the empty directories of `config` from an
+    /// archive written by `export_backup`, possibly on another machine. If
+    /// this fails the directories hold a partial store and should be removed.
+    pub async fn import_backup<R: AsyncRead + Unpin>(config: StorageConfig, reader: R) -> Result<(Self, BackupInfo)> {
+        let (lsm, mut imported) = Self::import_backup_chain(config, vec![reader]).await?;
+        Ok((lsm, imported.remove(0)))
+    }
+    
+    /// Like `import_backup`, for a full backup followed by differential
+    /// backups of the same store, applied in order. The chain is checked
+    /// before anything is written.
+    pub async fn import_backup_chain<R: AsyncRead + Unpin>(
+        config: StorageConfig,
+        readers: Vec<R>,
+    ) -> Result<(Self, Vec<BackupInfo>)> {
+        for dir in [&config.data_dir, &config.wal_dir] {
+            let occupied = std::fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some());
+            if occupied {
+                return Err(StorageError::Config(format!("cannot import a backup into non-empty directory {}", dir)));
+            }
+        }
+        
+        let mut backups = Vec::with_capacity(readers.len());
+        let mut previous: Option<BackupInfo> = None;
+        for reader in readers {
+            let backup = BackupReader::open(reader).await?;
+            let info = BackupInfo { since: backup.since(), sequence: backup.sequence(), entries: 0 };
+            backup::check_chain(previous.as_ref(), &info)?;
+            previous = Some(info);
+            backups.push(backup);
+        }
+        if backups.is_empty() {
+            return Err(StorageError::Config("no backups to import".to_string()));
+        }
+        
+        let lsm = Self::open(config).await?;
+        let mut imported = Vec::with_capacity(backups.len());
+        for mut backup in backups {
+            let mut entries = 0;
+            while let Some(chunk) = backup.next_chunk().await? {
+                let first = lsm.sequence_number.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+                let timestamp = now_millis();
+                entries += chunk.len() as u64;
+                let kv_pairs = chunk.into_iter()
+                    .zip(first..)
+                    .map(|(entry, seq)| match entry.value {
+                        Some(value) => KVPair {
+                            expires_at: entry.expires_at,
+                            ..KVPair::new(entry.key, value, timestamp, seq)
+                        },
+                        None => KVPair::delete(entry.key, timestamp, seq),
+                    })
+                    .collect();
+                lsm.apply_batch(kv_pairs).await?;
+            }
+            imported.push(BackupInfo { since: backup.since(), sequence: backup.sequence(), entries });
+        }
+        lsm.flush().await?;
+        
+        Ok((lsm, imported))
+    }
+    
+    /// Import the archives listed in the manifest at `path`, which must match
+    /// what the manifest recorded for them
+    pub async fn restore_backup(config: StorageConfig, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let manifest = BackupManifest::load(path).await?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        
+        let mut readers = Vec::with_capacity(manifest.archives.len());
+        for archive in &manifest.archives {
+            let file = tokio::fs::File::open(dir.join(&archive.file)).await?;
+            readers.push(tokio::io::BufReader::new(file));
+        }
+        
+        let (lsm, imported) = Self::import_backup_chain(config, readers).await?;
+        for (archive, info) in manifest.archives.iter().zip(&imported) {
+            if archive.info != *info {
+                return Err(StorageError::Corruption(format!(
+                    "backup {} does not match its manifest entry", archive.file
+                )));
+            }
+        }
+        Ok(lsm)
+    }
+    
+    /// Freeze the current contents of the tree. Holding the active memtable
+    /// exclusively keeps writes and rotations out while the sources are
+    /// collected; memtables and SSTables never change once immutable.
+    /// Every write numbered below the view's sequence number is in it, since
+    /// writes still in flight hold it back. SSTables with nothing newer than
+    /// `since` are left out.
+    async fn pin_view(&self, since: u64) -> PinnedView {
+        let active = self.active_memtable.write().await;
+        let sequence = {
+            let in_flight = self.in_flight_writes.lock();
+            in_flight.first().copied().unwrap_or_else(|| self.sequence_number.load(Ordering::SeqCst))
+        };
+        let frozen = active.iter().map(|(key, entry)| (key.clone(), entry.clone())).collect();
+        // Immutable memtables before levels: a memtable being flushed leaves
+        // the list only after its SSTable is added, so it is seen at least once
+        let immutable = self.immutable_memtables.lock().newest_first().cloned().collect();
+        let (sstables, lookup_sstables) = {
+            let levels = self.levels.read().await;
+            let sstables = levels.iter().flatten()
+                .filter(|table| table.max_sequence().is_none_or(|newest| newest >= since))
+                .cloned()
+                .collect();
+            (sstables, levels.iter().flat_map(|level| level.iter().rev()).cloned().collect())
+        };
+        drop(active);
+        
+        PinnedView { sequence, active: frozen, immutable, sstables, lookup_sstables }
+    }
+    
+    /// Shut the tree down cleanly. Later writes fail with `Closed`; writes
+    /// already under way finish first. Memtables are then flushed, the WAL
+    /// is synced, and the SSTables are recorded so that the next `open`
+    /// loads them instead of replaying the WAL. Reads keep working, except
+    /// in an ephemeral store, whose directories are deleted instead.
+    pub async fn close(&self) -> Result<()> {
+        {
+            // Under the lock `begin_write` checks the flag with
+            let _in_flight = self.in_flight_writes.lock();
+            if self.closed.swap(true, Ordering::SeqCst) {
+                return Ok(());
+            }
+        }
+        while !self.in_flight_writes.lock().is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        
+        let _maintenance = self.maintenance_lock.lock().await;
+        if self.config.ephemeral {
+            for dir in &self.created_dirs {
+                match tokio::fs::remove_dir_all(dir).await {
+                    Ok(()) => {}
+                    // The WAL directory may have been inside the data directory
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            tracing::info!("Storage closed and its directories deleted");
+            return Ok(());
+        }
+        self.flush().await?;
+        self.wal.sync().await?;
+        if self.config.durability == Durability::Memory {
+            return Ok(());
+        }
+        
+        let marker = CleanShutdown::new(&self.levels.read().await, self.sequence_number.load(Ordering::SeqCst));
+        marker.write(Path::new(&self.config.data_dir)).await?;
+        
+        tracing::info!("Storage closed cleanly at sequence {}", marker.sequence);
+        Ok(())
+    }
+    
+    /// Load the SSTables recorded by `close`, if it left a marker and `load`
+    /// is set. The marker is removed either way, so a crash from here on
+    /// recovers from the WAL, which still holds every write.
+    async fn reopen_after_close(&self, load: bool) -> Result<bool> {
+        let dir = Path::new(&self.config.data_dir);
+        let path = dir.join(CLEAN_SHUTDOWN_FILE);
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        
+        if load {
+            let marker: CleanShutdown = serde_json::from_slice(&bytes)
+                .map_err(|e| StorageError::Corruption(format!("Invalid clean shutdown marker: {}", e)))?;
+            let mut levels = self.levels.write().await;
+            for (level, files) in marker.levels.iter().enumerate() {
+                // A tree reopened with fewer levels keeps the deeper tables in its last
+                let level = level.min(levels.len() - 1);
+                for file in files {
+                    let sstable = match SSTable::open_with_mmap(dir.join(file), self.config.mmap_reads).await {
+                        Ok(sstable) => sstable,
+                        Err(e) if self.config.skip_corrupt_sstables && is_damage(&e) => {
+                            tracing::error!("Skipping damaged SSTable {} in level {}: {}", file, level, e);
+                            let error = e.to_string();
+                            self.skipped_sstables.lock().push(SkippedSSTable { file: file.clone(), level, error });
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    levels[level].push(Arc::new(sstable));
+                }
+            }
+            self.sequence_number.store(marker.sequence, Ordering::SeqCst);
+        }
+        
+        tokio::fs::remove_file(&path).await?;
+        sync_dir(dir)?;
+        Ok(load)
+    }
+    
+    /// SSTables `open` left out as damaged. They stay on disk for
+    /// inspection, but the tree no longer refers to them once closed, and
+    /// reads miss what they hold.
+    pub fn skipped_sstables(&self) -> Vec<SkippedSSTable> {
+        self.skipped_sstables.lock().clone()
+    }
+    
+    /// Write a copy of the tree to `dir`, which must not exist yet, that
+    /// `open` loads as it would after `close` when given `dir` as its data
+    /// directory and an empty WAL directory. Memtables are flushed first,
+    /// so the copy holds every write acknowledged before the call.
+    /// SSTables are hard-linked where the file system allows and copied
+    /// otherwise.
+    pub async fn checkpoint(&self, dir: impl AsRef<Path>) -> Result<CheckpointInfo> {
+        let dir = dir.as_ref();
+        // Keeps compactions from removing files before they are linked
+        let _maintenance = self.maintenance_lock.lock().await;
+        self.flush().await?;
+        tokio::fs::create_dir(dir).await?;
+        
+        let levels = self.levels.read().await.clone();
+        let mut info = CheckpointInfo { files: 0, bytes: 0, sequence: self.sequence_number.load(Ordering::SeqCst) };
+        for table in levels.iter().flatten() {
+            let target = dir.join(file_name(table));
+            if std::fs::hard_link(table.path(), &target).is_err() {
+                tokio::fs::copy(table.path(), &target).await?;
+            }
+            info.files += 1;
+            info.bytes += table.file_size();
+        }
+        CleanShutdown::new(&levels, info.sequence).write(dir).await?;
+        
+        tracing::info!("Checkpointed {} SSTables to {}", info.files, dir.display());
+        Ok(info)
+    }
+    
+    /// Every SSTable by level, oldest first within a level
+    pub async fn sstables(&self) -> Vec<Vec<SSTableInfo>> {
+        self.levels.read().await.iter()
+            .map(|level| level.iter()
+                .map(|table| SSTableInfo {
+                    file: file_name(table),
+                    size_bytes: table.file_size(),
+                    entries: table.num_entries(),
+                    smallest_key: table.key_range().map(|(first, _)| first.to_vec()),
+                    largest_key: table.largest_key().map(<[u8]>::to_vec),
+                })
+                .collect())
+            .collect()
+    }
+    
+    /// Resize the block cache to `capacity_bytes`, evicting blocks over it
+    pub fn resize_cache(&self, capacity_bytes: usize) {
+        self.cache.resize(capacity_bytes);
+    }
+    
+    /// Sync the WAL to disk, for writes made without `Durability::Full`
+    pub async fn sync_wal(&self) -> Result<()> {
+        self.wal.sync().await
+    }
+    
     pub async fn flush(&self) -> Result<()> {
         self.rotate_memtable().await?;
         self.flush_immutable_memtables().await?;
@@ -163,28 +1498,27 @@ impl LSMTree {
     }
     
     async fn rotate_memtable(&self) -> Result<()> {
-        let old_memtable;
         {
+            // The old memtable joins the immutable list before the lock is
+            // released, so its entries are always visible in one place
             let mut active = self.active_memtable.write().await;
-            old_memtable = std::mem::replace(&mut *active, MemTable::new());
-        }
-        
-        if !old_memtable.is_empty() {
-            let old_memtable = Arc::new(old_memtable);
-            self.immutable_memtables.lock().push(old_memtable);
+            if !active.is_empty() {
+                let old_memtable = Arc::new(std::mem::take(&mut *active));
+                self.immutable_memtables.lock().push(old_memtable);
+            }
         }
         
         self.flush_immutable_memtables().await
     }
     
     async fn flush_immutable_memtables(&self) -> Result<()> {
-        let memtables_to_flush = {
-            let mut immutable = self.immutable_memtables.lock();
-            std::mem::take(&mut *immutable)
-        };
+        let _flushing = self.flush_lock.lock().await;
+        let memtables_to_flush = self.immutable_memtables.lock().oldest_first();
         
-        for memtable in memtables_to_flush {
-            self.flush_memtable_to_l0(memtable).await?;
+        for (id, memtable) in memtables_to_flush {
+            self.flush_memtable_to_l0(memtable).await.map_err(|e| self.note_write_error(e))?;
+            // Dropped only once its SSTable is in L0, for the same reason
+            self.immutable_memtables.lock().remove(id);
         }
         
         Ok(())
@@ -199,16 +1533,14 @@ impl LSMTree {
         let file_path = Path::new(&self.config.data_dir)
             .join(format!("{}.sst", file_number));
         
-        let mut builder = SSTableBuilder::new(
-            file_path,
-            self.config.compression.clone(),
-        ).await?;
-        
-        for (key, entry) in memtable.iter() {
-            builder.add(key, &entry.value, entry.sequence)?;
-        }
-        
-        let sstable = builder.finish().await?;
+        let sstable = match self.write_sstable(&file_path, &memtable).await {
+            Ok(sstable) => sstable,
+            Err(e) => {
+                // Nothing refers to the partly written file
+                let _ = tokio::fs::remove_file(&file_path).await;
+                return Err(e);
+            }
+        };
         
         // Add to level 0
         {
@@ -216,36 +1548,199 @@ impl LSMTree {
             levels[0].push(Arc::new(sstable));
         }
         
-        // Check if L0 compaction is needed
-        {
-            let levels = self.levels.read().await;
-            if levels[0].len() >= self.config.l0_compaction_trigger {
-                // Schedule compaction (simplified for now)
+        // Close, checkpoints and backups flush while holding the maintenance
+        // lock. Compaction then waits for the next flush to check again.
+        if self.l0_needs_compaction().await && !self.closed.load(Ordering::SeqCst) && !self.is_read_only() {
+            if let Ok(_maintenance) = self.maintenance_lock.try_lock() {
                 tracing::info!("L0 compaction triggered");
+                if let Err(e) = self.compact_l0().await {
+                    tracing::warn!("L0 compaction failed: {}", e);
+                }
             }
         }
         
         Ok(())
     }
     
-    async fn recover_from_wal(&self) -> Result<()> {
-        let entries = self.wal.recover().await?;
+    /// Write `memtable` out as the SSTable at `file_path`
+    async fn write_sstable(&self, file_path: &Path, memtable: &MemTable) -> Result<SSTable> {
+        let mut builder = SSTableBuilder::new(
+            file_path,
+            self.config.compression.clone(),
+        ).await?
+            .compression_threshold(self.config.compression_threshold)
+            .mmap_reads(self.config.mmap_reads);
+        
+        // SSTables hold folded values only. Older memtables are flushed
+        // first, so the values merges apply to are already in SSTables.
+        let now = now_millis();
+        for (key, entry) in memtable.iter() {
+            let value = match &entry.merge {
+                Some(_) => {
+                    let mut operands = Vec::new();
+                    let value = match entry.read_through(now, &mut operands) {
+                        Some(value) => value,
+                        None => self.sstable_get(key).await?,
+                    };
+                    self.apply_merges(value, operands)?
+                }
+                None => entry.value.clone(),
+            };
+            builder.add_with_expiry(key, &value, entry.sequence, entry.expires_at).await?;
+        }
+        
+        builder.finish().await
+    }
+    
+    async fn recover_from_wal(&self, until: Option<u64>) -> Result<()> {
+        let mut entries = self.wal.recover().await?;
+        if let Some(until) = until {
+            // Writes are logged roughly, not strictly, in timestamp order, so
+            // entries are filtered rather than cut at the first later one.
+            // A batch shares one timestamp and is kept or dropped whole.
+            let total = entries.len();
+            entries.retain(|entry| entry.timestamp <= until);
+            if entries.len() < total {
+                tracing::info!("Point-in-time recovery to {} drops {} WAL entries", until, total - entries.len());
+                self.wal.replace(&entries, &format!("wal-{}-{}.log", until, now_millis())).await?;
+            }
+        }
         
         let mut memtable = self.active_memtable.write().await;
+        let mut replayed = 0;
         for entry in entries {
-            if let Some(value) = entry.value {
-                memtable.put(entry.key, value, entry.sequence);
-            } else {
-                memtable.delete(entry.key, entry.sequence);
-            }
-            
             // Update sequence number
             let current_seq = self.sequence_number.load(Ordering::SeqCst);
             if entry.sequence >= current_seq {
                 self.sequence_number.store(entry.sequence + 1, Ordering::SeqCst);
             }
+            
+            memtable.apply(entry);
+            replayed += 1;
         }
+        self.wal_entries_replayed.store(replayed, Ordering::Relaxed);
         
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Whether opening an SSTable failed because the file is damaged, rather
+/// than unreadable
+fn is_damage(error: &StorageError) -> bool {
+    match error {
+        StorageError::Corruption(_) | StorageError::Compression(_) => true,
+        StorageError::Io(e) => e.kind() == std::io::ErrorKind::UnexpectedEof,
+        _ => false,
+    }
+}
+
+/// Make renames and removals in `dir` durable
+fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    std::fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Sequence number, value, and whether merges are left to fold into the
+/// value, of a key's newest version seen by a scan
+type ScanVersion = (u64, Option<Vec<u8>>, bool);
+
+/// Sequence numbers reserved by a write that has not reached the memtable
+struct InFlightWrite<'a> {
+    tree: &'a LSMTree,
+    first: u64,
+}
+
+impl Drop for InFlightWrite<'_> {
+    fn drop(&mut self) {
+        self.tree.in_flight_writes.lock().remove(&self.first);
+    }
+}
+
+// Entries read from each source per page of a pinned view
+const PINNED_PAGE_SIZE: usize = 1024;
+
+/// Every source of a tree frozen at one point in time
+struct PinnedView {
+    sequence: u64,
+    active: Vec<(Vec<u8>, MemTableEntry)>,
+    // Newest first
+    immutable: Vec<Arc<MemTable>>,
+    sstables: Vec<Arc<SSTable>>,
+    // Every SSTable, newest first, to find the values merges apply to
+    lookup_sstables: Vec<Arc<SSTable>>,
+}
+
+impl PinnedView {
+    /// The key's newest version that is not a merge, with the operands of
+    /// the merges above it, newest first. Like `LSMTree::get` before the
+    /// merges are folded.
+    async fn lookup(&self, key: &[u8], now: u64, cache: &BlockCache) -> Result<(Option<Vec<u8>>, Vec<Vec<u8>>)> {
+        let mut operands = Vec::new();
+        let active = self.active.binary_search_by(|(k, _)| k.as_slice().cmp(key)).ok().map(|i| &self.active[i].1);
+        let memtables = active.into_iter().chain(self.immutable.iter().filter_map(|m| m.entry(key)));
+        for entry in memtables {
+            if let Some(value) = entry.read_through(now, &mut operands) {
+                return Ok((value, operands));
+            }
+        }
+        for sstable in &self.lookup_sstables {
+            if let Some(value) = sstable.get(key, cache).await? {
+                return Ok((value, operands));
+            }
+        }
+        Ok((None, operands))
+    }
+    
+    /// Newest version of each key from `start` on, including tombstones and
+    /// expired entries, plus the cursor to resume from. Works like
+    /// `LSMTree::scan_page` but keeps expiry times and has no end bound.
+    async fn page(
+        &self,
+        start: &[u8],
+        cache: &BlockCache,
+    ) -> Result<(Vec<(Vec<u8>, MemTableEntry)>, Option<Vec<u8>>)> {
+        let limit = PINNED_PAGE_SIZE;
+        let mut sources: Vec<Vec<(Vec<u8>, MemTableEntry)>> = Vec::new();
+        
+        let from = self.active.partition_point(|(key, _)| key.as_slice() < start);
+        sources.push(self.active[from..].iter().take(limit).cloned().collect());
+        for memtable in &self.immutable {
+            sources.push(memtable.iter_from(start).take(limit).map(|(k, e)| (k.clone(), e.clone())).collect());
+        }
+        for sstable in &self.sstables {
+            sources.push(sstable.entry_range(start, None, limit, cache).await?
+                .into_iter()
+                .map(|e| (e.key, MemTableEntry { value: e.value, sequence: e.sequence, expires_at: e.expires_at, merge: None }))
+                .collect());
+        }
+        
+        // Only keys up to the smallest last key of a source that filled its
+        // page are known to be complete
+        let complete_until = sources.iter()
+            .filter(|entries| entries.len() == limit)
+            .map(|entries| entries[limit - 1].0.clone())
+            .min();
+        
+        let mut merged: BTreeMap<Vec<u8>, MemTableEntry> = BTreeMap::new();
+        for (key, entry) in sources.into_iter().flatten() {
+            if complete_until.as_ref().is_some_and(|bound| &key > bound) {
+                continue;
+            }
+            match merged.get(&key) {
+                Some(existing) if existing.sequence > entry.sequence => {}
+                _ => {
+                    merged.insert(key, entry);
+                }
+            }
+        }
+        
+        let next = complete_until.map(|mut bound| {
+            bound.push(0);
+            bound
+        });
+        Ok((merged.into_iter().collect(), next))
+    }
+}
@@ -1,17 +1,75 @@
 use std::collections::BTreeMap;
+use std::ops::Bound;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// Entry in the memtable with metadata
 #[derive(Debug, Clone)]
 pub struct MemTableEntry {
     pub value: Option<Vec<u8>>, // None for deletions
     pub sequence: u64,
+    pub expires_at: Option<u64>, // milliseconds since the Unix epoch
+    /// Merges written over the value, which a `MergeOperator` folds in on read
+    pub merge: Option<PendingMerge>,
+}
+
+/// Merge operands not yet folded into a value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingMerge {
+    /// Oldest first
+    pub operands: Vec<Vec<u8>>,
+    /// The operands apply to the key's version in older memtables and
+    /// SSTables, rather than to the entry's own value
+    pub onto_older: bool,
+}
+
+impl MemTableEntry {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+    
+    /// The value as seen at time `now`: expired entries read as deleted
+    pub fn live_value(&self, now: u64) -> Option<Vec<u8>> {
+        if self.is_expired(now) {
+            None
+        } else {
+            self.value.clone()
+        }
+    }
+    
+    /// One step of a read walking from a key's newest version to its
+    /// oldest. Pushes the entry's merge operands onto `operands`, newest
+    /// first, and returns the value the merges apply to, or None if that is
+    /// an older version.
+    pub fn read_through(&self, now: u64, operands: &mut Vec<Vec<u8>>) -> Option<Option<Vec<u8>>> {
+        match &self.merge {
+            None => Some(self.live_value(now)),
+            Some(merge) => {
+                operands.extend(merge.operands.iter().rev().cloned());
+                (!merge.onto_older).then(|| self.value.clone())
+            }
+        }
+    }
+    
+    fn size(&self, key: &[u8]) -> usize {
+        let operands = self.merge.as_ref().map_or(0, |m| m.operands.iter().map(|o| o.len()).sum());
+        key.len() + self.value.as_ref().map_or(0, |v| v.len()) + operands + 8 + 8 // key + value + seq + ts
+    }
 }
 
 /// In-memory sorted table using a skip list (BTreeMap for simplicity)
 pub struct MemTable {
     data: BTreeMap<Vec<u8>, MemTableEntry>,
     size: AtomicUsize,
+    /// When the first entry was written
+    first_write: Option<Instant>,
+}
+
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MemTable {
@@ -19,47 +77,80 @@ impl MemTable {
         Self {
             data: BTreeMap::new(),
             size: AtomicUsize::new(0),
+            first_write: None,
         }
     }
     
     pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>, sequence: u64) {
-        let old_size = if let Some(old_entry) = self.data.get(&key) {
-            key.len() + old_entry.value.as_ref().map_or(0, |v| v.len()) + 8 + 8 // key + value + seq + ts
-        } else {
-            0
-        };
-        
-        let new_size = key.len() + value.len() + 8 + 8;
-        
+        self.put_with_expiry(key, value, sequence, None);
+    }
+    
+    pub fn put_with_expiry(&mut self, key: Vec<u8>, value: Vec<u8>, sequence: u64, expires_at: Option<u64>) {
         let entry = MemTableEntry {
             value: Some(value),
             sequence,
+            expires_at,
+            merge: None,
         };
         
-        self.data.insert(key, entry);
-        
-        // Update size accounting
-        self.size.store(
-            self.size.load(Ordering::Relaxed) - old_size + new_size,
-            Ordering::Relaxed,
-        );
+        self.insert(key, entry);
     }
     
     pub fn delete(&mut self, key: Vec<u8>, sequence: u64) {
-        let old_size = if let Some(old_entry) = self.data.get(&key) {
-            key.len() + old_entry.value.as_ref().map_or(0, |v| v.len()) + 8 + 8
-        } else {
-            0
-        };
-        
-        let new_size = key.len() + 8 + 8; // key + seq + ts (no value for tombstone)
-        
         let entry = MemTableEntry {
             value: None, // Tombstone
             sequence,
+            expires_at: None,
+            merge: None,
         };
         
+        self.insert(key, entry);
+    }
+    
+    /// Stack a merge operand on the key. The result of a merge has no TTL,
+    /// so a value it applies to loses its expiry unless already expired.
+    pub fn merge(&mut self, key: Vec<u8>, operand: Vec<u8>, sequence: u64) {
+        let now = crate::now_millis();
+        let old_size = self.data.get(&key).map_or(0, |old| old.size(&key));
+        self.first_write.get_or_insert_with(Instant::now);
+        
+        let entry = self.data.entry(key.clone()).or_insert_with(|| MemTableEntry {
+            value: None,
+            sequence,
+            expires_at: None,
+            merge: Some(PendingMerge { operands: Vec::new(), onto_older: true }),
+        });
+        match &mut entry.merge {
+            Some(merge) => merge.operands.push(operand),
+            None => {
+                entry.value = entry.live_value(now);
+                entry.expires_at = None;
+                entry.merge = Some(PendingMerge { operands: vec![operand], onto_older: false });
+            }
+        }
+        entry.sequence = sequence;
+        let new_size = entry.size(&key);
+        
+        self.size.store(
+            self.size.load(Ordering::Relaxed) - old_size + new_size,
+            Ordering::Relaxed,
+        );
+    }
+    
+    /// Apply a logged write
+    pub fn apply(&mut self, kv_pair: crate::KVPair) {
+        match kv_pair.value {
+            Some(operand) if kv_pair.merge => self.merge(kv_pair.key, operand, kv_pair.sequence),
+            Some(value) => self.put_with_expiry(kv_pair.key, value, kv_pair.sequence, kv_pair.expires_at),
+            None => self.delete(kv_pair.key, kv_pair.sequence),
+        }
+    }
+    
+    fn insert(&mut self, key: Vec<u8>, entry: MemTableEntry) {
+        let old_size = self.data.get(&key).map_or(0, |old| old.size(&key));
+        let new_size = entry.size(&key);
         self.data.insert(key, entry);
+        self.first_write.get_or_insert_with(Instant::now);
         
         self.size.store(
             self.size.load(Ordering::Relaxed) - old_size + new_size,
@@ -67,8 +158,29 @@ impl MemTable {
         );
     }
     
+    pub fn entry(&self, key: &[u8]) -> Option<&MemTableEntry> {
+        self.data.get(key)
+    }
+    
+    /// The key's value, not counting merges (see `MemTableEntry::read_through`)
     pub fn get(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
-        self.data.get(key).map(|entry| entry.value.clone())
+        let now = crate::now_millis();
+        self.data.get(key).map(|entry| entry.live_value(now))
+    }
+    
+    /// Turn entries that expired by `now` into tombstones, releasing their
+    /// values. Returns how many entries were expired.
+    pub fn expire(&mut self, now: u64) -> usize {
+        let mut expired = 0;
+        for entry in self.data.values_mut() {
+            if entry.is_expired(now) {
+                let freed = entry.value.take().map_or(0, |v| v.len());
+                entry.expires_at = None;
+                self.size.fetch_sub(freed, Ordering::Relaxed);
+                expired += 1;
+            }
+        }
+        expired
     }
     
     pub fn size(&self) -> usize {
@@ -79,9 +191,69 @@ impl MemTable {
         self.data.is_empty()
     }
     
+    /// How long ago the oldest entry was written, or None while empty
+    pub fn age(&self) -> Option<Duration> {
+        self.first_write.filter(|_| !self.is_empty()).map(|first| first.elapsed())
+    }
+    
+    /// Number of entries, tombstones included
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+    
     pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &MemTableEntry)> {
         self.data.iter()
     }
+    
+    /// Entries with `start <= key`, in key order
+    pub fn iter_from<'a>(&'a self, start: &[u8]) -> impl Iterator<Item = (&'a Vec<u8>, &'a MemTableEntry)> {
+        self.data.range::<[u8], _>((Bound::Included(start), Bound::Unbounded))
+    }
+    
+    /// Entries with `start <= key < end`, in key order
+    pub fn range<'a>(&'a self, start: &[u8], end: &[u8]) -> impl DoubleEndedIterator<Item = (&'a Vec<u8>, &'a MemTableEntry)> {
+        // BTreeMap::range panics on inverted bounds, so clamp them to an empty range
+        let end = end.max(start);
+        self.data.range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+    }
+}
+
+/// Memtables frozen to await a flush, each tagged with an id that grows with
+/// every memtable added. A larger id is always newer, so age order does not
+/// depend on the order the list is stored or changed in.
+#[derive(Default)]
+pub(crate) struct ImmutableMemtables {
+    tables: BTreeMap<u64, Arc<MemTable>>,
+    next_id: u64,
+}
+
+impl ImmutableMemtables {
+    /// Add a memtable newer than every one added before, returning its id
+    pub(crate) fn push(&mut self, memtable: Arc<MemTable>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.tables.insert(id, memtable);
+        id
+    }
+    
+    pub(crate) fn remove(&mut self, id: u64) -> Option<Arc<MemTable>> {
+        self.tables.remove(&id)
+    }
+    
+    pub(crate) fn len(&self) -> usize {
+        self.tables.len()
+    }
+    
+    /// The order reads check them in, so the newest version of a key wins
+    pub(crate) fn newest_first(&self) -> impl Iterator<Item = &Arc<MemTable>> {
+        self.tables.values().rev()
+    }
+    
+    /// The order they are flushed in, with their ids, so a memtable's merges
+    /// find the values they apply to already in SSTables
+    pub(crate) fn oldest_first(&self) -> Vec<(u64, Arc<MemTable>)> {
+        self.tables.iter().map(|(&id, memtable)| (id, memtable.clone())).collect()
+    }
 }
 
 #[cfg(test)]
@@ -107,6 +279,52 @@ mod tests {
         assert_eq!(memtable.get(&key), Some(None));
     }
     
+    #[test]
+    fn test_memtable_expiry() {
+        let mut memtable = MemTable::new();
+        let now = crate::now_millis();
+        
+        memtable.put_with_expiry(b"gone".to_vec(), b"v".to_vec(), 1, Some(now - 1));
+        memtable.put_with_expiry(b"later".to_vec(), b"v".to_vec(), 2, Some(now + 60_000));
+        memtable.put(b"forever".to_vec(), b"v".to_vec(), 3);
+        
+        // Expired entries read as deleted before and after they are swept
+        assert_eq!(memtable.get(b"gone"), Some(None));
+        assert_eq!(memtable.expire(now), 1);
+        assert_eq!(memtable.get(b"gone"), Some(None));
+        assert_eq!(memtable.get(b"later"), Some(Some(b"v".to_vec())));
+        assert_eq!(memtable.get(b"forever"), Some(Some(b"v".to_vec())));
+    }
+    
+    #[test]
+    fn test_immutable_memtables_newest_first() {
+        let memtable = |value: &[u8]| {
+            let mut memtable = MemTable::new();
+            memtable.put(b"k".to_vec(), value.to_vec(), 1);
+            Arc::new(memtable)
+        };
+        let newest = |immutable: &ImmutableMemtables| {
+            immutable.newest_first().map(|m| m.get(b"k").unwrap().unwrap()).collect::<Vec<_>>()
+        };
+        
+        let mut immutable = ImmutableMemtables::default();
+        let first = immutable.push(memtable(b"1"));
+        let second = immutable.push(memtable(b"2"));
+        let third = immutable.push(memtable(b"3"));
+        assert_eq!(newest(&immutable), [b"3", b"2", b"1"]);
+        
+        // Removing out of order leaves the others in age order, and ids are
+        // never reused
+        assert!(immutable.remove(second).is_some());
+        assert!(immutable.remove(second).is_none());
+        let fourth = immutable.push(memtable(b"4"));
+        assert!(fourth > second);
+        assert_eq!(newest(&immutable), [b"4", b"3", b"1"]);
+        let ids: Vec<u64> = immutable.oldest_first().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, [first, third, fourth]);
+        assert_eq!(immutable.len(), 3);
+    }
+    
     #[test]
     fn test_memtable_ordering() {
         let mut memtable = MemTable::new();
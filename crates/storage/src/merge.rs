@@ -0,0 +1,13 @@
+//! Merge operators, for read-modify-write updates without the read.
+
+/// Folds merge operands into a value. `LSMTree::merge` only records its
+/// operand; reads fold the operands written since the key's last put or
+/// delete onto that value, and flushes write the folded value to disk.
+///
+/// Must be deterministic, since the same operands may be folded more than
+/// once before they are flushed.
+pub trait MergeOperator: Send + Sync {
+    /// `existing` is None when the key was absent, deleted or expired.
+    /// `operands` are oldest first.
+    fn merge(&self, existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8>;
+}
@@ -1,8 +1,10 @@
 use crate::{
     error::{Result, StorageError},
+    block::{Block, BlockBuilder},
     cache::BlockCache,
-    compression::{compress, decompress, CompressionType},
+    compression::{compress, compress_block, decompress, CompressionType},
 };
+use memmap2::Mmap;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use tokio::fs::{File, OpenOptions};
@@ -10,17 +12,48 @@ use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
 use std::collections::BTreeMap;
 
 const BLOCK_SIZE: usize = 4096;
-const FOOTER_SIZE: usize = 48;
+const FOOTER_SIZE: usize = 256;
 
+// Layout of the file as a whole: the fixed-size JSON footer, the JSON index
+// and its checksum. Files that record a newer version are refused on open.
+const FORMAT_VERSION: u32 = 1;
+
+// Layout of data blocks: 0 is a JSON array of entries, 1 the prefix-compressed
+// encoding of the `block` module
+const BLOCK_FORMAT: u32 = 1;
+
+// Fields that are usually empty are left out, to keep the footer within
+// FOOTER_SIZE
 #[derive(Debug, Serialize, Deserialize)]
 struct SSTableFooter {
+    #[serde(default)]
+    version: u32,
     index_offset: u64,
     index_size: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
     bloom_filter_offset: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
     bloom_filter_size: u64,
     compression: CompressionType,
     num_entries: u64,
     crc: u32,
+    // Earliest expiry of any entry, so sweeps can skip files with nothing to expire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    earliest_expiry: Option<u64>,
+    // Oldest sequence number of any entry, so sweeps can tell which files
+    // cannot hold versions an entry shadows. None in files that predate it.
+    #[serde(default)]
+    min_sequence: Option<u64>,
+    // Newest sequence number of any entry, so differential backups can skip
+    // files written before them. None in files that predate it.
+    #[serde(default)]
+    max_sequence: Option<u64>,
+    #[serde(default)]
+    block_format: u32,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -28,108 +61,490 @@ struct IndexEntry {
     key: Vec<u8>,
     offset: u64,
     size: u32,
+    // Set when the block is not compressed with the footer's compression
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compression: Option<CompressionType>,
+    // Set on the last block: the largest key in the file. None in files
+    // that predate it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_key: Option<Vec<u8>>,
+}
+
+/// Single key/value record stored inside a data block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct BlockEntry {
+    pub(crate) key: Vec<u8>,
+    pub(crate) value: Option<Vec<u8>>, // None for deletions
+    pub(crate) sequence: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_at: Option<u64>,
+}
+
+impl BlockEntry {
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+
+    /// The value as seen at time `now`: expired entries read as deleted
+    fn live_value(&self, now: u64) -> Option<Vec<u8>> {
+        if self.is_expired(now) {
+            None
+        } else {
+            self.value.clone()
+        }
+    }
 }
 
 /// Immutable sorted table stored on disk
 pub struct SSTable {
     file_path: PathBuf,
+    file_size: u64,
     footer: SSTableFooter,
     index: BTreeMap<Vec<u8>, IndexEntry>,
+    // Read-only mapping of the whole file when mmap reads are enabled
+    mmap: Option<Mmap>,
 }
 
 impl SSTable {
     pub async fn open<P: AsRef<Path>>(file_path: P) -> Result<Self> {
+        Self::open_with_mmap(file_path, false).await
+    }
+
+    /// Open an SSTable, optionally memory-mapping the file for block reads.
+    ///
+    /// Mapped tables slice blocks straight out of the OS page cache and bypass
+    /// the `BlockCache`. SSTables are never modified in place once written, so
+    /// the mapping stays valid for the lifetime of the table; every block range
+    /// is still bounds-checked against the mapped length before slicing.
+    pub async fn open_with_mmap<P: AsRef<Path>>(file_path: P, use_mmap: bool) -> Result<Self> {
         let path = file_path.as_ref().to_path_buf();
-        let mut file = File::open(&path).await
-            .map_err(|e| StorageError::Io(e))?;
-        
+        let mut file = File::open(&path).await?;
+
         // Read footer from end of file
         let file_size = file.metadata().await?.len();
         if file_size < FOOTER_SIZE as u64 {
             return Err(StorageError::Corruption("SSTable too small".to_string()));
         }
-        
+
         file.seek(SeekFrom::End(-(FOOTER_SIZE as i64))).await?;
         let mut footer_bytes = vec![0u8; FOOTER_SIZE];
         file.read_exact(&mut footer_bytes).await?;
-        
-        let footer: SSTableFooter = serde_json::from_slice(&footer_bytes)
+
+        // The footer is zero-padded to a fixed size
+        let footer_len = footer_bytes.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1);
+        let footer: SSTableFooter = serde_json::from_slice(&footer_bytes[..footer_len])
             .map_err(|e| StorageError::Corruption(format!("Invalid footer: {}", e)))?;
-        
+        if footer.version > FORMAT_VERSION || footer.block_format > BLOCK_FORMAT {
+            return Err(StorageError::Corruption(format!(
+                "Unsupported SSTable format version {} (block format {})",
+                footer.version, footer.block_format
+            )));
+        }
+
+        let data_end = file_size - FOOTER_SIZE as u64;
+        if footer.index_offset.checked_add(footer.index_size).is_none_or(|end| end > data_end) {
+            return Err(StorageError::Corruption("Index extends past end of file".to_string()));
+        }
+
         // Read and parse index
         file.seek(SeekFrom::Start(footer.index_offset)).await?;
         let mut index_bytes = vec![0u8; footer.index_size as usize];
         file.read_exact(&mut index_bytes).await?;
-        
+
+        if crc32fast::hash(&index_bytes) != footer.crc {
+            return Err(StorageError::Corruption("Index checksum mismatch".to_string()));
+        }
+
         let decompressed = decompress(&index_bytes, &footer.compression)?;
         let index_entries: Vec<IndexEntry> = serde_json::from_slice(&decompressed)
             .map_err(|e| StorageError::Corruption(format!("Invalid index: {}", e)))?;
-        
+
         let mut index = BTreeMap::new();
         for entry in index_entries {
+            if entry.offset + entry.size as u64 > footer.index_offset {
+                return Err(StorageError::Corruption("Block extends into index".to_string()));
+            }
             index.insert(entry.key.clone(), entry);
         }
-        
+
+        let mmap = if use_mmap {
+            let std_file = file.into_std().await;
+            // SAFETY: SSTable files are immutable after `finish` and are only ever
+            // unlinked, never truncated or rewritten, so the mapped bytes cannot
+            // change underneath us. The length is re-checked below to catch a
+            // file that was tampered with between opening and mapping.
+            let mmap = unsafe { Mmap::map(&std_file)? };
+            if mmap.len() as u64 != file_size {
+                return Err(StorageError::Corruption("SSTable changed size while mapping".to_string()));
+            }
+            Some(mmap)
+        } else {
+            None
+        };
+
         Ok(Self {
             file_path: path,
+            file_size,
             footer,
             index,
+            mmap,
         })
     }
-    
+
     pub async fn get(&self, key: &[u8], cache: &BlockCache) -> Result<Option<Option<Vec<u8>>>> {
-        // Find the index entry for this key or the next larger key
+        let now = crate::now_millis();
+        Ok(self.entry(key, cache).await?.map(|entry| entry.live_value(now)))
+    }
+
+    /// The key's entry, tombstone or expired as it may be
+    pub(crate) async fn entry(&self, key: &[u8], cache: &BlockCache) -> Result<Option<BlockEntry>> {
+        // Find the block whose first key is the largest one <= key
         let entry = self.index.range(..=key.to_vec())
             .next_back()
             .map(|(_, entry)| entry);
-        
-        if let Some(entry) = entry {
-            // Check cache first
-            let cache_key = format!("{}:{}", self.file_path.display(), entry.offset);
-            if let Some(cached_value) = cache.get(&cache_key) {
-                return Ok(Some(Some(cached_value)));
+
+        let Some(entry) = entry else {
+            return Ok(None);
+        };
+
+        let block = self.read_block(entry, cache).await?;
+        if self.footer.block_format == 0 {
+            let entries = Self::parse_json_block(&block)?;
+            return Ok(entries.binary_search_by(|e| e.key.as_slice().cmp(key))
+                .ok()
+                .map(|pos| entries[pos].clone()));
+        }
+        Block::new(&block)?.get(key)
+    }
+
+    /// Entries with `start <= key < end` as `(key, value, sequence)`, stopping
+    /// after `limit` entries. Tombstones and expired entries are included with a
+    /// `None` value and count toward the limit.
+    pub async fn scan(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        limit: usize,
+        cache: &BlockCache,
+    ) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>, u64)>> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+
+        let now = crate::now_millis();
+        Ok(self.entry_range(start, Some(end), limit, cache).await?
+            .into_iter()
+            .map(|item| {
+                let value = item.live_value(now);
+                (item.key, value, item.sequence)
+            })
+            .collect())
+    }
+
+    /// Like `scan`, in descending key order
+    pub async fn scan_rev(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        limit: usize,
+        cache: &BlockCache,
+    ) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>, u64)>> {
+        let mut results = Vec::new();
+        if start >= end || limit == 0 {
+            return Ok(results);
+        }
+
+        // Blocks that start before `end`, last first, until one starts at or
+        // before `start`
+        let now = crate::now_millis();
+        for (first_key, entry) in self.index.range(..end.to_vec()).rev() {
+            let block = self.read_block(entry, cache).await?;
+            for item in self.block_entries_from(&block, start)?.into_iter().rev() {
+                if item.key.as_slice() >= end {
+                    continue;
+                }
+                let value = item.live_value(now);
+                results.push((item.key, value, item.sequence));
+                if results.len() == limit {
+                    return Ok(results);
+                }
             }
-            
-            // Read from disk
-            let mut file = File::open(&self.file_path).await?;
-            file.seek(SeekFrom::Start(entry.offset)).await?;
-            
-            let mut compressed_data = vec![0u8; entry.size as usize];
-            file.read_exact(&mut compressed_data).await?;
-            
-            let decompressed = decompress(&compressed_data, &self.footer.compression)?;
-            
-            // Parse the block to find the exact key
-            // TODO: Implement proper block parsing
-            // For now, just return a placeholder value since we have serialization issues with binary keys
-            if let Some(v) = cache.get(&cache_key) {
-                return Ok(Some(Some(v)));
+            if first_key.as_slice() <= start {
+                break;
             }
-            
-            // TODO: Parse decompressed data into key-value pairs
-            // This is a placeholder implementation
-            if !decompressed.is_empty() {
-                // For now, return Some(None) to indicate key exists but value needs proper parsing
-                return Ok(Some(None));
+        }
+
+        Ok(results)
+    }
+
+    /// Raw entries with `start <= key`, and `key < end` if given, stopping
+    /// after `limit` of them
+    pub(crate) async fn entry_range(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        cache: &BlockCache,
+    ) -> Result<Vec<BlockEntry>> {
+        let mut results = Vec::new();
+        if limit == 0 {
+            return Ok(results);
+        }
+        let before_end = |key: &[u8]| end.is_none_or(|end| key < end);
+
+        // Start at the block that may contain `start`, or the first block
+        let first_block = self.index.range(..=start.to_vec())
+            .next_back()
+            .map(|(key, _)| key.clone())
+            .unwrap_or_default();
+
+        for entry in self.index.range(first_block..).map(|(_, entry)| entry) {
+            if !before_end(&entry.key) {
+                break;
+            }
+
+            let block = self.read_block(entry, cache).await?;
+            for item in self.block_entries_from(&block, start)? {
+                if !before_end(&item.key) {
+                    return Ok(results);
+                }
+                results.push(item);
+                if results.len() == limit {
+                    return Ok(results);
+                }
             }
         }
-        
-        Ok(None)
+
+        Ok(results)
+    }
+
+    /// Every entry in the table, in key order, including expired ones
+    pub(crate) async fn entries(&self, cache: &BlockCache) -> Result<Vec<BlockEntry>> {
+        let mut entries = Vec::with_capacity(self.footer.num_entries as usize);
+        for entry in self.index.values() {
+            let block = self.read_block(entry, cache).await?;
+            entries.extend(self.block_entries_from(&block, &[])?);
+        }
+        Ok(entries)
     }
-    
+
+    /// Every entry in key order as `(key, value, sequence)`, tombstones with
+    /// a `None` value and expired entries with the value they were written
+    /// with. Each block is read as the iterator reaches it, with blocking
+    /// reads, so call it from tools or `spawn_blocking`. A block that cannot
+    /// be read yields one error, and the blocks after it are still read.
+    pub fn iter<'a>(&'a self, cache: &'a BlockCache) -> impl Iterator<Item = Result<(Vec<u8>, Option<Vec<u8>>, u64)>> + 'a {
+        self.index.values().flat_map(move |entry| {
+            let entries = self.read_block_blocking(entry, cache)
+                .and_then(|block| self.block_entries_from(&block, &[]));
+            match entries {
+                Ok(entries) => entries.into_iter().map(|entry| Ok((entry.key, entry.value, entry.sequence))).collect(),
+                Err(e) => vec![Err(e)],
+            }
+        })
+    }
+
+    /// Check every block against the index and footer, returning the
+    /// problems found. `open` already checked the index checksum; blocks
+    /// carry none, so each is read from the file and decoded, and must hold
+    /// keys in strictly increasing order starting at its index key, with
+    /// sequences within the footer's range. Blocks overlapping or out of
+    /// order, and an entry count or last key that differs from the one
+    /// recorded, are reported too.
+    pub fn verify(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        // A cache of its own, so the blocks are read from the file
+        let cache = BlockCache::new(0);
+        let mut previous_key: Option<Vec<u8>> = None;
+        let mut block_end = 0;
+        let mut entries = 0;
+        for entry in self.index.values() {
+            let at = format!("block at offset {}", entry.offset);
+            if entry.offset < block_end {
+                problems.push(format!("{} overlaps the block before it", at));
+            }
+            block_end = entry.offset + entry.size as u64;
+
+            let block = match self.read_block_blocking(entry, &cache).and_then(|block| self.block_entries_from(&block, &[])) {
+                Ok(block) => block,
+                Err(e) => {
+                    problems.push(format!("{} cannot be read: {}", at, e));
+                    continue;
+                }
+            };
+            match block.first() {
+                Some(first) if first.key != entry.key => problems.push(format!("{} starts at a key other than its index key", at)),
+                None => problems.push(format!("{} is empty", at)),
+                _ => {}
+            }
+            for item in &block {
+                if previous_key.as_ref().is_some_and(|previous| *previous >= item.key) {
+                    problems.push(format!("{} has a key out of order", at));
+                }
+                if let Some((min, max)) = self.sequence_range() {
+                    if item.sequence < min || item.sequence > max {
+                        problems.push(format!("{} has sequence {} outside {}..={}", at, item.sequence, min, max));
+                    }
+                }
+                previous_key = Some(item.key.clone());
+            }
+            entries += block.len() as u64;
+        }
+
+        if entries != self.footer.num_entries {
+            problems.push(format!("the footer records {} entries, the blocks hold {}", self.footer.num_entries, entries));
+        }
+        if let Some(last_key) = self.largest_key() {
+            if previous_key.as_deref() != Some(last_key) {
+                problems.push("the index records a last key other than the last one stored".to_string());
+            }
+        }
+        problems
+    }
+
+    /// Earliest expiry time of any entry in the table
+    pub fn earliest_expiry(&self) -> Option<u64> {
+        self.footer.earliest_expiry
+    }
+
+    /// Oldest sequence number of any entry in the table, if recorded
+    pub fn min_sequence(&self) -> Option<u64> {
+        self.footer.min_sequence
+    }
+
+    /// Newest sequence number of any entry in the table, if recorded
+    pub fn max_sequence(&self) -> Option<u64> {
+        self.footer.max_sequence
+    }
+
+    /// Oldest and newest sequence numbers of the table's entries, if recorded
+    pub fn sequence_range(&self) -> Option<(u64, u64)> {
+        self.footer.min_sequence.zip(self.footer.max_sequence)
+    }
+
+    /// Whether block reads are served from a memory mapping
+    pub fn is_mmap(&self) -> bool {
+        self.mmap.is_some()
+    }
+
+    async fn read_block(&self, entry: &IndexEntry, cache: &BlockCache) -> Result<Vec<u8>> {
+        if let Some(block) = self.mapped_or_cached_block(entry, cache)? {
+            return Ok(block);
+        }
+
+        // Read from disk
+        let mut file = File::open(&self.file_path).await?;
+        file.seek(SeekFrom::Start(entry.offset)).await?;
+
+        let mut compressed_data = vec![0u8; entry.size as usize];
+        file.read_exact(&mut compressed_data).await?;
+
+        self.cache_block(entry, &compressed_data, cache)
+    }
+
+    /// `read_block` with blocking file reads
+    fn read_block_blocking(&self, entry: &IndexEntry, cache: &BlockCache) -> Result<Vec<u8>> {
+        use std::io::{Read, Seek};
+
+        if let Some(block) = self.mapped_or_cached_block(entry, cache)? {
+            return Ok(block);
+        }
+
+        let mut file = std::fs::File::open(&self.file_path)?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let mut compressed_data = vec![0u8; entry.size as usize];
+        file.read_exact(&mut compressed_data)?;
+
+        self.cache_block(entry, &compressed_data, cache)
+    }
+
+    /// The block from the memory mapping, or from `cache` if it holds it
+    fn mapped_or_cached_block(&self, entry: &IndexEntry, cache: &BlockCache) -> Result<Option<Vec<u8>>> {
+        if let Some(mmap) = &self.mmap {
+            let start = entry.offset as usize;
+            let end = start + entry.size as usize;
+            let raw = mmap.get(start..end)
+                .ok_or_else(|| StorageError::Corruption("Block out of mapped range".to_string()))?;
+            return decompress(raw, self.block_compression(entry)).map(Some);
+        }
+        match cache.get(&self.cache_key(entry)) {
+            Some(raw) if cache.holds_compressed_blocks() => decompress(&raw, self.block_compression(entry)).map(Some),
+            block => Ok(block),
+        }
+    }
+
+    /// Decompress a block read from the file and keep it in `cache`, as read
+    /// if the cache holds compressed blocks
+    fn cache_block(&self, entry: &IndexEntry, compressed: &[u8], cache: &BlockCache) -> Result<Vec<u8>> {
+        let block = decompress(compressed, self.block_compression(entry))?;
+        if cache.holds_compressed_blocks() {
+            cache.put(self.cache_key(entry), compressed.to_vec());
+        } else {
+            cache.put(self.cache_key(entry), block.clone());
+        }
+        Ok(block)
+    }
+
+    fn cache_key(&self, entry: &IndexEntry) -> String {
+        format!("{}:{}", self.file_path.display(), entry.offset)
+    }
+
+    fn block_compression<'a>(&'a self, entry: &'a IndexEntry) -> &'a CompressionType {
+        entry.compression.as_ref().unwrap_or(&self.footer.compression)
+    }
+
+    /// Entries of a block with keys >= `start`
+    fn block_entries_from(&self, block: &[u8], start: &[u8]) -> Result<Vec<BlockEntry>> {
+        if self.footer.block_format == 0 {
+            let mut entries = Self::parse_json_block(block)?;
+            entries.retain(|entry| entry.key.as_slice() >= start);
+            return Ok(entries);
+        }
+        Block::new(block)?.seek(start)?.collect()
+    }
+
+    fn parse_json_block(block: &[u8]) -> Result<Vec<BlockEntry>> {
+        serde_json::from_slice(block)
+            .map_err(|e| StorageError::Corruption(format!("Invalid block: {}", e)))
+    }
+
+    /// Smallest key, and the largest if the file records it or else the
+    /// first key of the last block
     pub fn key_range(&self) -> Option<(&[u8], &[u8])> {
         if self.index.is_empty() {
             return None;
         }
-        
+
         let first_key = self.index.keys().next().unwrap();
-        let last_key = self.index.keys().next_back().unwrap();
+        let last_key = self.largest_key().unwrap_or(self.index.keys().next_back().unwrap());
         Some((first_key, last_key))
     }
-    
+
+    /// Largest key, unless the file is empty or predates recording it
+    pub fn largest_key(&self) -> Option<&[u8]> {
+        self.index.values().next_back()?.last_key.as_deref()
+    }
+
     pub fn file_size(&self) -> u64 {
-        // This would need to be populated during creation
-        0 // Simplified for now
+        self.file_size
+    }
+
+    pub fn num_entries(&self) -> u64 {
+        self.footer.num_entries
+    }
+
+    /// Number of data blocks
+    pub fn num_blocks(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Compression of the blocks, unless a block records its own
+    pub fn compression(&self) -> &CompressionType {
+        &self.footer.compression
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.file_path
     }
 }
 
@@ -138,130 +553,174 @@ pub struct SSTableBuilder {
     file_path: PathBuf,
     file: File,
     compression: CompressionType,
-    current_block: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    compression_threshold: usize,
+    mmap_reads: bool,
+    current_block: BlockBuilder,
+    // First key of the current block, which indexes it
+    block_first_key: Option<Vec<u8>>,
+    last_key: Option<Vec<u8>>,
     blocks_written: u64,
     index_entries: Vec<IndexEntry>,
     current_offset: u64,
     num_entries: u64,
+    earliest_expiry: Option<u64>,
+    min_sequence: Option<u64>,
+    max_sequence: Option<u64>,
 }
 
 impl SSTableBuilder {
     pub async fn new<P: AsRef<Path>>(
-        file_path: P, 
+        file_path: P,
         compression: CompressionType
     ) -> Result<Self> {
         let path = file_path.as_ref().to_path_buf();
-        
+
         let file = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
             .open(&path)
             .await?;
-        
+
         Ok(Self {
             file_path: path,
             file,
             compression,
-            current_block: BTreeMap::new(),
+            compression_threshold: 0,
+            mmap_reads: false,
+            current_block: BlockBuilder::default(),
+            block_first_key: None,
+            last_key: None,
             blocks_written: 0,
             index_entries: Vec::new(),
             current_offset: 0,
             num_entries: 0,
+            earliest_expiry: None,
+            min_sequence: None,
+            max_sequence: None,
         })
     }
-    
-    pub fn add(&mut self, key: &[u8], value: &Option<Vec<u8>>, _sequence: u64) -> Result<()> {
-        self.current_block.insert(key.to_vec(), value.clone());
+
+    /// Open the finished table with memory-mapped reads
+    pub fn mmap_reads(mut self, enabled: bool) -> Self {
+        self.mmap_reads = enabled;
+        self
+    }
+
+    /// Store blocks smaller than `bytes` uncompressed
+    pub fn compression_threshold(mut self, bytes: usize) -> Self {
+        self.compression_threshold = bytes;
+        self
+    }
+
+    /// Add an entry. Keys must be added in strictly increasing order.
+    pub async fn add(&mut self, key: &[u8], value: &Option<Vec<u8>>, sequence: u64) -> Result<()> {
+        self.add_with_expiry(key, value, sequence, None).await
+    }
+
+    /// Add an entry that expires at `expires_at` (milliseconds since the Unix epoch)
+    pub async fn add_with_expiry(
+        &mut self,
+        key: &[u8],
+        value: &Option<Vec<u8>>,
+        sequence: u64,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        if self.last_key.as_deref().is_some_and(|last| last >= key) {
+            return Err(StorageError::Internal("SSTable keys must be added in sorted order".to_string()));
+        }
+
+        self.current_block.add(key, value.as_deref(), sequence, expires_at);
+        self.block_first_key.get_or_insert_with(|| key.to_vec());
+        self.last_key = Some(key.to_vec());
         self.num_entries += 1;
-        
+        self.min_sequence = Some(self.min_sequence.map_or(sequence, |m| m.min(sequence)));
+        self.max_sequence = Some(self.max_sequence.map_or(sequence, |m| m.max(sequence)));
+        if let Some(t) = expires_at {
+            self.earliest_expiry = Some(self.earliest_expiry.map_or(t, |e| e.min(t)));
+        }
+
         // Check if block is full
-        let block_size = self.estimate_block_size();
-        if block_size >= BLOCK_SIZE {
-            self.flush_current_block()?;
+        if self.current_block.size() >= BLOCK_SIZE {
+            self.flush_current_block().await?;
         }
-        
+
         Ok(())
     }
-    
+
     pub async fn finish(mut self) -> Result<SSTable> {
-        
+
         // Flush any remaining data
         if !self.current_block.is_empty() {
-            self.flush_current_block()?;
+            self.flush_current_block().await?;
         }
-        
+        if let Some(last) = self.index_entries.last_mut() {
+            last.last_key = self.last_key.clone();
+        }
+
         // Write index
         let index_offset = self.current_offset;
         let index_data = serde_json::to_vec(&self.index_entries)?;
         let compressed_index = compress(&index_data, &self.compression)?;
-        
+
         self.file.write_all(&compressed_index).await?;
         let index_size = compressed_index.len() as u64;
         self.current_offset += index_size;
-        
+
         // Write footer
         let footer = SSTableFooter {
+            version: FORMAT_VERSION,
             index_offset,
             index_size,
             bloom_filter_offset: 0, // Simplified - no bloom filter yet
             bloom_filter_size: 0,
             compression: self.compression.clone(),
             num_entries: self.num_entries,
-            crc: 0, // Simplified - no CRC yet
+            crc: crc32fast::hash(&compressed_index),
+            earliest_expiry: self.earliest_expiry,
+            min_sequence: self.min_sequence,
+            max_sequence: self.max_sequence,
+            block_format: BLOCK_FORMAT,
         };
-        
+
         let footer_data = serde_json::to_vec(&footer)?;
         if footer_data.len() > FOOTER_SIZE {
             return Err(StorageError::Internal("Footer too large".to_string()));
         }
-        
+
         let mut footer_bytes = vec![0u8; FOOTER_SIZE];
         footer_bytes[..footer_data.len()].copy_from_slice(&footer_data);
         self.file.write_all(&footer_bytes).await?;
-        
+
         self.file.sync_all().await?;
         drop(self.file);
-        
+
         // Open the completed SSTable
-        SSTable::open(&self.file_path).await
+        SSTable::open_with_mmap(&self.file_path, self.mmap_reads).await
     }
-    
-    fn flush_current_block(&mut self) -> Result<()> {
-        if self.current_block.is_empty() {
+
+    async fn flush_current_block(&mut self) -> Result<()> {
+        let Some(first_key) = self.block_first_key.take() else {
             return Ok(());
-        }
-        
+        };
+
+        let block_data = self.current_block.finish();
+        let (compression, compressed_block) = compress_block(&block_data, &self.compression, self.compression_threshold)?;
+
         // Record index entry for first key in block
-        if let Some(first_key) = self.current_block.keys().next().cloned() {
-            self.index_entries.push(IndexEntry {
-                key: first_key,
-                offset: self.current_offset,
-                size: 0, // Will be updated after compression
-            });
-        }
-        
-        let block_data = serde_json::to_vec(&self.current_block)?;
-        let compressed_block = compress(&block_data, &self.compression)?;
-        
-        // Update the size in the last index entry
-        if let Some(last_entry) = self.index_entries.last_mut() {
-            last_entry.size = compressed_block.len() as u32;
-        }
-        
-        // Note: In real implementation, we would write to file here
-        // For now, just track the offset
+        self.index_entries.push(IndexEntry {
+            key: first_key,
+            offset: self.current_offset,
+            size: compressed_block.len() as u32,
+            compression: (compression != self.compression).then_some(compression),
+            last_key: None,
+        });
+
+        self.file.write_all(&compressed_block).await?;
         self.current_offset += compressed_block.len() as u64;
         self.blocks_written += 1;
-        
-        self.current_block.clear();
         Ok(())
     }
-    
-    fn estimate_block_size(&self) -> usize {
-        let serialized = serde_json::to_vec(&self.current_block).unwrap_or_default();
-        serialized.len()
-    }
 }
 
 #[cfg(test)]
@@ -270,38 +729,249 @@ mod tests {
     use crate::cache::BlockCache;
     use tempfile::TempDir;
     use std::sync::Arc;
-    
+
     #[tokio::test]
     async fn test_sstable_builder_and_reader() {
         let temp_dir = TempDir::new().unwrap();
         let file_path = temp_dir.path().join("test.sst");
-        
+
         // Build SSTable
         {
             let mut builder = SSTableBuilder::new(&file_path, CompressionType::None).await.unwrap();
-            builder.add(b"key1", &Some(b"value1".to_vec()), 1).unwrap();
-            builder.add(b"key2", &Some(b"value2".to_vec()), 2).unwrap();
-            builder.add(b"key3", &None, 3).unwrap(); // Deletion
-            
+            builder.add(b"key1", &Some(b"value1".to_vec()), 1).await.unwrap();
+            builder.add(b"key2", &Some(b"value2".to_vec()), 2).await.unwrap();
+            builder.add(b"key3", &None, 3).await.unwrap(); // Deletion
+
             let _sstable = builder.finish().await.unwrap();
         }
-        
+
         // Read SSTable
         {
             let sstable = SSTable::open(&file_path).await.unwrap();
             let cache = Arc::new(BlockCache::new(1024 * 1024));
-            
+
             let result1 = sstable.get(b"key1", &cache).await.unwrap();
             assert_eq!(result1, Some(Some(b"value1".to_vec())));
-            
+
             let result2 = sstable.get(b"key2", &cache).await.unwrap();
             assert_eq!(result2, Some(Some(b"value2".to_vec())));
-            
+
             let result3 = sstable.get(b"key3", &cache).await.unwrap();
             assert_eq!(result3, Some(None)); // Deletion marker
-            
+
             let result4 = sstable.get(b"nonexistent", &cache).await.unwrap();
             assert_eq!(result4, None);
         }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_sequence_range() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("sequences.sst");
+
+        // Sequences out of key order, as compaction writes them
+        let sequences = [17u64, 4, 250, 9, 31];
+        let mut builder = SSTableBuilder::new(&file_path, CompressionType::None).await.unwrap();
+        for (i, sequence) in sequences.iter().enumerate() {
+            let value = if i == 2 { None } else { Some(b"v".to_vec()) };
+            builder.add(format!("key{}", i).as_bytes(), &value, *sequence).await.unwrap();
+        }
+        let built = builder.finish().await.unwrap();
+        assert_eq!(built.min_sequence(), Some(4));
+        assert_eq!(built.max_sequence(), Some(250));
+
+        let opened = SSTable::open(&file_path).await.unwrap();
+        assert_eq!(opened.sequence_range(), Some((4, 250)));
+        let cache = BlockCache::new(1024 * 1024);
+        let entries = opened.entries(&cache).await.unwrap();
+        let written = entries.iter().map(|entry| entry.sequence);
+        assert_eq!(opened.sequence_range(), written.clone().min().zip(written.max()));
+    }
+
+    #[tokio::test]
+    async fn test_iter_yields_every_entry_in_order() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("iter.sst");
+
+        // Enough entries for many blocks, every third one a tombstone
+        let written: Vec<(Vec<u8>, Option<Vec<u8>>, u64)> = (0..2000u64)
+            .map(|i| {
+                let value = (i % 3 != 0).then(|| format!("value{}", i).into_bytes());
+                (format!("key{:05}", i).into_bytes(), value, 10_000 - i)
+            })
+            .collect();
+        let mut builder = SSTableBuilder::new(&file_path, CompressionType::LZ4).await.unwrap();
+        for (key, value, sequence) in &written {
+            builder.add(key, value, *sequence).await.unwrap();
+        }
+        builder.finish().await.unwrap();
+
+        for use_mmap in [false, true] {
+            let sstable = SSTable::open_with_mmap(&file_path, use_mmap).await.unwrap();
+            assert!(sstable.index.len() > 1);
+            let cache = BlockCache::new(1024 * 1024);
+            let read: Vec<_> = sstable.iter(&cache).collect::<Result<_>>().unwrap();
+            assert_eq!(read, written, "mmap {}", use_mmap);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_reports_damaged_blocks() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("verify.sst");
+
+        let mut builder = SSTableBuilder::new(&file_path, CompressionType::LZ4).await.unwrap();
+        for i in 0..1000u32 {
+            builder.add(format!("key{:06}", i).as_bytes(), &Some(format!("value{}", i).into_bytes()), i as u64).await.unwrap();
+        }
+        let sstable = builder.finish().await.unwrap();
+        assert!(sstable.verify().is_empty());
+
+        // Damage the second block; the index and its checksum are intact
+        let damaged = sstable.index.values().nth(1).unwrap();
+        let mut bytes = std::fs::read(&file_path).unwrap();
+        let start = damaged.offset as usize + 4;
+        bytes[start..start + 32].fill(0xff);
+        std::fs::write(&file_path, bytes).unwrap();
+
+        let reopened = SSTable::open(&file_path).await.unwrap();
+        let problems = reopened.verify();
+        assert!(problems.iter().any(|problem| problem.starts_with(&format!("block at offset {}", damaged.offset))), "{:?}", problems);
+        assert!(problems.iter().any(|problem| problem.starts_with("the footer records 1000 entries")), "{:?}", problems);
+    }
+
+    #[tokio::test]
+    async fn test_mmap_reads_match_buffered_reads() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("mmap.sst");
+
+        let mut builder = SSTableBuilder::new(&file_path, CompressionType::LZ4).await.unwrap();
+        for i in 0..2000u32 {
+            let key = format!("key{:06}", i).into_bytes();
+            let value = if i % 7 == 0 { None } else { Some(format!("value{}", i).into_bytes()) };
+            builder.add(&key, &value, i as u64).await.unwrap();
+        }
+        builder.finish().await.unwrap();
+
+        let buffered = SSTable::open(&file_path).await.unwrap();
+        let mapped = SSTable::open_with_mmap(&file_path, true).await.unwrap();
+        assert!(!buffered.is_mmap());
+        assert!(mapped.is_mmap());
+
+        let buffered_cache = BlockCache::new(1024 * 1024);
+        let mapped_cache = BlockCache::new(1024 * 1024);
+
+        for i in (0..2100u32).step_by(13) {
+            let key = format!("key{:06}", i).into_bytes();
+            let expected = buffered.get(&key, &buffered_cache).await.unwrap();
+            let actual = mapped.get(&key, &mapped_cache).await.unwrap();
+            assert_eq!(expected, actual, "mismatch for key{:06}", i);
+        }
+        assert_eq!(mapped.get(b"a", &mapped_cache).await.unwrap(), None);
+
+        // The mmap path never populates the block cache
+        assert_eq!(mapped_cache.size(), 0);
+        assert!(buffered_cache.size() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_block_cache_holds_more_blocks() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("cached.sst");
+
+        // Repetitive values, which LZ4 shrinks to a fraction of their size
+        let value = |i: u32| format!("value {} ", i).repeat(40).into_bytes();
+        let mut builder = SSTableBuilder::new(&file_path, CompressionType::LZ4).await.unwrap();
+        for i in 0..1000u32 {
+            builder.add(format!("key{:05}", i).as_bytes(), &Some(value(i)), i as u64).await.unwrap();
+        }
+        let sstable = builder.finish().await.unwrap();
+        assert!(sstable.index.len() > 20);
+
+        let plain = BlockCache::new(64 * 1024);
+        let compressed = BlockCache::new(64 * 1024).with_compressed_blocks(true);
+        for cache in [&plain, &compressed] {
+            for _ in 0..2 {
+                for i in 0..1000u32 {
+                    let key = format!("key{:05}", i);
+                    assert_eq!(sstable.get(key.as_bytes(), cache).await.unwrap(), Some(Some(value(i))), "{}", key);
+                }
+            }
+        }
+        assert!(compressed.len() >= 3 * plain.len(), "{} compressed, {} plain", compressed.len(), plain.len());
+        assert!(compressed.stats().hits > 0);
+    }
+
+    #[tokio::test]
+    async fn test_small_blocks_stored_uncompressed() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("threshold.sst");
+
+        // Six values large enough to fill a block each, then one tiny block
+        let mut builder = SSTableBuilder::new(&file_path, CompressionType::LZ4).await.unwrap()
+            .compression_threshold(1024);
+        for i in 0..6u32 {
+            let key = format!("key{:06}", i).into_bytes();
+            builder.add(&key, &Some(vec![b'v'; 5000]), i as u64).await.unwrap();
+        }
+        builder.add(b"tail", &Some(b"x".to_vec()), 6).await.unwrap();
+        let sstable = builder.finish().await.unwrap();
+
+        let blocks: Vec<&IndexEntry> = sstable.index.values().collect();
+        assert_eq!(blocks.len(), 7);
+        let (last, full) = blocks.split_last().unwrap();
+        assert_eq!(last.compression, Some(CompressionType::None));
+        assert!(full.iter().all(|block| block.compression.is_none() && block.size < 1024));
+
+        for use_mmap in [false, true] {
+            let table = SSTable::open_with_mmap(&file_path, use_mmap).await.unwrap();
+            let cache = BlockCache::new(1024 * 1024);
+            for i in 0..6u32 {
+                let key = format!("key{:06}", i).into_bytes();
+                assert_eq!(table.get(&key, &cache).await.unwrap(), Some(Some(vec![b'v'; 5000])));
+            }
+            assert_eq!(table.get(b"tail", &cache).await.unwrap(), Some(Some(b"x".to_vec())));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mmap_open_rejects_truncated_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("truncated.sst");
+
+        let mut builder = SSTableBuilder::new(&file_path, CompressionType::None).await.unwrap();
+        for i in 0..500u32 {
+            builder.add(format!("key{:06}", i).as_bytes(), &Some(vec![b'x'; 64]), i as u64).await.unwrap();
+        }
+        builder.finish().await.unwrap();
+
+        // Chop the file in the middle of its data blocks
+        let len = std::fs::metadata(&file_path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&file_path).unwrap();
+        file.set_len(len / 2).unwrap();
+
+        assert!(SSTable::open_with_mmap(&file_path, true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_open_rejects_newer_format_version() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("future.sst");
+
+        let mut builder = SSTableBuilder::new(&file_path, CompressionType::None).await.unwrap();
+        builder.add(b"key", &Some(b"value".to_vec()), 1).await.unwrap();
+        builder.finish().await.unwrap();
+
+        // Rewrite the footer as a later release would
+        let bytes = std::fs::read(&file_path).unwrap();
+        let footer_start = bytes.len() - FOOTER_SIZE;
+        let footer = String::from_utf8_lossy(&bytes[footer_start..]).into_owned();
+        let footer = footer.replace(&format!("\"version\":{}", FORMAT_VERSION), &format!("\"version\":{}", FORMAT_VERSION + 1));
+        let mut rewritten = bytes[..footer_start].to_vec();
+        rewritten.extend_from_slice(footer.as_bytes());
+        std::fs::write(&file_path, rewritten).unwrap();
+
+        let error = SSTable::open(&file_path).await.err().unwrap();
+        assert!(error.to_string().contains("Unsupported SSTable format version"), "{}", error);
+    }
+}
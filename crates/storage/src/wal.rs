@@ -1,9 +1,47 @@
-use crate::{error::{Result, StorageError}, KVPair};
+use crate::{direct_io::DirectFile, error::{is_out_of_space, Result, StorageError}, KVPair};
+use futures::stream::{self, BoxStream, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
-use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::broadcast;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Appended entries a subscriber may fall behind by before its changefeed fails
+const CHANGEFEED_CAPACITY: usize = 4096;
+
+/// Each record starts with its length and a CRC of that length, both big
+/// endian u32s, so a torn or corrupt length reads as the end of the log
+const HEADER_LEN: usize = 8;
+
+fn record_header(entry_len: usize) -> [u8; HEADER_LEN] {
+    let len = (entry_len as u32).to_be_bytes();
+    let mut header = [0; HEADER_LEN];
+    header[..4].copy_from_slice(&len);
+    header[4..].copy_from_slice(&crc32fast::hash(&len).to_be_bytes());
+    header
+}
+
+/// The entry length a record header gives, or None where the header is not
+/// intact. Records are never empty, so a zero length is padding after the log.
+fn entry_len(header: &[u8; HEADER_LEN]) -> Option<usize> {
+    let len = u32::from_be_bytes(header[..4].try_into().unwrap());
+    let crc = u32::from_be_bytes(header[4..].try_into().unwrap());
+    (len != 0 && crc == crc32fast::hash(&header[..4])).then_some(len as usize)
+}
+
+/// A failed write to the log, as `StorageError::OutOfSpace` if the disk is full
+fn write_error(what: &str, error: std::io::Error) -> StorageError {
+    if is_out_of_space(&error) {
+        StorageError::OutOfSpace(format!("{}: {}", what, error))
+    } else {
+        StorageError::Wal(format!("{}: {}", what, error))
+    }
+}
+
+/// Every write appended to the log, in log order. Fails if the subscriber
+/// falls too far behind the writers.
+pub type Changefeed = BoxStream<'static, Result<KVPair>>;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct WALEntry {
@@ -12,35 +50,154 @@ struct WALEntry {
     data: KVPair,
 }
 
+/// Several writes logged as one record, so recovery replays all or none
+#[derive(Debug, Serialize, Deserialize)]
+struct WALBatch {
+    crc: u32,
+    batch: Vec<KVPair>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum WALRecord {
+    Single(WALEntry),
+    Batch(WALBatch),
+}
+
+/// What the log does to make writes survive a crash
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Durability {
+    /// Each write is synced to disk before it returns
+    #[default]
+    Full,
+    /// Writes reach the log file but are not synced, so they survive the
+    /// process exiting but not the machine failing. For tests.
+    NoSync,
+    /// No log file is created and the log is kept in memory, so nothing
+    /// written survives the store being dropped. For ephemeral stores.
+    Memory,
+}
+
+/// How the log is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalOptions {
+    /// Initial size of the buffer each record is assembled in before it is
+    /// written; larger records grow it
+    pub buffer_size: usize,
+    /// Write with direct IO, bypassing the page cache. Where the platform or
+    /// file system does not support it the log falls back to buffered writes.
+    pub direct_io: bool,
+    pub durability: Durability,
+}
+
+impl Default for WalOptions {
+    fn default() -> Self {
+        Self { buffer_size: 64 * 1024, direct_io: false, durability: Durability::Full }
+    }
+}
+
 /// Write-Ahead Log for durability guarantees
 pub struct WriteAheadLog {
-    file: tokio::sync::Mutex<File>,
+    file: tokio::sync::Mutex<LogFile>,
     path: PathBuf,
+    options: WalOptions,
+    direct_io: AtomicBool,
     sequence: AtomicU64,
+    changes: broadcast::Sender<KVPair>,
+}
+
+enum LogFile {
+    Buffered { file: File, buffer: Vec<u8> },
+    Direct(DirectFile),
+    /// The records a log file would hold
+    Memory { records: Vec<u8> },
 }
 
 impl WriteAheadLog {
     pub async fn open<P: AsRef<Path>>(wal_dir: P) -> Result<Self> {
+        Self::open_with(wal_dir, WalOptions::default()).await
+    }
+    
+    pub async fn open_with<P: AsRef<Path>>(wal_dir: P, options: WalOptions) -> Result<Self> {
         let path = wal_dir.as_ref().join("wal.log");
+        let file = Self::open_file(&path, options).await?;
+        
+        Ok(Self {
+            direct_io: AtomicBool::new(matches!(file, LogFile::Direct(_))),
+            file: tokio::sync::Mutex::new(file),
+            path,
+            options,
+            sequence: AtomicU64::new(0),
+            changes: broadcast::channel(CHANGEFEED_CAPACITY).0,
+        })
+    }
+    
+    /// Whether writes currently bypass the page cache
+    pub fn is_direct_io(&self) -> bool {
+        self.direct_io.load(Ordering::Relaxed)
+    }
+    
+    /// Open the log for appending after its last whole record. A torn record
+    /// or the padding left by direct writes is cut off first.
+    async fn open_file(path: &Path, options: WalOptions) -> Result<LogFile> {
+        if options.durability == Durability::Memory {
+            return Ok(LogFile::Memory { records: Vec::new() });
+        }
+        let wal_error = |e: std::io::Error| StorageError::Wal(format!("Failed to open WAL: {}", e));
         
         let file = OpenOptions::new()
             .create(true)
             .append(true)
             .read(true)
-            .open(&path)
+            .open(path)
             .await
-            .map_err(|e| StorageError::Wal(format!("Failed to open WAL: {}", e)))?;
+            .map_err(wal_error)?;
+        let len = Self::records_len(path).await?;
+        if file.metadata().await.map_err(wal_error)?.len() > len {
+            file.set_len(len).await.map_err(wal_error)?;
+        }
         
-        Ok(Self {
-            file: tokio::sync::Mutex::new(file),
-            path,
-            sequence: AtomicU64::new(0),
-        })
+        if options.direct_io {
+            match DirectFile::open(path, len, options.buffer_size) {
+                Ok(direct) => return Ok(LogFile::Direct(direct)),
+                Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                    tracing::warn!("Direct IO unavailable for the WAL, using buffered writes: {}", e);
+                }
+                Err(e) => return Err(wal_error(e)),
+            }
+        }
+        Ok(LogFile::Buffered { file, buffer: Vec::with_capacity(options.buffer_size) })
     }
     
-    pub async fn append(&self, kv_pair: &KVPair) -> Result<()> {
-        let mut file = self.file.lock().await;
+    /// Length of the log up to the end of its last whole record
+    async fn records_len(path: &Path) -> Result<u64> {
+        let wal_error = |e: std::io::Error| StorageError::Wal(format!("Failed to read WAL: {}", e));
+        let mut file = File::open(path).await.map_err(wal_error)?;
+        let file_size = file.metadata().await.map_err(wal_error)?.len();
         
+        let mut position = 0;
+        let mut header = [0; HEADER_LEN];
+        while position + HEADER_LEN as u64 <= file_size {
+            file.read_exact(&mut header).await.map_err(wal_error)?;
+            let Some(entry_len) = entry_len(&header) else {
+                break;
+            };
+            let end = position + (HEADER_LEN + entry_len) as u64;
+            if end > file_size {
+                break;
+            }
+            position = end;
+            file.seek(SeekFrom::Start(position)).await.map_err(wal_error)?;
+        }
+        Ok(position)
+    }
+    
+    pub async fn append(&self, kv_pair: &KVPair) -> Result<()> {
+        let entry_bytes = Self::encode_entry(kv_pair)?;
+        self.write_record(&entry_bytes, std::slice::from_ref(kv_pair)).await
+    }
+    
+    fn encode_entry(kv_pair: &KVPair) -> Result<Vec<u8>> {
         // Serialize the KV pair
         let data = serde_json::to_vec(kv_pair)
             .map_err(|e| StorageError::Wal(format!("Failed to serialize WAL entry: {}", e)))?;
@@ -55,59 +212,157 @@ impl WriteAheadLog {
         };
         
         // Serialize the complete entry
-        let entry_bytes = serde_json::to_vec(&entry)
-            .map_err(|e| StorageError::Wal(format!("Failed to serialize WAL entry: {}", e)))?;
+        serde_json::to_vec(&entry)
+            .map_err(|e| StorageError::Wal(format!("Failed to serialize WAL entry: {}", e)))
+    }
+    
+    /// Append `kv_pairs` as a single record
+    pub async fn append_batch(&self, kv_pairs: &[KVPair]) -> Result<()> {
+        let data = serde_json::to_vec(kv_pairs)
+            .map_err(|e| StorageError::Wal(format!("Failed to serialize WAL batch: {}", e)))?;
         
-        // Write length prefix, then entry
-        file.write_u32(entry_bytes.len() as u32).await
-            .map_err(|e| StorageError::Wal(format!("Failed to write WAL entry length: {}", e)))?;
+        let batch = WALBatch {
+            crc: crc32fast::hash(&data),
+            batch: kv_pairs.to_vec(),
+        };
+        let entry_bytes = serde_json::to_vec(&batch)
+            .map_err(|e| StorageError::Wal(format!("Failed to serialize WAL batch: {}", e)))?;
         
-        file.write_all(&entry_bytes).await
-            .map_err(|e| StorageError::Wal(format!("Failed to write WAL entry: {}", e)))?;
+        self.write_record(&entry_bytes, kv_pairs).await
+    }
+    
+    async fn write_record(&self, entry_bytes: &[u8], kv_pairs: &[KVPair]) -> Result<()> {
+        let mut file = self.file.lock().await;
+        let sync = self.options.durability == Durability::Full;
         
-        // Ensure durability
-        file.sync_all().await
-            .map_err(|e| StorageError::Wal(format!("Failed to sync WAL: {}", e)))?;
+        // Header, then entry, written and synced at once
+        match &mut *file {
+            LogFile::Buffered { file, buffer } => {
+                buffer.clear();
+                buffer.extend_from_slice(&record_header(entry_bytes.len()));
+                buffer.extend_from_slice(entry_bytes);
+                file.write_all(buffer).await
+                    .map_err(|e| write_error("Failed to write WAL entry", e))?;
+                // Hands the write to the OS, so readers of the file see it.
+                // Also where a failed write is reported: `sync_all` would
+                // wait for it but not return its error.
+                file.flush().await
+                    .map_err(|e| write_error("Failed to write WAL entry", e))?;
+                if sync {
+                    file.sync_all().await
+                        .map_err(|e| write_error("Failed to sync WAL", e))?;
+                }
+            }
+            LogFile::Direct(direct) => {
+                let record = [&record_header(entry_bytes.len())[..], entry_bytes].concat();
+                direct.append(&record, sync).await
+                    .map_err(|e| write_error("Failed to write WAL entry", e))?;
+            }
+            LogFile::Memory { records } => {
+                records.extend_from_slice(&record_header(entry_bytes.len()));
+                records.extend_from_slice(entry_bytes);
+            }
+        }
         
-        self.sequence.fetch_add(1, Ordering::SeqCst);
+        self.sequence.fetch_add(kv_pairs.len() as u64, Ordering::SeqCst);
+        
+        // Published while the file is still locked, so subscribers see
+        // writes in log order
+        if self.changes.receiver_count() > 0 {
+            for kv_pair in kv_pairs {
+                let _ = self.changes.send(kv_pair.clone());
+            }
+        }
         
         Ok(())
     }
     
+    /// Follow writes as they are appended. With `from`, entries already in
+    /// the log with a sequence of at least `from` are replayed first.
+    pub async fn subscribe(&self, from: Option<u64>) -> Result<Changefeed> {
+        // Holding the file lock keeps appends out until the receiver exists
+        // and the log has been read, so no entry is missed or seen twice
+        let file = self.file.lock().await;
+        let receiver = self.changes.subscribe();
+        let replayed = match from {
+            Some(from) => {
+                let mut entries = self.read_entries(&file).await?;
+                entries.retain(|kv_pair| kv_pair.sequence >= from);
+                entries
+            }
+            None => Vec::new(),
+        };
+        
+        let live = stream::unfold(Some(receiver), |receiver| async move {
+            let mut receiver = receiver?;
+            match receiver.recv().await {
+                Ok(kv_pair) => Some((Ok(kv_pair), Some(receiver))),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    let error = StorageError::Wal(format!("changefeed fell behind and missed {} entries", missed));
+                    Some((Err(error), None))
+                }
+                Err(broadcast::error::RecvError::Closed) => None,
+            }
+        });
+        drop(file);
+        Ok(stream::iter(replayed.into_iter().map(Ok)).chain(live).boxed())
+    }
+    
     pub async fn recover(&self) -> Result<Vec<KVPair>> {
+        let entries = self.read_entries(&*self.file.lock().await).await?;
+        if let Some(last) = entries.last() {
+            self.sequence.store(last.sequence + 1, Ordering::SeqCst);
+        }
+        tracing::info!("Recovered {} entries from WAL", entries.len());
+        Ok(entries)
+    }
+    
+    /// Every intact entry in the log, in order
+    async fn read_entries(&self, file: &LogFile) -> Result<Vec<KVPair>> {
         let mut entries = Vec::new();
         
-        // Open file for reading from beginning
-        let mut read_file = File::open(&self.path).await
-            .map_err(|e| StorageError::Wal(format!("Failed to open WAL for recovery: {}", e)))?;
+        let read;
+        let bytes = match file {
+            LogFile::Memory { records } => records.as_slice(),
+            LogFile::Buffered { .. } | LogFile::Direct(_) => {
+                read = tokio::fs::read(&self.path).await
+                    .map_err(|e| StorageError::Wal(format!("Failed to read WAL for recovery: {}", e)))?;
+                read.as_slice()
+            }
+        };
         
         let mut position = 0;
-        let file_size = read_file.metadata().await
-            .map_err(|e| StorageError::Wal(format!("Failed to get WAL metadata: {}", e)))?
-            .len();
-        
-        while position < file_size {
-            // Read entry length
-            let entry_len = match read_file.read_u32().await {
-                Ok(len) => len,
-                Err(_) => break, // End of file or corruption
+        while position + HEADER_LEN <= bytes.len() {
+            let header: &[u8; HEADER_LEN] = bytes[position..position + HEADER_LEN].try_into().unwrap();
+            let Some(entry_len) = entry_len(header) else {
+                if header.iter().any(|&b| b != 0) {
+                    tracing::warn!("Corrupt WAL record header at position {}, ignoring the rest of the log", position);
+                }
+                break;
             };
-            position += 4;
+            position += HEADER_LEN;
             
-            if position + entry_len as u64 > file_size {
+            if position + entry_len > bytes.len() {
                 tracing::warn!("Truncated WAL entry at position {}, skipping", position);
                 break;
             }
             
-            // Read entry data
-            let mut entry_bytes = vec![0u8; entry_len as usize];
-            read_file.read_exact(&mut entry_bytes).await
-                .map_err(|e| StorageError::Wal(format!("Failed to read WAL entry: {}", e)))?;
-            position += entry_len as u64;
+            let entry_bytes = &bytes[position..position + entry_len];
+            position += entry_len;
             
             // Deserialize entry
-            match serde_json::from_slice::<WALEntry>(&entry_bytes) {
-                Ok(entry) => {
+            match serde_json::from_slice::<WALRecord>(entry_bytes) {
+                Ok(WALRecord::Batch(batch)) => {
+                    let data_bytes = serde_json::to_vec(&batch.batch)
+                        .map_err(|e| StorageError::Wal(format!("Failed to serialize for CRC check: {}", e)))?;
+                    if batch.crc != crc32fast::hash(&data_bytes) {
+                        tracing::warn!("CRC mismatch in WAL batch, skipping");
+                        continue;
+                    }
+                    
+                    entries.extend(batch.batch);
+                }
+                Ok(WALRecord::Single(entry)) => {
                     // Verify CRC
                     let data_bytes = serde_json::to_vec(&entry.data)
                         .map_err(|e| StorageError::Wal(format!("Failed to serialize for CRC check: {}", e)))?;
@@ -118,9 +373,7 @@ impl WriteAheadLog {
                         continue;
                     }
                     
-                    let sequence = entry.data.sequence;
                     entries.push(entry.data);
-                    self.sequence.store(sequence + 1, Ordering::SeqCst);
                 }
                 Err(e) => {
                     tracing::warn!("Failed to deserialize WAL entry: {}, skipping", e);
@@ -129,20 +382,81 @@ impl WriteAheadLog {
             }
         }
         
-        tracing::info!("Recovered {} entries from WAL", entries.len());
         Ok(entries)
     }
     
-    pub async fn truncate(&self) -> Result<()> {
+    /// Replace the log with one holding only `entries`. The old log is kept,
+    /// renamed to `archive_name` in the same directory.
+    pub async fn replace(&self, entries: &[KVPair], archive_name: &str) -> Result<()> {
         let mut file = self.file.lock().await;
-        file.seek(SeekFrom::Start(0)).await
-            .map_err(|e| StorageError::Wal(format!("Failed to seek WAL: {}", e)))?;
+        let wal_error = |action: &str, e: std::io::Error| StorageError::Wal(format!("Failed to {}: {}", action, e));
+        
+        if let LogFile::Memory { records } = &mut *file {
+            records.clear();
+            for kv_pair in entries {
+                let entry_bytes = Self::encode_entry(kv_pair)?;
+                records.extend_from_slice(&record_header(entry_bytes.len()));
+                records.extend_from_slice(&entry_bytes);
+            }
+            self.sequence.store(entries.last().map_or(0, |kv| kv.sequence + 1), Ordering::SeqCst);
+            return Ok(());
+        }
         
-        file.set_len(0).await
-            .map_err(|e| StorageError::Wal(format!("Failed to truncate WAL: {}", e)))?;
+        let temp_path = self.path.with_extension("log.tmp");
+        let mut replacement = File::create(&temp_path).await
+            .map_err(|e| wal_error("create replacement WAL", e))?;
+        for kv_pair in entries {
+            let entry_bytes = Self::encode_entry(kv_pair)?;
+            replacement.write_all(&record_header(entry_bytes.len())).await
+                .map_err(|e| wal_error("write replacement WAL", e))?;
+            replacement.write_all(&entry_bytes).await
+                .map_err(|e| wal_error("write replacement WAL", e))?;
+        }
+        replacement.sync_all().await
+            .map_err(|e| wal_error("sync replacement WAL", e))?;
+        drop(replacement);
         
-        file.sync_all().await
-            .map_err(|e| StorageError::Wal(format!("Failed to sync WAL after truncate: {}", e)))?;
+        tokio::fs::rename(&self.path, self.path.with_file_name(archive_name)).await
+            .map_err(|e| wal_error("archive WAL", e))?;
+        tokio::fs::rename(&temp_path, &self.path).await
+            .map_err(|e| wal_error("install replacement WAL", e))?;
+        *file = Self::open_file(&self.path, self.options).await?;
+        self.direct_io.store(matches!(*file, LogFile::Direct(_)), Ordering::Relaxed);
+        
+        self.sequence.store(entries.last().map_or(0, |kv| kv.sequence + 1), Ordering::SeqCst);
+        Ok(())
+    }
+    
+    /// Sync the log to disk, for writes appended without `Durability::Full`
+    pub async fn sync(&self) -> Result<()> {
+        let mut file = self.file.lock().await;
+        let result = match &mut *file {
+            LogFile::Buffered { file, .. } => file.sync_all().await,
+            LogFile::Direct(direct) => direct.sync(),
+            LogFile::Memory { .. } => Ok(()),
+        };
+        result.map_err(|e| write_error("Failed to sync WAL", e))
+    }
+    
+    pub async fn truncate(&self) -> Result<()> {
+        let mut file = self.file.lock().await;
+        match &mut *file {
+            LogFile::Buffered { file, .. } => {
+                file.seek(SeekFrom::Start(0)).await
+                    .map_err(|e| StorageError::Wal(format!("Failed to seek WAL: {}", e)))?;
+                
+                file.set_len(0).await
+                    .map_err(|e| StorageError::Wal(format!("Failed to truncate WAL: {}", e)))?;
+                
+                file.sync_all().await
+                    .map_err(|e| StorageError::Wal(format!("Failed to sync WAL after truncate: {}", e)))?;
+            }
+            LogFile::Direct(direct) => {
+                direct.truncate()
+                    .map_err(|e| StorageError::Wal(format!("Failed to truncate WAL: {}", e)))?;
+            }
+            LogFile::Memory { records } => records.clear(),
+        }
         
         self.sequence.store(0, Ordering::SeqCst);
         
@@ -154,6 +468,8 @@ impl WriteAheadLog {
 mod tests {
     use super::*;
     use tempfile::TempDir;
+    #[cfg(target_os = "linux")]
+    use crate::direct_io::ALIGNMENT;
     
     #[tokio::test]
     async fn test_wal_append_and_recover() {
@@ -180,4 +496,96 @@ mod tests {
         assert_eq!(recovered[2].key, kv3.key);
         assert!(recovered[2].value.is_none()); // Deletion
     }
+    
+    #[tokio::test]
+    async fn test_wal_batch_recovers_whole() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal = WriteAheadLog::open(temp_dir.path()).await.unwrap();
+        
+        wal.append(&KVPair::new(b"a".to_vec(), b"1".to_vec(), 1000, 1)).await.unwrap();
+        wal.append_batch(&[
+            KVPair::new(b"b".to_vec(), b"2".to_vec(), 1001, 2),
+            KVPair::delete(b"a".to_vec(), 1001, 3),
+        ]).await.unwrap();
+        
+        let recovered = wal.recover().await.unwrap();
+        let keys: Vec<&[u8]> = recovered.iter().map(|kv| kv.key.as_slice()).collect();
+        assert_eq!(keys, vec![b"a".as_slice(), b"b", b"a"]);
+        assert!(recovered[2].is_deleted());
+        
+        // A torn batch at the tail is dropped entirely
+        let len = std::fs::metadata(temp_dir.path().join("wal.log")).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(temp_dir.path().join("wal.log")).unwrap();
+        file.set_len(len - 5).unwrap();
+        assert_eq!(wal.recover().await.unwrap().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_corrupt_length_ends_log() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal.log");
+        let wal = WriteAheadLog::open(temp_dir.path()).await.unwrap();
+        
+        wal.append(&KVPair::new(b"a".to_vec(), b"1".to_vec(), 1000, 1)).await.unwrap();
+        let second = std::fs::metadata(&path).unwrap().len() as usize;
+        wal.append(&KVPair::new(b"b".to_vec(), b"2".to_vec(), 1001, 2)).await.unwrap();
+        wal.append(&KVPair::new(b"c".to_vec(), b"3".to_vec(), 1002, 3)).await.unwrap();
+        
+        // A length far past the end of the log, as a torn write might leave
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[second] = 0x7f;
+        std::fs::write(&path, &bytes).unwrap();
+        let recovered = wal.recover().await.unwrap();
+        assert_eq!(recovered.iter().map(|kv| kv.key.as_slice()).collect::<Vec<_>>(), vec![b"a".as_slice()]);
+        drop(wal);
+        
+        // Reopening cuts the log at the corrupt record and appends after it
+        let wal = WriteAheadLog::open(temp_dir.path()).await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len() as usize, second);
+        wal.append(&KVPair::new(b"d".to_vec(), b"4".to_vec(), 1003, 2)).await.unwrap();
+        let keys: Vec<Vec<u8>> = wal.recover().await.unwrap().into_iter().map(|kv| kv.key).collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"d".to_vec()]);
+    }
+    
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    #[ignore = "needs a temp directory on a file system that supports O_DIRECT"]
+    async fn test_wal_direct_io() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal.log");
+        let options = WalOptions { buffer_size: 512, direct_io: true, ..WalOptions::default() };
+        let wal = WriteAheadLog::open_with(temp_dir.path(), options).await.unwrap();
+        assert!(wal.is_direct_io());
+        
+        // Small appends share blocks; the large one spans several and
+        // outgrows the buffer
+        let mut expected: Vec<KVPair> = (0..50)
+            .map(|i| KVPair::new(format!("key{}", i).into_bytes(), vec![b'v'; 20], 1000, i + 1))
+            .collect();
+        expected.push(KVPair::new(b"large".to_vec(), vec![b'x'; 3 * ALIGNMENT], 1000, 51));
+        for kv in &expected {
+            wal.append(kv).await.unwrap();
+        }
+        assert_eq!(std::fs::metadata(&path).unwrap().len() % ALIGNMENT as u64, 0);
+        let keys = |entries: &[KVPair]| entries.iter().map(|kv| kv.key.clone()).collect::<Vec<_>>();
+        assert_eq!(keys(&wal.recover().await.unwrap()), keys(&expected));
+        drop(wal);
+        
+        // Reopening continues after the last record, not the padding
+        let wal = WriteAheadLog::open_with(temp_dir.path(), options).await.unwrap();
+        let kv = KVPair::new(b"after".to_vec(), b"1".to_vec(), 1000, 52);
+        wal.append(&kv).await.unwrap();
+        expected.push(kv);
+        assert_eq!(keys(&wal.recover().await.unwrap()), keys(&expected));
+        drop(wal);
+        
+        // So does a buffered log, which drops the padding
+        let wal = WriteAheadLog::open(temp_dir.path()).await.unwrap();
+        assert!(!wal.is_direct_io());
+        let kv = KVPair::new(b"buffered".to_vec(), b"2".to_vec(), 1000, 53);
+        wal.append(&kv).await.unwrap();
+        expected.push(kv);
+        assert_eq!(keys(&wal.recover().await.unwrap()), keys(&expected));
+        assert_eq!(wal.recover().await.unwrap()[50].value.as_ref().unwrap().len(), 3 * ALIGNMENT);
+    }
 }
\ No newline at end of file
@@ -1,4 +1,9 @@
-use nextdb_storage::{LSMTree, StorageConfig, KVPair};
+#![allow(clippy::field_reassign_with_default)]
+
+use futures::StreamExt;
+use nextdb_storage::{BackupInfo, BackupManifest, CompactionFilter, Decision, Durability, KVPair, LSMTree, MergeOperator, StallReason, StorageConfig, StorageError, WriteOp};
+use std::sync::Arc;
+use std::time::Duration;
 use tempfile::TempDir;
 
 #[tokio::test]
@@ -69,4 +74,1143 @@ async fn test_lsm_persistence() {
         let retrieved = lsm.get(&key).await.expect("Failed to get after reopen");
         assert_eq!(retrieved, Some(value));
     }
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_write_stall_reported_for_l0_backlog() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        l0_stall_trigger: 3,
+        ..Default::default()
+    };
+    
+    let lsm = LSMTree::open(config).await.expect("Failed to open LSM tree");
+    assert_eq!(lsm.write_stall_reason().await, None);
+    
+    // Each flush produces one L0 file and nothing compacts them away
+    for i in 0..3 {
+        lsm.put(format!("key{}", i).into_bytes(), b"value".to_vec()).await.expect("Failed to put");
+        lsm.flush().await.expect("Failed to flush");
+    }
+    
+    assert_eq!(
+        lsm.write_stall_reason().await,
+        Some(StallReason::TooManyL0Files { count: 3, limit: 3 })
+    );
+    assert_eq!(lsm.total_stall_time(), std::time::Duration::ZERO);
+    
+    // Writes still succeed but are slowed down and accounted for
+    lsm.put(b"stalled".to_vec(), b"value".to_vec()).await.expect("Failed to put");
+    assert_eq!(lsm.get(b"stalled").await.unwrap(), Some(b"value".to_vec()));
+    
+    let stats = lsm.stats().await;
+    assert_eq!(stats.level_file_counts[0], 3);
+    assert_eq!(stats.write_stall_count, 1);
+    assert!(lsm.total_stall_time() > std::time::Duration::ZERO);
+}
+
+#[tokio::test]
+async fn test_scan_merges_memtable_and_sstables() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    
+    let lsm = LSMTree::open(config).await.expect("Failed to open LSM tree");
+    
+    for i in 0..100u32 {
+        let key = format!("key_{:03}", i).into_bytes();
+        lsm.put(key, b"old".to_vec()).await.unwrap();
+    }
+    lsm.flush().await.unwrap();
+    
+    // Overwrite the even keys and delete every tenth one after the flush
+    for i in (0..100u32).step_by(2) {
+        let key = format!("key_{:03}", i).into_bytes();
+        lsm.put(key, b"new".to_vec()).await.unwrap();
+    }
+    for i in (0..100u32).step_by(10) {
+        let key = format!("key_{:03}", i).into_bytes();
+        lsm.delete(&key).await.unwrap();
+    }
+    lsm.put(b"other".to_vec(), b"x".to_vec()).await.unwrap();
+    
+    // Page through the range in small chunks
+    let mut seen = Vec::new();
+    let mut cursor = b"key_".to_vec();
+    loop {
+        let page = lsm.scan(&cursor, b"key_~", 7).await.unwrap();
+        if page.is_empty() {
+            break;
+        }
+        cursor = page.last().unwrap().0.clone();
+        cursor.push(0);
+        seen.extend(page);
+    }
+    
+    assert_eq!(seen.len(), 90);
+    assert!(seen.windows(2).all(|w| w[0].0 < w[1].0));
+    for (key, value) in &seen {
+        let i: u32 = std::str::from_utf8(&key[4..]).unwrap().parse().unwrap();
+        assert_ne!(i % 10, 0);
+        let expected: &[u8] = if i.is_multiple_of(2) { b"new" } else { b"old" };
+        assert_eq!(value.as_slice(), expected);
+    }
+}
+
+#[tokio::test]
+async fn test_scan_rev_yields_newest_versions_in_descending_order() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    
+    let lsm = LSMTree::open(config).await.expect("Failed to open LSM tree");
+    let key = |i: u32| format!("key_{:03}", i).into_bytes();
+    
+    // Values long enough to spread each SSTable over several blocks
+    for i in 0..300u32 {
+        lsm.put(key(i), vec![b'o'; 100]).await.unwrap();
+    }
+    lsm.flush().await.unwrap();
+    
+    // Overwrite the even keys and delete every tenth, half of it flushed to a
+    // second SSTable and half left in the memtable
+    for i in (0..300u32).step_by(2) {
+        lsm.put(key(i), b"new".to_vec()).await.unwrap();
+        if i == 150 {
+            lsm.flush().await.unwrap();
+        }
+    }
+    for i in (0..300u32).step_by(10) {
+        lsm.delete(&key(i)).await.unwrap();
+        if i == 100 {
+            lsm.flush().await.unwrap();
+        }
+    }
+    lsm.put(b"other".to_vec(), b"x".to_vec()).await.unwrap();
+    
+    // The last keys alone
+    let last: Vec<Vec<u8>> = lsm.scan_rev(b"key_", b"key_~", 3).await.unwrap().into_iter().map(|(key, _)| key).collect();
+    assert_eq!(last, vec![key(299), key(298), key(297)]);
+    
+    // Page back through the range in small chunks
+    let mut seen = Vec::new();
+    let mut cursor = b"key_~".to_vec();
+    loop {
+        let page = lsm.scan_rev(b"key_", &cursor, 7).await.unwrap();
+        if page.is_empty() {
+            break;
+        }
+        cursor = page.last().unwrap().0.clone();
+        seen.extend(page);
+    }
+    
+    assert_eq!(seen.len(), 270);
+    assert!(seen.windows(2).all(|w| w[0].0 > w[1].0));
+    for (key, value) in &seen {
+        let i: u32 = std::str::from_utf8(&key[4..]).unwrap().parse().unwrap();
+        assert_ne!(i % 10, 0);
+        let expected = if i.is_multiple_of(2) { b"new".to_vec() } else { vec![b'o'; 100] };
+        assert_eq!(value, &expected);
+    }
+    
+    // The same entries a forward scan finds, reversed
+    let mut forward = lsm.scan(b"key_", b"key_~", usize::MAX).await.unwrap();
+    forward.reverse();
+    assert_eq!(seen, forward);
+    assert_eq!(lsm.scan_rev(b"key_100", b"key_103", 10).await.unwrap().len(), 2);
+    assert!(lsm.scan_rev(b"key_~", b"key_", 10).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_ttl_sweeper_removes_expired_entries() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        ttl_sweep_interval_ms: 100,
+        ..Default::default()
+    };
+    
+    let lsm = std::sync::Arc::new(LSMTree::open(config).await.expect("Failed to open LSM tree"));
+    let sweeper = lsm.spawn_ttl_sweeper().expect("sweeper enabled");
+    
+    for i in 0..20u32 {
+        let key = format!("key_{:02}", i).into_bytes();
+        if i.is_multiple_of(2) {
+            lsm.put_with_ttl(key, b"short".to_vec(), Duration::from_millis(20)).await.unwrap();
+        } else {
+            lsm.put(key, b"kept".to_vec()).await.unwrap();
+        }
+    }
+    lsm.flush().await.unwrap();
+    assert_eq!(lsm.stats().await.sstable_entries, 20);
+    
+    // Expired keys read as missing right away, but stay on disk until swept
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(lsm.get(b"key_00").await.unwrap(), None);
+    
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let stats = lsm.stats().await;
+    assert_eq!(stats.sstable_entries, 10);
+    assert_eq!(stats.expired_entries_swept, 10);
+    
+    let remaining = lsm.scan(b"key_", b"key_~", 100).await.unwrap();
+    assert_eq!(remaining.len(), 10);
+    assert!(remaining.iter().all(|(_, value)| value == b"kept"));
+    
+    sweeper.abort();
+}
+
+#[tokio::test]
+async fn test_old_memtable_is_flushed() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        memtable_max_age_ms: 100,
+        ..Default::default()
+    };
+    
+    let lsm = Arc::new(LSMTree::open(config).await.expect("Failed to open LSM tree"));
+    let flusher = lsm.spawn_memtable_flusher().expect("flusher enabled");
+    
+    // An empty memtable is left alone
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(lsm.stats().await.level_file_counts[0], 0);
+    
+    // One small key, far below memtable_size_mb
+    lsm.put(b"key".to_vec(), b"value".to_vec()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(lsm.stats().await.level_file_counts[0], 0);
+    
+    let mut stats = lsm.stats().await;
+    for _ in 0..100 {
+        if stats.level_file_counts[0] > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        stats = lsm.stats().await;
+    }
+    assert_eq!(stats.level_file_counts[0], 1);
+    assert_eq!((stats.memtable_entries, stats.sstable_entries), (0, 1));
+    assert_eq!(lsm.get(b"key").await.unwrap(), Some(b"value".to_vec()));
+    
+    flusher.abort();
+}
+
+#[tokio::test]
+async fn test_write_batch_survives_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    
+    {
+        let lsm = LSMTree::open(config.clone()).await.unwrap();
+        lsm.put(b"old".to_vec(), b"1".to_vec()).await.unwrap();
+        lsm.write_batch(vec![
+            WriteOp::Put { key: b"new".to_vec(), value: b"2".to_vec() },
+            WriteOp::Delete { key: b"old".to_vec() },
+            WriteOp::Put { key: b"new".to_vec(), value: b"3".to_vec() },
+        ]).await.unwrap();
+        assert_eq!(lsm.get(b"old").await.unwrap(), None);
+        assert_eq!(lsm.get(b"new").await.unwrap(), Some(b"3".to_vec()));
+    }
+    
+    let lsm = LSMTree::open(config).await.unwrap();
+    assert_eq!(lsm.get(b"old").await.unwrap(), None);
+    assert_eq!(lsm.get(b"new").await.unwrap(), Some(b"3".to_vec()));
+    
+    // Later writes are ordered after the recovered batch
+    lsm.put(b"new".to_vec(), b"4".to_vec()).await.unwrap();
+    assert_eq!(lsm.get(b"new").await.unwrap(), Some(b"4".to_vec()));
+}
+
+async fn everything(lsm: &LSMTree) -> Vec<(Vec<u8>, Vec<u8>)> {
+    lsm.scan(b"", b"\xff", 1_000_000).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_backup_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = |name: &str| StorageConfig {
+        data_dir: temp_dir.path().join(name).join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join(name).join("wal").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    
+    // Spread versions of the same keys over two SSTables and the memtable,
+    // with more keys per source than one export page
+    let lsm = Arc::new(LSMTree::open(config("source")).await.unwrap());
+    for i in 0..3000u32 {
+        lsm.put(format!("key_{:05}", i).into_bytes(), b"v1".to_vec()).await.unwrap();
+    }
+    lsm.flush().await.unwrap();
+    for i in (0..3000u32).step_by(3) {
+        lsm.put(format!("key_{:05}", i).into_bytes(), b"v2".to_vec()).await.unwrap();
+    }
+    lsm.flush().await.unwrap();
+    for i in (0..3000u32).step_by(5) {
+        lsm.delete(format!("key_{:05}", i).as_bytes()).await.unwrap();
+    }
+    lsm.put(b"binary".to_vec(), vec![0, 255, b'\n', 7]).await.unwrap();
+    lsm.put_with_ttl(b"ttl_long".to_vec(), b"t".to_vec(), Duration::from_secs(3600)).await.unwrap();
+    lsm.put_with_ttl(b"ttl_gone".to_vec(), b"t".to_vec(), Duration::from_millis(1)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    
+    // Sequential writes racing with the export must show up as a prefix
+    let writer = {
+        let lsm = lsm.clone();
+        tokio::spawn(async move {
+            for i in 0..300u32 {
+                lsm.put(format!("race_{:05}", i).into_bytes(), b"r".to_vec()).await.unwrap();
+            }
+        })
+    };
+    let mut archive = Vec::new();
+    let exported = lsm.export_backup(&mut archive).await.unwrap();
+    writer.await.unwrap();
+    
+    let (restored, imported) = LSMTree::import_backup(config("restored"), archive.as_slice()).await.unwrap();
+    assert_eq!(imported, exported);
+    
+    let restored_entries = everything(&restored).await;
+    assert_eq!(restored_entries.len() as u64, exported.entries);
+    
+    let races: Vec<_> = restored_entries.iter().filter(|(key, _)| key.starts_with(b"race_")).collect();
+    for (i, (key, _)) in races.iter().enumerate() {
+        assert_eq!(key, &format!("race_{:05}", i).into_bytes());
+    }
+    let source_entries: Vec<_> = everything(&lsm).await.into_iter()
+        .filter(|(key, _)| !key.starts_with(b"race_") || races.iter().any(|(k, _)| k == key))
+        .collect();
+    assert_eq!(restored_entries, source_entries);
+    
+    assert_eq!(restored.get(b"key_00003").await.unwrap(), Some(b"v2".to_vec()));
+    assert_eq!(restored.get(b"key_00004").await.unwrap(), Some(b"v1".to_vec()));
+    assert_eq!(restored.get(b"key_00005").await.unwrap(), None);
+    assert_eq!(restored.get(b"binary").await.unwrap(), Some(vec![0, 255, b'\n', 7]));
+    assert_eq!(restored.get(b"ttl_long").await.unwrap(), Some(b"t".to_vec()));
+    assert_eq!(restored.get(b"ttl_gone").await.unwrap(), None);
+    
+    // The imported store is durable on its own
+    drop(restored);
+    let reopened = LSMTree::open(config("restored")).await.unwrap();
+    assert_eq!(everything(&reopened).await, restored_entries);
+    
+    // Importing needs an empty target and an intact archive
+    assert!(LSMTree::import_backup(config("restored"), archive.as_slice()).await.is_err());
+    let mut corrupt = archive.clone();
+    let middle = corrupt.len() / 2;
+    corrupt[middle] ^= 0x40;
+    assert!(LSMTree::import_backup(config("corrupt"), corrupt.as_slice()).await.is_err());
+    let truncated = &archive[..archive.len() - 10];
+    assert!(LSMTree::import_backup(config("truncated"), truncated).await.is_err());
+    assert!(LSMTree::import_backup(config("garbage"), &b"not a backup"[..]).await.is_err());
+}
+
+/// Write a full backup, or a differential one since `since`, to `path`
+async fn export_to(lsm: &LSMTree, path: &std::path::Path, since: Option<u64>) -> BackupInfo {
+    let mut file = tokio::fs::File::create(path).await.unwrap();
+    let info = match since {
+        Some(since) => lsm.export_differential_backup(&mut file, since).await.unwrap(),
+        None => lsm.export_backup(&mut file).await.unwrap(),
+    };
+    file.sync_all().await.unwrap();
+    info
+}
+
+#[tokio::test]
+async fn test_differential_backup_chain() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = |name: &str| StorageConfig {
+        data_dir: temp_dir.path().join(name).join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join(name).join("wal").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let backups = temp_dir.path().join("backups");
+    std::fs::create_dir_all(&backups).unwrap();
+    let mut manifest = BackupManifest::default();
+    
+    let lsm = LSMTree::open(config("source")).await.unwrap();
+    for i in 0..2000u32 {
+        lsm.put(format!("key_{:05}", i).into_bytes(), b"base".to_vec()).await.unwrap();
+    }
+    lsm.flush().await.unwrap();
+    let base = export_to(&lsm, &backups.join("base.bak"), None).await;
+    assert_eq!(base, BackupInfo { since: None, sequence: base.sequence, entries: 2000 });
+    manifest.push("base.bak", base).unwrap();
+    
+    // Overwrites, deletions and new keys, partly flushed to a new SSTable
+    for i in (0..2000u32).step_by(10) {
+        lsm.put(format!("key_{:05}", i).into_bytes(), b"diff1".to_vec()).await.unwrap();
+    }
+    lsm.flush().await.unwrap();
+    for i in (0..2000u32).step_by(25) {
+        lsm.delete(format!("key_{:05}", i).as_bytes()).await.unwrap();
+    }
+    lsm.put(b"new_key".to_vec(), b"diff1".to_vec()).await.unwrap();
+    let diff1 = export_to(&lsm, &backups.join("diff1.bak"), manifest.sequence()).await;
+    assert_eq!(diff1.since, Some(base.sequence));
+    // Only the keys changed since the base: 200 overwritten, 80 deleted (40
+    // of them overwritten first) and one new
+    assert_eq!(diff1.entries, 200 + 80 - 40 + 1);
+    manifest.push("diff1.bak", diff1).unwrap();
+    
+    lsm.delete(b"new_key").await.unwrap();
+    lsm.put(b"key_00025".to_vec(), b"diff2".to_vec()).await.unwrap();
+    lsm.put_with_ttl(b"key_00001".to_vec(), b"gone".to_vec(), Duration::from_millis(1)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let diff2 = export_to(&lsm, &backups.join("diff2.bak"), manifest.sequence()).await;
+    assert_eq!(diff2.entries, 3);
+    manifest.push("diff2.bak", diff2).unwrap();
+    
+    let manifest_path = backups.join("manifest.json");
+    manifest.save(&manifest_path).await.unwrap();
+    assert_eq!(BackupManifest::load(&manifest_path).await.unwrap(), manifest);
+    
+    let restored = LSMTree::restore_backup(config("restored"), &manifest_path).await.unwrap();
+    assert_eq!(everything(&restored).await, everything(&lsm).await);
+    assert_eq!(restored.get(b"key_00025").await.unwrap(), Some(b"diff2".to_vec()));
+    assert_eq!(restored.get(b"key_00050").await.unwrap(), None);
+    assert_eq!(restored.get(b"key_00001").await.unwrap(), None);
+    assert_eq!(restored.get(b"new_key").await.unwrap(), None);
+    
+    // A chain must start with a full backup and have no gaps
+    let open = |name: &str| std::fs::read(backups.join(name)).unwrap();
+    let (diff1_bytes, diff2_bytes, base_bytes) = (open("diff1.bak"), open("diff2.bak"), open("base.bak"));
+    assert!(LSMTree::import_backup_chain(config("headless"), vec![diff1_bytes.as_slice()]).await.is_err());
+    assert!(LSMTree::import_backup_chain(config("gap"), vec![base_bytes.as_slice(), diff2_bytes.as_slice()]).await.is_err());
+    assert!(manifest.clone().push("again.bak", base).is_err());
+    let mut gap = BackupManifest::default();
+    gap.push("base.bak", base).unwrap();
+    assert!(gap.push("diff2.bak", diff2).is_err());
+}
+
+#[tokio::test]
+async fn test_point_in_time_recovery() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let now = || std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+    
+    let before_writes = now() - 1;
+    let target = {
+        let lsm = LSMTree::open(config.clone()).await.unwrap();
+        lsm.put(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+        lsm.write_batch(vec![
+            WriteOp::Put { key: b"b".to_vec(), value: b"1".to_vec() },
+            WriteOp::Put { key: b"c".to_vec(), value: b"1".to_vec() },
+        ]).await.unwrap();
+        lsm.flush().await.unwrap();
+        
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let target = now();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        
+        // The erroneous writes
+        lsm.put(b"a".to_vec(), b"2".to_vec()).await.unwrap();
+        lsm.delete(b"b").await.unwrap();
+        lsm.write_batch(vec![
+            WriteOp::Put { key: b"d".to_vec(), value: b"2".to_vec() },
+            WriteOp::Delete { key: b"c".to_vec() },
+        ]).await.unwrap();
+        target
+    };
+    
+    {
+        let lsm = LSMTree::open_at(config.clone(), target).await.unwrap();
+        assert_eq!(everything(&lsm).await, vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"1".to_vec()),
+            (b"c".to_vec(), b"1".to_vec()),
+        ]);
+        lsm.put(b"e".to_vec(), b"3".to_vec()).await.unwrap();
+    }
+    
+    // The dropped writes stay dropped, and writes after recovery are kept
+    let lsm = LSMTree::open(config.clone()).await.unwrap();
+    assert_eq!(lsm.get(b"a").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(lsm.get(b"d").await.unwrap(), None);
+    assert_eq!(lsm.get(b"e").await.unwrap(), Some(b"3".to_vec()));
+    drop(lsm);
+    
+    // The full log was archived next to the new one
+    let archived: Vec<String> = std::fs::read_dir(temp_dir.path().join("wal")).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|name| name.starts_with(&format!("wal-{}-", target)))
+        .collect();
+    assert_eq!(archived.len(), 1);
+    
+    // Recovering to a time before every write leaves an empty store
+    let lsm = LSMTree::open_at(config, before_writes).await.unwrap();
+    assert!(everything(&lsm).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_changefeed_follows_writes() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let lsm = LSMTree::open(config).await.unwrap();
+    
+    lsm.put(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+    lsm.put(b"b".to_vec(), b"1".to_vec()).await.unwrap();
+    let live = lsm.changefeed(None).await.unwrap();
+    
+    lsm.put(b"c".to_vec(), b"1".to_vec()).await.unwrap();
+    lsm.delete(b"a").await.unwrap();
+    lsm.write_batch(vec![
+        WriteOp::Put { key: b"d".to_vec(), value: b"2".to_vec() },
+        WriteOp::Put { key: b"b".to_vec(), value: b"2".to_vec() },
+    ]).await.unwrap();
+    
+    let changes: Vec<KVPair> = live.take(4).map(Result::unwrap).collect().await;
+    let summary: Vec<(&[u8], Option<&[u8]>)> = changes.iter()
+        .map(|kv| (kv.key.as_slice(), kv.value.as_deref()))
+        .collect();
+    assert_eq!(summary, vec![
+        (&b"c"[..], Some(&b"1"[..])),
+        (&b"a"[..], None),
+        (&b"d"[..], Some(&b"2"[..])),
+        (&b"b"[..], Some(&b"2"[..])),
+    ]);
+    assert!(changes.windows(2).all(|pair| pair[1].sequence == pair[0].sequence + 1));
+    
+    // Starting from a sequence replays the log from there, then follows it
+    let from = changes[0].sequence - 1;
+    let mut replay = lsm.changefeed(Some(from)).await.unwrap();
+    lsm.put(b"e".to_vec(), b"3".to_vec()).await.unwrap();
+    let mut sequences = Vec::new();
+    let mut last_key = Vec::new();
+    for _ in 0..6 {
+        let kv = tokio::time::timeout(Duration::from_secs(5), replay.next()).await.unwrap().unwrap().unwrap();
+        sequences.push(kv.sequence);
+        last_key = kv.key;
+    }
+    assert_eq!(sequences, (from..from + 6).collect::<Vec<_>>());
+    assert_eq!(last_key, b"e");
+}
+
+#[tokio::test]
+async fn test_durability_levels() {
+    for durability in [Durability::Full, Durability::NoSync, Durability::Memory] {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+            wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+            durability,
+            ..Default::default()
+        };
+        
+        let lsm = LSMTree::open(config.clone()).await.unwrap();
+        lsm.put(b"key".to_vec(), b"value".to_vec()).await.unwrap();
+        assert_eq!(lsm.get(b"key").await.unwrap(), Some(b"value".to_vec()), "{:?}", durability);
+        // Every level keeps the log changefeeds replay from
+        let replayed = lsm.changefeed(Some(0)).await.unwrap().next().await.unwrap().unwrap();
+        assert_eq!(replayed.key, b"key", "{:?}", durability);
+        drop(lsm);
+        
+        // Only an in-memory log leaves no file behind and forgets on drop
+        let wal_created = temp_dir.path().join("wal").exists();
+        let recovered = LSMTree::open(config).await.unwrap().get(b"key").await.unwrap();
+        if durability == Durability::Memory {
+            assert!(!wal_created);
+            assert_eq!(recovered, None);
+        } else {
+            assert!(wal_created, "{:?}", durability);
+            assert_eq!(recovered, Some(b"value".to_vec()), "{:?}", durability);
+        }
+    }
+}
+
+/// Drops `tmp:` keys and upper-cases the values of `upper:` keys
+struct PrefixFilter;
+
+impl CompactionFilter for PrefixFilter {
+    fn filter(&self, key: &[u8], value: &[u8]) -> Decision {
+        if key.starts_with(b"tmp:") {
+            Decision::Remove
+        } else if key.starts_with(b"upper:") {
+            Decision::Change(value.to_ascii_uppercase())
+        } else {
+            Decision::Keep
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_compaction_filter() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    
+    let lsm = LSMTree::open(config).await.unwrap().with_compaction_filter(Arc::new(PrefixFilter));
+    for i in 0..5 {
+        lsm.put(format!("tmp:{}", i).into_bytes(), b"scratch".to_vec()).await.unwrap();
+        lsm.put(format!("keep:{}", i).into_bytes(), b"old".to_vec()).await.unwrap();
+    }
+    lsm.flush().await.unwrap();
+    for i in 0..5 {
+        lsm.put(format!("keep:{}", i).into_bytes(), b"new".to_vec()).await.unwrap();
+        lsm.put(format!("upper:{}", i).into_bytes(), b"value".to_vec()).await.unwrap();
+    }
+    lsm.delete(b"keep:4").await.unwrap();
+    lsm.flush().await.unwrap();
+    
+    // Nothing is filtered before compaction
+    assert_eq!(lsm.get(b"tmp:0").await.unwrap(), Some(b"scratch".to_vec()));
+    
+    lsm.compact().await.unwrap();
+    let stats = lsm.stats().await;
+    assert_eq!(stats.level_file_counts.iter().sum::<usize>(), 1);
+    assert_eq!(stats.sstable_entries, 9);
+    
+    assert!(lsm.scan(b"tmp:", b"tmp:~", 100).await.unwrap().is_empty());
+    let kept = lsm.scan(b"keep:", b"keep:~", 100).await.unwrap();
+    assert_eq!(kept.len(), 4);
+    assert!(kept.iter().all(|(_, value)| value == b"new"));
+    assert_eq!(lsm.get(b"keep:4").await.unwrap(), None);
+    assert_eq!(lsm.get(b"upper:2").await.unwrap(), Some(b"VALUE".to_vec()));
+}
+
+#[tokio::test]
+async fn test_row_cache() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        row_cache_size_mb: 1,
+        // Compacted only when the test says
+        l0_compaction_trigger: 10,
+        ..Default::default()
+    };
+    
+    let lsm = LSMTree::open(config).await.unwrap().with_compaction_filter(Arc::new(PrefixFilter));
+    lsm.put(b"key".to_vec(), b"v1".to_vec()).await.unwrap();
+    lsm.put(b"upper:key".to_vec(), b"value".to_vec()).await.unwrap();
+    lsm.put_with_ttl(b"short".to_vec(), b"lived".to_vec(), Duration::from_millis(100)).await.unwrap();
+    lsm.flush().await.unwrap();
+    
+    // A repeated point read is served by the row cache, without reading a
+    // block
+    assert_eq!(lsm.get(b"key").await.unwrap(), Some(b"v1".to_vec()));
+    let blocks_read = |stats: &nextdb_storage::LSMStats| stats.cache.hits + stats.cache.misses;
+    let before = lsm.stats().await;
+    assert_eq!(lsm.get(b"key").await.unwrap(), Some(b"v1".to_vec()));
+    let after = lsm.stats().await;
+    assert_eq!(blocks_read(&after), blocks_read(&before));
+    assert_eq!(after.row_cache.unwrap().hits, before.row_cache.unwrap().hits + 1);
+    
+    // A write to the key invalidates it, in the memtable and once flushed
+    lsm.put(b"key".to_vec(), b"v2".to_vec()).await.unwrap();
+    assert_eq!(lsm.get(b"key").await.unwrap(), Some(b"v2".to_vec()));
+    lsm.flush().await.unwrap();
+    assert_eq!(lsm.get(b"key").await.unwrap(), Some(b"v2".to_vec()));
+    lsm.delete(b"key").await.unwrap();
+    lsm.flush().await.unwrap();
+    assert_eq!(lsm.get(b"key").await.unwrap(), None);
+    lsm.write_batch(vec![WriteOp::Put { key: b"key".to_vec(), value: b"v3".to_vec() }]).await.unwrap();
+    lsm.flush().await.unwrap();
+    assert_eq!(lsm.get(b"key").await.unwrap(), Some(b"v3".to_vec()));
+    
+    // Cached values expire, and a compaction filter's changes show
+    assert_eq!(lsm.get(b"short").await.unwrap(), Some(b"lived".to_vec()));
+    assert_eq!(lsm.get(b"upper:key").await.unwrap(), Some(b"value".to_vec()));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(lsm.get(b"short").await.unwrap(), None);
+    lsm.compact().await.unwrap();
+    assert_eq!(lsm.get(b"upper:key").await.unwrap(), Some(b"VALUE".to_vec()));
+}
+
+/// Adds big-endian i64 operands to the existing count
+struct AddOperator;
+
+impl MergeOperator for AddOperator {
+    fn merge(&self, existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+        let decode = |bytes: &[u8]| i64::from_be_bytes(bytes.try_into().unwrap());
+        let total = operands.iter().fold(existing.map_or(0, decode), |sum, operand| sum + decode(operand));
+        total.to_be_bytes().to_vec()
+    }
+}
+
+#[tokio::test]
+async fn test_merge_operator_counters() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let count = |value: Option<Vec<u8>>| value.map(|bytes| i64::from_be_bytes(bytes.try_into().unwrap()));
+    
+    let lsm = LSMTree::open(config.clone()).await.unwrap();
+    assert!(lsm.merge(b"hits".to_vec(), 1i64.to_be_bytes().to_vec()).await.is_err());
+    let lsm = lsm.with_merge_operator(Arc::new(AddOperator));
+    
+    for amount in [1i64, 2, 3] {
+        lsm.merge(b"hits".to_vec(), amount.to_be_bytes().to_vec()).await.unwrap();
+    }
+    assert_eq!(count(lsm.get(b"hits").await.unwrap()), Some(6));
+    
+    // Merges fold onto flushed values, a put resets the count and a delete
+    // starts it over from nothing
+    lsm.flush().await.unwrap();
+    lsm.merge(b"hits".to_vec(), 10i64.to_be_bytes().to_vec()).await.unwrap();
+    assert_eq!(count(lsm.get(b"hits").await.unwrap()), Some(16));
+    lsm.put(b"reset".to_vec(), 100i64.to_be_bytes().to_vec()).await.unwrap();
+    lsm.merge(b"reset".to_vec(), (-1i64).to_be_bytes().to_vec()).await.unwrap();
+    lsm.delete(b"gone").await.unwrap();
+    lsm.merge(b"gone".to_vec(), 5i64.to_be_bytes().to_vec()).await.unwrap();
+    
+    let scanned: Vec<_> = lsm.scan(b"a", b"z", 10).await.unwrap()
+        .into_iter()
+        .map(|(key, value)| (key, count(Some(value)).unwrap()))
+        .collect();
+    assert_eq!(scanned, vec![(b"gone".to_vec(), 5), (b"hits".to_vec(), 16), (b"reset".to_vec(), 99)]);
+    
+    // Flushing writes the folded value, and the WAL replays the operands
+    lsm.flush().await.unwrap();
+    assert_eq!(count(lsm.get(b"hits").await.unwrap()), Some(16));
+    drop(lsm);
+    let lsm = LSMTree::open(config).await.unwrap().with_merge_operator(Arc::new(AddOperator));
+    assert_eq!(count(lsm.get(b"hits").await.unwrap()), Some(16));
+    assert_eq!(count(lsm.get(b"reset").await.unwrap()), Some(99));
+}
+
+#[tokio::test]
+async fn test_close_reopens_without_wal_replay() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        durability: Durability::NoSync,
+        ..Default::default()
+    };
+    let key = |i: u32| format!("key{:03}", i).into_bytes();
+    
+    {
+        let lsm = LSMTree::open(config.clone()).await.unwrap();
+        for i in 0..50 {
+            lsm.put(key(i), b"old".to_vec()).await.unwrap();
+        }
+        lsm.flush().await.unwrap();
+        for i in 25..100 {
+            lsm.put(key(i), b"new".to_vec()).await.unwrap();
+        }
+        lsm.delete(&key(7)).await.unwrap();
+        lsm.close().await.unwrap();
+        
+        // Closed trees still read but refuse writes
+        assert_eq!(lsm.get(&key(30)).await.unwrap(), Some(b"new".to_vec()));
+        assert!(matches!(lsm.put(key(0), b"late".to_vec()).await, Err(StorageError::Closed)));
+    }
+    
+    {
+        let lsm = LSMTree::open(config.clone()).await.unwrap();
+        assert_eq!(lsm.stats().await.wal_entries_replayed, 0);
+        let entries = everything(&lsm).await;
+        assert_eq!(entries.len(), 99);
+        assert_eq!(lsm.get(&key(7)).await.unwrap(), None);
+        assert_eq!(lsm.get(&key(10)).await.unwrap(), Some(b"old".to_vec()));
+        assert_eq!(lsm.get(&key(30)).await.unwrap(), Some(b"new".to_vec()));
+        
+        // This session ends in a crash
+        lsm.put(key(7), b"again".to_vec()).await.unwrap();
+    }
+    
+    // which the WAL recovers from, with every write since the first open
+    let lsm = LSMTree::open(config).await.unwrap();
+    assert!(lsm.stats().await.wal_entries_replayed > 0);
+    assert_eq!(everything(&lsm).await.len(), 100);
+    assert_eq!(lsm.get(&key(7)).await.unwrap(), Some(b"again".to_vec()));
+}
+
+#[tokio::test]
+async fn test_open_skips_corrupt_sstables() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        durability: Durability::NoSync,
+        ..Default::default()
+    };
+    
+    // One SSTable of a keys and one of b keys
+    let lsm = LSMTree::open(config.clone()).await.unwrap();
+    for prefix in ["a", "b"] {
+        for i in 0..20 {
+            lsm.put(format!("{}{:02}", prefix, i).into_bytes(), b"value".to_vec()).await.unwrap();
+        }
+        lsm.flush().await.unwrap();
+    }
+    let files = lsm.sstables().await.concat();
+    assert_eq!(files.len(), 2);
+    lsm.close().await.unwrap();
+    drop(lsm);
+    
+    // Damage the footer of the b file
+    let bad = files.iter().find(|file| file.smallest_key.as_deref() == Some(b"b00".as_slice())).unwrap();
+    let path = temp_dir.path().join("data").join(&bad.file);
+    let mut bytes = std::fs::read(&path).unwrap();
+    let footer = bytes.len() - 256;
+    bytes[footer..].fill(b'x');
+    std::fs::write(&path, bytes).unwrap();
+    
+    // Without the option the store does not open
+    assert!(matches!(LSMTree::open(config.clone()).await, Err(StorageError::Corruption(_))));
+    
+    let lsm = LSMTree::open(StorageConfig { skip_corrupt_sstables: true, ..config }).await.unwrap();
+    let skipped = lsm.skipped_sstables();
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].file, bad.file);
+    assert!(skipped[0].error.contains("footer"), "{}", skipped[0].error);
+    assert_eq!(lsm.get(b"a05").await.unwrap(), Some(b"value".to_vec()));
+    assert_eq!(lsm.get(b"b05").await.unwrap(), None);
+    assert_eq!(lsm.sstables().await.concat().len(), 1);
+    assert!(path.exists());
+}
+
+#[tokio::test]
+async fn test_rejects_keys_over_the_size_limit() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        max_key_size_bytes: 16,
+        ..Default::default()
+    };
+    let wal_bytes = || -> u64 {
+        std::fs::read_dir(temp_dir.path().join("wal")).unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+    
+    let lsm = LSMTree::open(config.clone()).await.unwrap();
+    lsm.put(vec![b'k'; 16], b"fits".to_vec()).await.unwrap();
+    lsm.sync_wal().await.unwrap();
+    let logged = wal_bytes();
+    
+    let long = vec![b'k'; 17];
+    let too_large = |result: Result<(), StorageError>| {
+        assert!(matches!(result, Err(StorageError::KeyTooLarge { size: 17, limit: 16 })), "{:?}", result);
+    };
+    too_large(lsm.put(long.clone(), b"value".to_vec()).await);
+    too_large(lsm.delete(&long).await);
+    too_large(lsm.write_batch(vec![
+        WriteOp::Put { key: b"short".to_vec(), value: b"value".to_vec() },
+        WriteOp::Delete { key: long.clone() },
+    ]).await);
+    
+    // Nothing was logged or applied, not even the batch's short key
+    lsm.sync_wal().await.unwrap();
+    assert_eq!(wal_bytes(), logged);
+    assert_eq!(lsm.get(b"short").await.unwrap(), None);
+    drop(lsm);
+    
+    let lsm = LSMTree::open(config).await.unwrap();
+    assert_eq!(lsm.stats().await.wal_entries_replayed, 1);
+    assert_eq!(lsm.get(&[b'k'; 16]).await.unwrap(), Some(b"fits".to_vec()));
+}
+
+#[tokio::test]
+async fn test_compact_range() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        // Kept clear of the flushes below, which would compact L0 otherwise
+        l0_compaction_trigger: 10,
+        ..Default::default()
+    };
+    let lsm = LSMTree::open(config).await.unwrap();
+    
+    // Files holding a..., b..., and a file spanning b... and c...
+    for prefix in ["a", "b", "b"] {
+        for i in 0..10 {
+            lsm.put(format!("{}{}", prefix, i).into_bytes(), b"old".to_vec()).await.unwrap();
+        }
+        lsm.flush().await.unwrap();
+    }
+    lsm.put(b"b5".to_vec(), b"new".to_vec()).await.unwrap();
+    lsm.delete(b"b6").await.unwrap();
+    lsm.put(b"c0".to_vec(), b"new".to_vec()).await.unwrap();
+    lsm.flush().await.unwrap();
+    let files: Vec<usize> = lsm.sstables().await.iter().map(Vec::len).collect();
+    assert_eq!(files[..2], [4, 0]);
+    
+    // Compacting c... takes in every file its keys interleave with, and
+    // leaves the a... file alone
+    let summary = lsm.compact_range(b"c", None).await.unwrap();
+    assert_eq!((summary.input_files, summary.output_files), (3, 1));
+    assert!(summary.output_bytes > 0);
+    let sstables = lsm.sstables().await;
+    assert_eq!(sstables[0].len(), 1);
+    assert_eq!(sstables[0][0].smallest_key.as_deref(), Some(&b"a0"[..]));
+    assert_eq!(sstables[0][0].largest_key.as_deref(), Some(&b"a9"[..]));
+    let last = sstables.last().unwrap();
+    assert_eq!((last[0].smallest_key.as_deref(), last[0].largest_key.as_deref()), (Some(&b"b0"[..]), Some(&b"c0"[..])));
+    assert_eq!(last[0].entries, 10);
+    
+    assert_eq!(lsm.get(b"a3").await.unwrap(), Some(b"old".to_vec()));
+    assert_eq!(lsm.get(b"b5").await.unwrap(), Some(b"new".to_vec()));
+    assert_eq!(lsm.get(b"b6").await.unwrap(), None);
+    assert_eq!(everything(&lsm).await.len(), 20);
+    
+    // A range no file holds keys in does nothing
+    assert_eq!(lsm.compact_range(b"x", Some(b"y")).await.unwrap(), Default::default());
+}
+
+#[tokio::test]
+async fn test_checkpoint_opens_as_a_copy() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let lsm = LSMTree::open(config).await.unwrap();
+    for i in 0..20 {
+        lsm.put(format!("key{:02}", i).into_bytes(), b"flushed".to_vec()).await.unwrap();
+    }
+    lsm.flush().await.unwrap();
+    lsm.put(b"key05".to_vec(), b"unflushed".to_vec()).await.unwrap();
+    lsm.delete(b"key06").await.unwrap();
+    
+    let dir = temp_dir.path().join("checkpoint");
+    let info = lsm.checkpoint(&dir).await.unwrap();
+    assert_eq!(info.files, 2);
+    assert!(info.bytes > 0);
+    assert!(lsm.checkpoint(&dir).await.is_err());
+    
+    // Later writes are not in it, and compacting the original leaves it whole
+    lsm.put(b"key99".to_vec(), b"later".to_vec()).await.unwrap();
+    lsm.compact().await.unwrap();
+    
+    let copy = LSMTree::open(StorageConfig {
+        data_dir: dir.to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("checkpoint-wal").to_string_lossy().to_string(),
+        ..Default::default()
+    }).await.unwrap();
+    assert_eq!(everything(&copy).await.len(), 19);
+    assert_eq!(copy.get(b"key05").await.unwrap(), Some(b"unflushed".to_vec()));
+    assert_eq!(copy.get(b"key06").await.unwrap(), None);
+    assert_eq!(copy.get(b"key99").await.unwrap(), None);
+    copy.put(b"key99".to_vec(), b"copy".to_vec()).await.unwrap();
+    assert_eq!(lsm.get(b"key99").await.unwrap(), Some(b"later".to_vec()));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_newest_value_wins_during_concurrent_flushes() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        max_immutable_memtables: 64,
+        ..Default::default()
+    };
+    let lsm = Arc::new(LSMTree::open(config).await.unwrap());
+    let keys: Vec<Vec<u8>> = (0..4).map(|k| format!("key_{}", k).into_bytes()).collect();
+    let version = |value: Option<Vec<u8>>| value.map(|v| u32::from_be_bytes(v.try_into().unwrap()));
+    
+    // Flushes rotate memtables while earlier ones are still being written
+    // out, so several immutable memtables hold versions of each key
+    let flushers: Vec<_> = (0..3).map(|_| {
+        let lsm = lsm.clone();
+        tokio::spawn(async move {
+            for _ in 0..40 {
+                lsm.flush().await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        })
+    }).collect();
+    // A reader never sees a key go back to an older version
+    let readers: Vec<_> = (0..3).map(|_| {
+        let (lsm, keys) = (lsm.clone(), keys.clone());
+        tokio::spawn(async move {
+            let mut seen = vec![None; keys.len()];
+            for _ in 0..500 {
+                for (key, seen) in keys.iter().zip(&mut seen) {
+                    let current = version(lsm.get(key).await.unwrap());
+                    assert!(current >= *seen, "{:?} went back from {:?} to {:?}", key, seen, current);
+                    *seen = current;
+                }
+                tokio::task::yield_now().await;
+            }
+        })
+    }).collect();
+    
+    // and the writer always reads back what it just wrote
+    for i in 0..400u32 {
+        let key = &keys[i as usize % keys.len()];
+        lsm.put(key.clone(), i.to_be_bytes().to_vec()).await.unwrap();
+        assert_eq!(version(lsm.get(key).await.unwrap()), Some(i));
+        if i % 10 == 0 {
+            tokio::task::yield_now().await;
+        }
+    }
+    for task in flushers.into_iter().chain(readers) {
+        task.await.unwrap();
+    }
+    
+    lsm.flush().await.unwrap();
+    for (k, key) in keys.iter().enumerate() {
+        assert_eq!(version(lsm.get(key).await.unwrap()), Some(396 + k as u32));
+    }
+}
+
+#[tokio::test]
+async fn test_multi_get() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    
+    let lsm = LSMTree::open(config).await.unwrap();
+    for key in [b"a", b"b", b"c", b"d"] {
+        lsm.put(key.to_vec(), key.repeat(2)).await.unwrap();
+    }
+    lsm.flush().await.unwrap();
+    // Newer versions in the memtable shadow the flushed ones
+    lsm.put(b"b".to_vec(), b"new".to_vec()).await.unwrap();
+    lsm.delete(b"c").await.unwrap();
+    lsm.put(b"e".to_vec(), b"ee".to_vec()).await.unwrap();
+    
+    let keys: Vec<Vec<u8>> = [&b"e"[..], b"c", b"missing", b"a", b"b", b"a", b"d"].iter().map(|k| k.to_vec()).collect();
+    let values = lsm.multi_get(&keys).await.unwrap();
+    let expected: Vec<Option<Vec<u8>>> = vec![
+        Some(b"ee".to_vec()), None, None, Some(b"aa".to_vec()),
+        Some(b"new".to_vec()), Some(b"aa".to_vec()), Some(b"dd".to_vec()),
+    ];
+    assert_eq!(values, expected);
+    for (key, value) in keys.iter().zip(&values) {
+        assert_eq!(&lsm.get(key).await.unwrap(), value);
+    }
+    assert!(lsm.multi_get(&[]).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_l0_compaction_triggered_by_size() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        l0_compaction_trigger: 100,
+        l0_compaction_bytes: 64 * 1024,
+        compression: nextdb_storage::CompressionType::None,
+        ..Default::default()
+    };
+    
+    let lsm = LSMTree::open(config).await.unwrap();
+    // One file of about 40KB stays under the byte trigger
+    for i in 0..10 {
+        lsm.put(format!("key{}", i).into_bytes(), vec![b'a'; 4096]).await.unwrap();
+    }
+    lsm.flush().await.unwrap();
+    assert_eq!(lsm.stats().await.level_file_counts[0], 1);
+    
+    // The second takes L0 past it with far fewer files than the count trigger
+    for i in 5..15 {
+        lsm.put(format!("key{}", i).into_bytes(), vec![b'b'; 4096]).await.unwrap();
+    }
+    lsm.flush().await.unwrap();
+    let stats = lsm.stats().await;
+    assert_eq!(stats.level_file_counts[0], 0);
+    assert_eq!(stats.level_file_counts.iter().sum::<usize>(), 1);
+    
+    assert_eq!(lsm.get(b"key0").await.unwrap(), Some(vec![b'a'; 4096]));
+    assert_eq!(lsm.get(b"key7").await.unwrap(), Some(vec![b'b'; 4096]));
+    assert_eq!(lsm.get(b"key14").await.unwrap(), Some(vec![b'b'; 4096]));
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_out_of_space_turns_read_only() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = StorageConfig {
+        data_dir: temp_dir.path().join("data").to_string_lossy().to_string(),
+        wal_dir: temp_dir.path().join("wal").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let lsm = LSMTree::open(config.clone()).await.unwrap();
+    for i in 0..10 {
+        lsm.put(format!("key{}", i).into_bytes(), b"value".to_vec()).await.unwrap();
+    }
+    lsm.close().await.unwrap();
+    
+    // Every write to /dev/full fails with ENOSPC
+    let wal = temp_dir.path().join("wal").join("wal.log");
+    std::fs::remove_file(&wal).unwrap();
+    std::os::unix::fs::symlink("/dev/full", &wal).unwrap();
+    let lsm = LSMTree::open(config).await.unwrap();
+    assert!(!lsm.is_read_only());
+    
+    assert!(matches!(lsm.put(b"key0".to_vec(), b"new".to_vec()).await, Err(StorageError::OutOfSpace(_))));
+    assert!(lsm.is_read_only());
+    // Later writes are refused without reaching the WAL
+    assert!(matches!(lsm.delete(b"key1").await, Err(StorageError::OutOfSpace(_))));
+    let batch = vec![WriteOp::Put { key: b"other".to_vec(), value: b"v".to_vec() }];
+    assert!(matches!(lsm.write_batch(batch).await, Err(StorageError::OutOfSpace(_))));
+    
+    // Reads see what was there before
+    assert_eq!(lsm.get(b"key0").await.unwrap(), Some(b"value".to_vec()));
+    assert_eq!(lsm.get(b"key1").await.unwrap(), Some(b"value".to_vec()));
+    assert_eq!(lsm.get(b"other").await.unwrap(), None);
+    assert_eq!(lsm.scan(b"key", b"key~", 100).await.unwrap().len(), 10);
+}
+
+#[tokio::test]
+async fn test_ephemeral_store_deletes_its_directories() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = temp_dir.path().join("store");
+    let config = StorageConfig {
+        data_dir: store.join("data").to_string_lossy().to_string(),
+        wal_dir: store.join("wal").to_string_lossy().to_string(),
+        ephemeral: true,
+        ..Default::default()
+    };
+    
+    let lsm = LSMTree::open(config.clone()).await.unwrap();
+    lsm.put(b"key".to_vec(), b"value".to_vec()).await.unwrap();
+    lsm.flush().await.unwrap();
+    lsm.put(b"unflushed".to_vec(), b"value".to_vec()).await.unwrap();
+    assert!(store.join("data").read_dir().unwrap().next().is_some());
+    lsm.close().await.unwrap();
+    assert!(!store.exists());
+    assert!(temp_dir.path().exists());
+    
+    // Directories that were there before are left alone
+    let kept = temp_dir.path().join("kept");
+    std::fs::create_dir(&kept).unwrap();
+    let config = StorageConfig {
+        data_dir: kept.to_string_lossy().to_string(),
+        wal_dir: kept.join("wal").to_string_lossy().to_string(),
+        ..config
+    };
+    let lsm = LSMTree::open(config).await.unwrap();
+    lsm.put(b"key".to_vec(), b"value".to_vec()).await.unwrap();
+    lsm.close().await.unwrap();
+    assert!(kept.exists());
+    assert!(!kept.join("wal").exists());
+}
@@ -0,0 +1,223 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Assumed bound on clock skew between nodes unless configured otherwise
+pub const DEFAULT_MAX_OFFSET: Duration = Duration::from_millis(250);
+
+/// Source of wall-clock time for the hybrid logical clock
+pub trait PhysicalClock: Send + Sync {
+    /// Milliseconds since the Unix epoch. May jump backwards.
+    fn now_millis(&self) -> u64;
+}
+
+/// Physical clock backed by `SystemTime`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl PhysicalClock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+}
+
+/// Hybrid logical clock timestamp: wall-clock milliseconds plus a logical
+/// counter that orders events sharing the same millisecond
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct HlcTimestamp {
+    pub physical: u64,
+    pub logical: u32,
+}
+
+impl HlcTimestamp {
+    pub fn new(physical: u64, logical: u32) -> Self {
+        Self { physical, logical }
+    }
+    
+    /// Smallest timestamp strictly greater than `self`
+    fn successor(self) -> Self {
+        match self.logical.checked_add(1) {
+            Some(logical) => Self::new(self.physical, logical),
+            None => Self::new(self.physical + 1, 0),
+        }
+    }
+}
+
+impl fmt::Display for HlcTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.physical, self.logical)
+    }
+}
+
+/// Hybrid logical clock. Timestamps from one clock are strictly increasing
+/// even if the physical clock goes backwards, and a timestamp issued after
+/// `update` with a remote timestamp is greater than that remote timestamp,
+/// so causally related events are ordered across nodes.
+pub struct HybridLogicalClock {
+    physical_clock: Arc<dyn PhysicalClock>,
+    last: Mutex<HlcTimestamp>,
+    max_offset: Duration,
+}
+
+impl Default for HybridLogicalClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HybridLogicalClock {
+    pub fn new() -> Self {
+        Self::with_physical_clock(Arc::new(SystemClock))
+    }
+    
+    pub fn with_physical_clock(physical_clock: Arc<dyn PhysicalClock>) -> Self {
+        Self {
+            physical_clock,
+            last: Mutex::new(HlcTimestamp::default()),
+            max_offset: DEFAULT_MAX_OFFSET,
+        }
+    }
+    
+    /// Set the bound on how far any node's physical clock may be from this one's
+    pub fn with_max_offset(mut self, max_offset: Duration) -> Self {
+        self.max_offset = max_offset;
+        self
+    }
+    
+    pub fn max_offset(&self) -> Duration {
+        self.max_offset
+    }
+    
+    /// Timestamp for a local event, or for a message about to be sent
+    pub fn now(&self) -> HlcTimestamp {
+        let physical = self.physical_clock.now_millis();
+        let mut last = self.last.lock().unwrap();
+        
+        *last = if physical > last.physical {
+            HlcTimestamp::new(physical, 0)
+        } else {
+            last.successor()
+        };
+        *last
+    }
+    
+    /// Merge a timestamp received from another node and return a timestamp
+    /// for the receive event, greater than both `remote` and anything this
+    /// clock has issued
+    pub fn update(&self, remote: HlcTimestamp) -> HlcTimestamp {
+        let physical = self.physical_clock.now_millis();
+        let mut last = self.last.lock().unwrap();
+        
+        let newest = (*last).max(remote);
+        *last = if physical > newest.physical {
+            HlcTimestamp::new(physical, 0)
+        } else {
+            newest.successor()
+        };
+        *last
+    }
+    
+    /// Wait until `timestamp` has passed on every node: until this node's
+    /// physical clock is more than `max_offset` beyond it. Returns the time
+    /// spent waiting.
+    pub async fn wait_out_uncertainty(&self, timestamp: HlcTimestamp) -> Duration {
+        let started = Instant::now();
+        let target = timestamp.physical + self.max_offset.as_millis() as u64;
+        loop {
+            let now = self.physical_clock.now_millis();
+            if now > target {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(target - now + 1)).await;
+        }
+        started.elapsed()
+    }
+    
+    /// Most recent timestamp issued, without advancing the clock
+    pub fn last(&self) -> HlcTimestamp {
+        *self.last.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    
+    struct ManualClock(AtomicU64);
+    
+    impl ManualClock {
+        fn set(&self, millis: u64) {
+            self.0.store(millis, Ordering::SeqCst);
+        }
+    }
+    
+    impl PhysicalClock for ManualClock {
+        fn now_millis(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+    
+    fn manual_clock(millis: u64) -> (Arc<ManualClock>, HybridLogicalClock) {
+        let physical = Arc::new(ManualClock(AtomicU64::new(millis)));
+        let hlc = HybridLogicalClock::with_physical_clock(physical.clone());
+        (physical, hlc)
+    }
+    
+    #[test]
+    fn test_monotonic_when_physical_clock_jumps_backward() {
+        let (physical, hlc) = manual_clock(1_000);
+        
+        let t1 = hlc.now();
+        assert_eq!(t1, HlcTimestamp::new(1_000, 0));
+        let t2 = hlc.now();
+        assert_eq!(t2, HlcTimestamp::new(1_000, 1));
+        
+        // Clock steps back by 500ms: keep the old physical part, count logically
+        physical.set(500);
+        let t3 = hlc.now();
+        let t4 = hlc.now();
+        assert!(t2 < t3 && t3 < t4);
+        assert_eq!(t4, HlcTimestamp::new(1_000, 3));
+        
+        // Once the physical clock passes the old high-water mark it takes over again
+        physical.set(1_001);
+        let t5 = hlc.now();
+        assert_eq!(t5, HlcTimestamp::new(1_001, 0));
+        assert!(t4 < t5);
+    }
+    
+    #[test]
+    fn test_logical_overflow_advances_physical() {
+        let (_physical, hlc) = manual_clock(10);
+        hlc.update(HlcTimestamp::new(10, u32::MAX));
+        
+        assert_eq!(hlc.last(), HlcTimestamp::new(11, 0));
+        assert_eq!(hlc.now(), HlcTimestamp::new(11, 1));
+    }
+    
+    #[test]
+    fn test_update_orders_causally_across_nodes() {
+        let (_a_physical, node_a) = manual_clock(5_000);
+        let (b_physical, node_b) = manual_clock(2_000);
+        
+        // A message from a node whose clock is ahead moves the receiver forward
+        let sent = node_a.now();
+        let received = node_b.update(sent);
+        assert!(received > sent);
+        assert!(node_b.now() > received);
+        
+        // A stale remote timestamp never moves the clock backwards
+        let before = node_b.last();
+        let after = node_b.update(HlcTimestamp::new(1, 0));
+        assert!(after > before);
+        
+        // The receiver's physical clock catching up resets the logical counter
+        b_physical.set(6_000);
+        assert_eq!(node_b.update(sent), HlcTimestamp::new(6_000, 0));
+    }
+}
@@ -1,7 +1,35 @@
+pub mod hlc;
 pub mod mvcc;
 pub mod manager;
 pub mod error;
 
 pub use error::{TransactionError, Result};
+pub use hlc::{HlcTimestamp, HybridLogicalClock, PhysicalClock, SystemClock};
 pub use manager::TransactionManager;
-pub use mvcc::{TransactionId, IsolationLevel};
\ No newline at end of file
+pub use mvcc::{TransactionId, IsolationLevel};
+
+use serde::{Deserialize, Serialize};
+
+/// Transaction manager configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransactionConfig {
+    /// Wait out clock uncertainty before commits return (see
+    /// `TransactionManager::with_commit_wait`)
+    pub commit_wait: bool,
+    /// Bound on how far any node's clock may be from this one's
+    pub max_clock_offset_ms: u64,
+    /// Level of transactions begun without one, and of the transaction
+    /// each statement outside one runs in
+    pub default_isolation_level: IsolationLevel,
+}
+
+impl Default for TransactionConfig {
+    fn default() -> Self {
+        Self {
+            commit_wait: false,
+            max_clock_offset_ms: hlc::DEFAULT_MAX_OFFSET.as_millis() as u64,
+            default_isolation_level: IsolationLevel::ReadCommitted,
+        }
+    }
+}
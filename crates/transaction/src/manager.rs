@@ -1,47 +1,144 @@
 use crate::{
     error::{Result, TransactionError},
+    hlc::{HlcTimestamp, HybridLogicalClock},
     mvcc::{Transaction, TransactionId, TransactionStatus, IsolationLevel},
+    TransactionConfig,
 };
 use dashmap::DashMap;
+use std::collections::BTreeMap;
+use std::ops::Bound;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{RwLock, RwLockReadGuard};
 
-/// Transaction manager with MVCC support
+/// Values of keys by key, where None is an absent or deleted key
+pub type WriteSet = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// Transaction manager with MVCC support.
+///
+/// Writes made inside a transaction are buffered here until it commits. The
+/// store underneath keeps one version of each key, so snapshots are kept
+/// copy-on-write: while a transaction that reads from a snapshot is active,
+/// every writer records the value a key had before overwriting it, and the
+/// transaction reads that value instead of the new one.
 pub struct TransactionManager {
     active_transactions: Arc<DashMap<TransactionId, Transaction>>,
+    clock: Arc<HybridLogicalClock>,
+    commit_wait: bool,
+    default_isolation_level: IsolationLevel,
+    // Buffered writes of each open transaction
+    write_sets: DashMap<TransactionId, WriteSet>,
+    // Values overwritten since each open snapshot transaction began
+    snapshots: DashMap<TransactionId, WriteSet>,
+    // Writers share it while saving and overwriting values; a snapshot
+    // starts with it held exclusively, so it never sees half of a write
+    write_gate: RwLock<()>,
+}
+
+impl Default for TransactionManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TransactionManager {
     pub fn new() -> Self {
+        Self::with_clock(Arc::new(HybridLogicalClock::new()))
+    }
+    
+    /// Create a manager that takes start and commit timestamps from `clock`
+    pub fn with_clock(clock: Arc<HybridLogicalClock>) -> Self {
         Self {
             active_transactions: Arc::new(DashMap::new()),
+            clock,
+            commit_wait: false,
+            default_isolation_level: IsolationLevel::ReadCommitted,
+            write_sets: DashMap::new(),
+            snapshots: DashMap::new(),
+            write_gate: RwLock::new(()),
         }
     }
     
+    /// Create a manager with its own clock, set up as `config` says
+    pub fn with_config(config: &TransactionConfig) -> Self {
+        let clock = HybridLogicalClock::new().with_max_offset(Duration::from_millis(config.max_clock_offset_ms));
+        Self::with_clock(Arc::new(clock))
+            .with_commit_wait(config.commit_wait)
+            .with_default_isolation_level(config.default_isolation_level)
+    }
+    
+    /// When enabled, `commit` waits out the clock's uncertainty bound after
+    /// choosing the commit timestamp, so any transaction that starts after
+    /// the commit returns, on any node, gets a later start timestamp
+    pub fn with_commit_wait(mut self, enabled: bool) -> Self {
+        self.commit_wait = enabled;
+        self
+    }
+    
+    /// Level of transactions begun without one
+    pub fn with_default_isolation_level(mut self, level: IsolationLevel) -> Self {
+        self.default_isolation_level = level;
+        self
+    }
+    
+    pub fn default_isolation_level(&self) -> IsolationLevel {
+        self.default_isolation_level
+    }
+    
+    pub fn clock(&self) -> &Arc<HybridLogicalClock> {
+        &self.clock
+    }
+    
+    /// Merge a timestamp seen on a message from another node so that later
+    /// transactions here are ordered after it
+    pub fn observe_timestamp(&self, remote: HlcTimestamp) -> HlcTimestamp {
+        self.clock.update(remote)
+    }
+    
     pub async fn begin(&self, isolation_level: IsolationLevel) -> Result<TransactionId> {
-        let txn = Transaction::new(isolation_level);
+        let _gate = if isolation_level.uses_snapshot() {
+            Some(self.write_gate.write().await)
+        } else {
+            None
+        };
+        let txn = Transaction::new(isolation_level, self.clock.now());
         let txn_id = txn.id;
         
         self.active_transactions.insert(txn_id, txn);
+        self.write_sets.insert(txn_id, WriteSet::new());
+        if isolation_level.uses_snapshot() {
+            self.snapshots.insert(txn_id, WriteSet::new());
+        }
         
         Ok(txn_id)
     }
     
     pub async fn commit(&self, txn_id: TransactionId) -> Result<()> {
-        if let Some(mut txn) = self.active_transactions.get_mut(&txn_id) {
+        let commit_timestamp = if let Some(mut txn) = self.active_transactions.get_mut(&txn_id) {
             if !txn.is_active() {
                 return Err(TransactionError::NotFound(txn_id.0.to_string()));
             }
             
+            let commit_timestamp = self.clock.now();
+            txn.commit_timestamp = Some(commit_timestamp);
             txn.status = TransactionStatus::Committed;
-            Ok(())
+            commit_timestamp
         } else {
-            Err(TransactionError::NotFound(txn_id.0.to_string()))
+            return Err(TransactionError::NotFound(txn_id.0.to_string()));
+        };
+        self.release(txn_id);
+        
+        if self.commit_wait {
+            self.clock.wait_out_uncertainty(commit_timestamp).await;
         }
+        Ok(())
     }
     
     pub async fn abort(&self, txn_id: TransactionId) -> Result<()> {
         if let Some(mut txn) = self.active_transactions.get_mut(&txn_id) {
             txn.status = TransactionStatus::Aborted;
+            drop(txn);
+            self.release(txn_id);
             Ok(())
         } else {
             Err(TransactionError::NotFound(txn_id.0.to_string()))
@@ -51,11 +148,110 @@ impl TransactionManager {
     pub fn get_transaction(&self, txn_id: &TransactionId) -> Option<Transaction> {
         self.active_transactions.get(txn_id).map(|t| t.clone())
     }
+    
+    /// Buffer a write of `key` until the transaction commits. None deletes it.
+    pub fn buffer_write(&self, txn_id: TransactionId, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<()> {
+        let mut writes = self.write_sets.get_mut(&txn_id)
+            .ok_or_else(|| TransactionError::NotFound(txn_id.0.to_string()))?;
+        writes.insert(key, value);
+        Ok(())
+    }
+    
+    /// The transaction's buffered write of `key`, if it has one
+    pub fn buffered(&self, txn_id: TransactionId, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.write_sets.get(&txn_id)?.get(key).cloned()
+    }
+    
+    /// Buffered writes of keys in `start..end`, in key order
+    pub fn buffered_range(&self, txn_id: TransactionId, start: &[u8], end: &[u8]) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        self.write_sets.get(&txn_id).map_or_else(Vec::new, |writes| range(&writes, start, end))
+    }
+    
+    /// Every write the transaction has buffered, to be applied when it commits
+    pub fn write_set(&self, txn_id: TransactionId) -> Result<WriteSet> {
+        self.write_sets.get(&txn_id)
+            .map(|writes| writes.clone())
+            .ok_or_else(|| TransactionError::NotFound(txn_id.0.to_string()))
+    }
+    
+    /// Shared by a writer from reading the values it is about to overwrite
+    /// until it has written them, so no snapshot begins in between
+    pub async fn write_gate(&self) -> RwLockReadGuard<'_, ()> {
+        self.write_gate.read().await
+    }
+    
+    /// Whether any open transaction reads from a snapshot, so writers need
+    /// to `preserve` the values they overwrite
+    pub fn has_snapshots(&self) -> bool {
+        !self.snapshots.is_empty()
+    }
+    
+    /// Record that `key` held `old` before a write that is about to replace
+    /// it. Snapshots that already hold an older value of the key keep it.
+    pub fn preserve(&self, key: &[u8], old: Option<Vec<u8>>) {
+        for mut snapshot in self.snapshots.iter_mut() {
+            snapshot.entry(key.to_vec()).or_insert_with(|| old.clone());
+        }
+    }
+    
+    /// The value `key` had when the transaction's snapshot was taken, if it
+    /// has been overwritten since
+    pub fn snapshot_version(&self, txn_id: TransactionId, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.snapshots.get(&txn_id)?.get(key).cloned()
+    }
+    
+    /// Snapshot values of the keys in `start..end` overwritten since the
+    /// snapshot was taken, in key order
+    pub fn snapshot_range(&self, txn_id: TransactionId, start: &[u8], end: &[u8]) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        self.snapshots.get(&txn_id).map_or_else(Vec::new, |snapshot| range(&snapshot, start, end))
+    }
+    
+    /// Fail with `Conflict` if another transaction has committed a write to
+    /// a key this snapshot transaction also writes, so the first committer
+    /// wins. Transactions without a snapshot never conflict.
+    pub fn check_write_conflicts(&self, txn_id: TransactionId) -> Result<()> {
+        let (Some(snapshot), Some(writes)) = (self.snapshots.get(&txn_id), self.write_sets.get(&txn_id)) else {
+            return Ok(());
+        };
+        if writes.keys().any(|key| snapshot.contains_key(key)) {
+            return Err(TransactionError::Conflict);
+        }
+        Ok(())
+    }
+    
+    fn release(&self, txn_id: TransactionId) {
+        self.write_sets.remove(&txn_id);
+        self.snapshots.remove(&txn_id);
+    }
+}
+
+fn range(writes: &WriteSet, start: &[u8], end: &[u8]) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+    if start >= end {
+        return Vec::new();
+    }
+    writes.range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hlc::{PhysicalClock, SystemClock};
+    use std::time::{Duration, Instant};
+    
+    /// System time shifted by a fixed skew, standing in for another node's clock
+    struct SkewedClock(i64);
+    
+    impl PhysicalClock for SkewedClock {
+        fn now_millis(&self) -> u64 {
+            (SystemClock.now_millis() as i64 + self.0) as u64
+        }
+    }
+    
+    fn node(skew_millis: i64, max_offset: Duration) -> Arc<HybridLogicalClock> {
+        Arc::new(HybridLogicalClock::with_physical_clock(Arc::new(SkewedClock(skew_millis))).with_max_offset(max_offset))
+    }
     
     #[tokio::test]
     async fn test_transaction_lifecycle() {
@@ -74,6 +270,7 @@ mod tests {
         // Verify transaction is committed
         let txn = manager.get_transaction(&txn_id).unwrap();
         assert!(!txn.is_active());
+        assert!(txn.commit_timestamp.unwrap() > txn.start_timestamp);
     }
     
     #[tokio::test]
@@ -89,5 +286,84 @@ mod tests {
         let txn = manager.get_transaction(&txn_id).unwrap();
         assert!(!txn.is_active());
         assert!(matches!(txn.status, TransactionStatus::Aborted));
+        assert_eq!(txn.commit_timestamp, None);
+    }
+    
+    #[tokio::test]
+    async fn test_commit_wait_covers_clock_uncertainty() {
+        let max_offset = Duration::from_millis(100);
+        
+        // Node B's clock runs the full uncertainty window behind node A's
+        for commit_wait in [false, true] {
+            let node_a = TransactionManager::with_clock(node(0, max_offset)).with_commit_wait(commit_wait);
+            let node_b = TransactionManager::with_clock(node(-100, max_offset));
+            
+            let txn_id = node_a.begin(IsolationLevel::Serializable).await.unwrap();
+            let started = Instant::now();
+            node_a.commit(txn_id).await.unwrap();
+            let waited = started.elapsed();
+            let commit_timestamp = node_a.get_transaction(&txn_id).unwrap().commit_timestamp.unwrap();
+            
+            let later = node_b.begin(IsolationLevel::Serializable).await.unwrap();
+            let later_start = node_b.get_transaction(&later).unwrap().start_timestamp;
+            
+            if commit_wait {
+                assert!(waited >= max_offset, "waited only {:?}", waited);
+                assert!(waited < max_offset * 3, "waited {:?}", waited);
+                assert!(later_start > commit_timestamp);
+            } else {
+                assert!(waited < max_offset);
+                // Without the wait the lagging node can start "before" the commit
+                assert!(later_start < commit_timestamp);
+            }
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_timestamps_follow_remote_clock() {
+        let manager = TransactionManager::new();
+        let first = manager.begin(IsolationLevel::Serializable).await.unwrap();
+        
+        // A node far in the future commits; our next transaction must start after it
+        let remote = HlcTimestamp::new(manager.clock().last().physical + 60_000, 7);
+        manager.observe_timestamp(remote);
+        let second = manager.begin(IsolationLevel::Serializable).await.unwrap();
+        
+        let first = manager.get_transaction(&first).unwrap();
+        let second = manager.get_transaction(&second).unwrap();
+        assert!(first.start_timestamp < remote);
+        assert!(second.start_timestamp > remote);
+    }    
+    #[tokio::test]
+    async fn test_buffered_writes_and_snapshots() {
+        let manager = TransactionManager::new();
+        let reader = manager.begin(IsolationLevel::RepeatableRead).await.unwrap();
+        let writer = manager.begin(IsolationLevel::ReadCommitted).await.unwrap();
+        assert!(manager.has_snapshots());
+        
+        manager.buffer_write(writer, b"a".to_vec(), Some(b"1".to_vec())).unwrap();
+        manager.buffer_write(writer, b"b".to_vec(), None).unwrap();
+        assert_eq!(manager.buffered(writer, b"a"), Some(Some(b"1".to_vec())));
+        assert_eq!(manager.buffered(writer, b"b"), Some(None));
+        assert_eq!(manager.buffered(reader, b"a"), None);
+        assert_eq!(manager.buffered_range(writer, b"a", b"b").len(), 1);
+        
+        // The reader keeps the first value each key had after it began
+        manager.preserve(b"a", None);
+        manager.preserve(b"a", Some(b"later".to_vec()));
+        assert_eq!(manager.snapshot_version(reader, b"a"), Some(None));
+        assert_eq!(manager.snapshot_version(reader, b"z"), None);
+        assert_eq!(manager.snapshot_version(writer, b"a"), None);
+        
+        // Keys written since it began conflict with its own writes
+        manager.buffer_write(reader, b"c".to_vec(), Some(b"2".to_vec())).unwrap();
+        manager.check_write_conflicts(reader).unwrap();
+        manager.buffer_write(reader, b"a".to_vec(), Some(b"3".to_vec())).unwrap();
+        assert!(matches!(manager.check_write_conflicts(reader), Err(TransactionError::Conflict)));
+        
+        manager.commit(writer).await.unwrap();
+        manager.abort(reader).await.unwrap();
+        assert!(!manager.has_snapshots());
+        assert!(manager.write_set(writer).is_err());
     }
 }
\ No newline at end of file
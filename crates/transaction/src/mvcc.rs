@@ -1,3 +1,4 @@
+use crate::hlc::HlcTimestamp;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -10,7 +11,13 @@ impl TransactionId {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Default for TransactionId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum IsolationLevel {
     ReadUncommitted,
     ReadCommitted,
@@ -18,11 +25,21 @@ pub enum IsolationLevel {
     Serializable,
 }
 
+impl IsolationLevel {
+    /// Whether a transaction at this level reads the data as it was when it
+    /// began, rather than the latest committed data
+    pub fn uses_snapshot(&self) -> bool {
+        matches!(self, IsolationLevel::RepeatableRead | IsolationLevel::Serializable)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Transaction {
     pub id: TransactionId,
     pub isolation_level: IsolationLevel,
-    pub start_timestamp: u64,
+    pub start_timestamp: HlcTimestamp,
+    /// Set when the transaction commits
+    pub commit_timestamp: Option<HlcTimestamp>,
     pub status: TransactionStatus,
 }
 
@@ -34,14 +51,12 @@ pub enum TransactionStatus {
 }
 
 impl Transaction {
-    pub fn new(isolation_level: IsolationLevel) -> Self {
+    pub fn new(isolation_level: IsolationLevel, start_timestamp: HlcTimestamp) -> Self {
         Self {
             id: TransactionId::new(),
             isolation_level,
-            start_timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64,
+            start_timestamp,
+            commit_timestamp: None,
             status: TransactionStatus::Active,
         }
     }
@@ -1,35 +1,101 @@
-use nextdb::{server::DatabaseServer, client::DatabaseClient};
-use std::env;
-use tokio;
+use nextdb::{server::{self, ApiToken, AuthConfig, Config, DatabaseServer, Shutdown, TlsConfig}, client::{DatabaseClient, FormatOptions, OutputFormat}};
+use nextdb::storage::{BlockCache, SSTable};
+use std::{env, path::{Path, PathBuf}};
 use tracing::info;
+use tracing_subscriber::{prelude::*, reload, EnvFilter};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize tracing
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+    // Initialize tracing, with a filter a config reload can replace
+    let (filter, filter_handle) = reload::Layer::new(EnvFilter::from_default_env());
+    tracing_subscriber::registry().with(filter).with(tracing_subscriber::fmt::layer()).init();
+    // Directives from the config's log_level, or RUST_LOG without one
+    let set_log_level = move |level: Option<&str>| {
+        let filter = match level {
+            Some(level) => EnvFilter::try_new(level).map_err(|e| e.to_string())?,
+            None => EnvFilter::from_default_env(),
+        };
+        filter_handle.reload(filter).map_err(|e| e.to_string())
+    };
 
     let args: Vec<String> = env::args().collect();
     
     match args.get(1).map(|s| s.as_str()) {
         Some("server") => {
             info!("🚀 Starting NextDB Server...");
-            let port = args.get(2)
-                .and_then(|s| s.parse::<u16>().ok())
-                .unwrap_or(8080);
+            let mut config_path = env::var("NEXTDB_CONFIG").ok();
+            let mut overrides = Vec::new();
+            let mut rest = args[2..].iter();
+            while let Some(arg) = rest.next() {
+                let (flag, inline) = match arg.split_once('=') {
+                    Some((flag, value)) => (flag, Some(value.to_string())),
+                    None => (arg.as_str(), None),
+                };
+                let mut value = || inline.clone().or_else(|| rest.next().cloned()).ok_or(format!("{} needs a value", flag));
+                match flag {
+                    "--config" => config_path = Some(value()?),
+                    "--port" => overrides.push(("server.port", value()?)),
+                    "--data-dir" => overrides.push(("server.data_dir", value()?)),
+                    "--raft-address" => overrides.push(("consensus.raft_address", value()?)),
+                    "--join" => overrides.push(("consensus.join", value()?)),
+                    _ if arg.parse::<u16>().is_ok() => overrides.push(("server.port", arg.clone())),
+                    _ => return Err(format!("unknown server argument '{}'", arg).into()),
+                }
+            }
             
-            let server = DatabaseServer::new(port).await?;
-            server.start().await?;
+            let config_path = config_path.map(PathBuf::from);
+            let config = load_config(config_path.as_deref(), &overrides)?;
+            if config.server.log_level.is_some() {
+                set_log_level(config.server.log_level.as_deref())?;
+            }
+            
+            // Reloads read the same file, environment and flags again
+            let server = DatabaseServer::new(config).await?
+                .with_config_loader(move || load_config(config_path.as_deref(), &overrides))
+                .with_log_filter(set_log_level);
+            if server.start().await? == Shutdown::TimedOut {
+                // Storage was still closed cleanly, but requests were cut off
+                std::process::exit(2);
+            }
         }
         Some("client") => {
             info!("📡 Starting NextDB Client...");
-            let default_addr = "localhost:8080".to_string();
-            let addr = args.get(2).unwrap_or(&default_addr);
+            let mut addr = "localhost:8080";
+            let mut output = OutputFormat::Table;
+            let mut rest = args[2..].iter();
+            while let Some(arg) = rest.next() {
+                if let Some(value) = arg.strip_prefix("--format=") {
+                    output = value.parse()?;
+                } else if arg == "--format" {
+                    output = rest.next().ok_or("--format needs a value")?.parse()?;
+                } else {
+                    addr = arg;
+                }
+            }
             
-            let client = DatabaseClient::new(addr).await?;
+            let format = FormatOptions { output, ..Default::default() };
+            let client = DatabaseClient::new(addr).await?.with_format(format);
             client.run_interactive().await?;
         }
+        Some(command @ ("dump" | "verify")) => {
+            let mut file = None;
+            let mut keys = false;
+            for arg in &args[2..] {
+                match arg.as_str() {
+                    "--keys" if command == "dump" => keys = true,
+                    _ if file.is_none() => file = Some(PathBuf::from(arg)),
+                    _ => return Err(format!("unknown {} argument '{}'", command, arg).into()),
+                }
+            }
+            let file = file.ok_or(format!("{} needs an SSTable file", command))?;
+            let sstable = SSTable::open(&file).await
+                .map_err(|e| format!("cannot open {}: {}", file.display(), e))?;
+            if command == "dump" {
+                dump_sstable(&sstable, keys)?;
+            } else if !verify_sstable(&sstable) {
+                std::process::exit(1);
+            }
+        }
         Some("benchmark") => {
             info!("📊 Running NextDB Benchmark...");
             run_benchmark().await?;
@@ -38,19 +104,106 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             println!("NextDB - Next-generation distributed database engine");
             println!();
             println!("Usage:");
-            println!("  {} server [port]     - Start database server (default port: 8080)", args[0]);
-            println!("  {} client [address]  - Start interactive client (default: localhost:8080)", args[0]);
+            println!("  {} server [port] [--config FILE] [--port PORT] [--data-dir DIR]", args[0]);
+            println!("                       - Start database server (default port: 8080); flags override");
+            println!("                         the config file (see nextdb.example.toml) and environment");
+            println!("        [--raft-address ADDR] [--join ADDR]");
+            println!("                       - Run in cluster mode, with Raft listening on ADDR, joining");
+            println!("                         the cluster through the member at --join (see docs/cluster.md)");
+            println!("                       - On SIGHUP the server reloads its config, applying changes");
+            println!("                         that need no restart (see nextdb.example.toml)");
+            println!("  {} client [address] [--format table|csv|json]", args[0]);
+            println!("                       - Start interactive client (default: localhost:8080); the address");
+            println!("                         may be a connection string like nextdb://host:8080/?timeout=5s");
+            println!("  {} dump FILE [--keys]", args[0]);
+            println!("                       - Print an SSTable's footer, and with --keys every entry");
+            println!("  {} verify FILE       - Check an SSTable's blocks against its index, exiting with", args[0]);
+            println!("                         status 1 if it is damaged");
             println!("  {} benchmark         - Run performance benchmark", args[0]);
             println!();
             println!("Environment Variables:");
             println!("  RUST_LOG=info        - Set logging level");
+            println!("  NEXTDB_CONFIG        - Config file, unless --config is given");
+            println!("  NEXTDB_<SECTION>__<KEY>");
+            println!("                       - Override a config file key, e.g. NEXTDB_STORAGE__CACHE_SIZE_MB=512");
             println!("  NEXTDB_DATA_DIR      - Database data directory");
+            println!("  NEXTDB_PROTOCOL_PORT - Port for the binary protocol (off by default)");
+            println!("  NEXTDB_API_TOKENS    - HTTP API tokens as name:ro|rw:token,... (API open by default)");
+            println!("  NEXTDB_TLS_CERT, NEXTDB_TLS_KEY");
+            println!("                       - PEM certificate and key to serve HTTPS with");
+            println!("  NEXTDB_TLS_CLIENT_CA - PEM CA certificates client certificates must chain to");
+            println!("  NEXTDB_SHUTDOWN_TIMEOUT_MS");
+            println!("                       - How long requests may drain on SIGTERM (default: 30000);");
+            println!("                         the server exits with status 2 if they do not finish");
+            #[cfg(feature = "postgres")]
+            println!("  NEXTDB_PG_PASSWORD   - Password PostgreSQL clients must give (none by default)");
         }
     }
     
     Ok(())
 }
 
+/// The server config from `path`, the environment and `overrides`, as
+/// flags give them
+fn load_config(path: Option<&Path>, overrides: &[(&str, String)]) -> server::Result<Config> {
+    let overrides: Vec<(&str, &str)> = overrides.iter().map(|(key, value)| (*key, value.as_str())).collect();
+    let mut config = Config::load(path, env::vars(), &overrides)?;
+    if let Ok(tokens) = env::var("NEXTDB_API_TOKENS") {
+        config.server.auth = Some(AuthConfig { tokens: ApiToken::parse_list(&tokens)? });
+    }
+    if let (Ok(cert_path), Ok(key_path)) = (env::var("NEXTDB_TLS_CERT"), env::var("NEXTDB_TLS_KEY")) {
+        config.server.tls = Some(TlsConfig {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            client_ca_path: env::var("NEXTDB_TLS_CLIENT_CA").ok().map(Into::into),
+        });
+    }
+    #[cfg(feature = "postgres")]
+    if let (Some(postgres), Ok(password)) = (&mut config.server.postgres, env::var("NEXTDB_PG_PASSWORD")) {
+        postgres.password = Some(password);
+    }
+    Ok(config)
+}
+
+/// Print the footer of an SSTable, and with `keys` every entry in it
+fn dump_sstable(sstable: &SSTable, keys: bool) -> Result<(), Box<dyn std::error::Error>> {
+    println!("file: {}", sstable.path().display());
+    println!("size: {} bytes", sstable.file_size());
+    println!("entries: {}", sstable.num_entries());
+    println!("blocks: {}", sstable.num_blocks());
+    println!("compression: {:?}", sstable.compression());
+    match sstable.sequence_range() {
+        Some((min, max)) => println!("sequences: {}..={}", min, max),
+        None => println!("sequences: not recorded"),
+    }
+    if let Some((first, last)) = sstable.key_range() {
+        println!("keys: {}..={}", first.escape_ascii(), last.escape_ascii());
+    }
+    if keys {
+        let cache = BlockCache::new(0);
+        for entry in sstable.iter(&cache) {
+            let (key, value, sequence) = entry?;
+            match value {
+                Some(value) => println!("{} @{} ({} bytes)", key.escape_ascii(), sequence, value.len()),
+                None => println!("{} @{} (deleted)", key.escape_ascii(), sequence),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Report what is wrong with an SSTable, returning whether it is sound
+fn verify_sstable(sstable: &SSTable) -> bool {
+    let problems = sstable.verify();
+    for problem in &problems {
+        println!("corrupt: {}", problem);
+    }
+    if problems.is_empty() {
+        println!("{}: ok, {} entries in {} blocks", sstable.path().display(), sstable.num_entries(), sstable.num_blocks());
+    }
+    problems.is_empty()
+}
+
 async fn run_benchmark() -> Result<(), Box<dyn std::error::Error>> {
     use std::time::Instant;
     
@@ -0,0 +1,427 @@
+//! Runs queries through `DatabaseClient` against a server process.
+
+use futures::StreamExt;
+use nextdb::client::{ClientError, DatabaseClient, IsolationLevel, Value};
+use serde::Deserialize;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Kills the server when the test ends, passing or not
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Start a server in `data_dir`, and connect to it with `options` in the
+/// connection string
+async fn start(data_dir: &TempDir, options: &str) -> (Server, DatabaseClient) {
+    start_with_env(data_dir, options, &[]).await
+}
+
+/// `start`, with `env` set for the server, such as to override its config
+async fn start_with_env(data_dir: &TempDir, options: &str, env: &[(&str, &str)]) -> (Server, DatabaseClient) {
+    let port = free_port();
+    let server = Server(Command::new(env!("CARGO_BIN_EXE_nextdb"))
+        .args(["server", &port.to_string(), "--data-dir"])
+        .arg(data_dir.path())
+        .envs(env.iter().copied())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap());
+
+    (server, connect(&format!("http://127.0.0.1:{}/{}", port, options)).await)
+}
+
+/// Connect as `address` says, once a server there is up
+async fn connect(address: &str) -> DatabaseClient {
+    for _ in 0..200 {
+        match DatabaseClient::new(address).await {
+            Ok(client) => return client,
+            Err(ClientError::Connection(_)) => tokio::time::sleep(Duration::from_millis(50)).await,
+            Err(e) => panic!("unexpected error connecting: {}", e),
+        }
+    }
+    panic!("server did not start")
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[tokio::test]
+async fn test_client_runs_queries_against_server() {
+    let data_dir = TempDir::new().unwrap();
+    let (_server, client) = start(&data_dir, "").await;
+
+    let created = client.execute_query("CREATE TABLE users (id INT PRIMARY KEY, name TEXT, score FLOAT)").await.unwrap();
+    assert!(created.rows.is_empty());
+    let inserted = client.execute_query("INSERT INTO users VALUES (1, 'Ada', 9.5), (2, 'Grace', NULL)").await.unwrap();
+    assert_eq!(inserted.rows_affected, Some(2));
+
+    let result = client.execute_query("SELECT id, name, score FROM users ORDER BY id").await.unwrap();
+    assert_eq!(result.column_names(), vec!["id", "name", "score"]);
+    assert_eq!(result.columns[2].data_type.as_deref(), Some("FLOAT"));
+    assert_eq!(result.rows, vec![
+        vec![Value::Integer(1), Value::Text("Ada".to_string()), Value::Float(9.5)],
+        vec![Value::Integer(2), Value::Text("Grace".to_string()), Value::Null],
+    ]);
+
+    // The server's error codes come back as the matching errors
+    assert!(matches!(client.execute_query("SELEKT 1").await, Err(ClientError::Query(_))));
+    assert!(matches!(client.execute_query("SELECT * FROM missing").await, Err(ClientError::NotFound(_))));
+    assert!(matches!(
+        client.execute_query("INSERT INTO users VALUES (1, 'Again', 0.0)").await,
+        Err(ClientError::ConstraintViolation(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_binary_keys_and_values_round_trip() {
+    let data_dir = TempDir::new().unwrap();
+    let (_server, client) = start(&data_dir, "?fetch_size=7").await;
+    client.execute_query("CREATE TABLE t (id INT PRIMARY KEY)").await.unwrap();
+    client.execute_query("INSERT INTO t VALUES (1)").await.unwrap();
+
+    // Zero bytes and bytes that are not UTF-8, in keys and values alike
+    let prefix = b"\x00bin\xff";
+    let key = |i: u8| [&prefix[..], &[i, 0x00, 0xc3, 0x28]].concat();
+    let value = |i: u8| vec![0x00, i, 0xff, 0xfe];
+    for i in 0..25 {
+        client.kv_put(&key(i), &value(i)).await.unwrap();
+    }
+    client.kv_put(b"\x00bim\xff", b"before").await.unwrap();
+    client.kv_put(b"\x00bio", b"after").await.unwrap();
+    assert_eq!(client.kv_get(&key(3)).await.unwrap(), Some(value(3)));
+    assert_eq!(client.kv_get(b"\x00bio").await.unwrap(), Some(b"after".to_vec()));
+    assert_eq!(client.kv_get(b"\x00missing").await.unwrap(), None);
+
+    // Scans page through the server seven entries at a time
+    let entries = client.kv_scan_prefix(prefix, 100).await.unwrap();
+    assert_eq!(entries, (0..25).map(|i| (key(i), value(i))).collect::<Vec<_>>());
+    let entries = client.kv_scan_prefix(prefix, 10).await.unwrap();
+    assert_eq!(entries, (0..10).map(|i| (key(i), value(i))).collect::<Vec<_>>());
+    let everything = client.kv_scan_prefix(b"", 1000).await.unwrap();
+    assert_eq!(everything.len(), 27);
+
+    let values = client.kv_multi_get(&[&key(0), b"\x00missing", &key(24)]).await.unwrap();
+    assert_eq!(values, vec![Some(value(0)), None, Some(value(24))]);
+
+    client.kv_delete(&key(0)).await.unwrap();
+    client.kv_delete(&key(0)).await.unwrap();
+    assert_eq!(client.kv_get(&key(0)).await.unwrap(), None);
+    assert_eq!(client.kv_scan_prefix(prefix, 100).await.unwrap().len(), 24);
+
+    // Keys holding SQL data are not raw keys
+    assert!(matches!(client.kv_put(b"\x01c", b"x").await, Err(ClientError::Query(_))));
+    assert!(matches!(client.kv_get(b"\x01").await, Err(ClientError::Query(_))));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_pool_shares_connections_between_parallel_queries() {
+    let data_dir = TempDir::new().unwrap();
+    let (_server, client) = start(&data_dir, "?pool=8&checkout_timeout=30s").await;
+    let client = Arc::new(client);
+    client.execute_query("CREATE TABLE items (id INT PRIMARY KEY, label TEXT)").await.unwrap();
+
+    // Watch the pool while 200 queries share its 8 connections
+    let finished = Arc::new(AtomicBool::new(false));
+    let watcher = {
+        let (client, finished) = (client.clone(), finished.clone());
+        tokio::spawn(async move {
+            let mut most = (0, 0);
+            while !finished.load(Ordering::SeqCst) {
+                let stats = client.pool_stats();
+                most = (most.0.max(stats.in_use), most.1.max(stats.waiters));
+                tokio::task::yield_now().await;
+            }
+            most
+        })
+    };
+    let queries: Vec<_> = (0..200)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let inserted = client.execute_query(&format!("INSERT INTO items VALUES ({}, 'item {}')", i, i)).await?;
+                let selected = client.execute_query(&format!("SELECT label FROM items WHERE id = {}", i)).await?;
+                Ok::<_, ClientError>((inserted.rows_affected, selected.rows))
+            })
+        })
+        .collect();
+    for (i, query) in queries.into_iter().enumerate() {
+        // Every query finished, none starved past the checkout timeout
+        let (inserted, rows) = query.await.unwrap().unwrap();
+        assert_eq!(inserted, Some(1));
+        assert_eq!(rows, vec![vec![Value::Text(format!("item {}", i))]]);
+    }
+
+    finished.store(true, Ordering::SeqCst);
+    let (most_in_use, most_waiting) = watcher.await.unwrap();
+    assert!(most_in_use <= 8, "{} connections in use", most_in_use);
+    assert!(most_waiting > 0, "no query waited for a connection");
+    let stats = client.pool_stats();
+    assert!(stats.created <= 8, "{:?}", stats);
+    assert_eq!(stats.idle as u64, stats.created - stats.reaped);
+    let count = client.execute_query("SELECT COUNT(*) FROM items").await.unwrap();
+    assert_eq!(count.rows, vec![vec![Value::Integer(200)]]);
+}
+
+#[tokio::test]
+async fn test_conflicting_transactions() {
+    let data_dir = TempDir::new().unwrap();
+    let (_server, client) = start(&data_dir, "?backoff=10ms").await;
+    client.execute_query("CREATE TABLE accounts (id INT PRIMARY KEY, balance INT)").await.unwrap();
+    client.execute_query("INSERT INTO accounts VALUES (1, 100)").await.unwrap();
+    let balance = async || client.execute_query("SELECT balance FROM accounts WHERE id = 1").await.unwrap().rows;
+    
+    // Of two transactions writing the same row, the second to commit conflicts
+    let mut first = client.begin(IsolationLevel::RepeatableRead).await.unwrap();
+    let mut second = client.begin(IsolationLevel::RepeatableRead).await.unwrap();
+    assert_eq!(first.execute("UPDATE accounts SET balance = balance + 10 WHERE id = 1").await.unwrap(), 1);
+    assert_eq!(second.execute("UPDATE accounts SET balance = balance + 20 WHERE id = 1").await.unwrap(), 1);
+    first.commit().await.unwrap();
+    assert!(matches!(second.commit().await, Err(ClientError::Conflict(_))));
+    assert_eq!(balance().await, vec![vec![Value::Integer(110)]]);
+    
+    // The helper runs a transaction that conflicts again, on what the other
+    // one committed
+    let mut interloper = Some(client.begin(IsolationLevel::RepeatableRead).await.unwrap());
+    interloper.as_mut().unwrap().execute("UPDATE accounts SET balance = balance + 1 WHERE id = 1").await.unwrap();
+    let mut runs = 0;
+    let seen = client.transaction(IsolationLevel::RepeatableRead, async |txn| {
+        runs += 1;
+        txn.execute("UPDATE accounts SET balance = balance + 20 WHERE id = 1").await?;
+        if let Some(interloper) = interloper.take() {
+            interloper.commit().await?;
+        }
+        Ok(txn.query("SELECT balance FROM accounts WHERE id = 1").await?.rows)
+    }).await.unwrap();
+    assert_eq!(runs, 2);
+    assert_eq!(seen, vec![vec![Value::Integer(131)]]);
+    assert_eq!(balance().await, seen);
+    
+    // A transaction dropped unfinished is rolled back, and its connection
+    // handed back
+    let mut dropped = client.begin(IsolationLevel::ReadCommitted).await.unwrap();
+    dropped.execute("UPDATE accounts SET balance = 0 WHERE id = 1").await.unwrap();
+    drop(dropped);
+    for _ in 0..100 {
+        if client.pool_stats().in_use == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(client.pool_stats().in_use, 0);
+    assert_eq!(balance().await, seen);
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+struct User {
+    id: i64,
+    name: String,
+    #[serde(rename = "email_address")]
+    email: Option<String>,
+    age: Option<u8>,
+    active: bool,
+}
+
+#[tokio::test]
+async fn test_rows_read_into_structs() {
+    let data_dir = TempDir::new().unwrap();
+    let (_server, client) = start(&data_dir, "").await;
+    client.execute_query("CREATE TABLE users (id INT PRIMARY KEY, name TEXT, email_address TEXT, age INT, active BOOLEAN)").await.unwrap();
+    client.execute_query("INSERT INTO users VALUES (1, 'Ada', 'ada@example.com', 36, true), (2, 'Grace', NULL, NULL, false)").await.unwrap();
+    
+    let users: Vec<User> = client.query_as("SELECT * FROM users ORDER BY id").await.unwrap();
+    assert_eq!(users, vec![
+        User { id: 1, name: "Ada".to_string(), email: Some("ada@example.com".to_string()), age: Some(36), active: true },
+        User { id: 2, name: "Grace".to_string(), email: None, age: None, active: false },
+    ]);
+    let grace: User = client.query_one("SELECT * FROM users WHERE id = 2").await.unwrap();
+    assert_eq!(grace, users[1]);
+    assert_eq!(client.query_opt::<User>("SELECT * FROM users WHERE id = 3").await.unwrap(), None);
+    
+    // Row counts other than asked for
+    assert!(matches!(
+        client.query_one::<User>("SELECT * FROM users WHERE id = 3").await,
+        Err(ClientError::UnexpectedRows { found: 0, .. })
+    ));
+    assert!(matches!(client.query_opt::<User>("SELECT * FROM users").await, Err(ClientError::UnexpectedRows { found: 2, .. })));
+    
+    // Values that do not fit their fields, and columns missing for them
+    client.execute_query("UPDATE users SET age = 300 WHERE id = 1").await.unwrap();
+    match client.query_as::<User>("SELECT * FROM users ORDER BY id").await {
+        Err(ClientError::Decode(message)) => {
+            assert!(message.contains("row 1: column `age` (INTEGER)") && message.contains("expected u8"), "{}", message);
+        }
+        other => panic!("expected a decode error, got {:?}", other),
+    }
+    match client.query_as::<User>("SELECT id, name AS active, name FROM users").await {
+        Err(ClientError::Decode(message)) => {
+            assert!(message.contains("column `active` (TEXT)") && message.contains("expected a boolean"), "{}", message);
+        }
+        other => panic!("expected a decode error, got {:?}", other),
+    }
+    match client.query_one::<User>("SELECT id, email_address FROM users WHERE id = 2").await {
+        Err(ClientError::Decode(message)) => assert!(message.contains("missing field `name`"), "{}", message),
+        other => panic!("expected a decode error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_large_results_streamed_a_page_at_a_time() {
+    let data_dir = TempDir::new().unwrap();
+    let env = [("NEXTDB_SERVER__MAX_CURSORS_PER_CLIENT", "1")];
+    let (_server, client) = start_with_env(&data_dir, "?fetch_size=500", &env).await;
+    client.execute_query("CREATE TABLE events (id INT PRIMARY KEY, payload TEXT)").await.unwrap();
+    for chunk in (0..20_000).collect::<Vec<i64>>().chunks(1000) {
+        let values: Vec<String> = chunk.iter().map(|id| format!("({}, 'event {}')", id, id)).collect();
+        client.execute_query(&format!("INSERT INTO events VALUES {}", values.join(", "))).await.unwrap();
+    }
+
+    // Each row is looked at and let go, so the client holds a page at most
+    let sql = "SELECT id, payload FROM events";
+    let mut rows = client.query_stream(sql);
+    let (mut count, mut next_id) = (0, 0);
+    while let Some(row) = rows.next().await {
+        let row = row.unwrap();
+        assert_eq!(row.get("id"), Some(&Value::Integer(next_id)));
+        assert_eq!(row.get("payload"), Some(&Value::Text(format!("event {}", next_id))));
+        count += 1;
+        next_id += 1;
+    }
+    assert_eq!(count, 20_000);
+
+    // The server keeps one cursor for the client, so a stream dropped
+    // halfway must have closed its own for another to start
+    let mut rows = client.query_stream(sql);
+    for _ in 0..10_000 {
+        rows.next().await.unwrap().unwrap();
+    }
+    let mut blocked = client.query_stream(sql);
+    match blocked.next().await {
+        Some(Err(ClientError::Server { code, .. })) => assert_eq!(code, "too_many_cursors"),
+        other => panic!("expected the cursor limit, got {:?}", other),
+    }
+    drop(rows);
+    let mut reopened = None;
+    for _ in 0..100 {
+        let mut rows = client.query_stream(sql);
+        match rows.next().await {
+            Some(Ok(row)) => {
+                reopened = Some(row);
+                break;
+            }
+            Some(Err(ClientError::Server { code, .. })) if code == "too_many_cursors" => {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+    assert_eq!(reopened.expect("the dropped stream's cursor was not closed").get("id"), Some(&Value::Integer(0)));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_client_fails_over_when_the_leader_dies() {
+    // A bootstraps the cluster, and B and C join it
+    let ids = ["6f1c2a4e-0b7d-4c39-9a51-2d8e7f3b1c05", "0d6b9e2f-3a41-4f8c-b7e5-91c2d4a6f803", "b3e8d1c7-5f2a-4e96-8c04-7a9d2e6f1b38"];
+    let (ports, raft_ports) = ([free_port(), free_port(), free_port()], [free_port(), free_port(), free_port()]);
+    let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let mut servers = Vec::new();
+    for i in 0..3 {
+        let join = if i > 0 { format!("join = \"127.0.0.1:{}\"\n", raft_ports[0]) } else { String::new() };
+        let config = format!(
+            "[consensus]\nnode_id = \"{}\"\nraft_address = \"127.0.0.1:{}\"\n{}[consensus.addresses]\n\"{}\" = \"127.0.0.1:{}\"\n",
+            ids[i], raft_ports[i], join, ids[i], ports[i]
+        );
+        let path = dirs[i].path().join("nextdb.toml");
+        std::fs::write(&path, config).unwrap();
+        servers.push(Some(Server(Command::new(env!("CARGO_BIN_EXE_nextdb"))
+            .args(["server", &ports[i].to_string(), "--data-dir"])
+            .arg(dirs[i].path().join("data"))
+            .arg("--config")
+            .arg(&path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap())));
+        if i == 0 {
+            connect(&format!("nextdb://127.0.0.1:{}/", ports[0])).await
+                .execute_query("CREATE TABLE events (id INT PRIMARY KEY)").await.unwrap();
+        }
+    }
+    for port in &ports[1..] {
+        let member = connect(&format!("nextdb://127.0.0.1:{}/", port)).await;
+        let mut joined = false;
+        for _ in 0..200 {
+            joined = member.execute_query("SELECT COUNT(*) FROM events").await.is_ok();
+            if joined {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(joined, "the node on port {} did not catch up", port);
+    }
+
+    let seeds: Vec<String> = ports.iter().map(|port| format!("127.0.0.1:{}", port)).collect();
+    let client = Arc::new(connect(&format!("nextdb://{}/?retries=10&backoff=100ms", seeds.join(","))).await);
+    assert_eq!(client.leader().as_deref(), Some(seeds[0].as_str()));
+
+    // Reads run alongside writes while the leader is killed
+    let finished = Arc::new(AtomicBool::new(false));
+    let reader = tokio::spawn({
+        let (client, finished) = (client.clone(), finished.clone());
+        async move {
+            let mut reads = 0;
+            while !finished.load(Ordering::SeqCst) {
+                if let Err(e) = client.execute_query("SELECT COUNT(*) FROM events").await {
+                    panic!("a read failed after {} reads: {}", reads, e);
+                }
+                reads += 1;
+            }
+            reads
+        }
+    });
+    let mut written = Vec::new();
+    for id in 0..200 {
+        if id == 50 {
+            let leader = client.leader().expect("the client knows the leader");
+            let leader = seeds.iter().position(|seed| *seed == leader).unwrap();
+            servers[leader] = None;
+        }
+        match client.execute_query(&format!("INSERT INTO events VALUES ({})", id)).await {
+            Ok(_) => written.push(id),
+            // An insert in flight as the leader died may or may not have run
+            Err(ClientError::Ambiguous(_)) => {}
+            Err(e) => panic!("insert {} failed: {}", id, e),
+        }
+    }
+    finished.store(true, Ordering::SeqCst);
+    assert!(reader.await.unwrap() > 0);
+    assert!(written.len() >= 195, "only {} inserts succeeded", written.len());
+    let leader = client.leader().expect("the client follows the new leader");
+    assert_ne!(leader, seeds[0]);
+
+    // Every acknowledged insert survives on the new leader's side
+    let mut found = Vec::new();
+    for _ in 0..100 {
+        found = client.execute_query("SELECT id FROM events").await.unwrap().rows.into_iter()
+            .map(|row| match row[..] {
+                [Value::Integer(id)] => id,
+                _ => panic!("unexpected row {:?}", row),
+            })
+            .collect();
+        if written.iter().all(|id| found.contains(id)) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(written.iter().all(|id| found.contains(id)), "lost acknowledged inserts");
+}
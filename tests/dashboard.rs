@@ -0,0 +1,57 @@
+//! Starts a server process from a read-only working directory.
+
+#![cfg(unix)]
+
+use std::os::unix::fs::PermissionsExt;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// GET `path`, returning the raw response
+async fn get(port: u16, path: &str) -> std::io::Result<String> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response)
+}
+
+#[tokio::test]
+async fn test_dashboard_served_from_read_only_directory() {
+    let data_dir = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    std::fs::set_permissions(cwd.path(), std::fs::Permissions::from_mode(0o555)).unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_nextdb"))
+        .args(["server", &port.to_string()])
+        .env("NEXTDB_DATA_DIR", data_dir.path())
+        .current_dir(cwd.path())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut dashboard = None;
+    for _ in 0..200 {
+        if let Ok(response) = get(port, "/").await {
+            dashboard = Some(response);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let static_page = get(port, "/static/index.html").await;
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    let dashboard = dashboard.expect("server did not start");
+    assert!(dashboard.starts_with("HTTP/1.1 200"), "{}", dashboard);
+    assert!(dashboard.to_ascii_lowercase().contains("content-type: text/html; charset=utf-8"), "{}", dashboard);
+    assert!(dashboard.contains("NextDB"), "{}", dashboard);
+    assert!(static_page.unwrap().starts_with("HTTP/1.1 200"));
+    // Even with permission to (as root), nothing was written there
+    assert_eq!(std::fs::read_dir(cwd.path()).unwrap().count(), 0);
+    std::fs::set_permissions(cwd.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+}
@@ -0,0 +1,100 @@
+//! Stops a server process with SIGTERM in the middle of a write workload.
+
+#![cfg(unix)]
+
+use nextdb::query::QueryExecutor;
+use nextdb::server::ServerConfig;
+use nextdb::storage::LSMTree;
+use std::collections::HashSet;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Run `sql` through the HTTP API, returning the status code and whether
+/// the query succeeded
+async fn query(port: u16, sql: &str) -> std::io::Result<(u16, bool)> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let body = format!("{{\"sql\":\"{}\"}}", sql);
+    let request = format!(
+        "POST /api/query HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body,
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    let status = response.split(' ').nth(1).and_then(|code| code.parse().ok()).unwrap_or(0);
+    Ok((status, response.contains("\"success\":true")))
+}
+
+#[tokio::test]
+async fn test_sigterm_mid_workload_closes_storage_cleanly() {
+    let temp_dir = TempDir::new().unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_nextdb"))
+        .args(["server", &port.to_string()])
+        .env("NEXTDB_DATA_DIR", temp_dir.path())
+        .env("NEXTDB_SHUTDOWN_TIMEOUT_MS", "10000")
+        .current_dir(temp_dir.path())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut ready = false;
+    for _ in 0..200 {
+        if let Ok((200, _)) = query(port, "SELECT 1").await {
+            ready = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(ready, "server did not start");
+    assert_eq!(query(port, "CREATE TABLE t (id INT PRIMARY KEY)").await.unwrap(), (200, true));
+
+    // Writers keep inserting until the server goes away
+    let acknowledged = Arc::new(Mutex::new(Vec::new()));
+    let writers: Vec<_> = (0..4).map(|writer| {
+        let acknowledged = acknowledged.clone();
+        tokio::spawn(async move {
+            for i in 0.. {
+                let id = writer * 1_000_000 + i;
+                match query(port, &format!("INSERT INTO t VALUES ({})", id)).await {
+                    Ok((200, true)) => acknowledged.lock().unwrap().push(id),
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+            }
+        })
+    }).collect();
+
+    while acknowledged.lock().unwrap().len() < 100 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let killed = Command::new("kill").args(["-TERM", &server.id().to_string()]).status().unwrap();
+    assert!(killed.success());
+
+    let status = tokio::task::spawn_blocking(move || server.wait()).await.unwrap().unwrap();
+    assert_eq!(status.code(), Some(0), "the drain should finish in time");
+    for writer in writers {
+        writer.await.unwrap();
+    }
+
+    // The data directory reopens from SSTables alone, with every acknowledged write
+    let config = ServerConfig { data_dir: temp_dir.path().to_path_buf(), ..ServerConfig::default() };
+    let storage = Arc::new(LSMTree::open(config.storage_config()).await.unwrap());
+    assert_eq!(storage.stats().await.wal_entries_replayed, 0);
+    let executor = QueryExecutor::open(storage).await.unwrap();
+    let stored: HashSet<String> = executor.execute_sql("SELECT id FROM t").await.unwrap()
+        .text_rows()
+        .into_iter()
+        .map(|row| row[0].clone())
+        .collect();
+    let acknowledged = acknowledged.lock().unwrap();
+    assert!(acknowledged.len() >= 100);
+    let missing: Vec<_> = acknowledged.iter().filter(|id| !stored.contains(&id.to_string())).collect();
+    assert!(missing.is_empty(), "acknowledged writes missing after restart: {:?}", missing);
+}